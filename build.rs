@@ -2,9 +2,18 @@ use std::env;
 use std::path::Path;
 use std::process::Command;
 
+#[path = "build/packaging.rs"]
+mod packaging;
+#[path = "build/zip_writer.rs"]
+mod zip_writer;
+
 fn main() {
-    // Only run this script when building in release mode
-    if env::var("PROFILE").unwrap() == "release" {
+    embed_build_metadata();
+
+    // Only package release builds, and not when opted out with FSR_SKIP_PACKAGE=1
+    println!("cargo:rerun-if-env-changed=FSR_SKIP_PACKAGE");
+    let skip_package = env::var("FSR_SKIP_PACKAGE").is_ok_and(|skip| skip == "1");
+    if env::var("PROFILE").unwrap() == "release" && !skip_package {
         let out_dir = env::var("OUT_DIR").unwrap();
         let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
 
@@ -15,115 +24,99 @@ fn main() {
             .unwrap()
             .join("release");
 
-        // Create zip file with version (includes HTTP files from project root)
-        create_release_zip(&target_dir, &manifest_dir);
+        // Zip or tar.gz by target, with the HTTP and Lua files from the project root
+        create_release_archive(&target_dir, &manifest_dir);
     }
 }
 
-fn create_release_zip(target_dir: &Path, manifest_dir: &str) {
-    // Get the version from Cargo.toml
+// Git commit and build time for GetServerInfo, available as env!() in the crate
+fn embed_build_metadata() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let git_dir = Path::new(&manifest_dir).join(".git");
+
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .current_dir(&manifest_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=FSR_GIT_HASH={}", git_hash);
+
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=FSR_BUILD_TIMESTAMP={}", build_time);
+
+    // Declaring any rerun-if-changed replaces cargo's default of rerunning on every
+    // package change, so list what the release archive is built from as well
+    if git_dir.join("HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        // HEAD only names the branch; commits move the branch ref
+        if let Ok(head) = std::fs::read_to_string(git_dir.join("HEAD")) {
+            if let Some(reference) = head.trim().strip_prefix("ref: ") {
+                println!("cargo:rerun-if-changed=.git/{}", reference);
+            }
+        }
+    }
+    for input in ["build.rs", "build", "Cargo.toml", "src", "http", "lua"] {
+        println!("cargo:rerun-if-changed={}", input);
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+fn create_release_archive(target_dir: &Path, manifest_dir: &str) {
     let version = env::var("CARGO_PKG_VERSION").unwrap_or_else(|_| "unknown".to_string());
     let package_name = env::var("CARGO_PKG_NAME").unwrap_or_else(|_| "fsr-rs".to_string());
-
-    // Create zip filename with version
-    let zip_filename = format!("{}-{}.zip", package_name, version);
-    let zip_path = target_dir.join(&zip_filename);
-
-    println!("cargo:warning=Creating release zip: {}", zip_path.display());
-
-    // Determine executable name based on platform
-    let exe_name = if cfg!(target_os = "windows") {
+    // The target being built, not the host running this script, decides the format
+    let target = env::var("TARGET").unwrap_or_else(|_| "unknown".to_string());
+    let format = packaging::Format::for_target_family(
+        &env::var("CARGO_CFG_TARGET_FAMILY").unwrap_or_default(),
+    );
+    let archive_path = target_dir.join(packaging::archive_name(
+        &package_name,
+        &version,
+        &target,
+        format,
+    ));
+
+    let exe_name = if env::var("CARGO_CFG_TARGET_OS").is_ok_and(|os| os == "windows") {
         format!("{}.exe", package_name)
     } else {
-        package_name.clone()
+        package_name
     };
-
     let exe_path = target_dir.join(&exe_name);
 
+    // The script runs before the executable is linked, so a clean release build has
+    // nothing to package yet; the next one packages it
     if !exe_path.exists() {
         println!(
-            "cargo:warning=Executable not found at: {}",
+            "cargo:warning=Executable not found at {}, skipping the release archive",
             exe_path.display()
         );
         return;
     }
 
-    // HTTP files source directory (project root)
-    let http_source = Path::new(manifest_dir).join("http");
-
-    if !http_source.exists() {
-        println!(
-            "cargo:warning=HTTP source directory not found at {}",
-            http_source.display()
-        );
-        return;
-    }
-
-    // Lua files source directory (project root)
-    let lua_source = Path::new(manifest_dir).join("lua");
-
-    if !lua_source.exists() {
-        println!(
-            "cargo:warning=Lua source directory not found at {}",
-            lua_source.display()
-        );
-        return;
-    }
-
-    // Try to use PowerShell's Compress-Archive if available (Windows)
-    if cfg!(target_os = "windows") {
-        let powershell_result = Command::new("powershell")
-            .args(&[
-                "-Command",
-                &format!(
-                    "Compress-Archive -Path '{}', '{}', '{}' -DestinationPath '{}' -Force",
-                    exe_path.display(),
-                    http_source.display(),
-                    lua_source.display(),
-                    zip_path.display()
-                ),
-            ])
-            .output();
-
-        if let Ok(output) = powershell_result {
-            if output.status.success() {
-                println!(
-                    "cargo:warning=Release zip created successfully: {}",
-                    zip_path.display()
-                );
-                return;
-            } else {
-                let error = String::from_utf8_lossy(&output.stderr);
-                println!("cargo:warning=PowerShell zip creation failed: {}", error);
-            }
-        }
-    }
-
-    // Fallback: try to use zip command if available
-    let zip_result = Command::new("zip")
-        .args(&[
-            "-r",
-            zip_path.to_str().unwrap(),
-            exe_name.as_str(),
-            http_source.to_str().unwrap(),
-            lua_source.to_str().unwrap(),
-        ])
-        .current_dir(target_dir)
-        .output();
-
-    if let Ok(output) = zip_result {
-        if output.status.success() {
-            println!(
-                "cargo:warning=Release zip created successfully: {}",
-                zip_path.display()
-            );
-        } else {
-            let error = String::from_utf8_lossy(&output.stderr);
-            println!("cargo:warning=Zip creation failed: {}", error);
-        }
-    } else {
-        println!(
-            "cargo:warning=No zip tool available. Please install zip or use PowerShell on Windows."
+    let packaged = packaging::release_entries(&exe_path, Path::new(manifest_dir), &["http", "lua"])
+        .and_then(|entries| packaging::write_archive(format, &entries, &archive_path));
+    if let Err(e) = packaged {
+        panic!(
+            "Creating release archive {} failed: {} (set FSR_SKIP_PACKAGE=1 to build without it)",
+            archive_path.display(),
+            e
         );
     }
+    println!(
+        "cargo:warning=Release archive created: {}",
+        archive_path.display()
+    );
 }
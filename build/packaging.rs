@@ -0,0 +1,248 @@
+// Release archives, built by build.rs. The crate includes this file under cfg(test) as
+// well, so the archive layout is covered by `cargo test`.
+use crate::zip_writer::zip_files;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Zip for Windows, tar.gz for unix targets, whose users expect one and whose tools keep
+// the executable bit of a tar
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Zip,
+    TarGz,
+}
+
+impl Format {
+    // From the target family cargo gives the build script (CARGO_CFG_TARGET_FAMILY)
+    pub fn for_target_family(family: &str) -> Self {
+        if family.split(',').any(|family| family == "unix") {
+            Format::TarGz
+        } else {
+            Format::Zip
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::TarGz => "tar.gz",
+        }
+    }
+}
+
+// `fsr-rs-0.2.0-x86_64-unknown-linux-gnu.tar.gz`: named by target, so the archives of
+// cross-compiled builds don't overwrite each other
+pub fn archive_name(package: &str, version: &str, target: &str, format: Format) -> String {
+    format!("{}-{}-{}.{}", package, version, target, format.extension())
+}
+
+// Unix permissions of the packaged files
+pub const EXECUTABLE_MODE: u32 = 0o755;
+pub const FILE_MODE: u32 = 0o644;
+
+// One file of an archive
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub source: PathBuf,
+    // Relative, '/'-separated
+    pub name: String,
+    pub mode: u32,
+}
+
+impl Entry {
+    pub fn file(source: impl Into<PathBuf>, name: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            name: name.into(),
+            mode: FILE_MODE,
+        }
+    }
+
+    pub fn executable(source: impl Into<PathBuf>, name: impl Into<String>) -> Self {
+        Self {
+            mode: EXECUTABLE_MODE,
+            ..Self::file(source, name)
+        }
+    }
+}
+
+// The files of a release: the executable at the top, then every file under the given
+// directories of the project, named by their path relative to it. Sorted, so the same
+// tree always gives the same archive.
+pub fn release_entries(exe: &Path, manifest_dir: &Path, dirs: &[&str]) -> io::Result<Vec<Entry>> {
+    let exe_name = exe
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "executable has no name"))?;
+    if !exe.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("executable not found at {}", exe.display()),
+        ));
+    }
+    let mut entries = vec![Entry::executable(exe, exe_name)];
+    for dir in dirs {
+        let mut files = Vec::new();
+        walk(&manifest_dir.join(dir), dir, &mut files)?;
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        entries.extend(files);
+    }
+    Ok(entries)
+}
+
+fn walk(dir: &Path, name: &str, files: &mut Vec<Entry>) -> io::Result<()> {
+    let listing = std::fs::read_dir(dir)
+        .map_err(|e| io::Error::new(e.kind(), format!("can't read {}: {}", dir.display(), e)))?;
+    for item in listing {
+        let item = item?;
+        let path = item.path();
+        let file_name = item.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} isn't valid UTF-8", path.display()),
+            ));
+        };
+        let child = format!("{}/{}", name, file_name);
+        if item.file_type()?.is_dir() {
+            walk(&path, &child, files)?;
+        } else {
+            files.push(Entry::file(path, child));
+        }
+    }
+    Ok(())
+}
+
+// Write the entries to an archive at `path`. The archive is written beside it and
+// renamed into place, so a failed build never leaves a truncated one behind.
+pub fn write_archive(format: Format, entries: &[Entry], path: &Path) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let result = File::create(&partial).and_then(|file| match format {
+        Format::Zip => write_zip(entries, file),
+        Format::TarGz => write_tar_gz(entries, file),
+    });
+    match result {
+        Ok(()) => std::fs::rename(&partial, path),
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+fn open_source(entry: &Entry) -> io::Result<File> {
+    File::open(&entry.source).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("can't read {}: {}", entry.source.display(), e),
+        )
+    })
+}
+
+fn write_zip(entries: &[Entry], file: File) -> io::Result<()> {
+    let files = entries
+        .iter()
+        .map(|entry| Ok((entry.name.as_str(), entry.mode, open_source(entry)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+    zip_files(file, files)?.flush()
+}
+
+fn write_tar_gz(entries: &[Entry], file: File) -> io::Result<()> {
+    let gz = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    let mut tar = tar::Builder::new(gz);
+    for entry in entries {
+        let source = open_source(entry)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(source.metadata()?.len());
+        header.set_mode(entry.mode);
+        // The zip dates its files 1980-01-01; a fixed time here as well keeps builds of the
+        // same tree identical
+        header.set_mtime(0);
+        tar.append_data(&mut header, &entry.name, source)?;
+    }
+    tar.into_inner()?.finish()?.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const EXPECTED: [(&str, u32, &str); 4] = [
+        ("fsr-rs", EXECUTABLE_MODE, "binary"),
+        ("http/img/pad.svg", FILE_MODE, "<svg>"),
+        ("http/index.html", FILE_MODE, "<html>"),
+        ("lua/globals.lua", FILE_MODE, "-- lua"),
+    ];
+
+    // A project tree with a built executable, in a fresh temp dir
+    fn release_tree(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fsr-rs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("http/img")).unwrap();
+        std::fs::create_dir_all(dir.join("lua")).unwrap();
+        std::fs::write(dir.join("fsr-rs"), b"binary").unwrap();
+        std::fs::write(dir.join("http/index.html"), b"<html>").unwrap();
+        std::fs::write(dir.join("http/img/pad.svg"), b"<svg>").unwrap();
+        std::fs::write(dir.join("lua/globals.lua"), b"-- lua").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_zip_has_release_layout() {
+        let dir = release_tree("packaging-zip");
+        let entries = release_entries(&dir.join("fsr-rs"), &dir, &["http", "lua"]).unwrap();
+        let zip_path = dir.join("release.zip");
+        write_archive(Format::Zip, &entries, &zip_path).unwrap();
+        assert!(!dir.join("release.zip.partial").exists());
+
+        let mut zip = zip::ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(zip.len(), EXPECTED.len());
+        for (name, mode, contents) in EXPECTED {
+            let mut file = zip.by_name(name).unwrap();
+            assert_eq!(file.unix_mode().map(|m| m & 0o777), Some(mode), "{}", name);
+            let mut read = String::new();
+            file.read_to_string(&mut read).unwrap();
+            assert_eq!(read, contents);
+        }
+
+        // A missing executable is an error, not an archive without it
+        assert!(release_entries(&dir.join("missing"), &dir, &["http"]).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_tar_gz_keeps_executable_bit() {
+        let dir = release_tree("packaging-tar");
+        let entries = release_entries(&dir.join("fsr-rs"), &dir, &["http", "lua"]).unwrap();
+        let name = archive_name("fsr-rs", "0.2.0", "x86_64-unknown-linux-gnu", Format::TarGz);
+        assert_eq!(name, "fsr-rs-0.2.0-x86_64-unknown-linux-gnu.tar.gz");
+        let tar_path = dir.join(&name);
+        write_archive(Format::TarGz, &entries, &tar_path).unwrap();
+
+        let gz = flate2::read::GzDecoder::new(File::open(&tar_path).unwrap());
+        let mut tar = tar::Archive::new(gz);
+        let mut found = Vec::new();
+        for file in tar.entries().unwrap() {
+            let mut file = file.unwrap();
+            let name = file.path().unwrap().to_string_lossy().into_owned();
+            let mode = file.header().mode().unwrap();
+            let mut read = String::new();
+            file.read_to_string(&mut read).unwrap();
+            found.push((name, mode, read));
+        }
+        let expected: Vec<_> = EXPECTED
+            .iter()
+            .map(|&(name, mode, contents)| (name.to_string(), mode, contents.to_string()))
+            .collect();
+        assert_eq!(found, expected);
+
+        assert_eq!(Format::for_target_family("unix"), Format::TarGz);
+        assert_eq!(Format::for_target_family("windows"), Format::Zip);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
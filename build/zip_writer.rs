@@ -0,0 +1,21 @@
+// Zip writing shared by build.rs, for the release archive, and the server, which zips the
+// Lua integration on request
+use std::io::{self, Read, Seek, Write};
+
+// Zip files given as name ('/'-separated), unix mode and contents
+pub fn zip_files<'a, W: Write + Seek, R: Read>(
+    writer: W,
+    files: impl IntoIterator<Item = (&'a str, u32, R)>,
+) -> io::Result<W> {
+    use zip::write::SimpleFileOptions;
+
+    let mut zip = zip::ZipWriter::new(writer);
+    for (name, mode, mut contents) in files {
+        let options = SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .unix_permissions(mode);
+        zip.start_file(name, options).map_err(io::Error::other)?;
+        io::copy(&mut contents, &mut zip)?;
+    }
+    zip.finish().map_err(io::Error::other)
+}
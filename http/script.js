@@ -19,7 +19,10 @@ function connectWebSocket() {
         addMessage('System', 'Creating new WebSocket connection...', 'error');
         // Use relative WebSocket URL to connect to the same server that serves this page
         const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
-        const wsUrl = `${protocol}//${window.location.host}/ws`;
+        // A token given to the page (index.html?token=...) is passed on to the server
+        const token = new URLSearchParams(window.location.search).get('token');
+        const query = token ? `?token=${encodeURIComponent(token)}` : '';
+        const wsUrl = `${protocol}//${window.location.host}/ws${query}`;
         ws = new WebSocket(wsUrl);
         setupWebSocketHandlers();
     } catch (error) {
@@ -99,8 +102,8 @@ function setupWebSocketHandlers() {
             document.getElementById('streamStatus').textContent = 'Stream stopped';
         }
 
-        // Handle active player broadcast
-        if (response.response_type === 'active_player_broadcast') {
+        // Handle profiles updates (sent whenever the profiles or active player change)
+        if (response.response_type === 'profiles_updated') {
             // Update the active player display without logging every broadcast
             updateActivePlayerDisplay(response.data);
         }
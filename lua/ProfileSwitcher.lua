@@ -1,6 +1,46 @@
 ---@type WebSocket?
 local ws = nil
 
+-- Game buttons of the panels of each layout, in the server's sensor order. A `custom`
+-- layout has no buttons to map to.
+---@type table<string, string[]>
+local PanelButtons = {
+	dance4 = { "Left", "Down", "Up", "Right" },
+	pump5 = { "DownLeft", "UpLeft", "Center", "UpRight", "DownRight" },
+	pad9 = { "UpLeft", "Up", "UpRight", "Left", "Center", "Right", "DownLeft", "Down", "DownRight" },
+}
+
+-- Layout of the current profile, as last sent by the server
+local layout = "dance4"
+
+---@param data table
+---@return nil
+local function updateLayout(data)
+	local profile = data.profiles and data.profiles[data.current_profile]
+	if profile then
+		-- The default layout is left out
+		layout = profile.layout or "dance4"
+	end
+end
+
+-- Broadcast the game buttons of the panels a `press` event has pressed
+---@param pressed boolean[]
+---@return nil
+local function broadcastPress(pressed)
+	local buttons = PanelButtons[layout]
+	-- A profile for another number of panels than the layout has
+	if not buttons or #buttons ~= #pressed then
+		return
+	end
+	local held = {}
+	for sensor, isPressed in ipairs(pressed) do
+		if isPressed then
+			held[#held + 1] = buttons[sensor]
+		end
+	end
+	MESSAGEMAN:Broadcast("FSRPanelsPressed", { Layout = layout, Buttons = held })
+end
+
 ---@param s string
 ---@return nil
 local function parseMessage(s)
@@ -10,7 +50,13 @@ local function parseMessage(s)
 
 	local data = JsonDecode(s)
 
-	if data.response_type == "active_player_broadcast" then
+	if data.data then
+		updateLayout(data.data)
+	end
+
+	if data.response_type == "press" and data.payload then
+		broadcastPress(data.payload.pressed)
+	elseif data.response_type == "active_player_broadcast" then
 		local activePlayer = data.data.current_player
 		local p1 = PROFILEMAN:GetPlayerName(PLAYER_1)
 		local command = {}
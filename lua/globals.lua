@@ -32,6 +32,11 @@ Def = {}
 ---@field [string] any
 
 
+---@class MessageManager
+---@field Broadcast fun(self: MessageManager, message: string, params?: table): nil
+MESSAGEMAN = {}
+
+
 ---@class ProfileManager
 ---@field GetPlayerName fun(self: ProfileManager, player: number): string
 PROFILEMAN = {}
@@ -0,0 +1,67 @@
+use crate::sensors::Sensors;
+use std::time::{Duration, Instant};
+
+// Polling rate of an adaptive stream while nobody is on the pad
+pub const IDLE_RATE_HZ: u32 = 5;
+
+// Smallest change of a sensor, in raw device units, that counts as activity; below it is
+// sensor noise
+pub const ACTIVITY_EPSILON: i32 = 8;
+
+pub const DEFAULT_STREAM_IDLE_SECS: u64 = 5;
+
+// Whether the pad is in use, for the adaptive stream. Readings are compared with the one
+// at the last change rather than the previous one, so a slow drift still counts once it
+// adds up.
+pub struct Activity {
+    idle_after: Duration,
+    reference: Option<Sensors<i32>>,
+    last_change: Instant,
+}
+
+impl Activity {
+    pub fn new(idle_after: Duration) -> Self {
+        Self {
+            idle_after,
+            reference: None,
+            last_change: Instant::now(),
+        }
+    }
+
+    // Record a reading; true once no sensor has moved for the idle period
+    pub fn observe(&mut self, values: Sensors<i32>, now: Instant) -> bool {
+        let changed = self.reference.is_none_or(|reference| {
+            reference
+                .iter()
+                .zip(values)
+                .any(|(&before, value)| (value - before).abs() > ACTIVITY_EPSILON)
+        });
+        if changed {
+            self.reference = Some(values);
+            self.last_change = now;
+        }
+        now.duration_since(self.last_change) >= self.idle_after
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_after_quiet_period() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut activity = Activity::new(Duration::from_secs(5));
+        assert!(!activity.observe([100; 4].into(), at(0)));
+        // Noise doesn't keep the pad awake
+        assert!(!activity.observe([104, 96, 100, 108].into(), at(4000)));
+        assert!(activity.observe([100; 4].into(), at(5000)));
+        // The first real change wakes it at once
+        assert!(!activity.observe([100, 100, 600, 100].into(), at(5200)));
+        assert!(!activity.observe([100, 100, 600, 100].into(), at(10100)));
+        // Drift adds up against the last change
+        assert!(activity.observe([100, 100, 605, 100].into(), at(10200)));
+        assert!(!activity.observe([100, 100, 609, 100].into(), at(10300)));
+    }
+}
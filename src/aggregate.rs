@@ -0,0 +1,155 @@
+use crate::event::{Event, WireJson};
+use crate::sensors::Sensors;
+use crate::state::AppState;
+use crate::supervisor::{supervise, Backoff};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+// A pad's frame older than this many stream periods is flagged stale
+pub const STALE_PERIODS: u32 = 3;
+
+// Latest frame of one pad in an aggregate_stream message
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PadReading {
+    pub pad: String,
+    // None until the pad sends a frame after the stream started
+    pub values: Option<Sensors<i32>>,
+    pub age_ms: Option<u64>,
+    pub stale: bool,
+}
+
+// Spawn the supervised aggregator
+pub fn spawn(state: &AppState) -> JoinHandle<()> {
+    let state = state.clone();
+    tokio::spawn(supervise(
+        "aggregate_stream",
+        state.clone(),
+        Backoff::default(),
+        move || aggregate_task(state.clone()),
+    ))
+}
+
+// Pairs the latest sensor frame of every pad into one aggregate_stream event per stream
+// tick, for overlays that show all pads side by side. A pad that stopped sending doesn't
+// hold the others up: its last values go out flagged stale, or null if it never sent any.
+pub async fn aggregate_task(state: AppState) {
+    let mut changes = state.stream.subscribe();
+    let mut frames = state.events.subscribe();
+
+    'config: loop {
+        let config = *changes.borrow_and_update();
+        if !config.enabled {
+            if changes.changed().await.is_err() {
+                return;
+            }
+            continue;
+        }
+
+        // Frames from before a restart of the stream don't count
+        let mut latest: HashMap<Arc<str>, (Sensors<i32>, Instant)> = HashMap::new();
+        let stale_after = config.period() * STALE_PERIODS;
+        let mut ticks = interval(config.period());
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                changed = changes.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    continue 'config;
+                }
+                event = frames.recv() => match event {
+                    Ok(Event::SensorFrame { pad, values, .. }) => {
+                        latest.insert(pad, (values, Instant::now()));
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                _ = ticks.tick() => {
+                    let pads = state.profiles.borrow().pads.clone();
+                    let readings = pads
+                        .into_iter()
+                        .map(|pad| match latest.get(pad.id.as_str()) {
+                            Some((values, at)) => PadReading {
+                                pad: pad.id,
+                                values: Some(*values),
+                                age_ms: Some(at.elapsed().as_millis() as u64),
+                                stale: at.elapsed() > stale_after,
+                            },
+                            None => PadReading {
+                                pad: pad.id,
+                                values: None,
+                                age_ms: None,
+                                stale: true,
+                            },
+                        })
+                        .collect::<Vec<_>>();
+                    let _ = state.events.send(Event::AggregateFrame(readings.into(), WireJson::default()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{Pad, Profiles};
+    use std::time::Duration;
+
+    // Next aggregate in which the first pad has values
+    async fn next_aggregate(events: &mut broadcast::Receiver<Event>) -> Arc<[PadReading]> {
+        loop {
+            if let Ok(Event::AggregateFrame(pads, _)) = events.recv().await {
+                if pads[0].values.is_some() {
+                    return pads;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_pairs_pads_and_flags_missing() {
+        let mut profiles = Profiles::default();
+        profiles.pads.push(Pad {
+            id: "p2".to_string(),
+            ..Pad::default_pad(String::new(), String::new())
+        });
+        let state = AppState::with_mock_port(profiles);
+        let mut events = state.events.subscribe();
+        state.set_stream_enabled(true);
+        let task = tokio::spawn(aggregate_task(state.clone()));
+        tokio::task::yield_now().await;
+
+        let _ = state.events.send(Event::SensorFrame {
+            pad: "default".into(),
+            values: [1, 2, 3, 4].into(),
+            calibrated: None,
+            rate_hz: 60,
+            wire: WireJson::default(),
+        });
+        let pads = tokio::time::timeout(Duration::from_secs(1), next_aggregate(&mut events))
+            .await
+            .unwrap();
+        assert_eq!(pads.len(), 2);
+        assert_eq!(pads[0].values, Some([1, 2, 3, 4].into()));
+        assert!(!pads[0].stale);
+        assert_eq!(pads[1].values, None);
+        assert!(pads[1].stale);
+
+        // The other pad missing doesn't stop the frames; the old one is flagged stale
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut events = events.resubscribe();
+        let pads = tokio::time::timeout(Duration::from_secs(1), next_aggregate(&mut events))
+            .await
+            .unwrap();
+        assert!(pads[0].stale);
+        assert!(pads[0].age_ms.unwrap() >= 100);
+
+        task.abort();
+    }
+}
@@ -0,0 +1,45 @@
+// Counting allocator for the test build, used by the allocation benchmarks. Counts are
+// per thread so tests running in parallel don't disturb each other.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    static BYTES: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(layout);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(Layout::from_size_align_unchecked(new_size, layout.align()));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn record(layout: Layout) {
+    // try_with: the thread locals may already be gone while a thread shuts down
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+    let _ = BYTES.try_with(|bytes| bytes.set(bytes.get() + layout.size() as u64));
+}
+
+// (allocations, bytes) made by the current thread while running `f`
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, u64, u64) {
+    let start = (ALLOCATIONS.get(), BYTES.get());
+    let result = f();
+    (result, ALLOCATIONS.get() - start.0, BYTES.get() - start.1)
+}
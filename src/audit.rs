@@ -0,0 +1,246 @@
+use crate::journal;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+// Mutating commands kept in memory for GetCommandHistory and `/api/history`
+pub const AUDIT_CAPACITY: usize = 500;
+
+// Size at which the history file is rotated to `<file>.1`, like the journal
+pub const AUDIT_MAX_BYTES: u64 = 1024 * 1024;
+
+// Entries returned by GetCommandHistory when no limit is given
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+// Entries waiting for the file writer; beyond this they are only kept in memory
+const QUEUE_CAPACITY: usize = 256;
+
+// Parameter names whose values never reach the history
+const SENSITIVE_KEYS: [&str; 5] = ["token", "password", "secret", "api_key", "authorization"];
+
+const REDACTED: &str = "[redacted]";
+
+// Where a command came from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "transport", rename_all = "snake_case")]
+pub enum Origin {
+    // A websocket connection, by its id in GetClients
+    Websocket {
+        connection: u64,
+    },
+    // A line of `fsr-rs pipe`
+    Pipe,
+    // A request to the HTTP API
+    Http,
+    // A profile switch of AddSchedule
+    Schedule,
+    // The server itself, or the one-shot CLI
+    #[default]
+    Internal,
+}
+
+// One mutating command as executed. Dry runs and idempotent replays change nothing, so
+// they aren't recorded.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub command: String,
+    // The command's fields, with sensitive values redacted; null for a command without
+    pub params: serde_json::Value,
+    #[serde(flatten)]
+    pub origin: Origin,
+    pub success: bool,
+    // message_code of the response, or the error code of a failure
+    pub code: String,
+}
+
+// Bounded history of the mutating commands, for finding out what changed the thresholds
+// overnight. Optionally also appended to a size-capped JSONL file, which survives a
+// restart; recording never blocks on it.
+pub struct AuditLog {
+    entries: Mutex<VecDeque<AuditEntry>>,
+    capacity: usize,
+    tx: Option<mpsc::Sender<AuditEntry>>,
+    dropped: AtomicU64,
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            tx: None,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // A history that is also written to `path`
+    pub fn with_file(capacity: usize, path: PathBuf, max_bytes: u64) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(writer_task(path, max_bytes, rx));
+        Self {
+            tx: Some(tx),
+            ..Self::new(capacity)
+        }
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        if let Some(tx) = &self.tx {
+            if tx.try_send(entry.clone()).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    // The last `limit` entries whose command name contains `filter` (case-insensitive),
+    // oldest first
+    pub fn tail(&self, limit: usize, filter: Option<&str>) -> Vec<AuditEntry> {
+        let Ok(entries) = self.entries.lock() else {
+            return Vec::new();
+        };
+        let filter = filter.map(str::to_lowercase);
+        let mut found: Vec<AuditEntry> = entries
+            .iter()
+            .rev()
+            .filter(|entry| {
+                filter
+                    .as_deref()
+                    .is_none_or(|filter| entry.command.to_lowercase().contains(filter))
+            })
+            .take(limit)
+            .cloned()
+            .collect();
+        found.reverse();
+        found
+    }
+
+    // Entries not written to the file because the writer was behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new(AUDIT_CAPACITY)
+    }
+}
+
+// The fields of a serialized command, `{"UpdateThreshold": {...}}` or `"SaveProfiles"`,
+// ready to be stored
+pub fn params(command: serde_json::Value) -> serde_json::Value {
+    let mut params = match command {
+        serde_json::Value::Object(fields) if fields.len() == 1 => {
+            fields.into_iter().next().map(|(_, params)| params)
+        }
+        _ => None,
+    }
+    .unwrap_or_default();
+    redact(&mut params);
+    params
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields {
+                let key = key.to_lowercase();
+                if SENSITIVE_KEYS
+                    .iter()
+                    .any(|sensitive| key.contains(sensitive))
+                {
+                    *value = REDACTED.into();
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+async fn writer_task(path: PathBuf, max_bytes: u64, mut rx: mpsc::Receiver<AuditEntry>) {
+    while let Some(entry) = rx.recv().await {
+        if let Err(e) = journal::append(&path, max_bytes, &entry).await {
+            eprintln!("Failed to write command history {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(command: &str) -> AuditEntry {
+        AuditEntry {
+            timestamp: Utc::now(),
+            command: command.to_string(),
+            params: serde_json::Value::Null,
+            origin: Origin::Websocket { connection: 3 },
+            success: true,
+            code: "OK".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_history_is_bounded_and_filtered() {
+        let log = AuditLog::new(3);
+        for command in [
+            "AddProfile",
+            "UpdateThreshold",
+            "ChangeProfile",
+            "UpdateThreshold",
+        ] {
+            log.record(entry(command));
+        }
+        let names = |entries: Vec<AuditEntry>| -> Vec<String> {
+            entries.into_iter().map(|entry| entry.command).collect()
+        };
+        // The oldest fell out
+        assert_eq!(
+            names(log.tail(10, None)),
+            ["UpdateThreshold", "ChangeProfile", "UpdateThreshold"]
+        );
+        assert_eq!(names(log.tail(1, None)), ["UpdateThreshold"]);
+        assert_eq!(log.tail(10, Some("threshold")).len(), 2);
+
+        let serialized = serde_json::to_value(entry("SaveProfiles")).unwrap();
+        assert_eq!(serialized["transport"], "websocket");
+        assert_eq!(serialized["connection"], 3);
+        let parsed: AuditEntry = serde_json::from_value(serialized).unwrap();
+        assert_eq!(parsed.origin, Origin::Websocket { connection: 3 });
+    }
+
+    #[test]
+    fn test_params_are_redacted() {
+        let command = json!({
+            "AddWebhook": {
+                "url": "http://example",
+                "auth_token": "hunter2",
+                "headers": [{"Authorization": "Bearer x", "accept": "json"}],
+            }
+        });
+        assert_eq!(
+            params(command),
+            json!({
+                "url": "http://example",
+                "auth_token": REDACTED,
+                "headers": [{"Authorization": REDACTED, "accept": "json"}],
+            })
+        );
+        assert_eq!(params(json!("SaveProfiles")), serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::error::AppError;
+use crate::profile::Command;
+use crate::state::AppState;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// What a client may do; every role may do what the ones below it may
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // Reads and subscriptions, e.g. a stream overlay
+    Viewer,
+    // Tuning: thresholds, players, profiles, the stream and calibration
+    Operator,
+    // Everything, including removing profiles, the pads' wiring and serial ports
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!(
+                "unknown role '{}', expected viewer, operator or admin",
+                other
+            )),
+        }
+    }
+}
+
+// A token and the role it grants, `--token <role>:<token>`
+#[derive(Clone, PartialEq, Eq)]
+pub struct RoleToken {
+    pub role: Role,
+    token: String,
+}
+
+impl RoleToken {
+    // In the `<role>:<token>` form it was given in
+    pub fn to_arg(&self) -> String {
+        format!("{}:{}", self.role, self.token)
+    }
+}
+
+// Keeps tokens out of logs and error messages
+impl fmt::Debug for RoleToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RoleToken({}, <redacted>)", self.role)
+    }
+}
+
+pub fn parse_token_arg(arg: &str) -> Result<RoleToken, String> {
+    let (role, token) = arg
+        .split_once(':')
+        .ok_or_else(|| "expected <role>:<token>, e.g. viewer:abc".to_string())?;
+    let role = role.parse()?;
+    if token.is_empty() {
+        return Err(format!("the {} token is empty", role));
+    }
+    Ok(RoleToken {
+        role,
+        token: token.to_string(),
+    })
+}
+
+// The configured tokens. Without any, every client is admin, as before roles existed.
+#[derive(Debug, Default)]
+pub struct Auth {
+    tokens: Vec<RoleToken>,
+}
+
+impl Auth {
+    // A token given twice would grant whichever role came first
+    pub fn new(tokens: Vec<RoleToken>) -> Result<Self, String> {
+        for (i, token) in tokens.iter().enumerate() {
+            if let Some(other) = tokens[..i].iter().find(|other| other.token == token.token) {
+                return Err(format!(
+                    "the same token is given for roles {} and {}",
+                    other.role, token.role
+                ));
+            }
+        }
+        Ok(Self { tokens })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    // Role of a client presenting `token`; Err(UNAUTHORIZED) if it matches none
+    pub fn resolve(&self, token: Option<&str>) -> Result<Role, AppError> {
+        if !self.is_enabled() {
+            return Ok(Role::Admin);
+        }
+        let token = token.ok_or(AppError::Unauthorized)?;
+        // Every token is compared in full, so the time taken doesn't tell how close it was
+        self.tokens
+            .iter()
+            .fold(None, |found, candidate| {
+                let matches = constant_time_eq(candidate.token.as_bytes(), token.as_bytes());
+                found.or(matches.then_some(candidate.role))
+            })
+            .ok_or(AppError::Unauthorized)
+    }
+
+    // Whether `role` may still make changes in soft read-only mode
+    pub fn authorized(&self, role: Role) -> bool {
+        self.is_enabled() && role >= Role::Operator
+    }
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Err(FORBIDDEN) unless `role` may send `command`
+pub fn check(command: &Command, role: Role) -> Result<(), AppError> {
+    let required = command.required_role();
+    if role >= required {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden {
+            command: command.name(),
+            required,
+            role,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+// `Authorization: Bearer <token>`, or `?token=<token>` for browsers, which can't set
+// headers on a websocket
+fn request_token(parts: &Parts) -> Option<String> {
+    bearer_token(&parts.headers).or_else(|| {
+        Query::<TokenQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|query| query.0.token)
+    })
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim().to_string())
+}
+
+// Role of the client making a request; rejects the request with 401 if tokens are
+// configured and it has no valid one
+pub struct ClientRole(pub Role);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for ClientRole {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let token = request_token(parts);
+        state.auth.resolve(token.as_deref()).map(ClientRole)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth::new(vec![
+            parse_token_arg("viewer:abc").unwrap(),
+            parse_token_arg("Admin:x:y").unwrap(),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tokens_resolve_to_roles() {
+        let auth = auth();
+        assert_eq!(auth.resolve(Some("abc")).unwrap(), Role::Viewer);
+        // Only the first colon separates the role
+        assert_eq!(auth.resolve(Some("x:y")).unwrap(), Role::Admin);
+        assert_eq!(auth.resolve(Some("ab")).unwrap_err().code(), "UNAUTHORIZED");
+        assert_eq!(auth.resolve(None).unwrap_err().code(), "UNAUTHORIZED");
+        assert!(!auth.authorized(Role::Viewer));
+        assert!(auth.authorized(Role::Admin));
+
+        // Without tokens nothing changes for existing setups
+        assert_eq!(Auth::default().resolve(None).unwrap(), Role::Admin);
+        assert!(!Auth::default().authorized(Role::Admin));
+
+        assert!(parse_token_arg("abc").is_err());
+        assert!(parse_token_arg("root:abc").is_err());
+        assert!(parse_token_arg("viewer:").is_err());
+        assert!(Auth::new(vec![
+            parse_token_arg("viewer:abc").unwrap(),
+            parse_token_arg("admin:abc").unwrap(),
+        ])
+        .is_err());
+        assert!(!format!("{:?}", auth.tokens).contains("abc"));
+    }
+
+    #[test]
+    fn test_commands_need_their_role() {
+        let remove = Command::RemoveProfile {
+            name: "P".to_string(),
+            confirm: false,
+            reassign_to: None,
+        };
+        assert!(check(&remove, Role::Admin).is_ok());
+        let error = check(&remove, Role::Operator).unwrap_err();
+        assert_eq!(error.code(), "FORBIDDEN");
+        assert_eq!(
+            error.to_string(),
+            "RemoveProfile needs the admin role, this client is operator"
+        );
+        assert!(check(&Command::StartSensorStream, Role::Operator).is_ok());
+        assert!(check(&Command::StartSensorStream, Role::Viewer).is_err());
+        assert!(check(&Command::GetProfiles, Role::Viewer).is_ok());
+    }
+}
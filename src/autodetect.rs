@@ -0,0 +1,177 @@
+use crate::config::Args;
+use crate::serial::{open_serial_port, read_sensor_values};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// --com-port value that asks for the pad to be found
+pub const AUTO_PORT: &str = "auto";
+
+// Port tried on Windows when no port answers, the default before auto-detection
+#[cfg(windows)]
+pub const FALLBACK_PORT: &str = "COM6";
+
+#[cfg(windows)]
+pub const COM_PORT_HELP: &str =
+    "Serial port of the pad, e.g. COM6; \"auto\" probes the COM ports and falls back to COM6";
+#[cfg(target_os = "macos")]
+pub const COM_PORT_HELP: &str = "Serial port of the pad, e.g. /dev/cu.usbmodem1101; \"auto\" probes /dev/cu.usbmodem*, /dev/ttyACM* and /dev/ttyUSB*";
+#[cfg(not(any(windows, target_os = "macos")))]
+pub const COM_PORT_HELP: &str =
+    "Serial port of the pad, e.g. /dev/ttyACM0; \"auto\" probes /dev/ttyACM* and /dev/ttyUSB*";
+
+// Names of the ports auto-detection probes, in the order they are tried
+#[cfg(windows)]
+const PORT_PREFIXES: &[&str] = &["COM"];
+#[cfg(target_os = "macos")]
+const PORT_PREFIXES: &[&str] = &["/dev/cu.usbmodem", "/dev/ttyACM", "/dev/ttyUSB"];
+#[cfg(not(any(windows, target_os = "macos")))]
+const PORT_PREFIXES: &[&str] = &["/dev/ttyACM", "/dev/ttyUSB"];
+
+// How long a port gets to answer a sensor read. Boards that reset when the port opens
+// answer once their firmware is up, so reads are retried until then.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+// Replace --com-port auto by the port the pad answers on. On Windows a failed detection
+// falls back to COM6; elsewhere it fails with the ports that were examined.
+pub async fn resolve_port(args: &mut Args) -> Result<(), String> {
+    if args.mock_serial || args.com_port != AUTO_PORT {
+        return Ok(());
+    }
+    match detect().await {
+        Ok(port) => {
+            println!("Pad detected on {}", port);
+            args.com_port = port;
+            Ok(())
+        }
+        #[cfg(windows)]
+        Err(e) => {
+            eprintln!("Warning: {}, trying {}", e, FALLBACK_PORT);
+            args.com_port = FALLBACK_PORT.to_string();
+            Ok(())
+        }
+        #[cfg(not(windows))]
+        Err(e) => Err(e),
+    }
+}
+
+// The first candidate port that answers a sensor read
+pub async fn detect() -> Result<String, String> {
+    let examined = candidates(present_ports(), PORT_PREFIXES);
+    for port in &examined {
+        if probe(port).await {
+            return Ok(port.clone());
+        }
+    }
+    Err(not_found(&examined, PORT_PREFIXES))
+}
+
+fn not_found(examined: &[String], prefixes: &[&str]) -> String {
+    if examined.is_empty() {
+        let patterns: Vec<String> = prefixes
+            .iter()
+            .map(|prefix| format!("{}*", prefix))
+            .collect();
+        format!(
+            "No pad found: no serial port matches {}",
+            patterns.join(", ")
+        )
+    } else {
+        format!("No pad found: no answer on {}", examined.join(", "))
+    }
+}
+
+// The names with one of the prefixes, in prefix order and then by number, so ttyACM2
+// comes before ttyACM10
+fn candidates(names: impl IntoIterator<Item = String>, prefixes: &[&str]) -> Vec<String> {
+    let mut found: Vec<(usize, u64, String)> = names
+        .into_iter()
+        .filter_map(|name| {
+            let rank = prefixes
+                .iter()
+                .position(|prefix| name.starts_with(prefix))?;
+            let number = name[prefixes[rank].len()..].parse().unwrap_or(u64::MAX);
+            Some((rank, number, name))
+        })
+        .collect();
+    found.sort();
+    found.into_iter().map(|(_, _, name)| name).collect()
+}
+
+// Device nodes are listed directly: every one of them is a candidate, whether or not
+// the serial port enumeration knows about it
+#[cfg(unix)]
+fn present_ports() -> Vec<String> {
+    std::fs::read_dir("/dev")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .map(|name| format!("/dev/{}", name))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(windows)]
+fn present_ports() -> Vec<String> {
+    serialport::available_ports()
+        .map(|ports| ports.into_iter().map(|port| port.port_name).collect())
+        .unwrap_or_default()
+}
+
+// Whether the device on the port answers like a pad
+async fn probe(name: &str) -> bool {
+    let opened = {
+        let name = name.to_string();
+        tokio::task::spawn_blocking(move || open_serial_port(&name, None)).await
+    };
+    let Ok(Ok(port)) = opened else {
+        return false;
+    };
+    let port = Arc::new(Mutex::new(port));
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    while Instant::now() < deadline {
+        if read_sensor_values(&port).await.is_ok() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates_in_probe_order() {
+        let names = [
+            "/dev/ttyUSB0",
+            "/dev/ttyACM10",
+            "/dev/tty0",
+            "/dev/ttyACM2",
+            "/dev/cu.usbmodem1101",
+            "/dev/null",
+        ];
+        let prefixes = ["/dev/cu.usbmodem", "/dev/ttyACM", "/dev/ttyUSB"];
+        let found = candidates(names.map(String::from), &prefixes);
+        assert_eq!(
+            found,
+            [
+                "/dev/cu.usbmodem1101",
+                "/dev/ttyACM2",
+                "/dev/ttyACM10",
+                "/dev/ttyUSB0"
+            ]
+        );
+
+        assert_eq!(
+            not_found(&found[1..3], &prefixes),
+            "No pad found: no answer on /dev/ttyACM2, /dev/ttyACM10"
+        );
+        assert_eq!(
+            not_found(&[], &prefixes[1..]),
+            "No pad found: no serial port matches /dev/ttyACM*, /dev/ttyUSB*"
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use crate::error::ValidationError;
+use crate::event::Event;
+use crate::profile::Profile;
+use crate::sensors::Sensors;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::{timeout_at, Instant};
+
+// How long CalibrateGain watches the stream unless told otherwise
+pub const CALIBRATION_WINDOW: Duration = Duration::from_secs(10);
+
+pub const MAX_CALIBRATION_WINDOW: Duration = Duration::from_secs(60);
+
+// Gains are positive and finite, anything else would flip or blank out the values
+pub fn check_gain(gain: &Sensors<f32>) -> Result<(), ValidationError> {
+    match gain
+        .iter()
+        .position(|gain| !gain.is_finite() || *gain <= 0.0)
+    {
+        Some(sensor) => Err(ValidationError::InvalidGain {
+            sensor,
+            gain: gain[sensor],
+        }),
+        None => Ok(()),
+    }
+}
+
+// Highest raw value of every sensor of `pad` in the frames streamed during `window`,
+// none without a frame
+pub async fn peaks(
+    events: &mut broadcast::Receiver<Event>,
+    pad: &str,
+    window: Duration,
+) -> Sensors<i32> {
+    let deadline = Instant::now() + window;
+    let mut peaks = Sensors::default();
+    loop {
+        match timeout_at(deadline, events.recv()).await {
+            Ok(Ok(Event::SensorFrame {
+                pad: frame_pad,
+                values,
+                ..
+            })) if *frame_pad == *pad => {
+                if peaks.len() != values.len() {
+                    peaks = Sensors::filled(values.len(), i32::MIN);
+                }
+                for (peak, value) in peaks.iter_mut().zip(values) {
+                    *peak = (*peak).max(value);
+                }
+            }
+            Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => {}
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => return peaks,
+        }
+    }
+}
+
+// Gains that bring every sensor's peak above the profile's offset to the average of
+// them, so the same weight reads the same on every panel. A sensor that never got past
+// its threshold wasn't pressed and fails the calibration rather than getting a wild gain.
+pub fn derive_gain(
+    profile: &Profile,
+    peaks: Sensors<i32>,
+) -> Result<Sensors<f32>, ValidationError> {
+    let sensors = profile.thresholds.len();
+    let peak = |i| peaks.get(i).copied().unwrap_or(i32::MIN);
+    let offset = |i| {
+        profile
+            .offset
+            .and_then(|offset| offset.get(i).copied())
+            .unwrap_or(0)
+    };
+    let missed: Vec<usize> = (0..sensors)
+        .filter(|&i| peak(i) < profile.thresholds[i] || peak(i) <= offset(i))
+        .collect();
+    if !missed.is_empty() {
+        return Err(ValidationError::CalibrationIncomplete(missed));
+    }
+    let spans = Sensors::from_fn(sensors, |i| (peak(i) - offset(i)) as f32);
+    let target = spans.iter().sum::<f32>() / sensors as f32;
+    Ok(spans.map(|span| target / span))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::handle_command;
+    use crate::event::WireJson;
+    use crate::profile::{Command, Pad, Profiles};
+    use crate::state::AppState;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_calibrate_and_derive_gain() {
+        let mut profile = Profile::new([100, 100, 100, 100]);
+        assert_eq!(profile.calibrate([10, 20, 30, 40].into()), None);
+
+        profile.offset = Some([10, 0, 0, 0].into());
+        profile.gain = Some([2.0, 0.5, 1.0, 4.0].into());
+        assert_eq!(
+            profile.calibrate([5, 20, 31, 400].into()),
+            Some([0, 10, 31, 1023].into())
+        );
+
+        let gain = derive_gain(&profile, [410, 200, 800, 400].into()).unwrap();
+        assert_eq!(gain, [1.125, 2.25, 0.5625, 1.125]);
+        assert_eq!(
+            derive_gain(&profile, [410, 50, 800, 0].into()),
+            Err(ValidationError::CalibrationIncomplete(vec![1, 3]))
+        );
+        assert!(check_gain(&[1.0, 0.0, 1.0, 1.0].into()).is_err());
+        assert!(check_gain(&[1.0, 1.0, f32::NAN, 1.0].into()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_calibrate_gain_command() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([100; 4]))]),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        let state = AppState::with_mock_port(profiles);
+        let calibrate = || Command::CalibrateGain {
+            profile_name: "Profile1".to_string(),
+            duration_ms: Some(200),
+        };
+
+        let response = handle_command(calibrate(), &state).await;
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "STREAM_STOPPED" }))
+        );
+
+        state.set_stream_enabled(true);
+        let events = state.events.clone();
+        let presses = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            for values in [[200, 0, 0, 0], [0, 400, 0, 0], [0, 0, 800, 400]] {
+                let _ = events.send(Event::SensorFrame {
+                    pad: "default".into(),
+                    values: values.into(),
+                    calibrated: None,
+                    rate_hz: 60,
+                    wire: WireJson::default(),
+                });
+            }
+        });
+        let response = handle_command(calibrate(), &state).await;
+        presses.await.unwrap();
+        assert!(response.success, "{}", response.message);
+        let profile = &state.profiles_snapshot().profiles["Profile1"];
+        assert_eq!(profile.gain, Some([2.25, 1.125, 0.5625, 1.125].into()));
+        assert_eq!(profile.thresholds, [100; 4]);
+    }
+}
@@ -0,0 +1,480 @@
+use crate::error::CaptureError;
+use crate::profile::PROFILES_FILE;
+use crate::serial_trace::{capture_header, TraceSink};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Directory for captures started at runtime, next to profiles.json
+pub const CAPTURE_DIR: &str = "captures";
+
+// A capture stops recording once its file reaches this size
+pub const CAPTURE_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+// Trace lines waiting for the writer; more are dropped rather than stalling the port
+const QUEUE_CAPACITY: usize = 1024;
+
+// Longest client supplied suffix kept in a capture file name
+const HINT_MAX_LEN: usize = 32;
+
+pub fn default_capture_dir() -> PathBuf {
+    Path::new(PROFILES_FILE)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(CAPTURE_DIR)
+}
+
+// State of the runtime capture, as reported by the capture commands and `/health`
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct CaptureStatus {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    pub bytes_written: u64,
+    pub dropped_lines: u64,
+    pub limit_reached: bool,
+}
+
+// Counters of one capture, shared with its writer
+struct CaptureStats {
+    path: PathBuf,
+    started_at: DateTime<Utc>,
+    bytes_written: AtomicU64,
+    dropped_lines: AtomicU64,
+    limit_reached: AtomicBool,
+}
+
+impl CaptureStats {
+    fn status(&self, active: bool) -> CaptureStatus {
+        CaptureStatus {
+            active,
+            path: Some(self.path.clone()),
+            started_at: Some(self.started_at),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            dropped_lines: self.dropped_lines.load(Ordering::Relaxed),
+            limit_reached: self.limit_reached.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct ActiveCapture {
+    stats: Arc<CaptureStats>,
+    writer: JoinHandle<()>,
+}
+
+// Records the traffic of the serial port to a file on request. The port's TracingPort
+// hands each trace line to a bounded queue; a blocking task does the file I/O, so a slow
+// disk costs dropped lines instead of serial latency.
+pub struct SerialCapture {
+    sink: TraceSink,
+    dir: PathBuf,
+    max_bytes: u64,
+    active: Mutex<Option<ActiveCapture>>,
+}
+
+impl SerialCapture {
+    pub fn new(sink: TraceSink, dir: PathBuf, max_bytes: u64) -> Self {
+        Self {
+            sink,
+            dir,
+            max_bytes,
+            active: Mutex::new(None),
+        }
+    }
+
+    pub fn status(&self) -> CaptureStatus {
+        match &*self.active.lock().unwrap() {
+            Some(capture) => capture.stats.status(true),
+            None => CaptureStatus::default(),
+        }
+    }
+
+    pub fn start(&self, path_hint: Option<&str>) -> Result<CaptureStatus, CaptureError> {
+        let mut active = self.active.lock().unwrap();
+        if let Some(capture) = &*active {
+            return Err(CaptureError::AlreadyRunning(
+                capture.stats.path.display().to_string(),
+            ));
+        }
+
+        let started_at = Utc::now();
+        let path = self.dir.join(capture_file_name(started_at, path_hint));
+        std::fs::create_dir_all(&self.dir).map_err(CaptureError::Create)?;
+        let mut file = File::create_new(&path).map_err(CaptureError::Create)?;
+        let header = capture_header();
+        file.write_all(header.as_bytes())
+            .map_err(CaptureError::Create)?;
+
+        let stats = Arc::new(CaptureStats {
+            path,
+            started_at,
+            bytes_written: AtomicU64::new(header.len() as u64),
+            dropped_lines: AtomicU64::new(0),
+            limit_reached: AtomicBool::new(false),
+        });
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let writer = tokio::task::spawn_blocking({
+            let stats = stats.clone();
+            let max_bytes = self.max_bytes;
+            move || {
+                if let Err(e) = write_capture(file, rx, &stats, max_bytes) {
+                    eprintln!("Serial capture {} failed: {}", stats.path.display(), e);
+                }
+            }
+        });
+        // Only the capture adds writers after the port is opened, so it is always last
+        self.sink.lock().unwrap().push(Box::new(CaptureWriter {
+            tx,
+            stats: stats.clone(),
+        }));
+
+        let status = stats.status(true);
+        *active = Some(ActiveCapture { stats, writer });
+        Ok(status)
+    }
+
+    // Detach the capture from the port and wait for everything queued to reach the file
+    pub async fn stop(&self) -> Result<CaptureStatus, CaptureError> {
+        let capture = {
+            let mut active = self.active.lock().unwrap();
+            let capture = active.take().ok_or(CaptureError::NotRunning)?;
+            self.sink.lock().unwrap().pop();
+            capture
+        };
+        let _ = capture.writer.await;
+        Ok(capture.stats.status(false))
+    }
+}
+
+// `serial-20261016-183005-123-<hint>.log`; the hint is reduced to a safe file name part
+fn capture_file_name(started_at: DateTime<Utc>, path_hint: Option<&str>) -> String {
+    let hint: String = path_hint
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .take(HINT_MAX_LEN)
+        .collect();
+    let hint = hint.trim_matches('-');
+    let stamp = started_at.format("%Y%m%d-%H%M%S-%3f");
+    if hint.is_empty() {
+        format!("serial-{}.log", stamp)
+    } else {
+        format!("serial-{}-{}.log", stamp, hint)
+    }
+}
+
+// Sink writer on the serial path: queues the line and never blocks
+struct CaptureWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+    stats: Arc<CaptureStats>,
+}
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(buf.to_vec()) {
+            self.stats.dropped_lines.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Runs until the CaptureWriter is dropped and the queue is drained
+fn write_capture(
+    file: File,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    stats: &CaptureStats,
+    max_bytes: u64,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(file);
+    while let Some(line) = rx.blocking_recv() {
+        if stats.limit_reached.load(Ordering::Relaxed) {
+            stats.dropped_lines.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let written = stats.bytes_written.load(Ordering::Relaxed);
+        if written + line.len() as u64 > max_bytes {
+            writeln!(
+                out,
+                "# size limit of {} bytes reached, later traffic was not recorded",
+                max_bytes
+            )?;
+            out.flush()?;
+            stats.limit_reached.store(true, Ordering::Relaxed);
+            stats.dropped_lines.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        out.write_all(&line)?;
+        stats
+            .bytes_written
+            .fetch_add(line.len() as u64, Ordering::Relaxed);
+        if rx.is_empty() {
+            out.flush()?;
+        }
+    }
+    out.flush()
+}
+
+// One entry of a decoded capture: a command sent, a line received or a note
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CaptureLine {
+    pub time: String,
+    pub direction: &'static str, // "tx", "rx" or "note"
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<f64>,
+}
+
+impl std::fmt::Display for CaptureLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arrow = match self.direction {
+            "tx" => ">",
+            "rx" => "<",
+            _ => "#",
+        };
+        if !self.time.is_empty() {
+            write!(f, "{} ", self.time)?;
+        }
+        write!(f, "{} {}", arrow, self.text)?;
+        if let Some(latency) = self.latency_ms {
+            write!(f, "  (+{:.3}ms)", latency)?;
+        }
+        Ok(())
+    }
+}
+
+// Bytes of a `TX`/`RX` trace line, e.g. `TX   2 74 0a |t.|`
+fn parse_chunk(rest: &str) -> Option<(Vec<u8>, Option<f64>)> {
+    let mut tokens = rest.split_whitespace();
+    let len: usize = tokens.next()?.parse().ok()?;
+    let bytes: Vec<u8> = tokens
+        .by_ref()
+        .take(len)
+        .map(|hex| u8::from_str_radix(hex, 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+    if bytes.len() != len {
+        return None;
+    }
+    let latency = rest
+        .rsplit_once(" +")
+        .and_then(|(_, ms)| ms.strip_suffix("ms"))
+        .and_then(|ms| ms.parse().ok());
+    Some((bytes, latency))
+}
+
+fn printable(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+    bytes.escape_ascii().to_string()
+}
+
+// Turn a capture (runtime or --trace-serial-file) back into the exchanged lines. Received
+// chunks are joined until a newline, so each device reply shows up whole with the time
+// it took since the command was written.
+pub fn decode(capture: &str) -> Vec<CaptureLine> {
+    let mut lines = Vec::new();
+    let mut pending: Option<(String, Vec<u8>)> = None;
+    let note = |time: &str, text: String| CaptureLine {
+        time: time.to_string(),
+        direction: "note",
+        text,
+        latency_ms: None,
+    };
+
+    for raw in capture.lines() {
+        let raw = raw.trim_end();
+        if raw.is_empty() || raw.starts_with("# time") {
+            continue;
+        }
+        if let Some(comment) = raw.strip_prefix("# ") {
+            lines.push(note("", comment.to_string()));
+            continue;
+        }
+        let mut parts = raw.splitn(3, ' ');
+        let (Some(time), Some(direction), Some(rest)) = (parts.next(), parts.next(), parts.next())
+        else {
+            lines.push(note("", format!("unrecognized line: {}", raw)));
+            continue;
+        };
+        if rest.starts_with("error:") {
+            lines.push(note(time, format!("{} {}", direction, rest)));
+            continue;
+        }
+        let Some((bytes, latency_ms)) = parse_chunk(rest) else {
+            lines.push(note(time, format!("unrecognized line: {}", raw)));
+            continue;
+        };
+        match direction {
+            "TX" => lines.push(CaptureLine {
+                time: time.to_string(),
+                direction: "tx",
+                text: printable(&bytes),
+                latency_ms: None,
+            }),
+            "RX" => {
+                let (started, buffer) =
+                    pending.get_or_insert_with(|| (time.to_string(), Vec::new()));
+                buffer.extend_from_slice(&bytes);
+                while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    lines.push(CaptureLine {
+                        time: started.clone(),
+                        direction: "rx",
+                        text: printable(&line),
+                        latency_ms,
+                    });
+                    *started = time.to_string();
+                }
+                if buffer.is_empty() {
+                    pending = None;
+                }
+            }
+            _ => lines.push(note(time, format!("unrecognized line: {}", raw))),
+        }
+    }
+    if let Some((started, buffer)) = pending {
+        lines.push(CaptureLine {
+            time: started,
+            direction: "rx",
+            text: format!("{} (incomplete)", printable(&buffer)),
+            latency_ms: None,
+        });
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::handle_command;
+    use crate::profile::{Command, Profiles};
+    use crate::serial::{get_current_thresholds_from_device, MockSerialPort};
+    use crate::serial_trace::with_tracing;
+    use crate::state::AppState;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fsr-rs-capture-{}-{}", name, std::process::id()))
+    }
+
+    fn traced_state(dir: PathBuf, max_bytes: u64) -> AppState {
+        let sink = TraceSink::default();
+        let port = with_tracing(
+            Box::new(MockSerialPort::new([100, 200, 300, 400])),
+            sink.clone(),
+        );
+        AppState {
+            capture: Arc::new(SerialCapture::new(sink, dir, max_bytes)),
+            ..AppState::with_port(Profiles::default(), port)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_records_and_decodes_exchange() {
+        let dir = temp_dir("exchange");
+        let state = traced_state(dir.clone(), CAPTURE_MAX_BYTES);
+
+        let response = handle_command(
+            Command::StartSerialCapture {
+                path_hint: Some("../stuck pad".to_string()),
+            },
+            &state,
+        )
+        .await;
+        assert!(response.success, "{}", response.message);
+        assert!(state.capture.status().active);
+        let again = handle_command(Command::StartSerialCapture { path_hint: None }, &state).await;
+        assert_eq!(
+            again.payload,
+            Some(serde_json::json!({ "code": "CAPTURE_RUNNING" }))
+        );
+
+        get_current_thresholds_from_device(&state.serial)
+            .await
+            .unwrap();
+        let response = handle_command(Command::StopSerialCapture, &state).await;
+        assert!(response.success);
+        assert!(!state.capture.status().active);
+
+        // The hint can't leave the capture directory
+        let path = PathBuf::from(response.payload.unwrap()["path"].as_str().unwrap());
+        assert_eq!(path.parent(), Some(dir.as_path()));
+        assert!(path.to_string_lossy().ends_with("-stuck-pad.log"));
+
+        let decoded = decode(&std::fs::read_to_string(&path).unwrap());
+        let exchange: Vec<String> = decoded
+            .iter()
+            .filter(|line| line.direction != "note")
+            .map(|line| format!("{} {}", line.direction, line.text))
+            .collect();
+        assert_eq!(exchange, ["tx t", "rx t 100 200 300 400"]);
+        assert!(decoded[decoded.len() - 1].latency_ms.is_some());
+
+        let stopped = handle_command(Command::StopSerialCapture, &state).await;
+        assert_eq!(
+            stopped.payload,
+            Some(serde_json::json!({ "code": "CAPTURE_NOT_RUNNING" }))
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_capture_stops_recording_at_size_limit() {
+        let dir = temp_dir("limit");
+        let state = traced_state(dir.clone(), 300);
+
+        state.capture.start(None).unwrap();
+        for _ in 0..10 {
+            get_current_thresholds_from_device(&state.serial)
+                .await
+                .unwrap();
+        }
+        let status = state.capture.stop().await.unwrap();
+
+        assert!(status.limit_reached);
+        assert!(status.dropped_lines > 0);
+        assert!(status.bytes_written <= 300);
+        let content = std::fs::read_to_string(status.path.unwrap()).unwrap();
+        assert!(content.ends_with("later traffic was not recorded\n"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_decode_joins_split_replies() {
+        let capture = "\
+# fsr-rs serial capture started 2026-10-16T18:30:05+02:00
+# time direction length hex |ascii| [+ms since last write]
+18:30:05.000100 TX   2 76 0a |v.|
+18:30:05.001000 RX   4 76 20 31 20 |v 1 | +0.900ms
+18:30:05.002000 RX   6 32 20 33 20 34 0a |2 3 4.| +1.900ms
+18:30:05.120000 RX error: Operation timed out +119.900ms
+";
+        let decoded: Vec<String> = decode(capture).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            decoded,
+            [
+                "# fsr-rs serial capture started 2026-10-16T18:30:05+02:00",
+                "18:30:05.000100 > v",
+                "18:30:05.001000 < v 1 2 3 4  (+1.900ms)",
+                "18:30:05.120000 # RX error: Operation timed out +119.900ms",
+            ]
+        );
+    }
+}
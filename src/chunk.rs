@@ -0,0 +1,167 @@
+use crate::error::ValidationError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Largest serialized response sent as a single websocket message
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+// Largest serialized response sent at all; anything bigger is answered with an error
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkLimits {
+    pub chunk_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkLimits {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_size: DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+}
+
+// One part of a response too large for a single message. Clients concatenate the
+// payloads of parts 0..total_parts of a request_id and parse the result.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub response_type: String, // "chunk"
+    pub request_id: u64,
+    pub part: usize,
+    pub total_parts: usize,
+    pub payload: String,
+}
+
+// Split `json` in pieces of at most `chunk_size` bytes, never inside a character
+pub fn split(json: &str, chunk_size: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = json;
+    while !rest.is_empty() {
+        let mut end = chunk_size.max(1).min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // A character wider than the chunk size still has to go somewhere
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let (part, tail) = rest.split_at(end);
+        parts.push(part);
+        rest = tail;
+    }
+    parts
+}
+
+// The messages to send for a serialized response: the response itself if it fits in one
+// chunk, else its chunks tagged with `request_id`
+pub fn messages(
+    json: String,
+    request_id: u64,
+    limits: ChunkLimits,
+) -> Result<Vec<String>, ValidationError> {
+    if json.len() > limits.max_size {
+        return Err(ValidationError::ResponseTooLarge {
+            size: json.len(),
+            limit: limits.max_size,
+        });
+    }
+    if json.len() <= limits.chunk_size {
+        return Ok(vec![json]);
+    }
+    let parts = split(&json, limits.chunk_size);
+    let total_parts = parts.len();
+    Ok(parts
+        .into_iter()
+        .enumerate()
+        .filter_map(|(part, payload)| {
+            serde_json::to_string(&Chunk {
+                response_type: "chunk".to_string(),
+                request_id,
+                part,
+                total_parts,
+                payload: payload.to_string(),
+            })
+            .ok()
+        })
+        .collect())
+}
+
+// The client side: collects the chunks of each request_id until all of its parts are in.
+// A connection delivers the parts of a response in order.
+#[derive(Debug, Default)]
+pub struct Reassembly {
+    parts: HashMap<u64, Vec<String>>,
+}
+
+impl Reassembly {
+    // The whole response once `chunk` was its last part
+    pub fn push(&mut self, chunk: Chunk) -> Option<String> {
+        let parts = self.parts.entry(chunk.request_id).or_default();
+        parts.push(chunk.payload);
+        if parts.len() < chunk.total_parts {
+            return None;
+        }
+        self.parts
+            .remove(&chunk.request_id)
+            .map(|parts| parts.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_on_char_boundaries() {
+        assert_eq!(split("abcdefg", 3), vec!["abc", "def", "g"]);
+        assert_eq!(split("", 3), Vec::<&str>::new());
+        // "é" is two bytes and never cut in half
+        assert_eq!(split("aéé", 2), vec!["a", "é", "é"]);
+        assert_eq!(split("éa", 1), vec!["é", "a"]);
+    }
+
+    #[test]
+    fn test_messages_reassemble() {
+        let limits = ChunkLimits {
+            chunk_size: 10,
+            max_size: 100,
+        };
+        let json = serde_json::json!({ "message": "ünïcödé everywhere", "n": [1, 2, 3] });
+        let json = json.to_string();
+
+        let single = messages("{}".to_string(), 1, limits).unwrap();
+        assert_eq!(single, vec!["{}"]);
+
+        let chunks: Vec<Chunk> = messages(json.clone(), 7, limits)
+            .unwrap()
+            .iter()
+            .map(|message| serde_json::from_str(message).unwrap())
+            .collect();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().enumerate().all(|(i, chunk)| chunk.part == i
+            && chunk.total_parts == chunks.len()
+            && chunk.request_id == 7
+            && chunk.payload.len() <= 10));
+        let joined: String = chunks.iter().map(|chunk| chunk.payload.as_str()).collect();
+        assert_eq!(joined, json);
+        let mut reassembly = Reassembly::default();
+        let reassembled: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| reassembly.push(chunk))
+            .collect();
+        assert!(reassembled[..reassembled.len() - 1]
+            .iter()
+            .all(Option::is_none));
+        assert_eq!(reassembled.last().unwrap().as_deref(), Some(json.as_str()));
+
+        assert_eq!(
+            messages("x".repeat(101), 8, limits),
+            Err(ValidationError::ResponseTooLarge {
+                size: 101,
+                limit: 100
+            })
+        );
+    }
+}
@@ -0,0 +1,386 @@
+use crate::autodetect::resolve_port;
+use crate::capture;
+use crate::commands::handle_command;
+use crate::config::{Args, CliCommand};
+use crate::error::{AppError, SerialOp};
+use crate::info::{DeviceKind, ServerInfo};
+use crate::journal::{self, Journal, JOURNAL_MAX_BYTES};
+use crate::profile::{load_profiles, save_profiles, Command};
+use crate::range::DeviceRange;
+use crate::repair;
+use crate::self_test::{self, SelfTestReport};
+use crate::sensors::{Sensors, MAX_SENSORS};
+use crate::serial::{
+    get_current_thresholds_from_device, open_device, read_sensor_values, set_all_thresholds,
+    DummySerialPort,
+};
+use crate::state::AppState;
+use crate::threshold_log::{ThresholdWriteLog, WriteReason, WRITE_LOG_CAPACITY};
+use serde_json::json;
+use serialport::{SerialPort, SerialPortType};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+// Result of a subcommand in both output forms
+#[derive(Debug)]
+pub struct CliOutput {
+    pub text: String,
+    pub json: serde_json::Value,
+}
+
+fn list_ports() -> Result<CliOutput, String> {
+    let ports = serialport::available_ports().map_err(|e| e.to_string())?;
+
+    let mut lines = Vec::new();
+    let mut entries = Vec::new();
+    for port in ports {
+        match &port.port_type {
+            SerialPortType::UsbPort(usb) => {
+                lines.push(format!(
+                    "{} (USB {:04x}:{:04x}{})",
+                    port.port_name,
+                    usb.vid,
+                    usb.pid,
+                    usb.product
+                        .as_ref()
+                        .map(|product| format!(" {}", product))
+                        .unwrap_or_default()
+                ));
+                entries.push(json!({
+                    "name": port.port_name,
+                    "type": "usb",
+                    "vid": usb.vid,
+                    "pid": usb.pid,
+                    "serial_number": usb.serial_number,
+                    "manufacturer": usb.manufacturer,
+                    "product": usb.product,
+                }));
+            }
+            other => {
+                let kind = match other {
+                    SerialPortType::PciPort => "pci",
+                    SerialPortType::BluetoothPort => "bluetooth",
+                    _ => "unknown",
+                };
+                lines.push(format!("{} ({})", port.port_name, kind));
+                entries.push(json!({ "name": port.port_name, "type": kind }));
+            }
+        }
+    }
+    if lines.is_empty() {
+        lines.push("No serial ports found".to_string());
+    }
+
+    Ok(CliOutput {
+        text: lines.join("\n"),
+        json: json!({ "ports": entries }),
+    })
+}
+
+fn decode_capture(file: &Path) -> Result<CliOutput, String> {
+    let content = std::fs::read_to_string(file)
+        .map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+    let lines = capture::decode(&content);
+    Ok(CliOutput {
+        text: lines
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        json: json!({ "lines": lines }),
+    })
+}
+
+// Run a device subcommand against an already opened port, logging its threshold writes
+// to `writes`
+pub async fn execute(
+    command: CliCommand,
+    port: &Arc<Mutex<Box<dyn SerialPort>>>,
+    writes: Arc<ThresholdWriteLog>,
+) -> Result<CliOutput, String> {
+    match command {
+        CliCommand::GetThresholds => {
+            let thresholds = get_current_thresholds_from_device(port)
+                .await
+                .map_err(|e| AppError::serial(SerialOp::ReadThresholds)(e).to_string())?;
+            Ok(CliOutput {
+                text: format!("Thresholds: {:?}", thresholds),
+                json: json!({ "thresholds": thresholds }),
+            })
+        }
+        CliCommand::SetThresholds { thresholds } => {
+            let thresholds = Sensors::from_slice(&thresholds)
+                .ok_or_else(|| format!("At most {} thresholds, one per sensor", MAX_SENSORS))?;
+            set_all_thresholds(port, thresholds, writes.reason(WriteReason::UserCommand))
+                .await
+                .map_err(|e| AppError::serial(SerialOp::SetThresholds)(e).to_string())?;
+            Ok(CliOutput {
+                text: format!("Set thresholds to {:?}", thresholds),
+                json: json!({ "thresholds": thresholds }),
+            })
+        }
+        CliCommand::GetValues { count } => {
+            let mut readings = Vec::new();
+            for i in 0..count {
+                if i > 0 {
+                    // Same pacing as the server's sensor stream
+                    tokio::time::sleep(Duration::from_millis(16)).await;
+                }
+                let values = read_sensor_values(port)
+                    .await
+                    .map_err(|e| AppError::serial(SerialOp::ReadSensorValues)(e).to_string())?;
+                readings.push(values);
+            }
+            Ok(CliOutput {
+                text: readings
+                    .iter()
+                    .map(|values| format!("{:?}", values))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json: json!({ "readings": readings }),
+            })
+        }
+        CliCommand::ApplyProfile { name } => {
+            // Same path as the websocket ChangeProfile command, including saving profiles.json
+            // Unlike the server this saves right away, so a file that doesn't load is an
+            // error rather than something to overwrite
+            let mut profiles = load_profiles().await.map_err(|e| e.to_string())?;
+            repair::report(&repair::repair(&mut profiles));
+            let state = AppState {
+                threshold_writes: writes,
+                ..AppState::new(profiles, port.clone())
+            };
+            let response = handle_command(
+                Command::ChangeProfile {
+                    name,
+                    pad: None,
+                    update_player: None,
+                },
+                &state,
+            )
+            .await;
+            if !response.success {
+                return Err(response.message);
+            }
+            save_profiles(&state.profiles_snapshot())
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(CliOutput {
+                text: response.message.clone(),
+                json: serde_json::to_value(&response).map_err(|e| e.to_string())?,
+            })
+        }
+        CliCommand::Serve
+        | CliCommand::ListPorts
+        | CliCommand::Pipe
+        | CliCommand::DecodeCapture { .. }
+        | CliCommand::SelfTest => Err("Command does not talk to the device".to_string()),
+    }
+}
+
+// The self-test against the profiles file and the device as the server would open them,
+// without starting the server. A device that doesn't open fails the device checks.
+async fn self_test(args: &mut Args) -> Result<SelfTestReport, String> {
+    let profiles = load_profiles().await.map_err(|e| e.to_string())?;
+    let opened = match resolve_port(args).await {
+        Ok(()) => open_device(args)
+            .map_err(|e| format!("Failed to open serial port {}: {}", args.com_port, e)),
+        Err(e) => Err(e),
+    };
+    let (port, device) = match opened {
+        Ok((port, _)) if args.mock_serial => (port, DeviceKind::Mock),
+        Ok((port, _)) => (port, DeviceKind::Serial),
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            (
+                Box::new(DummySerialPort) as Box<dyn SerialPort>,
+                DeviceKind::None,
+            )
+        }
+    };
+    let state = AppState {
+        info: Arc::new(ServerInfo::new(
+            args.host.clone(),
+            args.port,
+            device,
+            (!args.mock_serial).then(|| args.com_port.clone()),
+        )),
+        range: Arc::new(DeviceRange::new(args.threshold_max)),
+        read_only: args.read_only_policy(),
+        ..AppState::new(profiles, Arc::new(Mutex::new(port)))
+    };
+    Ok(self_test::run(&state).await)
+}
+
+// execute with the threshold writes going to the journal, as in the server, which is on
+// disk before this returns
+async fn execute_journaled(
+    command: CliCommand,
+    port: Box<dyn SerialPort>,
+) -> Result<CliOutput, String> {
+    let path = journal::default_journal_path();
+    let journal = Arc::new(Journal::spawn(path, JOURNAL_MAX_BYTES));
+    let writes = Arc::new(ThresholdWriteLog::new(WRITE_LOG_CAPACITY, journal.clone()));
+    let result = execute(command, &Arc::new(Mutex::new(port)), writes).await;
+    if let Ok(journal) = Arc::try_unwrap(journal) {
+        journal.close().await;
+    }
+    result
+}
+
+// Run a one-shot subcommand, print its result and return the process exit code
+pub async fn run(command: CliCommand, args: &mut Args) -> i32 {
+    // The self-test prints its report either way; only the exit code tells a failure
+    let mut failed = false;
+    let result = match command {
+        CliCommand::ListPorts => list_ports(),
+        CliCommand::DecodeCapture { file } => decode_capture(&file),
+        CliCommand::SelfTest => self_test(args).await.map(|report| {
+            failed = !report.passed;
+            CliOutput {
+                text: report.to_string(),
+                json: serde_json::to_value(&report).unwrap_or_default(),
+            }
+        }),
+        command => match resolve_port(args).await {
+            Err(e) => Err(e),
+            Ok(()) => match open_device(args) {
+                Ok((port, _)) => execute_journaled(command, port).await,
+                Err(e) => Err(format!(
+                    "Failed to open serial port {}: {}",
+                    args.com_port, e
+                )),
+            },
+        },
+    };
+
+    match result {
+        Ok(output) => {
+            if args.json {
+                println!("{}", output.json);
+            } else {
+                println!("{}", output.text);
+            }
+            i32::from(failed)
+        }
+        Err(e) => {
+            if args.json {
+                println!("{}", json!({ "success": false, "message": e }));
+            } else {
+                eprintln!("Error: {}", e);
+            }
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::{DummySerialPort, MockSerialPort};
+
+    fn mock_port() -> Arc<Mutex<Box<dyn SerialPort>>> {
+        Arc::new(Mutex::new(
+            Box::new(MockSerialPort::new([100, 200, 300, 400])) as Box<dyn SerialPort>,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_get_and_set_thresholds() {
+        let port = mock_port();
+
+        let output = execute(CliCommand::GetThresholds, &port, Arc::default())
+            .await
+            .unwrap();
+        assert_eq!(output.json, json!({ "thresholds": [100, 200, 300, 400] }));
+
+        let writes = Arc::new(ThresholdWriteLog::default());
+        let output = execute(
+            CliCommand::SetThresholds {
+                thresholds: vec![1, 2, 3, 4],
+            },
+            &port,
+            writes.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.text, "Set thresholds to [1, 2, 3, 4]");
+        let write = &writes.tail(1)[0];
+        assert_eq!(write.reason, WriteReason::UserCommand);
+        assert_eq!(write.before, [Some(100), Some(200), Some(300), Some(400)]);
+
+        let output = execute(CliCommand::GetThresholds, &port, Arc::default())
+            .await
+            .unwrap();
+        assert_eq!(output.json, json!({ "thresholds": [1, 2, 3, 4] }));
+
+        // The device has four sensors
+        let set_five = CliCommand::SetThresholds {
+            thresholds: vec![1, 2, 3, 4, 5],
+        };
+        let result = execute(set_five, &port, writes).await;
+        assert!(result.unwrap_err().contains("The device has 4 sensors"));
+    }
+
+    #[tokio::test]
+    async fn test_get_values_count() {
+        let port = mock_port();
+
+        let output = execute(CliCommand::GetValues { count: 3 }, &port, Arc::default())
+            .await
+            .unwrap();
+        assert_eq!(output.json["readings"].as_array().unwrap().len(), 3);
+        assert_eq!(output.text.lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_device_failure_is_an_error() {
+        let port = Arc::new(Mutex::new(Box::new(DummySerialPort) as Box<dyn SerialPort>));
+
+        let result = execute(CliCommand::GetThresholds, &port, Arc::default()).await;
+        assert!(result
+            .unwrap_err()
+            .contains("Failed to read thresholds from device"));
+    }
+
+    #[test]
+    fn test_subcommand_parsing() {
+        use clap::Parser;
+
+        let args = Args::try_parse_from(["fsr-rs", "set-thresholds", "1", "2", "3", "4", "--json"])
+            .unwrap();
+        assert_eq!(
+            args.command,
+            Some(CliCommand::SetThresholds {
+                thresholds: vec![1, 2, 3, 4]
+            })
+        );
+        assert!(args.json);
+        assert!(!args.is_serve());
+
+        assert!(
+            Args::try_parse_from(["fsr-rs", "set-thresholds", "1", "2", "3", "4", "5"]).is_ok()
+        );
+        let ten = [
+            "fsr-rs",
+            "set-thresholds",
+            "1",
+            "2",
+            "3",
+            "4",
+            "5",
+            "6",
+            "7",
+            "8",
+            "9",
+            "0",
+        ];
+        assert!(Args::try_parse_from(ten).is_err());
+        assert!(Args::try_parse_from(["fsr-rs"]).unwrap().is_serve());
+        assert!(Args::try_parse_from(["fsr-rs", "serve", "--mock-serial"])
+            .unwrap()
+            .is_serve());
+    }
+}
@@ -0,0 +1,339 @@
+use crate::auth::Role;
+use crate::error::AppError;
+use crate::event::{Event, Subscription, Topic};
+use crate::fresh::FreshReadLimiter;
+use crate::profile::PROTOCOL_VERSION;
+use crate::scale::Scale;
+use crate::session::Parked;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// A connection gets a lag warning every this many lag events
+pub const LAG_WARNING_EVERY: u64 = 5;
+
+// Websocket connections currently open, with send-path counters per connection
+#[derive(Default)]
+pub struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, Arc<ClientEntry>>>,
+}
+
+// Counters of one connection, updated by its forwarding task
+pub struct ClientEntry {
+    pub id: u64,
+    // Granted by the token the connection was opened with, see auth.rs
+    pub role: Role,
+    connected_at: DateTime<Utc>,
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    send_errors: AtomicU64,
+    lag_events: AtomicU64,
+    frames_skipped: AtomicU64,
+    // Protocol version the connection's messages are serialized in
+    protocol: AtomicU32,
+    // Websocket extension the connection's messages are compressed with, see deflate.rs
+    compression: OnceLock<&'static str>,
+    subscription: Mutex<Subscription>,
+    // Client id the connection was opened or greeted with, to resume it after a disconnect
+    session: Mutex<Option<String>>,
+    // Sequence number of the last event sent, or skipped as unwanted, by the forwarding task
+    delivered: AtomicU64,
+    // Sequence number a resumed session continues from, picked up by the forwarding task
+    rewind: Mutex<Option<u64>>,
+    // Earlier connections of a resumed session, see Parked
+    resumed_from: Mutex<Vec<u64>>,
+    // Spaces out the connection's ReadSensors commands
+    fresh_reads: FreshReadLimiter,
+}
+
+// One connection as returned by GetClients
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ClientStatus {
+    pub id: u64,
+    pub role: Role,
+    pub connected_at: DateTime<Utc>,
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub send_errors: u64,
+    pub lag_events: u64,
+    pub frames_skipped: u64,
+    pub protocol: u32,
+    // Negotiated compression, null for an uncompressed connection
+    pub compression: Option<&'static str>,
+    // Client id chosen by the client, if it gave one
+    pub session: Option<String>,
+    // Subscribed topics with their pad filters, null while receiving everything
+    pub topics: Option<Vec<Topic>>,
+}
+
+// Keeps a connection registered until dropped
+pub struct ClientGuard {
+    registry: Arc<ClientRegistry>,
+    entry: Arc<ClientEntry>,
+}
+
+impl ClientRegistry {
+    pub fn register(self: &Arc<Self>, role: Role) -> ClientGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Arc::new(ClientEntry {
+            id,
+            role,
+            connected_at: Utc::now(),
+            messages_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            send_errors: AtomicU64::new(0),
+            lag_events: AtomicU64::new(0),
+            frames_skipped: AtomicU64::new(0),
+            protocol: AtomicU32::new(PROTOCOL_VERSION),
+            compression: OnceLock::new(),
+            subscription: Mutex::new(Subscription::default()),
+            session: Mutex::new(None),
+            delivered: AtomicU64::new(0),
+            rewind: Mutex::new(None),
+            resumed_from: Mutex::new(Vec::new()),
+            fresh_reads: FreshReadLimiter::default(),
+        });
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.insert(id, entry.clone());
+        }
+        ClientGuard {
+            registry: self.clone(),
+            entry,
+        }
+    }
+
+    pub fn status(&self) -> Vec<ClientStatus> {
+        let Ok(clients) = self.clients.lock() else {
+            return Vec::new();
+        };
+        clients.values().map(|entry| entry.status()).collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.clients.lock().map_or(0, |clients| clients.len())
+    }
+
+    // Open connections that receive events of type `kind`
+    pub fn subscribers(&self, kind: &str) -> usize {
+        let Ok(clients) = self.clients.lock() else {
+            return 0;
+        };
+        clients
+            .values()
+            .filter(|entry| {
+                entry
+                    .subscription
+                    .lock()
+                    .map_or(true, |subscription| subscription.wants_kind(kind))
+            })
+            .count()
+    }
+}
+
+impl ClientEntry {
+    // Take the connection's next fresh sensor read, see FreshReadLimiter
+    pub fn check_fresh_read(&self) -> Result<(), AppError> {
+        self.fresh_reads.check()
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn protocol(&self) -> u32 {
+        self.protocol.load(Ordering::Relaxed)
+    }
+
+    pub fn set_protocol(&self, version: u32) {
+        self.protocol.store(version, Ordering::Relaxed);
+    }
+
+    pub fn set_compression(&self, extension: &'static str) {
+        let _ = self.compression.set(extension);
+    }
+
+    pub fn record_send_error(&self) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Returns the number of lag events so far when it's time to warn about them
+    pub fn record_lag(&self, skipped: u64) -> Option<u64> {
+        self.frames_skipped.fetch_add(skipped, Ordering::Relaxed);
+        let events = self.lag_events.fetch_add(1, Ordering::Relaxed) + 1;
+        events.is_multiple_of(LAG_WARNING_EVERY).then_some(events)
+    }
+
+    // Replace what the connection receives; returns the new subscription
+    pub fn subscribe(&self, topics: Vec<Topic>, scale: Scale) -> Subscription {
+        let subscription = Subscription::new(topics, scale);
+        if let Ok(mut current) = self.subscription.lock() {
+            *current = subscription.clone();
+        }
+        subscription
+    }
+
+    pub fn session(&self) -> Option<String> {
+        self.session.lock().ok().and_then(|session| session.clone())
+    }
+
+    pub fn set_session(&self, id: String) {
+        if let Ok(mut session) = self.session.lock() {
+            *session = Some(id);
+        }
+    }
+
+    // Take over a parked session: its subscriptions now, and the events it missed
+    // from the forwarding task's next look at the history
+    pub fn resume(&self, parked: Parked) {
+        if let Ok(mut subscription) = self.subscription.lock() {
+            *subscription = parked.subscription;
+        }
+        if let Ok(mut rewind) = self.rewind.lock() {
+            *rewind = Some(parked.seq);
+        }
+        if let Ok(mut resumed_from) = self.resumed_from.lock() {
+            *resumed_from = parked.connections;
+        }
+    }
+
+    pub fn take_rewind(&self) -> Option<u64> {
+        self.rewind.lock().ok().and_then(|mut rewind| rewind.take())
+    }
+
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::Relaxed)
+    }
+
+    pub fn set_delivered(&self, seq: u64) {
+        self.delivered.store(seq, Ordering::Relaxed);
+    }
+
+    // What to keep for the client to resume, if it gave a client id
+    pub fn park(&self) -> Option<(String, Parked)> {
+        let subscription = self.subscription.lock().ok()?.clone();
+        let mut connections = self.resumed_from.lock().ok()?.clone();
+        connections.push(self.id);
+        let parked = Parked {
+            subscription,
+            seq: self.delivered(),
+            connections,
+        };
+        self.session().map(|id| (id, parked))
+    }
+
+    pub fn wants(&self, event: &Event) -> bool {
+        // A command's result only goes back to the connection that sent it
+        if let Event::CommandResult { connection, .. } = event {
+            return *connection == self.id
+                || self
+                    .resumed_from
+                    .lock()
+                    .is_ok_and(|resumed_from| resumed_from.contains(connection));
+        }
+        self.subscription
+            .lock()
+            .map_or(true, |subscription| subscription.wants(event))
+    }
+
+    pub fn scale(&self) -> Scale {
+        self.subscription
+            .lock()
+            .map_or(Scale::Raw, |subscription| subscription.scale())
+    }
+
+    pub fn frames_skipped(&self) -> u64 {
+        self.frames_skipped.load(Ordering::Relaxed)
+    }
+
+    fn status(&self) -> ClientStatus {
+        ClientStatus {
+            id: self.id,
+            role: self.role,
+            connected_at: self.connected_at,
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            lag_events: self.lag_events.load(Ordering::Relaxed),
+            frames_skipped: self.frames_skipped(),
+            protocol: self.protocol(),
+            compression: self.compression.get().copied(),
+            session: self.session(),
+            topics: self
+                .subscription
+                .lock()
+                .ok()
+                .and_then(|subscription| subscription.topics().map(<[Topic]>::to_vec)),
+        }
+    }
+}
+
+impl ClientGuard {
+    // Counters of this connection, for tasks that don't own the guard
+    pub fn entry(&self) -> Arc<ClientEntry> {
+        self.entry.clone()
+    }
+}
+
+impl std::ops::Deref for ClientGuard {
+    type Target = ClientEntry;
+
+    fn deref(&self) -> &ClientEntry {
+        &self.entry
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        if let Ok(mut clients) = self.registry.clients.lock() {
+            clients.remove(&self.entry.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::PERMESSAGE_DEFLATE;
+
+    #[test]
+    fn test_client_registry_counters() {
+        let registry = Arc::new(ClientRegistry::default());
+        let first = registry.register(Role::Admin);
+        let second = registry.register(Role::Viewer);
+        assert_ne!(first.id, second.id);
+
+        first.record_sent(100);
+        first.record_sent(20);
+        first.record_send_error();
+        second.set_compression(PERMESSAGE_DEFLATE);
+        let warnings: Vec<_> = (0..LAG_WARNING_EVERY * 2)
+            .filter_map(|_| second.record_lag(3))
+            .collect();
+        assert_eq!(warnings, vec![LAG_WARNING_EVERY, LAG_WARNING_EVERY * 2]);
+
+        let status = registry.status();
+        assert_eq!(status.len(), 2);
+        assert_eq!((status[0].messages_sent, status[0].bytes_sent), (2, 120));
+        assert_eq!(status[0].send_errors, 1);
+        assert_eq!(status[1].lag_events, LAG_WARNING_EVERY * 2);
+        assert_eq!(status[1].frames_skipped, LAG_WARNING_EVERY * 2 * 3);
+        assert_eq!(
+            (status[0].compression, status[1].compression),
+            (None, Some(PERMESSAGE_DEFLATE))
+        );
+        assert_eq!(
+            (status[0].role, status[1].role),
+            (Role::Admin, Role::Viewer)
+        );
+
+        // Closing a connection removes its entry
+        drop(first);
+        let status = registry.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].id, second.id);
+    }
+}
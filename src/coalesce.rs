@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+// Threshold of one sensor of one profile
+pub type ThresholdKey = (String, u8);
+
+// Merges UpdateThreshold commands for the same threshold that arrive while one of them is
+// writing to the device. The first command keeps writing the newest value until no newer
+// one is waiting; the commands in between are answered with the value that was written.
+pub struct Coalescer {
+    // Extra time the writing command waits for newer values before each write
+    window: Duration,
+    slots: Mutex<HashMap<ThresholdKey, Slot>>,
+}
+
+#[derive(Default)]
+struct Slot {
+    // Newest value not written yet
+    pending: Option<i32>,
+    // Commands superseded by a newer value; told the final value, or None if the write
+    // failed and they should try on their own
+    superseded: Vec<oneshot::Sender<Option<i32>>>,
+}
+
+pub enum Ticket<'a> {
+    // Nothing is being written for the threshold; the command writes it itself
+    Lead(Lead<'a>),
+    // Another command is writing; wait for the value it ends up with
+    Follow(oneshot::Receiver<Option<i32>>),
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn join(&self, key: &ThresholdKey, value: i32) -> Ticket<'_> {
+        if let Ok(mut slots) = self.slots.lock() {
+            if let Some(slot) = slots.get_mut(key) {
+                let (tx, rx) = oneshot::channel();
+                slot.pending = Some(value);
+                slot.superseded.push(tx);
+                return Ticket::Follow(rx);
+            }
+            slots.insert(key.clone(), Slot::default());
+        }
+        Ticket::Lead(Lead {
+            coalescer: self,
+            key: key.clone(),
+            done: false,
+        })
+    }
+}
+
+// The command writing a threshold. Dropping it before the newest value was written, on
+// a failed write or a cancelled command, lets the waiting commands retry on their own.
+pub struct Lead<'a> {
+    coalescer: &'a Coalescer,
+    key: ThresholdKey,
+    done: bool,
+}
+
+impl Lead<'_> {
+    // The newest value another command is waiting with, if any
+    pub fn newer(&mut self) -> Option<i32> {
+        let mut slots = self.coalescer.slots.lock().ok()?;
+        slots.get_mut(&self.key)?.pending.take()
+    }
+
+    // The next value to write, or None once `written` is the newest. Then the superseded
+    // commands are answered with `written` and the threshold is free again.
+    pub fn advance(&mut self, written: i32) -> Option<i32> {
+        let Ok(mut slots) = self.coalescer.slots.lock() else {
+            return None;
+        };
+        if let Some(next) = slots
+            .get_mut(&self.key)
+            .and_then(|slot| slot.pending.take())
+        {
+            return Some(next);
+        }
+        self.done = true;
+        if let Some(slot) = slots.remove(&self.key) {
+            for waiter in slot.superseded {
+                let _ = waiter.send(Some(written));
+            }
+        }
+        None
+    }
+}
+
+impl Drop for Lead<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let Ok(mut slots) = self.coalescer.slots.lock() else {
+            return;
+        };
+        if let Some(slot) = slots.remove(&self.key) {
+            for waiter in slot.superseded {
+                let _ = waiter.send(None);
+            }
+        }
+    }
+}
+
+impl Default for Coalescer {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
@@ -0,0 +1,5822 @@
+use crate::audit::{self, AuditEntry, Origin, DEFAULT_HISTORY_LIMIT};
+use crate::auth::{self, Role};
+use crate::calibration::{self, CALIBRATION_WINDOW, MAX_CALIBRATION_WINDOW};
+use crate::coalesce::Ticket;
+use crate::compat;
+use crate::devices::{self, DeviceIdentity, DeviceState};
+use crate::error::{AppError, SerialError, SerialOp, StorageError, ValidationError};
+use crate::event::Event;
+use crate::fresh::{read_current, read_fresh};
+use crate::heartbeat::stream_status;
+use crate::idempotency::{Claim, MAX_IDEMPOTENCY_KEY_LEN};
+use crate::identify::{self, IDENTIFY_DURATION, SESSION_IDLE};
+use crate::journal::{JournalKind, DEFAULT_LOG_LIMIT};
+use crate::latency::LatencyStats;
+use crate::layout::{self, LAYOUTS};
+use crate::panel_test::{self, MAX_PANEL_TEST_TIMEOUT, PANEL_TEST_TIMEOUT};
+use crate::press;
+use crate::profile::import::{self, ImportedProfile, OnConflict};
+use crate::profile::{
+    load_profiles_from, Command, LoadFailure, Pad, Player, Profile, Profiles, Response, SensorMap,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::proposal::{self, Proposal, ProposalStatus};
+use crate::quota::Quotas;
+use crate::repair::{self, Repair};
+use crate::schedule::{self, Schedule, When};
+use crate::self_test;
+use crate::sensors::Sensors;
+use crate::serial::{get_current_thresholds_from_device, set_all_thresholds, set_threshold};
+use crate::share::{self, SharedProfile};
+use crate::snapshot::SnapshotInfo;
+use crate::state::AppState;
+use crate::tags;
+use crate::temporary::{self, TemporaryProfile, MAX_TEMPORARY_DURATION};
+use crate::threshold_log::{WriteReason, DEFAULT_WRITE_LOG_LIMIT};
+use crate::webhook::WebhookStatus;
+use chrono::{Local, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::error::Category;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Error code returned for commands rejected by --read-only
+pub const READ_ONLY_MODE: &str = "READ_ONLY_MODE";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadOnlyMode {
+    #[default]
+    Off,
+    // Every mutating command is rejected
+    Strict,
+    // Like strict, but authorized clients may still mutate
+    Soft,
+}
+
+// Which commands a server in read-only mode accepts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadOnlyPolicy {
+    pub mode: ReadOnlyMode,
+    pub allow_stream: bool,
+    // Following another instance with --mirror: strict, stream control included, and
+    // rejected with MIRROR_MODE instead
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub mirror: bool,
+}
+
+impl ReadOnlyPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.mode != ReadOnlyMode::Off
+    }
+
+    // The policy of a --mirror server, whose profiles and stream belong to the upstream
+    pub fn mirror() -> Self {
+        Self {
+            mode: ReadOnlyMode::Strict,
+            allow_stream: false,
+            mirror: true,
+        }
+    }
+
+    // Err(AppError::ReadOnly) for a command this policy rejects
+    pub fn check(&self, command: &Command, authorized: bool) -> Result<(), AppError> {
+        // A panel test turns the stream on for its duration
+        let controls_stream = matches!(
+            command,
+            Command::StartSensorStream | Command::StopSensorStream | Command::StartPanelTest { .. }
+        );
+        if self.mirror && (command.is_mutating() || controls_stream) {
+            return Err(AppError::MirrorMode);
+        }
+        let allowed = match self.mode {
+            ReadOnlyMode::Off => true,
+            ReadOnlyMode::Soft if authorized => true,
+            ReadOnlyMode::Strict | ReadOnlyMode::Soft if controls_stream => self.allow_stream,
+            ReadOnlyMode::Strict | ReadOnlyMode::Soft => !command.is_mutating(),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(AppError::ReadOnly)
+        }
+    }
+}
+
+// Successful result of a command, turned into a Response by handle_command
+#[derive(Debug, Default)]
+pub struct OkPayload {
+    // Stable code of the outcome and its parameters, for clients that render their own
+    // text; `message` is the English fallback
+    pub code: &'static str,
+    pub params: Option<serde_json::Value>,
+    // What the command replaced, captured before it changed anything
+    pub previous: Option<serde_json::Value>,
+    pub message: String,
+    // Shared snapshot of the profiles as published after the command
+    pub data: Option<Arc<Profiles>>,
+    pub payload: Option<serde_json::Value>,
+    // Pad the command acted on, tagged on the response
+    pub pad: Option<String>,
+    // Sensor values of a reading, in the response's `sensor_values`
+    pub sensor_values: Option<Sensors<i32>>,
+    attach_profiles: bool,
+}
+
+impl OkPayload {
+    fn with_profiles(code: &'static str, message: String) -> Self {
+        Self {
+            code,
+            message,
+            attach_profiles: true,
+            ..Self::default()
+        }
+    }
+}
+
+// Parse a command sent as JSON text. Broken JSON, numbers that don't fit their field
+// (floats, or integers out of range, such as a threshold index above 255) and unknown or
+// incomplete commands are told apart by their code.
+pub fn parse_command(text: &str) -> Result<Command, AppError> {
+    let mut deserializer = serde_json::Deserializer::from_str(text);
+    let command = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let field = e.path().to_string();
+        let error = e.into_inner();
+        let reason = error.to_string();
+        // serde reports these through the type being deserialized, not as a category
+        let bad_number = reason.starts_with("invalid type: floating point")
+            || reason.starts_with("invalid value: integer")
+            || reason.starts_with("number out of range");
+        match error.classify() {
+            _ if bad_number => AppError::InvalidNumber { field, reason },
+            Category::Syntax | Category::Eof | Category::Io => AppError::MalformedJson(reason),
+            Category::Data if field == "." => AppError::InvalidCommand(reason),
+            Category::Data => AppError::InvalidCommand(format!("{}: {}", field, reason)),
+        }
+    })?;
+    // Anything after the command, e.g. a second one on the same line
+    deserializer
+        .end()
+        .map_err(|e| AppError::MalformedJson(e.to_string()))?;
+    Ok(command)
+}
+
+// A command with the options sent next to it in the same object, e.g.
+// {"AddProfile": {...}, "idempotency_key": "retry-1"}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub command: Command,
+    pub idempotency_key: Option<String>,
+    // Validate and answer as usual, but change nothing
+    pub dry_run: bool,
+    // Reject a mutating command with CONFLICT unless what it changes is at this revision,
+    // see check_revision
+    pub expected_revision: Option<u64>,
+    // Set by the transport the message arrived on, for the command history
+    pub origin: Origin,
+}
+
+// Envelope fields; never the name of a command
+const ENVELOPE_KEYS: [&str; 3] = ["idempotency_key", "dry_run", "expected_revision"];
+
+// parse_command for a message that may carry envelope fields
+pub fn parse_envelope(text: &str) -> Result<Envelope, AppError> {
+    let bare = |command| Envelope {
+        command,
+        idempotency_key: None,
+        dry_run: false,
+        expected_revision: None,
+        origin: Origin::default(),
+    };
+    let Ok(serde_json::Value::Object(mut fields)) = serde_json::from_str(text) else {
+        // parse_command tells what is wrong with it, if anything
+        return parse_command(text).map(bare);
+    };
+    if !ENVELOPE_KEYS.iter().any(|key| fields.contains_key(*key)) {
+        return parse_command(text).map(bare);
+    }
+    let idempotency_key = match fields.remove("idempotency_key") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(key))
+            if (1..=MAX_IDEMPOTENCY_KEY_LEN).contains(&key.len()) =>
+        {
+            Some(key)
+        }
+        Some(_) => {
+            return Err(AppError::InvalidCommand(format!(
+                "idempotency_key must be a string of 1-{} characters",
+                MAX_IDEMPOTENCY_KEY_LEN
+            )))
+        }
+    };
+    let dry_run = match fields.remove("dry_run") {
+        None | Some(serde_json::Value::Null) => false,
+        Some(serde_json::Value::Bool(dry_run)) => dry_run,
+        Some(_) => {
+            return Err(AppError::InvalidCommand(
+                "dry_run must be true or false".to_string(),
+            ))
+        }
+    };
+    let expected_revision = match fields.remove("expected_revision") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(revision) => Some(revision.as_u64().ok_or_else(|| {
+            AppError::InvalidCommand("expected_revision must be a revision number".to_string())
+        })?),
+    };
+    let command = parse_command(&serde_json::Value::Object(fields).to_string())?;
+    Ok(Envelope {
+        command,
+        idempotency_key,
+        dry_run,
+        expected_revision,
+        origin: Origin::default(),
+    })
+}
+
+// handle_command for a parsed envelope. A mutating command sent again with the key of
+// one that succeeded within the window gets the stored response instead of executing
+// again; one sent while the first is still executing waits for its response. Dry runs
+// are never stored or replayed.
+pub async fn handle_envelope(envelope: Envelope, state: &AppState) -> Response {
+    let Envelope {
+        command,
+        idempotency_key,
+        dry_run,
+        expected_revision,
+        origin,
+    } = envelope;
+    let request = Request {
+        dry_run,
+        expected_revision,
+        origin,
+    };
+    if dry_run {
+        return respond(command, state, request).await;
+    }
+    let Some(key) = idempotency_key.filter(|_| command.is_mutating()) else {
+        return respond(command, state, request).await;
+    };
+    loop {
+        match state.idempotency.claim(&key) {
+            Claim::Run(pending) => {
+                let response = respond(command, state, request).await;
+                pending.finish(&response);
+                return response;
+            }
+            Claim::Replay(response) => return *response,
+            Claim::Wait(mut running) => {
+                if let Ok(response) = running.wait_for(Option::is_some).await {
+                    if let Some(response) = response.clone() {
+                        return response;
+                    }
+                }
+                // The first one was cancelled before it answered; execute this one
+            }
+        }
+    }
+}
+
+// Execute a single command against the profiles and serial device.
+// Shared by the websocket server, the one-shot CLI and the stdio pipe mode.
+pub async fn handle_command(command: Command, state: &AppState) -> Response {
+    respond(command, state, Request::default()).await
+}
+
+// handle_command for an HTTP request by a client of `role`, with the error of a failure
+// left for the HTTP handler to map to a status. Roles and read-only mode apply as on a
+// websocket.
+pub async fn handle_http(
+    command: Command,
+    role: Role,
+    state: &AppState,
+) -> Result<Response, AppError> {
+    auth::check(&command, role)?;
+    state
+        .read_only
+        .check(&command, state.auth.authorized(role))?;
+    let request = Request {
+        origin: Origin::Http,
+        ..Request::default()
+    };
+    run(command, state, request).await
+}
+
+// How a command is to be executed, from its envelope
+#[derive(Debug, Clone, Copy, Default)]
+struct Request {
+    dry_run: bool,
+    expected_revision: Option<u64>,
+    origin: Origin,
+}
+
+async fn respond(command: Command, state: &AppState, request: Request) -> Response {
+    let dry_run = request.dry_run;
+    run(command, state, request)
+        .await
+        .unwrap_or_else(|e| Response {
+            dry_run,
+            ..e.to_response()
+        })
+}
+
+#[tracing::instrument(name = "command", skip_all, fields(command = command.name(), outcome))]
+async fn run(command: Command, state: &AppState, request: Request) -> Result<Response, AppError> {
+    let Request {
+        dry_run,
+        expected_revision,
+        origin,
+    } = request;
+    let name = command.name();
+    let mutating = command.is_mutating() && !dry_run;
+    // Taken before the command is consumed, recorded with its outcome
+    let params =
+        mutating.then(|| audit::params(serde_json::to_value(&command).unwrap_or_default()));
+    let result = match command {
+        command @ (Command::ProposeThresholdChange { .. }
+        | Command::ApproveProposal { .. }
+        | Command::RejectProposal { .. }) => {
+            handle_proposal(command, state, dry_run, expected_revision, origin).await
+        }
+        command => execute(command, state, dry_run, expected_revision).await,
+    };
+    if let Err(e @ (AppError::Serial { .. } | AppError::DeviceOutOfSync { .. })) = &result {
+        let message = format!("{}: {}", name, e);
+        state.journal.record(JournalKind::SerialError, message);
+    }
+    let failure = result.as_ref().err().map(AppError::code);
+    state.metrics.record_command(name, failure);
+    tracing::Span::current().record("outcome", failure.unwrap_or("ok"));
+    let result = result.map(|ok| {
+        debug_assert!(!ok.code.is_empty(), "{} succeeded without a code", name);
+        // Changes a SaveProfiles would write, while autosave is off
+        let pending_changes = (mutating && !state.saves.autosave()).then(|| {
+            state
+                .saves
+                .pending(state.profiles_generation.load(Ordering::Relaxed))
+        });
+        Response {
+            success: true,
+            message: ok.message,
+            data: ok.data,
+            sensor_values: ok.sensor_values,
+            response_type: Some("command_response".to_string()),
+            payload: ok.payload,
+            pad: ok.pad,
+            message_code: Some(ok.code.to_string()),
+            params: ok.params,
+            previous: ok.previous,
+            seq: None,
+            dry_run,
+            unsaved_changes: pending_changes.is_some_and(|pending| pending > 0),
+            pending_changes,
+        }
+    });
+    if let Some(params) = params {
+        state.audit.record(AuditEntry {
+            timestamp: Utc::now(),
+            command: name.to_string(),
+            params,
+            origin,
+            success: result.is_ok(),
+            code: match &result {
+                Ok(response) => response.message_code.clone().unwrap_or_default(),
+                Err(e) => e.code().to_string(),
+            },
+        });
+    }
+    result
+}
+
+// Put the current profile of reloaded profiles on the device, like on startup. A device
+// that can't be written doesn't stop the reload; it is journaled, and GetCurrentThresholds
+// resyncs it once the device answers.
+async fn apply_reloaded(state: &AppState, loaded: &Profiles) {
+    let Some(profile) = loaded.profiles.get(loaded.current_profile()) else {
+        return;
+    };
+    if !loaded.device_enabled() {
+        return;
+    }
+    let Ok(_queued) = state.serial_queue.enter() else {
+        return;
+    };
+    let physical = loaded.sensor_map().to_physical(profile.thresholds);
+    if let Err(e) = write_thresholds(state, physical, WriteReason::Reload).await {
+        let message = format!("RetryLoadProfiles: {}", e);
+        state.journal.record(JournalKind::SerialError, message);
+    }
+}
+
+// The device's thresholds in logical order; None if the device pad is disabled or the
+// device doesn't answer
+async fn read_device_thresholds(state: &AppState, profiles: &Profiles) -> Option<Sensors<i32>> {
+    profiles.enabled_pad(None).ok()?;
+    let _queued = state.serial_queue.enter().ok()?;
+    let read = get_current_thresholds_from_device(&state.serial).await;
+    let physical = state.metrics.serial_read(read).ok()?;
+    state.range.count(physical.len());
+    Some(profiles.sensor_map().to_logical(physical))
+}
+
+// Write a profile's thresholds to the device, logged with `reason`. A write failing
+// halfway that couldn't be undone marks the device out of sync until the next complete
+// write. Drift compensation starts over from the profile's values; it is cleared first, so
+// an adjustment computed for the previous thresholds isn't written after them.
+async fn write_thresholds(
+    state: &AppState,
+    physical: Sensors<i32>,
+    reason: WriteReason,
+) -> Result<(), SerialError> {
+    state.drift.clear();
+    let logged = state.threshold_writes.reason(reason);
+    let count = physical.len() as u64;
+    let written = set_all_thresholds(&state.serial, physical, logged).await;
+    match &written {
+        Ok(_) => state.health.set_device_out_of_sync(false),
+        Err(e) if e.left_inconsistent() => state.health.set_device_out_of_sync(true),
+        Err(_) => {}
+    }
+    state.metrics.serial_write(count, written).map(drop)
+}
+
+// Put profile `name` on the pad's device for a ChangeProfile and the like
+async fn select_profile(
+    state: &AppState,
+    profiles: &Profiles,
+    pad: &Pad,
+    name: &str,
+    dry_run: bool,
+) -> Result<Prepared, AppError> {
+    let Some(profile) = profiles.profiles.get(name) else {
+        return Err(ValidationError::ProfileNotFound(name.to_string()).into());
+    };
+    if !profiles.drives_device(&pad.id) {
+        // Another pad's device isn't driven by this server; only the selection changes
+        return Ok(Prepared::Commit(None));
+    }
+    layout::check_device(profile, state.range.sensors())?;
+    if dry_run {
+        check_writable(state, SerialOp::SetThresholds)?;
+        return Ok(Prepared::Commit(Some(profile.thresholds)));
+    }
+    let _queued = state.serial_queue.enter()?;
+    // First, try to set all thresholds on the serial device
+    let physical = pad.sensor_map.to_physical(profile.thresholds);
+    write_thresholds(state, physical, WriteReason::ProfileSwitch)
+        .await
+        .map_err(AppError::serial(SerialOp::SetThresholds))?;
+    Ok(Prepared::Commit(Some(profile.thresholds)))
+}
+
+// What a dry run reports in place of a device write: the failure a write to a
+// disconnected device would have
+fn check_writable(state: &AppState, op: SerialOp) -> Result<(), AppError> {
+    if state.health.device_connected() {
+        return Ok(());
+    }
+    let source = std::io::Error::new(
+        std::io::ErrorKind::NotConnected,
+        "the device is disconnected",
+    );
+    Err(AppError::serial(op)(source.into()))
+}
+
+// What a command did before taking the mutation lock
+enum Prepared {
+    // Nothing to commit, the command is answered from the snapshot it ran against
+    Done(OkPayload),
+    // Commit the command; carries the profile thresholds written to the device, if any
+    Commit(Option<Sensors<i32>>),
+    // Commit a pad's device assignment: the device found for it and its port
+    Assign(Option<DeviceIdentity>, Option<String>),
+    // Commit derived gains; carries the offset they were derived against
+    Calibrate(Sensors<f32>, Option<Sensors<i32>>),
+    // Commit an UpdateThreshold with the value written last, which may be a newer
+    // command's value it was coalesced with
+    Threshold(i32),
+    // Replace the profiles with the ones loaded by RetryLoadProfiles, from the file at
+    // the given path, and what was repaired in them
+    Reload(Box<Profiles>, String, Vec<Repair>),
+    // Replace the profiles with those of a snapshot, whose current profile is on the
+    // device already
+    Restore(Box<Profiles>, SnapshotInfo),
+}
+
+// The player and profile a pad had before a ChangeProfile or ChangePlayer, with the
+// profile's thresholds if asked for. None for an unknown pad, which the command rejects.
+fn previous_selection(
+    profiles: &Profiles,
+    pad: Option<&str>,
+    thresholds: bool,
+) -> Option<serde_json::Value> {
+    let pad = profiles.pad(pad).ok()?;
+    let mut previous = serde_json::json!({
+        "player": Some(&pad.current_player).filter(|player| !player.is_empty()),
+        "profile": pad.current_profile,
+    });
+    if thresholds {
+        previous["thresholds"] = serde_json::json!(profiles
+            .profiles
+            .get(&pad.current_profile)
+            .map(|profile| profile.thresholds));
+    }
+    Some(previous)
+}
+
+// Err(CONFLICT) unless what a mutating command changes is at the expected revision
+fn check_revision(
+    command: &Command,
+    profiles: &Profiles,
+    expected: Option<u64>,
+) -> Result<(), AppError> {
+    let Some(expected) = expected.filter(|_| command.is_mutating()) else {
+        return Ok(());
+    };
+    let profile = command.target_profile();
+    let revision = profiles.revision_of(profile);
+    if revision == expected {
+        return Ok(());
+    }
+    Err(ValidationError::Conflict {
+        profile: profile.map(str::to_string),
+        expected,
+        revision,
+        current: profile
+            .and_then(|profile| profiles.profiles.get(profile))
+            .map(|profile| Box::new(profile.clone())),
+    }
+    .into())
+}
+
+// Run a command in two steps: validation and serial I/O against the published snapshot
+// without any lock, then a short read-modify-write of a private copy under the mutation
+// lock that publishes the copy. The commit re-checks what the device write relied on, so
+// a profile changed in between is reported instead of silently mismatching the device.
+// Saving is left to the persistence task, which picks up the published snapshot.
+async fn execute(
+    command: Command,
+    state: &AppState,
+    dry_run: bool,
+    expected_revision: Option<u64>,
+) -> Result<OkPayload, AppError> {
+    // Their effect is on the device or the disk, not something a dry run could skip
+    if dry_run
+        && matches!(
+            command,
+            Command::IdentifyPad { .. }
+                | Command::StartPanelTest { .. }
+                | Command::CancelPanelTest
+                | Command::ResetLatencyStats
+                | Command::RunSelfTest
+                | Command::StartSerialCapture { .. }
+                | Command::StopSerialCapture
+                | Command::CreateSnapshot { .. }
+                | Command::SaveProfiles
+        )
+    {
+        return Err(AppError::InvalidCommand(format!(
+            "{} can't be dry-run",
+            command.name()
+        )));
+    }
+    let snapshot = state.profiles_snapshot();
+    // Checked before the device is written, and again under the mutation lock
+    check_revision(&command, &snapshot, expected_revision)?;
+    let prepared = match prepare(&command, state, &snapshot, dry_run).await? {
+        Prepared::Done(mut ok) => {
+            if ok.attach_profiles {
+                ok.data = Some(snapshot);
+            }
+            return Ok(ok);
+        }
+        prepared => prepared,
+    };
+    if dry_run {
+        // Committed to a copy that is never published, so nothing is saved or broadcast
+        let mut copy = snapshot;
+        let mut ok = commit(
+            command,
+            Arc::make_mut(&mut copy),
+            prepared,
+            state.info.quotas,
+        )?;
+        if ok.attach_profiles {
+            ok.data = Some(copy);
+        }
+        return Ok(ok);
+    }
+    drop(snapshot);
+
+    let _mutation = state.mutations.lock().await;
+    let before = state.profiles_snapshot();
+    check_revision(&command, &before, expected_revision)?;
+    let end_reason = temporary::end_reason(&command);
+    let signs_in = matches!(
+        command,
+        Command::ChangePlayer { .. } | Command::ClearCurrentPlayer { .. }
+    );
+    // A commit that fails leaves the copy half changed; it is dropped unpublished
+    let mut snapshot = Arc::clone(&before);
+    let mut ok = commit(
+        command,
+        Arc::make_mut(&mut snapshot),
+        prepared,
+        state.info.quotas,
+    )?;
+    let ended = temporary::ended(&before, &snapshot, end_reason);
+    if *snapshot != *before {
+        Arc::make_mut(&mut snapshot).bump_revision(&before);
+    }
+    // Before publishing, so the idle task never sees the new player with the old session
+    state.idle.follow(&snapshot, signs_in);
+    state.publish_profiles(snapshot);
+    for event in ended {
+        state.publish(event);
+    }
+    if ok.attach_profiles {
+        ok.data = Some(state.profiles_snapshot());
+    }
+    Ok(ok)
+}
+
+// ProposeThresholdChange, ApproveProposal and RejectProposal, which record who sent them.
+// An approval writes the threshold like UpdateThreshold, at the revision the profile had
+// when it was proposed unless forced.
+async fn handle_proposal(
+    command: Command,
+    state: &AppState,
+    dry_run: bool,
+    expected_revision: Option<u64>,
+    origin: Origin,
+) -> Result<OkPayload, AppError> {
+    let snapshot = state.profiles_snapshot();
+    check_revision(&command, &snapshot, expected_revision)?;
+    match command {
+        Command::ProposeThresholdChange {
+            profile,
+            index,
+            value,
+            note,
+        } => {
+            let Some(current) = snapshot.profiles.get(&profile) else {
+                return Err(ValidationError::ProfileNotFound(profile).into());
+            };
+            if usize::from(index) >= current.thresholds.len() {
+                return Err(ValidationError::ThresholdIndex {
+                    max: current.thresholds.len() - 1,
+                }
+                .into());
+            }
+            state.range.bound().check(value)?;
+            proposal::check_note(note.as_deref())?;
+            let proposal = Proposal {
+                id: 0,
+                current: current.thresholds[usize::from(index)],
+                revision: snapshot.revision_of(Some(&profile)),
+                profile,
+                index,
+                value,
+                note,
+                proposed_at: Utc::now(),
+                proposed_by: origin,
+                status: ProposalStatus::Pending,
+                decided_at: None,
+                decided_by: None,
+                reason: None,
+            };
+            let proposal = match dry_run {
+                true => proposal,
+                false => {
+                    let proposal = state.proposals.create(proposal).await?;
+                    state.publish(Event::ProposalCreated(Arc::new(proposal.clone())));
+                    proposal
+                }
+            };
+            Ok(OkPayload {
+                code: "PROPOSAL_CREATED",
+                params: Some(serde_json::json!({
+                    "id": proposal.id,
+                    "profile": proposal.profile,
+                    "index": proposal.index,
+                    "value": proposal.value,
+                })),
+                message: format!(
+                    "Proposed threshold {} of profile {} go from {} to {}, waiting for approval",
+                    proposal.index, proposal.profile, proposal.current, proposal.value
+                ),
+                payload: Some(serde_json::json!({ "proposal": proposal })),
+                ..OkPayload::default()
+            })
+        }
+        Command::ApproveProposal { id, force } => {
+            // Held until the decision is recorded, so a proposal is never approved twice
+            let mut proposals = state.proposals.lock().await;
+            let proposal = proposals.get(id)?.clone();
+            let stale = |current_revision| {
+                AppError::from(ValidationError::ProposalStale {
+                    id,
+                    profile: proposal.profile.clone(),
+                    revision: proposal.revision,
+                    current_revision,
+                })
+            };
+            let revision = snapshot.revision_of(Some(&proposal.profile));
+            if revision != proposal.revision && !force {
+                return Err(stale(revision));
+            }
+            drop(snapshot);
+            let update = Command::UpdateThreshold {
+                profile_name: proposal.profile.clone(),
+                threshold_index: proposal.index,
+                value: proposal.value,
+            };
+            let expected = (!force).then_some(proposal.revision);
+            let updated = match execute(update, state, dry_run, expected).await {
+                Err(AppError::Validation(ValidationError::Conflict { revision, .. })) => {
+                    return Err(stale(revision));
+                }
+                updated => updated?,
+            };
+            if !dry_run {
+                state
+                    .proposals
+                    .decide(&mut proposals, id, ProposalStatus::Approved, origin, None)
+                    .await?;
+            }
+            Ok(OkPayload {
+                code: "PROPOSAL_APPROVED",
+                params: Some(serde_json::json!({
+                    "id": id,
+                    "profile": proposal.profile,
+                    "index": proposal.index,
+                    "value": proposal.value,
+                    "forced": force && revision != proposal.revision,
+                })),
+                message: format!("Approved proposal {}: {}", id, updated.message),
+                ..updated
+            })
+        }
+        Command::RejectProposal { id, reason } => {
+            proposal::check_note(reason.as_deref())?;
+            let mut proposals = state.proposals.lock().await;
+            let proposal = match dry_run {
+                true => proposals.get(id)?.clone(),
+                false => {
+                    state
+                        .proposals
+                        .decide(&mut proposals, id, ProposalStatus::Rejected, origin, reason)
+                        .await?
+                }
+            };
+            Ok(OkPayload {
+                code: "PROPOSAL_REJECTED",
+                params: Some(serde_json::json!({
+                    "id": id,
+                    "profile": proposal.profile,
+                    "index": proposal.index,
+                    "value": proposal.value,
+                })),
+                message: format!(
+                    "Rejected proposal {} to set threshold {} of profile {} to {}",
+                    id, proposal.index, proposal.profile, proposal.value
+                ),
+                payload: Some(serde_json::json!({ "proposal": proposal })),
+                ..OkPayload::default()
+            })
+        }
+        _ => unreachable!("only proposal commands are handled here"),
+    }
+}
+
+// Apply a prepared command to the profiles
+fn commit(
+    command: Command,
+    profiles: &mut Profiles,
+    prepared: Prepared,
+    quotas: Quotas,
+) -> Result<OkPayload, AppError> {
+    match prepared {
+        Prepared::Commit(written) => apply(command, profiles, written, quotas),
+        Prepared::Assign(device, port) => assign_pad(command, profiles, device, port),
+        Prepared::Calibrate(gain, offset) => set_gain(command, profiles, gain, offset),
+        Prepared::Reload(loaded, path, repairs) => {
+            *profiles = *loaded;
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "path": path,
+                    "profiles": profiles.profiles.len(),
+                    "players": profiles.players.len(),
+                    "repairs": repairs.len(),
+                })),
+                payload: Some(serde_json::json!({ "repairs": repairs })),
+                ..OkPayload::with_profiles(
+                    "PROFILES_RELOADED",
+                    format!(
+                        "Loaded {} profile(s) and {} player(s) from {}",
+                        profiles.profiles.len(),
+                        profiles.players.len(),
+                        path
+                    ),
+                )
+            })
+        }
+        Prepared::Restore(restored, info) => {
+            *profiles = *restored;
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "id": info.id,
+                    "label": info.label,
+                    "profiles": profiles.profiles.len(),
+                    "players": profiles.players.len(),
+                })),
+                payload: serde_json::to_value(&info).ok(),
+                ..OkPayload::with_profiles(
+                    "SNAPSHOT_RESTORED",
+                    format!(
+                        "Restored snapshot '{}' from {}",
+                        info.label.as_deref().unwrap_or(&info.id),
+                        info.created_at.to_rfc3339()
+                    ),
+                )
+            })
+        }
+        Prepared::Threshold(value) => match command {
+            Command::UpdateThreshold {
+                profile_name,
+                threshold_index,
+                ..
+            } => {
+                let command = Command::UpdateThreshold {
+                    profile_name,
+                    threshold_index,
+                    value,
+                };
+                apply(command, profiles, None, quotas)
+            }
+            _ => unreachable!("only UpdateThreshold is coalesced"),
+        },
+        Prepared::Done(_) => unreachable!("answered by execute"),
+    }
+}
+
+// Validate a command and talk to the device; never holds the mutation lock. A dry run
+// stops where the device would be written.
+async fn prepare(
+    command: &Command,
+    state: &AppState,
+    profiles: &Profiles,
+    dry_run: bool,
+) -> Result<Prepared, AppError> {
+    let serial_port = &state.serial;
+
+    match command {
+        Command::UpdateThreshold {
+            profile_name,
+            threshold_index,
+            value,
+        } => {
+            let Some(profile) = profiles.profiles.get(profile_name) else {
+                return Err(ValidationError::ProfileNotFound(profile_name.clone()).into());
+            };
+            profiles.enabled_pad(None)?;
+            if usize::from(*threshold_index) >= profile.thresholds.len() {
+                return Err(ValidationError::ThresholdIndex {
+                    max: profile.thresholds.len() - 1,
+                }
+                .into());
+            }
+            layout::check_device(profile, state.range.sensors())?;
+            state.range.bound().check(*value)?;
+            if dry_run {
+                check_writable(state, SerialOp::SetThreshold)?;
+                return Ok(Prepared::Threshold(*value));
+            }
+            // While another command writes this threshold, only the newest value waiting
+            // is written after it, and the commands in between are answered with that value
+            let key = (profile_name.clone(), *threshold_index);
+            let mut lead = loop {
+                match state.coalescer.join(&key, *value) {
+                    Ticket::Lead(lead) => break lead,
+                    Ticket::Follow(written) => {
+                        // The write it waited for failed; try again on its own
+                        let Ok(Some(written)) = written.await else {
+                            continue;
+                        };
+                        return Ok(Prepared::Done(OkPayload {
+                            code: "THRESHOLD_COALESCED",
+                            params: Some(serde_json::json!({
+                                "profile": profile_name,
+                                "index": threshold_index,
+                                "value": value,
+                                "final": written,
+                            })),
+                            message: format!(
+                                "Threshold {} of profile {} was coalesced, the device was set to {}",
+                                threshold_index, profile_name, written
+                            ),
+                            ..OkPayload::default()
+                        }));
+                    }
+                }
+            };
+            let _queued = state.serial_queue.enter()?;
+            // Set the threshold on the serial device, on the sensor the panel is wired to
+            let physical = profiles
+                .sensor_map()
+                .physical(usize::from(*threshold_index));
+            let mut value = *value;
+            // As the server has it: the profile's value, then the one last written
+            let mut before =
+                profiles.profiles[profile_name].thresholds[usize::from(*threshold_index)];
+            loop {
+                if !state.coalescer.window().is_zero() {
+                    tokio::time::sleep(state.coalescer.window()).await;
+                }
+                value = lead.newer().unwrap_or(value);
+                let logged = state.threshold_writes.reason(WriteReason::UserCommand);
+                let written = set_threshold(serial_port, physical, Some(before), value, logged);
+                let written = written.await;
+                before = value;
+                state
+                    .metrics
+                    .serial_write(1, written)
+                    .map_err(AppError::serial(SerialOp::SetThreshold))?;
+                match lead.advance(value) {
+                    Some(newer) => value = newer,
+                    None => return Ok(Prepared::Threshold(value)),
+                }
+            }
+        }
+        Command::ChangeProfile { name, pad, .. }
+        | Command::ApplyTemporaryProfile { name, pad, .. } => {
+            let pad = profiles.enabled_pad(pad.as_deref())?;
+            select_profile(state, profiles, pad, name, dry_run).await
+        }
+        Command::RevertTemporaryProfile { pad } => {
+            let pad = profiles.pad(pad.as_deref())?;
+            let Some(temporary) = &pad.temporary else {
+                return Err(ValidationError::NoTemporaryProfile(pad.id.clone()).into());
+            };
+            // A disabled pad gets its profile when enabled again; one whose profile from
+            // before is gone keeps the temporary one
+            if !pad.enabled || !profiles.profiles.contains_key(&temporary.previous) {
+                return Ok(Prepared::Commit(None));
+            }
+            select_profile(state, profiles, pad, &temporary.previous, dry_run).await
+        }
+        Command::ChangePlayer { name, pad } => {
+            let pad = profiles.enabled_pad(pad.as_deref())?;
+            // A new player is created in apply, without touching the device
+            let Some(player) = profiles.players.get(name) else {
+                return Ok(Prepared::Commit(None));
+            };
+            // Player exists, switch to their profile
+            let Some(profile) = profiles.profiles.get(&player.profile) else {
+                return Err(ValidationError::PlayerProfileMissing {
+                    player: name.clone(),
+                    profile: player.profile.clone(),
+                }
+                .into());
+            };
+            if !profiles.drives_device(&pad.id) {
+                return Ok(Prepared::Commit(None));
+            }
+            layout::check_device(profile, state.range.sensors())?;
+            if dry_run {
+                check_writable(state, SerialOp::SetThresholds)?;
+                return Ok(Prepared::Commit(Some(profile.thresholds)));
+            }
+            let _queued = state.serial_queue.enter()?;
+            // Set the profile thresholds on the serial device
+            let physical = pad.sensor_map.to_physical(profile.thresholds);
+            write_thresholds(state, physical, WriteReason::ProfileSwitch)
+                .await
+                .map_err(AppError::serial(SerialOp::SetThresholds))?;
+            Ok(Prepared::Commit(Some(profile.thresholds)))
+        }
+        Command::GetCurrentThresholds => {
+            profiles.enabled_pad(None)?;
+            let Some(current_profile) = profiles.profiles.get(profiles.current_profile()) else {
+                return Err(ValidationError::NoCurrentProfile.into());
+            };
+            let _queued = state.serial_queue.enter()?;
+            // First, try to get current thresholds from the serial device
+            let read = get_current_thresholds_from_device(serial_port).await;
+            let sensor_map = profiles.sensor_map();
+            let device_thresholds = state
+                .metrics
+                .serial_read(read)
+                .map(|physical| {
+                    state.range.count(physical.len());
+                    sensor_map.to_logical(physical)
+                })
+                .map_err(AppError::serial(SerialOp::ReadThresholds))?;
+
+            // Check if device thresholds match profile thresholds
+            let params = serde_json::json!({
+                "profile": profiles.current_profile(),
+                "thresholds": current_profile.thresholds,
+            });
+            // Drift compensation moves the device away from the profile on purpose
+            let expected = state
+                .drift
+                .compensated(profiles.current_profile(), current_profile.thresholds);
+            if device_thresholds == expected {
+                state.health.set_device_out_of_sync(false);
+                return Ok(Prepared::Done(OkPayload {
+                    params: Some(params),
+                    ..OkPayload::with_profiles(
+                        "THRESHOLDS_IN_SYNC",
+                        format!(
+                            "Current thresholds for profile '{}': {:?} (device synchronized)",
+                            profiles.current_profile(),
+                            current_profile.thresholds
+                        ),
+                    )
+                }));
+            }
+
+            // Device thresholds don't match profile, fix them; a dry run only reports it. A
+            // profile for another number of sensors can't be, that's reported as it is.
+            layout::check_device(current_profile, state.range.sensors())?;
+            if !dry_run {
+                let physical = sensor_map.to_physical(current_profile.thresholds);
+                write_thresholds(state, physical, WriteReason::Resync)
+                    .await
+                    .map_err(|source| AppError::DeviceOutOfSync {
+                        device: device_thresholds[..].into(),
+                        profile: current_profile.thresholds[..].into(),
+                        source,
+                    })?;
+                state.journal.record(
+                    JournalKind::Resync,
+                    format!(
+                        "Device thresholds {:?} didn't match profile '{}' {:?}, rewrote them",
+                        device_thresholds,
+                        profiles.current_profile(),
+                        current_profile.thresholds
+                    ),
+                );
+            }
+            Ok(Prepared::Done(OkPayload {
+                params: Some(params),
+                ..OkPayload::with_profiles(
+                    "THRESHOLDS_RESYNCED",
+                    format!(
+                        "Current thresholds for profile '{}': {:?} (device was out of sync, now fixed)",
+                        profiles.current_profile(),
+                        current_profile.thresholds
+                    ),
+                )
+            }))
+        }
+        Command::StartSensorStream | Command::StopSensorStream => {
+            let enabled = matches!(command, Command::StartSensorStream);
+            // The state before this command, so clients can tell a no-op toggle
+            let was_enabled = if dry_run {
+                state.stream_enabled()
+            } else {
+                state.set_stream_enabled(enabled)
+            };
+            let (code, verb) = if enabled {
+                ("SENSOR_STREAM_STARTED", "started")
+            } else {
+                ("SENSOR_STREAM_STOPPED", "stopped")
+            };
+            let message = if was_enabled == enabled {
+                format!("Sensor stream {} (it already was)", verb)
+            } else {
+                format!("Sensor stream {}", verb)
+            };
+            Ok(Prepared::Done(OkPayload {
+                params: Some(serde_json::json!({
+                    "enabled": enabled,
+                    "changed": was_enabled != enabled,
+                })),
+                previous: Some(serde_json::json!({ "enabled": was_enabled })),
+                ..OkPayload::with_profiles(code, message)
+            }))
+        }
+        Command::GetStreamStatus => {
+            let status = stream_status(state);
+            Ok(Prepared::Done(OkPayload {
+                code: "STREAM_STATUS",
+                params: Some(serde_json::json!({
+                    "enabled": status.stream.enabled,
+                    "rate_hz": status.stream.rate_hz,
+                })),
+                message: if status.stream.enabled {
+                    format!(
+                        "Sensor stream running at {} Hz ({} Hz achieved)",
+                        status.stream.effective_hz, status.stream.achieved_hz
+                    )
+                } else {
+                    "Sensor stream stopped".to_string()
+                },
+                payload: serde_json::to_value(status).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::GetWebhookStatus => {
+            let status: Vec<_> = state
+                .sinks
+                .status()
+                .into_iter()
+                .filter_map(WebhookStatus::from_sink)
+                .collect();
+            Ok(Prepared::Done(OkPayload {
+                code: "WEBHOOK_STATUS",
+                params: Some(serde_json::json!({ "count": status.len() })),
+                message: format!("{} webhook(s) configured", status.len()),
+                payload: serde_json::to_value(status).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::ListSinks => {
+            let sinks = state.sinks.status();
+            let enabled = sinks.iter().filter(|sink| sink.enabled).count();
+            Ok(Prepared::Done(OkPayload {
+                code: "SINKS",
+                params: Some(serde_json::json!({
+                    "count": sinks.len(),
+                    "enabled": enabled,
+                })),
+                message: format!("{} notification sink(s), {} enabled", sinks.len(), enabled),
+                payload: Some(serde_json::json!({ "sinks": sinks })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::EnableSink { name } | Command::DisableSink { name } => {
+            let enabled = matches!(command, Command::EnableSink { .. });
+            let was_enabled = match dry_run {
+                true => state.sinks.get(name)?.enabled,
+                false => state.sinks.set_enabled(name, enabled)? != enabled,
+            };
+            Ok(Prepared::Done(OkPayload {
+                code: if enabled {
+                    "SINK_ENABLED"
+                } else {
+                    "SINK_DISABLED"
+                },
+                params: Some(serde_json::json!({
+                    "sink": name,
+                    "changed": was_enabled != enabled,
+                })),
+                previous: Some(serde_json::json!({ "enabled": was_enabled })),
+                message: match enabled {
+                    true => format!("Notification sink {} enabled", name),
+                    false => format!(
+                        "Notification sink {} disabled, no events are queued for it",
+                        name
+                    ),
+                },
+                payload: serde_json::to_value(state.sinks.get(name)?).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::GetServerStats => {
+            let stats = state.metrics.snapshot();
+            Ok(Prepared::Done(OkPayload {
+                code: "SERVER_STATS",
+                params: Some(serde_json::json!({
+                    "uptime_secs": stats.uptime_secs,
+                    "commands": stats.commands.values().sum::<u64>(),
+                })),
+                message: format!(
+                    "Up {}s, {} command(s) handled",
+                    stats.uptime_secs,
+                    stats.commands.values().sum::<u64>()
+                ),
+                payload: serde_json::to_value(stats).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::GetSerialStats => {
+            let stats = state.serial_queue.stats();
+            Ok(Prepared::Done(OkPayload {
+                code: "SERIAL_STATS",
+                params: Some(serde_json::json!({
+                    "depth": stats.depth,
+                    "max_depth": stats.max_depth,
+                    "limit": stats.limit,
+                })),
+                message: format!(
+                    "{} of {} serial queue slot(s) in use, at most {} so far",
+                    stats.depth, stats.limit, stats.max_depth
+                ),
+                payload: serde_json::to_value(stats).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::GetLatencyStats => {
+            let stats = state.latency.stats();
+            Ok(Prepared::Done(OkPayload {
+                code: "LATENCY_STATS",
+                params: Some(latency_params(&stats)),
+                message: latency_message(&stats),
+                payload: serde_json::to_value(stats).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::ResetLatencyStats => {
+            let stats = state.latency.reset();
+            Ok(Prepared::Done(OkPayload {
+                code: "LATENCY_STATS_RESET",
+                params: Some(latency_params(&stats)),
+                message: format!("Latency stats reset; before: {}", latency_message(&stats)),
+                payload: serde_json::to_value(stats).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::RunSelfTest => {
+            let report = self_test::run(state).await;
+            let failed = report.failed();
+            Ok(Prepared::Done(OkPayload {
+                code: if report.passed {
+                    "SELF_TEST_PASSED"
+                } else {
+                    "SELF_TEST_FAILED"
+                },
+                params: Some(serde_json::json!({
+                    "checks": report.checks.len(),
+                    "failed": failed,
+                })),
+                message: match failed.is_empty() {
+                    true => format!("Self-test passed {} checks", report.checks.len()),
+                    false => format!("Self-test failed: {}", failed.join(", ")),
+                },
+                payload: serde_json::to_value(report).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::GetClients => {
+            let clients = state.clients.status();
+            Ok(Prepared::Done(OkPayload {
+                code: "CLIENTS",
+                params: Some(serde_json::json!({ "count": clients.len() })),
+                message: format!("{} client(s) connected", clients.len()),
+                payload: serde_json::to_value(clients).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::GetServerInfo => {
+            let info = state.server_info();
+            Ok(Prepared::Done(OkPayload {
+                code: "SERVER_INFO",
+                params: Some(serde_json::json!({
+                    "version": info.version,
+                    "protocol_version": info.protocol_version,
+                })),
+                message: format!(
+                    "fsr-rs {} ({}), protocol {}",
+                    info.version, info.git_hash, info.protocol_version
+                ),
+                payload: serde_json::to_value(info).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::SetAutosave { enabled } => {
+            let enabled = *enabled;
+            let was_enabled = if dry_run {
+                state.saves.autosave()
+            } else {
+                state.saves.set_autosave(enabled)
+            };
+            let pending = state
+                .saves
+                .pending(state.profiles_generation.load(Ordering::Relaxed));
+            Ok(Prepared::Done(OkPayload {
+                code: "AUTOSAVE_SET",
+                params: Some(serde_json::json!({
+                    "enabled": enabled,
+                    "changed": was_enabled != enabled,
+                })),
+                previous: Some(serde_json::json!({ "enabled": was_enabled })),
+                message: match (enabled, pending) {
+                    (true, 0) => "Autosave on".to_string(),
+                    (true, pending) => format!("Autosave on, saving {} change(s)", pending),
+                    (false, _) => "Autosave off, send SaveProfiles to save".to_string(),
+                },
+                ..OkPayload::default()
+            }))
+        }
+        Command::SaveProfiles => {
+            let report = state.saves.save_now().await?;
+            Ok(Prepared::Done(OkPayload {
+                code: "PROFILES_SAVED",
+                params: Some(serde_json::json!({
+                    "path": report.path,
+                    "bytes": report.bytes,
+                })),
+                message: format!("Saved {} bytes to {}", report.bytes, report.path),
+                payload: serde_json::to_value(report).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::RetryLoadProfiles => {
+            let Some(failure) = state.load_failure() else {
+                return Err(AppError::InvalidCommand(
+                    "profiles.json was loaded on startup, there is nothing to retry".to_string(),
+                ));
+            };
+            let mut loaded = match load_profiles_from(Path::new(&failure.path)).await {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    // Still broken; report where it is broken now
+                    if let (StorageError::Parse(error), false) = (&e, dry_run) {
+                        state.set_load_failure(Some(LoadFailure::new(error, failure.path)));
+                    }
+                    return Err(e.into());
+                }
+            };
+            let repairs = repair::repair(&mut loaded);
+            repair::report(&repairs);
+            if !dry_run {
+                apply_reloaded(state, &loaded).await;
+                // Committing a reload can't fail
+                state.set_load_failure(None);
+                if !repairs.is_empty() {
+                    state.publish(Event::ProfilesRepaired(repairs.clone().into()));
+                }
+            }
+            Ok(Prepared::Reload(Box::new(loaded), failure.path, repairs))
+        }
+        Command::GetErrorLog { limit } => {
+            let entries = state
+                .journal
+                .tail(limit.unwrap_or(DEFAULT_LOG_LIMIT))
+                .await
+                .map_err(StorageError::JournalRead)?;
+            Ok(Prepared::Done(OkPayload {
+                code: "ERROR_LOG",
+                params: Some(serde_json::json!({ "count": entries.len() })),
+                message: format!("{} journal entries", entries.len()),
+                payload: Some(serde_json::json!({
+                    "entries": entries,
+                    "dropped": state.journal.dropped(),
+                })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::GetThresholdWriteLog { limit } => {
+            let writes = state
+                .threshold_writes
+                .tail(limit.unwrap_or(DEFAULT_WRITE_LOG_LIMIT));
+            Ok(Prepared::Done(OkPayload {
+                code: "THRESHOLD_WRITE_LOG",
+                params: Some(serde_json::json!({ "count": writes.len() })),
+                message: format!("{} threshold write(s)", writes.len()),
+                payload: Some(serde_json::json!({ "writes": writes })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::GetCommandHistory { limit, filter } => {
+            let entries = state
+                .audit
+                .tail(limit.unwrap_or(DEFAULT_HISTORY_LIMIT), filter.as_deref());
+            Ok(Prepared::Done(OkPayload {
+                code: "COMMAND_HISTORY",
+                params: Some(serde_json::json!({ "count": entries.len() })),
+                message: format!("{} command(s)", entries.len()),
+                payload: Some(serde_json::json!({
+                    "entries": entries,
+                    "dropped": state.audit.dropped(),
+                })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::StartSerialCapture { path_hint } => {
+            let status = state.capture.start(path_hint.as_deref())?;
+            Ok(Prepared::Done(OkPayload {
+                code: "CAPTURE_STARTED",
+                params: Some(serde_json::json!({ "path": status.path })),
+                message: format!(
+                    "Capturing serial traffic to {}",
+                    status.path.as_deref().unwrap_or(Path::new("")).display()
+                ),
+                payload: serde_json::to_value(status).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::StopSerialCapture => {
+            let status = state.capture.stop().await?;
+            Ok(Prepared::Done(OkPayload {
+                code: "CAPTURE_STOPPED",
+                params: Some(serde_json::json!({
+                    "path": status.path,
+                    "bytes_written": status.bytes_written,
+                })),
+                message: format!(
+                    "Serial capture stopped, {} bytes written to {}",
+                    status.bytes_written,
+                    status.path.as_deref().unwrap_or(Path::new("")).display()
+                ),
+                payload: serde_json::to_value(status).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::IdentifyPad { pad, force } => {
+            let pad = profiles.enabled_pad(pad.as_deref())?;
+            let drives_device = profiles.drives_device(&pad.id);
+            // Presses are only seen on this server's device
+            if let Some(pressed) = state.latest.since_press() {
+                if drives_device && !force && pressed < SESSION_IDLE {
+                    return Err(ValidationError::PadInSession {
+                        pad: pad.id.clone(),
+                        pressed_ms: pressed.as_millis() as u64,
+                    }
+                    .into());
+                }
+            }
+            state.publish(Event::Identify {
+                pad: pad.id.as_str().into(),
+                duration: IDENTIFY_DURATION,
+            });
+            let (code, params, message) = if drives_device {
+                let sensor = pad.sensor_map.physical(identify::IDENTIFY_SENSOR);
+                let prior = identify::wiggle(state, sensor).await?;
+                (
+                    "PAD_IDENTIFIED",
+                    serde_json::json!({
+                        "pad": pad.id,
+                        "sensor": identify::IDENTIFY_SENSOR,
+                        "threshold": prior,
+                        "duration_ms": IDENTIFY_DURATION.as_millis() as u64,
+                    }),
+                    format!(
+                        "Identified pad '{}' by dropping threshold {} from {} for {}ms",
+                        pad.id,
+                        identify::IDENTIFY_SENSOR,
+                        prior,
+                        IDENTIFY_DURATION.as_millis()
+                    ),
+                )
+            } else {
+                (
+                    "PAD_NOTIFIED",
+                    serde_json::json!({ "pad": pad.id }),
+                    format!(
+                        "Pad '{}' has no device on this server, only its UI was notified",
+                        pad.id
+                    ),
+                )
+            };
+            Ok(Prepared::Done(OkPayload {
+                code,
+                params: Some(params),
+                message,
+                pad: Some(pad.id.clone()),
+                ..OkPayload::default()
+            }))
+        }
+        Command::AssignPadPort {
+            pad,
+            port_or_device_id,
+        } => {
+            profiles.pad(Some(pad))?;
+            let ports = tokio::task::spawn_blocking(devices::scan)
+                .await
+                .unwrap_or_default();
+            // A device id is followed to whatever port it is on now; a port name also
+            // pins the device found on it, so the pad keeps it after a rename
+            let commit = match DeviceIdentity::parse(port_or_device_id) {
+                Some(device) => {
+                    let port = ports
+                        .iter()
+                        .find(|port| port.identity.as_ref() == Some(&device))
+                        .map(|port| port.name.clone());
+                    Prepared::Assign(Some(device), port)
+                }
+                None => {
+                    let device = ports
+                        .iter()
+                        .find(|port| &port.name == port_or_device_id)
+                        .and_then(|port| port.identity.clone());
+                    Prepared::Assign(device, Some(port_or_device_id.clone()))
+                }
+            };
+            Ok(commit)
+        }
+        Command::GetPadMapping => {
+            let ports = tokio::task::spawn_blocking(devices::scan)
+                .await
+                .unwrap_or_default();
+            let mapping = devices::resolve(&profiles.pads, &ports);
+            let connected = mapping
+                .iter()
+                .filter(|pad| pad.state == DeviceState::Connected)
+                .count();
+            Ok(Prepared::Done(OkPayload {
+                code: "PAD_MAPPING",
+                params: Some(serde_json::json!({
+                    "pads": mapping.len(),
+                    "connected": connected,
+                })),
+                message: format!("{} pad(s), {} connected", mapping.len(), connected),
+                payload: Some(serde_json::json!({ "pads": mapping })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::SetPadEnabled { pad, enabled } => {
+            let pad = profiles.pad(Some(pad))?;
+            if !enabled || pad.enabled || !profiles.drives_device(&pad.id) {
+                return Ok(Prepared::Commit(None));
+            }
+            // Coming back goes through the same device write as selecting the profile
+            let Some(profile) = profiles.profiles.get(&pad.current_profile) else {
+                return Ok(Prepared::Commit(None));
+            };
+            if dry_run {
+                check_writable(state, SerialOp::SetThresholds)?;
+                return Ok(Prepared::Commit(Some(profile.thresholds)));
+            }
+            let _queued = state.serial_queue.enter()?;
+            let physical = pad.sensor_map.to_physical(profile.thresholds);
+            write_thresholds(state, physical, WriteReason::ProfileSwitch)
+                .await
+                .map_err(AppError::serial(SerialOp::SetThresholds))?;
+            Ok(Prepared::Commit(Some(profile.thresholds)))
+        }
+        Command::SetSensorMap { pad, sensor_map } => {
+            let pad = profiles.enabled_pad(pad.as_deref())?;
+            let given = sensor_map.len();
+            let sensor_map = SensorMap::try_from(sensor_map.clone())?;
+            // The device keeps its thresholds per physical sensor, so they move with the map
+            let Some(profile) = profiles.profiles.get(&pad.current_profile) else {
+                return Ok(Prepared::Commit(None));
+            };
+            // A map that moves sensors covers each of the profile's
+            if !sensor_map.is_identity() && given != profile.thresholds.len() {
+                return Err(ValidationError::ThresholdCount {
+                    expected: profile.thresholds.len(),
+                    got: given,
+                }
+                .into());
+            }
+            if !profiles.drives_device(&pad.id) || sensor_map == pad.sensor_map {
+                return Ok(Prepared::Commit(None));
+            }
+            layout::check_device(profile, state.range.sensors())?;
+            if dry_run {
+                check_writable(state, SerialOp::SetThresholds)?;
+                return Ok(Prepared::Commit(Some(profile.thresholds)));
+            }
+            let _queued = state.serial_queue.enter()?;
+            let physical = sensor_map.to_physical(profile.thresholds);
+            write_thresholds(state, physical, WriteReason::SensorMap)
+                .await
+                .map_err(AppError::serial(SerialOp::SetThresholds))?;
+            Ok(Prepared::Commit(Some(profile.thresholds)))
+        }
+        Command::SetCalibration {
+            profile_name,
+            gain,
+            offset,
+        } => {
+            let Some(profile) = profiles.profiles.get(profile_name) else {
+                return Err(ValidationError::ProfileNotFound(profile_name.clone()).into());
+            };
+            // One value per sensor of the profile
+            let counts = [
+                gain.map(|gain| gain.len()),
+                offset.map(|offset| offset.len()),
+            ];
+            if let Some(got) = counts
+                .into_iter()
+                .flatten()
+                .find(|&got| got != profile.thresholds.len())
+            {
+                return Err(ValidationError::ThresholdCount {
+                    expected: profile.thresholds.len(),
+                    got,
+                }
+                .into());
+            }
+            if let Some(gain) = gain {
+                calibration::check_gain(gain)?;
+            }
+            Ok(Prepared::Commit(None))
+        }
+        Command::SetDriftCompensation { profile_name, .. } => {
+            if !profiles.profiles.contains_key(profile_name) {
+                return Err(ValidationError::ProfileNotFound(profile_name.clone()).into());
+            }
+            Ok(Prepared::Commit(None))
+        }
+        Command::SetPressParameters {
+            profile_name,
+            hysteresis,
+            ..
+        } => {
+            let Some(profile) = profiles.profiles.get(profile_name) else {
+                return Err(ValidationError::ProfileNotFound(profile_name.clone()).into());
+            };
+            press::check_hysteresis(*hysteresis, profile.thresholds)?;
+            Ok(Prepared::Commit(None))
+        }
+        Command::SetProfileTags { name, tags: given } => {
+            if !profiles.profiles.contains_key(name) {
+                return Err(ValidationError::ProfileNotFound(name.clone()).into());
+            }
+            tags::normalize(given.clone())?;
+            Ok(Prepared::Commit(None))
+        }
+        Command::ListProfiles { tag_filter } => {
+            let mut names: Vec<&String> = profiles
+                .profiles
+                .iter()
+                .filter(|(_, profile)| {
+                    tag_filter.as_deref().is_none_or(|filter| {
+                        profile.tags.iter().any(|tag| tags::matches(tag, filter))
+                    })
+                })
+                .map(|(name, _)| name)
+                .collect();
+            names.sort();
+            let listed: Vec<serde_json::Value> = names
+                .into_iter()
+                .map(|name| {
+                    let profile = &profiles.profiles[name];
+                    serde_json::json!({
+                        "name": name,
+                        "thresholds": profile.thresholds,
+                        "tags": profile.tags,
+                    })
+                })
+                .collect();
+            // Every tag in use, for grouping, in the spelling first seen by profile name
+            let mut all_tags: Vec<&str> = Vec::new();
+            let mut sorted: Vec<_> = profiles.profiles.iter().collect();
+            sorted.sort_by_key(|(name, _)| *name);
+            for tag in sorted.into_iter().flat_map(|(_, profile)| &profile.tags) {
+                if !all_tags.iter().any(|kept| tags::matches(kept, tag)) {
+                    all_tags.push(tag);
+                }
+            }
+            Ok(Prepared::Done(OkPayload {
+                code: "PROFILES_LISTED",
+                params: Some(serde_json::json!({
+                    "count": listed.len(),
+                    "tag_filter": tag_filter,
+                })),
+                message: match tag_filter {
+                    Some(filter) => format!("{} profile(s) tagged '{}'", listed.len(), filter),
+                    None => format!("{} profile(s)", listed.len()),
+                },
+                payload: Some(serde_json::json!({
+                    "profiles": listed,
+                    "tags": all_tags,
+                })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::ShareProfile { name } => {
+            let Some(profile) = profiles.profiles.get(name) else {
+                return Err(ValidationError::ProfileNotFound(name.clone()).into());
+            };
+            let code = share::encode(&SharedProfile::new(name, profile)).map_err(|e| {
+                AppError::InvalidCommand(format!("Profile '{}' can't be shared: {}", name, e))
+            })?;
+            Ok(Prepared::Done(OkPayload {
+                code: "PROFILE_SHARED",
+                params: Some(serde_json::json!({ "profile": name, "code": code })),
+                message: format!("Share code of profile '{}': {}", name, code),
+                payload: Some(serde_json::json!({ "code": code })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::ImportSharedProfile { code, .. } => {
+            let shared = share::decode(code).map_err(ValidationError::InvalidShareCode)?;
+            tags::normalize(shared.tags)?;
+            state.range.bound().check_all(&shared.thresholds)?;
+            layout::check(&shared.layout, shared.thresholds.len())?;
+            Ok(Prepared::Commit(None))
+        }
+        Command::ImportExternal {
+            format, payload, ..
+        } => {
+            for imported in parse_import(format, payload)? {
+                state.range.bound().check_all(&imported.thresholds)?;
+            }
+            Ok(Prepared::Commit(None))
+        }
+        Command::GetDriftCompensation => {
+            let profile = profiles.current_profile();
+            let profile_enabled = profiles
+                .profiles
+                .get(profile)
+                .is_some_and(|profile| profile.drift_compensation);
+            let report = state.drift.report(profile, profile_enabled);
+            let active = report.settings.enabled && report.profile_enabled;
+            Ok(Prepared::Done(OkPayload {
+                code: "DRIFT_COMPENSATION",
+                params: Some(serde_json::json!({
+                    "profile": profile,
+                    "active": active,
+                    "offsets": report.offsets,
+                })),
+                message: format!(
+                    "Drift compensation of profile '{}' is {}, offsets {:?}",
+                    profile,
+                    if active { "on" } else { "off" },
+                    report.offsets
+                ),
+                payload: serde_json::to_value(&report).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::CalibrateGain {
+            profile_name,
+            duration_ms,
+        } => {
+            let Some(profile) = profiles.profiles.get(profile_name) else {
+                return Err(ValidationError::ProfileNotFound(profile_name.clone()).into());
+            };
+            if !state.stream_enabled() {
+                return Err(ValidationError::StreamStopped.into());
+            }
+            let window = duration_ms
+                .map_or(CALIBRATION_WINDOW, Duration::from_millis)
+                .min(MAX_CALIBRATION_WINDOW);
+            let mut events = state.events.subscribe();
+            let peaks = calibration::peaks(&mut events, profiles.device_pad_id(), window).await;
+            let gain = calibration::derive_gain(profile, peaks)?;
+            Ok(Prepared::Calibrate(gain, profile.offset))
+        }
+        Command::StartPanelTest { timeout_ms } => {
+            let timeout = timeout_ms
+                .map_or(PANEL_TEST_TIMEOUT, Duration::from_millis)
+                .min(MAX_PANEL_TEST_TIMEOUT);
+            let plan = panel_test::start(state, timeout)?;
+            Ok(Prepared::Done(OkPayload {
+                code: "PANEL_TEST_STARTED",
+                params: Some(serde_json::json!({
+                    "pad": plan.pad,
+                    "profile": plan.profile,
+                    "timeout_ms": plan.timeout_ms,
+                    "stream_forced": plan.stream_forced,
+                })),
+                message: format!(
+                    "Panel test of pad '{}' started, press each panel in turn within {}ms",
+                    plan.pad, plan.timeout_ms
+                ),
+                payload: serde_json::to_value(&plan).ok(),
+                ..OkPayload::default()
+            }))
+        }
+        Command::CancelPanelTest => {
+            let pad = state.panel_test.cancel()?;
+            Ok(Prepared::Done(OkPayload {
+                code: "PANEL_TEST_CANCELLED",
+                params: Some(serde_json::json!({ "pad": pad })),
+                message: format!("Panel test of pad '{}' cancelled", pad),
+                ..OkPayload::default()
+            }))
+        }
+        Command::CreateSnapshot { label } => {
+            let device_thresholds = read_device_thresholds(state, profiles).await;
+            let (info, deleted) = state
+                .snapshots
+                .create(label.clone(), profiles, device_thresholds)
+                .await?;
+            Ok(Prepared::Done(OkPayload {
+                code: "SNAPSHOT_CREATED",
+                params: Some(serde_json::json!({
+                    "id": info.id,
+                    "label": info.label,
+                    "device_read": info.device_thresholds.is_some(),
+                    "deleted": deleted.len(),
+                })),
+                message: match &info.device_thresholds {
+                    Some(_) => format!("Snapshot '{}' created", info.id),
+                    None => format!(
+                        "Snapshot '{}' created without the device's thresholds, it didn't answer",
+                        info.id
+                    ),
+                },
+                payload: Some(serde_json::json!({ "snapshot": info, "deleted": deleted })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::RestoreSnapshot { id } => {
+            let snapshot = state.snapshots.load(id).await?;
+            let restored = snapshot.profiles;
+            // Put its current profile on the device first, so a failed write leaves
+            // everything as it was
+            let current = restored.profiles.get(restored.current_profile());
+            if let Some(profile) = current.filter(|_| restored.device_enabled()) {
+                if dry_run {
+                    check_writable(state, SerialOp::SetThresholds)?;
+                } else {
+                    let _queued = state.serial_queue.enter()?;
+                    let physical = restored.sensor_map().to_physical(profile.thresholds);
+                    write_thresholds(state, physical, WriteReason::Reload)
+                        .await
+                        .map_err(AppError::serial(SerialOp::SetThresholds))?;
+                }
+            }
+            Ok(Prepared::Restore(Box::new(restored), snapshot.info))
+        }
+        Command::ListSnapshots => {
+            let snapshots = state.snapshots.list().await?;
+            Ok(Prepared::Done(OkPayload {
+                code: "SNAPSHOTS",
+                params: Some(serde_json::json!({ "count": snapshots.len() })),
+                message: format!("{} snapshot(s)", snapshots.len()),
+                payload: Some(serde_json::json!({
+                    "snapshots": snapshots,
+                    "dir": state.snapshots.dir(),
+                })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::AddSchedule {
+            cron_or_time,
+            profile,
+            pad,
+        } => {
+            When::parse(cron_or_time)?;
+            if !profiles.profiles.contains_key(profile) {
+                return Err(ValidationError::ProfileNotFound(profile.clone()).into());
+            }
+            profiles.pad(pad.as_deref())?;
+            Ok(Prepared::Commit(None))
+        }
+        Command::ListSchedules => {
+            let now = Local::now();
+            let listed: Vec<serde_json::Value> = profiles
+                .schedules
+                .iter()
+                .map(|schedule| {
+                    let mut listed = serde_json::json!(schedule);
+                    listed["next_run"] = serde_json::json!(When::parse(&schedule.cron_or_time)
+                        .ok()
+                        .and_then(|when| when.next_run(now))
+                        .map(|next| next.to_rfc3339()));
+                    listed
+                })
+                .collect();
+            Ok(Prepared::Done(OkPayload {
+                code: "SCHEDULES",
+                params: Some(serde_json::json!({ "count": listed.len() })),
+                message: format!("{} schedule(s)", listed.len()),
+                payload: Some(serde_json::json!({
+                    "schedules": listed,
+                    "timezone": schedule::local_timezone(),
+                })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::RemoveSchedule { id } => {
+            if !profiles.schedules.iter().any(|schedule| schedule.id == *id) {
+                return Err(ValidationError::ScheduleNotFound(id.clone()).into());
+            }
+            Ok(Prepared::Commit(None))
+        }
+        Command::GetLayouts => Ok(Prepared::Done(OkPayload {
+            code: "LAYOUTS",
+            params: Some(serde_json::json!({ "count": LAYOUTS.len() })),
+            message: format!("{} layout(s)", LAYOUTS.len()),
+            payload: Some(serde_json::json!({
+                "layouts": LAYOUTS,
+                "device_sensors": state.range.sensors(),
+            })),
+            ..OkPayload::default()
+        })),
+        Command::ListPlayers {
+            offset,
+            limit,
+            filter,
+        } => {
+            let page = profiles.player_page(*offset, *limit, filter.as_deref());
+            Ok(Prepared::Done(OkPayload {
+                code: "PLAYERS",
+                params: Some(serde_json::json!({
+                    "count": page.players.len(),
+                    "total": page.total,
+                })),
+                message: format!("{} of {} player(s)", page.players.len(), page.total),
+                payload: Some(serde_json::json!(page)),
+                ..OkPayload::default()
+            }))
+        }
+        // Connections switch their serialization themselves, this only validates
+        Command::Hello { protocol, .. } => {
+            let protocol = compat::check(*protocol)?;
+            Ok(Prepared::Done(OkPayload {
+                code: "HELLO",
+                params: Some(serde_json::json!({ "protocol": protocol })),
+                message: format!("Speaking protocol version {}", protocol),
+                payload: Some(serde_json::json!({
+                    "protocol": protocol,
+                    "min_protocol_version": MIN_PROTOCOL_VERSION,
+                    "protocol_version": PROTOCOL_VERSION,
+                })),
+                ..OkPayload::default()
+            }))
+        }
+        // Connections handle Subscribe themselves, it has no meaning for a one-shot command
+        Command::Subscribe { .. } => Err(AppError::InvalidCommand(
+            "Subscribe is only supported on websocket and pipe connections".to_string(),
+        )),
+        Command::GetProfiles => Ok(Prepared::Done(OkPayload {
+            params: Some(serde_json::json!({
+                "profiles": profiles.profiles.len(),
+                "players": profiles.players.len(),
+            })),
+            ..OkPayload::with_profiles(
+                "PROFILES",
+                format!(
+                    "{} profile(s), {} player(s)",
+                    profiles.profiles.len(),
+                    profiles.players.len()
+                ),
+            )
+        })),
+        Command::ListProposals => {
+            let proposals = state.proposals.list().await;
+            let pending = proposals
+                .iter()
+                .filter(|proposal| proposal.status == ProposalStatus::Pending)
+                .count();
+            // A pending proposal is stale once its profile changed after it was made
+            let listed: Vec<_> = proposals
+                .iter()
+                .map(|proposal| {
+                    let mut listed = serde_json::to_value(proposal).unwrap_or_default();
+                    if proposal.status == ProposalStatus::Pending {
+                        let revision = profiles.revision_of(Some(&proposal.profile));
+                        listed["stale"] = (revision != proposal.revision).into();
+                    }
+                    listed
+                })
+                .collect();
+            Ok(Prepared::Done(OkPayload {
+                code: "PROPOSALS",
+                params: Some(serde_json::json!({
+                    "proposals": proposals.len(),
+                    "pending": pending,
+                })),
+                message: format!("{} proposal(s), {} pending", proposals.len(), pending),
+                payload: Some(serde_json::json!({ "proposals": listed })),
+                ..OkPayload::default()
+            }))
+        }
+        Command::ProposeThresholdChange { .. }
+        | Command::ApproveProposal { .. }
+        | Command::RejectProposal { .. } => unreachable!("answered by handle_proposal"),
+        Command::GetSensorValues => {
+            // The old one-shot read: the stream's frame when it is fresh, else the device
+            let (frame, cached) = read_current(state).await?;
+            let mut payload = frame.to_json();
+            payload["cached"] = cached.into();
+            Ok(Prepared::Done(OkPayload {
+                code: "SENSOR_VALUES",
+                message: format!("Sensor values: {:?}", frame.values),
+                payload: Some(payload),
+                sensor_values: Some(frame.values),
+                ..OkPayload::default()
+            }))
+        }
+        Command::ReadSensors => {
+            let frame = read_fresh(state).await?;
+            Ok(Prepared::Done(OkPayload {
+                code: "SENSOR_VALUES",
+                message: format!("Sensor values: {:?}", frame.values),
+                payload: Some(frame.to_json()),
+                ..OkPayload::default()
+            }))
+        }
+        Command::SetDefaultProfile { pad, .. } | Command::ClearCurrentPlayer { pad } => {
+            profiles.pad(pad.as_deref())?;
+            Ok(Prepared::Commit(None))
+        }
+        Command::AddProfile {
+            thresholds, layout, ..
+        } => {
+            state.range.bound().check_all(thresholds)?;
+            // A profile holds one threshold per device sensor, so its layout must match
+            if let Some(layout) = layout {
+                layout::check(layout, thresholds.len())?;
+            }
+            Ok(Prepared::Commit(None))
+        }
+        Command::RemoveProfile {
+            name,
+            confirm,
+            reassign_to,
+        } => {
+            let Some(target) = reassign_to.as_ref().filter(|_| *confirm) else {
+                return Ok(Prepared::Commit(None));
+            };
+            if target == name {
+                return Err(AppError::InvalidCommand(format!(
+                    "reassign_to must name a profile other than '{}'",
+                    name
+                )));
+            }
+            let Some(profile) = profiles.profiles.get(target) else {
+                return Err(ValidationError::ProfileNotFound(target.clone()).into());
+            };
+            // The pad on this device moves to the profile its players are reassigned to
+            let Some(pad) = profiles
+                .pads
+                .first()
+                .filter(|pad| pad.current_profile == *name && pad.enabled)
+            else {
+                return Ok(Prepared::Commit(None));
+            };
+            layout::check_device(profile, state.range.sensors())?;
+            if dry_run {
+                check_writable(state, SerialOp::SetThresholds)?;
+                return Ok(Prepared::Commit(Some(profile.thresholds)));
+            }
+            let _queued = state.serial_queue.enter()?;
+            let physical = pad.sensor_map.to_physical(profile.thresholds);
+            write_thresholds(state, physical, WriteReason::ProfileSwitch)
+                .await
+                .map_err(AppError::serial(SerialOp::SetThresholds))?;
+            Ok(Prepared::Commit(Some(profile.thresholds)))
+        }
+    }
+}
+
+// The profiles of an ImportExternal payload
+fn parse_import(format: &str, payload: &str) -> Result<Vec<ImportedProfile>, ValidationError> {
+    import::parse(format, payload)
+        .ok_or_else(|| ValidationError::UnknownImportFormat(format.to_string()))?
+        .map_err(ValidationError::InvalidImport)
+}
+
+// Commit a prepared command to the latest profiles; runs under the mutation lock
+fn apply(
+    command: Command,
+    profiles: &mut Profiles,
+    written: Option<Sensors<i32>>,
+    quotas: Quotas,
+) -> Result<OkPayload, AppError> {
+    // The profile the device was set to must still have the thresholds that were written.
+    // Nothing is written for a pad without a device here, but the profile must still exist.
+    let check_written =
+        |profiles: &Profiles, name: &str| match (profiles.profiles.get(name), written) {
+            (Some(profile), Some(thresholds)) if profile.thresholds == thresholds => Ok(()),
+            (Some(_), None) => Ok(()),
+            _ => Err(AppError::from(ValidationError::ConcurrentChange(
+                name.to_string(),
+            ))),
+        };
+
+    match command {
+        Command::UpdateThreshold {
+            profile_name,
+            threshold_index,
+            value,
+        } => {
+            // Threshold was successfully set on the device, now update the profile
+            let threshold_index = usize::from(threshold_index);
+            let Some(profile) = profiles.profiles.get_mut(&profile_name) else {
+                return Err(ValidationError::ConcurrentChange(profile_name).into());
+            };
+            let previous = std::mem::replace(&mut profile.thresholds[threshold_index], value);
+            let label = layout::label(&profile.layout, threshold_index);
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "profile": profile_name,
+                    "index": threshold_index,
+                    "label": label,
+                    "value": value,
+                })),
+                previous: Some(serde_json::json!({ "value": previous })),
+                ..OkPayload::with_profiles(
+                    "THRESHOLD_UPDATED",
+                    format!(
+                        "Updated threshold {} ({}) to {} for profile {} and serial device",
+                        threshold_index, label, value, profile_name
+                    ),
+                )
+            })
+        }
+        Command::AddProfile {
+            name,
+            thresholds,
+            layout,
+        } => {
+            if profiles.profiles.contains_key(&name) {
+                return Err(ValidationError::ProfileExists(name).into());
+            }
+            quotas.check_new_profile(profiles)?;
+            let mut profile = Profile::new(thresholds);
+            if let Some(layout) = layout {
+                profile.layout = layout;
+            }
+            profiles.profiles.insert(name.clone(), profile);
+            let pad = profiles.device_pad_mut();
+            if pad.current_profile.is_empty() {
+                pad.current_profile = name.clone();
+            }
+            Ok(OkPayload {
+                params: Some(serde_json::json!({ "profile": name })),
+                ..OkPayload::with_profiles("PROFILE_ADDED", format!("Added profile '{}'", name))
+            })
+        }
+        Command::ImportSharedProfile { code, rename_to } => {
+            let shared = share::decode(&code).map_err(ValidationError::InvalidShareCode)?;
+            let name = rename_to.unwrap_or(shared.name);
+            let add = Command::AddProfile {
+                name: name.clone(),
+                thresholds: shared.thresholds,
+                layout: Some(shared.layout),
+            };
+            let added = apply(add, profiles, None, quotas)?;
+            if let Some(profile) = profiles.profiles.get_mut(&name) {
+                profile.tags = tags::normalize(shared.tags)?;
+            }
+            Ok(OkPayload {
+                code: "PROFILE_IMPORTED",
+                params: Some(serde_json::json!({
+                    "profile": name,
+                    "thresholds": shared.thresholds,
+                })),
+                message: format!(
+                    "Imported profile '{}' with thresholds {:?}",
+                    name, shared.thresholds
+                ),
+                ..added
+            })
+        }
+        Command::ImportExternal {
+            format,
+            payload,
+            on_conflict,
+        } => {
+            // Names are settled before anything is added, so a conflict or the quota leaves
+            // the profiles as they were
+            let mut added: Vec<(String, ImportedProfile)> = Vec::new();
+            let mut skipped = Vec::new();
+            for imported in parse_import(&format, &payload)? {
+                let taken = |name: &str| {
+                    profiles.profiles.contains_key(name)
+                        || added.iter().any(|(added, _)| added == name)
+                };
+                let name = match on_conflict {
+                    _ if !taken(&imported.name) => imported.name.clone(),
+                    OnConflict::Fail => {
+                        return Err(ValidationError::ProfileExists(imported.name).into())
+                    }
+                    OnConflict::Skip => {
+                        skipped.push(imported.name);
+                        continue;
+                    }
+                    OnConflict::Rename => import::free_name(&imported.name, taken),
+                };
+                added.push((name, imported));
+            }
+            quotas.check_new_profiles(profiles, added.len())?;
+            let renamed: serde_json::Map<String, serde_json::Value> = added
+                .iter()
+                .filter(|(name, imported)| *name != imported.name)
+                .map(|(name, imported)| (imported.name.clone(), name.as_str().into()))
+                .collect();
+            let names: Vec<String> = added.iter().map(|(name, _)| name.clone()).collect();
+            for (name, imported) in added {
+                profiles
+                    .profiles
+                    .insert(name, Profile::new(imported.thresholds));
+            }
+            let pad = profiles.device_pad_mut();
+            if pad.current_profile.is_empty() {
+                if let Some(first) = names.first() {
+                    pad.current_profile = first.clone();
+                }
+            }
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "format": format,
+                    "imported": names,
+                    "renamed": renamed,
+                    "skipped": skipped,
+                })),
+                ..OkPayload::with_profiles(
+                    "PROFILES_IMPORTED",
+                    format!(
+                        "Imported {} profile(s) from {} format, skipped {}",
+                        names.len(),
+                        format,
+                        skipped.len()
+                    ),
+                )
+            })
+        }
+        Command::SetPadEnabled { pad, enabled } => {
+            if written.is_some() {
+                let current_profile = profiles.pad(Some(&pad))?.current_profile.clone();
+                check_written(profiles, &current_profile)?;
+            }
+            profiles.pad_mut(Some(&pad))?.enabled = enabled;
+            Ok(OkPayload {
+                code: "PAD_ENABLED_CHANGED",
+                params: Some(serde_json::json!({ "pad": pad, "enabled": enabled })),
+                message: format!(
+                    "Pad '{}' {}",
+                    pad,
+                    if enabled { "enabled" } else { "disabled" }
+                ),
+                pad: Some(pad),
+                attach_profiles: true,
+                ..OkPayload::default()
+            })
+        }
+        Command::SetSensorMap { pad, sensor_map } => {
+            if written.is_some() {
+                let current_profile = profiles.pad(pad.as_deref())?.current_profile.clone();
+                check_written(profiles, &current_profile)?;
+            }
+            let target = profiles.pad_mut(pad.as_deref())?;
+            target.sensor_map = SensorMap::try_from(sensor_map.clone())?;
+            let id = target.id.clone();
+            Ok(OkPayload {
+                code: "SENSOR_MAP_SET",
+                params: Some(serde_json::json!({ "pad": id, "sensor_map": sensor_map })),
+                message: format!("Set sensor map of pad '{}' to {:?}", id, sensor_map),
+                pad: Some(id),
+                attach_profiles: true,
+                ..OkPayload::default()
+            })
+        }
+        Command::SetCalibration {
+            profile_name,
+            gain,
+            offset,
+        } => {
+            let Some(profile) = profiles.profiles.get_mut(&profile_name) else {
+                return Err(ValidationError::ConcurrentChange(profile_name).into());
+            };
+            // The identity calibration is the same as none
+            if let Some(gain) = gain {
+                profile.gain = Some(gain).filter(|gain| gain.iter().any(|&gain| gain != 1.0));
+            }
+            if let Some(offset) = offset {
+                profile.offset =
+                    Some(offset).filter(|offset| offset.iter().any(|&offset| offset != 0));
+            }
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "profile": profile_name,
+                    "gain": profile.gain,
+                    "offset": profile.offset,
+                })),
+                ..OkPayload::with_profiles(
+                    "CALIBRATION_SET",
+                    format!(
+                        "Set calibration of profile '{}': gain {:?}, offset {:?}",
+                        profile_name, profile.gain, profile.offset
+                    ),
+                )
+            })
+        }
+        Command::SetDriftCompensation {
+            profile_name,
+            enabled,
+        } => {
+            let Some(profile) = profiles.profiles.get_mut(&profile_name) else {
+                return Err(ValidationError::ConcurrentChange(profile_name).into());
+            };
+            profile.drift_compensation = enabled;
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "profile": profile_name,
+                    "enabled": enabled,
+                })),
+                ..OkPayload::with_profiles(
+                    "DRIFT_COMPENSATION_SET",
+                    format!(
+                        "Turned drift compensation of profile '{}' {}",
+                        profile_name,
+                        if enabled { "on" } else { "off" }
+                    ),
+                )
+            })
+        }
+        Command::SetPressParameters {
+            profile_name,
+            hysteresis,
+            debounce_ms,
+        } => {
+            let Some(profile) = profiles.profiles.get_mut(&profile_name) else {
+                return Err(ValidationError::ConcurrentChange(profile_name).into());
+            };
+            // The thresholds may have changed since prepare
+            press::check_hysteresis(hysteresis, profile.thresholds)?;
+            profile.hysteresis = hysteresis;
+            profile.debounce_ms = debounce_ms;
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "profile": profile_name,
+                    "hysteresis": hysteresis,
+                    "debounce_ms": debounce_ms,
+                })),
+                ..OkPayload::with_profiles(
+                    "PRESS_PARAMETERS_SET",
+                    format!(
+                        "Set press parameters of profile '{}': hysteresis {:?}, debounce {}ms",
+                        profile_name, hysteresis, debounce_ms
+                    ),
+                )
+            })
+        }
+        Command::AddSchedule {
+            cron_or_time,
+            profile,
+            pad,
+        } => {
+            if !profiles.profiles.contains_key(&profile) {
+                return Err(ValidationError::ConcurrentChange(profile).into());
+            }
+            let schedule = Schedule {
+                id: schedule::next_id(&profiles.schedules),
+                cron_or_time: cron_or_time.trim().to_string(),
+                profile,
+                pad,
+            };
+            let next_run = When::parse(&schedule.cron_or_time)?
+                .next_run(Local::now())
+                .map(|next| next.to_rfc3339());
+            let message = format!(
+                "Scheduled profile '{}' at {} ({})",
+                schedule.profile,
+                schedule.cron_or_time,
+                next_run.as_deref().unwrap_or("never runs")
+            );
+            let mut params = serde_json::json!(schedule);
+            params["next_run"] = serde_json::json!(next_run);
+            profiles.schedules.push(schedule);
+            Ok(OkPayload {
+                params: Some(params),
+                ..OkPayload::with_profiles("SCHEDULE_ADDED", message)
+            })
+        }
+        Command::RemoveSchedule { id } => {
+            let Some(index) = profiles
+                .schedules
+                .iter()
+                .position(|schedule| schedule.id == id)
+            else {
+                return Err(ValidationError::ScheduleNotFound(id).into());
+            };
+            let removed = profiles.schedules.remove(index);
+            Ok(OkPayload {
+                params: Some(serde_json::json!({ "id": id })),
+                previous: Some(serde_json::json!(removed)),
+                ..OkPayload::with_profiles(
+                    "SCHEDULE_REMOVED",
+                    format!(
+                        "Removed schedule {} (profile '{}' at {})",
+                        id, removed.profile, removed.cron_or_time
+                    ),
+                )
+            })
+        }
+        Command::SetProfileTags { name, tags: given } => {
+            let tags = tags::normalize(given)?;
+            let Some(profile) = profiles.profiles.get_mut(&name) else {
+                return Err(ValidationError::ConcurrentChange(name).into());
+            };
+            let previous = std::mem::replace(&mut profile.tags, tags.clone());
+            Ok(OkPayload {
+                params: Some(serde_json::json!({ "profile": name, "tags": tags })),
+                previous: Some(serde_json::json!({ "tags": previous })),
+                ..OkPayload::with_profiles(
+                    "PROFILE_TAGS_SET",
+                    format!("Set tags of profile '{}': {:?}", name, tags),
+                )
+            })
+        }
+        Command::RemoveProfile {
+            name,
+            confirm,
+            reassign_to,
+        } => {
+            if !profiles.profiles.contains_key(&name) {
+                return Err(ValidationError::ProfileNotFound(name).into());
+            }
+            let mut players: Vec<String> = profiles
+                .players
+                .values()
+                .filter(|player| player.profile == name)
+                .map(|player| player.name.clone())
+                .collect();
+            players.sort();
+            let Some(target) = reassign_to.filter(|_| confirm) else {
+                if !players.is_empty() {
+                    return Err(ValidationError::ConfirmationRequired {
+                        profile: name,
+                        players,
+                    }
+                    .into());
+                }
+                // Also the profile a pad switches back to from a temporary one
+                let in_use = profiles.pads.iter().any(|pad| {
+                    pad.current_profile == name
+                        || pad
+                            .temporary
+                            .as_ref()
+                            .is_some_and(|temporary| temporary.previous == name)
+                });
+                if in_use {
+                    return Err(ValidationError::RemoveCurrentProfile.into());
+                }
+                // Defaults naming it are cleared rather than left dangling
+                let default_cleared = profiles.default_profile == name;
+                if default_cleared {
+                    profiles.default_profile.clear();
+                }
+                for pad in &mut profiles.pads {
+                    pad.default_profile.take_if(|default| *default == name);
+                }
+                let removed = profiles.profiles.remove(&name);
+                return Ok(OkPayload {
+                    params: Some(serde_json::json!({
+                        "profile": name,
+                        "default_cleared": default_cleared,
+                    })),
+                    previous: Some(serde_json::json!({ "profile": name, "data": removed })),
+                    ..OkPayload::with_profiles(
+                        "PROFILE_REMOVED",
+                        if default_cleared {
+                            format!(
+                                "Removed profile '{}'; it was the default profile, which is now unset",
+                                name
+                            )
+                        } else {
+                            format!("Removed profile '{}'", name)
+                        },
+                    )
+                });
+            };
+            // Everything pointing at the profile moves to `target` in the same commit
+            check_written(profiles, &target)?;
+            for player in profiles.players.values_mut() {
+                if player.profile == name {
+                    player.profile = target.clone();
+                }
+            }
+            for pad in &mut profiles.pads {
+                if pad.current_profile == name {
+                    pad.current_profile = target.clone();
+                }
+                if pad.default_profile.as_ref() == Some(&name) {
+                    pad.default_profile = Some(target.clone());
+                }
+                if let Some(temporary) = pad
+                    .temporary
+                    .as_mut()
+                    .filter(|temporary| temporary.previous == name)
+                {
+                    temporary.previous = target.clone();
+                }
+            }
+            if profiles.default_profile == name {
+                profiles.default_profile = target.clone();
+            }
+            let removed = profiles.profiles.remove(&name);
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "profile": name,
+                    "reassigned_to": target,
+                    "players": players,
+                })),
+                previous: Some(serde_json::json!({
+                    "profile": name,
+                    "data": removed,
+                    "players": players,
+                })),
+                ..OkPayload::with_profiles(
+                    "PROFILE_REMOVED",
+                    format!(
+                        "Removed profile '{}', moved {} player(s) to '{}'",
+                        name,
+                        players.len(),
+                        target
+                    ),
+                )
+            })
+        }
+        Command::ChangeProfile {
+            name,
+            pad,
+            update_player,
+        } => {
+            check_written(profiles, &name)?;
+            let previous = previous_selection(profiles, pad.as_deref(), true);
+
+            // Thresholds were successfully set on the device, now change the profile
+            let pad = profiles.pad_mut(pad.as_deref())?;
+            pad.current_profile = name.clone();
+            // Chosen for good, so no longer switched back
+            pad.temporary = None;
+            let pad_id = pad.id.clone();
+            let current_player = pad.current_player.clone();
+
+            // The current player keeps this profile unless it is only for now
+            let player = profiles
+                .players
+                .get_mut(&current_player)
+                .filter(|_| update_player.unwrap_or(true));
+            let player_updated = player.is_some();
+            if let Some(player) = player {
+                player.profile = name.clone();
+            }
+
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "profile": name,
+                    "pad": pad_id,
+                    "device_updated": written.is_some(),
+                    "player": Some(&current_player).filter(|player| !player.is_empty()),
+                    "player_updated": player_updated,
+                })),
+                previous,
+                pad: Some(pad_id),
+                ..OkPayload::with_profiles(
+                    "PROFILE_CHANGED",
+                    format!(
+                        "Changed to profile '{}'{}{}",
+                        name,
+                        if written.is_some() {
+                            " and set all thresholds on serial device"
+                        } else {
+                            ""
+                        },
+                        match (current_player.is_empty(), player_updated) {
+                            (true, _) => String::new(),
+                            (false, true) => {
+                                format!(" (updated current player '{}' profile)", current_player)
+                            }
+                            (false, false) => {
+                                format!(" (current player '{}' profile unchanged)", current_player)
+                            }
+                        }
+                    ),
+                )
+            })
+        }
+        Command::ApplyTemporaryProfile {
+            name,
+            duration_secs,
+            pad,
+        } => {
+            check_written(profiles, &name)?;
+            let previous = previous_selection(profiles, pad.as_deref(), true);
+            let duration = Duration::from_secs(duration_secs.max(1)).min(MAX_TEMPORARY_DURATION);
+            let until = Utc::now() + duration;
+            let pad = profiles.pad_mut(pad.as_deref())?;
+            // Another one while on a temporary profile still goes back to the first profile
+            let back_to = match pad.temporary.take() {
+                Some(temporary) => temporary.previous,
+                None => pad.current_profile.clone(),
+            };
+            pad.current_profile = name.clone();
+            pad.temporary = Some(TemporaryProfile {
+                previous: back_to.clone(),
+                until,
+            });
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "profile": name,
+                    "pad": pad.id,
+                    "back_to": back_to,
+                    "duration_secs": duration.as_secs(),
+                    "until": until,
+                    "device_updated": written.is_some(),
+                })),
+                previous,
+                pad: Some(pad.id.clone()),
+                ..OkPayload::with_profiles(
+                    "TEMPORARY_PROFILE_APPLIED",
+                    format!(
+                        "Changed to profile '{}' for {}s, then back to '{}'",
+                        name,
+                        duration.as_secs(),
+                        back_to
+                    ),
+                )
+            })
+        }
+        Command::RevertTemporaryProfile { pad } => {
+            let target = profiles.pad(pad.as_deref())?;
+            let Some(temporary) = target.temporary.clone() else {
+                return Err(ValidationError::ConcurrentChange(target.id.clone()).into());
+            };
+            let restore = profiles.profiles.contains_key(&temporary.previous);
+            if restore {
+                check_written(profiles, &temporary.previous)?;
+            }
+            let pad = profiles.pad_mut(pad.as_deref())?;
+            pad.temporary = None;
+            let profile = if restore {
+                std::mem::replace(&mut pad.current_profile, temporary.previous.clone())
+            } else {
+                pad.current_profile.clone()
+            };
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "profile": pad.current_profile,
+                    "temporary": profile,
+                    "pad": pad.id,
+                    "device_updated": written.is_some(),
+                })),
+                pad: Some(pad.id.clone()),
+                ..OkPayload::with_profiles(
+                    "TEMPORARY_PROFILE_REVERTED",
+                    if restore {
+                        format!(
+                            "Switched back from temporary profile '{}' to '{}'",
+                            profile, temporary.previous
+                        )
+                    } else {
+                        format!(
+                            "Ended temporary profile '{}'; profile '{}' no longer exists, so it stays",
+                            profile, temporary.previous
+                        )
+                    },
+                )
+            })
+        }
+        Command::ChangePlayer { name, pad } => {
+            let previous = previous_selection(profiles, pad.as_deref(), false);
+            // Check if player exists
+            if let Some(player) = profiles.players.get(&name) {
+                // Player exists and the device already has their profile's thresholds
+                let player_profile = player.profile.clone();
+                check_written(profiles, &player_profile)?;
+                let pad = profiles.pad_mut(pad.as_deref())?;
+                pad.current_player = name.clone();
+                pad.current_profile = player_profile.clone();
+                pad.temporary = None;
+                return Ok(OkPayload {
+                    params: Some(serde_json::json!({
+                        "player": name,
+                        "profile": player_profile,
+                        "pad": pad.id,
+                        "device_updated": written.is_some(),
+                    })),
+                    previous,
+                    pad: Some(pad.id.clone()),
+                    ..OkPayload::with_profiles(
+                        "PLAYER_CHANGED",
+                        format!(
+                            "Switched to player '{}' with profile '{}'{}",
+                            name,
+                            player_profile,
+                            if written.is_some() {
+                                " and set thresholds on serial device"
+                            } else {
+                                ""
+                            }
+                        ),
+                    )
+                });
+            }
+
+            // Player doesn't exist, create new player
+            quotas.check_new_player(profiles)?;
+            let target = profiles.pad(pad.as_deref())?;
+            let profile_to_use = profiles
+                .resolve_profile_for_new_player(target)
+                .ok_or(ValidationError::NoProfileForNewPlayer)?
+                .to_string();
+
+            let new_player = Player {
+                name: name.clone(),
+                profile: profile_to_use.clone(),
+            };
+            profiles.players.insert(name.clone(), new_player);
+            let pad = profiles.pad_mut(pad.as_deref())?;
+            pad.current_player = name.clone();
+            pad.current_profile = profile_to_use.clone();
+            pad.temporary = None;
+
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "player": name,
+                    "profile": profile_to_use,
+                    "pad": pad.id,
+                })),
+                previous,
+                pad: Some(pad.id.clone()),
+                ..OkPayload::with_profiles(
+                    "PLAYER_CREATED",
+                    format!(
+                        "Created new player '{}' with profile '{}'",
+                        name, profile_to_use
+                    ),
+                )
+            })
+        }
+        Command::ClearCurrentPlayer { pad } => {
+            let previous = previous_selection(profiles, pad.as_deref(), false);
+            let pad = profiles.pad_mut(pad.as_deref())?;
+            if pad.current_player.is_empty() {
+                return Err(ValidationError::NoCurrentPlayer(pad.id.clone()).into());
+            }
+            let player = std::mem::take(&mut pad.current_player);
+            Ok(OkPayload {
+                params: Some(serde_json::json!({
+                    "player": player,
+                    "profile": pad.current_profile,
+                    "pad": pad.id,
+                })),
+                previous,
+                pad: Some(pad.id.clone()),
+                ..OkPayload::with_profiles(
+                    "PLAYER_CLEARED",
+                    format!(
+                        "Signed out player '{}', pad '{}' stays on profile '{}'",
+                        player, pad.id, pad.current_profile
+                    ),
+                )
+            })
+        }
+        Command::SetDefaultProfile { name: None, pad } => {
+            let previous = match pad.as_deref() {
+                Some(id) => profiles.pad_mut(Some(id))?.default_profile.take(),
+                None => Some(std::mem::take(&mut profiles.default_profile)),
+            }
+            .filter(|previous| !previous.is_empty());
+            let message = match &pad {
+                Some(pad) => format!("Cleared the default profile of pad '{}'", pad),
+                None => "Cleared the default profile".to_string(),
+            };
+            Ok(OkPayload {
+                params: Some(serde_json::json!({ "profile": null, "pad": pad })),
+                previous: Some(serde_json::json!({ "profile": previous })),
+                pad,
+                ..OkPayload::with_profiles("DEFAULT_PROFILE_CLEARED", message)
+            })
+        }
+        Command::SetDefaultProfile {
+            name: Some(name),
+            pad,
+        } => {
+            if !profiles.profiles.contains_key(&name) {
+                return Err(ValidationError::ProfileNotFound(name).into());
+            }
+            match pad {
+                Some(pad) => {
+                    profiles.pad_mut(Some(&pad))?.default_profile = Some(name.clone());
+                    Ok(OkPayload {
+                        code: "DEFAULT_PROFILE_SET",
+                        params: Some(serde_json::json!({ "profile": name, "pad": pad })),
+                        message: format!("Set '{}' as default profile of pad '{}'", name, pad),
+                        pad: Some(pad),
+                        attach_profiles: true,
+                        ..OkPayload::default()
+                    })
+                }
+                None => {
+                    profiles.default_profile = name.clone();
+                    Ok(OkPayload {
+                        params: Some(serde_json::json!({ "profile": name, "pad": null })),
+                        ..OkPayload::with_profiles(
+                            "DEFAULT_PROFILE_SET",
+                            format!("Set '{}' as default profile", name),
+                        )
+                    })
+                }
+            }
+        }
+        Command::GetCurrentThresholds
+        | Command::StartSensorStream
+        | Command::StopSensorStream
+        | Command::GetStreamStatus
+        | Command::GetWebhookStatus
+        | Command::GetServerStats
+        | Command::GetSerialStats
+        | Command::GetLatencyStats
+        | Command::ResetLatencyStats
+        | Command::RunSelfTest
+        | Command::GetServerInfo
+        | Command::GetClients
+        | Command::GetErrorLog { .. }
+        | Command::GetThresholdWriteLog { .. }
+        | Command::GetCommandHistory { .. }
+        | Command::GetDriftCompensation
+        | Command::ListProfiles { .. }
+        | Command::ShareProfile { .. }
+        | Command::StartSerialCapture { .. }
+        | Command::StopSerialCapture
+        | Command::IdentifyPad { .. }
+        | Command::StartPanelTest { .. }
+        | Command::CancelPanelTest
+        | Command::CreateSnapshot { .. }
+        | Command::ListSnapshots
+        | Command::ListSchedules
+        | Command::Subscribe { .. }
+        | Command::GetPadMapping
+        | Command::GetLayouts
+        | Command::ListPlayers { .. }
+        | Command::Hello { .. }
+        | Command::GetProfiles
+        | Command::ListProposals
+        | Command::GetSensorValues
+        | Command::ReadSensors => unreachable!("answered by prepare"),
+        Command::ProposeThresholdChange { .. }
+        | Command::ApproveProposal { .. }
+        | Command::RejectProposal { .. } => unreachable!("answered by handle_proposal"),
+        Command::AssignPadPort { .. } => unreachable!("committed by assign_pad"),
+        Command::RetryLoadProfiles | Command::RestoreSnapshot { .. } => {
+            unreachable!("committed by commit")
+        }
+        Command::SetAutosave { .. }
+        | Command::SaveProfiles
+        | Command::ListSinks
+        | Command::EnableSink { .. }
+        | Command::DisableSink { .. } => {
+            unreachable!("answered by prepare")
+        }
+        Command::CalibrateGain { .. } => unreachable!("committed by set_gain"),
+    }
+}
+
+// Record the device and port prepare found for an AssignPadPort. Identical devices without
+// a serial number may share an identity, but never a port.
+fn assign_pad(
+    command: Command,
+    profiles: &mut Profiles,
+    device: Option<DeviceIdentity>,
+    port: Option<String>,
+) -> Result<OkPayload, AppError> {
+    let Command::AssignPadPort { pad, .. } = command else {
+        unreachable!("only AssignPadPort is prepared as an assignment");
+    };
+    let taken = profiles.pads.iter().find(|other| {
+        other.id != pad
+            && match (&device, &other.device) {
+                (
+                    Some(
+                        device @ DeviceIdentity {
+                            serial_number: Some(_),
+                            ..
+                        },
+                    ),
+                    Some(other),
+                ) => device == other,
+                (None, None) => port.is_some() && other.port == port,
+                _ => false,
+            }
+    });
+    let described = match (&device, &port) {
+        (Some(device), Some(port)) => format!("{} on {}", device, port),
+        (Some(device), None) => device.to_string(),
+        (None, Some(port)) => port.clone(),
+        (None, None) => String::new(),
+    };
+    if let Some(other) = taken {
+        return Err(ValidationError::DeviceAssigned {
+            device: described,
+            pad: other.id.clone(),
+        }
+        .into());
+    }
+
+    let drives_device = profiles.drives_device(&pad);
+    let target = profiles.pad_mut(Some(&pad))?;
+    target.device = device;
+    target.port = port;
+    let params = serde_json::json!({
+        "pad": pad,
+        "device": target.device.as_ref().map(ToString::to_string),
+        "port": target.port,
+        "next_start": drives_device,
+    });
+    Ok(OkPayload {
+        code: "PAD_ASSIGNED",
+        params: Some(params),
+        message: format!(
+            "Assigned {} to pad '{}'{}",
+            described,
+            pad,
+            if drives_device {
+                " (takes effect on the next start)"
+            } else {
+                ""
+            }
+        ),
+        pad: Some(pad),
+        attach_profiles: true,
+        ..OkPayload::default()
+    })
+}
+
+// Store the gains a CalibrateGain derived, unless the offset they are relative to changed
+fn set_gain(
+    command: Command,
+    profiles: &mut Profiles,
+    gain: Sensors<f32>,
+    offset: Option<Sensors<i32>>,
+) -> Result<OkPayload, AppError> {
+    let Command::CalibrateGain { profile_name, .. } = command else {
+        unreachable!("only CalibrateGain is prepared as a calibration");
+    };
+    match profiles.profiles.get_mut(&profile_name) {
+        Some(profile) if profile.offset == offset => profile.gain = Some(gain),
+        _ => return Err(ValidationError::ConcurrentChange(profile_name).into()),
+    }
+    Ok(OkPayload {
+        code: "GAIN_CALIBRATED",
+        params: Some(serde_json::json!({ "profile": profile_name, "gain": gain })),
+        message: format!("Calibrated gain of profile '{}': {:?}", profile_name, gain),
+        payload: Some(serde_json::json!({ "gain": gain })),
+        attach_profiles: true,
+        ..OkPayload::default()
+    })
+}
+
+// Params of LATENCY_STATS and LATENCY_STATS_RESET
+fn latency_params(stats: &LatencyStats) -> serde_json::Value {
+    serde_json::json!({
+        "presses": stats.presses,
+        "p50_us": stats.total_us.map(|total| total.p50),
+        "p99_us": stats.total_us.map(|total| total.p99),
+    })
+}
+
+fn latency_message(stats: &LatencyStats) -> String {
+    match stats.total_us {
+        Some(total) => format!(
+            "{} press(es), serial read to broadcast p50 {}us, p99 {}us",
+            stats.presses, total.p50, total.p99
+        ),
+        None => "No presses measured yet".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::ServerInfo;
+    use crate::journal::{Journal, JOURNAL_MAX_BYTES};
+    use crate::scale::Scale;
+    use crate::serial::{DummySerialPort, MockSerialPort};
+    use crate::serial_queue::SerialQueue;
+    use crate::snapshot::{SnapshotStore, MAX_LABEL_LEN};
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use tokio::sync::broadcast;
+    use tracing::span::{Attributes, Id, Record};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::Layer;
+
+    #[test]
+    fn test_read_only_policy() {
+        let change = Command::ChangePlayer {
+            name: "Player1".to_string(),
+            pad: None,
+        };
+        let strict = ReadOnlyPolicy {
+            mode: ReadOnlyMode::Strict,
+            allow_stream: false,
+            mirror: false,
+        };
+
+        assert!(ReadOnlyPolicy::default().check(&change, false).is_ok());
+        let rejection = strict.check(&change, false).unwrap_err().to_response();
+        assert!(!rejection.success);
+        assert!(rejection.message.starts_with(READ_ONLY_MODE));
+        assert!(strict.check(&Command::GetCurrentThresholds, false).is_ok());
+        assert!(strict.check(&Command::StartSensorStream, false).is_err());
+        assert!(strict.check(&change, true).is_err());
+        // Identifying a pad writes its device's threshold
+        let identify = Command::IdentifyPad {
+            pad: None,
+            force: true,
+        };
+        assert!(strict.check(&identify, false).is_err());
+
+        let with_stream = ReadOnlyPolicy {
+            allow_stream: true,
+            ..strict
+        };
+        assert!(with_stream.check(&Command::StopSensorStream, false).is_ok());
+        let panel_test = Command::StartPanelTest { timeout_ms: None };
+        assert!(strict.check(&panel_test, false).is_err());
+        assert!(with_stream.check(&panel_test, false).is_ok());
+
+        let soft = ReadOnlyPolicy {
+            mode: ReadOnlyMode::Soft,
+            allow_stream: false,
+            mirror: false,
+        };
+        assert!(soft.check(&change, false).is_err());
+        assert!(soft.check(&change, true).is_ok());
+
+        // A mirror accepts no change from anyone, the stream included
+        let mirror = ReadOnlyPolicy::mirror();
+        assert_eq!(
+            mirror.check(&change, true).unwrap_err().code(),
+            "MIRROR_MODE"
+        );
+        assert!(mirror.check(&Command::StartSensorStream, true).is_err());
+        assert!(mirror.check(&panel_test, true).is_err());
+        assert!(mirror.check(&Command::GetProfiles, false).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_current_thresholds() {
+        let profiles = Profiles {
+            profiles: HashMap::from([
+                ("Profile1".to_string(), Profile::new([10, 20, 30, 40])),
+                ("Profile2".to_string(), Profile::new([50, 60, 70, 80])),
+            ]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+        state.set_stream_enabled(true);
+
+        let response = handle_command(Command::GetCurrentThresholds, &state).await;
+
+        // The test will likely fail because the mock serial port doesn't respond correctly
+        // This is expected behavior - the real device would need to be connected for this to work
+        // The test verifies that the command structure is correct
+        assert!(
+            response
+                .message
+                .contains("Failed to read thresholds from device")
+                || response.message.contains("device synchronized")
+                || response
+                    .message
+                    .contains("device was out of sync, now fixed")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_current_thresholds_no_profile() {
+        let profiles = Profiles {
+            profiles: HashMap::new(),
+            default_profile: String::new(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad(String::new(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+        state.set_stream_enabled(true);
+
+        let response = handle_command(Command::GetCurrentThresholds, &state).await;
+        assert!(!response.success);
+        assert!(response.message.contains("No current profile selected"));
+    }
+
+    #[tokio::test]
+    async fn test_start_sensor_stream() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+
+        let response = handle_command(Command::StartSensorStream, &state).await;
+        assert!(response.success);
+        assert!(response.message.contains("Sensor stream started"));
+        assert!(state.stream_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_stop_sensor_stream() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+        state.set_stream_enabled(true);
+        let mut events = state.events.subscribe();
+
+        let response = handle_command(Command::StopSensorStream, &state).await;
+        assert!(response.success);
+        assert!(response.message.contains("Sensor stream stopped"));
+        assert!(!state.stream_enabled());
+        assert_eq!(
+            response.previous,
+            Some(serde_json::json!({ "enabled": true }))
+        );
+        assert_eq!(response.params.unwrap()["changed"], true);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::StreamStateChanged { enabled: false })
+        ));
+
+        // Stopping again is a no-op, and says so
+        let _ = events.try_recv();
+        let response = handle_command(Command::StopSensorStream, &state).await;
+        assert!(response.message.contains("already"));
+        assert_eq!(response.params.unwrap()["changed"], false);
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event, Event::StreamStateChanged { .. }));
+        }
+
+        // Started by anything else than a command, it is announced all the same
+        state.set_stream_enabled(true);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::StreamStateChanged { enabled: true })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_stream_status() {
+        let state = AppState::with_mock_port(Profiles::default());
+        let _client = state.clients.register(Role::Admin);
+        state.set_stream_enabled(true);
+        state.health.record_serial_error("read timed out");
+
+        let response = handle_command(Command::GetStreamStatus, &state).await;
+        assert!(response.success);
+        assert_eq!(response.message_code.as_deref(), Some("STREAM_STATUS"));
+        let payload = response.payload.unwrap();
+        assert_eq!(payload["enabled"], true);
+        assert_eq!(payload["rate_hz"], state.stream.borrow().rate_hz);
+        assert_eq!(payload["subscribers"], 1);
+        assert_eq!(payload["frames_emitted"], 0);
+        assert_eq!(payload["consecutive_errors"], 1);
+        assert_eq!(payload["last_error"], "read timed out");
+    }
+
+    #[tokio::test]
+    async fn test_update_threshold_with_serial() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+
+        let response = handle_command(
+            Command::UpdateThreshold {
+                profile_name: "Profile1".to_string(),
+                threshold_index: 0,
+                value: 123,
+            },
+            &state,
+        )
+        .await;
+
+        // The test will likely fail because the mock serial port doesn't respond correctly
+        // This is expected behavior - the real device would need to be connected for this to work
+        // The test verifies that the command structure is correct
+        assert!(
+            response
+                .message
+                .contains("Failed to set threshold on serial device")
+                || response.message.contains(
+                    "Updated threshold 0 (Left) to 123 for profile Profile1 and serial device"
+                )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mutations_return_previous_values() {
+        let profiles = Profiles {
+            profiles: HashMap::from([
+                ("Profile1".to_string(), Profile::new([10, 20, 30, 40])),
+                ("Profile2".to_string(), Profile::new([50, 60, 70, 80])),
+                ("Profile3".to_string(), Profile::new([1, 2, 3, 4])),
+            ]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+        let state = AppState::with_mock_port(profiles);
+
+        let update = Command::UpdateThreshold {
+            profile_name: "Profile1".to_string(),
+            threshold_index: 2,
+            value: 99,
+        };
+        let response = handle_command(update, &state).await;
+        assert_eq!(response.previous, Some(serde_json::json!({ "value": 30 })));
+
+        let change = Command::ChangeProfile {
+            name: "Profile2".to_string(),
+            pad: None,
+            update_player: None,
+        };
+        let response = handle_command(change, &state).await;
+        assert_eq!(
+            response.previous,
+            Some(serde_json::json!({
+                "player": null,
+                "profile": "Profile1",
+                "thresholds": [10, 20, 99, 40],
+            }))
+        );
+
+        let player = |name: &str| Command::ChangePlayer {
+            name: name.to_string(),
+            pad: None,
+        };
+        handle_command(player("Alice"), &state).await;
+        let response = handle_command(player("Bob"), &state).await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(
+            response.previous,
+            Some(serde_json::json!({ "player": "Alice", "profile": "Profile1" }))
+        );
+
+        let remove = Command::RemoveProfile {
+            name: "Profile3".to_string(),
+            confirm: false,
+            reassign_to: None,
+        };
+        let response = handle_command(remove, &state).await;
+        assert_eq!(
+            response.previous,
+            Some(serde_json::json!({
+                "profile": "Profile3",
+                "data": { "thresholds": [1, 2, 3, 4] },
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_profile_with_serial() {
+        let profiles = Profiles {
+            profiles: HashMap::from([
+                ("Profile1".to_string(), Profile::new([10, 20, 30, 40])),
+                ("Profile2".to_string(), Profile::new([50, 60, 70, 80])),
+            ]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+
+        let response = handle_command(
+            Command::ChangeProfile {
+                name: "Profile2".to_string(),
+                pad: None,
+                update_player: None,
+            },
+            &state,
+        )
+        .await;
+
+        // The test will likely fail because the mock serial port doesn't respond correctly
+        // This is expected behavior - the real device would need to be connected for this to work
+        // The test verifies that the command structure is correct
+        assert!(
+            response
+                .message
+                .contains("Failed to set thresholds on serial device")
+                || response.message.contains(
+                    "Changed to profile 'Profile2' and set all thresholds on serial device"
+                )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_profile_with_current_player() {
+        let profiles = Profiles {
+            profiles: HashMap::from([
+                ("Profile1".to_string(), Profile::new([10, 20, 30, 40])),
+                ("Profile2".to_string(), Profile::new([50, 60, 70, 80])),
+            ]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::from([(
+                "Player1".to_string(),
+                Player {
+                    name: "Player1".to_string(),
+                    profile: "Profile1".to_string(),
+                },
+            )]),
+            pads: vec![Pad::default_pad(
+                "Profile1".to_string(),
+                "Player1".to_string(),
+            )],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+
+        let response = handle_command(
+            Command::ChangeProfile {
+                name: "Profile2".to_string(),
+                pad: None,
+                update_player: None,
+            },
+            &state,
+        )
+        .await;
+
+        // The test will likely fail because the mock serial port doesn't respond correctly
+        // This is expected behavior - the real device would need to be connected for this to work
+        // The test verifies that the command structure is correct
+        assert!(
+            response
+                .message
+                .contains("Failed to set thresholds on serial device")
+                || response.message.contains(
+                    "Changed to profile 'Profile2' and set all thresholds on serial device (updated current player 'Player1' profile)"
+                )
+        );
+
+        // Verify that the current player's profile was updated (only if the command succeeded)
+        if response.success {
+            if let Some(player) = state.profiles_snapshot().players.get("Player1") {
+                assert_eq!(player.profile, "Profile2");
+            } else {
+                panic!("Player1 not found in players");
+            }
+        } else {
+            // If the command failed due to serial port issues, the player profile should remain unchanged
+            if let Some(player) = state.profiles_snapshot().players.get("Player1") {
+                assert_eq!(player.profile, "Profile1");
+            } else {
+                panic!("Player1 not found in players");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_change_profile_update_player() {
+        let mut profiles = two_profiles();
+        let player = Player {
+            name: "Alice".to_string(),
+            profile: "Profile1".to_string(),
+        };
+        profiles.players.insert("Alice".to_string(), player);
+        profiles.pads[0].current_player = "Alice".to_string();
+        let state = AppState::with_mock_port(profiles);
+        let change = |name: &str, update_player| Command::ChangeProfile {
+            name: name.to_string(),
+            pad: None,
+            update_player,
+        };
+        let player_profile = || state.profiles_snapshot().players["Alice"].profile.clone();
+
+        // Only for now: the device and the pad change, Alice's profile stays
+        let response = handle_command(change("Profile2", Some(false)), &state).await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(response.params.unwrap()["player_updated"], false);
+        assert!(response
+            .message
+            .ends_with("(current player 'Alice' profile unchanged)"));
+        assert_eq!(state.profiles_snapshot().current_profile(), "Profile2");
+        assert_eq!(player_profile(), "Profile1");
+
+        // By default the player's profile follows, as before
+        let response = handle_command(change("Profile2", None), &state).await;
+        assert_eq!(response.params.unwrap()["player_updated"], true);
+        assert!(response
+            .message
+            .ends_with("(updated current player 'Alice' profile)"));
+        assert_eq!(player_profile(), "Profile2");
+        let response = handle_command(change("Profile1", Some(true)), &state).await;
+        assert_eq!(response.params.unwrap()["player_updated"], true);
+        assert_eq!(player_profile(), "Profile1");
+
+        // Without a current player there is nothing to update
+        let mut profiles = two_profiles();
+        profiles.pads[0].current_player.clear();
+        let state = AppState::with_mock_port(profiles);
+        let response = handle_command(change("Profile2", None), &state).await;
+        let params = response.params.unwrap();
+        assert_eq!(params["player_updated"], false);
+        assert_eq!(params["player"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_partial_write_marks_device_out_of_sync() {
+        // The third write and putting back the second one fail
+        let port = MockSerialPort::new([10, 20, 30, 40]).with_rejected_writes(&[3, 5]);
+        let state = AppState::with_port(two_profiles(), Box::new(port));
+
+        let name = "Profile2".to_string();
+        let response = handle_command(
+            Command::ChangeProfile {
+                name,
+                pad: None,
+                update_player: None,
+            },
+            &state,
+        )
+        .await;
+        assert!(!response.success);
+        assert_eq!(response.message_code.as_deref(), Some("PARTIAL_WRITE"));
+        let params = response.params.unwrap();
+        assert_eq!(params["failed"], 2);
+        assert_eq!(params["changed"], serde_json::json!([0, 1]));
+        assert_eq!(params["restored"], serde_json::json!([0, 2]));
+        assert_eq!(params["inconsistent"], serde_json::json!([1]));
+        assert_eq!(state.profiles_snapshot().current_profile(), "Profile1");
+        assert!(state.health.device_out_of_sync());
+        let report = state.health.report(false, ReadOnlyPolicy::default());
+        assert!(report.device_out_of_sync);
+        assert!(report.healthy);
+
+        // Resyncing writes the profile in full again
+        let response = handle_command(Command::GetCurrentThresholds, &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("THRESHOLDS_RESYNCED")
+        );
+        assert!(!state.health.device_out_of_sync());
+    }
+
+    #[tokio::test]
+    async fn test_expected_revision_rejects_stale_changes() {
+        let state = AppState::with_mock_port(two_profiles());
+        let update = |value: i32, expected: Option<u64>| Envelope {
+            command: Command::UpdateThreshold {
+                profile_name: "Profile1".to_string(),
+                threshold_index: 0,
+                value,
+            },
+            idempotency_key: None,
+            dry_run: false,
+            expected_revision: expected,
+            origin: Origin::default(),
+        };
+
+        // Two phones start from revision 0; the first one's change wins
+        let response = handle_envelope(update(15, Some(0)), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("THRESHOLD_UPDATED"));
+        let profiles = state.profiles_snapshot();
+        assert_eq!(profiles.revision, 1);
+        assert_eq!(profiles.profiles["Profile1"].revision, 1);
+        assert_eq!(profiles.profiles["Profile2"].revision, 0);
+
+        let response = handle_envelope(update(25, Some(0)), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("CONFLICT"));
+        let params = response.params.unwrap();
+        assert_eq!(params["profile"], "Profile1");
+        assert_eq!(params["expected_revision"], 0);
+        assert_eq!(params["revision"], 1);
+        assert_eq!(
+            params["current"]["thresholds"],
+            serde_json::json!([15, 20, 30, 40])
+        );
+        assert_eq!(
+            state.profiles_snapshot().profiles["Profile1"].thresholds[0],
+            15
+        );
+
+        // Rebased on the current revision, or sent without one
+        let response = handle_envelope(update(25, Some(1)), &state).await;
+        assert!(response.success);
+        let response = handle_envelope(update(35, None), &state).await;
+        assert!(response.success);
+        let profiles = state.profiles_snapshot();
+        assert_eq!(profiles.revision, 3);
+        assert_eq!(profiles.profiles["Profile1"].revision, 3);
+
+        // Changes of another profile don't conflict with this one
+        let envelope = parse_envelope(
+            r#"{"SetDriftCompensation": {"profile_name": "Profile2", "enabled": true}, "expected_revision": 0}"#,
+        )
+        .unwrap();
+        assert!(handle_envelope(envelope, &state).await.success);
+        // Everything else is compared with the revision of all profiles
+        let envelope =
+            parse_envelope(r#"{"ChangeProfile": {"name": "Profile2"}, "expected_revision": 3}"#)
+                .unwrap();
+        let response = handle_envelope(envelope, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("CONFLICT"));
+        assert_eq!(response.params.unwrap()["revision"], 4);
+
+        // Kept in profiles.json
+        let stored = serde_json::to_string(&*state.profiles_snapshot()).unwrap();
+        let loaded: Profiles = serde_json::from_str(&stored).unwrap();
+        assert_eq!(loaded, *state.profiles_snapshot());
+
+        let error = parse_envelope(r#"{"GetProfiles": null, "expected_revision": -1}"#);
+        assert_eq!(error.unwrap_err().code(), "INVALID_COMMAND");
+    }
+
+    #[tokio::test]
+    async fn test_proposals_wait_for_an_operator() {
+        let state = AppState::with_mock_port(two_profiles());
+        let viewer = Origin::Websocket { connection: 7 };
+        let send = |command: Command, origin: Origin| Envelope {
+            command,
+            idempotency_key: None,
+            dry_run: false,
+            expected_revision: None,
+            origin,
+        };
+        let propose = |value: i32| Command::ProposeThresholdChange {
+            profile: "Profile1".to_string(),
+            index: 0,
+            value,
+            note: Some("too sensitive".to_string()),
+        };
+        let approve = |id: u64, force: bool| Command::ApproveProposal { id, force };
+
+        let response = handle_envelope(send(propose(50), viewer), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROPOSAL_CREATED"));
+        assert_eq!(response.params.unwrap()["id"], 1);
+        // Nothing changes until it is approved
+        assert_eq!(
+            state.profiles_snapshot().profiles["Profile1"].thresholds[0],
+            10
+        );
+        let response = handle_envelope(send(propose(60), viewer), &state).await;
+        assert_eq!(response.params.unwrap()["id"], 2);
+
+        // Approving one makes the other stale
+        let response = handle_command(approve(1, false), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROPOSAL_APPROVED"));
+        assert_eq!(response.previous.unwrap()["value"], 10);
+        assert_eq!(
+            state.profiles_snapshot().profiles["Profile1"].thresholds[0],
+            50
+        );
+        let response = handle_command(approve(1, false), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROPOSAL_DECIDED"));
+        let response = handle_command(Command::ListProposals, &state).await;
+        let proposals = response.payload.unwrap()["proposals"].clone();
+        assert_eq!(proposals[0]["status"], "approved");
+        assert_eq!(proposals[0]["proposed_by"]["connection"], 7);
+        assert_eq!(proposals[1]["stale"], true);
+        let response = handle_command(approve(2, false), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROPOSAL_STALE"));
+        assert_eq!(
+            state.profiles_snapshot().profiles["Profile1"].thresholds[0],
+            50
+        );
+        let response = handle_command(approve(2, true), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROPOSAL_APPROVED"));
+        assert_eq!(response.params.unwrap()["forced"], true);
+        assert_eq!(
+            state.profiles_snapshot().profiles["Profile1"].thresholds[0],
+            60
+        );
+
+        // A rejection keeps its reason, and an invalid proposal is refused up front
+        handle_envelope(send(propose(70), viewer), &state).await;
+        let reject = Command::RejectProposal {
+            id: 3,
+            reason: Some("keep it".to_string()),
+        };
+        let response = handle_command(reject, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROPOSAL_REJECTED"));
+        assert_eq!(response.payload.unwrap()["proposal"]["reason"], "keep it");
+        assert_eq!(
+            state.profiles_snapshot().profiles["Profile1"].thresholds[0],
+            60
+        );
+        let response = handle_command(approve(9, false), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROPOSAL_NOT_FOUND"));
+        let response = handle_command(propose(-1), &state).await;
+        assert!(!response.success);
+        assert_eq!(state.proposals.list().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_threshold_writes_are_logged_with_their_reason() {
+        let state = AppState::with_mock_port(two_profiles());
+        // The mock device starts out unlike Profile1
+        let response = handle_command(Command::GetCurrentThresholds, &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("THRESHOLDS_RESYNCED")
+        );
+        let update = Command::UpdateThreshold {
+            profile_name: "Profile1".to_string(),
+            threshold_index: 2,
+            value: 35,
+        };
+        assert!(handle_command(update, &state).await.success);
+        let change = Command::ChangeProfile {
+            name: "Profile2".to_string(),
+            pad: None,
+            update_player: None,
+        };
+        assert!(handle_command(change, &state).await.success);
+
+        let response = handle_command(Command::GetThresholdWriteLog { limit: None }, &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("THRESHOLD_WRITE_LOG")
+        );
+        assert_eq!(response.params.unwrap()["count"], 3);
+        let writes = response.payload.unwrap()["writes"].clone();
+        assert_eq!(writes[0]["reason"], "resync");
+        assert_eq!(writes[0]["before"], serde_json::json!([100, 200, 300, 400]));
+        assert_eq!(writes[0]["after"], serde_json::json!([10, 20, 30, 40]));
+        assert_eq!(writes[1]["reason"], "user_command");
+        assert_eq!(
+            writes[1]["before"],
+            serde_json::json!([null, null, 30, null])
+        );
+        assert_eq!(
+            writes[1]["after"],
+            serde_json::json!([null, null, 35, null])
+        );
+        assert_eq!(writes[1]["success"], true);
+        assert_eq!(writes[2]["reason"], "profile_switch");
+        assert_eq!(writes[2]["before"], serde_json::json!([10, 20, 35, 40]));
+        assert_eq!(writes[2]["after"], serde_json::json!([50, 60, 70, 80]));
+
+        // Newest last, and each one is journaled
+        let response =
+            handle_command(Command::GetThresholdWriteLog { limit: Some(1) }, &state).await;
+        assert_eq!(
+            response.payload.unwrap()["writes"][0]["reason"],
+            "profile_switch"
+        );
+        assert_eq!(
+            state.threshold_writes.tail(1)[0].to_string(),
+            "profile switch: thresholds from [10, 20, 35, 40] to [50, 60, 70, 80]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_profile_imports_as_a_copy() {
+        let state = AppState::with_port(
+            two_profiles(),
+            Box::new(MockSerialPort::new([10, 20, 30, 40])),
+        );
+        let share = |name: &str| Command::ShareProfile {
+            name: name.to_string(),
+        };
+        let import = |code: &str, rename_to: Option<&str>| Command::ImportSharedProfile {
+            code: code.to_string(),
+            rename_to: rename_to.map(str::to_string),
+        };
+
+        let response = handle_command(share("Profile2"), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_SHARED"));
+        let code = response.payload.unwrap()["code"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // Under its own name it collides with the original
+        let response = handle_command(import(&code, None), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_EXISTS"));
+        let response = handle_command(import(&code, Some("Copy")), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_IMPORTED"));
+        let profiles = state.profiles_snapshot();
+        let (copy, original) = (&profiles.profiles["Copy"], &profiles.profiles["Profile2"]);
+        assert_eq!(copy.thresholds, original.thresholds);
+        assert_eq!(copy.layout, original.layout);
+
+        let truncated = &code[..code.len() - 4];
+        let response = handle_command(import(truncated, Some("Other")), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("INVALID_SHARE_CODE"));
+        assert_eq!(response.params.unwrap()["reason"], "truncated");
+        assert!(!state.profiles_snapshot().profiles.contains_key("Other"));
+    }
+
+    #[tokio::test]
+    async fn test_import_external() {
+        let state = AppState::with_mock_port(two_profiles());
+        let import = |format: &str, payload: &str, on_conflict| Command::ImportExternal {
+            format: format.to_string(),
+            payload: payload.to_string(),
+            on_conflict,
+        };
+
+        let response = handle_command(import("ini", "", OnConflict::Fail), &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("UNKNOWN_IMPORT_FORMAT")
+        );
+        assert_eq!(
+            response.params.unwrap()["supported"],
+            serde_json::json!(["teejusb", "plain"])
+        );
+        let response =
+            handle_command(import("plain", "Casual 1 2 3 4", OnConflict::Fail), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("INVALID_IMPORT"));
+        assert_eq!(
+            response.params.unwrap(),
+            serde_json::json!({ "reason": "malformed", "line": 1 })
+        );
+
+        // One taken name fails the whole import by default
+        let payload = "Casual: 100,200,300,400\nProfile2: 1,2,3,4";
+        let response = handle_command(import("plain", payload, OnConflict::Fail), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_EXISTS"));
+        assert!(!state.profiles_snapshot().profiles.contains_key("Casual"));
+
+        let response = handle_command(import("plain", payload, OnConflict::Skip), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILES_IMPORTED"));
+        let params = response.params.unwrap();
+        assert_eq!(params["imported"], serde_json::json!(["Casual"]));
+        assert_eq!(params["skipped"], serde_json::json!(["Profile2"]));
+        assert_eq!(
+            state.profiles_snapshot().profiles["Profile2"].thresholds,
+            [50, 60, 70, 80]
+        );
+
+        let payload = "Profile2 1 2 3 4\nCasual 5 6 7 8";
+        let response = handle_command(import("teejusb", payload, OnConflict::Rename), &state).await;
+        let params = response.params.unwrap();
+        assert_eq!(
+            params["imported"],
+            serde_json::json!(["Profile2 (2)", "Casual (2)"])
+        );
+        assert_eq!(params["renamed"]["Casual"], "Casual (2)");
+        let profiles = state.profiles_snapshot();
+        assert_eq!(profiles.profiles["Casual (2)"].thresholds, [5, 6, 7, 8]);
+
+        // Thresholds are checked like those of AddProfile
+        let response = handle_command(
+            import("plain", "Big: 1,2,3,99999", OnConflict::Fail),
+            &state,
+        )
+        .await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("THRESHOLD_OUT_OF_RANGE")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_profile_tags() {
+        let state = AppState::with_mock_port(two_profiles());
+        let set_tags = |name: &str, tags: &[&str]| Command::SetProfileTags {
+            name: name.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+        let list = |filter: Option<&str>| Command::ListProfiles {
+            tag_filter: filter.map(str::to_string),
+        };
+
+        let response =
+            handle_command(set_tags("Profile1", &["Kids", " socks", "kids"]), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_TAGS_SET"));
+        // Broadcast with the profiles, for pickers that group them
+        let data = serde_json::to_value(response.data.unwrap()).unwrap();
+        assert_eq!(
+            data["profiles"]["Profile1"]["tags"],
+            serde_json::json!(["Kids", "socks"])
+        );
+        assert!(
+            handle_command(set_tags("Profile2", &["socks"]), &state)
+                .await
+                .success
+        );
+
+        let response = handle_command(list(Some("KIDS")), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILES_LISTED"));
+        let payload = response.payload.unwrap();
+        assert_eq!(
+            payload["profiles"],
+            serde_json::json!([
+                { "name": "Profile1", "thresholds": [10, 20, 30, 40], "tags": ["Kids", "socks"] },
+            ])
+        );
+        assert_eq!(payload["tags"], serde_json::json!(["Kids", "socks"]));
+        let response = handle_command(list(None), &state).await;
+        assert_eq!(response.params.unwrap()["count"], 2);
+
+        let response = handle_command(set_tags("Profile1", &[&"x".repeat(33)]), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("INVALID_TAG"));
+        let many: Vec<String> = (0..17).map(|i| i.to_string()).collect();
+        let many: Vec<&str> = many.iter().map(String::as_str).collect();
+        let response = handle_command(set_tags("Profile1", &many), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("TOO_MANY_TAGS"));
+
+        // Tags travel with a share code and through profiles.json
+        let response = handle_command(
+            Command::ShareProfile {
+                name: "Profile1".to_string(),
+            },
+            &state,
+        )
+        .await;
+        let code = response.payload.unwrap()["code"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let import = Command::ImportSharedProfile {
+            code,
+            rename_to: Some("Copy".to_string()),
+        };
+        assert!(handle_command(import, &state).await.success);
+        let profiles = state.profiles_snapshot();
+        assert_eq!(profiles.profiles["Copy"].tags, ["Kids", "socks"]);
+        let stored = serde_json::to_string(&*profiles).unwrap();
+        let loaded: Profiles = serde_json::from_str(&stored).unwrap();
+        assert_eq!(loaded, *profiles);
+    }
+
+    #[tokio::test]
+    async fn test_press_parameters() {
+        let state = AppState::with_mock_port(two_profiles());
+        let mut events = state.events.subscribe();
+        let set = |hysteresis: Sensors<i32>, debounce_ms: u32| Command::SetPressParameters {
+            profile_name: "Profile1".to_string(),
+            hysteresis,
+            debounce_ms,
+        };
+
+        // Profile1's thresholds are [10, 20, 30, 40]
+        let response = handle_command(set([5, 5, 31, 5].into(), 0), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("INVALID_HYSTERESIS"));
+        assert_eq!(response.params.unwrap()["sensor"], 2);
+        let response = handle_command(set([5, 20, 30, 0].into(), 15), &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("PRESS_PARAMETERS_SET")
+        );
+        let status = handle_command(Command::GetStreamStatus, &state).await;
+        assert_eq!(
+            status.payload.unwrap()["press"],
+            serde_json::json!({ "hysteresis": [5, 20, 30, 0], "debounce_ms": 15 })
+        );
+
+        // Presses are judged with the parameters of the profile current at the reading
+        let switch = Command::ChangeProfile {
+            name: "Profile2".to_string(),
+            pad: None,
+            update_player: None,
+        };
+        assert!(handle_command(switch, &state).await.success);
+        crate::fresh::record_reading(&state, [50, 0, 0, 0].into());
+        let press = loop {
+            match events.recv().await.unwrap() {
+                event @ Event::Press(_) => break event.to_response(),
+                _ => continue,
+            }
+        };
+        let payload = press.payload.unwrap();
+        assert_eq!(
+            payload["pressed"],
+            serde_json::json!([true, false, false, false])
+        );
+        assert_eq!(payload["hysteresis"], serde_json::json!([0, 0, 0, 0]));
+        assert_eq!(payload["debounce_ms"], 0);
+
+        // Profiles saved before the parameters existed have none
+        let stored = serde_json::to_value(&*state.profiles_snapshot()).unwrap();
+        assert_eq!(stored["profiles"]["Profile1"]["debounce_ms"], 15);
+        assert!(stored["profiles"]["Profile2"].get("hysteresis").is_none());
+        let loaded: Profile = serde_json::from_str(r#"{"thresholds": [1, 2, 3, 4]}"#).unwrap();
+        assert_eq!((loaded.hysteresis.len(), loaded.debounce_ms), (0, 0));
+        assert_eq!(press::PressParameters::of(&loaded).hysteresis, [0; 4]);
+    }
+
+    #[tokio::test]
+    async fn test_schedules() {
+        let state = AppState::with_mock_port(two_profiles());
+        let add = |cron_or_time: &str, profile: &str| Command::AddSchedule {
+            cron_or_time: cron_or_time.to_string(),
+            profile: profile.to_string(),
+            pad: None,
+        };
+
+        let response = handle_command(add(" 22:00", "Profile2"), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SCHEDULE_ADDED"));
+        let params = response.params.unwrap();
+        assert_eq!(params["id"], "1");
+        assert_eq!(params["cron_or_time"], "22:00");
+        assert!(params["next_run"].is_string());
+        let response = handle_command(add("0 8 * * 1-5", "Profile1"), &state).await;
+        assert_eq!(response.params.unwrap()["id"], "2");
+
+        let response = handle_command(add("22:5", "Profile2"), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("INVALID_SCHEDULE"));
+        let response = handle_command(add("22:00", "Missing"), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_NOT_FOUND"));
+
+        let response = handle_command(Command::ListSchedules, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SCHEDULES"));
+        let payload = response.payload.unwrap();
+        assert_eq!(payload["schedules"][1]["profile"], "Profile1");
+        assert!(payload["timezone"]["utc_offset"].is_string());
+
+        // Kept in profiles.json
+        let stored = serde_json::to_string(&*state.profiles_snapshot()).unwrap();
+        let loaded: Profiles = serde_json::from_str(&stored).unwrap();
+        assert_eq!(loaded.schedules, state.profiles_snapshot().schedules);
+
+        let remove = |id: &str| Command::RemoveSchedule { id: id.to_string() };
+        let response = handle_command(remove("1"), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SCHEDULE_REMOVED"));
+        assert_eq!(response.previous.unwrap()["profile"], "Profile2");
+        let response = handle_command(remove("1"), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SCHEDULE_NOT_FOUND"));
+        // Ids aren't reused while a higher one exists
+        let response = handle_command(add("22:00", "Profile2"), &state).await;
+        assert_eq!(response.params.unwrap()["id"], "3");
+    }
+
+    #[tokio::test]
+    async fn test_temporary_profile() {
+        let state = AppState::with_mock_port(two_profiles());
+        let mut events = state.events.subscribe();
+        let apply = |name: &str| Command::ApplyTemporaryProfile {
+            name: name.to_string(),
+            duration_secs: 600,
+            pad: None,
+        };
+        let revert = || Command::RevertTemporaryProfile { pad: None };
+        let ended = |events: &mut broadcast::Receiver<Event>| loop {
+            match events.try_recv() {
+                Ok(Event::TemporaryProfileEnded { reason, .. }) => return reason,
+                Ok(_) => {}
+                Err(e) => panic!("no temporary_profile_ended event: {}", e),
+            }
+        };
+
+        let response = handle_command(apply("Profile2"), &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("TEMPORARY_PROFILE_APPLIED")
+        );
+        assert_eq!(response.params.unwrap()["back_to"], "Profile1");
+        // A second one still goes back to the profile from before the first
+        let response = handle_command(apply("Profile2"), &state).await;
+        assert_eq!(response.params.unwrap()["back_to"], "Profile1");
+        let profiles = state.profiles_snapshot();
+        assert_eq!(profiles.current_profile(), "Profile2");
+        let status = temporary::status(&profiles).unwrap();
+        assert!(status.remaining_secs > 590);
+
+        let remove = Command::RemoveProfile {
+            name: "Profile1".to_string(),
+            confirm: false,
+            reassign_to: None,
+        };
+        let response = handle_command(remove, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_IN_USE"));
+
+        let response = handle_command(revert(), &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("TEMPORARY_PROFILE_REVERTED")
+        );
+        assert_eq!(state.profiles_snapshot().current_profile(), "Profile1");
+        assert_eq!(state.profiles_snapshot().pads[0].temporary, None);
+        assert_eq!(ended(&mut events), "reverted");
+        let response = handle_command(revert(), &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("NO_TEMPORARY_PROFILE")
+        );
+
+        // Changing the player ends it
+        handle_command(apply("Profile2"), &state).await;
+        let change = Command::ChangePlayer {
+            name: "Guest".to_string(),
+            pad: None,
+        };
+        assert!(handle_command(change, &state).await.success);
+        assert_eq!(state.profiles_snapshot().pads[0].temporary, None);
+        assert_eq!(ended(&mut events), "player_changed");
+    }
+
+    #[tokio::test]
+    async fn test_temporary_profile_expires() {
+        let state = AppState::with_mock_port(two_profiles());
+        let mut events = state.events.subscribe();
+        let mut profiles = two_profiles();
+        profiles.pads[0].current_profile = "Profile2".to_string();
+        profiles.pads[0].temporary = Some(TemporaryProfile {
+            previous: "Profile1".to_string(),
+            until: Utc::now(),
+        });
+        state.publish_profiles(Arc::new(profiles));
+
+        let task = tokio::spawn(temporary::temporary_task(state.clone()));
+        let reason = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(Event::TemporaryProfileEnded { reason, .. }) = events.recv().await {
+                    return reason;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        task.abort();
+        assert_eq!(reason, "expired");
+        assert_eq!(state.profiles_snapshot().current_profile(), "Profile1");
+    }
+
+    #[tokio::test]
+    async fn test_get_current_thresholds_with_device_sync() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+
+        let response = handle_command(Command::GetCurrentThresholds, &state).await;
+
+        // The test will likely fail because the mock serial port doesn't respond correctly
+        // This is expected behavior - the real device would need to be connected for this to work
+        // The test verifies that the command structure is correct
+        assert!(
+            response
+                .message
+                .contains("Failed to read thresholds from device")
+                || response.message.contains("device synchronized")
+                || response
+                    .message
+                    .contains("device was out of sync, now fixed")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_player() {
+        let profiles = Profiles {
+            profiles: HashMap::from([
+                ("Profile1".to_string(), Profile::new([10, 20, 30, 40])),
+                ("Profile2".to_string(), Profile::new([50, 60, 70, 80])),
+            ]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+
+        // Test creating a new player
+        let response = handle_command(
+            Command::ChangePlayer {
+                name: "Player1".to_string(),
+                pad: None,
+            },
+            &state,
+        )
+        .await;
+
+        // The test will likely fail because the mock serial port doesn't respond correctly
+        // This is expected behavior - the real device would need to be connected for this to work
+        // The test verifies that the command structure is correct
+        assert!(
+            response
+                .message
+                .contains("Failed to set thresholds on serial device")
+                || response
+                    .message
+                    .contains("Created new player 'Player1' with profile 'Profile1'")
+        );
+
+        // Test switching to an existing player
+        let response = handle_command(
+            Command::ChangePlayer {
+                name: "Player1".to_string(),
+                pad: None,
+            },
+            &state,
+        )
+        .await;
+
+        assert!(
+            response
+                .message
+                .contains("Failed to set thresholds on serial device")
+                || response
+                    .message
+                    .contains("Switched to player 'Player1' with profile 'Profile1'")
+        );
+    }
+
+    // Allocations on the broadcast path for 1000 UpdateThreshold commands against the mock.
+    // Run with `cargo test --release bench_update_threshold_broadcast -- --ignored --nocapture`
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn bench_update_threshold_broadcast() {
+        const COMMANDS: usize = 1000;
+        const SUBSCRIBERS: usize = 4;
+
+        // A well used pad: 20 profiles and 20 players
+        let profiles = Profiles {
+            profiles: (0..20)
+                .map(|i| (format!("Profile{}", i), Profile::new([100, 200, 300, 400])))
+                .collect(),
+            players: (0..20)
+                .map(|i| {
+                    let name = format!("Player{}", i);
+                    let player = Player {
+                        name: name.clone(),
+                        profile: format!("Profile{}", i),
+                    };
+                    (name, player)
+                })
+                .collect(),
+            pads: vec![Pad::default_pad("Profile0".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        let state = AppState::with_mock_port(profiles);
+        let mut receivers: Vec<_> = (0..SUBSCRIBERS).map(|_| state.events.subscribe()).collect();
+
+        let (mut allocations, mut bytes) = (0, 0);
+        let started = std::time::Instant::now();
+        for i in 0..COMMANDS {
+            let command = Command::UpdateThreshold {
+                profile_name: "Profile0".to_string(),
+                threshold_index: (i % 4) as u8,
+                value: 100 + i as i32,
+            };
+            let response = handle_command(command, &state).await;
+            assert!(response.success, "{}", response.message);
+
+            // What the websocket path does: one send, one clone per subscriber on recv
+            let ((), a, b) = crate::alloc_count::measure(|| {
+                let result = Event::CommandResult {
+                    connection: 1,
+                    response,
+                };
+                let _ = state.events.send(result);
+                for rx in receivers.iter_mut() {
+                    rx.try_recv().unwrap();
+                }
+            });
+            allocations += a;
+            bytes += b;
+        }
+
+        let deliveries = (COMMANDS * SUBSCRIBERS) as u64;
+        println!(
+            "{} commands x {} subscribers in {:?}: {} allocations ({:.1}/delivery), {} bytes ({:.0}/delivery)",
+            COMMANDS,
+            SUBSCRIBERS,
+            started.elapsed(),
+            allocations,
+            allocations as f64 / deliveries as f64,
+            bytes,
+            bytes as f64 / deliveries as f64
+        );
+
+        // Subscribers share the profiles snapshot instead of deep-copying it: before
+        // responses carried Arc<Profiles> this was 85 allocations (4.4KB) per delivery
+        assert!(allocations <= deliveries * 4);
+    }
+
+    #[tokio::test]
+    async fn test_get_profiles_shares_published_snapshot() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        let state = AppState::with_port(profiles, Box::new(DummySerialPort));
+
+        let response = handle_command(Command::GetProfiles, &state).await;
+        assert!(response.success);
+        assert_eq!(response.message, "1 profile(s), 0 player(s)");
+        let data = response.data.unwrap();
+        assert!(Arc::ptr_eq(&data, &state.profiles_snapshot()));
+    }
+
+    fn two_profiles() -> Profiles {
+        Profiles {
+            profiles: HashMap::from([
+                ("Profile1".to_string(), Profile::new([10, 20, 30, 40])),
+                ("Profile2".to_string(), Profile::new([50, 60, 70, 80])),
+            ]),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            ..Profiles::default()
+        }
+    }
+
+    fn slow_device_state() -> AppState {
+        let profiles = two_profiles();
+        // A read and four writes per profile change, 500ms in total
+        let port = MockSerialPort::new([10, 20, 30, 40]).with_latency(Duration::from_millis(100));
+        AppState::with_port(profiles, Box::new(port))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_profiles_while_device_is_busy() {
+        let state = slow_device_state();
+        let change = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let name = "Profile2".to_string();
+                handle_command(
+                    Command::ChangeProfile {
+                        name,
+                        pad: None,
+                        update_player: None,
+                    },
+                    &state,
+                )
+                .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Reads don't wait for the device writes of the profile change
+        let started = Instant::now();
+        let response = handle_command(Command::GetProfiles, &state).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(response.data.unwrap().current_profile(), "Profile1");
+
+        let response = change.await.unwrap();
+        assert!(response.success, "{}", response.message);
+        assert_eq!(state.profiles_snapshot().current_profile(), "Profile2");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_change_during_device_write_is_reported() {
+        let state = slow_device_state();
+        let change = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let name = "Profile2".to_string();
+                handle_command(
+                    Command::ChangeProfile {
+                        name,
+                        pad: None,
+                        update_player: None,
+                    },
+                    &state,
+                )
+                .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // The profile being applied is removed before the change commits
+        let name = "Profile2".to_string();
+        let remove = Command::RemoveProfile {
+            name,
+            confirm: false,
+            reassign_to: None,
+        };
+        let response = handle_command(remove, &state).await;
+        assert!(response.success);
+
+        let response = change.await.unwrap();
+        assert!(!response.success);
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "CONCURRENT_CHANGE" }))
+        );
+        assert_eq!(state.profiles_snapshot().current_profile(), "Profile1");
+    }
+
+    // Records every span with its parent and fields, to assert on span hierarchies
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<std::sync::Mutex<Vec<RecordedSpan>>>);
+
+    #[derive(Debug)]
+    struct RecordedSpan {
+        name: &'static str,
+        parent: Option<&'static str>,
+        fields: HashMap<&'static str, String>,
+    }
+
+    struct FieldVisitor<'a>(&'a mut HashMap<&'static str, String>);
+
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name(), format!("{:?}", value));
+        }
+    }
+
+    impl<S> Layer<S> for SpanRecorder
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            let mut spans = self.0.lock().unwrap();
+            span.extensions_mut().insert(spans.len());
+            spans.push(RecordedSpan {
+                name: span.name(),
+                parent: span.parent().map(|parent| parent.name()),
+                fields,
+            });
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let index = *span.extensions().get::<usize>().unwrap();
+            values.record(&mut FieldVisitor(&mut self.0.lock().unwrap()[index].fields));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_span_hierarchy() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing_subscriber::registry()
+            .with(recorder.clone())
+            .set_default();
+        let state = slow_device_state();
+
+        let name = "Profile2".to_string();
+        let response = handle_command(
+            Command::ChangeProfile {
+                name,
+                pad: None,
+                update_player: None,
+            },
+            &state,
+        )
+        .await;
+        assert!(response.success);
+
+        let spans = recorder.0.lock().unwrap();
+        let command = spans.iter().find(|span| span.name == "command").unwrap();
+        assert_eq!(command.parent, None);
+        assert_eq!(command.fields["command"], "ChangeProfile");
+        assert_eq!(command.fields["outcome"], "ok");
+
+        let set_all = spans
+            .iter()
+            .find(|span| span.name == "set_all_thresholds")
+            .unwrap();
+        assert_eq!(set_all.parent, Some("command"));
+
+        let writes: Vec<_> = spans
+            .iter()
+            .filter(|span| span.name == "set_threshold")
+            .collect();
+        assert_eq!(writes.len(), 4);
+        for write in writes {
+            assert_eq!(write.parent, Some("set_all_thresholds"));
+            assert_eq!(write.fields["lines_read"], "1");
+            assert!(write.fields.contains_key("bytes_written"));
+            // Every write sleeps 100ms in the slow mock
+            assert!(write.fields["duration_us"].parse::<u64>().unwrap() >= 100_000);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_stats_count_commands() {
+        let port = MockSerialPort::new([10, 20, 30, 40]);
+        let state = AppState::with_port(two_profiles(), Box::new(port));
+
+        let name = "Profile2".to_string();
+        assert!(
+            handle_command(
+                Command::ChangeProfile {
+                    name,
+                    pad: None,
+                    update_player: None
+                },
+                &state
+            )
+            .await
+            .success
+        );
+        let name = "Missing".to_string();
+        assert!(
+            !handle_command(
+                Command::ChangeProfile {
+                    name,
+                    pad: None,
+                    update_player: None
+                },
+                &state
+            )
+            .await
+            .success
+        );
+        assert!(
+            handle_command(Command::GetCurrentThresholds, &state)
+                .await
+                .success
+        );
+
+        let response = handle_command(Command::GetServerStats, &state).await;
+        assert!(response.success);
+        let stats = response.payload.unwrap();
+        assert_eq!(stats["commands"]["ChangeProfile"], 2);
+        assert_eq!(stats["failures"]["PROFILE_NOT_FOUND"], 1);
+        assert_eq!(stats["serial_writes"], 4);
+        assert_eq!(stats["serial_reads"], 1);
+        // GetServerStats counts itself only once it has answered
+        assert_eq!(state.metrics.snapshot().commands["GetServerStats"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_second_pad_selection_skips_device() {
+        let mut profiles = two_profiles();
+        profiles.pads.push(Pad {
+            id: "p2".to_string(),
+            default_profile: Some("Profile2".to_string()),
+            ..Pad::default_pad("Profile1".to_string(), String::new())
+        });
+        let state = AppState::with_port(profiles, Box::new(MockSerialPort::new([10, 20, 30, 40])));
+
+        let pad = Some("p2".to_string());
+        let response = handle_command(
+            Command::ChangePlayer {
+                name: "Player1".to_string(),
+                pad: pad.clone(),
+            },
+            &state,
+        )
+        .await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(response.pad.as_deref(), Some("p2"));
+
+        let snapshot = state.profiles_snapshot();
+        // The pad's default wins over the shared one for new players
+        assert_eq!(snapshot.players["Player1"].profile, "Profile2");
+        assert_eq!(snapshot.pads[1].current_profile, "Profile2");
+        assert_eq!(snapshot.pads[1].current_player, "Player1");
+        assert_eq!(snapshot.current_profile(), "Profile1");
+        assert_eq!(state.metrics.snapshot().serial_writes, 0);
+
+        let response = handle_command(
+            Command::ChangeProfile {
+                name: "Profile1".to_string(),
+                pad: Some("p3".to_string()),
+                update_player: None,
+            },
+            &state,
+        )
+        .await;
+        assert!(!response.success);
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "PAD_NOT_FOUND" }))
+        );
+    }
+
+    #[test]
+    fn test_parse_command_rejections() {
+        let cases = [
+            (
+                r#"{"UpdateThreshold": {"profile_name": "P", "threshold_index": 18446744073709551615, "value": 1}}"#,
+                "INVALID_NUMBER",
+                Some("UpdateThreshold.threshold_index"),
+            ),
+            (
+                r#"{"UpdateThreshold": {"profile_name": "P", "threshold_index": 256, "value": 1}}"#,
+                "INVALID_NUMBER",
+                Some("UpdateThreshold.threshold_index"),
+            ),
+            (
+                r#"{"UpdateThreshold": {"profile_name": "P", "threshold_index": -1, "value": 1}}"#,
+                "INVALID_NUMBER",
+                Some("UpdateThreshold.threshold_index"),
+            ),
+            (
+                r#"{"UpdateThreshold": {"profile_name": "P", "threshold_index": 0, "value": 1.5}}"#,
+                "INVALID_NUMBER",
+                Some("UpdateThreshold.value"),
+            ),
+            (
+                r#"{"UpdateThreshold": {"profile_name": "P", "threshold_index": 0, "value": 1e400}}"#,
+                "INVALID_NUMBER",
+                Some("UpdateThreshold.value"),
+            ),
+            (
+                r#"{"UpdateThreshold": {"profile_name": "P", "threshold_index": 0, "value": 4294967296}}"#,
+                "INVALID_NUMBER",
+                Some("UpdateThreshold.value"),
+            ),
+            (
+                r#"{"AddProfile": {"name": "P", "thresholds": [1, 2, 3.0, 4]}}"#,
+                "INVALID_NUMBER",
+                Some("AddProfile.thresholds[2]"),
+            ),
+            (
+                r#"{"UpdateThreshold": {"profile_name": "P", "threshold_index": 0, "value": NaN}}"#,
+                "MALFORMED_JSON",
+                None,
+            ),
+            (
+                r#"{"UpdateThreshold": {"profile_name": "P""#,
+                "MALFORMED_JSON",
+                None,
+            ),
+            (r#""GetProfiles" "GetProfiles""#, "MALFORMED_JSON", None),
+            ("", "MALFORMED_JSON", None),
+            (
+                r#"{"AddProfile": {"name": "P", "thresholds": [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]}}"#,
+                "INVALID_COMMAND",
+                None,
+            ),
+            (
+                r#"{"UpdateThreshold": {"profile_name": 7, "threshold_index": 0, "value": 1}}"#,
+                "INVALID_COMMAND",
+                None,
+            ),
+            (r#""Explode""#, "INVALID_COMMAND", None),
+            (r#"{"GetProfiles": 5}"#, "INVALID_COMMAND", None),
+        ];
+        for (json, code, field) in cases {
+            let error = parse_command(json).expect_err(json);
+            assert_eq!(error.code(), code, "{}: {}", json, error);
+            if let Some(field) = field {
+                assert_eq!(
+                    error.to_response().params.unwrap()["field"],
+                    field,
+                    "{}",
+                    json
+                );
+            }
+        }
+        assert_eq!(
+            parse_command(
+                r#" {"UpdateThreshold": {"profile_name": "P", "threshold_index": 3, "value": -5}} "#
+            )
+            .unwrap(),
+            Command::UpdateThreshold {
+                profile_name: "P".to_string(),
+                threshold_index: 3,
+                value: -5,
+            }
+        );
+    }
+
+    // Mangled versions of valid commands never panic the parser or the command handler,
+    // and every rejection carries a code
+    #[tokio::test]
+    async fn test_hostile_commands_are_rejected_cleanly() {
+        let state = AppState::with_port(two_profiles(), Box::new(MockSerialPort::new([0; 4])));
+        let corpus = [
+            r#"{"UpdateThreshold": {"profile_name": "Profile1", "threshold_index": 3, "value": 500}}"#,
+            r#"{"AddProfile": {"name": "Fuzz", "thresholds": [1, 2, 3, 4], "layout": "dance4"}}"#,
+            r#"{"ListPlayers": {"offset": 18446744073709551615, "limit": 18446744073709551615}}"#,
+            r#"{"SetSensorMap": {"pad": null, "sensor_map": [3, 2, 1, 0]}}"#,
+            r#"{"Hello": {"protocol": 4294967295, "client_id": "x"}}"#,
+        ];
+        let replacements = [
+            "",
+            "-",
+            "1.5",
+            "-1e308",
+            "99999999999999999999",
+            "null",
+            "[]",
+            "{}",
+            "\"\"",
+            "\u{0}",
+            "\"é\"",
+        ];
+        // Deterministic pseudo-random positions, so a failure can be reproduced
+        let mut seed: u64 = 0x5eed;
+        let mut next = |bound: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % bound
+        };
+        for _ in 0..2000 {
+            let base = corpus[next(corpus.len())];
+            let mut cut = next(base.len());
+            while !base.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            let mut end = (cut + next(4)).min(base.len());
+            while !base.is_char_boundary(end) {
+                end -= 1;
+            }
+            let mangled = format!(
+                "{}{}{}",
+                &base[..cut],
+                replacements[next(replacements.len())],
+                &base[end..]
+            );
+            match parse_command(&mangled) {
+                Ok(command) => {
+                    let response = handle_command(command, &state).await;
+                    assert!(response.message_code.is_some(), "{}", mangled);
+                }
+                Err(e) => assert!(!e.code().is_empty(), "{}", mangled),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_rapid_threshold_updates_are_coalesced() {
+        let state = slow_device_state();
+        let update = |value: i32| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let command = Command::UpdateThreshold {
+                    profile_name: "Profile1".to_string(),
+                    threshold_index: 1,
+                    value,
+                };
+                handle_command(command, &state).await
+            })
+        };
+        // A slider drag: the first update starts writing, the rest arrive during the write
+        let mut updates = vec![update(100)];
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        updates.extend((101..150).map(update));
+        let mut responses = Vec::new();
+        for update in updates {
+            responses.push(update.await.unwrap());
+        }
+
+        // The first value and the newest one reach the device, nothing in between
+        assert_eq!(state.metrics.snapshot().serial_writes, 2);
+        assert!(responses.iter().all(|response| response.success));
+        let (updated, coalesced): (Vec<_>, Vec<_>) = responses
+            .iter()
+            .partition(|response| response.message_code.as_deref() == Some("THRESHOLD_UPDATED"));
+        assert_eq!(updated.len(), 1);
+        let written = updated[0].params.as_ref().unwrap()["value"]
+            .as_i64()
+            .unwrap() as i32;
+        assert_ne!(written, 100);
+        for response in coalesced {
+            assert_eq!(
+                response.message_code.as_deref(),
+                Some("THRESHOLD_COALESCED")
+            );
+            assert_eq!(response.params.as_ref().unwrap()["final"], written);
+        }
+        assert_eq!(
+            state.profiles_snapshot().profiles["Profile1"].thresholds,
+            [10, written, 30, 40]
+        );
+        let device = get_current_thresholds_from_device(&state.serial)
+            .await
+            .unwrap();
+        assert_eq!(device[1], written);
+
+        // With nothing in flight, an update goes straight through
+        let response = update(7).await.unwrap();
+        assert_eq!(response.message_code.as_deref(), Some("THRESHOLD_UPDATED"));
+        assert_eq!(state.metrics.snapshot().serial_writes, 3);
+    }
+
+    #[tokio::test]
+    async fn test_serial_queue_overflow() {
+        let state = AppState {
+            serial_queue: Arc::new(SerialQueue::new(2)),
+            ..AppState::with_port(
+                two_profiles(),
+                Box::new(MockSerialPort::new([10, 20, 30, 40])),
+            )
+        };
+        let update = |threshold_index: u8| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let command = Command::UpdateThreshold {
+                    profile_name: "Profile1".to_string(),
+                    threshold_index,
+                    value: 500,
+                };
+                handle_command(command, &state).await
+            })
+        };
+        // The device is held, so both wait for it in the queue
+        let held = state.serial.clone().lock_owned().await;
+        let queued = [update(0), update(2)];
+        while state.serial_queue.stats().depth < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // A third is refused instead of waiting its turn
+        let response = update(3).await.unwrap();
+        assert_eq!(response.message_code.as_deref(), Some("SERIAL_BUSY"));
+        assert_eq!(
+            response.params,
+            Some(serde_json::json!({ "depth": 2, "limit": 2 }))
+        );
+        // A stream read arriving now is dropped so the queued commands go first
+        assert!(!state.serial_queue.admit_read());
+
+        drop(held);
+        for update in queued {
+            let response = update.await.unwrap();
+            assert_eq!(response.message_code.as_deref(), Some("THRESHOLD_UPDATED"));
+        }
+        assert!(state.serial_queue.admit_read());
+        let response = handle_command(Command::GetSerialStats, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SERIAL_STATS"));
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({
+                "depth": 0,
+                "max_depth": 2,
+                "limit": 2,
+                "rejected": 1,
+                "dropped_reads": 1,
+            }))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_idempotency_keys() {
+        let state = slow_device_state();
+        let add = |key: &str| {
+            let text = serde_json::json!({
+                "AddProfile": { "name": "Third", "thresholds": [1, 2, 3, 4] },
+                "idempotency_key": key,
+            });
+            parse_envelope(&text.to_string()).unwrap()
+        };
+        assert_eq!(add("retry-1").idempotency_key.as_deref(), Some("retry-1"));
+        let first = handle_envelope(add("retry-1"), &state).await;
+        assert_eq!(first.message_code.as_deref(), Some("PROFILE_ADDED"));
+
+        // The retry gets the original answer, not PROFILE_EXISTS
+        let retry = handle_envelope(add("retry-1"), &state).await;
+        assert_eq!(retry, first);
+        assert_eq!(state.metrics.snapshot().commands["AddProfile"], 1);
+        let other = handle_envelope(add("retry-2"), &state).await;
+        assert_eq!(other.message_code.as_deref(), Some("PROFILE_EXISTS"));
+
+        // A retry sent while the first is still writing to the device waits for it
+        let change = parse_envelope(
+            r#"{"ChangeProfile": {"name": "Profile2"}, "idempotency_key": "change"}"#,
+        )
+        .unwrap();
+        let first = tokio::spawn({
+            let state = state.clone();
+            let change = change.clone();
+            async move { handle_envelope(change, &state).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let retry = handle_envelope(change, &state).await;
+        assert_eq!(retry, first.await.unwrap());
+        assert!(retry.success);
+        assert_eq!(state.metrics.snapshot().serial_writes, 4);
+
+        // Keys only apply to mutating commands, and must be usable strings
+        let envelope = parse_envelope(r#"{"GetProfiles": null, "idempotency_key": "x"}"#);
+        assert_eq!(envelope.unwrap().command, Command::GetProfiles);
+        for text in [
+            r#"{"GetProfiles": null, "idempotency_key": 5}"#,
+            r#"{"GetProfiles": null, "idempotency_key": ""}"#,
+        ] {
+            let error = parse_envelope(text).expect_err(text);
+            assert_eq!(error.code(), "INVALID_COMMAND");
+        }
+        // The command itself is reported like without the key
+        let text = r#"{"UpdateThreshold": {"profile_name": "P", "threshold_index": 0, "value": 1.5}, "idempotency_key": "x"}"#;
+        assert_eq!(parse_envelope(text).unwrap_err().code(), "INVALID_NUMBER");
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_changes_nothing() {
+        let state =
+            AppState::with_port(two_profiles(), Box::new(MockSerialPort::new([1, 2, 3, 4])));
+        let before = state.profiles_snapshot();
+        let dry_run = |command: serde_json::Value| {
+            let mut text = command;
+            text["dry_run"] = true.into();
+            let envelope = parse_envelope(&text.to_string()).unwrap();
+            assert!(envelope.dry_run);
+            handle_envelope(envelope, &state)
+        };
+
+        let response =
+            dry_run(serde_json::json!({ "ChangeProfile": { "name": "Profile2" } })).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_CHANGED"));
+        assert!(response.dry_run);
+        // The answer shows the profiles as they would be
+        assert_eq!(response.data.unwrap().current_profile(), "Profile2");
+
+        let response = dry_run(serde_json::json!({
+            "UpdateThreshold": { "profile_name": "Profile1", "threshold_index": 0, "value": 99 }
+        }))
+        .await;
+        assert_eq!(response.message_code.as_deref(), Some("THRESHOLD_UPDATED"));
+        let response = dry_run(serde_json::json!({
+            "AddProfile": { "name": "Profile1", "thresholds": [1, 2, 3, 4] }
+        }))
+        .await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_EXISTS"));
+        assert!(response.dry_run);
+        let response = dry_run(serde_json::json!({ "StartSensorStream": null })).await;
+        assert!(response.success);
+        let response = dry_run(serde_json::json!({ "IdentifyPad": {} })).await;
+        assert_eq!(response.message_code.as_deref(), Some("INVALID_COMMAND"));
+
+        assert!(Arc::ptr_eq(&state.profiles_snapshot(), &before));
+        assert!(!state.stream_enabled());
+        assert_eq!(state.metrics.snapshot().serial_writes, 0);
+        let device = get_current_thresholds_from_device(&state.serial)
+            .await
+            .unwrap();
+        assert_eq!(device, [1, 2, 3, 4]);
+
+        // A write to a disconnected device would fail, so the dry run does too
+        let state = AppState {
+            health: Arc::new(crate::health::Health::new(false)),
+            ..state
+        };
+        let envelope =
+            parse_envelope(r#"{"ChangeProfile": {"name": "Profile2"}, "dry_run": true}"#).unwrap();
+        let response = handle_envelope(envelope, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SERIAL_IO"));
+        assert!(response.dry_run);
+    }
+
+    #[tokio::test]
+    async fn test_retry_load_profiles() {
+        let state = AppState::with_mock_port(Profiles::default());
+        let response = handle_command(Command::RetryLoadProfiles, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("INVALID_COMMAND"));
+
+        let path =
+            std::env::temp_dir().join(format!("fsr-rs-retry-load-{}.json", std::process::id()));
+        std::fs::write(&path, "{\"profiles\": {,}}").unwrap();
+        let failure = serde_json::from_str::<Profiles>("{,}").unwrap_err();
+        state.set_load_failure(Some(LoadFailure::new(&failure, path.display().to_string())));
+
+        // Still broken; the failure now tells where
+        let response = handle_command(Command::RetryLoadProfiles, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("LOAD_FAILED"));
+        assert_eq!(state.load_failure().unwrap().column, 15);
+
+        // Fixed by hand, but the current profile was deleted along the way
+        let mut fixed = two_profiles();
+        fixed.pads[0].current_profile = "Deleted".to_string();
+        std::fs::write(&path, serde_json::to_string(&fixed).unwrap()).unwrap();
+        let envelope = Envelope {
+            command: Command::RetryLoadProfiles,
+            idempotency_key: None,
+            dry_run: true,
+            expected_revision: None,
+            origin: Origin::default(),
+        };
+        let response = handle_envelope(envelope, &state).await;
+        assert!(response.success);
+        assert!(state.profiles_snapshot().profiles.is_empty());
+        assert!(state.load_failure().is_some());
+
+        let mut rx = state.events.subscribe();
+        let writes = state.metrics.snapshot().serial_writes;
+        let response = handle_command(Command::RetryLoadProfiles, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILES_RELOADED"));
+        assert_eq!(response.params.unwrap()["profiles"], 2);
+        assert_eq!(response.payload.unwrap()["repairs"][0]["to"], "Profile1");
+        // Back on the first profile by name, which the device got as well
+        // The same but for the revisions, which count the reload
+        let mut reloaded = (*state.profiles_snapshot()).clone();
+        assert_eq!(reloaded.revision, 1);
+        reloaded.revision = 0;
+        for profile in reloaded.profiles.values_mut() {
+            profile.revision = 0;
+        }
+        assert_eq!(reloaded, two_profiles());
+        assert_eq!(state.metrics.snapshot().serial_writes, writes + 4);
+        assert_eq!(state.load_failure(), None);
+        let Ok(Event::ProfilesRepaired(repairs)) = rx.try_recv() else {
+            panic!("repairs weren't announced");
+        };
+        assert_eq!(repairs.len(), 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_clear_default_profile() {
+        let mut profiles = two_profiles();
+        profiles.default_profile = "Profile2".to_string();
+        let state = AppState::with_mock_port(profiles);
+        let set_default = |name: Option<&str>| Command::SetDefaultProfile {
+            name: name.map(String::from),
+            pad: None,
+        };
+
+        let response = handle_command(set_default(None), &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("DEFAULT_PROFILE_CLEARED")
+        );
+        assert_eq!(response.previous.unwrap()["profile"], "Profile2");
+        assert_eq!(state.profiles_snapshot().default_profile, "");
+        // A new player then gets the profile the pad is on
+        let create = Command::ChangePlayer {
+            name: "Alice".to_string(),
+            pad: None,
+        };
+        let response = handle_command(create, &state).await;
+        assert_eq!(response.params.unwrap()["profile"], "Profile1");
+
+        // Removing the default profile clears it, with a notice
+        handle_command(set_default(Some("Profile2")), &state).await;
+        let remove = Command::RemoveProfile {
+            name: "Profile2".to_string(),
+            confirm: false,
+            reassign_to: None,
+        };
+        let response = handle_command(remove, &state).await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(response.params.unwrap()["default_cleared"], true);
+        assert!(response.message.contains("it was the default profile"));
+        assert_eq!(state.profiles_snapshot().default_profile, "");
+    }
+
+    #[tokio::test]
+    async fn test_remove_profile_players_have() {
+        let mut profiles = two_profiles();
+        for name in ["Bob", "Alice"] {
+            let player = Player {
+                name: name.to_string(),
+                profile: "Profile1".to_string(),
+            };
+            profiles.players.insert(name.to_string(), player);
+        }
+        profiles.pads[0].current_player = "Alice".to_string();
+        let state = AppState::with_port(profiles, Box::new(MockSerialPort::new([10, 20, 30, 40])));
+        let remove = |confirm: bool, reassign_to: Option<&str>| Command::RemoveProfile {
+            name: "Profile1".to_string(),
+            confirm,
+            reassign_to: reassign_to.map(str::to_string),
+        };
+
+        for command in [remove(false, Some("Profile2")), remove(true, None)] {
+            let response = handle_command(command, &state).await;
+            assert_eq!(
+                response.message_code.as_deref(),
+                Some("CONFIRMATION_REQUIRED")
+            );
+            assert_eq!(
+                response.params,
+                Some(serde_json::json!({
+                    "profile": "Profile1",
+                    "players": ["Alice", "Bob"],
+                }))
+            );
+        }
+        let response = handle_command(remove(true, Some("Profile1")), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("INVALID_COMMAND"));
+        let response = handle_command(remove(true, Some("Missing")), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_NOT_FOUND"));
+        assert_eq!(state.metrics.snapshot().serial_writes, 0);
+
+        let response = handle_command(remove(true, Some("Profile2")), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_REMOVED"));
+        assert_eq!(
+            response.params,
+            Some(serde_json::json!({
+                "profile": "Profile1",
+                "reassigned_to": "Profile2",
+                "players": ["Alice", "Bob"],
+            }))
+        );
+        let profiles = state.profiles_snapshot();
+        assert!(!profiles.profiles.contains_key("Profile1"));
+        assert!(profiles
+            .players
+            .values()
+            .all(|player| player.profile == "Profile2"));
+        // Alice was playing on it, so the device moved along
+        assert_eq!(profiles.current_profile(), "Profile2");
+        let device = get_current_thresholds_from_device(&state.serial)
+            .await
+            .unwrap();
+        assert_eq!(device, [50, 60, 70, 80]);
+    }
+
+    #[tokio::test]
+    async fn test_quotas() {
+        let quotas = Quotas {
+            max_profiles: 2,
+            max_players: 1,
+        };
+        let state = AppState {
+            info: Arc::new(ServerInfo {
+                quotas,
+                ..ServerInfo::default()
+            }),
+            ..AppState::with_port(two_profiles(), Box::new(MockSerialPort::new([0; 4])))
+        };
+        let add = Command::AddProfile {
+            name: "Third".to_string(),
+            thresholds: [1, 2, 3, 4].into(),
+            layout: None,
+        };
+        let response = handle_command(add, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("QUOTA_EXCEEDED"));
+        assert_eq!(
+            response.params,
+            Some(serde_json::json!({ "kind": "profiles", "limit": 2 }))
+        );
+
+        let change = |name: &str| Command::ChangePlayer {
+            name: name.to_string(),
+            pad: None,
+        };
+        assert!(handle_command(change("Alice"), &state).await.success);
+        let response = handle_command(change("Bob"), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("QUOTA_EXCEEDED"));
+        // Existing players can still be selected
+        assert!(handle_command(change("Alice"), &state).await.success);
+
+        let lower = Quotas {
+            max_profiles: 1,
+            ..quotas
+        };
+        assert_eq!(lower.warnings(&state.profiles_snapshot()).len(), 1);
+        assert!(quotas.warnings(&state.profiles_snapshot()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_threshold_bound() {
+        let state = AppState::with_port(
+            two_profiles(),
+            Box::new(MockSerialPort::new([10, 20, 30, 40])),
+        );
+        let update = |value| Command::UpdateThreshold {
+            profile_name: "Profile1".to_string(),
+            threshold_index: 0,
+            value,
+        };
+
+        let response = handle_command(update(2000), &state).await;
+        assert!(!response.success);
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("THRESHOLD_OUT_OF_RANGE")
+        );
+        assert_eq!(
+            response.params,
+            Some(serde_json::json!({ "value": 2000, "min": 0, "max": 1023, "source": "default" }))
+        );
+        assert_eq!(state.metrics.snapshot().serial_writes, 0);
+
+        let add = Command::AddProfile {
+            name: "Negative".to_string(),
+            thresholds: [10, -1, 30, 40].into(),
+            layout: None,
+        };
+        assert!(!handle_command(add, &state).await.success);
+
+        // A 12-bit board shows itself by its readings
+        state.range.observe(&[10, 3500, 30, 40]);
+        let response = handle_command(update(2000), &state).await;
+        assert!(response.success, "{}", response.message);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_layout_is_not_written() {
+        let mut profiles = two_profiles();
+        // As if profiles.json was edited by hand
+        profiles.profiles.get_mut("Profile2").unwrap().layout = "dance3".to_string();
+        let state = AppState::with_port(profiles, Box::new(MockSerialPort::new([10, 20, 30, 40])));
+
+        let name = "Profile2".to_string();
+        let response = handle_command(
+            Command::ChangeProfile {
+                name,
+                pad: None,
+                update_player: None,
+            },
+            &state,
+        )
+        .await;
+        assert!(!response.success);
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "UNKNOWN_LAYOUT" }))
+        );
+        assert_eq!(state.metrics.snapshot().serial_writes, 0);
+        assert_eq!(state.profiles_snapshot().current_profile(), "Profile1");
+
+        let add = |layout: &str| Command::AddProfile {
+            name: "Wired".to_string(),
+            thresholds: [1, 2, 3, 4].into(),
+            layout: Some(layout.to_string()),
+        };
+        let response = handle_command(add("dance3"), &state).await;
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "UNKNOWN_LAYOUT" }))
+        );
+        // Four thresholds can't be for nine panels
+        let response = handle_command(add("pad9"), &state).await;
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "INVALID_THRESHOLD_COUNT" }))
+        );
+        assert!(handle_command(add("custom"), &state).await.success);
+
+        // The device's sensors are counted once its thresholds were read
+        let layouts = || async {
+            let response = handle_command(Command::GetLayouts, &state).await;
+            response.payload.unwrap()
+        };
+        assert_eq!(layouts().await["device_sensors"], serde_json::Value::Null);
+        assert!(
+            handle_command(Command::GetCurrentThresholds, &state)
+                .await
+                .success
+        );
+        let payload = layouts().await;
+        assert_eq!(payload["device_sensors"], 4);
+        assert_eq!(payload["layouts"][0]["labels"][2], "Up");
+
+        // A five sensor profile isn't written to the four sensor device
+        let pump = Command::AddProfile {
+            name: "Pump".to_string(),
+            thresholds: [1, 2, 3, 4, 5].into(),
+            layout: None,
+        };
+        assert!(handle_command(pump, &state).await.success);
+        let writes = state.metrics.snapshot().serial_writes;
+        let response = handle_command(
+            Command::ChangeProfile {
+                name: "Pump".to_string(),
+                pad: None,
+                update_player: None,
+            },
+            &state,
+        )
+        .await;
+        assert!(!response.success);
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "LAYOUT_MISMATCH" }))
+        );
+        assert_eq!(state.metrics.snapshot().serial_writes, writes);
+        let profiles = state.profiles_snapshot();
+        assert_eq!(profiles.profiles["Pump"].layout, "pump5");
+        assert_eq!(profiles.current_profile(), "Profile1");
+    }
+
+    #[tokio::test]
+    async fn test_nine_sensor_pad() {
+        let profiles = Profiles {
+            profiles: HashMap::from([
+                ("Nine".to_string(), Profile::new([100; 9])),
+                ("Steep".to_string(), Profile::new([500; 9])),
+            ]),
+            pads: vec![Pad::default_pad("Nine".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        let state = AppState::with_port(profiles, Box::new(MockSerialPort::new([100; 9])));
+        assert_eq!(state.profiles_snapshot().profiles["Nine"].layout, "pad9");
+
+        let change = Command::ChangeProfile {
+            name: "Steep".to_string(),
+            pad: None,
+            update_player: None,
+        };
+        let response = handle_command(change, &state).await;
+        assert!(response.success, "{}", response.message);
+        let device = get_current_thresholds_from_device(&state.serial)
+            .await
+            .unwrap();
+        assert_eq!(device, [500; 9]);
+
+        // The last panel has an index, and a tenth doesn't
+        let update = |threshold_index| Command::UpdateThreshold {
+            profile_name: "Steep".to_string(),
+            threshold_index,
+            value: 450,
+        };
+        assert!(handle_command(update(8), &state).await.success);
+        let response = handle_command(update(9), &state).await;
+        assert_eq!(response.message, "Threshold index must be 0-8");
+        let response = handle_command(Command::GetCurrentThresholds, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("THRESHOLDS_IN_SYNC"));
+    }
+
+    #[tokio::test]
+    async fn test_sensor_map_round_trip() {
+        let state = AppState::with_port(
+            two_profiles(),
+            Box::new(MockSerialPort::new([10, 20, 30, 40])),
+        );
+
+        let set_map = |sensor_map| Command::SetSensorMap {
+            pad: None,
+            sensor_map,
+        };
+        let response = handle_command(set_map(vec![0, 1, 1, 3]), &state).await;
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "INVALID_SENSOR_MAP" }))
+        );
+        let response = handle_command(set_map(vec![3, 2, 1, 0]), &state).await;
+        assert!(response.success, "{}", response.message);
+        // The device's thresholds moved with the wiring
+        assert_eq!(
+            get_current_thresholds_from_device(&state.serial)
+                .await
+                .unwrap(),
+            [40, 30, 20, 10]
+        );
+
+        let response = handle_command(
+            Command::UpdateThreshold {
+                profile_name: "Profile1".to_string(),
+                threshold_index: 0,
+                value: 123,
+            },
+            &state,
+        )
+        .await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(
+            get_current_thresholds_from_device(&state.serial)
+                .await
+                .unwrap(),
+            [40, 30, 20, 123]
+        );
+        let response = handle_command(Command::GetCurrentThresholds, &state).await;
+        assert!(response
+            .message
+            .contains("[123, 20, 30, 40] (device synchronized)"));
+        assert_eq!(state.metrics.snapshot().serial_writes, 5);
+
+        let response = handle_command(Command::GetPadMapping, &state).await;
+        assert_eq!(
+            response.payload.unwrap()["pads"][0]["sensor_map"],
+            serde_json::json!([3, 2, 1, 0])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_pad_leaves_device_alone() {
+        let state = AppState::with_port(two_profiles(), Box::new(MockSerialPort::new([0; 4])));
+        let set_enabled = |enabled| Command::SetPadEnabled {
+            pad: "default".to_string(),
+            enabled,
+        };
+
+        assert!(handle_command(set_enabled(false), &state).await.success);
+        assert!(!state.profiles_snapshot().device_enabled());
+        let name = "Profile2".to_string();
+        let response = handle_command(
+            Command::ChangeProfile {
+                name,
+                pad: None,
+                update_player: None,
+            },
+            &state,
+        )
+        .await;
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "PAD_DISABLED" }))
+        );
+        assert!(
+            !handle_command(Command::GetCurrentThresholds, &state)
+                .await
+                .success
+        );
+        // Configuration can still be changed
+        let response = handle_command(
+            Command::SetDefaultProfile {
+                name: Some("Profile2".to_string()),
+                pad: Some("default".to_string()),
+            },
+            &state,
+        )
+        .await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(state.metrics.snapshot().serial_writes, 0);
+
+        // Coming back puts the current profile on the device
+        assert!(handle_command(set_enabled(true), &state).await.success);
+        assert_eq!(
+            get_current_thresholds_from_device(&state.serial)
+                .await
+                .unwrap(),
+            [10, 20, 30, 40]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_every_response_has_a_message_code() {
+        let mut profiles = two_profiles();
+        profiles.pads.push(Pad {
+            id: "p2".to_string(),
+            ..Pad::default_pad("Profile1".to_string(), String::new())
+        });
+        let state = AppState::with_port(profiles, Box::new(MockSerialPort::new([10, 20, 30, 40])));
+        let name = |name: &str| name.to_string();
+        let p2 = Some(name("p2"));
+        let commands = vec![
+            Command::UpdateThreshold {
+                profile_name: name("Profile1"),
+                threshold_index: 0,
+                value: 15,
+            },
+            Command::ProposeThresholdChange {
+                profile: name("Profile1"),
+                index: 1,
+                value: 25,
+                note: None,
+            },
+            Command::ListProposals,
+            Command::RejectProposal {
+                id: 1,
+                reason: None,
+            },
+            Command::ApproveProposal {
+                id: 1,
+                force: false,
+            },
+            Command::AddProfile {
+                name: name("Profile3"),
+                thresholds: [1, 2, 3, 4].into(),
+                layout: None,
+            },
+            Command::RemoveProfile {
+                name: name("Profile3"),
+                confirm: false,
+                reassign_to: None,
+            },
+            Command::ChangeProfile {
+                name: name("Profile2"),
+                pad: None,
+                update_player: None,
+            },
+            Command::ChangePlayer {
+                name: name("Player1"),
+                pad: None,
+            },
+            Command::ChangePlayer {
+                name: name("Player1"),
+                pad: p2.clone(),
+            },
+            Command::SetDefaultProfile {
+                name: Some(name("Profile1")),
+                pad: None,
+            },
+            Command::SetDefaultProfile {
+                name: Some(name("Profile2")),
+                pad: p2.clone(),
+            },
+            Command::SetDefaultProfile {
+                name: None,
+                pad: p2.clone(),
+            },
+            Command::GetCurrentThresholds,
+            Command::GetProfiles,
+            Command::GetSensorValues,
+            Command::ReadSensors,
+            Command::StartSensorStream,
+            Command::StopSensorStream,
+            Command::GetStreamStatus,
+            Command::GetWebhookStatus,
+            Command::GetServerStats,
+            Command::GetSerialStats,
+            Command::GetLatencyStats,
+            Command::ResetLatencyStats,
+            Command::RunSelfTest,
+            Command::GetClients,
+            Command::GetServerInfo,
+            Command::RetryLoadProfiles,
+            Command::SetAutosave { enabled: true },
+            Command::ListSinks,
+            Command::EnableSink {
+                name: name("webhook-1"),
+            },
+            Command::DisableSink {
+                name: name("webhook-1"),
+            },
+            Command::SaveProfiles,
+            Command::GetErrorLog { limit: Some(1) },
+            Command::GetThresholdWriteLog { limit: Some(1) },
+            Command::GetCommandHistory {
+                limit: None,
+                filter: None,
+            },
+            Command::StopSerialCapture,
+            Command::AssignPadPort {
+                pad: name("p2"),
+                port_or_device_id: name("COM9"),
+            },
+            Command::GetPadMapping,
+            Command::GetLayouts,
+            Command::ListPlayers {
+                offset: 0,
+                limit: None,
+                filter: Some(name("player")),
+            },
+            Command::Hello {
+                protocol: 1,
+                client_id: Some(name("overlay")),
+            },
+            Command::SetSensorMap {
+                pad: None,
+                sensor_map: vec![1, 0, 2, 3],
+            },
+            Command::SetCalibration {
+                profile_name: name("Profile1"),
+                gain: Some([1.0, 2.0, 1.0, 1.0].into()),
+                offset: None,
+            },
+            Command::CalibrateGain {
+                profile_name: name("Profile1"),
+                duration_ms: Some(1),
+            },
+            Command::SetDriftCompensation {
+                profile_name: name("Profile1"),
+                enabled: true,
+            },
+            Command::GetDriftCompensation,
+            Command::SetPressParameters {
+                profile_name: name("Profile1"),
+                hysteresis: [10; 4].into(),
+                debounce_ms: 5,
+            },
+            Command::SetProfileTags {
+                name: name("Profile1"),
+                tags: vec![name("kids")],
+            },
+            Command::ListProfiles {
+                tag_filter: Some(name("kids")),
+            },
+            Command::ShareProfile {
+                name: name("Profile1"),
+            },
+            Command::ImportSharedProfile {
+                code: name("fsr:AQ"),
+                rename_to: None,
+            },
+            Command::ImportExternal {
+                format: name("ini"),
+                payload: name("[Casual]"),
+                on_conflict: OnConflict::Fail,
+            },
+            Command::SetPadEnabled {
+                pad: name("p2"),
+                enabled: false,
+            },
+            Command::Subscribe {
+                topics: Vec::new(),
+                scale: Scale::Raw,
+            },
+            Command::IdentifyPad {
+                pad: None,
+                force: true,
+            },
+            Command::StartPanelTest {
+                timeout_ms: Some(1),
+            },
+            Command::CancelPanelTest,
+            Command::CreateSnapshot {
+                label: Some("x".repeat(MAX_LABEL_LEN + 1)),
+            },
+            Command::RestoreSnapshot {
+                id: name("../profiles"),
+            },
+            Command::ListSnapshots,
+            Command::ApplyTemporaryProfile {
+                name: name("Missing"),
+                duration_secs: 60,
+                pad: None,
+            },
+            Command::RevertTemporaryProfile { pad: None },
+            Command::ClearCurrentPlayer { pad: None },
+            Command::AddSchedule {
+                cron_or_time: name("25:00"),
+                profile: name("Profile1"),
+                pad: None,
+            },
+            Command::ListSchedules,
+            Command::RemoveSchedule { id: name("1") },
+            Command::ChangeProfile {
+                name: name("Missing"),
+                pad: None,
+                update_player: None,
+            },
+        ];
+
+        for command in commands {
+            // Fails to compile when a command is added, as a reminder to add it above
+            match command {
+                Command::UpdateThreshold { .. }
+                | Command::ProposeThresholdChange { .. }
+                | Command::ListProposals
+                | Command::ApproveProposal { .. }
+                | Command::RejectProposal { .. }
+                | Command::AddProfile { .. }
+                | Command::RemoveProfile { .. }
+                | Command::ChangeProfile { .. }
+                | Command::ChangePlayer { .. }
+                | Command::ClearCurrentPlayer { .. }
+                | Command::SetDefaultProfile { .. }
+                | Command::GetCurrentThresholds
+                | Command::GetProfiles
+                | Command::GetSensorValues
+                | Command::ReadSensors
+                | Command::StartSensorStream
+                | Command::StopSensorStream
+                | Command::GetStreamStatus
+                | Command::GetWebhookStatus
+                | Command::GetServerStats
+                | Command::GetSerialStats
+                | Command::GetLatencyStats
+                | Command::ResetLatencyStats
+                | Command::RunSelfTest
+                | Command::GetClients
+                | Command::GetServerInfo
+                | Command::RetryLoadProfiles
+                | Command::SetAutosave { .. }
+                | Command::ListSinks
+                | Command::EnableSink { .. }
+                | Command::DisableSink { .. }
+                | Command::SaveProfiles
+                | Command::GetErrorLog { .. }
+                | Command::GetThresholdWriteLog { .. }
+                | Command::GetCommandHistory { .. }
+                | Command::StartSerialCapture { .. }
+                | Command::StopSerialCapture
+                | Command::AssignPadPort { .. }
+                | Command::GetPadMapping
+                | Command::GetLayouts
+                | Command::ListPlayers { .. }
+                | Command::Hello { .. }
+                | Command::SetSensorMap { .. }
+                | Command::SetCalibration { .. }
+                | Command::CalibrateGain { .. }
+                | Command::SetDriftCompensation { .. }
+                | Command::GetDriftCompensation
+                | Command::SetProfileTags { .. }
+                | Command::SetPressParameters { .. }
+                | Command::ListProfiles { .. }
+                | Command::ShareProfile { .. }
+                | Command::ImportSharedProfile { .. }
+                | Command::ImportExternal { .. }
+                | Command::SetPadEnabled { .. }
+                | Command::Subscribe { .. }
+                | Command::StartPanelTest { .. }
+                | Command::CancelPanelTest
+                | Command::CreateSnapshot { .. }
+                | Command::RestoreSnapshot { .. }
+                | Command::ListSnapshots
+                | Command::AddSchedule { .. }
+                | Command::ListSchedules
+                | Command::RemoveSchedule { .. }
+                | Command::ApplyTemporaryProfile { .. }
+                | Command::RevertTemporaryProfile { .. }
+                | Command::IdentifyPad { .. } => {}
+            }
+            let command_name = command.name();
+            let response = handle_command(command, &state).await;
+            let code = response.message_code.unwrap_or_default();
+            assert!(!code.is_empty(), "{} has no message code", command_name);
+            if !response.success {
+                // Failures use the error code
+                assert_eq!(response.payload.unwrap()["code"], code);
+            }
+        }
+
+        let response = handle_command(
+            Command::ChangePlayer {
+                name: name("Player2"),
+                pad: None,
+            },
+            &state,
+        )
+        .await;
+        assert_eq!(response.message_code.as_deref(), Some("PLAYER_CREATED"));
+        assert_eq!(
+            response.params,
+            Some(serde_json::json!({
+                "player": "Player2",
+                "profile": "Profile1",
+                "pad": "default",
+            }))
+        );
+        let response = handle_command(
+            Command::RemoveProfile {
+                name: name("Missing"),
+                confirm: false,
+                reassign_to: None,
+            },
+            &state,
+        )
+        .await;
+        assert_eq!(response.message_code.as_deref(), Some("PROFILE_NOT_FOUND"));
+        assert_eq!(
+            response.params,
+            Some(serde_json::json!({ "profile": "Missing" }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serial_failure_is_journaled() {
+        let path = std::env::temp_dir().join(format!(
+            "fsr-rs-commands-journal-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let state = AppState {
+            journal: Arc::new(Journal::spawn(path.clone(), JOURNAL_MAX_BYTES)),
+            ..AppState::with_port(two_profiles(), Box::new(DummySerialPort))
+        };
+
+        let name = "Profile2".to_string();
+        assert!(
+            !handle_command(
+                Command::ChangeProfile {
+                    name,
+                    pad: None,
+                    update_player: None
+                },
+                &state
+            )
+            .await
+            .success
+        );
+
+        // The journal is written in the background
+        let mut entries = serde_json::Value::Null;
+        for _ in 0..100 {
+            let response = handle_command(Command::GetErrorLog { limit: Some(10) }, &state).await;
+            assert!(response.success);
+            entries = response.payload.unwrap()["entries"].clone();
+            if entries
+                .as_array()
+                .is_some_and(|entries| !entries.is_empty())
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(entries[0]["kind"], "serial_error");
+        assert!(entries[0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("ChangeProfile: Failed to set thresholds"));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_command_history_attributes_changes() {
+        let state = AppState::with_mock_port(two_profiles());
+        let from = |command, dry_run| Envelope {
+            command,
+            idempotency_key: None,
+            dry_run,
+            expected_revision: None,
+            origin: Origin::Websocket { connection: 7 },
+        };
+        let update = || Command::UpdateThreshold {
+            profile_name: "Profile1".to_string(),
+            threshold_index: 2,
+            value: 35,
+        };
+        handle_envelope(from(update(), false), &state).await;
+        // Dry runs and reads change nothing and aren't recorded
+        handle_envelope(from(update(), true), &state).await;
+        handle_envelope(from(Command::GetProfiles, false), &state).await;
+        let missing = Command::ChangeProfile {
+            name: "Missing".to_string(),
+            pad: None,
+            update_player: None,
+        };
+        handle_command(missing, &state).await;
+
+        let history = |filter: Option<&str>| Command::GetCommandHistory {
+            limit: None,
+            filter: filter.map(String::from),
+        };
+        let response = handle_command(history(None), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("COMMAND_HISTORY"));
+        let entries = response.payload.unwrap()["entries"].clone();
+        assert_eq!(entries.as_array().unwrap().len(), 2);
+        assert_eq!(entries[0]["command"], "UpdateThreshold");
+        assert_eq!(entries[0]["params"]["value"], 35);
+        assert_eq!(entries[0]["transport"], "websocket");
+        assert_eq!(entries[0]["connection"], 7);
+        assert_eq!(entries[0]["success"], true);
+        assert_eq!(entries[0]["code"], "THRESHOLD_UPDATED");
+        assert_eq!(entries[1]["transport"], "internal");
+        assert_eq!(entries[1]["success"], false);
+        assert_eq!(entries[1]["code"], "PROFILE_NOT_FOUND");
+
+        let response = handle_command(history(Some("threshold")), &state).await;
+        assert_eq!(response.params.unwrap()["count"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_and_restore() {
+        let dir = std::env::temp_dir().join(format!("fsr-rs-snapshots-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let state = AppState {
+            snapshots: Arc::new(SnapshotStore::new(dir.clone(), 2)),
+            ..AppState::with_port(
+                two_profiles(),
+                Box::new(MockSerialPort::new([11, 20, 30, 40])),
+            )
+        };
+        let create = |label: &str| Command::CreateSnapshot {
+            label: Some(label.to_string()),
+        };
+
+        let response = handle_command(create("before the event"), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SNAPSHOT_CREATED"));
+        let params = response.params.unwrap();
+        assert_eq!(params["device_read"], true);
+        // What the device really had, not the profile's thresholds
+        assert_eq!(
+            response.payload.unwrap()["snapshot"]["device_thresholds"],
+            serde_json::json!([11, 20, 30, 40])
+        );
+        let id = params["id"].as_str().unwrap().to_string();
+
+        for command in [
+            Command::ChangeProfile {
+                name: "Profile2".to_string(),
+                pad: None,
+                update_player: None,
+            },
+            Command::UpdateThreshold {
+                profile_name: "Profile1".to_string(),
+                threshold_index: 0,
+                value: 99,
+            },
+        ] {
+            assert!(handle_command(command, &state).await.success);
+        }
+        let revision = state.profiles_snapshot().revision;
+
+        let restore = |id: &str| Command::RestoreSnapshot { id: id.to_string() };
+        let response = handle_command(restore(&id), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SNAPSHOT_RESTORED"));
+        let restored = state.profiles_snapshot();
+        assert_eq!(restored.current_profile(), "Profile1");
+        assert_eq!(restored.profiles["Profile1"].thresholds, [10, 20, 30, 40]);
+        // Clients holding the revision from before the restore get CONFLICT
+        assert!(restored.revision > revision);
+        // The restored current profile is on the device
+        let response = handle_command(Command::GetCurrentThresholds, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("THRESHOLDS_IN_SYNC"));
+
+        // Only the newest two are kept
+        for label in ["second", "third"] {
+            assert!(handle_command(create(label), &state).await.success);
+        }
+        let response = handle_command(Command::ListSnapshots, &state).await;
+        let payload = response.payload.unwrap();
+        let labels: Vec<&str> = payload["snapshots"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|snapshot| snapshot["label"].as_str().unwrap())
+            .collect();
+        assert_eq!(labels, ["second", "third"]);
+        let response = handle_command(restore(&id), &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SNAPSHOT_NOT_FOUND"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_latency_stats_and_reset() {
+        let state = AppState::with_mock_port(two_profiles());
+        let response = handle_command(Command::GetLatencyStats, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("LATENCY_STATS"));
+        assert_eq!(response.params.unwrap()["p50_us"], serde_json::Value::Null);
+
+        let ms = Duration::from_millis;
+        state
+            .latency
+            .record([true, false, false, false].into(), ms(4), ms(1));
+        let response = handle_command(Command::GetLatencyStats, &state).await;
+        let payload = response.payload.unwrap();
+        assert_eq!(payload["presses"], 1);
+        assert_eq!(payload["total_us"]["p50"], 5_000);
+        assert_eq!(payload["round_trip_us"]["max"], 4_000);
+
+        let response = handle_command(Command::ResetLatencyStats, &state).await;
+        assert_eq!(
+            response.message_code.as_deref(),
+            Some("LATENCY_STATS_RESET")
+        );
+        assert_eq!(response.params.unwrap()["presses"], 1);
+        assert_eq!(state.latency.stats().presses, 0);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::error::ValidationError;
+use crate::profile::{Response, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use serde_json::Value;
+
+// Close code of a websocket connection that asked for a protocol the server doesn't speak
+pub const UNSUPPORTED_PROTOCOL_CLOSE: u16 = 4000;
+
+// Fields added to responses after version 1
+const V2_FIELDS: [&str; 5] = ["message_code", "params", "previous", "seq", "dry_run"];
+
+// The version a client declared, if the server can speak it
+pub fn check(version: u32) -> Result<u32, ValidationError> {
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        Ok(version)
+    } else {
+        Err(ValidationError::UnsupportedProtocol {
+            version,
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        })
+    }
+}
+
+// A response in the shape of `version`
+pub fn to_value(response: &Response, version: u32) -> serde_json::Result<Value> {
+    let mut value = serde_json::to_value(response)?;
+    if version >= PROTOCOL_VERSION {
+        return Ok(value);
+    }
+    // Version 1: no codes, previous values or sequence numbers, and the profiles with
+    // their players but without revisions
+    if let Value::Object(fields) = &mut value {
+        for field in V2_FIELDS {
+            fields.remove(field);
+        }
+        if let Some(profiles) = &response.data {
+            let mut data = serde_json::to_value(&**profiles)?;
+            strip_revisions(&mut data);
+            fields.insert("data".to_string(), data);
+        }
+    }
+    Ok(value)
+}
+
+fn strip_revisions(data: &mut Value) {
+    let Value::Object(data) = data else {
+        return;
+    };
+    data.remove("revision");
+    if let Some(Value::Object(profiles)) = data.get_mut("profiles") {
+        for profile in profiles.values_mut() {
+            if let Value::Object(profile) = profile {
+                profile.remove("revision");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AppError;
+    use crate::profile::{Pad, Player, Profile, Profiles};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    // Responses as a version 1 server sent them
+    const V1_PROFILE_CHANGED: &str = include_str!("fixtures/protocol_v1/profile_changed.json");
+    const V1_PROFILE_NOT_FOUND: &str = include_str!("fixtures/protocol_v1/profile_not_found.json");
+
+    fn fixture(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_check_version() {
+        assert_eq!(check(1), Ok(1));
+        assert_eq!(check(PROTOCOL_VERSION), Ok(PROTOCOL_VERSION));
+        assert!(check(0).is_err());
+        assert!(check(PROTOCOL_VERSION + 1).is_err());
+    }
+
+    #[test]
+    fn test_legacy_shape_matches_recorded_fixtures() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Casual".to_string(), Profile::new([10, 20, 30, 40]))]),
+            default_profile: "Casual".to_string(),
+            players: HashMap::from([(
+                "Alice".to_string(),
+                Player {
+                    name: "Alice".to_string(),
+                    profile: "Casual".to_string(),
+                },
+            )]),
+            pads: vec![Pad::default_pad("Casual".to_string(), "Alice".to_string())],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+        let changed = Response {
+            success: true,
+            message: "Changed to profile 'Casual'".to_string(),
+            data: Some(Arc::new(profiles)),
+            sensor_values: None,
+            response_type: Some("command_response".to_string()),
+            payload: None,
+            pad: Some("default".to_string()),
+            message_code: Some("PROFILE_CHANGED".to_string()),
+            params: Some(serde_json::json!({ "profile": "Casual" })),
+            previous: Some(serde_json::json!({ "profile": "Hard" })),
+            seq: Some(7),
+            dry_run: false,
+            unsaved_changes: false,
+            pending_changes: None,
+        };
+        assert_eq!(to_value(&changed, 1).unwrap(), fixture(V1_PROFILE_CHANGED));
+
+        let not_found =
+            AppError::from(ValidationError::ProfileNotFound("Hard".to_string())).to_response();
+        assert_eq!(
+            to_value(&not_found, 1).unwrap(),
+            fixture(V1_PROFILE_NOT_FOUND)
+        );
+
+        // The current version is the plain serialization
+        let current = to_value(&changed, PROTOCOL_VERSION).unwrap();
+        assert_eq!(current["seq"], 7);
+        assert!(current["data"].get("players").is_none());
+    }
+}
@@ -0,0 +1,1219 @@
+use crate::adaptive::DEFAULT_STREAM_IDLE_SECS;
+use crate::auth::{self, RoleToken};
+use crate::chunk::{ChunkLimits, DEFAULT_CHUNK_SIZE, DEFAULT_MAX_RESPONSE_SIZE};
+use crate::commands::{ReadOnlyMode, ReadOnlyPolicy};
+use crate::deflate::WsCompression;
+use crate::drift::{DriftSettings, DEFAULT_DRIFT_MAX, DEFAULT_DRIFT_STEP};
+use crate::idempotency::DEFAULT_IDEMPOTENCY_WINDOW;
+use crate::layout::{Layout, DEFAULT_LAYOUT, LAYOUTS};
+use crate::mdns;
+use crate::mirror;
+use crate::profile::PROFILES_FILE;
+use crate::quota::{Quotas, DEFAULT_MAX_PLAYERS, DEFAULT_MAX_PROFILES};
+use crate::sensors::{Sensors, MAX_SENSORS};
+use crate::serial_queue::DEFAULT_SERIAL_QUEUE_LIMIT;
+use crate::serial_trace::TraceOptions;
+use crate::snapshot::{default_snapshot_dir, DEFAULT_SNAPSHOT_RETENTION};
+use crate::startup_sync::StartupSync;
+use crate::webhook::{self, WebhookConfig, WebhookEventKind};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Config file looked up next to the profiles file when --config isn't given
+pub const DEFAULT_CONFIG_FILE: &str = "fsr-rs.toml";
+
+#[derive(Parser, Debug)]
+#[command(author, version, long_version = crate::info::LONG_VERSION.as_str(), about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+
+    #[arg(
+        short,
+        long,
+        default_value = crate::autodetect::AUTO_PORT,
+        help = crate::autodetect::COM_PORT_HELP,
+        global = true
+    )]
+    pub com_port: String,
+
+    /// Web server port to listen on
+    #[arg(short, long, default_value = "3000", global = true)]
+    pub port: u16,
+
+    /// Host address to bind to
+    #[arg(long, default_value = "127.0.0.1", global = true)]
+    pub host: String,
+
+    /// Default profile to use for new players, or `pad=name` for new players on one pad
+    /// (can be given multiple times)
+    #[arg(long, value_parser = parse_default_profile, global = true)]
+    pub default_profile: Vec<DefaultProfile>,
+
+    /// Use a mock serial device for development (no hardware required)
+    #[arg(long, default_value_t = false, global = true)]
+    pub mock_serial: bool,
+
+    /// Layout the mock serial device emulates, with a sensor per panel: dance4, pump5 or
+    /// pad9
+    #[arg(long, value_parser = parse_mock_layout, default_value = DEFAULT_LAYOUT, global = true)]
+    pub mock_layout: String,
+
+    /// Disable mDNS/zeroconf advertisement of the server
+    #[arg(long, default_value_t = false, global = true)]
+    pub no_mdns: bool,
+
+    /// Name advertised via mDNS for this pad
+    #[arg(long, default_value = mdns::DEFAULT_PAD_NAME, global = true)]
+    pub pad_name: String,
+
+    /// List other fsr-rs instances on the LAN and exit
+    #[arg(long, default_value_t = false)]
+    pub discover: bool,
+
+    /// Run the self-test before serving and exit instead if a check fails
+    #[arg(long, default_value_t = false)]
+    pub self_test: bool,
+
+    /// POST player/profile changes to a URL, e.g. `url=https://...,events=player_changed`
+    /// (can be given multiple times)
+    #[arg(long = "webhook", value_parser = webhook::parse_webhook_arg, global = true)]
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// Thresholds for the profile created on first run, one per sensor, e.g.
+    /// `400,400,450,400` [default: 100,200,300,400, or as many as the mock layout has
+    /// sensors]
+    #[arg(long, value_parser = parse_thresholds, global = true)]
+    pub default_thresholds: Option<Sensors<i32>>,
+
+    /// Name of the profile created on first run
+    #[arg(long, default_value = "DEFAULT", global = true)]
+    pub bootstrap_profile: String,
+
+    /// Seconds between profiles keepalive broadcasts (0, the default, disables the
+    /// keepalive; changes are always broadcast)
+    #[arg(long, default_value_t = 0, global = true)]
+    pub active_broadcast_interval: u64,
+
+    /// Most profiles commands may create; a profiles file with more still loads
+    #[arg(long, default_value_t = DEFAULT_MAX_PROFILES, global = true)]
+    pub max_profiles: usize,
+
+    /// Most players commands may create; a profiles file with more still loads
+    #[arg(long, default_value_t = DEFAULT_MAX_PLAYERS, global = true)]
+    pub max_players: usize,
+
+    /// Largest threshold accepted, until sensor readings show the device's range (default:
+    /// 1023)
+    #[arg(long, value_parser = clap::value_parser!(i32).range(1..), global = true)]
+    pub threshold_max: Option<i32>,
+
+    /// Milliseconds a threshold write waits for newer UpdateThreshold commands for the
+    /// same threshold before going to the device (0, the default, only merges commands
+    /// arriving while a write is in flight)
+    #[arg(long, default_value_t = 0, global = true)]
+    pub threshold_debounce_ms: u64,
+
+    /// Most commands waiting for the serial device; more are refused with SERIAL_BUSY
+    #[arg(
+        long,
+        default_value_t = DEFAULT_SERIAL_QUEUE_LIMIT,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        global = true
+    )]
+    pub serial_queue_limit: usize,
+
+    /// Seconds the response of a mutating command sent with an idempotency_key is
+    /// replayed to retries with the same key
+    #[arg(long, default_value_t = DEFAULT_IDEMPOTENCY_WINDOW, global = true)]
+    pub idempotency_window: u64,
+
+    /// Seconds between `heartbeat` broadcasts with the server status (0 disables them)
+    #[arg(long, default_value_t = 5, global = true)]
+    pub heartbeat_interval: u64,
+
+    /// Minutes without a press before the signed in player is signed out (0 disables it)
+    #[arg(long, default_value_t = 0, global = true)]
+    pub idle_sign_out: u64,
+
+    /// Poll the pad at 5Hz while no sensor has moved for --stream-idle-secs, and at the
+    /// full rate again as soon as one does
+    #[arg(long, default_value_t = false, global = true)]
+    pub adaptive_stream: bool,
+
+    /// Seconds without sensor activity before an adaptive stream slows down
+    #[arg(
+        long,
+        default_value_t = DEFAULT_STREAM_IDLE_SECS,
+        value_parser = clap::value_parser!(u64).range(1..),
+        global = true
+    )]
+    pub stream_idle_secs: u64,
+
+    /// Send the next sensor read request as soon as a response arrives, so the device
+    /// prepares a reading while the previous one is broadcast
+    #[arg(long, default_value_t = false, global = true)]
+    pub pipelined_reads: bool,
+
+    /// Restart the sensor stream when it hasn't ticked for this many seconds while
+    /// enabled (0 disables the watchdog)
+    #[arg(long, default_value_t = 5, global = true)]
+    pub stream_watchdog_timeout: u64,
+
+    /// On startup write the current profile's thresholds to the device
+    /// (profile-to-device), take the device's into the profile and save them
+    /// (device-to-profile), or only report a difference (none)
+    #[arg(long, value_enum, default_value = "profile-to-device", global = true)]
+    pub startup_sync: StartupSync,
+
+    /// Let the device thresholds of profiles with drift compensation follow the
+    /// sensors' rest values; the profiles themselves keep their thresholds
+    #[arg(long, default_value_t = false, global = true)]
+    pub drift_compensation: bool,
+
+    /// Change of a sensor's rest value, in raw units, before drift compensation moves its
+    /// threshold by the same amount
+    #[arg(
+        long,
+        default_value_t = DEFAULT_DRIFT_STEP,
+        value_parser = clap::value_parser!(i32).range(1..),
+        global = true
+    )]
+    pub drift_step: i32,
+
+    /// Largest total adjustment of one threshold by drift compensation
+    #[arg(
+        long,
+        default_value_t = DEFAULT_DRIFT_MAX,
+        value_parser = clap::value_parser!(i32).range(0..),
+        global = true
+    )]
+    pub drift_max: i32,
+
+    /// Reject all mutating commands (`--read-only` or `--read-only=strict`); with `soft`
+    /// authorized clients may still make changes
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_value = "off",
+        default_missing_value = "strict",
+        global = true
+    )]
+    pub read_only: ReadOnlyMode,
+
+    /// Still allow starting and stopping the sensor stream in read-only mode
+    #[arg(long, default_value_t = false, global = true)]
+    pub read_only_allow_stream: bool,
+
+    /// Follow the fsr-rs at this websocket URL, e.g. `ws://192.168.1.20:3000/ws`, and
+    /// serve its profiles and sensor stream read-only instead of opening a device
+    #[arg(long, value_parser = mirror::parse_upstream_url)]
+    pub mirror: Option<String>,
+
+    /// Require clients to present a token, granting the role it is given with:
+    /// `viewer:<token>`, `operator:<token>` or `admin:<token>` (can be given multiple times)
+    #[arg(long = "token", value_parser = auth::parse_token_arg, global = true)]
+    pub tokens: Vec<RoleToken>,
+
+    /// Websocket responses larger than this many bytes are split into numbered chunks
+    #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE, global = true)]
+    pub ws_chunk_size: usize,
+
+    /// Largest websocket response in bytes; bigger ones are answered with
+    /// RESPONSE_TOO_LARGE instead
+    #[arg(long, default_value_t = DEFAULT_MAX_RESPONSE_SIZE, global = true)]
+    pub ws_max_response_size: usize,
+
+    /// Compress websocket messages of 1 KiB and more with permessage-deflate for clients
+    /// that offer it
+    #[arg(long, value_enum, default_value = "on", global = true)]
+    pub ws_compression: WsCompression,
+
+    /// Log every serial write and read chunk with a hex dump to stderr
+    #[arg(long, default_value_t = false, global = true)]
+    pub trace_serial: bool,
+
+    /// Write a timestamped text capture of all serial traffic to this file
+    #[arg(long, global = true)]
+    pub trace_serial_file: Option<PathBuf>,
+
+    /// Also append the command history to this JSONL file, rotated at 1 MiB
+    #[arg(long)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Keep threshold change proposals in this JSON file, so they survive a restart
+    #[arg(long)]
+    pub proposals_file: Option<PathBuf>,
+
+    /// Directory for CreateSnapshot (default: `snapshots` next to profiles.json)
+    #[arg(long, global = true)]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Snapshots kept, at least 1; creating another deletes the oldest
+    #[arg(long, default_value_t = DEFAULT_SNAPSHOT_RETENTION, global = true)]
+    pub snapshot_retention: usize,
+
+    /// Export tracing spans to an OpenTelemetry collector over OTLP/HTTP,
+    /// e.g. `http://localhost:4318/v1/traces`
+    #[cfg(feature = "otlp")]
+    #[arg(long, global = true)]
+    pub tracing_otlp: Option<String>,
+
+    /// Run under a service manager (systemd notify/watchdog on Linux, the Windows SCM on Windows)
+    #[arg(long, default_value_t = false)]
+    pub service: bool,
+
+    /// Shut down the fsr-rs already running in this directory and start in its place
+    #[arg(long, default_value_t = false)]
+    pub takeover: bool,
+
+    /// TOML config file (default: fsr-rs.toml next to the profiles file)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective configuration and exit
+    #[arg(long, default_value_t = false)]
+    pub print_config: bool,
+
+    /// Print subcommand results as JSON
+    #[arg(long, default_value_t = false, global = true)]
+    pub json: bool,
+}
+
+impl Args {
+    // Whether this invocation runs the web server
+    pub fn is_serve(&self) -> bool {
+        matches!(self.command, None | Some(CliCommand::Serve))
+    }
+
+    pub fn trace_options(&self) -> TraceOptions {
+        TraceOptions {
+            stderr: self.trace_serial,
+            file: self.trace_serial_file.clone(),
+        }
+    }
+
+    pub fn chunk_limits(&self) -> ChunkLimits {
+        ChunkLimits {
+            chunk_size: self.ws_chunk_size,
+            max_size: self.ws_max_response_size,
+        }
+    }
+
+    pub fn read_only_policy(&self) -> ReadOnlyPolicy {
+        if self.mirror.is_some() {
+            return ReadOnlyPolicy::mirror();
+        }
+        ReadOnlyPolicy {
+            mode: self.read_only,
+            allow_stream: self.read_only_allow_stream,
+            mirror: false,
+        }
+    }
+
+    // Keepalive period for the profiles broadcast, if enabled
+    pub fn active_broadcast_keepalive(&self) -> Option<Duration> {
+        (self.active_broadcast_interval > 0)
+            .then(|| Duration::from_secs(self.active_broadcast_interval))
+    }
+
+    pub fn quotas(&self) -> Quotas {
+        Quotas {
+            max_profiles: self.max_profiles,
+            max_players: self.max_players,
+        }
+    }
+
+    pub fn drift_settings(&self) -> DriftSettings {
+        DriftSettings {
+            enabled: self.drift_compensation,
+            step: self.drift_step,
+            max: self.drift_max,
+        }
+    }
+
+    pub fn snapshot_dir(&self) -> PathBuf {
+        self.snapshot_dir
+            .clone()
+            .unwrap_or_else(default_snapshot_dir)
+    }
+
+    pub fn idempotency_window(&self) -> Duration {
+        Duration::from_secs(self.idempotency_window)
+    }
+
+    pub fn threshold_debounce(&self) -> Duration {
+        Duration::from_millis(self.threshold_debounce_ms)
+    }
+
+    // Period of the heartbeat broadcast, if enabled
+    pub fn heartbeat(&self) -> Option<Duration> {
+        (self.heartbeat_interval > 0).then(|| Duration::from_secs(self.heartbeat_interval))
+    }
+
+    // Time without a press before the player is signed out, if enabled
+    pub fn idle_sign_out(&self) -> Option<Duration> {
+        (self.idle_sign_out > 0).then(|| Duration::from_secs(self.idle_sign_out * 60))
+    }
+
+    // Stall timeout of the sensor stream watchdog, if enabled
+    // Idle period of the adaptive stream, None for a fixed rate
+    pub fn adaptive_stream(&self) -> Option<Duration> {
+        self.adaptive_stream
+            .then(|| Duration::from_secs(self.stream_idle_secs))
+    }
+
+    pub fn stream_watchdog(&self) -> Option<Duration> {
+        (self.stream_watchdog_timeout > 0)
+            .then(|| Duration::from_secs(self.stream_watchdog_timeout))
+    }
+
+    // Sensors of the mock serial device
+    pub fn mock_sensors(&self) -> usize {
+        Layout::find(&self.mock_layout).map_or(MAX_SENSORS, Layout::sensor_count)
+    }
+
+    // Thresholds of the profile created on first run: --default-thresholds, or the stock
+    // ones for a device of the default layout, or of the mock's
+    pub fn bootstrap_thresholds(&self) -> Sensors<i32> {
+        let layout = if self.mock_serial {
+            &self.mock_layout
+        } else {
+            DEFAULT_LAYOUT
+        };
+        let sensors = Layout::find(layout).map_or(MAX_SENSORS, Layout::sensor_count);
+        self.default_thresholds
+            .unwrap_or_else(|| stock_thresholds(sensors))
+    }
+}
+
+// Thresholds 100, 200, ... for each of `sensors` sensors, as the mock device starts with
+pub fn stock_thresholds(sensors: usize) -> Sensors<i32> {
+    Sensors::from_fn(sensors, |index| (index as i32 + 1) * 100)
+}
+
+// A --mock-layout: a known layout, whose panels the mock device has a sensor for
+pub fn parse_mock_layout(s: &str) -> Result<String, String> {
+    match Layout::find(s) {
+        Some(layout) => Ok(layout.name.to_string()),
+        None => {
+            let names: Vec<&str> = LAYOUTS.iter().map(|layout| layout.name).collect();
+            Err(format!(
+                "unknown layout '{}', expected one of {}",
+                s,
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum CliCommand {
+    /// Run the web server (default)
+    Serve,
+    /// List the serial ports available on this machine
+    ListPorts,
+    /// Read the current thresholds from the device
+    GetThresholds,
+    /// Write all thresholds to the device, one per sensor
+    SetThresholds {
+        #[arg(num_args = 1..=MAX_SENSORS, required = true, value_name = "THRESHOLD")]
+        thresholds: Vec<i32>,
+    },
+    /// Read sensor values from the device
+    GetValues {
+        /// Number of readings to take
+        #[arg(long, default_value_t = 1)]
+        count: u32,
+    },
+    /// Apply a saved profile to the device and make it the current profile
+    ApplyProfile { name: String },
+    /// Read newline-delimited Command JSON from stdin and write Response JSON to stdout
+    Pipe,
+    /// Pretty-print a serial capture file as the lines sent and received
+    DecodeCapture { file: PathBuf },
+    /// Check the device, the profiles file and the event channel; exits nonzero if a
+    /// check fails
+    SelfTest,
+}
+
+// Parse a comma separated threshold list with one value per sensor
+pub fn parse_thresholds(s: &str) -> Result<Sensors<i32>, String> {
+    let values = s
+        .split(',')
+        .map(|value| {
+            value
+                .trim()
+                .parse::<i32>()
+                .map_err(|e| format!("invalid threshold '{}': {}", value.trim(), e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Sensors::from_slice(&values).ok_or_else(|| {
+        format!(
+            "expected at most {} comma separated thresholds (one per sensor), got {}",
+            MAX_SENSORS,
+            values.len()
+        )
+    })
+}
+
+// A `--default-profile` value: the shared default, or one pad's with `pad=name`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DefaultProfile {
+    pub pad: Option<String>,
+    pub name: String,
+}
+
+pub fn parse_default_profile(s: &str) -> Result<DefaultProfile, String> {
+    let (pad, name) = match s.split_once('=') {
+        Some((pad, name)) => (Some(pad.trim().to_string()), name.trim()),
+        None => (None, s.trim()),
+    };
+    if name.is_empty() || pad.as_deref() == Some("") {
+        return Err(format!("expected a profile name or pad=name, got '{}'", s));
+    }
+    Ok(DefaultProfile {
+        pad,
+        name: name.to_string(),
+    })
+}
+
+impl TryFrom<String> for DefaultProfile {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        parse_default_profile(&s)
+    }
+}
+
+impl From<DefaultProfile> for String {
+    fn from(default: DefaultProfile) -> Self {
+        match default.pad {
+            Some(pad) => format!("{}={}", pad, default.name),
+            None => default.name,
+        }
+    }
+}
+
+// `default_profile` in the config file: a single entry or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(
+        Option::<OneOrMany<T>>::deserialize(deserializer)?.map(|value| match value {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }),
+    )
+}
+
+// Webhook entry in the config file (`[[webhook]]`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct WebhookEntry {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<WebhookEventKind>,
+}
+
+// Options that can be set in the config file. Keys mirror the long CLI flags.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FileConfig {
+    pub com_port: Option<String>,
+    pub port: Option<u16>,
+    pub host: Option<String>,
+    #[serde(
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default_profile: Option<Vec<DefaultProfile>>,
+    pub mock_serial: Option<bool>,
+    pub mock_layout: Option<String>,
+    pub mdns: Option<bool>,
+    pub pad_name: Option<String>,
+    pub default_thresholds: Option<Sensors<i32>>,
+    pub bootstrap_profile: Option<String>,
+    pub active_broadcast_interval: Option<u64>,
+    pub heartbeat_interval: Option<u64>,
+    pub idle_sign_out: Option<u64>,
+    pub threshold_max: Option<i32>,
+    pub max_profiles: Option<usize>,
+    pub max_players: Option<usize>,
+    pub threshold_debounce_ms: Option<u64>,
+    pub serial_queue_limit: Option<usize>,
+    pub idempotency_window: Option<u64>,
+    pub adaptive_stream: Option<bool>,
+    pub stream_idle_secs: Option<u64>,
+    pub pipelined_reads: Option<bool>,
+    pub stream_watchdog_timeout: Option<u64>,
+    pub startup_sync: Option<StartupSync>,
+    pub drift_compensation: Option<bool>,
+    pub drift_step: Option<i32>,
+    pub drift_max: Option<i32>,
+    pub read_only: Option<ReadOnlyMode>,
+    pub read_only_allow_stream: Option<bool>,
+    pub mirror: Option<String>,
+    // `<role>:<token>` like --token
+    pub tokens: Option<Vec<String>>,
+    pub ws_chunk_size: Option<usize>,
+    pub ws_max_response_size: Option<usize>,
+    pub ws_compression: Option<WsCompression>,
+    pub snapshot_dir: Option<PathBuf>,
+    pub snapshot_retention: Option<usize>,
+    #[serde(rename = "webhook", skip_serializing_if = "Option::is_none")]
+    pub webhooks: Option<Vec<WebhookEntry>>,
+}
+
+const KNOWN_KEYS: &[&str] = &[
+    "com_port",
+    "port",
+    "host",
+    "default_profile",
+    "mock_serial",
+    "mock_layout",
+    "mdns",
+    "pad_name",
+    "default_thresholds",
+    "bootstrap_profile",
+    "active_broadcast_interval",
+    "heartbeat_interval",
+    "idle_sign_out",
+    "threshold_max",
+    "max_profiles",
+    "max_players",
+    "threshold_debounce_ms",
+    "serial_queue_limit",
+    "idempotency_window",
+    "adaptive_stream",
+    "stream_idle_secs",
+    "pipelined_reads",
+    "stream_watchdog_timeout",
+    "startup_sync",
+    "drift_compensation",
+    "drift_step",
+    "drift_max",
+    "read_only",
+    "read_only_allow_stream",
+    "mirror",
+    "tokens",
+    "ws_chunk_size",
+    "ws_max_response_size",
+    "ws_compression",
+    "snapshot_dir",
+    "snapshot_retention",
+    "webhook",
+];
+
+// Known keys that are close to an unknown one
+fn suggestions(key: &str, known: &[&str]) -> Vec<String> {
+    let normalized = key.replace('-', "_").to_lowercase();
+    known
+        .iter()
+        .filter(|candidate| {
+            **candidate == normalized
+                || strsim::levenshtein(&normalized, candidate) <= 2
+                || strsim::jaro_winkler(&normalized, candidate) > 0.9
+        })
+        .map(|candidate| candidate.to_string())
+        .collect()
+}
+
+// Warnings for keys the config file doesn't understand
+fn unknown_key_warnings(table: &toml::Table) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for key in table
+        .keys()
+        .filter(|key| !KNOWN_KEYS.contains(&key.as_str()))
+    {
+        let near = suggestions(key, KNOWN_KEYS);
+        if near.is_empty() {
+            warnings.push(format!("unknown config key '{}'", key));
+        } else {
+            warnings.push(format!(
+                "unknown config key '{}' (did you mean {}?)",
+                key,
+                near.iter()
+                    .map(|k| format!("'{}'", k))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ));
+        }
+    }
+
+    if let Some(toml::Value::Array(entries)) = table.get("webhook") {
+        for entry in entries.iter().filter_map(|entry| entry.as_table()) {
+            for key in entry.keys().filter(|key| *key != "url" && *key != "events") {
+                warnings.push(format!("unknown webhook key '{}'", key));
+            }
+        }
+    }
+    warnings
+}
+
+// Parse config file contents, returning the options and any warnings
+pub fn parse_config(content: &str) -> Result<(FileConfig, Vec<String>), String> {
+    let table: toml::Table = content.parse().map_err(|e| format!("{}", e))?;
+    let warnings = unknown_key_warnings(&table);
+    let config = FileConfig::deserialize(toml::Value::Table(table)).map_err(|e| e.to_string())?;
+    Ok((config, warnings))
+}
+
+pub fn default_config_path() -> PathBuf {
+    Path::new(PROFILES_FILE)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(DEFAULT_CONFIG_FILE)
+}
+
+// Whether an option was given explicitly on the command line
+fn from_cli(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+fn merge<T>(matches: &ArgMatches, id: &str, target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        if !from_cli(matches, id) {
+            *target = value;
+        }
+    }
+}
+
+// Apply config file values to everything not given explicitly on the command line
+pub fn apply_file_config(
+    args: &mut Args,
+    matches: &ArgMatches,
+    file: FileConfig,
+) -> Result<(), String> {
+    merge(matches, "com_port", &mut args.com_port, file.com_port);
+    merge(matches, "port", &mut args.port, file.port);
+    merge(matches, "host", &mut args.host, file.host);
+    merge(
+        matches,
+        "default_profile",
+        &mut args.default_profile,
+        file.default_profile,
+    );
+    merge(
+        matches,
+        "mock_serial",
+        &mut args.mock_serial,
+        file.mock_serial,
+    );
+    let mock_layout = file.mock_layout.as_deref().map(parse_mock_layout);
+    merge(
+        matches,
+        "mock_layout",
+        &mut args.mock_layout,
+        mock_layout.transpose()?,
+    );
+    merge(
+        matches,
+        "no_mdns",
+        &mut args.no_mdns,
+        file.mdns.map(|on| !on),
+    );
+    merge(matches, "pad_name", &mut args.pad_name, file.pad_name);
+    merge(
+        matches,
+        "default_thresholds",
+        &mut args.default_thresholds,
+        file.default_thresholds.map(Some),
+    );
+    merge(
+        matches,
+        "bootstrap_profile",
+        &mut args.bootstrap_profile,
+        file.bootstrap_profile,
+    );
+    merge(
+        matches,
+        "active_broadcast_interval",
+        &mut args.active_broadcast_interval,
+        file.active_broadcast_interval,
+    );
+    merge(
+        matches,
+        "heartbeat_interval",
+        &mut args.heartbeat_interval,
+        file.heartbeat_interval,
+    );
+    merge(
+        matches,
+        "idle_sign_out",
+        &mut args.idle_sign_out,
+        file.idle_sign_out,
+    );
+    merge(
+        matches,
+        "threshold_max",
+        &mut args.threshold_max,
+        file.threshold_max.map(Some),
+    );
+    merge(
+        matches,
+        "max_profiles",
+        &mut args.max_profiles,
+        file.max_profiles,
+    );
+    merge(
+        matches,
+        "max_players",
+        &mut args.max_players,
+        file.max_players,
+    );
+    merge(
+        matches,
+        "threshold_debounce_ms",
+        &mut args.threshold_debounce_ms,
+        file.threshold_debounce_ms,
+    );
+    merge(
+        matches,
+        "serial_queue_limit",
+        &mut args.serial_queue_limit,
+        file.serial_queue_limit,
+    );
+    merge(
+        matches,
+        "idempotency_window",
+        &mut args.idempotency_window,
+        file.idempotency_window,
+    );
+    merge(
+        matches,
+        "adaptive_stream",
+        &mut args.adaptive_stream,
+        file.adaptive_stream,
+    );
+    merge(
+        matches,
+        "stream_idle_secs",
+        &mut args.stream_idle_secs,
+        file.stream_idle_secs,
+    );
+    merge(
+        matches,
+        "pipelined_reads",
+        &mut args.pipelined_reads,
+        file.pipelined_reads,
+    );
+    merge(
+        matches,
+        "stream_watchdog_timeout",
+        &mut args.stream_watchdog_timeout,
+        file.stream_watchdog_timeout,
+    );
+    merge(
+        matches,
+        "startup_sync",
+        &mut args.startup_sync,
+        file.startup_sync,
+    );
+    merge(
+        matches,
+        "drift_compensation",
+        &mut args.drift_compensation,
+        file.drift_compensation,
+    );
+    merge(matches, "drift_step", &mut args.drift_step, file.drift_step);
+    merge(matches, "drift_max", &mut args.drift_max, file.drift_max);
+    merge(matches, "read_only", &mut args.read_only, file.read_only);
+    merge(
+        matches,
+        "read_only_allow_stream",
+        &mut args.read_only_allow_stream,
+        file.read_only_allow_stream,
+    );
+    if let Some(url) = file.mirror {
+        let url = mirror::parse_upstream_url(&url)?;
+        merge(matches, "mirror", &mut args.mirror, Some(Some(url)));
+    }
+    if let Some(tokens) = file.tokens {
+        let tokens = tokens
+            .iter()
+            .map(|token| auth::parse_token_arg(token))
+            .collect::<Result<Vec<_>, _>>()?;
+        merge(matches, "tokens", &mut args.tokens, Some(tokens));
+    }
+    merge(
+        matches,
+        "ws_chunk_size",
+        &mut args.ws_chunk_size,
+        file.ws_chunk_size,
+    );
+    merge(
+        matches,
+        "ws_max_response_size",
+        &mut args.ws_max_response_size,
+        file.ws_max_response_size,
+    );
+    merge(
+        matches,
+        "ws_compression",
+        &mut args.ws_compression,
+        file.ws_compression,
+    );
+    merge(
+        matches,
+        "snapshot_dir",
+        &mut args.snapshot_dir,
+        file.snapshot_dir.map(Some),
+    );
+    merge(
+        matches,
+        "snapshot_retention",
+        &mut args.snapshot_retention,
+        file.snapshot_retention,
+    );
+
+    if let Some(entries) = file.webhooks {
+        let webhooks = entries
+            .into_iter()
+            .map(|entry| WebhookConfig::new(entry.url, entry.events))
+            .collect::<Result<Vec<_>, _>>()?;
+        merge(matches, "webhooks", &mut args.webhooks, Some(webhooks));
+    }
+    Ok(())
+}
+
+// The fully resolved configuration in config file form
+pub fn effective_config(args: &Args) -> FileConfig {
+    FileConfig {
+        com_port: Some(args.com_port.clone()),
+        port: Some(args.port),
+        host: Some(args.host.clone()),
+        default_profile: Some(args.default_profile.clone()).filter(|defaults| !defaults.is_empty()),
+        mock_serial: Some(args.mock_serial),
+        mock_layout: Some(args.mock_layout.clone()),
+        mdns: Some(!args.no_mdns),
+        pad_name: Some(args.pad_name.clone()),
+        default_thresholds: Some(args.bootstrap_thresholds()),
+        bootstrap_profile: Some(args.bootstrap_profile.clone()),
+        active_broadcast_interval: Some(args.active_broadcast_interval),
+        heartbeat_interval: Some(args.heartbeat_interval),
+        idle_sign_out: Some(args.idle_sign_out),
+        threshold_max: args.threshold_max,
+        max_profiles: Some(args.max_profiles),
+        max_players: Some(args.max_players),
+        threshold_debounce_ms: Some(args.threshold_debounce_ms),
+        serial_queue_limit: Some(args.serial_queue_limit),
+        idempotency_window: Some(args.idempotency_window),
+        adaptive_stream: Some(args.adaptive_stream),
+        stream_idle_secs: Some(args.stream_idle_secs),
+        pipelined_reads: Some(args.pipelined_reads),
+        stream_watchdog_timeout: Some(args.stream_watchdog_timeout),
+        startup_sync: Some(args.startup_sync),
+        drift_compensation: Some(args.drift_compensation),
+        drift_step: Some(args.drift_step),
+        drift_max: Some(args.drift_max),
+        read_only: Some(args.read_only),
+        read_only_allow_stream: Some(args.read_only_allow_stream),
+        mirror: args.mirror.clone(),
+        tokens: Some(args.tokens.iter().map(RoleToken::to_arg).collect()),
+        ws_chunk_size: Some(args.ws_chunk_size),
+        ws_max_response_size: Some(args.ws_max_response_size),
+        ws_compression: Some(args.ws_compression),
+        snapshot_dir: Some(args.snapshot_dir()),
+        snapshot_retention: Some(args.snapshot_retention),
+        webhooks: Some(
+            args.webhooks
+                .iter()
+                .map(|webhook| WebhookEntry {
+                    url: webhook.url.clone(),
+                    events: webhook.events.clone(),
+                })
+                .collect(),
+        ),
+    }
+}
+
+// Resolve parsed arguments: CLI flags over config file over built-in defaults
+pub fn resolve_args(matches: &ArgMatches) -> Result<Args, String> {
+    let mut args = Args::from_arg_matches(matches).map_err(|e| e.to_string())?;
+
+    let (path, explicit) = match &args.config {
+        Some(path) => (path.clone(), true),
+        None => (default_config_path(), false),
+    };
+    match fs::read_to_string(&path) {
+        Ok(content) => {
+            let (file, warnings) = parse_config(&content)
+                .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+            for warning in warnings {
+                eprintln!("Warning: {}: {}", path.display(), warning);
+            }
+            apply_file_config(&mut args, matches, file)
+                .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+            if !args.print_config && args.is_serve() {
+                println!("Loaded config file {}", path.display());
+            }
+        }
+        Err(e) if explicit => {
+            return Err(format!(
+                "Failed to read config file {}: {}",
+                path.display(),
+                e
+            ));
+        }
+        Err(_) => {} // No default config file, built-in defaults apply
+    }
+    Ok(args)
+}
+
+// Parse the process arguments, exiting with a message on failure
+pub fn load_args() -> Args {
+    let matches = Args::command().get_matches();
+    match resolve_args(&matches) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args_with_file(argv: &[&str], content: &str) -> Args {
+        let matches = Args::command().try_get_matches_from(argv).unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        let (file, _) = parse_config(content).unwrap();
+        apply_file_config(&mut args, &matches, file).unwrap();
+        args
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let (config, warnings) = parse_config(
+            r#"
+com_port = "/dev/ttyACM0"
+port = 8080
+mdns = false
+
+[[webhook]]
+url = "https://example.com/hook"
+events = ["player_changed"]
+"#,
+        )
+        .unwrap();
+
+        assert!(warnings.is_empty());
+        assert_eq!(config.com_port.as_deref(), Some("/dev/ttyACM0"));
+        assert_eq!(config.port, Some(8080));
+        assert_eq!(config.mdns, Some(false));
+        assert_eq!(config.host, None);
+        assert_eq!(
+            config.webhooks,
+            Some(vec![WebhookEntry {
+                url: "https://example.com/hook".to_string(),
+                events: vec![WebhookEventKind::PlayerChanged],
+            }])
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys_suggest_near_misses() {
+        let (_, warnings) = parse_config("com-port = \"COM3\"\nprot = 1\nbananas = 2\n").unwrap();
+
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings
+            .iter()
+            .any(|w| w.contains("'com-port'") && w.contains("'com_port'")));
+        assert!(warnings
+            .iter()
+            .any(|w| w.contains("'prot'") && w.contains("'port'")));
+        assert!(warnings.iter().any(|w| w == "unknown config key 'bananas'"));
+    }
+
+    #[test]
+    fn test_invalid_config_is_an_error() {
+        assert!(parse_config("port = \"not a number\"").is_err());
+        assert!(parse_config("port = ").is_err());
+    }
+
+    #[test]
+    fn test_precedence_cli_over_file_over_defaults() {
+        let content =
+            "port = 8080\nhost = \"0.0.0.0\"\nmock_serial = true\nactive_broadcast_interval = 60\n";
+
+        // File overrides defaults
+        let args = args_with_file(&["fsr-rs"], content);
+        assert_eq!(args.port, 8080);
+        assert_eq!(args.host, "0.0.0.0");
+        assert!(args.mock_serial);
+        assert_eq!(args.com_port, "auto");
+        assert_eq!(
+            args.active_broadcast_keepalive(),
+            Some(Duration::from_secs(60))
+        );
+
+        // Explicit CLI flags override the file
+        let args = args_with_file(
+            &[
+                "fsr-rs",
+                "--port",
+                "9000",
+                "--host",
+                "127.0.0.1",
+                "--active-broadcast-interval",
+                "5",
+            ],
+            content,
+        );
+        assert_eq!(args.port, 9000);
+        assert_eq!(args.host, "127.0.0.1");
+        assert!(args.mock_serial);
+        assert_eq!(
+            args.active_broadcast_keepalive(),
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_default_profile_per_pad() {
+        let args = Args::try_parse_from([
+            "fsr-rs",
+            "--default-profile",
+            "Casual",
+            "--default-profile",
+            "left=Heavy",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.default_profile,
+            vec![
+                parse_default_profile("Casual").unwrap(),
+                DefaultProfile {
+                    pad: Some("left".to_string()),
+                    name: "Heavy".to_string(),
+                },
+            ]
+        );
+        assert!(Args::try_parse_from(["fsr-rs", "--default-profile", "left="]).is_err());
+
+        // The config file takes the old single name as well as a list
+        let args = args_with_file(&["fsr-rs"], "default_profile = \"Casual\"\n");
+        assert_eq!(args.default_profile[0].name, "Casual");
+        let args = args_with_file(
+            &["fsr-rs"],
+            "default_profile = [\"Casual\", \"left=Heavy\"]\n",
+        );
+        assert_eq!(args.default_profile[1].pad.as_deref(), Some("left"));
+    }
+
+    #[test]
+    fn test_mdns_and_webhooks_from_file() {
+        let content = "mdns = false\n[[webhook]]\nurl = \"http://localhost/hook\"\n";
+        let args = args_with_file(&["fsr-rs"], content);
+        assert!(args.no_mdns);
+        assert_eq!(args.webhooks.len(), 1);
+        assert_eq!(args.webhooks[0].events, WebhookEventKind::ALL.to_vec());
+
+        let args = args_with_file(&["fsr-rs", "--webhook", "url=http://other/hook"], content);
+        assert_eq!(args.webhooks.len(), 1);
+        assert_eq!(args.webhooks[0].url, "http://other/hook");
+    }
+
+    #[test]
+    fn test_tokens_from_file() {
+        let content = "tokens = [\"viewer:abc\", \"operator:def\"]\n";
+        let args = args_with_file(&["fsr-rs"], content);
+        let roles: Vec<_> = args.tokens.iter().map(|token| token.role).collect();
+        assert_eq!(roles, [auth::Role::Viewer, auth::Role::Operator]);
+
+        // The command line replaces the file's tokens rather than adding to them
+        let args = args_with_file(&["fsr-rs", "--token", "admin:xyz"], content);
+        assert_eq!(args.tokens.len(), 1);
+        assert_eq!(args.tokens[0].to_arg(), "admin:xyz");
+    }
+
+    #[test]
+    fn test_default_thresholds() {
+        assert_eq!(
+            parse_thresholds("400, 400,450,400"),
+            Ok([400, 400, 450, 400].into())
+        );
+        assert_eq!(parse_thresholds("1,2,3,4,5"), Ok([1, 2, 3, 4, 5].into()));
+        assert!(parse_thresholds("0,0,0,0,0,0,0,0,0,0")
+            .unwrap_err()
+            .contains("expected at most 9 comma separated thresholds"));
+        assert!(parse_thresholds("1,2,x,4").is_err());
+        assert!(Args::try_parse_from(["fsr-rs", "--default-thresholds", ""]).is_err());
+
+        let args = args_with_file(&["fsr-rs"], "");
+        assert_eq!(args.bootstrap_thresholds(), [100, 200, 300, 400]);
+        assert_eq!(args.bootstrap_profile, "DEFAULT");
+
+        // The mock's layout decides how many there are, unless they are given
+        let args = args_with_file(&["fsr-rs", "--mock-serial", "--mock-layout", "pump5"], "");
+        assert_eq!(args.mock_sensors(), 5);
+        assert_eq!(args.bootstrap_thresholds(), [100, 200, 300, 400, 500]);
+        let content = "mock_serial = true\nmock_layout = \"pad9\"\n";
+        let args = args_with_file(&["fsr-rs", "--default-thresholds", "1,2"], content);
+        assert_eq!(args.mock_sensors(), 9);
+        assert_eq!(args.bootstrap_thresholds(), [1, 2]);
+        assert!(Args::try_parse_from(["fsr-rs", "--mock-layout", "custom"]).is_err());
+
+        let content = "default_thresholds = [400, 400, 450, 400]\nbootstrap_profile = \"Pad\"\n";
+        let args = args_with_file(&["fsr-rs", "--default-thresholds", "1,2,3,4"], content);
+        assert_eq!(args.bootstrap_thresholds(), [1, 2, 3, 4]);
+        assert_eq!(args.bootstrap_profile, "Pad");
+
+        assert!(parse_config("default_thresholds = []").is_err());
+    }
+
+    #[test]
+    fn test_read_only_flag() {
+        let args = args_with_file(&["fsr-rs"], "");
+        assert!(!args.read_only_policy().is_enabled());
+
+        let args = args_with_file(&["fsr-rs", "--read-only"], "");
+        assert_eq!(args.read_only, ReadOnlyMode::Strict);
+
+        let args = args_with_file(&["fsr-rs", "--read-only=soft"], "");
+        assert_eq!(args.read_only, ReadOnlyMode::Soft);
+
+        let args = args_with_file(
+            &["fsr-rs"],
+            "read_only = \"strict\"\nread_only_allow_stream = true\n",
+        );
+        assert_eq!(
+            args.read_only_policy(),
+            ReadOnlyPolicy {
+                mode: ReadOnlyMode::Strict,
+                allow_stream: true,
+                mirror: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_effective_config_round_trip() {
+        let args = args_with_file(&["fsr-rs", "--pad-name", "Cab"], "port = 8080\n");
+        let printed = toml::to_string_pretty(&effective_config(&args)).unwrap();
+        let (config, warnings) = parse_config(&printed).unwrap();
+
+        assert!(warnings.is_empty());
+        assert_eq!(config, effective_config(&args));
+        assert_eq!(config.pad_name.as_deref(), Some("Cab"));
+        assert_eq!(config.port, Some(8080));
+    }
+}
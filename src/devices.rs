@@ -0,0 +1,283 @@
+use crate::profile::{Pad, SensorMap};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPortType;
+use std::collections::HashSet;
+
+// What identifies a pad's device when the OS renames its port: the USB ids, plus the
+// serial number where the firmware reports one. Identical devices without one are told
+// apart by the port they were last seen on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+}
+
+impl DeviceIdentity {
+    // "usb:VID:PID[:SERIAL]" with hex ids, the "usb:" prefix being optional
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.strip_prefix("usb:").unwrap_or(text);
+        let mut parts = text.splitn(3, ':');
+        let vid = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let pid = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let serial_number = parts
+            .next()
+            .filter(|serial| !serial.is_empty())
+            .map(str::to_string);
+        Some(Self {
+            vid,
+            pid,
+            serial_number,
+        })
+    }
+
+    fn matches(&self, other: &DeviceIdentity) -> bool {
+        self.vid == other.vid
+            && self.pid == other.pid
+            && (self.serial_number.is_none() || self.serial_number == other.serial_number)
+    }
+}
+
+impl std::fmt::Display for DeviceIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "usb:{:04x}:{:04x}", self.vid, self.pid)?;
+        if let Some(serial) = &self.serial_number {
+            write!(f, ":{}", serial)?;
+        }
+        Ok(())
+    }
+}
+
+// A serial port present on this machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectedPort {
+    pub name: String,
+    // None for ports that aren't USB
+    pub identity: Option<DeviceIdentity>,
+}
+
+// Serial ports currently present; empty if they can't be listed
+pub fn scan() -> Vec<DetectedPort> {
+    let ports = match serialport::available_ports() {
+        Ok(ports) => ports,
+        Err(e) => {
+            eprintln!("Failed to list serial ports: {}", e);
+            return Vec::new();
+        }
+    };
+    ports
+        .into_iter()
+        .map(|port| DetectedPort {
+            identity: match port.port_type {
+                SerialPortType::UsbPort(usb) => Some(DeviceIdentity {
+                    vid: usb.vid,
+                    pid: usb.pid,
+                    serial_number: usb.serial_number,
+                }),
+                _ => None,
+            },
+            name: port.port_name,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceState {
+    Connected,
+    // Assigned, but the device isn't present
+    Disconnected,
+    // Neither a device nor a port was assigned
+    Unassigned,
+}
+
+// Where a pad's device is right now, as returned by GetPadMapping
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PadMapping {
+    pub pad: String,
+    pub name: String,
+    pub device: Option<DeviceIdentity>,
+    // Port assigned to the pad, or where its device was last seen
+    pub assigned_port: Option<String>,
+    // Port the device is on now
+    pub port: Option<String>,
+    pub state: DeviceState,
+    // False while the pad is switched off with SetPadEnabled
+    pub enabled: bool,
+    // How the pad's panels are wired to the device's sensors
+    pub sensor_map: SensorMap,
+}
+
+// Find the port of every pad. Ports are handed out most specific assignment first, and a
+// port found for one pad is never given to another, so a missing device leaves its pad
+// disconnected instead of taking over a port that belongs to someone else.
+pub fn resolve(pads: &[Pad], ports: &[DetectedPort]) -> Vec<PadMapping> {
+    let mut claimed: HashSet<&str> = HashSet::new();
+    let mut found: Vec<Option<&str>> = vec![None; pads.len()];
+    // Ports pads were last seen on are held for them while their identity is unproven
+    let reserved: HashSet<&str> = pads.iter().filter_map(|pad| pad.port.as_deref()).collect();
+
+    // Most specific first: serial number, then USB ids, then a bare port name
+    let matches = |pass: usize, pad: &Pad, port: &DetectedPort| match (pass, &pad.device) {
+        (0 | 1, Some(wanted)) => {
+            let Some(identity) = &port.identity else {
+                return false;
+            };
+            match wanted.serial_number {
+                Some(_) => pass == 0 && wanted.matches(identity),
+                None => {
+                    pass == 1
+                        && wanted.matches(identity)
+                        && (pad.port.as_deref() == Some(&port.name)
+                            || !reserved.contains(port.name.as_str()))
+                }
+            }
+        }
+        (2, None) => pad.port.as_deref() == Some(&port.name),
+        _ => false,
+    };
+    for pass in 0..3 {
+        for (pad, slot) in pads.iter().zip(found.iter_mut()) {
+            if slot.is_some() {
+                continue;
+            }
+            let candidates: Vec<&DetectedPort> = ports
+                .iter()
+                .filter(|port| !claimed.contains(port.name.as_str()) && matches(pass, pad, port))
+                .collect();
+            // Prefer the port the pad was last seen on among equally good candidates
+            let port = candidates
+                .iter()
+                .find(|port| pad.port.as_deref() == Some(&port.name))
+                .or(candidates.first());
+            if let Some(port) = port {
+                claimed.insert(&port.name);
+                *slot = Some(&port.name);
+            }
+        }
+    }
+
+    pads.iter()
+        .zip(found)
+        .map(|(pad, port)| PadMapping {
+            pad: pad.id.clone(),
+            name: pad.name.clone(),
+            device: pad.device.clone(),
+            assigned_port: pad.port.clone(),
+            port: port.map(str::to_string),
+            state: match (port, &pad.device, &pad.port) {
+                (Some(_), _, _) => DeviceState::Connected,
+                (None, None, None) => DeviceState::Unassigned,
+                (None, _, _) => DeviceState::Disconnected,
+            },
+            enabled: pad.enabled,
+            sensor_map: pad.sensor_map,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pad(id: &str, device: Option<&str>, port: Option<&str>) -> Pad {
+        Pad {
+            id: id.to_string(),
+            device: device.map(|device| DeviceIdentity::parse(device).unwrap()),
+            port: port.map(str::to_string),
+            ..Pad::default_pad(String::new(), String::new())
+        }
+    }
+
+    fn port(name: &str, identity: &str) -> DetectedPort {
+        DetectedPort {
+            name: name.to_string(),
+            identity: DeviceIdentity::parse(identity),
+        }
+    }
+
+    #[test]
+    fn test_device_identity_round_trip() {
+        let identity = DeviceIdentity::parse("1209:2333:A1").unwrap();
+        assert_eq!(identity.to_string(), "usb:1209:2333:A1");
+        assert_eq!(DeviceIdentity::parse("usb:1209:2333:A1"), Some(identity));
+        assert_eq!(
+            DeviceIdentity::parse("usb:1209:2333")
+                .unwrap()
+                .serial_number,
+            None
+        );
+        assert_eq!(DeviceIdentity::parse("/dev/ttyACM0"), None);
+    }
+
+    #[test]
+    fn test_resolve_follows_renamed_ports() {
+        let pads = [
+            pad("left", Some("1209:2333:A1"), Some("COM3")),
+            pad("right", Some("1209:2333:B2"), Some("COM4")),
+        ];
+        // The OS swapped the port names
+        let ports = [port("COM4", "1209:2333:A1"), port("COM3", "1209:2333:B2")];
+
+        let mapping = resolve(&pads, &ports);
+        assert_eq!(mapping[0].port.as_deref(), Some("COM4"));
+        assert_eq!(mapping[1].port.as_deref(), Some("COM3"));
+        assert!(mapping.iter().all(|m| m.state == DeviceState::Connected));
+    }
+
+    #[test]
+    fn test_resolve_missing_device_does_not_steal() {
+        let pads = [
+            pad("left", Some("1209:2333"), Some("COM3")),
+            pad("right", Some("1209:2333"), Some("COM4")),
+            pad("spare", None, None),
+        ];
+        // Only the right pad is plugged in
+        let ports = [port("COM4", "1209:2333")];
+
+        let mapping = resolve(&pads, &ports);
+        assert_eq!(mapping[0].state, DeviceState::Disconnected);
+        assert_eq!(mapping[0].port, None);
+        assert_eq!(mapping[1].port.as_deref(), Some("COM4"));
+        assert_eq!(mapping[2].state, DeviceState::Unassigned);
+    }
+
+    #[tokio::test]
+    async fn test_assign_pad_port() {
+        use crate::commands::handle_command;
+        use crate::profile::{Command, Profiles};
+        use crate::state::AppState;
+
+        let mut profiles = Profiles::default();
+        profiles.pads.push(pad("right", None, None));
+        let state = AppState::with_mock_port(profiles);
+        let assign = |pad: &str, target: &str| Command::AssignPadPort {
+            pad: pad.to_string(),
+            port_or_device_id: target.to_string(),
+        };
+
+        let response = handle_command(assign("right", "usb:1209:2333:B2"), &state).await;
+        assert!(response.success, "{}", response.message);
+        let device = state.profiles_snapshot().pads[1].device.clone();
+        assert_eq!(device, DeviceIdentity::parse("1209:2333:B2"));
+
+        // The same device can't be on two pads
+        let response = handle_command(assign("default", "1209:2333:B2"), &state).await;
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "DEVICE_IN_USE" }))
+        );
+        let response = handle_command(assign("missing", "COM9"), &state).await;
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "PAD_NOT_FOUND" }))
+        );
+
+        let response = handle_command(Command::GetPadMapping, &state).await;
+        assert!(response.success);
+        let pads = &response.payload.unwrap()["pads"];
+        assert_eq!(pads[1]["pad"], "right");
+        assert_eq!(pads[1]["device"]["serial_number"], "B2");
+    }
+}
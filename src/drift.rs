@@ -0,0 +1,438 @@
+use crate::event::Event;
+use crate::journal::JournalKind;
+use crate::sensors::Sensors;
+use crate::serial::set_threshold_locked;
+use crate::state::AppState;
+use crate::threshold_log::WriteReason;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Rest value change, in raw device units, before a threshold follows it
+pub const DEFAULT_DRIFT_STEP: i32 = 20;
+
+// Largest total adjustment of one threshold
+pub const DEFAULT_DRIFT_MAX: i32 = 100;
+
+// Weight of a new reading in the rest value. At 60Hz the rest value follows a change
+// within a few seconds, while a short stomp below the threshold barely moves it.
+const REST_SMOOTHING: f64 = 1.0 / 64.0;
+
+// `--drift-compensation`, `--drift-step` and `--drift-max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DriftSettings {
+    pub enabled: bool,
+    pub step: i32,
+    pub max: i32,
+}
+
+impl Default for DriftSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            step: DEFAULT_DRIFT_STEP,
+            max: DEFAULT_DRIFT_MAX,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjustReason {
+    // The sensor's rest value moved
+    Drift,
+    // Compensation was turned off or the stream stopped; the profile's threshold is back
+    Reset,
+}
+
+// One change of a device threshold, logical sensor order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Adjustment {
+    pub sensor: usize,
+    pub before: i32,
+    pub after: i32,
+    // Total adjustment of the sensor after this one
+    pub offset: i32,
+    // Rest value the adjustment follows
+    pub rest: i32,
+    pub reason: AdjustReason,
+    // Compensation it was computed for; a clear in between makes it stale
+    #[serde(skip)]
+    generation: u64,
+}
+
+// Compensation of one sensor
+#[derive(Debug, Clone, Copy)]
+struct SensorDrift {
+    // Smoothed reading while the sensor isn't pressed
+    rest: f64,
+    // Rest value at the last adjustment
+    reference: i32,
+    // Profile threshold the offset is added to
+    base: i32,
+    offset: i32,
+}
+
+#[derive(Debug, Default)]
+struct Compensator {
+    profile: String,
+    sensors: Sensors<Option<SensorDrift>>,
+}
+
+// Payload of GetDriftCompensation
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct DriftReport {
+    #[serde(flatten)]
+    pub settings: DriftSettings,
+    pub profile: String,
+    // The current profile's drift_compensation flag
+    pub profile_enabled: bool,
+    pub offsets: Sensors<i32>,
+    // Rest value of every sensor, null before it was seen at rest
+    pub rest: Sensors<Option<i32>>,
+}
+
+// Moves device thresholds along with the sensors' rest values, which drift with
+// temperature over a long session. Only the device is adjusted; the profile keeps its
+// thresholds, and a full write of them (profile change, resync) starts over from zero.
+pub struct DriftCompensation {
+    settings: DriftSettings,
+    inner: Mutex<Compensator>,
+    generation: AtomicU64,
+}
+
+impl DriftCompensation {
+    pub fn new(settings: DriftSettings) -> Self {
+        Self {
+            settings,
+            inner: Mutex::default(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn settings(&self) -> DriftSettings {
+        self.settings
+    }
+
+    // Feed one reading of the current profile's pad. Returns the threshold changes due,
+    // already counted; the caller writes them and reverts the ones that fail. With
+    // `active` false any offsets left are taken back.
+    pub fn observe(
+        &self,
+        profile: &str,
+        thresholds: Sensors<i32>,
+        active: bool,
+        values: Sensors<i32>,
+        ceiling: i32,
+    ) -> Vec<Adjustment> {
+        let Ok(mut inner) = self.inner.lock() else {
+            return Vec::new();
+        };
+        if inner.profile != profile {
+            *inner = Compensator {
+                profile: profile.to_string(),
+                ..Compensator::default()
+            };
+        }
+        if inner.sensors.len() != thresholds.len() {
+            inner.sensors = Sensors::filled(thresholds.len(), None);
+        }
+        let generation = self.generation.load(Ordering::Relaxed);
+        let mut adjustments = Vec::new();
+        for (sensor, slot) in inner.sensors.iter_mut().enumerate() {
+            let base = thresholds[sensor];
+            // A command wrote the sensor's threshold; the device has no offset anymore
+            if slot.is_some_and(|drift| drift.base != base) {
+                *slot = None;
+            }
+            if !active {
+                if let Some(drift) = slot.take().filter(|drift| drift.offset != 0) {
+                    adjustments.push(Adjustment {
+                        sensor,
+                        before: base + drift.offset,
+                        after: base,
+                        offset: 0,
+                        rest: drift.rest.round() as i32,
+                        reason: AdjustReason::Reset,
+                        generation,
+                    });
+                }
+                continue;
+            }
+            let Some(&value) = values.get(sensor) else {
+                continue;
+            };
+            let drift = match slot {
+                Some(drift) if value < drift.base + drift.offset => drift,
+                // Pressed: not a rest value
+                Some(_) => continue,
+                None if value < base => slot.insert(SensorDrift {
+                    rest: value as f64,
+                    reference: value,
+                    base,
+                    offset: 0,
+                }),
+                None => continue,
+            };
+            drift.rest += (value as f64 - drift.rest) * REST_SMOOTHING;
+            let rest = drift.rest.round() as i32;
+            let delta = rest - drift.reference;
+            if delta.abs() <= self.settings.step {
+                continue;
+            }
+            drift.reference = rest;
+            let limit = self.settings.max;
+            let offset = (drift.offset + delta)
+                .clamp(-limit, limit)
+                .clamp(-base, ceiling - base);
+            if offset == drift.offset {
+                continue;
+            }
+            adjustments.push(Adjustment {
+                sensor,
+                before: base + drift.offset,
+                after: base + offset,
+                offset,
+                rest,
+                reason: AdjustReason::Drift,
+                generation,
+            });
+            drift.offset = offset;
+        }
+        adjustments
+    }
+
+    // Take back an adjustment that didn't reach the device
+    pub fn revert(&self, adjustment: &Adjustment) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        if let Some(drift) = inner.sensors[adjustment.sensor].as_mut() {
+            drift.offset = adjustment.before - drift.base;
+        }
+    }
+
+    // Forget all offsets, for a write of the profile's thresholds. Adjustments computed
+    // before are stale and no longer written.
+    pub fn clear(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut inner) = self.inner.lock() {
+            *inner = Compensator::default();
+        }
+    }
+
+    fn is_current(&self, adjustment: &Adjustment) -> bool {
+        self.generation.load(Ordering::Relaxed) == adjustment.generation
+    }
+
+    // The thresholds the device should have for `profile`
+    pub fn compensated(&self, profile: &str, thresholds: Sensors<i32>) -> Sensors<i32> {
+        let offsets = self.report_offsets(profile).0;
+        let offset = |sensor| offsets.get(sensor).copied().unwrap_or(0);
+        Sensors::from_fn(thresholds.len(), |sensor| {
+            thresholds[sensor] + offset(sensor)
+        })
+    }
+
+    fn report_offsets(&self, profile: &str) -> (Sensors<i32>, Sensors<Option<i32>>) {
+        let Ok(inner) = self.inner.lock() else {
+            return Default::default();
+        };
+        if inner.profile != profile {
+            return Default::default();
+        }
+        (
+            inner
+                .sensors
+                .map(|drift| drift.map_or(0, |drift| drift.offset)),
+            inner
+                .sensors
+                .map(|drift| drift.map(|drift| drift.rest.round() as i32)),
+        )
+    }
+
+    pub fn report(&self, profile: &str, profile_enabled: bool) -> DriftReport {
+        let (offsets, rest) = self.report_offsets(profile);
+        DriftReport {
+            settings: self.settings,
+            profile: profile.to_string(),
+            profile_enabled,
+            offsets,
+            rest,
+        }
+    }
+}
+
+impl Default for DriftCompensation {
+    fn default() -> Self {
+        Self::new(DriftSettings::default())
+    }
+}
+
+// Follow the rest values with a reading of the stream, `values` in logical order
+pub async fn compensate(state: &AppState, values: Sensors<i32>) {
+    let settings = state.drift.settings();
+    let profiles = state.profiles_snapshot();
+    let Some(profile) = profiles.profiles.get(profiles.current_profile()) else {
+        return;
+    };
+    let active = settings.enabled && profile.drift_compensation;
+    let adjustments = state.drift.observe(
+        profiles.current_profile(),
+        profile.thresholds,
+        active,
+        values,
+        state.range.bound().max,
+    );
+    write(state, &adjustments).await;
+}
+
+// Take back all offsets, when the stream stops
+pub async fn reset(state: &AppState) {
+    let profiles = state.profiles_snapshot();
+    let Some(profile) = profiles.profiles.get(profiles.current_profile()) else {
+        state.drift.clear();
+        return;
+    };
+    let adjustments = state.drift.observe(
+        profiles.current_profile(),
+        profile.thresholds,
+        false,
+        Sensors::default(),
+        state.range.bound().max,
+    );
+    write(state, &adjustments).await;
+}
+
+// Put the adjustments on the device and announce them
+async fn write(state: &AppState, adjustments: &[Adjustment]) {
+    if adjustments.is_empty() {
+        return;
+    }
+    let profiles = state.profiles_snapshot();
+    let sensor_map = profiles.sensor_map();
+    let pad: Arc<str> = profiles.device_pad_id().into();
+    for adjustment in adjustments {
+        let written = match state.serial_queue.enter() {
+            Ok(_queued) => {
+                let mut port = state.serial.lock().await;
+                // Checked under the port, so a profile written meanwhile isn't overwritten
+                if !state.drift.is_current(adjustment) {
+                    return;
+                }
+                let physical = sensor_map.physical(adjustment.sensor);
+                let written = set_threshold_locked(
+                    &mut **port,
+                    physical,
+                    Some(adjustment.before),
+                    adjustment.after,
+                    state
+                        .threshold_writes
+                        .reason(WriteReason::DriftCompensation),
+                )
+                .await;
+                state
+                    .metrics
+                    .serial_write(1, written)
+                    .map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        match written {
+            Ok(()) => state.publish(Event::DriftCompensation {
+                pad: Arc::clone(&pad),
+                adjustment: *adjustment,
+            }),
+            Err(e) => {
+                state.drift.revert(adjustment);
+                let message = format!(
+                    "Drift compensation of sensor {} to {} failed: {}",
+                    adjustment.sensor, adjustment.after, e
+                );
+                state.journal.record(JournalKind::SerialError, message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thresholds_follow_rest_values() {
+        let drift = DriftCompensation::new(DriftSettings {
+            enabled: true,
+            step: 20,
+            max: 50,
+        });
+        let thresholds = [400, 400, 400, 400].into();
+        let observe = |values: Sensors<i32>| drift.observe("P", thresholds, true, values, 1023);
+
+        // Rest values are learned; noise and presses don't move anything
+        assert!(observe([100, 100, 100, 100].into()).is_empty());
+        assert!(observe([110, 100, 900, 100].into()).is_empty());
+
+        // Sensor 0 settles 30 higher: once the rest value has moved past the step, its
+        // threshold follows by what it moved
+        let adjustments: Vec<Adjustment> = (0..400)
+            .flat_map(|_| observe([130, 100, 100, 100].into()))
+            .collect();
+        assert_eq!(adjustments.len(), 1);
+        let first = adjustments[0];
+        assert_eq!((first.sensor, first.before), (0, 400));
+        assert_eq!(first.after - first.before, first.rest - 100);
+        assert!(first.rest > 120);
+        assert_eq!(first.reason, AdjustReason::Drift);
+
+        // Bounded by the largest total adjustment
+        let adjustments: Vec<Adjustment> = (0..2000)
+            .flat_map(|_| observe([250, 100, 100, 100].into()))
+            .collect();
+        assert_eq!(adjustments.last().unwrap().after, 450);
+        assert_eq!(drift.report("P", true).offsets, [50, 0, 0, 0]);
+        assert_eq!(drift.compensated("P", thresholds), [450, 400, 400, 400]);
+        assert!(drift.report("Other", true).offsets.is_empty());
+
+        // Turned off: the threshold goes back
+        let reset = drift.observe("P", thresholds, false, [250, 100, 100, 100].into(), 1023);
+        assert_eq!(reset.len(), 1);
+        assert_eq!((reset[0].before, reset[0].after), (450, 400));
+        assert_eq!(reset[0].reason, AdjustReason::Reset);
+        assert_eq!(drift.report("P", true).offsets, [0; 4]);
+    }
+
+    #[test]
+    fn test_rewritten_threshold_drops_offset() {
+        let drift = DriftCompensation::new(DriftSettings {
+            enabled: true,
+            step: 5,
+            max: 100,
+        });
+        let observe = |thresholds: [i32; 4], values: [i32; 4]| {
+            drift.observe("P", thresholds.into(), true, values.into(), 1023)
+        };
+        observe([400; 4], [100; 4]);
+        let adjusted = (0..200).flat_map(|_| observe([400; 4], [150, 100, 100, 100]));
+        assert!(adjusted.count() > 0);
+        assert!(drift.report("P", true).offsets[0] > 0);
+
+        // UpdateThreshold wrote sensor 0: its offset is gone without a reset write
+        assert!(observe([300, 400, 400, 400], [150, 100, 100, 100]).is_empty());
+        assert_eq!(drift.report("P", true).offsets, [0; 4]);
+
+        // A stale adjustment isn't current after a clear
+        let adjustment = Adjustment {
+            sensor: 0,
+            before: 0,
+            after: 0,
+            offset: 0,
+            rest: 0,
+            reason: AdjustReason::Drift,
+            generation: 0,
+        };
+        assert!(drift.is_current(&adjustment));
+        drift.clear();
+        assert!(!drift.is_current(&adjustment));
+    }
+}
@@ -0,0 +1,933 @@
+use crate::auth::Role;
+use crate::instance::{held_message, LockInfo};
+use crate::profile::import::{ImportError, FORMATS};
+use crate::profile::{Profile, Response};
+use crate::proposal::ProposalStatus;
+use crate::range::BoundSource;
+use crate::share::ShareError;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde_json::json;
+use thiserror::Error;
+
+// Failures talking to the pad over the serial line
+#[derive(Debug, Error)]
+pub enum SerialError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("Timeout reading {0}")]
+    Timeout(&'static str),
+    #[error("Invalid {0} format")]
+    InvalidResponse(&'static str),
+    #[error("Failed to parse {0}")]
+    Parse(&'static str),
+    #[error("Threshold validation failed: expected {expected}, got {actual}")]
+    ThresholdMismatch { expected: i32, actual: i32 },
+    #[error("Response line longer than {0} bytes")]
+    LineTooLong(usize),
+    // Checked before writing, so thresholds never go to indices the device doesn't have
+    #[error("The device has {device} sensors, not the {thresholds} of the thresholds")]
+    SensorCount { device: usize, thresholds: usize },
+    // A write of set_all_thresholds failed after changing some thresholds, which were
+    // then put back where possible
+    #[error("Writing threshold {failed} failed ({source}); changed {changed:?}, restored {restored:?}, left inconsistent {inconsistent:?}")]
+    PartialWrite {
+        failed: usize,
+        source: Box<SerialError>,
+        changed: Vec<usize>,
+        restored: Vec<usize>,
+        inconsistent: Vec<usize>,
+    },
+}
+
+impl SerialError {
+    // Whether the device was left with thresholds from both before and after the write
+    pub fn left_inconsistent(&self) -> bool {
+        matches!(self, SerialError::PartialWrite { inconsistent, .. } if !inconsistent.is_empty())
+    }
+}
+
+// Failures reading or writing profiles.json and the error journal
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("Failed to load profiles: {0}")]
+    Read(#[source] std::io::Error),
+    #[error("Failed to load profiles: {0}")]
+    Parse(#[source] serde_json::Error),
+    #[error("Failed to save profiles: {0}")]
+    Serialize(#[source] serde_json::Error),
+    #[error("Failed to save profiles: {0}")]
+    Write(#[source] std::io::Error),
+    #[error("Failed to read the error journal: {0}")]
+    JournalRead(#[source] std::io::Error),
+    #[error("Failed to access snapshots: {0}")]
+    Snapshot(#[source] std::io::Error),
+    #[error("Failed to save proposals: {0}")]
+    Proposals(#[source] std::io::Error),
+}
+
+// Failures locking the server's directory on startup, see instance.rs
+#[derive(Debug, Error)]
+pub enum InstanceError {
+    #[error("{}", held_message(.0))]
+    Held(Option<LockInfo>),
+    #[error("Failed to lock {path}: {source}")]
+    Lock {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("The running fsr-rs{} didn't stop within {secs}s", pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default())]
+    TakeoverTimeout { pid: Option<u32>, secs: u64 },
+}
+
+// Failures starting or stopping a runtime serial capture
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("A serial capture is already running ({0})")]
+    AlreadyRunning(String),
+    #[error("No serial capture is running")]
+    NotRunning,
+    #[error("Failed to create the capture file: {0}")]
+    Create(#[source] std::io::Error),
+}
+
+// Commands that don't make sense for the current state
+#[derive(Debug, Error, PartialEq)]
+pub enum ValidationError {
+    #[error("Profile '{0}' not found")]
+    ProfileNotFound(String),
+    #[error("Profile '{0}' already exists")]
+    ProfileExists(String),
+    #[error("Threshold index must be 0-{max}")]
+    ThresholdIndex { max: usize },
+    #[error("Cannot remove the currently selected profile")]
+    RemoveCurrentProfile,
+    #[error("No current profile selected")]
+    NoCurrentProfile,
+    #[error("Player '{player}' has invalid profile '{profile}'")]
+    PlayerProfileMissing { player: String, profile: String },
+    #[error("No default profile or current profile available to assign to new player")]
+    NoProfileForNewPlayer,
+    #[error("Exactly {expected} values are required, one per sensor, got {got}")]
+    ThresholdCount { expected: usize, got: usize },
+    #[error(
+        "Profile '{0}' was changed by another command while the device was updated, please retry"
+    )]
+    ConcurrentChange(String),
+    #[error("Pad '{0}' not found")]
+    PadNotFound(String),
+    #[error("Pad '{pad}' was pressed {pressed_ms}ms ago, use force to identify it mid-session")]
+    PadInSession { pad: String, pressed_ms: u64 },
+    #[error("'{device}' is already assigned to pad '{pad}'")]
+    DeviceAssigned { device: String, pad: String },
+    #[error("Unknown layout '{0}'")]
+    UnknownLayout(String),
+    #[error("Layout '{layout}' needs {needs} sensors but the device has {sensors}")]
+    LayoutMismatch {
+        layout: String,
+        needs: usize,
+        sensors: usize,
+    },
+    #[error("Gain {gain} of sensor {sensor} must be a positive number")]
+    InvalidGain { sensor: usize, gain: f32 },
+    #[error(
+        "Hysteresis {hysteresis} of sensor {sensor} must be 0-{threshold}, the sensor's threshold"
+    )]
+    InvalidHysteresis {
+        sensor: usize,
+        hysteresis: i32,
+        threshold: i32,
+    },
+    #[error("The sensor stream must be running to calibrate")]
+    StreamStopped,
+    #[error("Sensors {0:?} were not pressed past their threshold during calibration")]
+    CalibrationIncomplete(Vec<usize>),
+    #[error("Sensor map {0:?} must use each of its sensors exactly once")]
+    InvalidSensorMap(Vec<usize>),
+    #[error("Pad '{0}' is disabled")]
+    PadDisabled(String),
+    #[error("Response of {size} bytes is larger than the limit of {limit} bytes")]
+    ResponseTooLarge { size: usize, limit: usize },
+    #[error("Protocol version {version} is not supported, this server speaks {min} to {max}")]
+    UnsupportedProtocol { version: u32, min: u32, max: u32 },
+    // Not named `source`, which thiserror takes for the underlying error
+    #[error("Threshold {value} is outside 0-{max} (bound {bound_source})")]
+    ThresholdOutOfRange {
+        value: i32,
+        max: i32,
+        bound_source: BoundSource,
+    },
+    #[error("Cannot create more {kind}, the limit is {limit}")]
+    QuotaExceeded { kind: &'static str, limit: usize },
+    // expected_revision of a command didn't match; `current` is the profile as it is now
+    #[error("{} is at revision {revision}, not the expected {expected}; reload and retry", revision_subject(.profile))]
+    Conflict {
+        profile: Option<String>,
+        expected: u64,
+        revision: u64,
+        current: Option<Box<Profile>>,
+    },
+    #[error("Invalid share code: {0}")]
+    InvalidShareCode(ShareError),
+    #[error("Unknown import format '{0}', expected one of {supported}", supported = FORMATS.join(", "))]
+    UnknownImportFormat(String),
+    #[error("Invalid import: {0}")]
+    InvalidImport(ImportError),
+    #[error("A panel test of pad '{0}' is already running")]
+    PanelTestRunning(String),
+    #[error("No panel test is running")]
+    PanelTestNotRunning,
+    #[error("Integration file '{0}' not found")]
+    FileNotFound(String),
+    #[error("Snapshot '{0}' not found")]
+    SnapshotNotFound(String),
+    #[error("Snapshot labels are at most {0} characters")]
+    LabelTooLong(usize),
+    #[error("Tag '{tag}' must be 1-{max} characters")]
+    InvalidTag { tag: String, max: usize },
+    #[error("Profiles have at most {0} tags")]
+    TooManyTags(usize),
+    #[error("Invalid schedule '{spec}': {reason}")]
+    InvalidSchedule { spec: String, reason: String },
+    #[error("Schedule '{0}' not found")]
+    ScheduleNotFound(String),
+    #[error("Pad '{0}' is not on a temporary profile")]
+    NoTemporaryProfile(String),
+    #[error("No player is signed in on pad '{0}'")]
+    NoCurrentPlayer(String),
+    #[error("Proposal {0} not found")]
+    ProposalNotFound(u64),
+    #[error("Proposal {id} was already {}", proposal_status(.status))]
+    ProposalDecided { id: u64, status: ProposalStatus },
+    #[error("Profile '{profile}' changed since proposal {id} was made (revision {revision}, now {current_revision}); use force to approve it anyway")]
+    ProposalStale {
+        id: u64,
+        profile: String,
+        revision: u64,
+        current_revision: u64,
+    },
+    #[error("Proposal notes are at most {0} characters")]
+    NoteTooLong(usize),
+    #[error("Notification sink '{0}' not found")]
+    SinkNotFound(String),
+    #[error("Profile '{profile}' is the profile of players {players:?}; confirm and give reassign_to to move them")]
+    ConfirmationRequired {
+        profile: String,
+        players: Vec<String>,
+    },
+}
+
+fn proposal_status(status: &ProposalStatus) -> &'static str {
+    match status {
+        ProposalStatus::Pending => "pending",
+        ProposalStatus::Approved => "approved",
+        ProposalStatus::Rejected => "rejected",
+    }
+}
+
+fn revision_subject(profile: &Option<String>) -> String {
+    match profile {
+        Some(profile) => format!("Profile '{}'", profile),
+        None => "Profiles".to_string(),
+    }
+}
+
+// What the server was doing when a serial error happened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SerialOp {
+    SetThreshold,
+    SetThresholds,
+    ReadThresholds,
+    ReadSensorValues,
+    Identify,
+}
+
+impl std::fmt::Display for SerialOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SerialOp::SetThreshold => "Failed to set threshold on serial device",
+            SerialOp::SetThresholds => "Failed to set thresholds on serial device",
+            SerialOp::ReadThresholds => "Failed to read thresholds from device",
+            SerialOp::ReadSensorValues => "Failed to read sensor values",
+            SerialOp::Identify => "Failed to identify pad on serial device",
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("{op}: {source}")]
+    Serial {
+        op: SerialOp,
+        #[source]
+        source: SerialError,
+    },
+    #[error("Device thresholds ({device:?}) don't match profile ({profile:?}) and failed to fix: {source}")]
+    DeviceOutOfSync {
+        device: Box<[i32]>,
+        profile: Box<[i32]>,
+        #[source]
+        source: SerialError,
+    },
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    #[error(transparent)]
+    Capture(#[from] CaptureError),
+    #[error("READ_ONLY_MODE: the server is read-only")]
+    ReadOnly,
+    #[error("MIRROR_MODE: the server mirrors another fsr-rs instance, send changes to that one")]
+    MirrorMode,
+    #[error("A valid token is required")]
+    Unauthorized,
+    #[error("{command} needs the {required} role, this client is {role}")]
+    Forbidden {
+        command: &'static str,
+        required: Role,
+        role: Role,
+    },
+    #[error("Invalid command: {0}")]
+    InvalidCommand(String),
+    #[error("Malformed JSON: {0}")]
+    MalformedJson(String),
+    #[error("Invalid number for '{field}': {reason}")]
+    InvalidNumber { field: String, reason: String },
+    #[error("The device is busy with {depth} queued command(s), the limit is {limit}")]
+    SerialBusy { depth: usize, limit: usize },
+    #[error("Too many fresh sensor reads, retry in {retry_after_ms} ms")]
+    RateLimited { retry_after_ms: u64 },
+}
+
+impl AppError {
+    pub fn serial(op: SerialOp) -> impl FnOnce(SerialError) -> AppError {
+        move |source| AppError::Serial { op, source }
+    }
+
+    // Stable machine readable code sent to clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Serial { source, .. } | AppError::DeviceOutOfSync { source, .. } => {
+                match source {
+                    SerialError::Io(_) => "SERIAL_IO",
+                    SerialError::Timeout(_) => "SERIAL_TIMEOUT",
+                    SerialError::InvalidResponse(_)
+                    | SerialError::Parse(_)
+                    | SerialError::LineTooLong(_) => "SERIAL_PROTOCOL",
+                    SerialError::ThresholdMismatch { .. } => "THRESHOLD_MISMATCH",
+                    SerialError::PartialWrite { .. } => "PARTIAL_WRITE",
+                    SerialError::SensorCount { .. } => "LAYOUT_MISMATCH",
+                }
+            }
+            AppError::Storage(
+                StorageError::Read(_) | StorageError::Parse(_) | StorageError::JournalRead(_),
+            ) => "LOAD_FAILED",
+            AppError::Storage(StorageError::Serialize(_) | StorageError::Write(_)) => "SAVE_FAILED",
+            AppError::Storage(StorageError::Snapshot(_)) => "SNAPSHOT_FAILED",
+            AppError::Storage(StorageError::Proposals(_)) => "SAVE_FAILED",
+            AppError::Validation(error) => match error {
+                ValidationError::ProfileNotFound(_) => "PROFILE_NOT_FOUND",
+                ValidationError::ProfileExists(_) => "PROFILE_EXISTS",
+                ValidationError::ThresholdIndex { .. } => "INVALID_THRESHOLD_INDEX",
+                ValidationError::RemoveCurrentProfile => "PROFILE_IN_USE",
+                ValidationError::NoCurrentProfile => "NO_CURRENT_PROFILE",
+                ValidationError::PlayerProfileMissing { .. } => "PLAYER_PROFILE_MISSING",
+                ValidationError::NoProfileForNewPlayer => "NO_PROFILE_FOR_PLAYER",
+                ValidationError::ThresholdCount { .. } => "INVALID_THRESHOLD_COUNT",
+                ValidationError::ConcurrentChange(_) => "CONCURRENT_CHANGE",
+                ValidationError::PadNotFound(_) => "PAD_NOT_FOUND",
+                ValidationError::PadInSession { .. } => "PAD_IN_SESSION",
+                ValidationError::DeviceAssigned { .. } => "DEVICE_IN_USE",
+                ValidationError::UnknownLayout(_) => "UNKNOWN_LAYOUT",
+                ValidationError::LayoutMismatch { .. } => "LAYOUT_MISMATCH",
+                ValidationError::InvalidGain { .. } => "INVALID_GAIN",
+                ValidationError::InvalidHysteresis { .. } => "INVALID_HYSTERESIS",
+                ValidationError::StreamStopped => "STREAM_STOPPED",
+                ValidationError::CalibrationIncomplete(_) => "CALIBRATION_INCOMPLETE",
+                ValidationError::InvalidSensorMap(_) => "INVALID_SENSOR_MAP",
+                ValidationError::PadDisabled(_) => "PAD_DISABLED",
+                ValidationError::ResponseTooLarge { .. } => "RESPONSE_TOO_LARGE",
+                ValidationError::UnsupportedProtocol { .. } => "UNSUPPORTED_PROTOCOL",
+                ValidationError::ThresholdOutOfRange { .. } => "THRESHOLD_OUT_OF_RANGE",
+                ValidationError::QuotaExceeded { .. } => "QUOTA_EXCEEDED",
+                ValidationError::ConfirmationRequired { .. } => "CONFIRMATION_REQUIRED",
+                ValidationError::FileNotFound(_) => "FILE_NOT_FOUND",
+                ValidationError::SnapshotNotFound(_) => "SNAPSHOT_NOT_FOUND",
+                ValidationError::LabelTooLong(_) => "LABEL_TOO_LONG",
+                ValidationError::InvalidTag { .. } => "INVALID_TAG",
+                ValidationError::TooManyTags(_) => "TOO_MANY_TAGS",
+                ValidationError::InvalidSchedule { .. } => "INVALID_SCHEDULE",
+                ValidationError::ScheduleNotFound(_) => "SCHEDULE_NOT_FOUND",
+                ValidationError::NoTemporaryProfile(_) => "NO_TEMPORARY_PROFILE",
+                ValidationError::NoCurrentPlayer(_) => "NO_CURRENT_PLAYER",
+                ValidationError::ProposalNotFound(_) => "PROPOSAL_NOT_FOUND",
+                ValidationError::ProposalDecided { .. } => "PROPOSAL_DECIDED",
+                ValidationError::ProposalStale { .. } => "PROPOSAL_STALE",
+                ValidationError::NoteTooLong(_) => "NOTE_TOO_LONG",
+                ValidationError::SinkNotFound(_) => "SINK_NOT_FOUND",
+                ValidationError::PanelTestRunning(_) => "PANEL_TEST_RUNNING",
+                ValidationError::PanelTestNotRunning => "PANEL_TEST_NOT_RUNNING",
+                ValidationError::InvalidShareCode(_) => "INVALID_SHARE_CODE",
+                ValidationError::UnknownImportFormat(_) => "UNKNOWN_IMPORT_FORMAT",
+                ValidationError::InvalidImport(_) => "INVALID_IMPORT",
+                ValidationError::Conflict { .. } => "CONFLICT",
+            },
+            AppError::Capture(error) => match error {
+                CaptureError::AlreadyRunning(_) => "CAPTURE_RUNNING",
+                CaptureError::NotRunning => "CAPTURE_NOT_RUNNING",
+                CaptureError::Create(_) => "CAPTURE_FAILED",
+            },
+            AppError::ReadOnly => crate::commands::READ_ONLY_MODE,
+            AppError::MirrorMode => crate::mirror::MIRROR_MODE,
+            AppError::InvalidCommand(_) => "INVALID_COMMAND",
+            AppError::MalformedJson(_) => "MALFORMED_JSON",
+            AppError::InvalidNumber { .. } => "INVALID_NUMBER",
+            AppError::SerialBusy { .. } => "SERIAL_BUSY",
+            AppError::RateLimited { .. } => "RATE_LIMITED",
+            AppError::Unauthorized => "UNAUTHORIZED",
+            AppError::Forbidden { .. } => "FORBIDDEN",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Serial {
+                source: SerialError::Timeout(_),
+                ..
+            } => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Serial { .. } | AppError::DeviceOutOfSync { .. } => StatusCode::BAD_GATEWAY,
+            AppError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Validation(
+                ValidationError::ProfileNotFound(_)
+                | ValidationError::PlayerProfileMissing { .. }
+                | ValidationError::PadNotFound(_)
+                | ValidationError::FileNotFound(_)
+                | ValidationError::SnapshotNotFound(_)
+                | ValidationError::ScheduleNotFound(_)
+                | ValidationError::ProposalNotFound(_)
+                | ValidationError::SinkNotFound(_),
+            ) => StatusCode::NOT_FOUND,
+            AppError::Validation(
+                ValidationError::ProfileExists(_)
+                | ValidationError::RemoveCurrentProfile
+                | ValidationError::ConfirmationRequired { .. }
+                | ValidationError::Conflict { .. }
+                | ValidationError::ConcurrentChange(_)
+                | ValidationError::PadInSession { .. }
+                | ValidationError::DeviceAssigned { .. }
+                | ValidationError::StreamStopped
+                | ValidationError::PanelTestRunning(_)
+                | ValidationError::PanelTestNotRunning
+                | ValidationError::ProposalDecided { .. }
+                | ValidationError::ProposalStale { .. }
+                | ValidationError::PadDisabled(_),
+            ) => StatusCode::CONFLICT,
+            AppError::Validation(ValidationError::ResponseTooLarge { .. }) => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Capture(CaptureError::Create(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Capture(_) => StatusCode::CONFLICT,
+            AppError::ReadOnly | AppError::MirrorMode => StatusCode::FORBIDDEN,
+            AppError::InvalidCommand(_)
+            | AppError::MalformedJson(_)
+            | AppError::InvalidNumber { .. } => StatusCode::BAD_REQUEST,
+            AppError::SerialBusy { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden { .. } => StatusCode::FORBIDDEN,
+        }
+    }
+
+    // What the message is about, for clients that render their own text from the code
+    fn params(&self) -> Option<serde_json::Value> {
+        let error = match self {
+            AppError::Validation(error) => error,
+            AppError::InvalidNumber { field, reason } => {
+                return Some(json!({ "field": field, "reason": reason }))
+            }
+            AppError::SerialBusy { depth, limit } => {
+                return Some(json!({ "depth": depth, "limit": limit }))
+            }
+            AppError::RateLimited { retry_after_ms } => {
+                return Some(json!({ "retry_after_ms": retry_after_ms }))
+            }
+            AppError::Forbidden {
+                command,
+                required,
+                role,
+            } => {
+                return Some(json!({
+                    "command": command,
+                    "required_role": required,
+                    "role": role,
+                }))
+            }
+            AppError::Serial {
+                source:
+                    SerialError::PartialWrite {
+                        failed,
+                        changed,
+                        restored,
+                        inconsistent,
+                        ..
+                    },
+                ..
+            }
+            | AppError::DeviceOutOfSync {
+                source:
+                    SerialError::PartialWrite {
+                        failed,
+                        changed,
+                        restored,
+                        inconsistent,
+                        ..
+                    },
+                ..
+            } => {
+                return Some(json!({
+                    "failed": failed,
+                    "changed": changed,
+                    "restored": restored,
+                    "inconsistent": inconsistent,
+                }))
+            }
+            AppError::Serial {
+                source: SerialError::SensorCount { device, thresholds },
+                ..
+            } => return Some(json!({ "needs": thresholds, "sensors": device })),
+            _ => return None,
+        };
+        Some(match error {
+            ValidationError::ProfileNotFound(profile)
+            | ValidationError::ProfileExists(profile)
+            | ValidationError::ConcurrentChange(profile) => json!({ "profile": profile }),
+            ValidationError::PlayerProfileMissing { player, profile } => {
+                json!({ "player": player, "profile": profile })
+            }
+            ValidationError::PadNotFound(pad)
+            | ValidationError::PadDisabled(pad)
+            | ValidationError::PanelTestRunning(pad)
+            | ValidationError::NoTemporaryProfile(pad)
+            | ValidationError::NoCurrentPlayer(pad) => json!({ "pad": pad }),
+            ValidationError::PadInSession { pad, pressed_ms } => {
+                json!({ "pad": pad, "pressed_ms": pressed_ms })
+            }
+            ValidationError::DeviceAssigned { device, pad } => {
+                json!({ "device": device, "pad": pad })
+            }
+            ValidationError::UnknownLayout(layout) => json!({ "layout": layout }),
+            ValidationError::LayoutMismatch {
+                layout,
+                needs,
+                sensors,
+            } => json!({ "layout": layout, "needs": needs, "sensors": sensors }),
+            ValidationError::ThresholdIndex { max } => json!({ "max": max }),
+            ValidationError::ThresholdCount { expected, got } => {
+                json!({ "expected": expected, "got": got })
+            }
+            ValidationError::FileNotFound(file) => json!({ "file": file }),
+            ValidationError::SnapshotNotFound(id) | ValidationError::ScheduleNotFound(id) => {
+                json!({ "id": id })
+            }
+            ValidationError::LabelTooLong(max)
+            | ValidationError::TooManyTags(max)
+            | ValidationError::NoteTooLong(max) => json!({ "max": max }),
+            ValidationError::ProposalNotFound(id) => json!({ "id": id }),
+            ValidationError::SinkNotFound(sink) => json!({ "sink": sink }),
+            ValidationError::ProposalDecided { id, status } => {
+                json!({ "id": id, "status": status })
+            }
+            ValidationError::ProposalStale {
+                id,
+                profile,
+                revision,
+                current_revision,
+            } => json!({
+                "id": id,
+                "profile": profile,
+                "revision": revision,
+                "current_revision": current_revision,
+            }),
+            ValidationError::InvalidTag { tag, max } => json!({ "tag": tag, "max": max }),
+            ValidationError::InvalidSchedule { spec, reason } => {
+                json!({ "spec": spec, "reason": reason })
+            }
+            ValidationError::InvalidShareCode(error) => json!({ "reason": error.reason() }),
+            ValidationError::UnknownImportFormat(format) => {
+                json!({ "format": format, "supported": FORMATS })
+            }
+            ValidationError::InvalidImport(error) => {
+                json!({ "reason": error.reason(), "line": error.line() })
+            }
+            ValidationError::Conflict {
+                profile,
+                expected,
+                revision,
+                current,
+            } => json!({
+                "profile": profile,
+                "expected_revision": expected,
+                "revision": revision,
+                "current": current,
+            }),
+            ValidationError::InvalidGain { sensor, gain } => {
+                json!({ "sensor": sensor, "gain": gain })
+            }
+            ValidationError::InvalidHysteresis {
+                sensor,
+                hysteresis,
+                threshold,
+            } => json!({ "sensor": sensor, "hysteresis": hysteresis, "threshold": threshold }),
+            ValidationError::CalibrationIncomplete(sensors) => json!({ "sensors": sensors }),
+            ValidationError::InvalidSensorMap(sensor_map) => json!({ "sensor_map": sensor_map }),
+            ValidationError::ResponseTooLarge { size, limit } => {
+                json!({ "size": size, "limit": limit })
+            }
+            ValidationError::UnsupportedProtocol { version, min, max } => {
+                json!({ "version": version, "min": min, "max": max })
+            }
+            ValidationError::ThresholdOutOfRange {
+                value,
+                max,
+                bound_source,
+            } => json!({ "value": value, "min": 0, "max": max, "source": bound_source }),
+            ValidationError::QuotaExceeded { kind, limit } => {
+                json!({ "kind": kind, "limit": limit })
+            }
+            ValidationError::ConfirmationRequired { profile, players } => {
+                json!({ "profile": profile, "players": players })
+            }
+            ValidationError::RemoveCurrentProfile
+            | ValidationError::NoCurrentProfile
+            | ValidationError::NoProfileForNewPlayer
+            | ValidationError::PanelTestNotRunning
+            | ValidationError::StreamStopped => return None,
+        })
+    }
+
+    // Websocket form: a failed command_response carrying the code in the payload
+    pub fn to_response(&self) -> Response {
+        Response {
+            success: false,
+            message: self.to_string(),
+            data: None,
+            sensor_values: None,
+            response_type: Some("command_response".to_string()),
+            payload: Some(json!({ "code": self.code() })),
+            pad: None,
+            message_code: Some(self.code().to_string()),
+            params: self.params(),
+            previous: None,
+            seq: None,
+            dry_run: false,
+            unsaved_changes: false,
+            pending_changes: None,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        let body = json!({
+            "success": false,
+            "code": self.code(),
+            "message": self.to_string(),
+        });
+        (self.status(), axum::Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{Pad, Profile, Profiles};
+    use crate::state::AppState;
+    use std::collections::HashMap;
+
+    fn serial(op: SerialOp, source: SerialError) -> AppError {
+        AppError::serial(op)(source)
+    }
+
+    #[test]
+    fn test_error_strings_map_to_variants() {
+        let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+        let parse_error = serde_json::from_str::<Profiles>("{").unwrap_err();
+        let cases: Vec<(AppError, &str, &str, StatusCode)> = vec![
+            (
+                serial(SerialOp::ReadSensorValues, SerialError::Timeout("sensor values")),
+                "Failed to read sensor values: Timeout reading sensor values",
+                "SERIAL_TIMEOUT",
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (
+                serial(SerialOp::SetThreshold, SerialError::InvalidResponse("threshold response")),
+                "Failed to set threshold on serial device: Invalid threshold response format",
+                "SERIAL_PROTOCOL",
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                serial(SerialOp::ReadThresholds, SerialError::Parse("threshold value")),
+                "Failed to read thresholds from device: Failed to parse threshold value",
+                "SERIAL_PROTOCOL",
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                serial(
+                    SerialOp::SetThresholds,
+                    SerialError::ThresholdMismatch {
+                        expected: 100,
+                        actual: 90,
+                    },
+                ),
+                "Failed to set thresholds on serial device: Threshold validation failed: expected 100, got 90",
+                "THRESHOLD_MISMATCH",
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                serial(SerialOp::SetThresholds, SerialError::Io(timeout)),
+                "Failed to set thresholds on serial device: timed out",
+                "SERIAL_IO",
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                AppError::DeviceOutOfSync {
+                    device: Box::new([1, 2, 3, 4]),
+                    profile: Box::new([10, 20, 30, 40]),
+                    source: SerialError::Timeout("threshold response"),
+                },
+                "Device thresholds ([1, 2, 3, 4]) don't match profile ([10, 20, 30, 40]) and failed to fix: Timeout reading threshold response",
+                "SERIAL_TIMEOUT",
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                serial(
+                    SerialOp::SetThresholds,
+                    SerialError::PartialWrite {
+                        failed: 2,
+                        source: Box::new(SerialError::Timeout("threshold response")),
+                        changed: vec![0, 1],
+                        restored: vec![0],
+                        inconsistent: vec![1],
+                    },
+                ),
+                "Failed to set thresholds on serial device: Writing threshold 2 failed (Timeout reading threshold response); changed [0, 1], restored [0], left inconsistent [1]",
+                "PARTIAL_WRITE",
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                serial(
+                    SerialOp::SetThresholds,
+                    SerialError::SensorCount {
+                        device: 4,
+                        thresholds: 5,
+                    },
+                ),
+                "Failed to set thresholds on serial device: The device has 4 sensors, not the 5 of the thresholds",
+                "LAYOUT_MISMATCH",
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                StorageError::Parse(parse_error).into(),
+                "Failed to load profiles: EOF while parsing an object at line 1 column 1",
+                "LOAD_FAILED",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                StorageError::Write(std::io::Error::other("disk full")).into(),
+                "Failed to save profiles: disk full",
+                "SAVE_FAILED",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                ValidationError::ProfileNotFound("P".to_string()).into(),
+                "Profile 'P' not found",
+                "PROFILE_NOT_FOUND",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                ValidationError::FileNotFound("x.lua".to_string()).into(),
+                "Integration file 'x.lua' not found",
+                "FILE_NOT_FOUND",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                ValidationError::SnapshotNotFound("20260101-000000-000".to_string()).into(),
+                "Snapshot '20260101-000000-000' not found",
+                "SNAPSHOT_NOT_FOUND",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                StorageError::Snapshot(std::io::Error::other("disk full")).into(),
+                "Failed to access snapshots: disk full",
+                "SNAPSHOT_FAILED",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                ValidationError::PanelTestRunning("left".to_string()).into(),
+                "A panel test of pad 'left' is already running",
+                "PANEL_TEST_RUNNING",
+                StatusCode::CONFLICT,
+            ),
+            (
+                ValidationError::Conflict {
+                    profile: Some("P".to_string()),
+                    expected: 3,
+                    revision: 5,
+                    current: None,
+                }
+                .into(),
+                "Profile 'P' is at revision 5, not the expected 3; reload and retry",
+                "CONFLICT",
+                StatusCode::CONFLICT,
+            ),
+            (
+                ValidationError::InvalidShareCode(ShareError::Truncated).into(),
+                "Invalid share code: it is truncated",
+                "INVALID_SHARE_CODE",
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                ValidationError::ProfileExists("P".to_string()).into(),
+                "Profile 'P' already exists",
+                "PROFILE_EXISTS",
+                StatusCode::CONFLICT,
+            ),
+            (
+                ValidationError::ThresholdIndex { max: 3 }.into(),
+                "Threshold index must be 0-3",
+                "INVALID_THRESHOLD_INDEX",
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                ValidationError::RemoveCurrentProfile.into(),
+                "Cannot remove the currently selected profile",
+                "PROFILE_IN_USE",
+                StatusCode::CONFLICT,
+            ),
+            (
+                ValidationError::NoCurrentProfile.into(),
+                "No current profile selected",
+                "NO_CURRENT_PROFILE",
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                ValidationError::PlayerProfileMissing {
+                    player: "A".to_string(),
+                    profile: "P".to_string(),
+                }
+                .into(),
+                "Player 'A' has invalid profile 'P'",
+                "PLAYER_PROFILE_MISSING",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                ValidationError::NoProfileForNewPlayer.into(),
+                "No default profile or current profile available to assign to new player",
+                "NO_PROFILE_FOR_PLAYER",
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                ValidationError::ThresholdCount {
+                    expected: 5,
+                    got: 4,
+                }
+                .into(),
+                "Exactly 5 values are required, one per sensor, got 4",
+                "INVALID_THRESHOLD_COUNT",
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                ValidationError::ConcurrentChange("P".to_string()).into(),
+                "Profile 'P' was changed by another command while the device was updated, please retry",
+                "CONCURRENT_CHANGE",
+                StatusCode::CONFLICT,
+            ),
+            (
+                CaptureError::NotRunning.into(),
+                "No serial capture is running",
+                "CAPTURE_NOT_RUNNING",
+                StatusCode::CONFLICT,
+            ),
+            (
+                AppError::ReadOnly,
+                "READ_ONLY_MODE: the server is read-only",
+                "READ_ONLY_MODE",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                AppError::MirrorMode,
+                "MIRROR_MODE: the server mirrors another fsr-rs instance, send changes to that one",
+                "MIRROR_MODE",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                AppError::Unauthorized,
+                "A valid token is required",
+                "UNAUTHORIZED",
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                AppError::Forbidden {
+                    command: "AssignPadPort",
+                    required: Role::Admin,
+                    role: Role::Viewer,
+                },
+                "AssignPadPort needs the admin role, this client is viewer",
+                "FORBIDDEN",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                AppError::InvalidCommand("expected value".to_string()),
+                "Invalid command: expected value",
+                "INVALID_COMMAND",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                AppError::MalformedJson("EOF while parsing".to_string()),
+                "Malformed JSON: EOF while parsing",
+                "MALFORMED_JSON",
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+
+        for (error, message, code, status) in cases {
+            assert_eq!(error.to_string(), message);
+            assert_eq!(error.code(), code, "{}", message);
+            assert_eq!(error.status(), status, "{}", message);
+
+            let response = error.to_response();
+            assert!(!response.success);
+            assert_eq!(response.message, message);
+            assert_eq!(response.payload, Some(json!({ "code": code })));
+            assert_eq!(error.into_response().status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_command_reports_error_code() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        let state = AppState::with_mock_port(profiles);
+
+        let response = crate::commands::handle_command(
+            crate::profile::Command::RemoveProfile {
+                name: "Missing".to_string(),
+                confirm: false,
+                reassign_to: None,
+            },
+            &state,
+        )
+        .await;
+        assert!(!response.success);
+        assert_eq!(response.message, "Profile 'Missing' not found");
+        assert_eq!(
+            response.payload,
+            Some(json!({ "code": "PROFILE_NOT_FOUND" }))
+        );
+    }
+}
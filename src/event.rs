@@ -0,0 +1,987 @@
+use crate::aggregate::PadReading;
+use crate::drift::Adjustment;
+use crate::error::AppError;
+use crate::heartbeat::Heartbeat;
+use crate::idle::SessionSummary;
+use crate::latest::SensorSnapshot;
+use crate::mirror::MirrorStatus;
+use crate::panel_test::{PanelResult, PanelTestSummary};
+use crate::profile::{Profiles, Response};
+use crate::proposal::Proposal;
+use crate::repair::Repair;
+use crate::scale::Scale;
+use crate::schedule::Schedule;
+use crate::sensors::Sensors;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+// Event types a client can subscribe to; command responses are always delivered
+pub const SUBSCRIBABLE_EVENTS: [&str; 20] = [
+    "sensor_stream",
+    "press",
+    "aggregate_stream",
+    "profiles_updated",
+    "players_changed",
+    "identify",
+    "error",
+    "degraded",
+    "recovered",
+    "heartbeat",
+    "stream_state_changed",
+    "profiles_repaired",
+    "drift_compensation",
+    "panel_test_progress",
+    "panel_test_complete",
+    "scheduled_switch",
+    "temporary_profile_ended",
+    "player_signed_out",
+    "proposal_created",
+    "mirror_status",
+];
+
+// Internal events fanned out to every sink (websocket clients, pipe output, webhooks).
+// They are turned into the wire `Response` only at the edge, by `to_response`.
+#[derive(Debug, Clone)]
+pub enum Event {
+    // One reading of every sensor of a pad from the stream task, in raw device units, plus
+    // the same reading calibrated by the pad's profile if it has a calibration
+    SensorFrame {
+        pad: Arc<str>,
+        values: Sensors<i32>,
+        calibrated: Option<Sensors<i32>>,
+        // Rate the stream polled at when it read the frame
+        rate_hz: u32,
+        wire: WireJson,
+    },
+    // A reading changed which sensors of its pad are pressed, see press.rs
+    Press(Arc<SensorSnapshot>),
+    // The latest frame of every pad, once per stream tick
+    AggregateFrame(Arc<[PadReading]>, WireJson),
+    // A new profiles snapshot was published, or a keepalive of the current one
+    ProfilesUpdated(Arc<Profiles>),
+    // Players were added or changed; `total` is how many there are now
+    PlayersChanged {
+        total: usize,
+    },
+    // The result of a command, for the websocket connection with the id `connection` that
+    // sent it only
+    CommandResult {
+        connection: u64,
+        response: Response,
+    },
+    // A failure outside of any command, e.g. a background save
+    Error(Arc<AppError>),
+    // A background task died and is restarted by its supervisor after `restart_in`
+    Degraded {
+        task: &'static str,
+        reason: String,
+        restart_in: Duration,
+    },
+    // A pad is being identified; its UI should flash for `duration`
+    Identify {
+        pad: Arc<str>,
+        duration: Duration,
+    },
+    // Periodic status of the server, see heartbeat_task
+    Heartbeat(Arc<Heartbeat>),
+    // A task stopped making progress for `stalled_for` and was restarted by its watchdog
+    Recovered {
+        task: &'static str,
+        stalled_for: Duration,
+    },
+    // The sensor stream was started or stopped, by a command or by the server itself
+    StreamStateChanged {
+        enabled: bool,
+    },
+    // Dangling references in a loaded profiles.json were pointed at existing profiles
+    ProfilesRepaired(Arc<[Repair]>),
+    // Drift compensation moved a device threshold of the pad
+    DriftCompensation {
+        pad: Arc<str>,
+        adjustment: Adjustment,
+    },
+    // A panel's turn in a panel test ended; `next` is the sensor whose turn follows
+    PanelTestProgress {
+        pad: Arc<str>,
+        result: PanelResult,
+        next: Option<usize>,
+    },
+    // A panel test finished or was cancelled
+    PanelTestComplete(Arc<PanelTestSummary>),
+    // A schedule switched `pad` to its profile
+    ScheduledSwitch {
+        pad: Arc<str>,
+        schedule: Arc<Schedule>,
+    },
+    // A pad's temporary `profile` ended: its time was up (`expired`), it was reverted, the
+    // player changed or another profile was chosen; `restored` is the pad's profile now
+    TemporaryProfileEnded {
+        pad: Arc<str>,
+        profile: String,
+        restored: Option<String>,
+        reason: &'static str,
+    },
+    // The server signed a pad's player out, e.g. after --idle-sign-out minutes without a
+    // press, ending the session summarized
+    PlayerSignedOut {
+        pad: Arc<str>,
+        reason: &'static str,
+        summary: Arc<SessionSummary>,
+    },
+    // A threshold change was proposed for an operator to approve
+    ProposalCreated(Arc<Proposal>),
+    // A self-test's probe of the channel for its own subscriber, never sent to clients
+    SelfTest(u64),
+    // An event of the instance a --mirror server follows, re-served as it came
+    Mirrored {
+        kind: &'static str,
+        response: Arc<Response>,
+    },
+    // A --mirror server connected to its upstream or lost it
+    MirrorStatus(Arc<MirrorStatus>),
+}
+
+// Websocket JSON of a stream frame in the current protocol version, serialized by the
+// first connection that sends the frame and shared with the others through the clones
+// of the event. At 60Hz with many clients this is most of the work of broadcasting.
+#[derive(Debug, Clone, Default)]
+pub struct WireJson(Arc<OnceLock<Option<String>>>);
+
+impl WireJson {
+    pub fn get_or_init(&self, serialize: impl FnOnce() -> Option<String>) -> Option<&str> {
+        self.0.get_or_init(serialize).as_deref()
+    }
+}
+
+impl Event {
+    pub fn error(error: AppError) -> Self {
+        Event::Error(Arc::new(error))
+    }
+
+    // Shared serialization of the event, for the stream frames
+    pub fn wire(&self) -> Option<&WireJson> {
+        match self {
+            Event::SensorFrame { wire, .. } | Event::AggregateFrame(_, wire) => Some(wire),
+            Event::Press(_)
+            | Event::ProfilesUpdated(_)
+            | Event::PlayersChanged { .. }
+            | Event::CommandResult { .. }
+            | Event::Error(_)
+            | Event::Degraded { .. }
+            | Event::Recovered { .. }
+            | Event::Identify { .. }
+            | Event::Heartbeat(_)
+            | Event::StreamStateChanged { .. }
+            | Event::ProfilesRepaired(_)
+            | Event::DriftCompensation { .. }
+            | Event::PanelTestProgress { .. }
+            | Event::PanelTestComplete(_)
+            | Event::ScheduledSwitch { .. }
+            | Event::TemporaryProfileEnded { .. }
+            | Event::PlayerSignedOut { .. }
+            | Event::ProposalCreated(_)
+            | Event::SelfTest(_)
+            | Event::Mirrored { .. }
+            | Event::MirrorStatus(_) => None,
+        }
+    }
+
+    // Wire name of the event, sent as `response_type`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::SensorFrame { .. } => "sensor_stream",
+            Event::Press(_) => "press",
+            Event::AggregateFrame(..) => "aggregate_stream",
+            Event::ProfilesUpdated(_) => "profiles_updated",
+            Event::PlayersChanged { .. } => "players_changed",
+            Event::CommandResult { .. } => "command_response",
+            Event::Error(_) => "error",
+            Event::Degraded { .. } => "degraded",
+            Event::Recovered { .. } => "recovered",
+            Event::Identify { .. } => "identify",
+            Event::Heartbeat(_) => "heartbeat",
+            Event::StreamStateChanged { .. } => "stream_state_changed",
+            Event::ProfilesRepaired(_) => "profiles_repaired",
+            Event::DriftCompensation { .. } => "drift_compensation",
+            Event::PanelTestProgress { .. } => "panel_test_progress",
+            Event::PanelTestComplete(_) => "panel_test_complete",
+            Event::ScheduledSwitch { .. } => "scheduled_switch",
+            Event::TemporaryProfileEnded { .. } => "temporary_profile_ended",
+            Event::PlayerSignedOut { .. } => "player_signed_out",
+            Event::ProposalCreated(_) => "proposal_created",
+            Event::SelfTest(_) => "self_test",
+            Event::Mirrored { kind, .. } => kind,
+            Event::MirrorStatus(_) => "mirror_status",
+        }
+    }
+
+    // Pad the event is about, if it is about one
+    pub fn pad(&self) -> Option<&str> {
+        match self {
+            Event::SensorFrame { pad, .. }
+            | Event::Identify { pad, .. }
+            | Event::DriftCompensation { pad, .. }
+            | Event::PanelTestProgress { pad, .. }
+            | Event::ScheduledSwitch { pad, .. }
+            | Event::TemporaryProfileEnded { pad, .. }
+            | Event::PlayerSignedOut { pad, .. } => Some(pad),
+            Event::Press(frame) => Some(&frame.pad),
+            Event::PanelTestComplete(summary) => Some(&summary.plan.pad),
+            Event::CommandResult { response, .. } => response.pad.as_deref(),
+            Event::Mirrored { response, .. } => response.pad.as_deref(),
+            Event::AggregateFrame(..)
+            | Event::ProfilesUpdated(_)
+            | Event::PlayersChanged { .. }
+            | Event::Error(_)
+            | Event::Degraded { .. }
+            | Event::Recovered { .. }
+            | Event::Heartbeat(_)
+            | Event::StreamStateChanged { .. }
+            | Event::ProfilesRepaired(_)
+            | Event::ProposalCreated(_)
+            | Event::SelfTest(_)
+            | Event::MirrorStatus(_) => None,
+        }
+    }
+
+    pub fn to_response(&self) -> Response {
+        match self {
+            Event::SensorFrame {
+                pad,
+                values,
+                calibrated,
+                rate_hz,
+                ..
+            } => Response {
+                success: true,
+                message: "Sensor stream data".to_string(),
+                data: None,
+                sensor_values: Some(*values),
+                response_type: Some(self.kind().to_string()),
+                payload: Some(match calibrated {
+                    Some(calibrated) => serde_json::json!({
+                        "rate_hz": rate_hz,
+                        "calibrated": calibrated,
+                        "scale": Scale::Raw,
+                    }),
+                    None => serde_json::json!({ "rate_hz": rate_hz, "scale": Scale::Raw }),
+                }),
+                pad: Some(pad.to_string()),
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::Press(frame) => Response {
+                success: true,
+                message: format!(
+                    "Pressed sensors of pad '{}': {:?}",
+                    frame.pad, frame.press.pressed
+                ),
+                data: None,
+                sensor_values: Some(frame.values),
+                response_type: Some(self.kind().to_string()),
+                // With the parameters the press was judged with, so a recording of these
+                // events can be read without the profiles of the time
+                payload: Some(serde_json::json!({
+                    "code": "PRESS",
+                    "pressed": frame.press.pressed,
+                    "thresholds": frame.thresholds,
+                    "hysteresis": frame.parameters.hysteresis,
+                    "debounce_ms": frame.parameters.debounce_ms,
+                    "seq": frame.seq,
+                })),
+                pad: Some(frame.pad.to_string()),
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::AggregateFrame(pads, _) => Response {
+                success: true,
+                message: "Aggregate sensor data".to_string(),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({ "pads": pads, "scale": Scale::Raw })),
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::ProfilesUpdated(profiles) => Response {
+                success: true,
+                message: format!("Active player: {}", profiles.current_player()),
+                data: Some(profiles.clone()),
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: None,
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::PlayersChanged { total } => Response {
+                success: true,
+                message: format!("Players changed ({} total)", total),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({ "total": total })),
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::CommandResult { response, .. } => response.clone(),
+            Event::Error(error) => Response {
+                response_type: Some(self.kind().to_string()),
+                ..error.to_response()
+            },
+            Event::Degraded {
+                task,
+                reason,
+                restart_in,
+            } => Response {
+                success: false,
+                message: format!(
+                    "Background task '{}' failed ({}), restarting in {}ms",
+                    task,
+                    reason,
+                    restart_in.as_millis()
+                ),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({
+                    "code": "TASK_FAILED",
+                    "task": task,
+                    "restart_in_ms": restart_in.as_millis() as u64,
+                })),
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::Identify { pad, duration } => Response {
+                success: true,
+                message: format!("Identifying pad '{}'", pad),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({
+                    "duration_ms": duration.as_millis() as u64,
+                })),
+                pad: Some(pad.to_string()),
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::Heartbeat(heartbeat) => Response {
+                success: true,
+                message: format!("Server up for {}s", heartbeat.uptime_secs),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: serde_json::to_value(&**heartbeat).ok(),
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::Recovered { task, stalled_for } => Response {
+                success: true,
+                message: format!(
+                    "Background task '{}' was stuck for {}ms and has been restarted",
+                    task,
+                    stalled_for.as_millis()
+                ),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({
+                    "code": "TASK_RECOVERED",
+                    "task": task,
+                    "stalled_ms": stalled_for.as_millis() as u64,
+                })),
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::StreamStateChanged { enabled } => Response {
+                success: true,
+                message: if *enabled {
+                    "Sensor stream started".to_string()
+                } else {
+                    "Sensor stream stopped".to_string()
+                },
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({ "enabled": enabled })),
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::ProfilesRepaired(repairs) => Response {
+                success: true,
+                message: format!(
+                    "Repaired {} dangling reference(s) in profiles.json",
+                    repairs.len()
+                ),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({
+                    "code": "PROFILES_REPAIRED",
+                    "repairs": repairs,
+                })),
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::DriftCompensation { pad, adjustment } => {
+                let mut payload = serde_json::to_value(adjustment).unwrap_or_default();
+                payload["code"] = "DRIFT_COMPENSATED".into();
+                Response {
+                    success: true,
+                    message: format!(
+                        "Threshold of sensor {} moved from {} to {} (offset {}, rest value {})",
+                        adjustment.sensor,
+                        adjustment.before,
+                        adjustment.after,
+                        adjustment.offset,
+                        adjustment.rest
+                    ),
+                    data: None,
+                    sensor_values: None,
+                    response_type: Some(self.kind().to_string()),
+                    payload: Some(payload),
+                    pad: Some(pad.to_string()),
+                    message_code: None,
+                    params: None,
+                    previous: None,
+                    seq: None,
+                    dry_run: false,
+                    unsaved_changes: false,
+                    pending_changes: None,
+                }
+            }
+            Event::PanelTestProgress { pad, result, next } => {
+                let mut payload = serde_json::to_value(result).unwrap_or_default();
+                payload["code"] = "PANEL_TEST_PROGRESS".into();
+                payload["next"] = serde_json::json!(next);
+                Response {
+                    success: true,
+                    message: if result.passed {
+                        format!(
+                            "Panel {} (sensor {}) passed, peak {}",
+                            result.panel,
+                            result.sensor,
+                            result.peak.unwrap_or_default()
+                        )
+                    } else {
+                        format!(
+                            "Panel {} (sensor {}) was not pressed past {} in time",
+                            result.panel, result.sensor, result.threshold
+                        )
+                    },
+                    data: None,
+                    sensor_values: None,
+                    response_type: Some(self.kind().to_string()),
+                    payload: Some(payload),
+                    pad: Some(pad.to_string()),
+                    message_code: None,
+                    params: None,
+                    previous: None,
+                    seq: None,
+                    dry_run: false,
+                    unsaved_changes: false,
+                    pending_changes: None,
+                }
+            }
+            Event::PanelTestComplete(summary) => {
+                let mut payload = serde_json::to_value(summary).unwrap_or_default();
+                payload["code"] = "PANEL_TEST_COMPLETE".into();
+                let passed = summary
+                    .results
+                    .iter()
+                    .filter(|result| result.passed)
+                    .count();
+                Response {
+                    success: true,
+                    message: match (summary.cancelled, summary.missed.is_empty()) {
+                        (true, _) => {
+                            format!("Panel test cancelled after {} panel(s) passed", passed)
+                        }
+                        (false, true) => "Panel test passed, every panel registered".to_string(),
+                        (false, false) => format!(
+                            "Panel test finished, sensors {:?} never fired",
+                            summary.missed
+                        ),
+                    },
+                    data: None,
+                    sensor_values: None,
+                    response_type: Some(self.kind().to_string()),
+                    payload: Some(payload),
+                    pad: Some(summary.plan.pad.clone()),
+                    message_code: None,
+                    params: None,
+                    previous: None,
+                    seq: None,
+                    dry_run: false,
+                    unsaved_changes: false,
+                    pending_changes: None,
+                }
+            }
+            Event::ScheduledSwitch { pad, schedule } => {
+                let mut payload = serde_json::to_value(&**schedule).unwrap_or_default();
+                payload["code"] = "SCHEDULED_SWITCH".into();
+                Response {
+                    success: true,
+                    message: format!(
+                        "Switched to profile '{}' as scheduled ({})",
+                        schedule.profile, schedule.cron_or_time
+                    ),
+                    data: None,
+                    sensor_values: None,
+                    response_type: Some(self.kind().to_string()),
+                    payload: Some(payload),
+                    pad: Some(pad.to_string()),
+                    message_code: None,
+                    params: None,
+                    previous: None,
+                    seq: None,
+                    dry_run: false,
+                    unsaved_changes: false,
+                    pending_changes: None,
+                }
+            }
+            Event::TemporaryProfileEnded {
+                pad,
+                profile,
+                restored,
+                reason,
+            } => Response {
+                success: true,
+                message: match restored {
+                    Some(restored) => format!(
+                        "Temporary profile '{}' ended ({}), pad '{}' is on profile '{}'",
+                        profile, reason, pad, restored
+                    ),
+                    None => format!("Temporary profile '{}' ended ({})", profile, reason),
+                },
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({
+                    "code": "TEMPORARY_PROFILE_ENDED",
+                    "profile": profile,
+                    "restored": restored,
+                    "reason": reason,
+                })),
+                pad: Some(pad.to_string()),
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::PlayerSignedOut {
+                pad,
+                reason,
+                summary,
+            } => Response {
+                success: true,
+                message: format!(
+                    "Player '{}' was signed out of pad '{}' ({}) after {} minute(s) and {} press(es)",
+                    summary.player,
+                    pad,
+                    reason,
+                    summary.duration_secs / 60,
+                    summary.presses
+                ),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({
+                    "code": "PLAYER_SIGNED_OUT",
+                    "reason": reason,
+                    "session": summary,
+                })),
+                pad: Some(pad.to_string()),
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::ProposalCreated(proposal) => Response {
+                success: true,
+                message: format!(
+                    "Proposal {}: threshold {} of profile '{}' from {} to {}",
+                    proposal.id, proposal.index, proposal.profile, proposal.current, proposal.value
+                ),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({
+                    "code": "PROPOSAL_CREATED",
+                    "proposal": proposal,
+                })),
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::SelfTest(token) => Response {
+                success: true,
+                message: format!("Self-test probe {}", token),
+                data: None,
+                sensor_values: None,
+                response_type: Some(self.kind().to_string()),
+                payload: Some(serde_json::json!({ "code": "SELF_TEST_PROBE", "token": token })),
+                pad: None,
+                message_code: None,
+                params: None,
+                previous: None,
+                seq: None,
+                dry_run: false,
+                unsaved_changes: false,
+                pending_changes: None,
+            },
+            Event::Mirrored { response, .. } => (**response).clone(),
+            Event::MirrorStatus(status) => {
+                let mut payload = serde_json::to_value(&**status).unwrap_or_default();
+                payload["code"] = if status.stale {
+                    "MIRROR_STALE"
+                } else {
+                    "MIRROR_CONNECTED"
+                }
+                .into();
+                Response {
+                    success: !status.stale,
+                    message: match (&status.last_error, status.stale) {
+                        (_, false) => format!("Mirroring {}", status.upstream_url),
+                        (Some(error), true) => format!(
+                            "Lost {} ({}), what is shown may be out of date",
+                            status.upstream_url, error
+                        ),
+                        (None, true) => format!(
+                            "Not connected to {} yet, what is shown may be out of date",
+                            status.upstream_url
+                        ),
+                    },
+                    data: None,
+                    sensor_values: None,
+                    response_type: Some(self.kind().to_string()),
+                    payload: Some(payload),
+                    pad: None,
+                    message_code: None,
+                    params: None,
+                    previous: None,
+                    seq: None,
+                    dry_run: false,
+                    unsaved_changes: false,
+                    pending_changes: None,
+                }
+            }
+        }
+    }
+}
+
+// An event type a client subscribes to, optionally only for one pad. Accepted as
+// "sensor_stream", "sensor_stream:pad1" or {"type": "sensor_stream", "pad": "pad1"}.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "TopicSpec")]
+pub struct Topic {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TopicSpec {
+    Short(String),
+    Full {
+        #[serde(rename = "type")]
+        kind: String,
+        #[serde(default)]
+        pad: Option<String>,
+    },
+}
+
+impl TryFrom<TopicSpec> for Topic {
+    type Error = String;
+
+    fn try_from(spec: TopicSpec) -> Result<Self, String> {
+        let (kind, pad) = match spec {
+            TopicSpec::Short(topic) => match topic.split_once(':') {
+                Some((kind, pad)) => (kind.to_string(), Some(pad.to_string())),
+                None => (topic, None),
+            },
+            TopicSpec::Full { kind, pad } => (kind, pad),
+        };
+        if !SUBSCRIBABLE_EVENTS.contains(&kind.as_str()) {
+            return Err(format!(
+                "unknown event type '{}', expected one of {}",
+                kind,
+                SUBSCRIBABLE_EVENTS.join(", ")
+            ));
+        }
+        Ok(Self { kind, pad })
+    }
+}
+
+impl Topic {
+    // The filter of subscriptions and notification sinks alike. Events that aren't about
+    // a pad match every topic of their type.
+    pub fn matches(&self, event: &Event) -> bool {
+        self.kind == event.kind()
+            && match (&self.pad, event.pad()) {
+                (Some(wanted), Some(pad)) => wanted == pad,
+                _ => true,
+            }
+    }
+}
+
+impl std::fmt::Display for Topic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.pad {
+            Some(pad) => write!(f, "{}:{}", self.kind, pad),
+            None => f.write_str(&self.kind),
+        }
+    }
+}
+
+// The events one connection receives. Until it subscribes that is everything, from
+// every pad, as before subscriptions existed; except the aggregate stream, which
+// duplicates the sensor frames and is only sent to clients that ask for it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Subscription {
+    topics: Option<Vec<Topic>>,
+    // How the stream frames show the sensor values
+    scale: Scale,
+}
+
+impl Subscription {
+    pub fn new(topics: Vec<Topic>, scale: Scale) -> Self {
+        Self {
+            topics: Some(topics),
+            scale,
+        }
+    }
+
+    pub fn topics(&self) -> Option<&[Topic]> {
+        self.topics.as_deref()
+    }
+
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    pub fn wants(&self, event: &Event) -> bool {
+        if matches!(event, Event::SelfTest(_)) {
+            return false;
+        }
+        let Some(topics) = &self.topics else {
+            return !matches!(event, Event::AggregateFrame(..));
+        };
+        if matches!(event, Event::CommandResult { .. }) {
+            return true;
+        }
+        topics.iter().any(|topic| topic.matches(event))
+    }
+
+    // Whether the connection receives events of type `kind` from at least one pad
+    pub fn wants_kind(&self, kind: &str) -> bool {
+        match &self.topics {
+            None => kind != "aggregate_stream",
+            Some(topics) => kind == "command_response" || topics.iter().any(|t| t.kind == kind),
+        }
+    }
+
+    // Reply to a Subscribe command, naming the websocket client it came from if any
+    pub fn to_response(&self, client: Option<u64>) -> Response {
+        let topics = self.topics().unwrap_or_default();
+        let names: Vec<String> = topics.iter().map(Topic::to_string).collect();
+        let mut subscribed = if names.is_empty() {
+            "command responses only".to_string()
+        } else {
+            names.join(", ")
+        };
+        if self.scale != Scale::Raw {
+            subscribed = format!("{} in the {} scale", subscribed, self.scale);
+        }
+        Response {
+            success: true,
+            message: match client {
+                Some(client) => format!("Client {} subscribed to {}", client, subscribed),
+                None => format!("Subscribed to {}", subscribed),
+            },
+            data: None,
+            sensor_values: None,
+            response_type: Some("command_response".to_string()),
+            payload: Some(serde_json::json!({
+                "client": client,
+                "topics": topics,
+                "scale": self.scale,
+            })),
+            pad: None,
+            message_code: Some("SUBSCRIBED".to_string()),
+            params: None,
+            previous: None,
+            seq: None,
+            dry_run: false,
+            unsaved_changes: false,
+            pending_changes: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::StorageError;
+    use crate::profile::Pad;
+
+    #[test]
+    fn test_event_wire_format() {
+        let frame = Event::SensorFrame {
+            pad: "default".into(),
+            values: [1, 2, 3, 4].into(),
+            calibrated: None,
+            rate_hz: 60,
+            wire: WireJson::default(),
+        }
+        .to_response();
+        assert_eq!(frame.response_type.as_deref(), Some("sensor_stream"));
+        assert_eq!(frame.sensor_values, Some([1, 2, 3, 4].into()));
+        assert_eq!(frame.pad.as_deref(), Some("default"));
+        assert_eq!(
+            frame.payload,
+            Some(serde_json::json!({ "rate_hz": 60, "scale": "raw" }))
+        );
+
+        let profiles = Arc::new(Profiles {
+            pads: vec![Pad::default_pad(String::new(), "Alice".to_string())],
+            ..Profiles::default()
+        });
+        let updated = Event::ProfilesUpdated(profiles.clone()).to_response();
+        assert_eq!(updated.message, "Active player: Alice");
+        assert!(Arc::ptr_eq(updated.data.as_ref().unwrap(), &profiles));
+
+        let error = Event::error(StorageError::Write(std::io::Error::other("disk full")).into())
+            .to_response();
+        assert!(!error.success);
+        assert_eq!(error.response_type.as_deref(), Some("error"));
+        assert_eq!(
+            error.payload,
+            Some(serde_json::json!({ "code": "SAVE_FAILED" }))
+        );
+    }
+
+    #[test]
+    fn test_topic_forms() {
+        let topics: Vec<Topic> = serde_json::from_value(serde_json::json!([
+            "sensor_stream:p1",
+            "identify",
+            { "type": "sensor_stream", "pad": "p2" },
+        ]))
+        .unwrap();
+        let names: Vec<String> = topics.iter().map(Topic::to_string).collect();
+        assert_eq!(names, ["sensor_stream:p1", "identify", "sensor_stream:p2"]);
+        assert_eq!(
+            serde_json::to_value(&topics[0]).unwrap(),
+            serde_json::json!({ "type": "sensor_stream", "pad": "p1" })
+        );
+        assert!(serde_json::from_value::<Topic>(serde_json::json!("frames")).is_err());
+    }
+
+    #[test]
+    fn test_subscription_filters_by_pad() {
+        let frame = |pad: &str| Event::SensorFrame {
+            pad: pad.into(),
+            values: [0; 4].into(),
+            calibrated: None,
+            rate_hz: 60,
+            wire: WireJson::default(),
+        };
+        let updated = Event::ProfilesUpdated(Arc::new(Profiles::default()));
+
+        let all = Subscription::default();
+        assert!(all.wants(&frame("p1")) && all.wants(&frame("p2")) && all.wants(&updated));
+
+        let p1 = Subscription::new(
+            vec![Topic {
+                kind: "sensor_stream".to_string(),
+                pad: Some("p1".to_string()),
+            }],
+            Scale::Raw,
+        );
+        assert!(p1.wants(&frame("p1")));
+        assert!(!p1.wants(&frame("p2")));
+        assert!(!p1.wants(&updated));
+        let result = Event::CommandResult {
+            connection: 1,
+            response: p1.to_response(Some(1)),
+        };
+        assert!(p1.wants(&result));
+    }
+}
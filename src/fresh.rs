@@ -0,0 +1,132 @@
+use crate::aggregate::STALE_PERIODS;
+use crate::error::{AppError, SerialOp};
+use crate::event::Event;
+use crate::journal::JournalKind;
+use crate::latest::SensorSnapshot;
+use crate::press::PressParameters;
+use crate::sensors::Sensors;
+use crate::serial::read_sensor_values;
+use crate::state::{period, AppState};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Shortest time between two fresh reads of one connection
+pub const FRESH_READ_INTERVAL: Duration = Duration::from_millis(50);
+
+// Record a successful reading of the device: it becomes the latest frame, in the pad's
+// logical sensor order, and a `press` event if it changed which sensors are pressed.
+// Returns it with the calibrated values for a stream frame.
+pub fn record_reading(
+    state: &AppState,
+    physical: Sensors<i32>,
+) -> (Arc<SensorSnapshot>, Option<Sensors<i32>>) {
+    state.range.observe(&physical);
+    let profiles = state.profiles.borrow();
+    let values = profiles.sensor_map().to_logical(physical);
+    let profile = profiles.profiles.get(profiles.current_profile());
+    let thresholds = profile.map(|profile| profile.thresholds);
+    let parameters = profile.map(PressParameters::of).unwrap_or_default();
+    let frame = state.latest.record(
+        profiles.device_pad_id().into(),
+        values,
+        thresholds,
+        parameters,
+    );
+    let calibrated = profile.and_then(|profile| profile.calibrate(values));
+    drop(profiles);
+    if frame.press.changed {
+        state.idle.record_press(frame.press.pressed);
+        state.publish(Event::Press(Arc::clone(&frame)));
+    }
+    (frame, calibrated)
+}
+
+// Read the sensors now instead of waiting for the next stream tick. The read queues like
+// a command, so stream reads give way to it, and it becomes the latest frame with the
+// next sequence number, so the stream and fresh reads share one numbering.
+pub async fn read_fresh(state: &AppState) -> Result<Arc<SensorSnapshot>, AppError> {
+    state.profiles_snapshot().enabled_pad(None)?;
+    let _queued = state.serial_queue.enter()?;
+    let read = read_sensor_values(&state.serial).await;
+    let physical = state
+        .metrics
+        .serial_read(read)
+        .map_err(AppError::serial(SerialOp::ReadSensorValues))?;
+    let errors = state.health.record_serial_ok();
+    if errors > 0 {
+        let message = format!("Sensor reads recovered after {} error(s)", errors);
+        state.journal.record(JournalKind::SerialRecovered, message);
+    }
+    Ok(record_reading(state, physical).0)
+}
+
+// The latest frame while the stream is running and keeping it current, else a fresh
+// read. Returns the frame and whether it came from the stream.
+pub async fn read_current(state: &AppState) -> Result<(Arc<SensorSnapshot>, bool), AppError> {
+    if state.stream_enabled() {
+        let stale_after = period(state.effective_rate_hz()) * STALE_PERIODS;
+        if let Some(frame) = state
+            .latest
+            .get()
+            .filter(|frame| frame.at.elapsed() <= stale_after)
+        {
+            return Ok((frame, true));
+        }
+    }
+    Ok((read_fresh(state).await?, false))
+}
+
+// Spaces out the fresh reads of one connection, so a client polling in a loop can't
+// crowd the stream off the serial port
+pub struct FreshReadLimiter {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl FreshReadLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    // Take the next fresh read, or fail with RATE_LIMITED until the interval has passed
+    pub fn check(&self) -> Result<(), AppError> {
+        let Ok(mut last) = self.last.lock() else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if let Some(wait) = last.and_then(|last| (last + self.interval).checked_duration_since(now))
+        {
+            return Err(AppError::RateLimited {
+                retry_after_ms: wait.as_millis() as u64 + 1,
+            });
+        }
+        *last = Some(now);
+        Ok(())
+    }
+}
+
+impl Default for FreshReadLimiter {
+    fn default() -> Self {
+        Self::new(FRESH_READ_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter_spaces_out_reads() {
+        let limiter = FreshReadLimiter::new(Duration::from_millis(30));
+        limiter.check().unwrap();
+        let Err(AppError::RateLimited { retry_after_ms }) = limiter.check() else {
+            panic!("second read wasn't limited");
+        };
+        assert!((1..=31).contains(&retry_after_ms));
+        std::thread::sleep(Duration::from_millis(retry_after_ms));
+        limiter.check().unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+use crate::capture::CaptureStatus;
+use crate::commands::ReadOnlyPolicy;
+use crate::latency::LatencyStats;
+use crate::metrics::StatsSummary;
+use crate::mirror::MirrorStatus;
+use crate::profile::LoadFailure;
+use crate::state::StreamConfig;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// Consecutive failed serial reads before the device counts as unhealthy
+pub const SERIAL_ERROR_LIMIT: u32 = 10;
+
+// How long the sensor stream task may go without ticking before it counts as stalled
+pub const STREAM_STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Liveness of the serial device and sensor stream task, shared by `/health` and the
+// service watchdog so both use the same criteria
+pub struct Health {
+    started_at: Instant,
+    serial_connected: bool,
+    consecutive_serial_errors: AtomicU32,
+    last_serial_error: Mutex<Option<String>>,
+    last_stream_tick: Mutex<Instant>,
+    device_out_of_sync: AtomicBool,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub status: String, // "ok" or "unhealthy"
+    pub problems: Vec<String>,
+    pub serial_connected: bool,
+    pub consecutive_serial_errors: u32,
+    pub last_serial_error: Option<String>,
+    pub stream_running: bool,
+    pub stream_stalled: bool,
+    // A threshold write failed halfway and couldn't be undone. The server works on, so
+    // it doesn't make it unhealthy.
+    pub device_out_of_sync: bool,
+    pub uptime_secs: u64,
+    pub read_only: ReadOnlyPolicy,
+    // Server counters, filled in by the `/health` handler
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<StatsSummary>,
+    // Latency of the stream's presses, filled in by the `/health` handler
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub press_latency: Option<LatencyStats>,
+    // Runtime serial capture, filled in by the `/health` handler
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_capture: Option<CaptureStatus>,
+    // profiles.json that failed to parse on startup, filled in by the `/health` handler.
+    // The server works on, so it doesn't make it unhealthy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profiles_load_failure: Option<LoadFailure>,
+    // Connection to the upstream of a --mirror server, see with_mirror
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<MirrorStatus>,
+}
+
+impl Health {
+    pub fn new(serial_connected: bool) -> Self {
+        let now = Instant::now();
+        Self {
+            started_at: now,
+            serial_connected,
+            consecutive_serial_errors: AtomicU32::new(0),
+            last_serial_error: Mutex::new(None),
+            last_stream_tick: Mutex::new(now),
+            device_out_of_sync: AtomicBool::new(false),
+        }
+    }
+
+    // Called by the sensor stream task on every tick while the stream is running
+    pub fn record_stream_tick(&self) {
+        if let Ok(mut last) = self.last_stream_tick.lock() {
+            *last = Instant::now();
+        }
+    }
+
+    // Time since the sensor stream task last started a tick
+    pub fn since_stream_tick(&self) -> Duration {
+        self.last_stream_tick
+            .lock()
+            .map(|last| last.elapsed())
+            .unwrap_or(Duration::MAX)
+    }
+
+    // Whether the device is attached and not in a streak of failed reads
+    pub fn device_connected(&self) -> bool {
+        self.serial_connected
+            && self.consecutive_serial_errors.load(Ordering::Relaxed) < SERIAL_ERROR_LIMIT
+    }
+
+    pub fn consecutive_serial_errors(&self) -> u32 {
+        self.consecutive_serial_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn last_serial_error(&self) -> Option<String> {
+        self.last_serial_error
+            .lock()
+            .ok()
+            .and_then(|last| last.clone())
+    }
+
+    // Returns how many errors in a row preceded this success
+    pub fn record_serial_ok(&self) -> u32 {
+        self.consecutive_serial_errors.swap(0, Ordering::Relaxed)
+    }
+
+    // Returns the length of the error streak, including this error
+    pub fn record_serial_error(&self, error: &str) -> u32 {
+        let streak = self
+            .consecutive_serial_errors
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if let Ok(mut last) = self.last_serial_error.lock() {
+            *last = Some(error.to_string());
+        }
+        streak
+    }
+
+    // Whether the device was left with part of a profile's thresholds, until they are
+    // written in full again
+    pub fn device_out_of_sync(&self) -> bool {
+        self.device_out_of_sync.load(Ordering::Relaxed)
+    }
+
+    pub fn set_device_out_of_sync(&self, out_of_sync: bool) {
+        self.device_out_of_sync
+            .store(out_of_sync, Ordering::Relaxed);
+    }
+
+    // Report for the running server, as served on `/health`
+    pub fn current_report(
+        &self,
+        stream: &watch::Sender<StreamConfig>,
+        read_only: ReadOnlyPolicy,
+    ) -> HealthReport {
+        let stream_running = stream.borrow().enabled;
+        self.report(stream_running, read_only)
+    }
+
+    pub fn report(&self, stream_running: bool, read_only: ReadOnlyPolicy) -> HealthReport {
+        let serial_connected = self.serial_connected;
+        let consecutive_serial_errors = self.consecutive_serial_errors.load(Ordering::Relaxed);
+        // A stopped stream task sleeps until started, so it can only stall while running
+        let stream_stalled = stream_running
+            && self
+                .last_stream_tick
+                .lock()
+                .map(|last| last.elapsed() > STREAM_STALL_TIMEOUT)
+                .unwrap_or(true);
+
+        let mut problems = Vec::new();
+        if !serial_connected {
+            problems.push("serial device not connected".to_string());
+        }
+        if consecutive_serial_errors >= SERIAL_ERROR_LIMIT {
+            problems.push(format!(
+                "{} consecutive serial errors",
+                consecutive_serial_errors
+            ));
+        }
+        if stream_stalled {
+            problems.push("sensor stream task stalled".to_string());
+        }
+        let healthy = problems.is_empty();
+
+        HealthReport {
+            healthy,
+            status: if healthy { "ok" } else { "unhealthy" }.to_string(),
+            problems,
+            serial_connected,
+            consecutive_serial_errors,
+            last_serial_error: self.last_serial_error.lock().ok().and_then(|e| e.clone()),
+            stream_running,
+            stream_stalled,
+            device_out_of_sync: self.device_out_of_sync(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            read_only,
+            stats: None,
+            press_latency: None,
+            serial_capture: None,
+            profiles_load_failure: None,
+            mirror: None,
+        }
+    }
+}
+
+impl HealthReport {
+    // A mirror that lost its upstream serves what it last received, so it is unhealthy
+    // until it reconnects
+    pub fn with_mirror(mut self, mirror: MirrorStatus) -> Self {
+        if mirror.stale {
+            self.problems
+                .push(format!("upstream {} unreachable", mirror.upstream_url));
+            self.healthy = false;
+            self.status = "unhealthy".to_string();
+        }
+        self.mirror = Some(mirror);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_report() {
+        let health = Health::new(true);
+        let report = health.report(false, ReadOnlyPolicy::default());
+        assert!(report.healthy);
+        assert_eq!(report.status, "ok");
+
+        for _ in 0..SERIAL_ERROR_LIMIT {
+            health.record_serial_error("timed out");
+        }
+        let report = health.report(true, ReadOnlyPolicy::default());
+        assert!(!report.healthy);
+        assert_eq!(report.last_serial_error.as_deref(), Some("timed out"));
+        assert_eq!(report.problems.len(), 1);
+
+        health.record_serial_ok();
+        assert!(health.report(true, ReadOnlyPolicy::default()).healthy);
+
+        let report = Health::new(false).report(false, ReadOnlyPolicy::default());
+        assert!(!report.healthy);
+        assert!(!report.serial_connected);
+    }
+
+    #[test]
+    fn test_stalled_stream_is_unhealthy() {
+        let health = Health::new(true);
+        *health.last_stream_tick.lock().unwrap() =
+            Instant::now() - STREAM_STALL_TIMEOUT - Duration::from_millis(10);
+        let report = health.report(true, ReadOnlyPolicy::default());
+        assert!(report.stream_stalled);
+        assert!(!report.healthy);
+
+        health.record_stream_tick();
+        assert!(
+            !health
+                .report(true, ReadOnlyPolicy::default())
+                .stream_stalled
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use crate::event::Event;
+use crate::idle::IdleStatus;
+use crate::mirror::MirrorStatus;
+use crate::press::PressParameters;
+use crate::range::ThresholdBound;
+use crate::startup_sync::StartupSyncReport;
+use crate::state::AppState;
+use crate::temporary::{self, TemporaryStatus};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::{interval, MissedTickBehavior};
+
+// Status broadcast every `--heartbeat-interval` seconds, so dashboards don't have to poll
+// `/health`, GetServerStats and GetClients separately
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Heartbeat {
+    pub uptime_secs: u64,
+    pub device: DeviceStatus,
+    pub stream: StreamStatus,
+    pub clients: usize,
+    pub player: String,
+    pub profile: String,
+    // Set while the profile is a temporary one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temporary_profile: Option<TemporaryStatus>,
+    // Countdown of --idle-sign-out while a player is signed in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_sign_out: Option<IdleStatus>,
+    // What the startup synchronization did, on the first heartbeat only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup_sync: Option<StartupSyncReport>,
+    // Connection to the upstream of a --mirror server, `stale` while it is lost
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<MirrorStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct DeviceStatus {
+    pub connected: bool,
+    // Time since the last successful sensor read, null if there was none yet
+    pub last_read_ms: Option<u64>,
+    // Largest threshold accepted, and where that bound comes from
+    pub threshold_max: ThresholdBound,
+    // A threshold write failed halfway and left the device unlike the profile
+    pub out_of_sync: bool,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct StreamStatus {
+    pub enabled: bool,
+    pub rate_hz: u32,
+    // "fixed", or "adaptive" when an idle pad is polled at a lower rate
+    pub mode: &'static str,
+    // Rate the stream polls at right now; below rate_hz while an adaptive stream idles
+    pub effective_hz: u32,
+    // Frames per second actually broadcast since the previous heartbeat
+    pub achieved_hz: f64,
+}
+
+// Payload of GetStreamStatus: the heartbeat's stream status and what a client toggling
+// the stream wants to know besides
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct StreamReport {
+    #[serde(flatten)]
+    pub stream: StreamStatus,
+    // Clients receiving the sensor frames
+    pub subscribers: usize,
+    pub frames_emitted: u64,
+    pub consecutive_errors: u32,
+    // Most recent failed sensor read, kept after the reads recover
+    pub last_error: Option<String>,
+    // Press detection of the device pad's current profile, None without one
+    pub press: Option<PressParameters>,
+}
+
+// Shortest period GetStreamStatus measures the achieved rate over; queries in between
+// get the previous measurement
+pub const STATUS_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// Frame counter at the previous heartbeat, to measure the achieved stream rate
+pub struct RateMeter {
+    frames: u64,
+    at: Instant,
+    last_rate: f64,
+}
+
+impl RateMeter {
+    pub fn new(frames: u64) -> Self {
+        Self {
+            frames,
+            at: Instant::now(),
+            last_rate: 0.0,
+        }
+    }
+
+    // The rate since the previous measurement if that was at least `window` ago, else
+    // the previous measurement
+    pub fn sample(&mut self, frames: u64, window: Duration) -> f64 {
+        if self.at.elapsed() >= window {
+            let rate = self.rate(frames);
+            self.last_rate = rate;
+        }
+        self.last_rate
+    }
+
+    // Frames per second since the previous call
+    pub fn rate(&mut self, frames: u64) -> f64 {
+        let elapsed = self.at.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            frames.saturating_sub(self.frames) as f64 / elapsed
+        } else {
+            0.0
+        };
+        *self = Self::new(frames);
+        // Two decimals are plenty for a status display
+        (rate * 100.0).round() / 100.0
+    }
+}
+
+fn status(state: &AppState, achieved_hz: f64) -> StreamStatus {
+    let stream = *state.stream.borrow();
+    StreamStatus {
+        enabled: stream.enabled,
+        rate_hz: stream.rate_hz,
+        mode: stream.mode(),
+        effective_hz: state.effective_rate_hz(),
+        achieved_hz,
+    }
+}
+
+pub fn stream_status(state: &AppState) -> StreamReport {
+    let frames = state.metrics.frames_broadcast();
+    let achieved_hz = state
+        .stream_meter
+        .lock()
+        .map(|mut meter| meter.sample(frames, STATUS_RATE_WINDOW))
+        .unwrap_or_default();
+    let profiles = state.profiles.borrow().clone();
+    StreamReport {
+        stream: status(state, achieved_hz),
+        subscribers: state.clients.subscribers("sensor_stream"),
+        frames_emitted: frames,
+        consecutive_errors: state.health.consecutive_serial_errors(),
+        last_error: state.health.last_serial_error(),
+        press: profiles
+            .profiles
+            .get(profiles.current_profile())
+            .map(PressParameters::of),
+    }
+}
+
+// Built from counters and the current snapshot; never waits for the mutation lock
+pub fn heartbeat(state: &AppState, meter: &mut RateMeter) -> Heartbeat {
+    let profiles = state.profiles.borrow().clone();
+    Heartbeat {
+        uptime_secs: state.metrics.uptime_secs(),
+        device: DeviceStatus {
+            connected: state.health.device_connected(),
+            last_read_ms: state.latest.since_read().map(|age| age.as_millis() as u64),
+            threshold_max: state.range.bound(),
+            out_of_sync: state.health.device_out_of_sync(),
+        },
+        stream: status(state, meter.rate(state.metrics.frames_broadcast())),
+        clients: state.clients.count(),
+        player: profiles.current_player().to_string(),
+        profile: profiles.current_profile().to_string(),
+        temporary_profile: temporary::status(&profiles),
+        idle_sign_out: state.idle.status(),
+        startup_sync: None,
+        mirror: state.mirror.as_ref().map(|mirror| mirror.status()),
+    }
+}
+
+pub async fn heartbeat_task(state: AppState, period: Duration) {
+    let mut timer = interval(period);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut meter = RateMeter::new(state.metrics.frames_broadcast());
+    // The first tick completes immediately; skip it so the first rate covers a period
+    timer.tick().await;
+    let mut startup_sync = state.info.startup_sync.clone();
+    loop {
+        timer.tick().await;
+        let beat = Heartbeat {
+            startup_sync: startup_sync.take(),
+            ..heartbeat(&state, &mut meter)
+        };
+        state.publish(Event::Heartbeat(Arc::new(beat)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::profile::{Pad, Profiles};
+    use crate::serial::MockSerialPort;
+
+    #[tokio::test]
+    async fn test_heartbeat_status() {
+        let profiles = Profiles {
+            pads: vec![Pad::default_pad("Casual".to_string(), "Alice".to_string())],
+            ..Profiles::default()
+        };
+        let state = AppState::with_port(profiles, Box::new(MockSerialPort::new([0; 4])));
+        let _client = state.clients.register(Role::Admin);
+        let mut meter = RateMeter::new(0);
+        for _ in 0..10 {
+            state.metrics.record_frame();
+        }
+        // Held by a command in progress; the heartbeat doesn't wait for it
+        let _mutating = state.mutations.lock().await;
+
+        let beat = heartbeat(&state, &mut meter);
+        assert_eq!(beat.player, "Alice");
+        assert_eq!(beat.profile, "Casual");
+        assert_eq!(beat.clients, 1);
+        assert!(beat.device.connected);
+        assert_eq!(beat.device.last_read_ms, None);
+        assert!(!beat.stream.enabled);
+        assert!(beat.stream.achieved_hz > 0.0);
+
+        let response = Event::Heartbeat(Arc::new(beat)).to_response();
+        assert_eq!(response.response_type.as_deref(), Some("heartbeat"));
+        assert_eq!(response.payload.unwrap()["player"], "Alice");
+        // Periodic, like the sensor frames, so it stays out of the history
+        assert_eq!(state.history.latest(), 0);
+        state.publish(Event::Heartbeat(Arc::new(heartbeat(&state, &mut meter))));
+        assert_eq!(state.history.latest(), 0);
+    }
+}
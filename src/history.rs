@@ -0,0 +1,175 @@
+use crate::event::Event;
+use crate::profile::Response;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+
+// Events kept for clients catching up, over the websocket or by polling `/api/events`
+pub const EVENT_HISTORY_CAPACITY: usize = 256;
+
+// How long `/api/events` waits for a new event unless told otherwise
+pub const DEFAULT_POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+pub const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Every event except the sensor frames, numbered in the order they were broadcast. The
+// numbers are shared by all transports, so a client can switch from the websocket to
+// polling and continue where it left off.
+pub struct EventHistory {
+    events: Mutex<VecDeque<(u64, Event)>>,
+    // Sequence number of the newest event, 0 before the first
+    latest: watch::Sender<u64>,
+}
+
+// Answer to a poll: the events after the requested sequence number
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct EventsPage {
+    // Sequence number to poll from next
+    pub seq: u64,
+    pub events: Vec<Response>,
+    // Events after the requested one dropped out of the history before they were read
+    pub missed: bool,
+}
+
+impl Default for EventHistory {
+    fn default() -> Self {
+        Self {
+            events: Mutex::new(VecDeque::with_capacity(EVENT_HISTORY_CAPACITY)),
+            latest: watch::Sender::new(0),
+        }
+    }
+}
+
+impl EventHistory {
+    // The 60Hz streams and the presses would push everything else out of the history, a
+    // missed heartbeat is superseded by the next one, and self-test probes are for the
+    // self-test alone. A mirror's relayed presses count as presses.
+    pub fn records(event: &Event) -> bool {
+        !matches!(
+            event,
+            Event::SensorFrame { .. }
+                | Event::Press(_)
+                | Event::AggregateFrame(..)
+                | Event::Heartbeat(_)
+                | Event::SelfTest(_)
+                | Event::Mirrored { kind: "press", .. }
+        )
+    }
+
+    // Number and broadcast an event. The history stays locked during the broadcast so
+    // events reach the channel in the order of their sequence numbers.
+    pub fn publish(&self, event: Event, channel: &broadcast::Sender<Event>) {
+        if !Self::records(&event) {
+            let _ = channel.send(event);
+            return;
+        }
+        let Ok(mut events) = self.events.lock() else {
+            let _ = channel.send(event);
+            return;
+        };
+        let seq = *self.latest.borrow() + 1;
+        if events.len() == EVENT_HISTORY_CAPACITY {
+            events.pop_front();
+        }
+        events.push_back((seq, event.clone()));
+        self.latest.send_replace(seq);
+        let _ = channel.send(event);
+    }
+
+    pub fn latest(&self) -> u64 {
+        *self.latest.borrow()
+    }
+
+    // Events after `seq` still in the history, and whether any before them were dropped
+    pub fn since(&self, seq: u64) -> (Vec<(u64, Event)>, bool) {
+        let Ok(events) = self.events.lock() else {
+            return (Vec::new(), false);
+        };
+        let missed = events.front().is_some_and(|(oldest, _)| *oldest > seq + 1);
+        let newer = events
+            .iter()
+            .filter(|(number, _)| *number > seq)
+            .cloned()
+            .collect();
+        (newer, missed)
+    }
+
+    // Events after `seq`, waiting up to `timeout` for the next one if there are none yet.
+    // A number from before a server restart starts over from the beginning.
+    pub async fn poll(&self, seq: u64, timeout: Duration) -> EventsPage {
+        let restarted = seq > self.latest();
+        let seq = if restarted { 0 } else { seq };
+        let mut latest = self.latest.subscribe();
+        let _ = tokio::time::timeout(timeout, latest.wait_for(|latest| *latest > seq)).await;
+        let (events, missed) = self.since(seq);
+        EventsPage {
+            seq: events.last().map_or(seq, |(number, _)| *number),
+            events: events
+                .into_iter()
+                .map(|(number, event)| numbered(number, &event))
+                .collect(),
+            missed: missed || restarted,
+        }
+    }
+}
+
+// An event as sent to clients, tagged with its sequence number
+pub fn numbered(seq: u64, event: &Event) -> Response {
+    Response {
+        seq: Some(seq),
+        ..event.to_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::WireJson;
+    use crate::profile::Profiles;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_poll_events() {
+        let history = EventHistory::default();
+        let (channel, mut rx) = broadcast::channel(16);
+        let profiles = || Event::ProfilesUpdated(Arc::new(Profiles::default()));
+
+        history.publish(profiles(), &channel);
+        history.publish(
+            Event::SensorFrame {
+                pad: "default".into(),
+                values: [0; 4].into(),
+                calibrated: None,
+                rate_hz: 60,
+                wire: WireJson::default(),
+            },
+            &channel,
+        );
+        assert_eq!(history.latest(), 1);
+        assert!(rx.try_recv().is_ok() && rx.try_recv().is_ok());
+
+        let page = history.poll(0, Duration::ZERO).await;
+        assert_eq!(page.seq, 1);
+        assert_eq!(page.events.len(), 1);
+        assert_eq!(page.events[0].seq, Some(1));
+        assert!(!page.missed);
+
+        // Nothing new: the poll waits out its timeout
+        let page = history.poll(1, Duration::from_millis(20)).await;
+        assert_eq!((page.seq, page.events.len()), (1, 0));
+
+        for _ in 0..EVENT_HISTORY_CAPACITY {
+            history.publish(profiles(), &channel);
+        }
+        let page = history.poll(0, Duration::ZERO).await;
+        assert!(page.missed);
+        assert_eq!(page.events.len(), EVENT_HISTORY_CAPACITY);
+        assert_eq!(page.seq, EVENT_HISTORY_CAPACITY as u64 + 1);
+
+        let page = history.poll(1000, Duration::ZERO).await;
+        assert!(page.missed);
+        assert_eq!(page.events.len(), EVENT_HISTORY_CAPACITY);
+    }
+}
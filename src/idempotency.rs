@@ -0,0 +1,226 @@
+use crate::profile::Response;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+pub const DEFAULT_IDEMPOTENCY_WINDOW: u64 = 60;
+
+// Most keys remembered; the least recently used one makes room for a new one
+pub const IDEMPOTENCY_CAPACITY: usize = 1024;
+
+// Longest idempotency key accepted
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+
+// Responses of recent mutating commands by the idempotency key they were sent with, so a
+// client retrying after a timeout gets the original answer instead of running it twice.
+// Kept in memory only; a restarted server has forgotten every key.
+pub struct IdempotencyCache {
+    window: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    state: EntryState,
+    // When the command finished; running commands don't expire
+    finished: Option<Instant>,
+    used: Instant,
+}
+
+enum EntryState {
+    // Still executing; duplicates wait for its response
+    Running(watch::Receiver<Option<Response>>),
+    Done(Box<Response>),
+}
+
+pub enum Claim<'a> {
+    // First time the key is seen: execute the command and hand the response to finish()
+    Run(Pending<'a>),
+    // A command with the key already succeeded
+    Replay(Box<Response>),
+    // A command with the key is executing; None if it was cancelled before answering
+    Wait(watch::Receiver<Option<Response>>),
+}
+
+// A command executing under a key. Dropped without finish(), e.g. cancelled with its
+// connection, it forgets the key so a retry executes again.
+pub struct Pending<'a> {
+    cache: &'a IdempotencyCache,
+    key: String,
+    response: watch::Sender<Option<Response>>,
+    finished: bool,
+}
+
+impl IdempotencyCache {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn claim(&self, key: &str) -> Claim<'_> {
+        let (response, receiver) = watch::channel(None);
+        let pending = |finished| Pending {
+            cache: self,
+            key: key.to_string(),
+            response,
+            finished,
+        };
+        let Ok(mut entries) = self.entries.lock() else {
+            // Nothing to remove when it is dropped
+            return Claim::Run(pending(true));
+        };
+        let now = Instant::now();
+        entries.retain(|_, entry| {
+            entry
+                .finished
+                .is_none_or(|finished| now < finished + self.window)
+        });
+        if let Some(entry) = entries.get_mut(key) {
+            entry.used = now;
+            return match &entry.state {
+                EntryState::Running(receiver) => Claim::Wait(receiver.clone()),
+                EntryState::Done(response) => Claim::Replay(response.clone()),
+            };
+        }
+        self.make_room(&mut entries);
+        entries.insert(
+            key.to_string(),
+            Entry {
+                state: EntryState::Running(receiver),
+                finished: None,
+                used: now,
+            },
+        );
+        Claim::Run(pending(false))
+    }
+
+    fn make_room(&self, entries: &mut HashMap<String, Entry>) {
+        while entries.len() >= self.capacity.max(1) {
+            let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone())
+            else {
+                return;
+            };
+            entries.remove(&oldest);
+        }
+    }
+}
+
+impl Pending<'_> {
+    // Answer the duplicates waiting for this command. Only a success is replayed later; a
+    // failed command changed nothing, so executing its retry again is safe.
+    pub fn finish(mut self, response: &Response) {
+        self.finished = true;
+        self.response.send_replace(Some(response.clone()));
+        let Ok(mut entries) = self.cache.entries.lock() else {
+            return;
+        };
+        if !response.success {
+            entries.remove(&self.key);
+            return;
+        }
+        let now = Instant::now();
+        if !entries.contains_key(&self.key) {
+            // Evicted while it executed
+            self.cache.make_room(&mut entries);
+        }
+        entries.insert(
+            self.key.clone(),
+            Entry {
+                state: EntryState::Done(Box::new(response.clone())),
+                finished: Some(now),
+                used: now,
+            },
+        );
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Ok(mut entries) = self.cache.entries.lock() {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new(
+            Duration::from_secs(DEFAULT_IDEMPOTENCY_WINDOW),
+            IDEMPOTENCY_CAPACITY,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(message: &str, success: bool) -> Response {
+        Response {
+            success,
+            message: message.to_string(),
+            data: None,
+            sensor_values: None,
+            response_type: Some("command_response".to_string()),
+            payload: None,
+            pad: None,
+            message_code: None,
+            params: None,
+            previous: None,
+            seq: None,
+            dry_run: false,
+            unsaved_changes: false,
+            pending_changes: None,
+        }
+    }
+
+    fn run(cache: &IdempotencyCache, key: &str, answer: Response) {
+        let Claim::Run(pending) = cache.claim(key) else {
+            panic!("{} was already claimed", key);
+        };
+        pending.finish(&answer);
+    }
+
+    #[test]
+    fn test_replay_eviction_and_expiry() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 2);
+        run(&cache, "a", response("added", true));
+        assert!(matches!(cache.claim("a"), Claim::Replay(r) if r.message == "added"));
+
+        // Failures are forgotten, the retry executes again
+        run(&cache, "failed", response("timeout", false));
+        run(&cache, "failed", response("added", true));
+
+        // "a" was used more recently than "failed", so "failed" makes room for "b"
+        assert!(matches!(cache.claim("a"), Claim::Replay(_)));
+        run(&cache, "b", response("b", true));
+        assert!(matches!(cache.claim("a"), Claim::Replay(_)));
+        assert!(matches!(cache.claim("failed"), Claim::Run(_)));
+
+        // A cancelled command leaves nothing behind
+        let Claim::Run(pending) = cache.claim("cancelled") else {
+            panic!("cancelled was already claimed");
+        };
+        let Claim::Wait(waiting) = cache.claim("cancelled") else {
+            panic!("cancelled isn't running");
+        };
+        drop(pending);
+        assert!(waiting.has_changed().is_err());
+        assert!(matches!(cache.claim("cancelled"), Claim::Run(_)));
+
+        // Past the window a key executes again
+        let cache = IdempotencyCache::new(Duration::ZERO, 2);
+        run(&cache, "a", response("added", true));
+        assert!(matches!(cache.claim("a"), Claim::Run(_)));
+    }
+}
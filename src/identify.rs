@@ -0,0 +1,221 @@
+use crate::error::{AppError, SerialOp};
+use crate::serial::{read_thresholds_locked, set_threshold_locked};
+use crate::state::AppState;
+use crate::threshold_log::{ThresholdWriteLog, WriteReason};
+use serialport::SerialPort;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OwnedMutexGuard;
+
+// Logical sensor whose threshold is dropped to identify a pad
+pub const IDENTIFY_SENSOR: usize = 0;
+
+// Threshold the sensor drops to, so the arrow reads as held and lights up on the pad
+pub const IDENTIFY_THRESHOLD: i32 = 0;
+
+pub const IDENTIFY_DURATION: Duration = Duration::from_millis(500);
+
+// A pad pressed more recently than this is considered mid-session
+pub const SESSION_IDLE: Duration = Duration::from_secs(10);
+
+// Holds the device for the whole wiggle and puts the prior threshold back if the wiggle
+// ends without restoring it, e.g. on a panic. That last-resort write doesn't wait for the
+// reply, which the next sensor read then discards as one bad frame; it is logged as
+// successful once sent.
+struct RestoreGuard {
+    port: OwnedMutexGuard<Box<dyn SerialPort>>,
+    writes: Arc<ThresholdWriteLog>,
+    sensor: usize,
+    // Of the device, once its thresholds were read
+    sensors: usize,
+    prior: Option<i32>,
+}
+
+impl Drop for RestoreGuard {
+    fn drop(&mut self) {
+        if let Some(prior) = self.prior {
+            let line = format!("{} {}\n", self.sensor, prior);
+            let sent = self.port.write_all(line.as_bytes());
+            let logged = self.writes.reason(WriteReason::Identify);
+            logged.one(self.sensor, self.sensors, None, prior, &sent);
+        }
+    }
+}
+
+// Drop the threshold of device sensor `sensor` for IDENTIFY_DURATION and restore the exact
+// value the device had before. Returns that value. The wiggle runs in its own task, so a
+// caller that goes away midway doesn't leave the device with the lowered threshold.
+pub async fn wiggle(state: &AppState, sensor: usize) -> Result<i32, AppError> {
+    let queue = state.serial_queue.clone();
+    let serial = state.serial.clone();
+    let metrics = state.metrics.clone();
+    let writes = state.threshold_writes.clone();
+    let task = tokio::spawn(async move {
+        let _queued = queue.enter()?;
+        let port = serial.lock_owned().await;
+        let mut guard = RestoreGuard {
+            port,
+            writes: writes.clone(),
+            sensor,
+            sensors: 0,
+            prior: None,
+        };
+        let logged = writes.reason(WriteReason::Identify);
+        let device = metrics
+            .serial_read(read_thresholds_locked(&mut **guard.port).await)
+            .map_err(AppError::serial(SerialOp::Identify))?;
+        let prior = device[sensor];
+        guard.sensors = device.len();
+        guard.prior = Some(prior);
+
+        let dropped = set_threshold_locked(
+            &mut **guard.port,
+            sensor,
+            Some(prior),
+            IDENTIFY_THRESHOLD,
+            logged,
+        )
+        .await;
+        let dropped = metrics.serial_write(1, dropped);
+        if dropped.is_ok() {
+            tokio::time::sleep(IDENTIFY_DURATION).await;
+        }
+        // Restore even if lowering failed, the device may have taken the value anyway
+        let restored = set_threshold_locked(
+            &mut **guard.port,
+            sensor,
+            Some(IDENTIFY_THRESHOLD),
+            prior,
+            logged,
+        )
+        .await;
+        let restored = metrics.serial_write(1, restored);
+        if restored.is_ok() {
+            guard.prior = None;
+        }
+        dropped
+            .and(restored)
+            .map_err(AppError::serial(SerialOp::Identify))?;
+        Ok(prior)
+    });
+    match task.await {
+        Ok(result) => result,
+        // The task is never aborted, so this is a panic
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::handle_command;
+    use crate::event::Event;
+    use crate::profile::{Command, Pad, Profile, Profiles};
+    use crate::serial::get_current_thresholds_from_device;
+    use std::collections::HashMap;
+
+    fn test_profiles() -> Profiles {
+        let mut pads = vec![Pad::default_pad("Profile1".to_string(), String::new())];
+        pads.push(Pad {
+            id: "p2".to_string(),
+            ..Pad::default_pad("Profile1".to_string(), String::new())
+        });
+        Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([100, 200, 300, 400]))]),
+            pads,
+            ..Profiles::default()
+        }
+    }
+
+    fn identify(force: bool) -> Command {
+        Command::IdentifyPad { pad: None, force }
+    }
+
+    #[tokio::test]
+    async fn test_identify_restores_threshold() {
+        let state = AppState::with_mock_port(test_profiles());
+        let mut events = state.events.subscribe();
+
+        let response = handle_command(identify(false), &state).await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(response.pad.as_deref(), Some("default"));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            Event::Identify { ref pad, .. } if &**pad == "default"
+        ));
+        assert_eq!(
+            get_current_thresholds_from_device(&state.serial)
+                .await
+                .unwrap(),
+            [100, 200, 300, 400]
+        );
+        assert_eq!(state.metrics.snapshot().serial_writes, 2);
+    }
+
+    #[tokio::test]
+    async fn test_identify_restores_after_caller_is_dropped() {
+        let state = AppState::with_mock_port(test_profiles());
+
+        let interrupted = tokio::time::timeout(
+            Duration::from_millis(100),
+            handle_command(identify(false), &state),
+        )
+        .await;
+        assert!(interrupted.is_err());
+
+        // The device is held until the wiggle is over, then has its old value back
+        assert_eq!(
+            get_current_thresholds_from_device(&state.serial)
+                .await
+                .unwrap(),
+            [100, 200, 300, 400]
+        );
+        assert_eq!(state.metrics.snapshot().serial_writes, 2);
+    }
+
+    #[tokio::test]
+    async fn test_identify_refuses_mid_session_unless_forced() {
+        let state = AppState::with_mock_port(test_profiles());
+        state.latest.record(
+            "default".into(),
+            [1000; 4].into(),
+            Some([100; 4].into()),
+            Default::default(),
+        );
+
+        let response = handle_command(identify(false), &state).await;
+        assert!(!response.success);
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "PAD_IN_SESSION" }))
+        );
+        assert_eq!(state.metrics.snapshot().serial_writes, 0);
+
+        let response = handle_command(identify(true), &state).await;
+        assert!(response.success, "{}", response.message);
+    }
+
+    #[tokio::test]
+    async fn test_identify_pad_without_device_only_broadcasts() {
+        let state = AppState::with_mock_port(test_profiles());
+        state.latest.record(
+            "default".into(),
+            [1000; 4].into(),
+            Some([100; 4].into()),
+            Default::default(),
+        );
+        let mut events = state.events.subscribe();
+
+        let command = Command::IdentifyPad {
+            pad: Some("p2".to_string()),
+            force: false,
+        };
+        let response = handle_command(command, &state).await;
+        assert!(response.success, "{}", response.message);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            Event::Identify { ref pad, .. } if &**pad == "p2"
+        ));
+        assert_eq!(state.metrics.snapshot().serial_writes, 0);
+    }
+}
@@ -0,0 +1,330 @@
+use crate::audit::Origin;
+use crate::commands::{handle_envelope, Envelope};
+use crate::event::Event;
+use crate::profile::{Command, Profiles};
+use crate::sensors::Sensors;
+use crate::state::AppState;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Wait before trying a failed sign-out again
+const SIGN_OUT_RETRY: Duration = Duration::from_secs(5);
+
+// A player's time on the device pad, from signing in until signing out
+#[derive(Debug, Clone)]
+struct Session {
+    player: String,
+    started_at: DateTime<Utc>,
+    started: Instant,
+    // The countdown runs from here: the sign-in, or the last press since
+    active: Instant,
+    // Sensors stepped on, counted when one goes from released to pressed
+    presses: u64,
+    pressed: Sensors<bool>,
+}
+
+// A session as reported by its `player_signed_out` event
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SessionSummary {
+    pub player: String,
+    pub profile: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub duration_secs: u64,
+    pub presses: u64,
+}
+
+// The countdown as the heartbeat shows it, so UIs can warn before it runs out
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct IdleStatus {
+    pub player: String,
+    pub timeout_secs: u64,
+    pub remaining_secs: u64,
+}
+
+// Signs the device pad's player out after `--idle-sign-out` minutes without a press.
+// Without a timeout nothing is tracked.
+#[derive(Debug, Default)]
+pub struct IdleSignOut {
+    timeout: Option<Duration>,
+    session: Mutex<Option<Session>>,
+}
+
+impl IdleSignOut {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            session: Mutex::default(),
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn update<T>(&self, change: impl FnOnce(&mut Option<Session>) -> T) -> T {
+        change(&mut self.session.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    // Follow the device pad's player: another one starts a session, none ends it. Set
+    // `restart` for a ChangePlayer or ClearCurrentPlayer, which start the countdown over
+    // even for the player already signed in.
+    pub fn follow(&self, profiles: &Profiles, restart: bool) {
+        if self.timeout.is_none() {
+            return;
+        }
+        let player = profiles.current_player();
+        self.update(|session| {
+            if player.is_empty() {
+                *session = None;
+                return;
+            }
+            if !restart
+                && session
+                    .as_ref()
+                    .is_some_and(|session| session.player == player)
+            {
+                return;
+            }
+            let now = Instant::now();
+            *session = Some(Session {
+                player: player.to_string(),
+                started_at: Utc::now(),
+                started: now,
+                active: now,
+                presses: 0,
+                pressed: Sensors::default(),
+            });
+        });
+    }
+
+    // A reading of the device pad changed which sensors are pressed
+    pub fn record_press(&self, pressed: Sensors<bool>) {
+        if self.timeout.is_none() {
+            return;
+        }
+        self.update(|session| {
+            let Some(session) = session.as_mut() else {
+                return;
+            };
+            let stepped = (0..pressed.len())
+                .filter(|&i| pressed[i] && !session.pressed.get(i).copied().unwrap_or(false))
+                .count();
+            session.presses += stepped as u64;
+            session.pressed = pressed;
+            session.active = Instant::now();
+        });
+    }
+
+    // The signed in player and the time left until they are signed out, zero once due
+    fn remaining(&self) -> Option<(String, Duration)> {
+        let timeout = self.timeout?;
+        self.update(|session| {
+            let session = session.as_ref()?;
+            let idle = session.active.elapsed();
+            Some((session.player.clone(), timeout.saturating_sub(idle)))
+        })
+    }
+
+    pub fn status(&self) -> Option<IdleStatus> {
+        let (player, remaining) = self.remaining()?;
+        Some(IdleStatus {
+            player,
+            timeout_secs: self.timeout?.as_secs(),
+            remaining_secs: remaining.as_secs(),
+        })
+    }
+
+    fn summary(&self, profile: &str) -> Option<SessionSummary> {
+        self.update(|session| {
+            let session = session.as_ref()?;
+            Some(SessionSummary {
+                player: session.player.clone(),
+                profile: profile.to_string(),
+                started_at: session.started_at,
+                ended_at: Utc::now(),
+                duration_secs: session.started.elapsed().as_secs(),
+                presses: session.presses,
+            })
+        })
+    }
+}
+
+// Signs the device pad's player out once their countdown runs out. Sign-ins, presses and
+// manual player changes only move the deadline, which is looked at again when reached.
+pub async fn idle_task(state: AppState) {
+    if state.idle.timeout().is_none() {
+        return;
+    }
+    let mut changes = state.profiles.subscribe();
+    loop {
+        // Commands keep the session in step themselves; this catches the profiles of
+        // startup and anything published otherwise
+        state.idle.follow(&changes.borrow_and_update(), false);
+        let wait = match state.idle.remaining() {
+            Some((player, remaining)) if remaining.is_zero() => sign_out(&state, &player).await,
+            Some((_, remaining)) => Some(remaining),
+            None => None,
+        };
+        let changed = match wait {
+            Some(wait) => tokio::select! {
+                () = tokio::time::sleep(wait) => Ok(()),
+                changed = changes.changed() => changed,
+            },
+            None => changes.changed().await,
+        };
+        if changed.is_err() {
+            return;
+        }
+    }
+}
+
+// Sign `player` out of the device pad with ClearCurrentPlayer. It only goes through at the
+// revision the profiles had here, so a player changed in between stays signed in and the
+// countdown is looked at again. Returns the wait before another try, None if it is done.
+async fn sign_out(state: &AppState, player: &str) -> Option<Duration> {
+    let profiles = state.profiles_snapshot();
+    let pad = profiles.pads.first()?;
+    if pad.current_player != player {
+        return Some(Duration::ZERO);
+    }
+    let summary = state.idle.summary(&pad.current_profile)?;
+    let response = handle_envelope(
+        Envelope {
+            command: Command::ClearCurrentPlayer {
+                pad: Some(pad.id.clone()),
+            },
+            idempotency_key: None,
+            dry_run: false,
+            expected_revision: Some(profiles.revision),
+            origin: Origin::Internal,
+        },
+        state,
+    )
+    .await;
+    if response.success {
+        println!(
+            "Signed out player '{}' after {} idle minute(s)",
+            player,
+            state.idle.timeout().unwrap_or_default().as_secs() / 60
+        );
+        state.publish(Event::PlayerSignedOut {
+            pad: pad.id.as_str().into(),
+            reason: "idle",
+            summary: Arc::new(summary),
+        });
+        return None;
+    }
+    if response.message_code.as_deref() == Some("CONFLICT") {
+        return Some(Duration::ZERO);
+    }
+    eprintln!(
+        "Failed to sign out idle player '{}': {}",
+        player, response.message
+    );
+    Some(SIGN_OUT_RETRY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::handle_command;
+    use crate::profile::{Pad, Player, Profile};
+    use std::collections::HashMap;
+
+    fn profiles() -> Profiles {
+        Profiles {
+            profiles: HashMap::from([("Casual".to_string(), Profile::new([400; 4]))]),
+            players: HashMap::from([(
+                "Alice".to_string(),
+                Player {
+                    name: "Alice".to_string(),
+                    profile: "Casual".to_string(),
+                },
+            )]),
+            pads: vec![Pad::default_pad("Casual".to_string(), "Alice".to_string())],
+            ..Profiles::default()
+        }
+    }
+
+    #[test]
+    fn test_countdown_follows_presses_and_players() {
+        let idle = IdleSignOut::new(Some(Duration::from_secs(600)));
+        let mut profiles = profiles();
+        idle.follow(&profiles, false);
+        let status = idle.status().unwrap();
+        assert_eq!(
+            (status.player.as_str(), status.timeout_secs),
+            ("Alice", 600)
+        );
+        assert!(status.remaining_secs >= 599);
+
+        idle.record_press([true, false, false, false].into());
+        idle.record_press([true, true, false, false].into());
+        idle.record_press([false, false, false, false].into());
+        idle.record_press([true, false, false, false].into());
+        let summary = idle.summary("Casual").unwrap();
+        assert_eq!((summary.player.as_str(), summary.presses), ("Alice", 3));
+
+        // The same player again keeps the session, a ChangePlayer starts it over
+        idle.follow(&profiles, false);
+        assert_eq!(idle.summary("Casual").unwrap().presses, 3);
+        idle.follow(&profiles, true);
+        assert_eq!(idle.summary("Casual").unwrap().presses, 0);
+
+        profiles.pads[0].current_player.clear();
+        idle.follow(&profiles, false);
+        assert_eq!(idle.status(), None);
+
+        // Nothing is tracked without a timeout
+        let off = IdleSignOut::default();
+        off.follow(&self::profiles(), false);
+        assert_eq!(off.status(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_player_is_signed_out() {
+        let state = AppState {
+            idle: Arc::new(IdleSignOut::new(Some(Duration::from_secs(60)))),
+            ..AppState::with_mock_port(profiles())
+        };
+        let mut events = state.events.subscribe();
+        let task = tokio::spawn(idle_task(state.clone()));
+        tokio::task::yield_now().await;
+
+        // Signing in again starts the countdown over, so it hasn't run out at first
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        let command = Command::ChangePlayer {
+            name: "Alice".to_string(),
+            pad: None,
+        };
+        assert!(handle_command(command, &state).await.success);
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        assert_eq!(state.profiles_snapshot().current_player(), "Alice");
+
+        let signed_out = tokio::time::timeout(Duration::from_secs(60), async {
+            loop {
+                if let Ok(event @ Event::PlayerSignedOut { .. }) = events.recv().await {
+                    break event.to_response();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let payload = signed_out.payload.unwrap();
+        assert_eq!(payload["reason"], "idle");
+        assert_eq!(payload["session"]["player"], "Alice");
+        assert_eq!(payload["session"]["profile"], "Casual");
+        assert_eq!(state.profiles_snapshot().current_player(), "");
+        assert_eq!(state.profiles_snapshot().current_profile(), "Casual");
+        assert_eq!(state.idle.status(), None);
+
+        // Nobody is signed in any more
+        let response = handle_command(Command::ClearCurrentPlayer { pad: None }, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("NO_CURRENT_PLAYER"));
+        task.abort();
+    }
+}
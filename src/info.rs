@@ -0,0 +1,144 @@
+use crate::profile::{LoadFailure, MIN_PROTOCOL_VERSION, PROFILES_FILE, PROTOCOL_VERSION};
+use crate::quota::Quotas;
+use crate::repair::Repair;
+use crate::startup_sync::StartupSyncReport;
+use chrono::DateTime;
+use serde::Serialize;
+use std::sync::LazyLock;
+
+// Commit the binary was built from, or "unknown" outside a git checkout
+pub const GIT_HASH: &str = env!("FSR_GIT_HASH");
+
+// Build time in seconds since the Unix epoch
+const BUILD_TIME: &str = env!("FSR_BUILD_TIMESTAMP");
+
+// `-V` prints the bare version for scripts, `--version` adds what a bug report needs
+pub static LONG_VERSION: LazyLock<String> = LazyLock::new(|| {
+    format!(
+        "{}\ncommit {}\nbuilt {}",
+        env!("CARGO_PKG_VERSION"),
+        GIT_HASH,
+        build_time()
+    )
+});
+
+// One line for the startup log, so a log alone says which build produced it
+pub fn banner() -> String {
+    format!(
+        "fsr-rs {} (commit {}, built {})",
+        env!("CARGO_PKG_VERSION"),
+        GIT_HASH,
+        build_time()
+    )
+}
+
+// Which serial device the server talks to
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    Serial,
+    Mock,
+    // Another fsr-rs instance's, followed with --mirror
+    Mirror,
+    // The port couldn't be opened; commands fail with serial errors
+    #[default]
+    None,
+}
+
+// "What exactly are you running", returned by GetServerInfo and `GET /api/info`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ServerInfo {
+    pub version: String,
+    pub git_hash: String,
+    pub build_time: String,
+    pub protocol_version: u32,
+    // Oldest version clients may declare with `?protocol=` or Hello
+    pub min_protocol_version: u32,
+    pub os: String,
+    pub arch: String,
+    pub host: String,
+    pub port: u16,
+    // Advertised via mDNS, and told to mirrors
+    pub pad_name: String,
+    pub profiles_path: String,
+    pub device: DeviceKind,
+    pub com_port: Option<String>,
+    // Most profiles and players commands may create
+    pub quotas: Quotas,
+    // Dangling references fixed when profiles.json was loaded
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub repairs_performed: Vec<Repair>,
+    // What --startup-sync did with the thresholds; none outside the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup_sync: Option<StartupSyncReport>,
+    // profiles.json failed to parse on startup, filled in by server_info()
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profiles_load_failure: Option<LoadFailure>,
+    pub uptime_secs: u64,
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        Self::new(String::new(), 0, DeviceKind::None, None)
+    }
+}
+
+impl ServerInfo {
+    pub fn new(host: String, port: u16, device: DeviceKind, com_port: Option<String>) -> Self {
+        let profiles_path = std::path::absolute(PROFILES_FILE)
+            .unwrap_or_else(|_| PROFILES_FILE.into())
+            .display()
+            .to_string();
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: GIT_HASH.to_string(),
+            build_time: build_time(),
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            host,
+            port,
+            pad_name: String::new(),
+            profiles_path,
+            device,
+            com_port,
+            quotas: Quotas::default(),
+            repairs_performed: Vec::new(),
+            startup_sync: None,
+            profiles_load_failure: None,
+            uptime_secs: 0,
+        }
+    }
+}
+
+// RFC 3339 build time, empty if the build script's value is unusable
+fn build_time() -> String {
+    BUILD_TIME
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_info_build_metadata() {
+        let info = ServerInfo::new("0.0.0.0".to_string(), 3000, DeviceKind::Mock, None);
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_hash.is_empty());
+        assert!(DateTime::parse_from_rfc3339(&info.build_time).is_ok());
+        assert!(info.profiles_path.ends_with(PROFILES_FILE));
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["device"], "mock");
+        assert_eq!(json["protocol_version"], PROTOCOL_VERSION);
+
+        assert!(LONG_VERSION.contains(&format!("commit {}", GIT_HASH)));
+        assert!(banner().contains(&info.build_time));
+    }
+}
@@ -0,0 +1,386 @@
+use crate::auth::constant_time_eq;
+use crate::error::InstanceError;
+use crate::profile::PROFILES_FILE;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Held by the running server, next to the profiles file it writes
+pub const LOCK_FILE: &str = "fsr-rs.lock";
+
+// How long --takeover waits for the running server to let go of the lock
+pub const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Between two attempts at the lock while taking over
+const TAKEOVER_POLL: Duration = Duration::from_millis(100);
+
+// How long a signalled server gets to stop by itself before it is killed
+const GRACEFUL_STOP: Duration = Duration::from_secs(5);
+
+pub fn default_lock_path() -> PathBuf {
+    Path::new(PROFILES_FILE)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(LOCK_FILE)
+}
+
+// Contents of the lock file: who holds it and how to reach them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub pid: u32,
+    pub host: String,
+    pub port: u16,
+    // Proves a `POST /api/shutdown` comes from someone who could read this file
+    pub shutdown_key: String,
+}
+
+impl LockInfo {
+    // Where the holder answers, on loopback if it listens on every interface
+    pub fn url(&self) -> String {
+        let host = match self.host.as_str() {
+            "0.0.0.0" | "::" | "[::]" => "127.0.0.1",
+            host => host,
+        };
+        format!("http://{}:{}", host, self.port)
+    }
+}
+
+// The message for a lock someone else holds
+pub fn held_message(holder: &Option<LockInfo>) -> String {
+    match holder {
+        Some(holder) => format!(
+            "Another fsr-rs (pid {}) is already running at {}; stop it or start with --takeover",
+            holder.pid,
+            holder.url()
+        ),
+        None => {
+            "Another fsr-rs is already starting in this directory; stop it or start with --takeover"
+                .to_string()
+        }
+    }
+}
+
+// The lock of this directory's server, released and removed when dropped. The lock is
+// an OS file lock, so it is also released when the process dies; the file it leaves
+// behind is then stale and taken over by the next server.
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+    info: LockInfo,
+    // Holder of a lock that was left behind, if there was one
+    stale: Option<LockInfo>,
+}
+
+impl InstanceLock {
+    // Take the lock at `path` for a server listening at `host`:`port`
+    pub fn acquire(path: &Path, host: &str, port: u16) -> Result<Self, InstanceError> {
+        let io = |source| InstanceError::Lock {
+            path: path.display().to_string(),
+            source,
+        };
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        // The shutdown key is only for the user running the server
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path).map_err(io)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(InstanceError::Held(read_info(&mut file))),
+            // No file locks on this file system: go by whether the PID in it still runs
+            Err(TryLockError::Error(e)) if e.kind() == ErrorKind::Unsupported => {
+                if let Some(holder) = read_info(&mut file).filter(|holder| pid_alive(holder.pid)) {
+                    return Err(InstanceError::Held(Some(holder)));
+                }
+            }
+            Err(TryLockError::Error(e)) => return Err(io(e)),
+        }
+        // A holder shutting down removes the file before unlocking it; a lock taken on
+        // the removed file in between guards nothing
+        if !path.exists() {
+            return Err(InstanceError::Held(None));
+        }
+        // A file left behind keeps the permissions it was created with
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))
+                .map_err(io)?;
+        }
+        let stale = read_info(&mut file).filter(|stale| stale.pid != std::process::id());
+        let mut lock = Self {
+            file,
+            path: path.to_path_buf(),
+            info: LockInfo {
+                pid: std::process::id(),
+                host: host.to_string(),
+                port,
+                shutdown_key: random_key().map_err(|e| io(std::io::Error::other(e.to_string())))?,
+            },
+            stale,
+        };
+        lock.write().map_err(io)?;
+        Ok(lock)
+    }
+
+    // Record the port actually bound, for `--port 0`
+    pub fn set_port(&mut self, port: u16) -> std::io::Result<()> {
+        self.info.port = port;
+        self.write()
+    }
+
+    pub fn info(&self) -> &LockInfo {
+        &self.info
+    }
+
+    pub fn stale(&self) -> Option<&LockInfo> {
+        self.stale.as_ref()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&mut self) -> std::io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.info)?;
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&json)?;
+        self.file.sync_data()
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so nobody reads it half gone
+        let _ = self.file.set_len(0);
+        let _ = std::fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+// Lock `path` for this server. With `takeover`, a server holding it is asked to shut
+// down through its local API, or signalled by PID if that fails and killed if it still
+// runs after GRACEFUL_STOP, and the lock is taken once it has let go.
+pub async fn claim(
+    path: &Path,
+    host: &str,
+    port: u16,
+    takeover: bool,
+) -> Result<InstanceLock, InstanceError> {
+    let holder = match InstanceLock::acquire(path, host, port) {
+        Err(InstanceError::Held(holder)) if takeover => holder,
+        claimed => return claimed,
+    };
+    let started = Instant::now();
+    // The PID to kill if it doesn't stop by itself in time
+    let mut signalled = None;
+    if let Some(holder) = &holder {
+        println!(
+            "Taking over from fsr-rs (pid {}) at {}",
+            holder.pid,
+            holder.url()
+        );
+        if let Err(e) = request_shutdown(holder).await {
+            eprintln!(
+                "Warning: shutdown request to {} failed ({}), signalling pid {}",
+                holder.url(),
+                e,
+                holder.pid
+            );
+            signal(holder.pid, false);
+            signalled = Some(holder.pid);
+        }
+    }
+    let deadline = started + TAKEOVER_TIMEOUT;
+    loop {
+        match InstanceLock::acquire(path, host, port) {
+            Err(InstanceError::Held(_)) if Instant::now() < deadline => {
+                if let Some(pid) = signalled.filter(|_| started.elapsed() >= GRACEFUL_STOP) {
+                    eprintln!(
+                        "Warning: pid {} didn't stop within {}s, killing it",
+                        pid,
+                        GRACEFUL_STOP.as_secs()
+                    );
+                    signal(pid, true);
+                    signalled = None;
+                }
+                tokio::time::sleep(TAKEOVER_POLL).await;
+            }
+            Err(InstanceError::Held(_)) => {
+                return Err(InstanceError::TakeoverTimeout {
+                    pid: holder.map(|holder| holder.pid),
+                    secs: TAKEOVER_TIMEOUT.as_secs(),
+                })
+            }
+            claimed => return claimed,
+        }
+    }
+}
+
+async fn request_shutdown(holder: &LockInfo) -> Result<(), String> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/shutdown", holder.url()))
+        .json(&serde_json::json!({ "key": holder.shutdown_key }))
+        .timeout(Duration::from_secs(2))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("answered {}", response.status()))
+    }
+}
+
+// Ask `pid` to stop, the fallback when its API doesn't answer, or with `force` kill it
+// without a chance to save
+fn signal(pid: u32, force: bool) {
+    let pid = pid.to_string();
+    #[cfg(unix)]
+    let status = std::process::Command::new("kill")
+        .args([if force { "-KILL" } else { "-TERM" }, &pid])
+        .status();
+    // Without /F its console is closed, which the server takes as a shutdown
+    #[cfg(windows)]
+    let status = std::process::Command::new("taskkill")
+        .args(if force { &["/F"][..] } else { &[][..] })
+        .args(["/PID", pid.as_str()])
+        .status();
+    if !status.is_ok_and(|status| status.success()) {
+        eprintln!("Warning: failed to signal pid {}", pid);
+    }
+}
+
+fn read_info(file: &mut File) -> Option<LockInfo> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+#[cfg(target_os = "linux")]
+fn pid_alive(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+// Without /proc, an instance that may be running is safer than two
+#[cfg(not(target_os = "linux"))]
+fn pid_alive(_pid: u32) -> bool {
+    true
+}
+
+// 128 bits from the operating system's random source, as hex
+fn random_key() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// `POST /api/shutdown` from a server taking over. Requests with the wrong key are
+// refused; without a lock, e.g. in tests, every request is.
+#[derive(Default)]
+pub struct RemoteShutdown {
+    key: Option<String>,
+    requested: Notify,
+}
+
+impl RemoteShutdown {
+    pub fn new(key: String) -> Self {
+        Self {
+            key: Some(key),
+            requested: Notify::new(),
+        }
+    }
+
+    // Whether `key` is right; if so the server starts shutting down
+    pub fn request(&self, key: &str) -> bool {
+        let accepted = self
+            .key
+            .as_ref()
+            .is_some_and(|expected| constant_time_eq(expected.as_bytes(), key.as_bytes()));
+        if accepted {
+            self.requested.notify_one();
+        }
+        accepted
+    }
+
+    // Completes once a shutdown was requested, also if that was before the call
+    pub async fn requested(&self) {
+        self.requested.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fsr-rs-{}-{}.lock", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_second_instance_is_refused() {
+        let path = lock_path("second");
+        let mut lock = InstanceLock::acquire(&path, "0.0.0.0", 0).unwrap();
+        assert!(lock.stale().is_none());
+        lock.set_port(3001).unwrap();
+
+        let Err(InstanceError::Held(Some(holder))) =
+            InstanceLock::acquire(&path, "127.0.0.1", 3000)
+        else {
+            panic!("the lock was taken twice");
+        };
+        assert_eq!(holder.pid, std::process::id());
+        assert_eq!(holder.url(), "http://127.0.0.1:3001");
+        assert_eq!(holder.shutdown_key, lock.info().shutdown_key);
+
+        drop(lock);
+        assert!(!path.exists());
+        let lock = InstanceLock::acquire(&path, "127.0.0.1", 3000).unwrap();
+        drop(lock);
+    }
+
+    #[test]
+    fn test_stale_lock_is_taken_over() {
+        let path = lock_path("stale");
+        let stale = LockInfo {
+            pid: u32::MAX,
+            host: "127.0.0.1".to_string(),
+            port: 3000,
+            shutdown_key: "old".to_string(),
+        };
+        std::fs::write(&path, serde_json::to_vec(&stale).unwrap()).unwrap();
+
+        let lock = InstanceLock::acquire(&path, "127.0.0.1", 3000).unwrap();
+        assert_eq!(lock.stale(), Some(&stale));
+        let written: LockInfo = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(&written, lock.info());
+        assert_eq!(written.shutdown_key.len(), 32);
+        assert_ne!(written.shutdown_key, random_key().unwrap());
+        // Even a file left behind by someone else is only readable by its owner now
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        drop(lock);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_needs_the_key() {
+        assert!(!RemoteShutdown::default().request(""));
+
+        let shutdown = RemoteShutdown::new("secret".to_string());
+        assert!(!shutdown.request("guess"));
+        assert!(shutdown.request("secret"));
+        // Requested before anyone waited
+        tokio::time::timeout(Duration::from_secs(1), shutdown.requested())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use crate::error::{AppError, ValidationError};
+use crate::zip_writer::zip_files;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+// The Lua files shipped in the release's `lua/` directory, built into the server so it can
+// hand them out without knowing where it was unpacked
+const LUA_FILES: [LuaFile; 2] = [
+    LuaFile {
+        name: "ProfileSwitcher.lua",
+        contents: include_str!("../lua/ProfileSwitcher.lua"),
+        install: true,
+    },
+    LuaFile {
+        name: "globals.lua",
+        contents: include_str!("../lua/globals.lua"),
+        install: false,
+    },
+];
+
+// The server address written in the bundled module, replaced by the one it's downloaded from
+pub const LUA_DEFAULT_URL: &str = "ws://localhost:3000/ws";
+
+// What the module runs in: a theme loading modules from its `Modules` folder, on an
+// ITGmania with websockets in the Lua API
+pub const REQUIRED_THEME: &str = "Simply Love";
+pub const MIN_THEME_VERSION: &str = "5.1.0";
+pub const MIN_ITGMANIA_VERSION: &str = "0.6.0";
+pub const INSTALL_DIR: &str = "Themes/Simply Love/Modules";
+
+pub const ZIP_NAME: &str = "fsr-rs-lua.zip";
+
+// Permissions of the zipped files, as in the release archive
+const FILE_MODE: u32 = 0o644;
+
+struct LuaFile {
+    name: &'static str,
+    contents: &'static str,
+    // Copied into the theme; the others are editor annotations
+    install: bool,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct FileInfo {
+    pub name: &'static str,
+    pub size: usize,
+    pub sha256: String,
+    pub install: bool,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Requirements {
+    pub theme: &'static str,
+    pub theme_version: &'static str,
+    pub itgmania_version: &'static str,
+}
+
+// Answer of `GET /api/integration/lua`. Sizes and hashes are of the files as served, with
+// `server_url` in them.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Manifest {
+    pub server_url: String,
+    pub requires: Requirements,
+    pub install_dir: &'static str,
+    pub files: Vec<FileInfo>,
+    pub zip: &'static str,
+}
+
+// `ws://<host>/ws` for the Host header of a request, if it is a plain host and port that
+// can go into a Lua string as is
+pub fn server_url(host: Option<&str>) -> String {
+    host.filter(|host| {
+        !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c))
+    })
+    .map_or_else(
+        || LUA_DEFAULT_URL.to_string(),
+        |host| format!("ws://{}/ws", host),
+    )
+}
+
+fn render(file: &LuaFile, server_url: &str) -> String {
+    file.contents.replace(LUA_DEFAULT_URL, server_url)
+}
+
+// One file with the server's URL in it
+pub fn file(name: &str, server_url: &str) -> Result<String, AppError> {
+    LUA_FILES
+        .iter()
+        .find(|file| file.name == name)
+        .map(|file| render(file, server_url))
+        .ok_or_else(|| ValidationError::FileNotFound(name.to_string()).into())
+}
+
+pub fn manifest(server_url: &str) -> Manifest {
+    let files = LUA_FILES
+        .iter()
+        .map(|file| {
+            let contents = render(file, server_url);
+            FileInfo {
+                name: file.name,
+                size: contents.len(),
+                sha256: hex(&Sha256::digest(contents.as_bytes())),
+                install: file.install,
+                url: format!("/api/integration/lua/{}", file.name),
+            }
+        })
+        .collect();
+    Manifest {
+        server_url: server_url.to_string(),
+        requires: Requirements {
+            theme: REQUIRED_THEME,
+            theme_version: MIN_THEME_VERSION,
+            itgmania_version: MIN_ITGMANIA_VERSION,
+        },
+        install_dir: INSTALL_DIR,
+        files,
+        zip: "/api/integration/install.zip",
+    }
+}
+
+// All files under `lua/`, as in the release archive
+pub fn install_zip(server_url: &str) -> std::io::Result<Vec<u8>> {
+    let rendered: Vec<(String, String)> = LUA_FILES
+        .iter()
+        .map(|file| (format!("lua/{}", file.name), render(file, server_url)))
+        .collect();
+    let files = rendered
+        .iter()
+        .map(|(name, contents)| (name.as_str(), FILE_MODE, contents.as_bytes()));
+    Ok(zip_files(Cursor::new(Vec::new()), files)?.into_inner())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_files_carry_the_server_url() {
+        // The replacement only works while the module still has the default address
+        assert!(LUA_FILES[0].contents.contains(LUA_DEFAULT_URL));
+
+        let url = server_url(Some("192.168.1.20:3000"));
+        assert_eq!(url, "ws://192.168.1.20:3000/ws");
+        // Anything that could break out of the Lua string is ignored
+        assert_eq!(server_url(Some("x\"..os.exit()..\"")), LUA_DEFAULT_URL);
+        assert_eq!(server_url(None), LUA_DEFAULT_URL);
+
+        let served = file("ProfileSwitcher.lua", &url).unwrap();
+        assert!(served.contains("url = \"ws://192.168.1.20:3000/ws\""));
+        assert!(!served.contains(LUA_DEFAULT_URL));
+        assert_eq!(
+            file("missing.lua", &url).unwrap_err().code(),
+            "FILE_NOT_FOUND"
+        );
+
+        let manifest = manifest(&url);
+        let entry = &manifest.files[0];
+        assert_eq!(entry.size, served.len());
+        assert_eq!(entry.sha256, hex(&Sha256::digest(served.as_bytes())));
+        assert_eq!(
+            hex(&Sha256::digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let zip = install_zip(&url).unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        assert_eq!(zip.len(), LUA_FILES.len());
+        let mut zipped = String::new();
+        zip.by_name("lua/ProfileSwitcher.lua")
+            .unwrap()
+            .read_to_string(&mut zipped)
+            .unwrap();
+        assert_eq!(zipped, served);
+    }
+}
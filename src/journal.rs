@@ -0,0 +1,239 @@
+use crate::profile::PROFILES_FILE;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Error journal written next to the profiles file
+pub const JOURNAL_FILE: &str = "fsr-rs-journal.jsonl";
+
+// Size at which the journal is rotated to `<file>.1`, keeping at most twice this on disk
+pub const JOURNAL_MAX_BYTES: u64 = 1024 * 1024;
+
+// Entries waiting for the writer; beyond this new entries are dropped and counted
+const QUEUE_CAPACITY: usize = 256;
+
+// Entries returned by GetErrorLog when no limit is given
+pub const DEFAULT_LOG_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalKind {
+    SerialError,
+    // Serial reads work again after failing
+    SerialRecovered,
+    DeviceDisconnected,
+    SaveFailed,
+    // Device thresholds didn't match the current profile and were rewritten
+    Resync,
+    Panic,
+    // A background task died and was restarted
+    TaskRestarted,
+    // A scheduled profile switch didn't happen, see schedule.rs
+    ScheduleSkipped,
+    // The device's thresholds were written, see threshold_log.rs
+    ThresholdWrite,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JournalEntry {
+    pub timestamp: DateTime<Utc>,
+    pub kind: JournalKind,
+    pub message: String,
+}
+
+// Bounded on-disk journal of errors and device events, for "the pad felt off around
+// 9pm" reports. Recording never blocks: entries go to a writer task over a bounded
+// channel and are dropped (and counted) when it can't keep up.
+pub struct Journal {
+    tx: Option<mpsc::Sender<JournalEntry>>,
+    writer: Option<JoinHandle<()>>,
+    path: Option<PathBuf>,
+    dropped: AtomicU64,
+}
+
+pub fn default_journal_path() -> PathBuf {
+    Path::new(PROFILES_FILE)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(JOURNAL_FILE)
+}
+
+// `fsr-rs-journal.jsonl` -> `fsr-rs-journal.jsonl.1`
+pub fn rotated_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".1");
+    PathBuf::from(name)
+}
+
+impl Journal {
+    // Journal that records nothing, for states without a writer task
+    pub fn disabled() -> Self {
+        Self {
+            tx: None,
+            writer: None,
+            path: None,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn spawn(path: PathBuf, max_bytes: u64) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let writer = tokio::spawn(writer_task(path.clone(), max_bytes, rx));
+        Self {
+            tx: Some(tx),
+            writer: Some(writer),
+            path: Some(path),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, kind: JournalKind, message: impl Into<String>) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let entry = JournalEntry {
+            timestamp: Utc::now(),
+            kind,
+            message: message.into(),
+        };
+        if tx.try_send(entry).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Stop recording and wait until the entries recorded are on disk, for a process about
+    // to exit
+    pub async fn close(self) {
+        drop(self.tx);
+        if let Some(writer) = self.writer {
+            let _ = writer.await;
+        }
+    }
+
+    // Entries dropped because the writer was behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // The last `limit` entries on disk, oldest first. Lines that don't parse are skipped.
+    pub async fn tail(&self, limit: usize) -> std::io::Result<Vec<JournalEntry>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let mut entries = Vec::new();
+        for file in [rotated_path(path), path.clone()] {
+            let content = match tokio::fs::read_to_string(&file).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            entries.extend(
+                content
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<JournalEntry>(line).ok()),
+            );
+        }
+        let skip = entries.len().saturating_sub(limit);
+        Ok(entries.split_off(skip))
+    }
+}
+
+async fn writer_task(path: PathBuf, max_bytes: u64, mut rx: mpsc::Receiver<JournalEntry>) {
+    while let Some(entry) = rx.recv().await {
+        if let Err(e) = append(&path, max_bytes, &entry).await {
+            eprintln!("Failed to write journal {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Append one JSON line, first rotating the file to `<file>.1` if it would grow past
+// `max_bytes`. Shared with the command history file.
+pub async fn append(path: &Path, max_bytes: u64, entry: &impl Serialize) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    let size = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    if size > 0 && size + line.len() as u64 > max_bytes {
+        tokio::fs::rename(path, rotated_path(path)).await?;
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    file.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fsr-rs-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated_path(&path));
+        path
+    }
+
+    async fn wait_for_entries(journal: &Journal, count: usize) -> Vec<JournalEntry> {
+        for _ in 0..100 {
+            let entries = journal.tail(usize::MAX).await.unwrap();
+            if entries.len() >= count {
+                return entries;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("journal never reached {} entries", count);
+    }
+
+    #[tokio::test]
+    async fn test_journal_tail() {
+        let journal = Journal::spawn(temp_path("tail.jsonl"), JOURNAL_MAX_BYTES);
+        journal.record(JournalKind::SerialError, "Timeout reading sensor values");
+        journal.record(JournalKind::SerialRecovered, "back");
+        journal.record(JournalKind::SaveFailed, "disk full");
+
+        let entries = wait_for_entries(&journal, 3).await;
+        assert_eq!(entries[0].kind, JournalKind::SerialError);
+        assert_eq!(entries[2].message, "disk full");
+
+        let tail = journal.tail(2).await.unwrap();
+        assert_eq!(tail.len(), 2);
+        assert_eq!(tail[0].kind, JournalKind::SerialRecovered);
+    }
+
+    #[tokio::test]
+    async fn test_journal_rotation_caps_size() {
+        let path = temp_path("rotate.jsonl");
+        let journal = Journal::spawn(path.clone(), 500);
+        for i in 0..20 {
+            journal.record(JournalKind::SerialError, format!("error {}", i));
+        }
+
+        // Only the newest entries survive, split over the current and the rotated file
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
+        loop {
+            let entries = journal.tail(usize::MAX).await.unwrap();
+            if entries.last().map(|e| e.message.as_str()) == Some("error 19") {
+                assert!(entries.len() < 20);
+                break;
+            }
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(std::fs::metadata(&path).unwrap().len() <= 500);
+        assert!(std::fs::metadata(rotated_path(&path)).unwrap().len() <= 500);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::sensors::Sensors;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Presses the percentiles are taken over; older ones fall out
+pub const LATENCY_WINDOW: usize = 1024;
+
+// How long detected presses take through the server, for GetLatencyStats and `/health`.
+// A press is a stream frame with a sensor pressed that wasn't in the frame before, as
+// the press detection judged it (see press.rs). Two spans are measured for each:
+//
+// - round trip: the serial exchange that produced the frame, from the request being
+//   written to the answer being parsed. With --pipelined-reads the request went out a
+//   tick earlier, so this is only the wait for its answer.
+// - dispatch: from the end of that exchange to the frame being handed to the event
+//   channel, i.e. the logical mapping, calibration and the send.
+//
+// Not covered: the time between the pad being pressed and the firmware sampling it, the
+// wait for the stream tick, the frame waiting in the channel, serializing it, the
+// websocket write, the network and the client. The server has no binary or UDP output,
+// so there is no socket write to measure; a frame is done once it is in the channel.
+pub struct PressLatency {
+    window: Mutex<Window>,
+}
+
+struct Window {
+    samples: VecDeque<Sample>,
+    // Sensors pressed in the previous frame
+    pressed: Sensors<bool>,
+    presses: u64,
+    since: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    round_trip: Duration,
+    dispatch: Duration,
+}
+
+// Payload of GetLatencyStats; the percentiles are None before the first press
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LatencyStats {
+    // Presses since the last reset, including those out of the window
+    pub presses: u64,
+    // Presses the percentiles are taken over
+    pub samples: usize,
+    pub since_reset_secs: u64,
+    pub dispatch_us: Option<Percentiles>,
+    pub round_trip_us: Option<Percentiles>,
+    // Both spans together: from the frame's request to its broadcast, the server's share
+    // of the sensor-to-client latency
+    pub total_us: Option<Percentiles>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Percentiles {
+    // Nearest-rank percentiles, None without values
+    fn of(mut values: Vec<u64>) -> Option<Self> {
+        values.sort_unstable();
+        let max = *values.last()?;
+        let rank = |p: usize| values[(values.len() * p).div_ceil(100) - 1];
+        Some(Self {
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max,
+        })
+    }
+}
+
+impl Default for PressLatency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PressLatency {
+    pub fn new() -> Self {
+        Self {
+            window: Mutex::new(Window {
+                samples: VecDeque::with_capacity(LATENCY_WINDOW),
+                pressed: Sensors::default(),
+                presses: 0,
+                since: Instant::now(),
+            }),
+        }
+    }
+
+    // A broadcast stream frame with the spans it took; only the start of a press counts
+    pub fn record(&self, pressed: Sensors<bool>, round_trip: Duration, dispatch: Duration) {
+        let Ok(mut window) = self.window.lock() else {
+            return;
+        };
+        let new_press = pressed
+            .iter()
+            .enumerate()
+            .any(|(i, &now)| now && !window.pressed.get(i).copied().unwrap_or(false));
+        window.pressed = pressed;
+        if !new_press {
+            return;
+        }
+        window.presses += 1;
+        if window.samples.len() == LATENCY_WINDOW {
+            window.samples.pop_front();
+        }
+        window.samples.push_back(Sample {
+            round_trip,
+            dispatch,
+        });
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        let Ok(window) = self.window.lock() else {
+            return Self::new().stats();
+        };
+        let micros = |span: fn(&Sample) -> Duration| {
+            Percentiles::of(
+                window
+                    .samples
+                    .iter()
+                    .map(|sample| span(sample).as_micros() as u64)
+                    .collect(),
+            )
+        };
+        LatencyStats {
+            presses: window.presses,
+            samples: window.samples.len(),
+            since_reset_secs: window.since.elapsed().as_secs(),
+            dispatch_us: micros(|sample| sample.dispatch),
+            round_trip_us: micros(|sample| sample.round_trip),
+            total_us: micros(|sample| sample.round_trip + sample.dispatch),
+        }
+    }
+
+    // Start over, returning the stats up to now. A sensor held through the reset isn't
+    // counted again.
+    pub fn reset(&self) -> LatencyStats {
+        let stats = self.stats();
+        if let Ok(mut window) = self.window.lock() {
+            window.samples.clear();
+            window.presses = 0;
+            window.since = Instant::now();
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONE: [bool; 4] = [false; 4];
+    const FIRST: [bool; 4] = [true, false, false, false];
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_only_the_start_of_a_press_counts() {
+        let latency = PressLatency::new();
+        latency.record(NONE.into(), ms(1), ms(1));
+        assert_eq!(latency.stats().presses, 0);
+        assert_eq!(latency.stats().dispatch_us, None);
+
+        latency.record(FIRST.into(), ms(2), ms(1));
+        // Held, then a second sensor joins in
+        latency.record(FIRST.into(), ms(9), ms(9));
+        latency.record([true, true, false, false].into(), ms(4), ms(3));
+        // Released
+        latency.record(NONE.into(), ms(9), ms(9));
+
+        let stats = latency.stats();
+        assert_eq!((stats.presses, stats.samples), (2, 2));
+        let total = stats.total_us.unwrap();
+        assert_eq!((total.p50, total.max), (3_000, 7_000));
+        assert_eq!(stats.round_trip_us.unwrap().p99, 4_000);
+    }
+
+    #[test]
+    fn test_percentiles_and_reset() {
+        let latency = PressLatency::new();
+        for i in 1..=100 {
+            latency.record(FIRST.into(), ms(0), ms(i));
+            latency.record(NONE.into(), ms(0), ms(0));
+        }
+        let dispatch = latency.stats().dispatch_us.unwrap();
+        assert_eq!(
+            dispatch,
+            Percentiles {
+                p50: 50_000,
+                p90: 90_000,
+                p99: 99_000,
+                max: 100_000,
+            }
+        );
+
+        assert_eq!(latency.reset().presses, 100);
+        let stats = latency.stats();
+        assert_eq!((stats.presses, stats.samples), (0, 0));
+        assert_eq!(stats.total_us, None);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::press::{PressParameters, PressState};
+use crate::sensors::Sensors;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// One sensor reading of the stream, complete with the thresholds it was judged against
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorSnapshot {
+    pub pad: Arc<str>,
+    // In the pad's logical sensor order, like the stream frames
+    pub values: Sensors<i32>,
+    // Of the current profile; None if the pad's profile doesn't exist
+    pub thresholds: Option<Sensors<i32>>,
+    // Of the current profile, what `press` was judged with
+    pub parameters: PressParameters,
+    pub press: PressState,
+    pub at: Instant,
+    // Readings recorded before this one; unrelated to the event history's numbers
+    pub seq: u64,
+    // Last reading with a pressed sensor, this one included
+    pub last_press: Option<Instant>,
+}
+
+impl SensorSnapshot {
+    // As served by `GET /api/sensors` and ReadSensors
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "pad": self.pad,
+            "values": self.values,
+            "thresholds": self.thresholds,
+            "pressed": self.press.pressed,
+            "seq": self.seq,
+            "age_ms": self.at.elapsed().as_millis() as u64,
+            "last_press_ms": self.last_press.map(|pressed| pressed.elapsed().as_millis() as u64),
+        })
+    }
+}
+
+// The newest reading of the sensor stream, for readers that want the current state
+// rather than every frame: the heartbeat, the greeting, the pad-in-session check of
+// IdentifyPad and `GET /api/sensors`. The stream task replaces the whole snapshot on
+// every reading, so a reader gets one reading or the next, never a mix of both, without
+// subscribing to the events or waiting on the stream.
+pub struct LatestFrame {
+    frame: watch::Sender<Option<Arc<SensorSnapshot>>>,
+}
+
+impl LatestFrame {
+    pub fn new() -> Self {
+        Self {
+            frame: watch::Sender::new(None),
+        }
+    }
+
+    // Replace the newest reading, returning it as stored. The press detection goes on
+    // from the reading before, with the parameters of the profile current now, so a
+    // profile change takes effect with the next reading.
+    pub fn record(
+        &self,
+        pad: Arc<str>,
+        values: Sensors<i32>,
+        thresholds: Option<Sensors<i32>>,
+        parameters: PressParameters,
+    ) -> Arc<SensorSnapshot> {
+        let at = Instant::now();
+        let mut recorded = None;
+        self.frame.send_modify(|frame| {
+            let previous = frame.as_deref();
+            // A reading of another pad starts over
+            let press = previous
+                .filter(|previous| previous.pad == pad)
+                .map(|previous| previous.press)
+                .unwrap_or_default()
+                .next(values, thresholds, parameters, at);
+            let snapshot = Arc::new(SensorSnapshot {
+                pad,
+                values,
+                thresholds,
+                parameters,
+                press,
+                at,
+                seq: previous.map_or(0, |previous| previous.seq + 1),
+                last_press: if press.any() {
+                    Some(at)
+                } else {
+                    previous.and_then(|previous| previous.last_press)
+                },
+            });
+            *frame = Some(Arc::clone(&snapshot));
+            recorded = Some(snapshot);
+        });
+        recorded.expect("send_modify runs the closure")
+    }
+
+    // The newest reading, None before the first
+    pub fn get(&self) -> Option<Arc<SensorSnapshot>> {
+        self.frame.borrow().clone()
+    }
+
+    // Time since the stream last read the sensors, None if it never did
+    pub fn since_read(&self) -> Option<Duration> {
+        self.get().map(|frame| frame.at.elapsed())
+    }
+
+    // Time since the stream last saw a pressed sensor, None if it never did
+    pub fn since_press(&self) -> Option<Duration> {
+        self.get()
+            .and_then(|frame| frame.last_press)
+            .map(|pressed| pressed.elapsed())
+    }
+}
+
+impl Default for LatestFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readers_never_see_a_torn_frame() {
+        let latest = Arc::new(LatestFrame::new());
+        assert!(latest.get().is_none());
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let latest = latest.clone();
+                std::thread::spawn(move || {
+                    let mut last_seq = None;
+                    for _ in 0..20_000 {
+                        let Some(frame) = latest.get() else {
+                            continue;
+                        };
+                        // Every field written together belongs to the same reading
+                        let i = frame.seq as i32;
+                        assert_eq!(frame.values, [i; 4]);
+                        assert_eq!(frame.thresholds, Some([i + 1; 4].into()));
+                        assert_eq!(&*frame.pad, format!("pad{}", i % 3));
+                        assert!(last_seq <= Some(frame.seq));
+                        last_seq = Some(frame.seq);
+                    }
+                })
+            })
+            .collect();
+        for i in 0..20_000 {
+            latest.record(
+                format!("pad{}", i % 3).into(),
+                [i; 4].into(),
+                Some([i + 1; 4].into()),
+                PressParameters::default(),
+            );
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(latest.get().unwrap().seq, 19_999);
+        // Below the thresholds throughout, so never pressed
+        assert_eq!(latest.since_press(), None);
+
+        let parameters = PressParameters {
+            hysteresis: [50; 4].into(),
+            debounce_ms: 0,
+        };
+        let frame = latest.record(
+            "pad0".into(),
+            [0, 0, 500, 0].into(),
+            Some([400; 4].into()),
+            parameters,
+        );
+        assert!(frame.press.changed);
+        // Held within the hysteresis
+        let frame = latest.record(
+            "pad0".into(),
+            [0, 0, 380, 0].into(),
+            Some([400; 4].into()),
+            parameters,
+        );
+        assert_eq!(frame.press.pressed, [false, false, true, false]);
+        assert!(!frame.press.changed);
+        // Another pad starts with nothing pressed
+        let frame = latest.record(
+            "pad1".into(),
+            [0, 0, 380, 0].into(),
+            Some([400; 4].into()),
+            parameters,
+        );
+        assert_eq!(frame.press.pressed, [false; 4]);
+        assert!(latest.since_press().unwrap() < Duration::from_secs(1));
+        assert!(latest.since_read().unwrap() < Duration::from_secs(1));
+    }
+}
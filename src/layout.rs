@@ -0,0 +1,143 @@
+use crate::error::ValidationError;
+use crate::profile::Profile;
+use crate::sensors::MAX_SENSORS;
+use serde::Serialize;
+
+// Layout of profiles that don't name one: the four panel dance pad
+pub const DEFAULT_LAYOUT: &str = "dance4";
+
+// Layout with any number of sensors and generic labels, for pads wired another way
+pub const CUSTOM_LAYOUT: &str = "custom";
+
+// A panel arrangement, with the panel of each sensor index in firmware order
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Layout {
+    pub name: &'static str,
+    pub labels: &'static [&'static str],
+}
+
+pub const LAYOUTS: [Layout; 3] = [
+    Layout {
+        name: DEFAULT_LAYOUT,
+        labels: &["Left", "Down", "Up", "Right"],
+    },
+    // Pump It Up
+    Layout {
+        name: "pump5",
+        labels: &["DownLeft", "UpLeft", "Center", "UpRight", "DownRight"],
+    },
+    Layout {
+        name: "pad9",
+        labels: &[
+            "UpLeft",
+            "Up",
+            "UpRight",
+            "Left",
+            "Center",
+            "Right",
+            "DownLeft",
+            "Down",
+            "DownRight",
+        ],
+    },
+];
+
+impl Layout {
+    pub fn find(name: &str) -> Option<&'static Layout> {
+        LAYOUTS.iter().find(|layout| layout.name == name)
+    }
+
+    pub fn sensor_count(&self) -> usize {
+        self.labels.len()
+    }
+}
+
+// The layout of a pad with `sensors` sensors when nothing says otherwise
+pub fn for_sensor_count(sensors: usize) -> &'static str {
+    LAYOUTS
+        .iter()
+        .find(|layout| layout.sensor_count() == sensors)
+        .map_or(CUSTOM_LAYOUT, |layout| layout.name)
+}
+
+// Check that a profile of `layout` can have `thresholds` thresholds: as many as the
+// layout has panels, and 1 to MAX_SENSORS for a custom one
+pub fn check(layout: &str, thresholds: usize) -> Result<(), ValidationError> {
+    let expected = match Layout::find(layout) {
+        Some(found) => found.sensor_count(),
+        None if layout == CUSTOM_LAYOUT => thresholds.clamp(1, MAX_SENSORS),
+        None => return Err(ValidationError::UnknownLayout(layout.to_string())),
+    };
+    if thresholds != expected {
+        return Err(ValidationError::ThresholdCount {
+            expected,
+            got: thresholds,
+        });
+    }
+    Ok(())
+}
+
+// Check that `profile` can go on a device with `sensors` sensors, when the device has
+// shown how many it has, so its thresholds are never written to indices the device
+// doesn't have
+pub fn check_device(profile: &Profile, sensors: Option<usize>) -> Result<(), ValidationError> {
+    check(&profile.layout, profile.thresholds.len())?;
+    match sensors {
+        Some(sensors) if sensors != profile.thresholds.len() => {
+            Err(ValidationError::LayoutMismatch {
+                layout: profile.layout.clone(),
+                needs: profile.thresholds.len(),
+                sensors,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Label of a sensor, "Sensor <n>" where the layout has none
+pub fn label(layout: &str, index: usize) -> String {
+    Layout::find(layout)
+        .and_then(|layout| layout.labels.get(index))
+        .map_or_else(|| format!("Sensor {}", index), |label| label.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layouts() {
+        assert!(check(DEFAULT_LAYOUT, 4).is_ok());
+        assert!(check("pump5", 5).is_ok());
+        assert!(check(CUSTOM_LAYOUT, 7).is_ok());
+        assert_eq!(
+            check("pad9", 4),
+            Err(ValidationError::ThresholdCount {
+                expected: 9,
+                got: 4
+            })
+        );
+        assert!(check(CUSTOM_LAYOUT, 10).is_err());
+        assert!(matches!(
+            check("dance3", 4),
+            Err(ValidationError::UnknownLayout(_))
+        ));
+        assert_eq!(for_sensor_count(5), "pump5");
+        assert_eq!(for_sensor_count(6), CUSTOM_LAYOUT);
+        assert_eq!(label("pump5", 2), "Center");
+        assert_eq!(label(CUSTOM_LAYOUT, 2), "Sensor 2");
+
+        let pump = Profile::new([100; 5]);
+        assert_eq!(pump.layout, "pump5");
+        assert!(check_device(&pump, None).is_ok());
+        assert!(check_device(&pump, Some(5)).is_ok());
+        assert_eq!(
+            check_device(&pump, Some(4)),
+            Err(ValidationError::LayoutMismatch {
+                layout: "pump5".to_string(),
+                needs: 5,
+                sensors: 4,
+            })
+        );
+    }
+}
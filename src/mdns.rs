@@ -0,0 +1,209 @@
+use crate::profile::PROTOCOL_VERSION;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+// Service type advertised on the LAN
+pub const SERVICE_TYPE: &str = "_fsr-rs._tcp.local.";
+
+// Default instance name when --pad-name is not given
+pub const DEFAULT_PAD_NAME: &str = "fsr-rs";
+
+// A fsr-rs instance found on the network by `--discover`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredInstance {
+    pub pad_name: String,
+    pub hostname: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub protocol_version: Option<String>,
+    pub version: Option<String>,
+}
+
+// Turn a pad name into something usable as an mDNS host label
+fn host_label(pad_name: &str) -> String {
+    let label: String = pad_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        DEFAULT_PAD_NAME.to_string()
+    } else {
+        label.to_string()
+    }
+}
+
+// Build the service record for this server
+pub fn build_service_info(
+    pad_name: &str,
+    host: &str,
+    port: u16,
+) -> Result<ServiceInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let properties = HashMap::from([
+        ("pad_name".to_string(), pad_name.to_string()),
+        ("protocol".to_string(), PROTOCOL_VERSION.to_string()),
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("path".to_string(), "/ws".to_string()),
+    ]);
+    let hostname = format!("{}.local.", host_label(pad_name));
+
+    // When bound to a wildcard address let the daemon track the interface addresses
+    let bind_addr = host.parse::<IpAddr>().ok();
+    let info = match bind_addr {
+        Some(addr) if !addr.is_unspecified() => {
+            ServiceInfo::new(SERVICE_TYPE, pad_name, &hostname, addr, port, properties)?
+        }
+        _ => ServiceInfo::new(SERVICE_TYPE, pad_name, &hostname, "", port, properties)?
+            .enable_addr_auto(),
+    };
+    Ok(info)
+}
+
+// Keeps the server's mDNS registration in sync with its name and port
+pub struct MdnsAdvertiser {
+    daemon: ServiceDaemon,
+    host: String,
+    registered: Option<(String, u16, String)>, // (pad name, port, fullname)
+}
+
+impl MdnsAdvertiser {
+    pub fn new(host: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self {
+            daemon: ServiceDaemon::new()?,
+            host: host.to_string(),
+            registered: None,
+        })
+    }
+
+    // Register the service, re-announcing if the name or port changed
+    pub fn announce(
+        &mut self,
+        pad_name: &str,
+        port: u16,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some((name, registered_port, _)) = &self.registered {
+            if name == pad_name && *registered_port == port {
+                return Ok(());
+            }
+        }
+        self.withdraw();
+
+        let info = build_service_info(pad_name, &self.host, port)?;
+        let fullname = info.get_fullname().to_string();
+        self.daemon.register(info)?;
+        println!("Advertising '{}' via mDNS on port {}", pad_name, port);
+        self.registered = Some((pad_name.to_string(), port, fullname));
+        Ok(())
+    }
+
+    // Remove the current registration (sends goodbye packets)
+    pub fn withdraw(&mut self) {
+        if let Some((name, _, fullname)) = self.registered.take() {
+            match self.daemon.unregister(&fullname) {
+                Ok(receiver) => {
+                    // Wait briefly so the goodbye actually goes out before shutdown
+                    let _ = receiver.recv_timeout(Duration::from_secs(1));
+                    println!("Withdrew mDNS advertisement for '{}'", name);
+                }
+                Err(e) => eprintln!("Failed to withdraw mDNS advertisement: {}", e),
+            }
+        }
+    }
+
+    pub fn shutdown(mut self) {
+        self.withdraw();
+        let _ = self.daemon.shutdown();
+    }
+}
+
+// Browse the LAN for other fsr-rs instances for the given duration
+pub async fn discover(
+    duration: Duration,
+) -> Result<Vec<DiscoveredInstance>, Box<dyn std::error::Error + Send + Sync>> {
+    let daemon = ServiceDaemon::new()?;
+    let receiver = daemon.browse(SERVICE_TYPE)?;
+    let mut instances: HashMap<String, DiscoveredInstance> = HashMap::new();
+
+    let deadline = tokio::time::Instant::now() + duration;
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, receiver.recv_async()).await {
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+                addresses.sort();
+                instances.insert(
+                    info.get_fullname().to_string(),
+                    DiscoveredInstance {
+                        pad_name: info
+                            .get_property_val_str("pad_name")
+                            .unwrap_or_else(|| info.get_fullname())
+                            .to_string(),
+                        hostname: info.get_hostname().to_string(),
+                        addresses,
+                        port: info.get_port(),
+                        protocol_version: info
+                            .get_property_val_str("protocol")
+                            .map(|v| v.to_string()),
+                        version: info.get_property_val_str("version").map(|v| v.to_string()),
+                    },
+                );
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                instances.remove(&fullname);
+            }
+            _ => {}
+        }
+    }
+
+    let _ = daemon.stop_browse(SERVICE_TYPE);
+    let _ = daemon.shutdown();
+
+    let mut instances: Vec<DiscoveredInstance> = instances.into_values().collect();
+    instances.sort_by(|a, b| a.pad_name.cmp(&b.pad_name));
+    Ok(instances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_label() {
+        assert_eq!(host_label("Left Pad #1"), "left-pad--1");
+        assert_eq!(host_label("cab"), "cab");
+        assert_eq!(host_label("!!!"), DEFAULT_PAD_NAME);
+    }
+
+    #[test]
+    fn test_build_service_info_txt_record() {
+        let info = build_service_info("Left Pad", "0.0.0.0", 3000).unwrap();
+
+        assert_eq!(info.get_fullname(), "Left Pad._fsr-rs._tcp.local.");
+        assert_eq!(info.get_hostname(), "left-pad.local.");
+        assert_eq!(info.get_port(), 3000);
+        assert!(info.is_addr_auto());
+        assert_eq!(info.get_property_val_str("pad_name"), Some("Left Pad"));
+        assert_eq!(
+            info.get_property_val_str("protocol"),
+            Some(PROTOCOL_VERSION.to_string().as_str())
+        );
+        assert_eq!(info.get_property_val_str("path"), Some("/ws"));
+    }
+
+    #[test]
+    fn test_build_service_info_specific_address() {
+        let info = build_service_info("cab", "192.168.1.20", 8080).unwrap();
+
+        assert!(!info.is_addr_auto());
+        assert!(info
+            .get_addresses()
+            .contains(&"192.168.1.20".parse::<IpAddr>().unwrap()));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::error::SerialError;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Counters kept by the server itself, queried with GetServerStats and summarized on
+// `/health`. Hot paths (serial I/O, the sensor stream) only touch relaxed atomics.
+pub struct Metrics {
+    started_at: Instant,
+    // Commands are rare enough for a lock; keyed by command variant / error code
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+    failures: Mutex<BTreeMap<&'static str, u64>>,
+    serial_reads: AtomicU64,
+    serial_writes: AtomicU64,
+    serial_timeouts: AtomicU64,
+    frames_broadcast: AtomicU64,
+    frames_dropped: AtomicU64,
+    saves: AtomicU64,
+    save_failures: AtomicU64,
+    saves_skipped: AtomicU64,
+    stream_restarts: AtomicU64,
+}
+
+// Full set of counters, the payload of GetServerStats
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ServerStats {
+    pub uptime_secs: u64,
+    pub commands: BTreeMap<&'static str, u64>,
+    pub failures: BTreeMap<&'static str, u64>,
+    pub serial_reads: u64,
+    pub serial_writes: u64,
+    pub serial_timeouts: u64,
+    pub frames_broadcast: u64,
+    pub frames_dropped: u64,
+    pub saves: u64,
+    pub save_failures: u64,
+    // Profile changes never written on their own because a later one replaced them first
+    pub saves_skipped: u64,
+    // Times the watchdog restarted a stalled sensor stream
+    pub stream_restarts: u64,
+}
+
+// Totals included in the `/health` report
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct StatsSummary {
+    pub commands: u64,
+    pub command_failures: u64,
+    pub serial_timeouts: u64,
+    pub frames_broadcast: u64,
+    pub frames_dropped: u64,
+    pub save_failures: u64,
+    pub stream_restarts: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            commands: Mutex::new(BTreeMap::new()),
+            failures: Mutex::new(BTreeMap::new()),
+            serial_reads: AtomicU64::new(0),
+            serial_writes: AtomicU64::new(0),
+            serial_timeouts: AtomicU64::new(0),
+            frames_broadcast: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            saves: AtomicU64::new(0),
+            save_failures: AtomicU64::new(0),
+            saves_skipped: AtomicU64::new(0),
+            stream_restarts: AtomicU64::new(0),
+        }
+    }
+
+    // A handled command and, if it failed, its error code
+    pub fn record_command(&self, command: &'static str, failure: Option<&'static str>) {
+        if let Ok(mut commands) = self.commands.lock() {
+            *commands.entry(command).or_default() += 1;
+        }
+        if let Some(code) = failure {
+            if let Ok(mut failures) = self.failures.lock() {
+                *failures.entry(code).or_default() += 1;
+            }
+        }
+    }
+
+    // Count a serial read exchange, passing its result through
+    pub fn serial_read<T>(&self, result: Result<T, SerialError>) -> Result<T, SerialError> {
+        self.serial_reads.fetch_add(1, Ordering::Relaxed);
+        self.count_timeout(&result);
+        result
+    }
+
+    // Count `writes` threshold writes, passing their result through
+    pub fn serial_write<T>(
+        &self,
+        writes: u64,
+        result: Result<T, SerialError>,
+    ) -> Result<T, SerialError> {
+        self.serial_writes.fetch_add(writes, Ordering::Relaxed);
+        self.count_timeout(&result);
+        result
+    }
+
+    fn count_timeout<T>(&self, result: &Result<T, SerialError>) {
+        if let Err(SerialError::Timeout(_)) = result {
+            self.serial_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_frame(&self) {
+        self.frames_broadcast.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Events a lagging subscriber skipped
+    pub fn record_lagged(&self, skipped: u64) {
+        self.frames_dropped.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub fn record_save(&self, ok: bool) {
+        let counter = if ok { &self.saves } else { &self.save_failures };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Profile changes folded into a later save
+    pub fn record_saves_skipped(&self, skipped: u64) {
+        self.saves_skipped.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub fn record_stream_restart(&self) {
+        self.stream_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn frames_broadcast(&self) -> u64 {
+        self.frames_broadcast.load(Ordering::Relaxed)
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+
+    pub fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            uptime_secs: self.uptime_secs(),
+            commands: self.commands.lock().map(|c| c.clone()).unwrap_or_default(),
+            failures: self.failures.lock().map(|f| f.clone()).unwrap_or_default(),
+            serial_reads: self.serial_reads.load(Ordering::Relaxed),
+            serial_writes: self.serial_writes.load(Ordering::Relaxed),
+            serial_timeouts: self.serial_timeouts.load(Ordering::Relaxed),
+            frames_broadcast: self.frames_broadcast.load(Ordering::Relaxed),
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            saves: self.saves.load(Ordering::Relaxed),
+            save_failures: self.save_failures.load(Ordering::Relaxed),
+            saves_skipped: self.saves_skipped.load(Ordering::Relaxed),
+            stream_restarts: self.stream_restarts.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn summary(&self) -> StatsSummary {
+        let stats = self.snapshot();
+        StatsSummary {
+            commands: stats.commands.values().sum(),
+            command_failures: stats.failures.values().sum(),
+            serial_timeouts: stats.serial_timeouts,
+            frames_broadcast: stats.frames_broadcast,
+            frames_dropped: stats.frames_dropped,
+            save_failures: stats.save_failures,
+            stream_restarts: stats.stream_restarts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_counters() {
+        let metrics = Metrics::new();
+        metrics.record_command("GetProfiles", None);
+        metrics.record_command("ChangeProfile", Some("PROFILE_NOT_FOUND"));
+        metrics.record_command("ChangeProfile", None);
+        let _ = metrics.serial_read::<()>(Err(SerialError::Timeout("sensor values")));
+        let _ = metrics.serial_write(4, Ok(()));
+        metrics.record_lagged(7);
+        metrics.record_save(true);
+        metrics.record_save(false);
+        metrics.record_saves_skipped(3);
+
+        let stats = metrics.snapshot();
+        assert_eq!(stats.commands["ChangeProfile"], 2);
+        assert_eq!(stats.failures["PROFILE_NOT_FOUND"], 1);
+        assert_eq!(stats.serial_reads, 1);
+        assert_eq!(stats.serial_timeouts, 1);
+        assert_eq!(stats.serial_writes, 4);
+        assert_eq!(stats.frames_dropped, 7);
+        assert_eq!((stats.saves, stats.save_failures), (1, 1));
+        assert_eq!(stats.saves_skipped, 3);
+
+        let summary = metrics.summary();
+        assert_eq!(summary.commands, 3);
+        assert_eq!(summary.command_failures, 1);
+    }
+}
@@ -0,0 +1,515 @@
+use crate::chunk::{Chunk, Reassembly};
+use crate::event::{Event, WireJson, SUBSCRIBABLE_EVENTS};
+use crate::press::PressParameters;
+use crate::profile::Response;
+use crate::state::AppState;
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::{interval, sleep, sleep_until, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::Message;
+
+// Error code returned for commands rejected by a --mirror server
+pub const MIRROR_MODE: &str = "MIRROR_MODE";
+
+// Upstream events served to the mirror's clients as they came. Stream frames, profiles
+// and the stream state become the mirror's own instead, so its commands and HTTP routes
+// answer from them, and the heartbeat only updates the upstream's device status.
+const RELAYED_EVENTS: [&str; 14] = [
+    "press",
+    "players_changed",
+    "identify",
+    "error",
+    "degraded",
+    "recovered",
+    "profiles_repaired",
+    "drift_compensation",
+    "panel_test_progress",
+    "panel_test_complete",
+    "scheduled_switch",
+    "temporary_profile_ended",
+    "player_signed_out",
+    "proposal_created",
+];
+
+// The upstream answers websocket pings; without any message for this long the connection
+// is given up as dead and made again
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(15);
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+// Delay before the first reconnection attempt, doubled up to RECONNECT_MAX while the
+// upstream stays unreachable
+const RECONNECT_INITIAL: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(10);
+
+// `--mirror` takes the upstream's websocket URL, `ws://host:port/ws`, with `?token=` if the
+// upstream requires one
+pub fn parse_upstream_url(url: &str) -> Result<String, String> {
+    if url.starts_with("wss://") {
+        return Err("wss:// isn't supported by this build, use ws://".to_string());
+    }
+    match url.strip_prefix("ws://") {
+        Some(rest) if !rest.is_empty() => Ok(url.to_string()),
+        _ => Err(format!(
+            "'{}' isn't a websocket URL like ws://192.168.1.20:3000/ws",
+            url
+        )),
+    }
+}
+
+// What the upstream told about itself and its pad
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct Upstream {
+    pub pad_name: Option<String>,
+    pub version: Option<String>,
+    // "serial", "mock" or "none", as in its greeting
+    pub device: Option<String>,
+    pub com_port: Option<String>,
+    // From the upstream's heartbeats; None before the first
+    pub device_connected: Option<bool>,
+    pub out_of_sync: bool,
+}
+
+// Connection to the upstream as reported to clients, in the greeting, the heartbeat and
+// `mirror_status` events
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct MirrorStatus {
+    pub upstream_url: String,
+    pub connected: bool,
+    // The upstream can't be reached: the profiles and readings shown are the last it sent
+    pub stale: bool,
+    // Since the connection was made or lost
+    pub since_ms: Option<u64>,
+    pub last_message_ms: Option<u64>,
+    pub reconnects: u64,
+    // Why the connection was lost or couldn't be made
+    pub last_error: Option<String>,
+    pub upstream: Option<Upstream>,
+}
+
+#[derive(Debug, Default)]
+struct Link {
+    connected: bool,
+    since: Option<Instant>,
+    last_message: Option<Instant>,
+    reconnects: u64,
+    last_error: Option<String>,
+    upstream: Option<Upstream>,
+}
+
+// The instance a --mirror server follows
+#[derive(Debug)]
+pub struct Mirror {
+    url: String,
+    link: Mutex<Link>,
+}
+
+impl Mirror {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            link: Mutex::default(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn status(&self) -> MirrorStatus {
+        let link = self.link.lock().unwrap_or_else(|e| e.into_inner());
+        let ms = |at: Option<Instant>| at.map(|at| at.elapsed().as_millis() as u64);
+        MirrorStatus {
+            upstream_url: self.url.clone(),
+            connected: link.connected,
+            stale: !link.connected,
+            since_ms: ms(link.since),
+            last_message_ms: ms(link.last_message),
+            reconnects: link.reconnects,
+            last_error: link.last_error.clone(),
+            upstream: link.upstream.clone(),
+        }
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.status().stale
+    }
+
+    fn update<T>(&self, change: impl FnOnce(&mut Link) -> T) -> T {
+        change(&mut self.link.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn connected(&self, upstream: Upstream) {
+        self.update(|link| {
+            link.connected = true;
+            link.since = Some(Instant::now());
+            link.last_error = None;
+            link.upstream = Some(upstream);
+        });
+    }
+
+    // Returns whether the connection had been made, i.e. whether this loses it
+    fn disconnected(&self, error: String) -> bool {
+        self.update(|link| {
+            let was_connected = link.connected;
+            if was_connected || link.since.is_none() {
+                link.since = Some(Instant::now());
+            }
+            link.connected = false;
+            link.last_error = Some(error);
+            was_connected
+        })
+    }
+
+    fn record_message(&self) {
+        self.update(|link| link.last_message = Some(Instant::now()));
+    }
+
+    fn record_reconnect(&self) {
+        self.update(|link| link.reconnects += 1);
+    }
+
+    // Returns whether the upstream's device status changed
+    fn record_device(&self, connected: Option<bool>, out_of_sync: bool) -> bool {
+        self.update(|link| {
+            let Some(upstream) = link.upstream.as_mut() else {
+                return false;
+            };
+            let changed =
+                upstream.device_connected != connected || upstream.out_of_sync != out_of_sync;
+            upstream.device_connected = connected;
+            upstream.out_of_sync = out_of_sync;
+            changed
+        })
+    }
+}
+
+// Follow the upstream for as long as the server runs, reconnecting with backoff. Clients
+// learn of every connection made or lost by a `mirror_status` event.
+pub async fn mirror_task(state: AppState, mirror: Arc<Mirror>) {
+    let mut delay = RECONNECT_INITIAL;
+    loop {
+        let error = follow(&state, &mirror).await;
+        if mirror.disconnected(error.clone()) {
+            delay = RECONNECT_INITIAL;
+            state.publish(Event::MirrorStatus(Arc::new(mirror.status())));
+        }
+        eprintln!(
+            "Mirror: {} ({}), reconnecting in {}ms",
+            mirror.url(),
+            error,
+            delay.as_millis()
+        );
+        sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX);
+        mirror.record_reconnect();
+    }
+}
+
+// One connection to the upstream, until it fails; returns why it did
+async fn follow(state: &AppState, mirror: &Mirror) -> String {
+    let socket = match tokio_tungstenite::connect_async(mirror.url()).await {
+        Ok((socket, _)) => socket,
+        Err(e) => return e.to_string(),
+    };
+    let (mut sink, mut stream) = socket.split();
+    let topics: Vec<&str> = SUBSCRIBABLE_EVENTS
+        .into_iter()
+        .filter(|topic| !matches!(*topic, "aggregate_stream" | "mirror_status"))
+        .collect();
+    let subscribe = serde_json::json!({ "Subscribe": { "topics": topics } });
+    if let Err(e) = sink.send(Message::Text(subscribe.to_string())).await {
+        return e.to_string();
+    }
+
+    let mut chunks = Reassembly::default();
+    let mut ping = interval(PING_INTERVAL);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut deadline = tokio::time::Instant::now() + SILENCE_TIMEOUT;
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = ping.tick() => {
+                if let Err(e) = sink.send(Message::Ping(Vec::new())).await {
+                    return e.to_string();
+                }
+                continue;
+            }
+            _ = sleep_until(deadline) => {
+                return format!("nothing received for {}s", SILENCE_TIMEOUT.as_secs());
+            }
+        };
+        let message = match message {
+            None => return "the connection closed".to_string(),
+            Some(Err(e)) => return e.to_string(),
+            Some(Ok(Message::Close(frame))) => {
+                return match frame {
+                    Some(frame) if !frame.reason.is_empty() => {
+                        format!("closed by the upstream: {}", frame.reason)
+                    }
+                    _ => "closed by the upstream".to_string(),
+                }
+            }
+            // Pongs only tell that the upstream is there
+            Some(Ok(message)) => message,
+        };
+        mirror.record_message();
+        deadline = tokio::time::Instant::now() + SILENCE_TIMEOUT;
+        let Message::Text(text) = message else {
+            continue;
+        };
+        if let Some(response) = parse(&text, &mut chunks) {
+            if apply(state, mirror, response) {
+                state.publish(Event::MirrorStatus(Arc::new(mirror.status())));
+            }
+        }
+    }
+}
+
+// A response of the upstream, once all of its chunks are in
+fn parse(text: &str, chunks: &mut Reassembly) -> Option<Response> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    let value = match value["response_type"].as_str() {
+        Some("chunk") => {
+            let chunk: Chunk = serde_json::from_value(value).ok()?;
+            serde_json::from_str(&chunks.push(chunk)?).ok()?
+        }
+        _ => value,
+    };
+    match serde_json::from_value(value) {
+        Ok(response) => Some(response),
+        Err(e) => {
+            eprintln!("Mirror: ignoring a message of the upstream: {}", e);
+            None
+        }
+    }
+}
+
+// Take over a response of the upstream. Returns whether the mirror status changed.
+fn apply(state: &AppState, mirror: &Mirror, response: Response) -> bool {
+    let payload = response.payload.as_ref();
+    match response.response_type.as_deref() {
+        // The greeting, with everything needed to serve the upstream's state
+        Some("command_response") if response.message_code.as_deref() == Some("CONNECTED") => {
+            let Some(payload) = payload else {
+                return false;
+            };
+            if let Some(profiles) = response.data {
+                state.publish_profiles(profiles);
+            }
+            let stream = &payload["stream"];
+            if let Some(rate_hz) = stream["rate_hz"].as_u64() {
+                let rate_hz = rate_hz as u32;
+                state.stream.send_if_modified(|config| {
+                    let changed = config.rate_hz != rate_hz;
+                    config.rate_hz = rate_hz;
+                    changed
+                });
+            }
+            state.set_stream_enabled(stream["enabled"].as_bool().unwrap_or(false));
+            let text = |value: &serde_json::Value| value.as_str().map(str::to_string);
+            let device = &payload["device"];
+            mirror.connected(Upstream {
+                pad_name: text(&payload["server"]["pad_name"]),
+                version: text(&payload["server"]["version"]),
+                device: text(&device["kind"]),
+                com_port: text(&device["port"]),
+                device_connected: None,
+                out_of_sync: device["out_of_sync"].as_bool().unwrap_or(false),
+            });
+            println!("Mirror: following {}", mirror.url());
+            true
+        }
+        // Replies to other clients of the upstream, and to the Subscribe
+        Some("command_response") => false,
+        Some("sensor_stream") => {
+            mirror_frame(state, &response);
+            false
+        }
+        Some("profiles_updated") => {
+            if let Some(profiles) = response.data {
+                state.publish_profiles(profiles);
+            }
+            false
+        }
+        Some("stream_state_changed") => {
+            if let Some(enabled) = payload.and_then(|payload| payload["enabled"].as_bool()) {
+                state.set_stream_enabled(enabled);
+            }
+            false
+        }
+        Some("heartbeat") => {
+            let device = payload.map(|payload| &payload["device"]);
+            mirror.record_device(
+                device.and_then(|device| device["connected"].as_bool()),
+                device
+                    .and_then(|device| device["out_of_sync"].as_bool())
+                    .unwrap_or(false),
+            )
+        }
+        Some(kind) => {
+            if let Some(kind) = RELAYED_EVENTS.into_iter().find(|relayed| *relayed == kind) {
+                // Numbered again by the mirror's own history
+                let response = Arc::new(Response {
+                    seq: None,
+                    ..response
+                });
+                state.publish(Event::Mirrored { kind, response });
+            }
+            false
+        }
+        None => false,
+    }
+}
+
+// A stream frame of the upstream, broadcast as the mirror's own and kept as its latest
+// reading, judged against the mirrored profile
+fn mirror_frame(state: &AppState, response: &Response) {
+    let (Some(pad), Some(values)) = (response.pad.as_deref(), response.sensor_values) else {
+        return;
+    };
+    let payload = response.payload.as_ref();
+    let rate_hz = payload
+        .and_then(|payload| payload["rate_hz"].as_u64())
+        .map_or(state.stream.borrow().rate_hz, |rate_hz| rate_hz as u32);
+    let calibrated = payload
+        .and_then(|payload| payload.get("calibrated"))
+        .and_then(|calibrated| serde_json::from_value(calibrated.clone()).ok());
+    let pad: Arc<str> = pad.into();
+    let profiles = state.profiles.borrow();
+    let profile = profiles.profiles.get(profiles.current_profile());
+    state.latest.record(
+        Arc::clone(&pad),
+        values,
+        profile.map(|profile| profile.thresholds),
+        profile.map(PressParameters::of).unwrap_or_default(),
+    );
+    drop(profiles);
+    state.health.record_stream_tick();
+    state.metrics.record_frame();
+    let _ = state.events.send(Event::SensorFrame {
+        pad,
+        values,
+        calibrated,
+        rate_hz,
+        wire: WireJson::default(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::ReadOnlyPolicy;
+    use crate::profile::{Pad, Profile, Profiles};
+    use std::collections::HashMap;
+
+    fn response(value: serde_json::Value) -> Response {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_upstream_url() {
+        assert!(parse_upstream_url("ws://192.168.1.20:3000/ws").is_ok());
+        assert!(parse_upstream_url("ws://host:3000/ws?token=abc").is_ok());
+        assert!(parse_upstream_url("wss://host/ws").is_err());
+        assert!(parse_upstream_url("http://host:3000").is_err());
+        assert!(parse_upstream_url("ws://").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_messages_become_local_state() {
+        let state = AppState {
+            read_only: ReadOnlyPolicy::mirror(),
+            ..AppState::with_mock_port(Profiles::default())
+        };
+        let mirror = Mirror::new("ws://upstream:3000/ws".to_string());
+        let mut events = state.events.subscribe();
+        assert!(mirror.is_stale());
+
+        let upstream = Profiles {
+            profiles: HashMap::from([("Casual".to_string(), Profile::new([400; 4]))]),
+            pads: vec![Pad::default_pad("Casual".to_string(), "Alice".to_string())],
+            ..Profiles::default()
+        };
+        let greeting = response(serde_json::json!({
+            "success": true,
+            "message": "Connected to profile manager",
+            "data": upstream,
+            "response_type": "command_response",
+            "message_code": "CONNECTED",
+            "payload": {
+                "stream": { "enabled": true, "rate_hz": 30 },
+                "device": { "kind": "serial", "port": "/dev/ttyACM0", "out_of_sync": false },
+                "server": { "version": "0.2.0", "pad_name": "Left Cab" },
+            },
+        }));
+        assert!(apply(&state, &mirror, greeting));
+        assert_eq!(*state.profiles_snapshot(), upstream);
+        assert_eq!(state.effective_rate_hz(), 30);
+        assert!(state.stream_enabled());
+        let status = mirror.status();
+        assert!(status.connected && !status.stale);
+        let upstream_info = status.upstream.unwrap();
+        assert_eq!(upstream_info.pad_name.as_deref(), Some("Left Cab"));
+        assert_eq!(upstream_info.com_port.as_deref(), Some("/dev/ttyACM0"));
+
+        let frame = response(serde_json::json!({
+            "success": true,
+            "message": "Sensor stream data",
+            "sensor_values": [450, 0, 0, 0],
+            "response_type": "sensor_stream",
+            "payload": { "rate_hz": 30, "scale": "raw" },
+            "pad": "default",
+        }));
+        assert!(!apply(&state, &mirror, frame));
+        let press = response(serde_json::json!({
+            "success": true,
+            "message": "Pressed sensors of pad 'default'",
+            "response_type": "press",
+            "payload": { "code": "PRESS", "pressed": [true, false, false, false] },
+            "pad": "default",
+            "seq": 12,
+        }));
+        apply(&state, &mirror, press);
+        // Another client's command on the upstream isn't the mirror's business
+        let other = response(serde_json::json!({
+            "success": true,
+            "message": "Profile added",
+            "response_type": "command_response",
+            "message_code": "PROFILE_ADDED",
+        }));
+        apply(&state, &mirror, other);
+
+        let mut kinds = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            kinds.push(event.kind());
+            if let Event::SensorFrame {
+                values, rate_hz, ..
+            } = event
+            {
+                assert_eq!((values, rate_hz), ([450, 0, 0, 0].into(), 30));
+            }
+        }
+        assert_eq!(kinds, ["stream_state_changed", "sensor_stream", "press"]);
+        let latest = state.latest.get().unwrap();
+        assert!(latest.press.pressed[0]);
+
+        let heartbeat = response(serde_json::json!({
+            "success": true,
+            "message": "Server up for 5s",
+            "response_type": "heartbeat",
+            "payload": { "device": { "connected": false, "out_of_sync": false } },
+        }));
+        assert!(apply(&state, &mirror, heartbeat));
+        assert_eq!(
+            mirror.status().upstream.unwrap().device_connected,
+            Some(false)
+        );
+
+        assert!(mirror.disconnected("the connection closed".to_string()));
+        let lost = Event::MirrorStatus(Arc::new(mirror.status())).to_response();
+        assert!(!lost.success);
+        assert_eq!(lost.payload.unwrap()["code"], "MIRROR_STALE");
+    }
+}
@@ -0,0 +1,349 @@
+use crate::error::{AppError, ValidationError};
+use crate::event::Event;
+use crate::layout;
+use crate::sensors::Sensors;
+use crate::state::AppState;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::{timeout_at, Instant};
+
+// How long StartPanelTest waits for each panel unless told otherwise
+pub const PANEL_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const MAX_PANEL_TEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+// What a test watches for: the device pad under its current profile
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PanelTestPlan {
+    pub pad: String,
+    pub profile: String,
+    pub thresholds: Sensors<i32>,
+    pub timeout_ms: u64,
+    // The stream was off and runs for the test only
+    pub stream_forced: bool,
+}
+
+// One panel's turn, logical sensor order
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PanelResult {
+    pub sensor: usize,
+    pub panel: String,
+    pub threshold: i32,
+    // Pressed past the threshold and let go (or still held) before the timeout
+    pub passed: bool,
+    // Highest reading of the sensor during its turn, null if no frame arrived
+    pub peak: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PanelTestSummary {
+    #[serde(flatten)]
+    pub plan: PanelTestPlan,
+    pub results: Vec<PanelResult>,
+    // Sensors that never fired, and with a cancel the ones whose turn didn't come
+    pub missed: Vec<usize>,
+    pub cancelled: bool,
+}
+
+struct Running {
+    id: u64,
+    pad: String,
+    cancel: oneshot::Sender<()>,
+}
+
+// The one panel test that may run at a time. The test itself is a task that follows the
+// stream frames, so the command that starts it answers right away.
+#[derive(Default)]
+pub struct PanelTest {
+    running: Mutex<Option<Running>>,
+    next_id: AtomicU64,
+}
+
+impl PanelTest {
+    // Pad of the running test
+    pub fn running(&self) -> Option<String> {
+        let running = self.running.lock().ok()?;
+        running.as_ref().map(|running| running.pad.clone())
+    }
+
+    // Stop the running test; its task publishes the summary. Returns the pad it was on.
+    pub fn cancel(&self) -> Result<String, ValidationError> {
+        let running = self
+            .running
+            .lock()
+            .ok()
+            .and_then(|mut running| running.take());
+        let running = running.ok_or(ValidationError::PanelTestNotRunning)?;
+        let _ = running.cancel.send(());
+        Ok(running.pad)
+    }
+
+    fn finish(&self, id: u64) {
+        if let Ok(mut running) = self.running.lock() {
+            if running.as_ref().is_some_and(|running| running.id == id) {
+                *running = None;
+            }
+        }
+    }
+}
+
+// Start a test of the device pad: each sensor in turn must be pressed past the current
+// profile's threshold within `timeout`. A stopped stream is started for it and stopped
+// again when it ends.
+pub fn start(state: &AppState, timeout: Duration) -> Result<PanelTestPlan, AppError> {
+    let profiles = state.profiles_snapshot();
+    let pad = profiles.enabled_pad(None)?.id.clone();
+    let profile_name = profiles.current_profile();
+    if profile_name.is_empty() {
+        return Err(ValidationError::NoCurrentProfile.into());
+    }
+    let profile = profiles
+        .profiles
+        .get(profile_name)
+        .ok_or_else(|| ValidationError::ProfileNotFound(profile_name.to_string()))?;
+    layout::check_device(profile, state.range.sensors())?;
+
+    let mut running = state.panel_test.running.lock().unwrap();
+    if let Some(running) = &*running {
+        return Err(ValidationError::PanelTestRunning(running.pad.clone()).into());
+    }
+    // Subscribed before the stream starts, so the first frames aren't missed
+    let events = state.events.subscribe();
+    let stream_forced = !state.set_stream_enabled(true);
+    let plan = PanelTestPlan {
+        pad: pad.clone(),
+        profile: profile_name.to_string(),
+        thresholds: profile.thresholds,
+        timeout_ms: timeout.as_millis() as u64,
+        stream_forced,
+    };
+    let labels = (0..profile.thresholds.len())
+        .map(|sensor| layout::label(&profile.layout, sensor))
+        .collect();
+    let (cancel, cancelled) = oneshot::channel();
+    let id = state.panel_test.next_id.fetch_add(1, Ordering::Relaxed);
+    *running = Some(Running { id, pad, cancel });
+    tokio::spawn(run(
+        state.clone(),
+        id,
+        plan.clone(),
+        labels,
+        events,
+        timeout,
+        cancelled,
+    ));
+    Ok(plan)
+}
+
+async fn run(
+    state: AppState,
+    id: u64,
+    plan: PanelTestPlan,
+    labels: Vec<String>,
+    mut events: broadcast::Receiver<Event>,
+    timeout: Duration,
+    mut cancelled: oneshot::Receiver<()>,
+) {
+    let pad: Arc<str> = plan.pad.as_str().into();
+    let mut results = Vec::new();
+    let mut cancel = false;
+    let sensors = labels.len();
+    for (sensor, panel) in labels.into_iter().enumerate() {
+        let threshold = plan.thresholds[sensor];
+        let (passed, peak) = tokio::select! {
+            watched = watch_sensor(&mut events, &pad, sensor, threshold, timeout) => watched,
+            _ = &mut cancelled => {
+                cancel = true;
+                break;
+            }
+        };
+        let result = PanelResult {
+            sensor,
+            panel,
+            threshold,
+            passed,
+            peak,
+        };
+        state.publish(Event::PanelTestProgress {
+            pad: Arc::clone(&pad),
+            result: result.clone(),
+            next: (sensor + 1 < sensors).then_some(sensor + 1),
+        });
+        results.push(result);
+    }
+
+    if plan.stream_forced {
+        state.set_stream_enabled(false);
+    }
+    state.panel_test.finish(id);
+    let missed = (0..sensors)
+        .filter(|&sensor| results.get(sensor).is_none_or(|result| !result.passed))
+        .collect();
+    state.publish(Event::PanelTestComplete(Arc::new(PanelTestSummary {
+        plan,
+        results,
+        missed,
+        cancelled: cancel,
+    })));
+}
+
+// Follow `sensor` of `pad` until it is pressed past `threshold` and let go, or until
+// `timeout`. A press still held at the timeout passes. Returns whether it passed and the
+// highest reading seen.
+async fn watch_sensor(
+    events: &mut broadcast::Receiver<Event>,
+    pad: &str,
+    sensor: usize,
+    threshold: i32,
+    timeout: Duration,
+) -> (bool, Option<i32>) {
+    let deadline = Instant::now() + timeout;
+    let mut peak: Option<i32> = None;
+    let mut pressed = false;
+    loop {
+        match timeout_at(deadline, events.recv()).await {
+            Ok(Ok(Event::SensorFrame {
+                pad: frame_pad,
+                values,
+                ..
+            })) if *frame_pad == *pad => {
+                let Some(&value) = values.get(sensor) else {
+                    continue;
+                };
+                peak = Some(peak.map_or(value, |peak| peak.max(value)));
+                // Judged in raw units, like the device and the press detection do
+                if value >= threshold {
+                    pressed = true;
+                } else if pressed {
+                    return (true, peak);
+                }
+            }
+            Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => {}
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => return (pressed, peak),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::handle_command;
+    use crate::event::WireJson;
+    use crate::profile::{Command, Pad, Profile, Profiles};
+    use std::collections::HashMap;
+
+    fn frame(values: Sensors<i32>) -> Event {
+        Event::SensorFrame {
+            pad: "default".into(),
+            values,
+            calibrated: None,
+            rate_hz: 60,
+            wire: WireJson::default(),
+        }
+    }
+
+    fn test_state() -> AppState {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([100; 4]))]),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        AppState::with_mock_port(profiles)
+    }
+
+    async fn summary(events: &mut broadcast::Receiver<Event>) -> Arc<PanelTestSummary> {
+        loop {
+            if let Event::PanelTestComplete(summary) = events.recv().await.unwrap() {
+                return summary;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panel_test_reports_each_panel() {
+        let state = test_state();
+        let mut events = state.events.subscribe();
+        let start = || Command::StartPanelTest {
+            timeout_ms: Some(300),
+        };
+
+        let response = handle_command(start(), &state).await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(response.params.as_ref().unwrap()["stream_forced"], true);
+        assert!(state.stream_enabled());
+        let again = handle_command(start(), &state).await;
+        assert_eq!(
+            again.payload,
+            Some(serde_json::json!({ "code": "PANEL_TEST_RUNNING" }))
+        );
+
+        // Sensor 0 is pressed and let go, sensor 1 is only brushed, the rest time out
+        for values in [
+            [50, 0, 0, 0],
+            [250, 300, 0, 0],
+            [180, 0, 0, 0],
+            [0, 0, 0, 0],
+            [0, 60, 0, 0],
+        ] {
+            let _ = state.events.send(frame(values.into()));
+        }
+        let summary = summary(&mut events).await;
+        assert!(!summary.cancelled);
+        assert_eq!(summary.missed, [1, 2, 3]);
+        assert_eq!(
+            summary.results[0],
+            PanelResult {
+                sensor: 0,
+                panel: "Left".to_string(),
+                threshold: 100,
+                passed: true,
+                peak: Some(250),
+            }
+        );
+        assert_eq!(summary.results[1].peak, Some(60));
+        assert_eq!(summary.results[3].peak, None);
+        // Stopped again, as it was before the test
+        assert!(!state.stream_enabled());
+        assert_eq!(state.panel_test.running(), None);
+
+        let progress: Vec<(usize, bool)> = state
+            .history
+            .since(0)
+            .0
+            .iter()
+            .filter_map(|(_, event)| match event {
+                Event::PanelTestProgress { result, .. } => Some((result.sensor, result.passed)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(progress, [(0, true), (1, false), (2, false), (3, false)]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_panel_test_keeps_a_running_stream() {
+        let state = test_state();
+        state.set_stream_enabled(true);
+        let mut events = state.events.subscribe();
+
+        let response = handle_command(Command::StartPanelTest { timeout_ms: None }, &state).await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(response.params.as_ref().unwrap()["stream_forced"], false);
+
+        let response = handle_command(Command::CancelPanelTest, &state).await;
+        assert!(response.success, "{}", response.message);
+        let summary = summary(&mut events).await;
+        assert!(summary.cancelled);
+        assert!(summary.results.is_empty());
+        assert_eq!(summary.missed, [0, 1, 2, 3]);
+        assert!(state.stream_enabled());
+
+        let response = handle_command(Command::CancelPanelTest, &state).await;
+        assert_eq!(
+            response.payload,
+            Some(serde_json::json!({ "code": "PANEL_TEST_NOT_RUNNING" }))
+        );
+    }
+}
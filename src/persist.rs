@@ -0,0 +1,411 @@
+use crate::error::StorageError;
+use crate::event::Event;
+use crate::journal::JournalKind;
+use crate::profile::{save_profiles_to, Profiles};
+use crate::state::AppState;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+// What SaveProfiles wrote
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SaveReport {
+    pub path: String,
+    pub bytes: usize,
+}
+
+type SaveReply = oneshot::Sender<Result<SaveReport, StorageError>>;
+
+// The autosave switch of SetAutosave and the explicit saves of SaveProfiles, shared by
+// the commands and the persistence task. Autosave is on at every start; the switch
+// isn't saved.
+pub struct SaveControl {
+    autosave: watch::Sender<bool>,
+    // Generation of the snapshot last written, see AppState::profiles_generation
+    saved: AtomicU64,
+    // Set while a persistence task runs
+    saver: Mutex<Option<mpsc::Sender<SaveReply>>>,
+}
+
+impl SaveControl {
+    pub fn new() -> Self {
+        Self {
+            autosave: watch::Sender::new(true),
+            saved: AtomicU64::new(0),
+            saver: Mutex::new(None),
+        }
+    }
+
+    pub fn autosave(&self) -> bool {
+        *self.autosave.borrow()
+    }
+
+    // Switch autosave, returning whether it was on. Switching it back on writes the
+    // changes made meanwhile.
+    pub fn set_autosave(&self, enabled: bool) -> bool {
+        self.autosave.send_replace(enabled)
+    }
+
+    // Changes published up to `generation` that aren't on disk
+    pub fn pending(&self, generation: u64) -> u64 {
+        generation.saturating_sub(self.saved.load(Ordering::Relaxed))
+    }
+
+    // Write the current profiles now, whether or not autosave is on, after any save in
+    // progress
+    pub async fn save_now(&self) -> Result<SaveReport, StorageError> {
+        let saver = self.saver.lock().ok().and_then(|saver| saver.clone());
+        let not_saved = || {
+            StorageError::Write(std::io::Error::other(
+                "this server doesn't save the profiles",
+            ))
+        };
+        let saver = saver.ok_or_else(not_saved)?;
+        let (reply, result) = oneshot::channel();
+        saver.send(reply).await.map_err(|_| not_saved())?;
+        result.await.map_err(|_| not_saved())?
+    }
+}
+
+impl Default for SaveControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Saves every published profiles snapshot to disk from a single background task, so
+// commands never wait on the file system and saves can't be reordered: the file always
+// ends up holding the latest snapshot. Bursts of changes are written once: a save writes
+// whatever is newest when it starts, and if more changes arrived while it was writing it
+// writes again right away, so only the first and the last state of a burst hit the disk.
+// SaveProfiles goes through the same task, so it never races a background save.
+pub struct Persistence {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Persistence {
+    pub fn spawn(state: AppState, path: PathBuf) -> Self {
+        let (stop, stop_rx) = oneshot::channel();
+        // Subscribe before spawning so a change published right away isn't missed. The
+        // snapshot seen now is on disk already; holding it keeps the generation in step.
+        let mut changes = state.profiles.subscribe();
+        {
+            let _current = changes.borrow_and_update();
+            let generation = state.profiles_generation.load(Ordering::Relaxed);
+            state.saves.saved.store(generation, Ordering::Relaxed);
+        }
+        let (saver, requests) = mpsc::channel(8);
+        if let Ok(mut current) = state.saves.saver.lock() {
+            *current = Some(saver);
+        }
+        let autosave = state.saves.autosave.subscribe();
+        let handle = tokio::spawn(persist_task(
+            state, changes, autosave, requests, path, stop_rx,
+        ));
+        Self { stop, handle }
+    }
+
+    // Write any change not saved yet and stop. With autosave off the changes are
+    // discarded, as the user chose, with a warning.
+    pub async fn shutdown(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.await;
+    }
+}
+
+async fn persist_task(
+    state: AppState,
+    mut changes: watch::Receiver<Arc<Profiles>>,
+    mut autosave: watch::Receiver<bool>,
+    mut requests: mpsc::Receiver<SaveReply>,
+    path: PathBuf,
+    mut stop: oneshot::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            changed = changes.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                if !*autosave.borrow() {
+                    continue;
+                }
+            }
+            changed = autosave.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                if !*autosave.borrow_and_update() {
+                    continue;
+                }
+            }
+            Some(reply) = requests.recv() => {
+                let _ = reply.send(save_now(&state, &mut changes, &path).await);
+                continue;
+            }
+            _ = &mut stop => {
+                if *autosave.borrow() {
+                    flush(&state, &mut changes, &path).await;
+                } else {
+                    discard(&state);
+                }
+                if let Ok(mut saver) = state.saves.saver.lock() {
+                    *saver = None;
+                }
+                return;
+            }
+        }
+        flush(&state, &mut changes, &path).await;
+    }
+}
+
+// The latest snapshot and its generation
+fn take_snapshot(
+    state: &AppState,
+    changes: &mut watch::Receiver<Arc<Profiles>>,
+) -> (Arc<Profiles>, u64) {
+    let profiles = changes.borrow_and_update();
+    (
+        profiles.clone(),
+        state.profiles_generation.load(Ordering::Relaxed),
+    )
+}
+
+// Write the latest snapshot until no change is left unwritten. Changes published in
+// between two written snapshots are counted as skipped. A failed save is retried with
+// the next change.
+async fn flush(state: &AppState, changes: &mut watch::Receiver<Arc<Profiles>>, path: &Path) {
+    loop {
+        let (profiles, generation) = take_snapshot(state, changes);
+        if state.saves.pending(generation) == 0 {
+            return;
+        }
+        if let Err(e) = save(state, path, profiles, generation).await {
+            state.publish(Event::error(e.into()));
+            return;
+        }
+    }
+}
+
+// SaveProfiles: write even if nothing changed, and answer the command instead of
+// broadcasting a failure
+async fn save_now(
+    state: &AppState,
+    changes: &mut watch::Receiver<Arc<Profiles>>,
+    path: &Path,
+) -> Result<SaveReport, StorageError> {
+    let (profiles, generation) = take_snapshot(state, changes);
+    let bytes = save(state, path, profiles, generation).await?;
+    Ok(SaveReport {
+        path: std::path::absolute(path)
+            .unwrap_or_else(|_| path.to_path_buf())
+            .display()
+            .to_string(),
+        bytes,
+    })
+}
+
+fn discard(state: &AppState) {
+    let pending = state
+        .saves
+        .pending(state.profiles_generation.load(Ordering::Relaxed));
+    if pending > 0 {
+        eprintln!(
+            "Warning: autosave is off, discarding {} unsaved profile change(s)",
+            pending
+        );
+    }
+}
+
+// The command already succeeded in memory, so a failed background save is reported to
+// clients as a separate SAVE_FAILED event. The next change retries with the full
+// snapshot.
+async fn save(
+    state: &AppState,
+    path: &Path,
+    profiles: Arc<Profiles>,
+    generation: u64,
+) -> Result<usize, StorageError> {
+    let result = save_profiles_to(path, &profiles).await;
+    state.metrics.record_save(result.is_ok());
+    match &result {
+        Ok(_) => {
+            let saved = state.saves.saved.swap(generation, Ordering::Relaxed);
+            state
+                .metrics
+                .record_saves_skipped(generation.saturating_sub(saved + 1));
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            state.journal.record(JournalKind::SaveFailed, e.to_string());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::handle_command;
+    use crate::profile::{load_profiles_from, Command, Profile};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fsr-rs-persist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_commands_are_all_saved() {
+        let path = temp_path("concurrent.json");
+        let state = AppState::with_mock_port(Profiles::default());
+        let persistence = Persistence::spawn(state.clone(), path.clone());
+
+        let tasks: Vec<_> = (0..50)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let command = Command::AddProfile {
+                        name: format!("Profile{}", i),
+                        thresholds: [i, i, i, i].into(),
+                        layout: None,
+                    };
+                    handle_command(command, &state).await
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert!(task.await.unwrap().success);
+        }
+        persistence.shutdown().await;
+
+        // No update lost in memory, and the file holds the final snapshot
+        let saved = load_profiles_from(&path).await.unwrap();
+        assert_eq!(saved.profiles.len(), 50);
+        assert_eq!(saved, *state.profiles_snapshot());
+        assert!(state.metrics.snapshot().saves >= 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_burst_of_changes_is_collapsed() {
+        let path = temp_path("burst.json");
+        let state = AppState::with_mock_port(Profiles::default());
+        let persistence = Persistence::spawn(state.clone(), path.clone());
+
+        // Let the saver run now and then, so some changes land while it is writing
+        for i in 0..100 {
+            let mut profiles = Profiles::clone(&state.profiles_snapshot());
+            profiles
+                .profiles
+                .insert(format!("Profile{}", i), Profile::new([i; 4]));
+            assert!(state.publish_profiles(Arc::new(profiles)));
+            if i % 10 == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+        persistence.shutdown().await;
+
+        let saved = load_profiles_from(&path).await.unwrap();
+        assert_eq!(saved, *state.profiles_snapshot());
+        let stats = state.metrics.snapshot();
+        // Every change was either written or folded into a later write
+        assert_eq!(stats.saves + stats.saves_skipped, 100);
+        assert!(stats.saves <= 20, "{} saves", stats.saves);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_save_failure_is_reported_as_event() {
+        let path = temp_path("missing-dir").join("profiles.json");
+        let state = AppState::with_mock_port(Profiles::default());
+        let mut events = state.events.subscribe();
+        let persistence = Persistence::spawn(state.clone(), path);
+
+        // The command itself succeeds without waiting for the disk
+        let response = handle_command(
+            Command::AddProfile {
+                name: "Profile1".to_string(),
+                thresholds: [1, 2, 3, 4].into(),
+                layout: None,
+            },
+            &state,
+        )
+        .await;
+        assert!(response.success);
+
+        let event = tokio::time::timeout(std::time::Duration::from_secs(1), events.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .to_response();
+        assert!(!event.success);
+        assert_eq!(event.response_type.as_deref(), Some("error"));
+        assert_eq!(
+            event.payload,
+            Some(serde_json::json!({ "code": "SAVE_FAILED" }))
+        );
+        assert_eq!(state.metrics.snapshot().save_failures, 1);
+        persistence.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_autosave_off_waits_for_save_profiles() {
+        let path = temp_path("autosave.json");
+        let _ = std::fs::remove_file(&path);
+        let state = AppState::with_mock_port(Profiles::default());
+        let persistence = Persistence::spawn(state.clone(), path.clone());
+
+        let response = handle_command(Command::SetAutosave { enabled: false }, &state).await;
+        assert!(response.success);
+        assert_eq!(response.pending_changes, Some(0));
+        assert!(!response.unsaved_changes);
+
+        for i in 0..3 {
+            let response = handle_command(
+                Command::AddProfile {
+                    name: format!("Profile{}", i),
+                    thresholds: [i; 4].into(),
+                    layout: None,
+                },
+                &state,
+            )
+            .await;
+            assert!(response.unsaved_changes);
+            assert_eq!(response.pending_changes, Some(i as u64 + 1));
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!path.exists());
+
+        let response = handle_command(Command::SaveProfiles, &state).await;
+        assert!(response.success, "{}", response.message);
+        assert_eq!(response.pending_changes, Some(0));
+        let params = response.params.unwrap();
+        assert!(params["path"].as_str().unwrap().ends_with("autosave.json"));
+        assert_eq!(params["bytes"], std::fs::metadata(&path).unwrap().len());
+        assert_eq!(load_profiles_from(&path).await.unwrap().profiles.len(), 3);
+
+        // Changes made with autosave off are dropped at shutdown, not written
+        let response = handle_command(
+            Command::RemoveProfile {
+                name: "Profile2".to_string(),
+                reassign_to: None,
+                confirm: false,
+            },
+            &state,
+        )
+        .await;
+        assert!(response.success, "{}", response.message);
+        persistence.shutdown().await;
+        assert_eq!(load_profiles_from(&path).await.unwrap().profiles.len(), 3);
+
+        // Without a persistence task there is nothing to save with
+        let response = handle_command(Command::SaveProfiles, &state).await;
+        assert_eq!(response.message_code.as_deref(), Some("SAVE_FAILED"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,351 @@
+use crate::audit::Origin;
+use crate::autodetect::resolve_port;
+use crate::capture::{default_capture_dir, SerialCapture, CAPTURE_MAX_BYTES};
+use crate::commands::{handle_envelope, parse_envelope, Envelope};
+use crate::config::Args;
+use crate::event::{Event, Subscription};
+use crate::idempotency::{IdempotencyCache, IDEMPOTENCY_CAPACITY};
+use crate::info::{DeviceKind, ServerInfo};
+use crate::journal::{default_journal_path, Journal, JOURNAL_MAX_BYTES};
+use crate::persist::Persistence;
+use crate::profile::{load_profiles_or_default, Command, Response, PROFILES_FILE};
+use crate::repair;
+use crate::scale::{self, Scale};
+use crate::serial::open_device;
+use crate::serial_queue::SerialQueue;
+use crate::sink::{self, SinkRegistry};
+use crate::snapshot::SnapshotStore;
+use crate::spawn_sensor_stream;
+use crate::state::AppState;
+use crate::threshold_log::{ThresholdWriteLog, WRITE_LOG_CAPACITY};
+use crate::webhook;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, Mutex};
+
+// Reads newline-delimited `Command` JSON from `input` and writes one `Response` JSON
+// line per command to `output` until EOF. Broadcast events (e.g. sensor frames while
+// the stream is running) are written to `output` as well, tagged by response_type.
+pub async fn run_pipe<R, W>(
+    input: R,
+    mut output: W,
+    state: AppState,
+    watchdog: Option<Duration>,
+) -> std::io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // A single writer keeps lines from the command loop and the broadcasts from interleaving
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<String>();
+
+    let mut rx = state.events.subscribe();
+    let metrics = state.metrics.clone();
+    let broadcast_out = out_tx.clone();
+    let subscription = Arc::new(std::sync::Mutex::new(Subscription::default()));
+    let forward_subscription = subscription.clone();
+    let forward_state = state.clone();
+    let forward_task = tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let (wanted, scale) = forward_subscription
+                        .lock()
+                        .map_or((true, Scale::Raw), |subscription| {
+                            (subscription.wants(&event), subscription.scale())
+                        });
+                    if !wanted {
+                        continue;
+                    }
+                    let Some(line) = event_line(&forward_state, &event, scale) else {
+                        continue;
+                    };
+                    if broadcast_out.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    metrics.record_lagged(skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let stream_task = spawn_sensor_stream(&state, watchdog);
+    let aggregate_task = crate::aggregate::spawn(&state);
+
+    let writer = async move {
+        while let Some(line) = out_rx.recv().await {
+            output.write_all(line.as_bytes()).await?;
+            output.write_all(b"\n").await?;
+            output.flush().await?;
+        }
+        Ok::<(), std::io::Error>(())
+    };
+
+    let reader = async move {
+        let mut lines = input.lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match parse_envelope(&line) {
+                Ok(Envelope {
+                    command: Command::Subscribe { topics, scale },
+                    ..
+                }) => {
+                    let updated = Subscription::new(topics, scale);
+                    let response = updated.to_response(None);
+                    if let Ok(mut current) = subscription.lock() {
+                        *current = updated;
+                    }
+                    response
+                }
+                Ok(envelope) => match state.read_only.check(&envelope.command, false) {
+                    Ok(()) => {
+                        let envelope = Envelope {
+                            origin: Origin::Pipe,
+                            ..envelope
+                        };
+                        handle_envelope(envelope, &state).await
+                    }
+                    Err(rejection) => rejection.to_response(),
+                },
+                Err(e) => e.to_response(),
+            };
+            let Some(line) = to_line(&response) else {
+                continue;
+            };
+            if out_tx.send(line).is_err() {
+                break;
+            }
+        }
+
+        // EOF: stop producing events so the writer can drain and finish
+        stream_task.abort();
+        aggregate_task.abort();
+        forward_task.abort();
+        Ok::<(), std::io::Error>(())
+    };
+
+    let (read_result, write_result) = tokio::join!(reader, writer);
+    read_result?;
+    write_result
+}
+
+fn to_line(response: &Response) -> Option<String> {
+    serde_json::to_string(response)
+        .map_err(|e| eprintln!("Failed to serialize response: {}", e))
+        .ok()
+}
+
+// A broadcast event as an output line, its stream frames in the subscribed scale
+fn event_line(state: &AppState, event: &Event, scale: Scale) -> Option<String> {
+    if scale == Scale::Raw {
+        return to_line(&event.to_response());
+    }
+    let mut line = serde_json::to_value(event.to_response())
+        .map_err(|e| eprintln!("Failed to serialize response: {}", e))
+        .ok()?;
+    let max = state.range.bound().max;
+    scale::rescale(&mut line, event, scale, &state.profiles.borrow(), max);
+    Some(line.to_string())
+}
+
+// `fsr-rs pipe`: drive the command layer over stdin/stdout
+pub async fn run(args: &mut Args) -> i32 {
+    if let Err(e) = resolve_port(args).await {
+        eprintln!("{}", e);
+        return 1;
+    }
+    let (serial_port, trace_sink) = match open_device(args) {
+        Ok((port, sink)) => (Arc::new(Mutex::new(port)), sink),
+        Err(e) => {
+            eprintln!("Failed to open serial port {}: {}", args.com_port, e);
+            return 1;
+        }
+    };
+    let read_only = args.read_only_policy();
+    let (mut profiles, load_failure) = load_profiles_or_default(!read_only.is_enabled()).await;
+    // Saved with the next change, like every other change in pipe mode
+    let repairs = repair::repair(&mut profiles);
+    repair::report(&repairs);
+    for warning in args.quotas().warnings(&profiles) {
+        eprintln!("Warning: {}", warning);
+    }
+    let journal = Arc::new(Journal::spawn(default_journal_path(), JOURNAL_MAX_BYTES));
+    let state = AppState {
+        sinks: Arc::new(SinkRegistry::new(webhook::sinks(
+            &args.webhooks,
+            &args.pad_name,
+            &profiles,
+        ))),
+        read_only,
+        threshold_writes: Arc::new(ThresholdWriteLog::new(WRITE_LOG_CAPACITY, journal.clone())),
+        journal,
+        // No listener in pipe mode, so no host or port
+        info: Arc::new(ServerInfo {
+            quotas: args.quotas(),
+            repairs_performed: repairs,
+            pad_name: args.pad_name.clone(),
+            ..ServerInfo::new(
+                String::new(),
+                0,
+                if args.mock_serial {
+                    DeviceKind::Mock
+                } else {
+                    DeviceKind::Serial
+                },
+                (!args.mock_serial).then(|| args.com_port.clone()),
+            )
+        }),
+        capture: Arc::new(SerialCapture::new(
+            trace_sink,
+            default_capture_dir(),
+            CAPTURE_MAX_BYTES,
+        )),
+        serial_queue: Arc::new(SerialQueue::new(args.serial_queue_limit)),
+        snapshots: Arc::new(SnapshotStore::new(
+            args.snapshot_dir(),
+            args.snapshot_retention,
+        )),
+        idempotency: Arc::new(IdempotencyCache::new(
+            args.idempotency_window(),
+            IDEMPOTENCY_CAPACITY,
+        )),
+        load_failure: Arc::new(std::sync::Mutex::new(load_failure)),
+        ..AppState::new(profiles, serial_port)
+    };
+
+    crate::supervisor::install_panic_hook(state.journal.clone());
+
+    if !state.sinks.is_empty() {
+        tokio::spawn(sink::sink_task(
+            state.sinks.clone(),
+            state.events.subscribe(),
+        ));
+    }
+
+    let persistence = (!state.read_only.is_enabled())
+        .then(|| Persistence::spawn(state.clone(), PathBuf::from(PROFILES_FILE)));
+
+    let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+    let result = run_pipe(stdin, tokio::io::stdout(), state, args.stream_watchdog()).await;
+    if let Some(persistence) = persistence {
+        persistence.shutdown().await;
+    }
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Pipe mode failed: {}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{ReadOnlyMode, ReadOnlyPolicy};
+    use crate::profile::{Pad, Profile, Profiles};
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, BufReader};
+
+    fn test_profiles() -> Profiles {
+        Profiles {
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([100, 200, 300, 400]))]),
+            default_profile: "Profile1".to_string(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        }
+    }
+
+    fn parse_lines(output: &str) -> Vec<Response> {
+        output
+            .lines()
+            .map(|line| serde_json::from_str::<Response>(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_pipe_one_response_per_command() {
+        let input = b"\"GetCurrentThresholds\"\nnot json\n\n\"GetWebhookStatus\"\n";
+        let mut output = Vec::new();
+        let state = AppState::with_mock_port(test_profiles());
+
+        run_pipe(BufReader::new(&input[..]), &mut output, state, None)
+            .await
+            .unwrap();
+
+        let responses = parse_lines(&String::from_utf8(output).unwrap());
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].success);
+        assert!(responses[0].message.contains("device synchronized"));
+        assert!(!responses[1].success);
+        assert_eq!(responses[1].message_code.as_deref(), Some("MALFORMED_JSON"));
+        assert!(responses[2].success);
+        assert!(responses[2].message.contains("0 webhook(s)"));
+    }
+
+    #[tokio::test]
+    async fn test_pipe_forwards_sensor_stream() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (mut input_writer, input_reader) = tokio::io::duplex(1024);
+        let state = AppState::with_mock_port(test_profiles());
+
+        let handle = tokio::spawn(run_pipe(BufReader::new(input_reader), server, state, None));
+
+        input_writer
+            .write_all(b"\"StartSensorStream\"\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(input_writer); // EOF
+
+        handle.await.unwrap().unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+
+        let responses = parse_lines(&output);
+        assert!(responses
+            .iter()
+            .any(|r| r.message == "Sensor stream started"));
+        assert!(responses
+            .iter()
+            .any(|r| r.response_type.as_deref() == Some("sensor_stream")
+                && r.sensor_values.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_pipe_read_only_rejects_mutations() {
+        let input = b"{\"ChangeProfile\":{\"name\":\"Profile1\"}}\n\"GetCurrentThresholds\"\n";
+        let mut output = Vec::new();
+        let state = AppState {
+            read_only: ReadOnlyPolicy {
+                mode: ReadOnlyMode::Strict,
+                allow_stream: false,
+                mirror: false,
+            },
+            ..AppState::with_mock_port(test_profiles())
+        };
+
+        run_pipe(BufReader::new(&input[..]), &mut output, state.clone(), None)
+            .await
+            .unwrap();
+
+        let responses = parse_lines(&String::from_utf8(output).unwrap());
+        assert_eq!(responses.len(), 2);
+        assert!(!responses[0].success);
+        assert_eq!(
+            responses[0].payload,
+            Some(serde_json::json!({ "code": "READ_ONLY_MODE" }))
+        );
+        assert!(responses[1].success);
+        assert_eq!(*state.profiles_snapshot(), test_profiles());
+    }
+}
@@ -0,0 +1,291 @@
+use serialport::SerialPort;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Whether a pipelined sensor request is waiting for its answer on the device
+// (--pipelined-reads). Shared by read_sensor_values_pipelined, which leaves the request
+// in flight, and the PipelinedPort it is left on, which flushes it before anyone else
+// writes. Only touched with the port's lock held.
+#[derive(Debug, Default)]
+pub struct SerialPipeline {
+    in_flight: AtomicBool,
+}
+
+impl SerialPipeline {
+    pub fn set_in_flight(&self) {
+        self.in_flight.store(true, Ordering::Relaxed);
+    }
+
+    // Whether a request was in flight; it isn't any more
+    pub fn take_in_flight(&self) -> bool {
+        self.in_flight.swap(false, Ordering::Relaxed)
+    }
+}
+
+// Wrap a port so every write first consumes the answer to a pipelined sensor request
+// still in flight. Threshold commands then get the line to themselves, as in the
+// simple mode, and the next pipelined read sends a fresh request.
+pub fn with_pipeline(
+    port: Box<dyn SerialPort>,
+    pipeline: Arc<SerialPipeline>,
+) -> Box<dyn SerialPort> {
+    Box::new(PipelinedPort {
+        inner: port,
+        pipeline,
+    })
+}
+
+pub struct PipelinedPort {
+    inner: Box<dyn SerialPort>,
+    pipeline: Arc<SerialPipeline>,
+}
+
+impl PipelinedPort {
+    // Read and drop the pending sensor line. If it doesn't come within the port's
+    // timeout it is left to the response skipping of the next exchange.
+    fn flush_in_flight(&mut self) -> io::Result<()> {
+        if !self.pipeline.take_in_flight() {
+            return Ok(());
+        }
+        let deadline = Instant::now() + self.inner.timeout().max(Duration::from_millis(1));
+        let mut buf = [0u8; 32];
+        while Instant::now() < deadline {
+            match self.inner.read(&mut buf) {
+                // Nothing else is outstanding, so the line is all there is to read
+                Ok(n) if buf[..n].contains(&b'\n') => return Ok(()),
+                Ok(0) => std::thread::yield_now(),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for PipelinedPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.flush_in_flight()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Read for PipelinedPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl SerialPort for PipelinedPort {
+    fn name(&self) -> Option<String> {
+        self.inner.name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.inner.baud_rate()
+    }
+
+    fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+        self.inner.data_bits()
+    }
+
+    fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+        self.inner.flow_control()
+    }
+
+    fn parity(&self) -> serialport::Result<serialport::Parity> {
+        self.inner.parity()
+    }
+
+    fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+        self.inner.stop_bits()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: serialport::DataBits) -> serialport::Result<()> {
+        self.inner.set_data_bits(data_bits)
+    }
+
+    fn set_flow_control(
+        &mut self,
+        flow_control: serialport::FlowControl,
+    ) -> serialport::Result<()> {
+        self.inner.set_flow_control(flow_control)
+    }
+
+    fn set_parity(&mut self, parity: serialport::Parity) -> serialport::Result<()> {
+        self.inner.set_parity(parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: serialport::StopBits) -> serialport::Result<()> {
+        self.inner.set_stop_bits(stop_bits)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_request_to_send(level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_data_terminal_ready(level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.inner.read_clear_to_send()
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.inner.read_data_set_ready()
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.inner.read_ring_indicator()
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.inner.read_carrier_detect()
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_read()
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_write()
+    }
+
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        self.inner.clear(buffer_to_clear)
+    }
+
+    // A clone shares the device but not this port's lock, so it gets no pipeline
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        self.inner.try_clone()
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.inner.set_break()
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.inner.clear_break()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::{
+        get_current_thresholds_from_device, read_sensor_values, read_sensor_values_pipelined,
+        set_threshold, MockSerialPort,
+    };
+    use crate::threshold_log::{ThresholdWriteLog, WriteReason};
+    use tokio::sync::Mutex;
+
+    fn pipelined(mock: MockSerialPort) -> (Arc<Mutex<Box<dyn SerialPort>>>, Arc<SerialPipeline>) {
+        let pipeline = Arc::new(SerialPipeline::default());
+        let port = with_pipeline(Box::new(mock), pipeline.clone());
+        (Arc::new(Mutex::new(port)), pipeline)
+    }
+
+    #[tokio::test]
+    async fn test_threshold_exchange_flushes_pipelined_read() {
+        let (port, pipeline) = pipelined(MockSerialPort::new([100, 200, 300, 400]));
+        for _ in 0..3 {
+            let values = read_sensor_values_pipelined(&port, &pipeline)
+                .await
+                .unwrap();
+            assert!(values.iter().all(|value| (0..=1023).contains(value)));
+        }
+
+        // The "v" line in flight is not taken for the threshold ack
+        let log = ThresholdWriteLog::default();
+        let logged = log.reason(WriteReason::UserCommand);
+        set_threshold(&port, 2, Some(300), 345, logged)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_current_thresholds_from_device(&port).await.unwrap(),
+            [100, 200, 345, 400]
+        );
+        // Nothing left over; the next pipelined read starts a fresh request
+        assert_eq!(port.lock().await.bytes_to_read().unwrap(), 0);
+        read_sensor_values_pipelined(&port, &pipeline)
+            .await
+            .unwrap();
+        read_sensor_values(&port).await.unwrap();
+        read_sensor_values_pipelined(&port, &pipeline)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_lines_are_skipped() {
+        let mut mock = MockSerialPort::new([100, 200, 300, 400]);
+        // An ack that arrived after its exchange timed out
+        mock.write_all(b"0 100\n").unwrap();
+        let port: Arc<Mutex<Box<dyn SerialPort>>> = Arc::new(Mutex::new(Box::new(mock)));
+        read_sensor_values(&port).await.unwrap();
+        assert_eq!(port.lock().await.bytes_to_read().unwrap(), 0);
+
+        // A sensor line no flush caught
+        port.lock().await.write_all(b"v\n").unwrap();
+        assert_eq!(
+            get_current_thresholds_from_device(&port).await.unwrap(),
+            [100, 200, 300, 400]
+        );
+    }
+
+    // Reads at the rate the stream could reach against a device that takes 8ms to answer,
+    // with 8ms of host work per frame. Run with
+    // `cargo test --release bench_pipelined_reads -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_pipelined_reads() {
+        const READS: u32 = 100;
+        let device = Duration::from_millis(8);
+        let host = Duration::from_millis(8);
+
+        let simple: Arc<Mutex<Box<dyn SerialPort>>> = Arc::new(Mutex::new(Box::new(
+            MockSerialPort::new([0; 4]).with_response_delay(device),
+        )));
+        let started = Instant::now();
+        for _ in 0..READS {
+            read_sensor_values(&simple).await.unwrap();
+            std::thread::sleep(host);
+        }
+        let simple_hz = f64::from(READS) / started.elapsed().as_secs_f64();
+
+        let (port, pipeline) = pipelined(MockSerialPort::new([0; 4]).with_response_delay(device));
+        let started = Instant::now();
+        for _ in 0..READS {
+            read_sensor_values_pipelined(&port, &pipeline)
+                .await
+                .unwrap();
+            std::thread::sleep(host);
+        }
+        let pipelined_hz = f64::from(READS) / started.elapsed().as_secs_f64();
+
+        println!(
+            "{} reads, {:?} device latency: {:.1} Hz simple, {:.1} Hz pipelined",
+            READS, device, simple_hz, pipelined_hz
+        );
+        assert!(pipelined_hz > simple_hz * 1.5);
+    }
+}
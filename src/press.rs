@@ -0,0 +1,181 @@
+use crate::error::ValidationError;
+use crate::profile::Profile;
+use crate::sensors::Sensors;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+// How the press detection judges the sensors of a profile, stored with the profile. The
+// device only knows the thresholds; this is the server's own reading of the stream.
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+pub struct PressParameters {
+    // A pressed sensor is released below its threshold minus this, raw device units
+    pub hysteresis: Sensors<i32>,
+    // How long a sensor has to read pressed, or released, before the change counts
+    pub debounce_ms: u32,
+}
+
+impl PressParameters {
+    // A profile without hysteresis has 0 for each of its sensors
+    pub fn of(profile: &Profile) -> Self {
+        let hysteresis = match profile.hysteresis.is_empty() {
+            true => Sensors::filled(profile.thresholds.len(), 0),
+            false => profile.hysteresis,
+        };
+        Self {
+            hysteresis,
+            debounce_ms: profile.debounce_ms,
+        }
+    }
+}
+
+// A hysteresis between 0 and the threshold of its sensor, for each sensor of the profile
+pub fn check_hysteresis(
+    hysteresis: Sensors<i32>,
+    thresholds: Sensors<i32>,
+) -> Result<(), ValidationError> {
+    if hysteresis.len() != thresholds.len() {
+        return Err(ValidationError::ThresholdCount {
+            expected: thresholds.len(),
+            got: hysteresis.len(),
+        });
+    }
+    for (sensor, (&hysteresis, &threshold)) in hysteresis.iter().zip(&thresholds).enumerate() {
+        if !(0..=threshold).contains(&hysteresis) {
+            return Err(ValidationError::InvalidHysteresis {
+                sensor,
+                hysteresis,
+                threshold,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Which sensors of a reading are pressed, carried from one reading to the next
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PressState {
+    pub pressed: Sensors<bool>,
+    // Whether `pressed` differs from the reading before
+    pub changed: bool,
+    // Since when a sensor reads the other way than `pressed`, waiting out the debounce
+    pending: Sensors<Option<Instant>>,
+}
+
+impl PressState {
+    // The state after a reading at `at`; nothing is pressed without thresholds. Presses are
+    // judged in raw units, like the device does with the thresholds.
+    pub fn next(
+        &self,
+        values: Sensors<i32>,
+        thresholds: Option<Sensors<i32>>,
+        parameters: PressParameters,
+        at: Instant,
+    ) -> Self {
+        let Some(thresholds) = thresholds else {
+            return Self {
+                changed: self.pressed.contains(&true),
+                ..Self::default()
+            };
+        };
+        let debounce = Duration::from_millis(parameters.debounce_ms.into());
+        let sensors = values.len().min(thresholds.len());
+        let mut next = Self {
+            pressed: Sensors::filled(sensors, false),
+            changed: false,
+            pending: Sensors::filled(sensors, None),
+        };
+        for sensor in 0..sensors {
+            let was = self.is_pressed(sensor);
+            // A threshold lowered below the hysteresis releases at 0
+            let hysteresis = parameters.hysteresis.get(sensor).copied().unwrap_or(0);
+            let hysteresis = hysteresis.clamp(0, thresholds[sensor].max(0));
+            let level = match was {
+                true => thresholds[sensor] - hysteresis,
+                false => thresholds[sensor],
+            };
+            let reads = values[sensor] >= level;
+            if reads == was {
+                next.pressed[sensor] = was;
+                continue;
+            }
+            let since = self.pending.get(sensor).copied().flatten().unwrap_or(at);
+            if at.saturating_duration_since(since) >= debounce {
+                next.pressed[sensor] = reads;
+            } else {
+                next.pressed[sensor] = was;
+                next.pending[sensor] = Some(since);
+            }
+        }
+        let compared = sensors.max(self.pressed.len());
+        next.changed =
+            (0..compared).any(|sensor| next.is_pressed(sensor) != self.is_pressed(sensor));
+        next
+    }
+
+    pub fn any(&self) -> bool {
+        self.pressed.contains(&true)
+    }
+
+    // Whether `sensor` is pressed; false past the sensors of the reading
+    fn is_pressed(&self, sensor: usize) -> bool {
+        self.pressed.get(sensor).copied().unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hysteresis_and_debounce() {
+        let thresholds = Some(Sensors::from([400; 4]));
+        let start = Instant::now();
+        let ms = |ms: u64| start + Duration::from_millis(ms);
+        let parameters = PressParameters {
+            hysteresis: [40, 0, 0, 0].into(),
+            debounce_ms: 0,
+        };
+        let state =
+            PressState::default().next([400, 0, 0, 0].into(), thresholds, parameters, ms(0));
+        assert!(state.changed && state.pressed[0]);
+        // Held within the hysteresis
+        let state = state.next([370, 0, 0, 0].into(), thresholds, parameters, ms(10));
+        assert!(!state.changed && state.pressed[0]);
+        let state = state.next([359, 0, 0, 0].into(), thresholds, parameters, ms(20));
+        assert!(state.changed && !state.any());
+
+        // A 20ms debounce ignores a bounce shorter than that
+        let parameters = PressParameters {
+            debounce_ms: 20,
+            ..parameters
+        };
+        let state = state.next([450, 0, 0, 0].into(), thresholds, parameters, ms(30));
+        assert!(!state.any());
+        let state = state.next([0; 4].into(), thresholds, parameters, ms(40));
+        let state = state.next([450, 0, 0, 0].into(), thresholds, parameters, ms(50));
+        assert!(!state.any());
+        let state = state.next([450, 0, 0, 0].into(), thresholds, parameters, ms(70));
+        assert!(state.changed && state.pressed[0]);
+
+        // Without a profile nothing is pressed
+        let state = state.next([500; 4].into(), None, parameters, ms(80));
+        assert!(state.changed && !state.any());
+    }
+
+    #[test]
+    fn test_hysteresis_never_exceeds_the_threshold() {
+        assert!(check_hysteresis([40, 5, 0, 400].into(), [400; 4].into()).is_ok());
+        assert!(matches!(
+            check_hysteresis([0, 0, 401, 0].into(), [400; 4].into()),
+            Err(ValidationError::InvalidHysteresis { sensor: 2, .. })
+        ));
+        assert!(check_hysteresis([-1, 0, 0, 0].into(), [400; 4].into()).is_err());
+        assert!(matches!(
+            check_hysteresis([0; 4].into(), [400; 5].into()),
+            Err(ValidationError::ThresholdCount {
+                expected: 5,
+                got: 4
+            })
+        ));
+    }
+}
@@ -1,10 +1,111 @@
+use crate::auth::Role;
+use crate::devices::DeviceIdentity;
+use crate::error::{StorageError, ValidationError};
+use crate::event::Topic;
+use crate::layout::{self, DEFAULT_LAYOUT};
+use crate::scale::Scale;
+use crate::schedule::Schedule;
+use crate::sensors::{Sensors, MAX_SENSORS};
+use crate::temporary::TemporaryProfile;
+use serde::ser::SerializeStruct;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+
+pub mod import;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Profile {
-    pub thresholds: [i32; 4],
+    // One per sensor of the layout
+    pub thresholds: Sensors<i32>,
+    // Panel arrangement the thresholds are for, see layout.rs
+    #[serde(default = "default_layout", skip_serializing_if = "is_default_layout")]
+    pub layout: String,
+    // Calibration of streamed values for display and analytics, see calibrate. The
+    // thresholds and the device never see it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gain: Option<Sensors<f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<Sensors<i32>>,
+    // Let the device thresholds follow the sensors' rest values while
+    // --drift-compensation is on, see drift.rs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub drift_compensation: bool,
+    // For grouping profiles in pickers, see tags.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Press detection of the streamed values, see press.rs; the device never sees them.
+    // Empty for none, as in profiles saved without it.
+    #[serde(default, skip_serializing_if = "is_no_hysteresis")]
+    pub hysteresis: Sensors<i32>,
+    #[serde(default, skip_serializing_if = "is_zero_ms")]
+    pub debounce_ms: u32,
+    // Profiles revision of the last change to this profile, 0 before revisions were kept
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub revision: u64,
+}
+
+fn is_zero(revision: &u64) -> bool {
+    *revision == 0
+}
+
+fn is_no_hysteresis(hysteresis: &Sensors<i32>) -> bool {
+    hysteresis.iter().all(|&value| value == 0)
+}
+
+fn is_zero_ms(debounce_ms: &u32) -> bool {
+    *debounce_ms == 0
+}
+
+fn default_layout() -> String {
+    DEFAULT_LAYOUT.to_string()
+}
+
+fn is_default_layout(layout: &str) -> bool {
+    layout == DEFAULT_LAYOUT
+}
+
+impl Profile {
+    // A profile with the thresholds of a pad of their count, in its usual layout
+    pub fn new(thresholds: impl Into<Sensors<i32>>) -> Self {
+        let thresholds = thresholds.into();
+        Self {
+            thresholds,
+            layout: layout::for_sensor_count(thresholds.len()).to_string(),
+            gain: None,
+            offset: None,
+            drift_compensation: false,
+            tags: Vec::new(),
+            hysteresis: Sensors::default(),
+            debounce_ms: 0,
+            revision: 0,
+        }
+    }
+
+    // Raw sensor values in calibrated units, (value - offset) * gain clamped to the sensor
+    // range; None for a profile without calibration. Sensors past the calibrated ones get a
+    // gain of 1 and no offset.
+    pub fn calibrate(&self, raw: Sensors<i32>) -> Option<Sensors<i32>> {
+        if self.gain.is_none() && self.offset.is_none() {
+            return None;
+        }
+        let gain = |i| {
+            self.gain
+                .and_then(|gain| gain.get(i).copied())
+                .unwrap_or(1.0)
+        };
+        let offset = |i| {
+            self.offset
+                .and_then(|offset| offset.get(i).copied())
+                .unwrap_or(0)
+        };
+        Some(Sensors::from_fn(raw.len(), |i| {
+            let value = (raw[i] - offset(i)) as f32 * gain(i);
+            (value.round() as i32).clamp(0, SENSOR_MAX)
+        }))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -13,80 +114,1063 @@ pub struct Player {
     pub profile: String,
 }
 
+// Id of the pad synthesized for single-pad setups
+pub const DEFAULT_PAD_ID: &str = "default";
+
+// Every sensor in use
+const ALL_SENSORS: u16 = (1 << MAX_SENSORS) - 1;
+
+fn all_sensors() -> u16 {
+    ALL_SENSORS
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+// How a pad's panels are wired: logical sensor i, as shown and stored in profiles, is
+// sensor `map[i]` of the device. Always a permutation; empty for a pad wired in order,
+// whatever its number of sensors, and then it leaves values as they are. So does a map
+// of another number of sensors than the values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<usize>", into = "Vec<usize>")]
+pub struct SensorMap(Sensors<usize>);
+
+impl SensorMap {
+    pub fn is_identity(&self) -> bool {
+        self.0
+            .iter()
+            .enumerate()
+            .all(|(index, &sensor)| index == sensor)
+    }
+
+    // Device sensor of logical sensor `index`
+    pub fn physical(self, index: usize) -> usize {
+        self.0.get(index).copied().unwrap_or(index)
+    }
+
+    // Thresholds in logical order, rearranged into the order of the device's sensors
+    pub fn to_physical(self, logical: Sensors<i32>) -> Sensors<i32> {
+        if self.0.len() != logical.len() {
+            return logical;
+        }
+        let mut physical = logical;
+        for (index, value) in logical.into_iter().enumerate() {
+            physical[self.0[index]] = value;
+        }
+        physical
+    }
+
+    // Values read from the device, rearranged into logical order
+    pub fn to_logical(self, physical: Sensors<i32>) -> Sensors<i32> {
+        if self.0.len() != physical.len() {
+            return physical;
+        }
+        self.0.map(|index| physical[index])
+    }
+}
+
+impl TryFrom<Vec<usize>> for SensorMap {
+    type Error = ValidationError;
+
+    fn try_from(map: Vec<usize>) -> Result<Self, Self::Error> {
+        let invalid = || ValidationError::InvalidSensorMap(map.clone());
+        let sensors = Sensors::from_slice(&map).ok_or_else(invalid)?;
+        let mut seen = [false; MAX_SENSORS];
+        for &index in &map {
+            if index >= map.len() || std::mem::replace(&mut seen[index], true) {
+                return Err(invalid());
+            }
+        }
+        Ok(Self(sensors))
+    }
+}
+
+impl From<SensorMap> for Vec<usize> {
+    fn from(map: SensorMap) -> Self {
+        map.0.to_vec()
+    }
+}
+
+// A dance pad with its own selection; profile definitions and players are shared
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Pad {
+    pub id: String,
+    pub name: String,
+    // Serial port of the pad, if not the one this server was started with. With a
+    // `device`, the port that device was last seen on.
+    #[serde(default)]
+    pub port: Option<String>,
+    // Device the pad is on, found by its USB identity whatever the port is called
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<DeviceIdentity>,
+    #[serde(default)]
+    pub current_profile: String,
+    #[serde(default)]
+    pub current_player: String,
+    // Sensors in use, bit 0 is sensor 0
+    #[serde(default = "all_sensors")]
+    pub sensor_mask: u16,
+    #[serde(default, skip_serializing_if = "SensorMap::is_identity")]
+    pub sensor_map: SensorMap,
+    // A disabled pad keeps its configuration, but its device is left alone
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    // Profile for new players on this pad, instead of the shared default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+    // Set while the current profile is only for now, see temporary.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temporary: Option<TemporaryProfile>,
+}
+
+impl Pad {
+    // The single pad of a setup without pads, carrying the legacy selection
+    pub fn default_pad(current_profile: String, current_player: String) -> Self {
+        Self {
+            id: DEFAULT_PAD_ID.to_string(),
+            name: "Default".to_string(),
+            port: None,
+            device: None,
+            current_profile,
+            current_player,
+            sensor_mask: ALL_SENSORS,
+            sensor_map: SensorMap::default(),
+            enabled: true,
+            default_profile: None,
+            temporary: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(from = "StoredProfiles")]
 pub struct Profiles {
     pub profiles: HashMap<String, Profile>,
-    pub current_profile: String,
     pub default_profile: String, // New field for default profile
     pub players: HashMap<String, Player>,
-    pub current_player: String,
+    // The first pad is the one on this server's serial device
+    pub pads: Vec<Pad>,
+    // Bumped by every command that changes anything, for expected_revision
+    pub revision: u64,
+    // Profile switches at set times, see schedule.rs
+    pub schedules: Vec<Schedule>,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self {
+            profiles: HashMap::new(),
+            default_profile: String::new(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad(String::new(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
+        }
+    }
+}
+
+// profiles.json as written by any version: files from before pads only have the top
+// level selection, which becomes the default pad
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct StoredProfiles {
+    profiles: HashMap<String, Profile>,
+    current_profile: String,
+    default_profile: String,
+    players: HashMap<String, Player>,
+    current_player: String,
+    pads: Vec<Pad>,
+    revision: u64,
+    schedules: Vec<Schedule>,
+}
+
+impl From<StoredProfiles> for Profiles {
+    fn from(stored: StoredProfiles) -> Self {
+        let pads = if stored.pads.is_empty() {
+            vec![Pad::default_pad(
+                stored.current_profile,
+                stored.current_player,
+            )]
+        } else {
+            stored.pads
+        };
+        Self {
+            profiles: stored.profiles,
+            default_profile: stored.default_profile,
+            players: stored.players,
+            pads,
+            revision: stored.revision,
+            schedules: stored.schedules,
+        }
+    }
+}
+
+// Written in the pads shape, plus the first pad's selection at the top level for clients
+// that don't know about pads
+impl Serialize for Profiles {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_fields(serializer, true)
+    }
+}
+
+fn serialize_without_players<S: serde::Serializer>(
+    profiles: &Option<Arc<Profiles>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match profiles {
+        Some(profiles) => profiles.serialize_fields(serializer, false),
+        None => serializer.serialize_none(),
+    }
+}
+
+// Players listed by ListPlayers unless it asks for another page size
+pub const PLAYER_PAGE_SIZE: usize = 20;
+
+pub const MAX_PLAYER_PAGE_SIZE: usize = 200;
+
+// One page of ListPlayers; `total` counts every player matching the filter
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PlayerPage {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub players: Vec<Player>,
+}
+
+impl Profiles {
+    fn serialize_fields<S: serde::Serializer>(
+        &self,
+        serializer: S,
+        players: bool,
+    ) -> Result<S::Ok, S::Error> {
+        let mut out = serializer.serialize_struct("Profiles", 8)?;
+        out.serialize_field("profiles", &self.profiles)?;
+        out.serialize_field("current_profile", self.current_profile())?;
+        out.serialize_field("default_profile", &self.default_profile)?;
+        if players {
+            out.serialize_field("players", &self.players)?;
+        } else {
+            out.skip_field("players")?;
+        }
+        out.serialize_field("current_player", self.current_player())?;
+        out.serialize_field("pads", &self.pads)?;
+        out.serialize_field("revision", &self.revision)?;
+        if self.schedules.is_empty() {
+            out.skip_field("schedules")?;
+        } else {
+            out.serialize_field("schedules", &self.schedules)?;
+        }
+        out.end()
+    }
+
+    // Count a change from `before`: the next revision, which every profile that changed
+    // takes as well. Stays ahead of `before` even when a reload brought an older file.
+    pub fn bump_revision(&mut self, before: &Profiles) {
+        self.revision = self.revision.max(before.revision) + 1;
+        for (name, profile) in &mut self.profiles {
+            if before.profiles.get(name) != Some(profile) {
+                profile.revision = self.revision;
+            }
+        }
+    }
+
+    // What expected_revision is compared with: the revision of the profile a command
+    // changes, 0 if it doesn't exist, or of all profiles
+    pub fn revision_of(&self, profile: Option<&str>) -> u64 {
+        match profile {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .map_or(0, |profile| profile.revision),
+            None => self.revision,
+        }
+    }
+
+    pub fn player_page(
+        &self,
+        offset: usize,
+        limit: Option<usize>,
+        filter: Option<&str>,
+    ) -> PlayerPage {
+        let filter = filter.map(str::to_lowercase);
+        let mut players: Vec<&Player> = self
+            .players
+            .values()
+            .filter(|player| {
+                filter
+                    .as_ref()
+                    .is_none_or(|filter| player.name.to_lowercase().contains(filter))
+            })
+            .collect();
+        players.sort_by(|a, b| a.name.cmp(&b.name));
+        let limit = limit.unwrap_or(PLAYER_PAGE_SIZE).min(MAX_PLAYER_PAGE_SIZE);
+        PlayerPage {
+            total: players.len(),
+            offset,
+            limit,
+            players: players
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    // Selection of the pad on this server's serial device
+    pub fn current_profile(&self) -> &str {
+        self.pads.first().map_or("", |pad| &pad.current_profile)
+    }
+
+    pub fn current_player(&self) -> &str {
+        self.pads.first().map_or("", |pad| &pad.current_player)
+    }
+
+    pub fn device_pad_id(&self) -> &str {
+        self.pads.first().map_or(DEFAULT_PAD_ID, |pad| &pad.id)
+    }
+
+    // Wiring of the pad on this server's serial device
+    pub fn sensor_map(&self) -> SensorMap {
+        self.pads
+            .first()
+            .map_or_else(SensorMap::default, |pad| pad.sensor_map)
+    }
+
+    // Whether `pad` is the one on this server's serial device
+    pub fn drives_device(&self, pad: &str) -> bool {
+        self.device_pad_id() == pad
+    }
+
+    // The pad a command names, or the one on this server's device without a name
+    pub fn pad(&self, id: Option<&str>) -> Result<&Pad, ValidationError> {
+        match id {
+            Some(id) => self.pads.iter().find(|pad| pad.id == id),
+            None => self.pads.first(),
+        }
+        .ok_or_else(|| ValidationError::PadNotFound(id.unwrap_or(DEFAULT_PAD_ID).to_string()))
+    }
+
+    // Like pad, for commands that need the pad's device
+    pub fn enabled_pad(&self, id: Option<&str>) -> Result<&Pad, ValidationError> {
+        let pad = self.pad(id)?;
+        if !pad.enabled {
+            return Err(ValidationError::PadDisabled(pad.id.clone()));
+        }
+        Ok(pad)
+    }
+
+    // Whether the stream should poll this server's device
+    pub fn device_enabled(&self) -> bool {
+        self.pads.first().is_none_or(|pad| pad.enabled)
+    }
+
+    pub fn pad_mut(&mut self, id: Option<&str>) -> Result<&mut Pad, ValidationError> {
+        match id {
+            Some(id) => self
+                .pads
+                .iter_mut()
+                .find(|pad| pad.id == id)
+                .ok_or_else(|| ValidationError::PadNotFound(id.to_string())),
+            None => Ok(self.device_pad_mut()),
+        }
+    }
+
+    // Profile a player created on `pad` starts with, in this order: the pad's own default
+    // profile, the shared default profile, the profile the pad is on. Unset defaults and
+    // names of missing profiles are skipped; None if nothing is left.
+    pub fn resolve_profile_for_new_player<'a>(&'a self, pad: &'a Pad) -> Option<&'a str> {
+        [
+            pad.default_profile.as_deref(),
+            Some(self.default_profile.as_str()),
+            Some(pad.current_profile.as_str()),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|name| !name.is_empty() && self.profiles.contains_key(*name))
+    }
+
+    // The pad on this server's serial device, created if there are no pads at all
+    pub fn device_pad_mut(&mut self) -> &mut Pad {
+        if self.pads.is_empty() {
+            self.pads
+                .push(Pad::default_pad(String::new(), String::new()));
+        }
+        &mut self.pads[0]
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Command {
     UpdateThreshold {
         profile_name: String,
-        threshold_index: usize,
+        threshold_index: u8,
+        value: i32,
+    },
+    // Suggest a threshold change for an operator to approve, see proposal.rs
+    ProposeThresholdChange {
+        profile: String,
+        index: u8,
         value: i32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+    },
+    // The proposals kept, pending and decided
+    ListProposals,
+    // Make a pending proposal's change like UpdateThreshold. One whose profile changed
+    // since it was made is refused as stale unless `force` is set.
+    ApproveProposal {
+        id: u64,
+        #[serde(default)]
+        force: bool,
+    },
+    RejectProposal {
+        id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
     },
     AddProfile {
         name: String,
-        thresholds: [i32; 4],
+        thresholds: Sensors<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        layout: Option<String>,
     },
+    // A profile that players have needs `confirm` and a `reassign_to` profile for them
     RemoveProfile {
         name: String,
+        #[serde(default)]
+        confirm: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reassign_to: Option<String>,
     },
+    // Without `pad`, these act on the pad on this server's serial device
+    // `update_player: false` switches the profile for now without making it the current
+    // player's profile
     ChangeProfile {
         name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pad: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        update_player: Option<bool>,
     },
     ChangePlayer {
         name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pad: Option<String>,
+    },
+    // Sign the pad's current player out; the pad keeps its profile
+    ClearCurrentPlayer {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pad: Option<String>,
     },
+    // With `pad`, sets that pad's default instead of the shared one; without `name`,
+    // clears it
     SetDefaultProfile {
-        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pad: Option<String>,
     },
     GetCurrentThresholds,
-    GetSensorValues, // Kept for backward compatibility
+    // Full profiles snapshot, for clients that joined late or missed an update
+    GetProfiles,
+    GetSensorValues, // One reading: the stream's latest frame if fresh, else the device
+    // Read the sensors now rather than waiting for the stream's next frame
+    ReadSensors,
     StartSensorStream,
     StopSensorStream,
+    // Whether the stream runs, its rates, subscribers, frames and last read error
+    GetStreamStatus,
+    GetWebhookStatus,
+    // Notification sinks, such as the webhooks, with their delivery counters, see sink.rs
+    ListSinks,
+    // Offer events to a sink again, or no longer
+    EnableSink {
+        name: String,
+    },
+    DisableSink {
+        name: String,
+    },
+    // Server counters: commands, failures, serial I/O, broadcast frames and saves
+    GetServerStats,
+    // Depth and limit of the serial command queue, refusals and skipped stream reads
+    GetSerialStats,
+    // Percentiles of the round trip and dispatch time of the stream's presses, see
+    // latency.rs
+    GetLatencyStats,
+    // Start the latency percentiles over, answering with those up to now
+    ResetLatencyStats,
+    // Check the device, the profiles file and the event channel, see self_test.rs
+    RunSelfTest,
+    // Open websocket connections with messages/bytes sent, send errors and lag
+    GetClients,
+    // Version, build, platform and configuration details of the server
+    GetServerInfo,
+    // Load the profiles.json that failed to parse on startup again, after it was fixed,
+    // replacing the profiles
+    RetryLoadProfiles,
+    // Pause or resume saving profiles.json after every change; on at every start
+    SetAutosave {
+        enabled: bool,
+    },
+    // Write profiles.json now, whether or not autosave is on
+    SaveProfiles,
+    // Tail of the error journal, newest last
+    GetErrorLog {
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    // Recent writes of the device's thresholds with their reason, newest last
+    GetThresholdWriteLog {
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    // Mutating commands with their origin and outcome, newest last; `filter` keeps the
+    // commands whose name contains it (case-insensitive)
+    GetCommandHistory {
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        filter: Option<String>,
+    },
+    // Record all serial traffic to a new file under captures/, named after the hint
+    StartSerialCapture {
+        #[serde(default)]
+        path_hint: Option<String>,
+    },
+    StopSerialCapture,
+    // Put `pad` on a serial port, or on a USB device given as "usb:VID:PID[:SERIAL]"
+    // that is followed whatever port it shows up on
+    AssignPadPort {
+        pad: String,
+        port_or_device_id: String,
+    },
+    // Where every pad's device is right now
+    GetPadMapping,
+    // Known layouts with their panel labels
+    GetLayouts,
+    // Stop or resume using a pad's device without touching its configuration
+    SetPadEnabled {
+        pad: String,
+        enabled: bool,
+    },
+    // Rewire a pad: logical sensor i is sensor `sensor_map[i]` of its device
+    SetSensorMap {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pad: Option<String>,
+        sensor_map: Vec<usize>,
+    },
+    // Calibrate a profile's streamed values; a gain of all 1 or an offset of all 0
+    // removes that part
+    SetCalibration {
+        profile_name: String,
+        #[serde(default)]
+        gain: Option<Sensors<f32>>,
+        #[serde(default)]
+        offset: Option<Sensors<i32>>,
+    },
+    // Turn a profile's drift compensation on or off
+    SetDriftCompensation {
+        profile_name: String,
+        enabled: bool,
+    },
+    // Offsets drift compensation applies to the current profile's thresholds right now
+    GetDriftCompensation,
+    // Replace a profile's tags; an empty list removes them
+    SetProfileTags {
+        name: String,
+        tags: Vec<String>,
+    },
+    // Set a profile's press detection hysteresis and debounce, see press.rs
+    SetPressParameters {
+        profile_name: String,
+        hysteresis: Sensors<i32>,
+        debounce_ms: u32,
+    },
+    // Profiles sorted by name with their thresholds and tags, only those tagged
+    // `tag_filter` (case-insensitive) if given
+    ListProfiles {
+        #[serde(default)]
+        tag_filter: Option<String>,
+    },
+    // A short code for pasting a profile into chat, see share.rs
+    ShareProfile {
+        name: String,
+    },
+    // Add the profile of a share code, under `rename_to` if given
+    ImportSharedProfile {
+        code: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rename_to: Option<String>,
+    },
+    // Add the profiles of another FSR tool's saved thresholds, see import.rs
+    ImportExternal {
+        format: String,
+        payload: String,
+        #[serde(default)]
+        on_conflict: import::OnConflict,
+    },
+    // Watch the stream while every panel is pressed with the same weight, and set the
+    // gains that make those presses read the same
+    CalibrateGain {
+        profile_name: String,
+        #[serde(default)]
+        duration_ms: Option<u64>,
+    },
+    // Watch for a press on each sensor of the device pad in turn, giving each
+    // `timeout_ms`; progress and the result are broadcast as events
+    StartPanelTest {
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+    CancelPanelTest,
+    // Save profiles, players, the pads and the device's thresholds to a snapshot file
+    CreateSnapshot {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        label: Option<String>,
+    },
+    // Replace everything with a snapshot and put its current profile on the device
+    RestoreSnapshot {
+        id: String,
+    },
+    ListSnapshots,
+    // Switch a pad to a profile for `duration_secs` like ChangeProfile without updating
+    // the player, then back to the profile it had
+    ApplyTemporaryProfile {
+        name: String,
+        duration_secs: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pad: Option<String>,
+    },
+    // Switch a pad back from its temporary profile now
+    RevertTemporaryProfile {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pad: Option<String>,
+    },
+    // Switch a pad to `profile` at a local time: a daily `HH:MM` or a cron expression,
+    // see schedule.rs
+    AddSchedule {
+        cron_or_time: String,
+        profile: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pad: Option<String>,
+    },
+    // The schedules with their next run, in the server's local time zone
+    ListSchedules,
+    RemoveSchedule {
+        id: String,
+    },
+    // Limit the events this connection receives to `topics`; command responses always
+    // arrive. `scale` is how its stream frames show the sensor values. Only meaningful on
+    // a websocket or pipe connection.
+    Subscribe {
+        topics: Vec<Topic>,
+        #[serde(default)]
+        scale: Scale,
+    },
+    // Flash the pad's UI and briefly drop a threshold on its device; refused while the
+    // pad is being played on unless forced
+    IdentifyPad {
+        #[serde(default)]
+        pad: Option<String>,
+        #[serde(default)]
+        force: bool,
+    },
+    // A page of the players sorted by name, optionally only those whose name contains
+    // `filter` (case-insensitive)
+    ListPlayers {
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        filter: Option<String>,
+    },
+    // Declare the protocol version the client speaks; a websocket connection is answered
+    // in that version's shape from then on. A websocket client that gives a `client_id`
+    // resumes the subscriptions it had under that id if it disconnected recently.
+    Hello {
+        protocol: u32,
+        #[serde(default)]
+        client_id: Option<String>,
+    },
+}
+
+impl Command {
+    // Whether the command changes profiles, players, thresholds or the sensor stream
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Command::UpdateThreshold { .. }
+            | Command::ProposeThresholdChange { .. }
+            | Command::ApproveProposal { .. }
+            | Command::RejectProposal { .. }
+            | Command::AddProfile { .. }
+            | Command::RemoveProfile { .. }
+            | Command::ChangeProfile { .. }
+            | Command::ApplyTemporaryProfile { .. }
+            | Command::RevertTemporaryProfile { .. }
+            | Command::ChangePlayer { .. }
+            | Command::ClearCurrentPlayer { .. }
+            | Command::SetDefaultProfile { .. }
+            | Command::AssignPadPort { .. }
+            | Command::SetCalibration { .. }
+            | Command::SetDriftCompensation { .. }
+            | Command::SetProfileTags { .. }
+            | Command::SetPressParameters { .. }
+            | Command::ImportSharedProfile { .. }
+            | Command::ImportExternal { .. }
+            | Command::SetSensorMap { .. }
+            | Command::SetPadEnabled { .. }
+            | Command::CalibrateGain { .. }
+            | Command::RetryLoadProfiles
+            | Command::RestoreSnapshot { .. }
+            | Command::AddSchedule { .. }
+            | Command::RemoveSchedule { .. }
+            | Command::SetAutosave { .. }
+            | Command::EnableSink { .. }
+            | Command::DisableSink { .. }
+            | Command::SaveProfiles
+            | Command::IdentifyPad { .. }
+            | Command::StartSensorStream
+            | Command::StopSensorStream => true,
+            Command::GetCurrentThresholds
+            | Command::GetProfiles
+            | Command::ListProposals
+            | Command::GetSensorValues
+            | Command::ReadSensors
+            | Command::GetStreamStatus
+            | Command::GetWebhookStatus
+            | Command::ListSinks
+            | Command::GetServerStats
+            | Command::GetSerialStats
+            | Command::GetLatencyStats
+            | Command::ResetLatencyStats
+            | Command::RunSelfTest
+            | Command::GetServerInfo
+            | Command::GetClients
+            | Command::GetErrorLog { .. }
+            | Command::GetThresholdWriteLog { .. }
+            | Command::GetCommandHistory { .. }
+            | Command::GetDriftCompensation
+            | Command::ListProfiles { .. }
+            | Command::ShareProfile { .. }
+            | Command::StartSerialCapture { .. }
+            | Command::StopSerialCapture
+            | Command::StartPanelTest { .. }
+            | Command::CancelPanelTest
+            | Command::CreateSnapshot { .. }
+            | Command::ListSnapshots
+            | Command::ListSchedules
+            | Command::GetPadMapping
+            | Command::GetLayouts
+            | Command::ListPlayers { .. }
+            | Command::Hello { .. }
+            | Command::Subscribe { .. } => false,
+        }
+    }
+
+    // Least role a client needs to send the command, see auth.rs
+    pub fn required_role(&self) -> Role {
+        match self {
+            Command::RemoveProfile { .. }
+            | Command::AssignPadPort { .. }
+            | Command::SetPadEnabled { .. }
+            | Command::SetSensorMap { .. }
+            | Command::RetryLoadProfiles
+            | Command::RestoreSnapshot { .. }
+            | Command::SetAutosave { .. }
+            | Command::EnableSink { .. }
+            | Command::DisableSink { .. }
+            | Command::StartSerialCapture { .. }
+            | Command::StopSerialCapture => Role::Admin,
+            Command::UpdateThreshold { .. }
+            | Command::ListProposals
+            | Command::ApproveProposal { .. }
+            | Command::RejectProposal { .. }
+            | Command::AddProfile { .. }
+            | Command::ChangeProfile { .. }
+            | Command::ApplyTemporaryProfile { .. }
+            | Command::RevertTemporaryProfile { .. }
+            | Command::ChangePlayer { .. }
+            | Command::ClearCurrentPlayer { .. }
+            | Command::SetDefaultProfile { .. }
+            | Command::SetCalibration { .. }
+            | Command::SetDriftCompensation { .. }
+            | Command::SetProfileTags { .. }
+            | Command::SetPressParameters { .. }
+            | Command::ImportSharedProfile { .. }
+            | Command::ImportExternal { .. }
+            | Command::CalibrateGain { .. }
+            | Command::SaveProfiles
+            | Command::StartSensorStream
+            | Command::StopSensorStream
+            | Command::IdentifyPad { .. }
+            | Command::StartPanelTest { .. }
+            | Command::CancelPanelTest
+            | Command::CreateSnapshot { .. }
+            | Command::AddSchedule { .. }
+            | Command::RemoveSchedule { .. }
+            | Command::ResetLatencyStats
+            | Command::RunSelfTest
+            | Command::GetErrorLog { .. }
+            | Command::GetThresholdWriteLog { .. }
+            | Command::GetCommandHistory { .. } => Role::Operator,
+            Command::ProposeThresholdChange { .. }
+            | Command::GetCurrentThresholds
+            | Command::GetProfiles
+            | Command::GetSensorValues
+            | Command::ReadSensors
+            | Command::GetStreamStatus
+            | Command::GetWebhookStatus
+            | Command::ListSinks
+            | Command::GetServerStats
+            | Command::GetSerialStats
+            | Command::GetLatencyStats
+            | Command::GetServerInfo
+            | Command::GetClients
+            | Command::GetDriftCompensation
+            | Command::ListProfiles { .. }
+            | Command::ShareProfile { .. }
+            | Command::GetPadMapping
+            | Command::GetLayouts
+            | Command::ListSnapshots
+            | Command::ListSchedules
+            | Command::ListPlayers { .. }
+            | Command::Hello { .. }
+            | Command::Subscribe { .. } => Role::Viewer,
+        }
+    }
+
+    // The profile whose content the command changes, if it is about a single one
+    pub fn target_profile(&self) -> Option<&str> {
+        match self {
+            Command::UpdateThreshold { profile_name, .. }
+            | Command::SetCalibration { profile_name, .. }
+            | Command::SetDriftCompensation { profile_name, .. }
+            | Command::SetPressParameters { profile_name, .. }
+            | Command::CalibrateGain { profile_name, .. } => Some(profile_name),
+            Command::AddProfile { name, .. }
+            | Command::RemoveProfile { name, .. }
+            | Command::SetProfileTags { name, .. } => Some(name),
+            Command::ProposeThresholdChange { profile, .. } => Some(profile),
+            _ => None,
+        }
+    }
+
+    // Variant name as sent on the wire, without the arguments
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::UpdateThreshold { .. } => "UpdateThreshold",
+            Command::ProposeThresholdChange { .. } => "ProposeThresholdChange",
+            Command::ListProposals => "ListProposals",
+            Command::ApproveProposal { .. } => "ApproveProposal",
+            Command::RejectProposal { .. } => "RejectProposal",
+            Command::AddProfile { .. } => "AddProfile",
+            Command::RemoveProfile { .. } => "RemoveProfile",
+            Command::ChangeProfile { .. } => "ChangeProfile",
+            Command::ApplyTemporaryProfile { .. } => "ApplyTemporaryProfile",
+            Command::RevertTemporaryProfile { .. } => "RevertTemporaryProfile",
+            Command::ChangePlayer { .. } => "ChangePlayer",
+            Command::ClearCurrentPlayer { .. } => "ClearCurrentPlayer",
+            Command::SetDefaultProfile { .. } => "SetDefaultProfile",
+            Command::GetCurrentThresholds => "GetCurrentThresholds",
+            Command::GetProfiles => "GetProfiles",
+            Command::GetSensorValues => "GetSensorValues",
+            Command::ReadSensors => "ReadSensors",
+            Command::StartSensorStream => "StartSensorStream",
+            Command::StopSensorStream => "StopSensorStream",
+            Command::GetStreamStatus => "GetStreamStatus",
+            Command::GetWebhookStatus => "GetWebhookStatus",
+            Command::GetServerStats => "GetServerStats",
+            Command::GetSerialStats => "GetSerialStats",
+            Command::GetLatencyStats => "GetLatencyStats",
+            Command::ResetLatencyStats => "ResetLatencyStats",
+            Command::RunSelfTest => "RunSelfTest",
+            Command::GetServerInfo => "GetServerInfo",
+            Command::GetClients => "GetClients",
+            Command::RetryLoadProfiles => "RetryLoadProfiles",
+            Command::SetAutosave { .. } => "SetAutosave",
+            Command::ListSinks => "ListSinks",
+            Command::EnableSink { .. } => "EnableSink",
+            Command::DisableSink { .. } => "DisableSink",
+            Command::SaveProfiles => "SaveProfiles",
+            Command::GetErrorLog { .. } => "GetErrorLog",
+            Command::GetThresholdWriteLog { .. } => "GetThresholdWriteLog",
+            Command::GetCommandHistory { .. } => "GetCommandHistory",
+            Command::StartSerialCapture { .. } => "StartSerialCapture",
+            Command::StopSerialCapture => "StopSerialCapture",
+            Command::IdentifyPad { .. } => "IdentifyPad",
+            Command::StartPanelTest { .. } => "StartPanelTest",
+            Command::CancelPanelTest => "CancelPanelTest",
+            Command::CreateSnapshot { .. } => "CreateSnapshot",
+            Command::RestoreSnapshot { .. } => "RestoreSnapshot",
+            Command::ListSnapshots => "ListSnapshots",
+            Command::AddSchedule { .. } => "AddSchedule",
+            Command::ListSchedules => "ListSchedules",
+            Command::RemoveSchedule { .. } => "RemoveSchedule",
+            Command::Subscribe { .. } => "Subscribe",
+            Command::AssignPadPort { .. } => "AssignPadPort",
+            Command::GetPadMapping => "GetPadMapping",
+            Command::GetLayouts => "GetLayouts",
+            Command::SetCalibration { .. } => "SetCalibration",
+            Command::SetDriftCompensation { .. } => "SetDriftCompensation",
+            Command::GetDriftCompensation => "GetDriftCompensation",
+            Command::SetProfileTags { .. } => "SetProfileTags",
+            Command::SetPressParameters { .. } => "SetPressParameters",
+            Command::ListProfiles { .. } => "ListProfiles",
+            Command::ShareProfile { .. } => "ShareProfile",
+            Command::ImportSharedProfile { .. } => "ImportSharedProfile",
+            Command::ImportExternal { .. } => "ImportExternal",
+            Command::SetSensorMap { .. } => "SetSensorMap",
+            Command::SetPadEnabled { .. } => "SetPadEnabled",
+            Command::CalibrateGain { .. } => "CalibrateGain",
+            Command::ListPlayers { .. } => "ListPlayers",
+            Command::Hello { .. } => "Hello",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Response {
     pub success: bool,
     pub message: String,
-    pub data: Option<Profiles>,
-    pub sensor_values: Option<[i32; 4]>,
+    // Shared with every subscriber; serialized through the Arc, without the players,
+    // which clients page through with ListPlayers
+    #[serde(serialize_with = "serialize_without_players")]
+    pub data: Option<Arc<Profiles>>,
+    pub sensor_values: Option<Sensors<i32>>,
     pub response_type: Option<String>, // "command_response", "sensor_stream"
+    // Structured result for commands that return more than profiles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+    // Pad the response or event is about
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad: Option<String>,
+    // Stable code of the outcome, e.g. PROFILE_CHANGED or PROFILE_NOT_FOUND, with its
+    // parameters, for clients that show their own text instead of `message`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<serde_json::Value>,
+    // What a mutation replaced, for clients that offer undo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<serde_json::Value>,
+    // Position of the event in the event history, shared by every transport
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    // Set on the answer to a dry run, which changed nothing
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+    // While autosave is off, on the answers to mutating commands: whether there are
+    // changes that aren't on disk, and how many
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unsaved_changes: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_changes: Option<u64>,
 }
 
+// Largest value the firmware reports for a sensor
+pub const SENSOR_MAX: i32 = 1023;
+
 pub const PROFILES_FILE: &str = "profiles.json";
 
-pub async fn load_profiles() -> Profiles {
-    match fs::read_to_string(PROFILES_FILE) {
-        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|_| Profiles {
-            profiles: HashMap::new(),
-            current_profile: String::new(),
-            default_profile: String::new(),
-            players: HashMap::new(),
-            current_player: String::new(),
-        }),
-        Err(_) => Profiles {
-            profiles: HashMap::new(),
-            current_profile: String::new(),
-            default_profile: String::new(),
-            players: HashMap::new(),
-            current_player: String::new(),
-        },
+// Version of the websocket protocol spoken by this server
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// Oldest protocol version clients may still declare, answered in that version's shape
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// A missing file is a fresh install and yields empty profiles; anything else is an error
+pub async fn load_profiles() -> Result<Profiles, StorageError> {
+    load_profiles_from(Path::new(PROFILES_FILE)).await
+}
+
+// Bytes that aren't UTF-8 fail as a parse error, with their position, like bad JSON
+pub async fn load_profiles_from(path: &Path) -> Result<Profiles, StorageError> {
+    match tokio::fs::read(path).await {
+        Ok(content) => serde_json::from_slice(&content).map_err(StorageError::Parse),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Profiles::default()),
+        Err(e) => Err(StorageError::Read(e)),
+    }
+}
+
+// profiles.json that didn't parse, reported by `/health`, GetServerInfo and the greeting
+// until RetryLoadProfiles loads it
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LoadFailure {
+    pub error: String,
+    pub line: usize,
+    pub column: usize,
+    // Where the file was preserved: profiles.json.invalid-<timestamp>, or profiles.json
+    // itself if it wasn't moved
+    pub path: String,
+}
+
+impl LoadFailure {
+    pub fn new(error: &serde_json::Error, path: String) -> Self {
+        Self {
+            error: error.to_string(),
+            line: error.line(),
+            column: error.column(),
+            path,
+        }
+    }
+}
+
+// Startup variant: report the problem and carry on with empty profiles. A file that
+// doesn't parse is moved aside first so the next save can't overwrite it; with
+// `preserve` false (read-only mode, which never saves) it stays where it is.
+pub async fn load_profiles_or_default(preserve: bool) -> (Profiles, Option<LoadFailure>) {
+    load_or_preserve(Path::new(PROFILES_FILE), preserve).await
+}
+
+async fn load_or_preserve(path: &Path, preserve: bool) -> (Profiles, Option<LoadFailure>) {
+    let error = match load_profiles_from(path).await {
+        Ok(profiles) => return (profiles, None),
+        Err(StorageError::Parse(error)) => error,
+        Err(e) => {
+            eprintln!("{}; starting with empty profiles", e);
+            return (Profiles::default(), None);
+        }
+    };
+    let mut kept = path.display().to_string();
+    if preserve {
+        let invalid = format!(
+            "{}.invalid-{}",
+            kept,
+            chrono::Utc::now().format("%Y%m%d-%H%M%S")
+        );
+        match tokio::fs::rename(path, &invalid).await {
+            Ok(()) => kept = invalid,
+            Err(e) => eprintln!("Failed to move {} aside: {}", kept, e),
+        }
     }
+    eprintln!(
+        "Failed to parse {} at line {}, column {}: {}",
+        path.display(),
+        error.line(),
+        error.column(),
+        error
+    );
+    eprintln!(
+        "The file was kept as {}; starting with empty profiles. Fix it and send RetryLoadProfiles to load it.",
+        kept
+    );
+    (Profiles::default(), Some(LoadFailure::new(&error, kept)))
+}
+
+pub async fn save_profiles(profiles: &Profiles) -> Result<usize, StorageError> {
+    save_profiles_to(Path::new(PROFILES_FILE), profiles).await
 }
 
-pub async fn save_profiles(
-    profiles: &Profiles,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let json = serde_json::to_string_pretty(profiles)?;
-    fs::write(PROFILES_FILE, json)?;
-    Ok(())
+// Returns the size of the file written
+pub async fn save_profiles_to(path: &Path, profiles: &Profiles) -> Result<usize, StorageError> {
+    let json = serde_json::to_string_pretty(profiles).map_err(StorageError::Serialize)?;
+    tokio::fs::write(path, &json)
+        .await
+        .map_err(StorageError::Write)?;
+    Ok(json.len())
 }
 
 #[cfg(test)]
@@ -94,10 +1178,84 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_profile_serialization() {
-        let profile = Profile {
-            thresholds: [100, 200, 300, 400],
+    fn test_resolve_profile_for_new_player() {
+        let profiles = |default: &str| Profiles {
+            profiles: HashMap::from([
+                ("Casual".to_string(), Profile::new([400; 4])),
+                ("Tight".to_string(), Profile::new([600; 4])),
+            ]),
+            default_profile: default.to_string(),
+            ..Profiles::default()
+        };
+        let pad = |pad_default: Option<&str>, current: &str| {
+            let mut pad = Pad::default_pad(current.to_string(), String::new());
+            pad.default_profile = pad_default.map(String::from);
+            pad
         };
+        // (pad default, shared default, current) -> profile of a new player
+        for (pad_default, default, current, expected) in [
+            (Some("Tight"), "Casual", "Casual", Some("Tight")),
+            (Some("Gone"), "Casual", "Tight", Some("Casual")),
+            (None, "Casual", "Tight", Some("Casual")),
+            (None, "Casual", "", Some("Casual")),
+            (None, "", "Tight", Some("Tight")),
+            (None, "Gone", "Tight", Some("Tight")),
+            (None, "", "", None),
+            (None, "", "Gone", None),
+        ] {
+            let profiles = profiles(default);
+            let pad = pad(pad_default, current);
+            assert_eq!(
+                profiles.resolve_profile_for_new_player(&pad),
+                expected,
+                "{:?} {:?} {:?}",
+                pad_default,
+                default,
+                current
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_file_is_preserved() {
+        let dir = std::env::temp_dir().join(format!("fsr-rs-invalid-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("profiles.json");
+        tokio::fs::write(&path, "{\n  \"profiles\": {},\n}")
+            .await
+            .unwrap();
+
+        // Read-only mode leaves it in place
+        let (profiles, failure) = load_or_preserve(&path, false).await;
+        let failure = failure.unwrap();
+        assert_eq!(profiles, Profiles::default());
+        assert_eq!((failure.line, failure.column), (3, 1));
+        assert_eq!(failure.path, path.display().to_string());
+
+        let (_, failure) = load_or_preserve(&path, true).await;
+        let kept = failure.unwrap().path;
+        assert!(kept.contains("profiles.json.invalid-"));
+        assert!(!path.exists());
+        assert_eq!(
+            tokio::fs::read_to_string(&kept).await.unwrap(),
+            "{\n  \"profiles\": {},\n}"
+        );
+
+        // So is one that isn't UTF-8
+        let content = b"{\"default_profile\": \"Caf\xe9\"}";
+        tokio::fs::write(&path, content).await.unwrap();
+        let (profiles, failure) = load_or_preserve(&path, true).await;
+        let kept = failure.unwrap().path;
+        assert_eq!(profiles, Profiles::default());
+        assert!(kept.contains("profiles.json.invalid-"));
+        assert!(!path.exists());
+        assert_eq!(tokio::fs::read(&kept).await.unwrap(), content);
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[test]
+    fn test_profile_serialization() {
+        let profile = Profile::new([100, 200, 300, 400]);
 
         let json = serde_json::to_string(&profile).unwrap();
         let deserialized: Profile = serde_json::from_str(&json).unwrap();
@@ -109,16 +1267,12 @@ mod tests {
     #[test]
     fn test_profiles_serialization() {
         let profiles = Profiles {
-            profiles: HashMap::from([(
-                "Profile1".to_string(),
-                Profile {
-                    thresholds: [10, 20, 30, 40],
-                },
-            )]),
-            current_profile: "Profile1".to_string(),
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
             default_profile: String::new(),
             players: HashMap::new(),
-            current_player: String::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
         };
 
         let json = serde_json::to_string_pretty(&profiles).unwrap();
@@ -126,7 +1280,7 @@ mod tests {
 
         assert_eq!(profiles, deserialized);
         assert_eq!(profiles.profiles.len(), 1);
-        assert_eq!(profiles.current_profile, "Profile1");
+        assert_eq!(profiles.current_profile(), "Profile1");
     }
 
     #[test]
@@ -148,20 +1302,25 @@ mod tests {
         let response = Response {
             success: true,
             message: "Success".to_string(),
-            data: Some(Profiles {
-                profiles: HashMap::from([(
-                    "Profile1".to_string(),
-                    Profile {
-                        thresholds: [10, 20, 30, 40],
-                    },
-                )]),
-                current_profile: "Profile1".to_string(),
+            data: Some(Arc::new(Profiles {
+                profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
                 default_profile: String::new(),
                 players: HashMap::new(),
-                current_player: String::new(),
-            }),
+                pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+                revision: 0,
+                schedules: Vec::new(),
+            })),
             sensor_values: None,
             response_type: Some("command_response".to_string()),
+            payload: None,
+            pad: None,
+            message_code: None,
+            params: None,
+            previous: None,
+            seq: None,
+            dry_run: false,
+            unsaved_changes: false,
+            pending_changes: None,
         };
 
         let json = serde_json::to_string_pretty(&response).unwrap();
@@ -170,45 +1329,74 @@ mod tests {
         assert_eq!(response, deserialized);
     }
 
+    #[test]
+    fn test_player_page() {
+        let profiles = Profiles {
+            players: ["carol", "Alice", "bob", "Alina"]
+                .into_iter()
+                .map(|name| {
+                    let player = Player {
+                        name: name.to_string(),
+                        profile: "P".to_string(),
+                    };
+                    (name.to_string(), player)
+                })
+                .collect(),
+            ..Profiles::default()
+        };
+        let names = |page: PlayerPage| -> Vec<String> {
+            page.players.into_iter().map(|player| player.name).collect()
+        };
+
+        let page = profiles.player_page(1, Some(2), None);
+        assert_eq!((page.total, page.limit), (4, 2));
+        assert_eq!(names(page), vec!["Alina", "bob"]);
+        let page = profiles.player_page(0, None, Some("ALI"));
+        assert_eq!((page.total, page.limit), (2, PLAYER_PAGE_SIZE));
+        assert_eq!(names(page), vec!["Alice", "Alina"]);
+        assert_eq!(
+            profiles.player_page(0, Some(1000), None).limit,
+            MAX_PLAYER_PAGE_SIZE
+        );
+
+        // Saved with the players, broadcast without them
+        assert!(serde_json::to_value(&profiles).unwrap()["players"].is_object());
+        let response = Response {
+            data: Some(Arc::new(profiles)),
+            ..crate::event::Event::PlayersChanged { total: 4 }.to_response()
+        };
+        let json = serde_json::to_value(&response).unwrap();
+        assert!(json["data"].get("players").is_none());
+        assert_eq!(json["data"]["pads"][0]["id"], DEFAULT_PAD_ID);
+    }
+
     #[test]
     fn test_profiles_equality() {
         let profiles1 = Profiles {
-            profiles: HashMap::from([(
-                "Profile1".to_string(),
-                Profile {
-                    thresholds: [10, 20, 30, 40],
-                },
-            )]),
-            current_profile: "Profile1".to_string(),
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
             default_profile: String::new(),
             players: HashMap::new(),
-            current_player: String::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
         };
 
         let profiles2 = Profiles {
-            profiles: HashMap::from([(
-                "Profile1".to_string(),
-                Profile {
-                    thresholds: [10, 20, 30, 40],
-                },
-            )]),
-            current_profile: "Profile1".to_string(),
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
             default_profile: String::new(),
             players: HashMap::new(),
-            current_player: String::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
         };
 
         let profiles3 = Profiles {
-            profiles: HashMap::from([(
-                "Profile2".to_string(),
-                Profile {
-                    thresholds: [10, 20, 30, 40],
-                },
-            )]),
-            current_profile: "Profile1".to_string(),
+            profiles: HashMap::from([("Profile2".to_string(), Profile::new([10, 20, 30, 40]))]),
             default_profile: String::new(),
             players: HashMap::new(),
-            current_player: String::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
         };
 
         assert_eq!(profiles1, profiles2);
@@ -217,9 +1405,7 @@ mod tests {
 
     #[test]
     fn test_profile_debug() {
-        let profile = Profile {
-            thresholds: [100, 200, 300, 400],
-        };
+        let profile = Profile::new([100, 200, 300, 400]);
 
         let debug_str = format!("{:?}", profile);
         assert!(debug_str.contains("100"));
@@ -231,16 +1417,12 @@ mod tests {
     #[test]
     fn test_profiles_debug() {
         let profiles = Profiles {
-            profiles: HashMap::from([(
-                "Profile1".to_string(),
-                Profile {
-                    thresholds: [10, 20, 30, 40],
-                },
-            )]),
-            current_profile: "Profile1".to_string(),
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
             default_profile: String::new(),
             players: HashMap::new(),
-            current_player: String::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
         };
 
         let debug_str = format!("{:?}", profiles);
@@ -278,20 +1460,25 @@ mod tests {
         let response = Response {
             success: true,
             message: "Success".to_string(),
-            data: Some(Profiles {
-                profiles: HashMap::from([(
-                    "Profile1".to_string(),
-                    Profile {
-                        thresholds: [10, 20, 30, 40],
-                    },
-                )]),
-                current_profile: "Profile1".to_string(),
+            data: Some(Arc::new(Profiles {
+                profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
                 default_profile: String::new(),
                 players: HashMap::new(),
-                current_player: String::new(),
-            }),
+                pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+                revision: 0,
+                schedules: Vec::new(),
+            })),
             sensor_values: None,
             response_type: Some("command_response".to_string()),
+            payload: None,
+            pad: None,
+            message_code: None,
+            params: None,
+            previous: None,
+            seq: None,
+            dry_run: false,
+            unsaved_changes: false,
+            pending_changes: None,
         };
 
         let debug_str = format!("{:?}", response);
@@ -306,29 +1493,23 @@ mod tests {
     #[test]
     fn test_profiles_clone() {
         let original = Profiles {
-            profiles: HashMap::from([(
-                "Profile1".to_string(),
-                Profile {
-                    thresholds: [10, 20, 30, 40],
-                },
-            )]),
-            current_profile: "Profile1".to_string(),
+            profiles: HashMap::from([("Profile1".to_string(), Profile::new([10, 20, 30, 40]))]),
             default_profile: String::new(),
             players: HashMap::new(),
-            current_player: String::new(),
+            pads: vec![Pad::default_pad("Profile1".to_string(), String::new())],
+            revision: 0,
+            schedules: Vec::new(),
         };
 
         let cloned = original.clone();
         assert_eq!(original, cloned);
         assert_eq!(original.profiles.len(), cloned.profiles.len());
-        assert_eq!(original.current_profile, cloned.current_profile);
+        assert_eq!(original.current_profile(), cloned.current_profile());
     }
 
     #[test]
     fn test_profile_clone() {
-        let original = Profile {
-            thresholds: [100, 200, 300, 400],
-        };
+        let original = Profile::new([100, 200, 300, 400]);
 
         let cloned = original.clone();
         assert_eq!(original, cloned);
@@ -347,17 +1528,19 @@ mod tests {
 
     #[tokio::test]
     async fn test_json_parsing_invalid_profile() {
-        let invalid_json = r#"{"thresholds":[100,200,300]}"#; // missing threshold 4
-        let parsed: Result<Profile, _> = serde_json::from_str(invalid_json);
-
-        assert!(parsed.is_err());
+        // No thresholds, and more than the largest layout has
+        for invalid_json in [
+            r#"{"thresholds":[]}"#,
+            r#"{"thresholds":[1,2,3,4,5,6,7,8,9,10]}"#,
+        ] {
+            let parsed: Result<Profile, _> = serde_json::from_str(invalid_json);
+            assert!(parsed.is_err(), "{}", invalid_json);
+        }
     }
 
     #[test]
     fn test_threshold_overflow() {
-        let profile = Profile {
-            thresholds: [i32::MAX, i32::MAX, i32::MAX, i32::MAX],
-        };
+        let profile = Profile::new([i32::MAX, i32::MAX, i32::MAX, i32::MAX]);
 
         let json = serde_json::to_string(&profile).unwrap();
         let deserialized: Profile = serde_json::from_str(&json).unwrap();
@@ -368,9 +1551,7 @@ mod tests {
 
     #[test]
     fn test_empty_strings() {
-        let profile = Profile {
-            thresholds: [0, 0, 0, 0],
-        };
+        let profile = Profile::new([0, 0, 0, 0]);
 
         let json = serde_json::to_string(&profile).unwrap();
         let deserialized: Profile = serde_json::from_str(&json).unwrap();
@@ -381,9 +1562,7 @@ mod tests {
 
     #[test]
     fn test_unicode_characters() {
-        let profile = Profile {
-            thresholds: [100, 200, 300, 400],
-        };
+        let profile = Profile::new([100, 200, 300, 400]);
 
         let json = serde_json::to_string(&profile).unwrap();
         let deserialized: Profile = serde_json::from_str(&json).unwrap();
@@ -436,6 +1615,7 @@ mod tests {
     fn test_change_player_command_serialization() {
         let command = Command::ChangePlayer {
             name: "Player1".to_string(),
+            pad: None,
         };
 
         let json = serde_json::to_string(&command).unwrap();
@@ -448,6 +1628,7 @@ mod tests {
     fn test_change_player_command_debug() {
         let command = Command::ChangePlayer {
             name: "Player1".to_string(),
+            pad: None,
         };
 
         let debug_str = format!("{:?}", command);
@@ -458,20 +1639,9 @@ mod tests {
     fn test_profiles_with_players() {
         let profiles = Profiles {
             profiles: HashMap::from([
-                (
-                    "Profile1".to_string(),
-                    Profile {
-                        thresholds: [10, 20, 30, 40],
-                    },
-                ),
-                (
-                    "Profile2".to_string(),
-                    Profile {
-                        thresholds: [50, 60, 70, 80],
-                    },
-                ),
+                ("Profile1".to_string(), Profile::new([10, 20, 30, 40])),
+                ("Profile2".to_string(), Profile::new([50, 60, 70, 80])),
             ]),
-            current_profile: "Profile1".to_string(),
             default_profile: String::new(),
             players: HashMap::from([
                 (
@@ -489,7 +1659,12 @@ mod tests {
                     },
                 ),
             ]),
-            current_player: "Player1".to_string(),
+            pads: vec![Pad::default_pad(
+                "Profile1".to_string(),
+                "Player1".to_string(),
+            )],
+            revision: 0,
+            schedules: Vec::new(),
         };
 
         let json = serde_json::to_string_pretty(&profiles).unwrap();
@@ -498,7 +1673,86 @@ mod tests {
         assert_eq!(profiles, deserialized);
         assert_eq!(profiles.profiles.len(), 2);
         assert_eq!(profiles.players.len(), 2);
-        assert_eq!(profiles.current_profile, "Profile1");
-        assert_eq!(profiles.current_player, "Player1");
+        assert_eq!(profiles.current_profile(), "Profile1");
+        assert_eq!(profiles.current_player(), "Player1");
+    }
+
+    #[test]
+    fn test_legacy_profiles_migrate_to_default_pad() {
+        let legacy = r#"{
+            "profiles": {"Profile1": {"thresholds": [1, 2, 3, 4]}},
+            "current_profile": "Profile1",
+            "default_profile": "Profile1",
+            "players": {},
+            "current_player": "Player1"
+        }"#;
+        let profiles: Profiles = serde_json::from_str(legacy).unwrap();
+
+        assert_eq!(profiles.pads.len(), 1);
+        assert_eq!(profiles.pads[0].id, DEFAULT_PAD_ID);
+        assert_eq!(profiles.pads[0].sensor_mask, 0b1_1111_1111);
+        assert_eq!(profiles.current_profile(), "Profile1");
+        assert_eq!(profiles.current_player(), "Player1");
+
+        let saved = serde_json::to_value(&profiles).unwrap();
+        assert_eq!(saved["pads"][0]["current_profile"], "Profile1");
+        assert_eq!(saved["current_profile"], "Profile1");
+        assert_eq!(serde_json::from_value::<Profiles>(saved).unwrap(), profiles);
+    }
+
+    #[test]
+    fn test_pad_lookup() {
+        let mut profiles = Profiles::default();
+        profiles.pads.push(Pad {
+            id: "p2".to_string(),
+            ..Pad::default_pad("Profile2".to_string(), String::new())
+        });
+
+        assert_eq!(profiles.pad(None).unwrap().id, DEFAULT_PAD_ID);
+        assert_eq!(
+            profiles.pad(Some("p2")).unwrap().current_profile,
+            "Profile2"
+        );
+        assert!(profiles.drives_device(DEFAULT_PAD_ID));
+        assert!(!profiles.drives_device("p2"));
+        assert!(matches!(
+            profiles.pad_mut(Some("p3")),
+            Err(ValidationError::PadNotFound(id)) if id == "p3"
+        ));
+    }
+
+    #[test]
+    fn test_sensor_map() {
+        let map = SensorMap::try_from(vec![3, 0, 1, 2]).unwrap();
+        assert_eq!(map.physical(0), 3);
+        assert_eq!(map.to_physical([10, 20, 30, 40].into()), [20, 30, 40, 10]);
+        assert_eq!(map.to_logical([20, 30, 40, 10].into()), [10, 20, 30, 40]);
+        // A map of four sensors leaves a five-sensor pad's values alone
+        assert_eq!(map.to_logical([1, 2, 3, 4, 5].into()), [1, 2, 3, 4, 5]);
+
+        let map = SensorMap::try_from(vec![4, 3, 2, 1, 0]).unwrap();
+        assert_eq!(map.to_physical([1, 2, 3, 4, 5].into()), [5, 4, 3, 2, 1]);
+        assert!(SensorMap::try_from(vec![0, 1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap()
+            .is_identity());
+
+        assert_eq!(
+            SensorMap::try_from(vec![0, 1, 1, 2]),
+            Err(ValidationError::InvalidSensorMap(vec![0, 1, 1, 2]))
+        );
+        assert!(SensorMap::try_from(vec![0, 1, 2, 4]).is_err());
+        assert!(SensorMap::try_from((0..10).collect::<Vec<_>>()).is_err());
+
+        // A broken map in profiles.json fails to load instead of misrouting thresholds
+        let pad = r#"{"id": "p2", "name": "P2", "sensor_map": [0, 0, 0, 0]}"#;
+        assert!(serde_json::from_str::<Pad>(pad).is_err());
+        let pad = r#"{"id": "p2", "name": "P2", "sensor_map": [1, 0, 2, 3]}"#;
+        let pad: Pad = serde_json::from_str(pad).unwrap();
+        assert_eq!(pad.sensor_map.physical(1), 0);
+        assert!(
+            !serde_json::to_string(&Pad::default_pad(String::new(), String::new()))
+                .unwrap()
+                .contains("sensor_map")
+        );
     }
 }
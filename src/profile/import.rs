@@ -0,0 +1,259 @@
+use crate::sensors::Sensors;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// Formats ImportExternal reads, as named in the command
+pub const FORMATS: [&str; 2] = ["teejusb", "plain"];
+
+// A profile read from another tool's file, thresholds in file order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportedProfile {
+    pub name: String,
+    pub thresholds: Sensors<i32>,
+}
+
+// What ImportExternal does with a profile whose name is taken. Like ImportSharedProfile
+// it fails by default, and nothing is imported then.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    #[default]
+    Fail,
+    // Leave the existing profile and don't import this one
+    Skip,
+    // Import it as "<name> (2)", or the first free number after that
+    Rename,
+}
+
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum ImportError {
+    #[error("it contains no profiles")]
+    Empty,
+    #[error("line {line} isn't `{expected}`")]
+    Malformed { line: usize, expected: &'static str },
+    #[error("line {line} has {count} thresholds, at most 9")]
+    ThresholdCount { line: usize, count: usize },
+    #[error("'{value}' on line {line} is not a number")]
+    Number { line: usize, value: String },
+    #[error("profile '{name}' is on lines {first} and {line}")]
+    Duplicate {
+        name: String,
+        first: usize,
+        line: usize,
+    },
+}
+
+impl ImportError {
+    // For the `reason` param of INVALID_IMPORT
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ImportError::Empty => "empty",
+            ImportError::Malformed { .. } => "malformed",
+            ImportError::ThresholdCount { .. } => "threshold_count",
+            ImportError::Number { .. } => "number",
+            ImportError::Duplicate { .. } => "duplicate",
+        }
+    }
+
+    // 1-based line the error is on, for the `line` param
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            ImportError::Empty => None,
+            ImportError::Malformed { line, .. }
+            | ImportError::ThresholdCount { line, .. }
+            | ImportError::Number { line, .. }
+            | ImportError::Duplicate { line, .. } => Some(*line),
+        }
+    }
+}
+
+// The profiles of `payload` in `format`, None for a format that isn't in FORMATS. Blank
+// lines are skipped in both.
+pub fn parse(format: &str, payload: &str) -> Option<Result<Vec<ImportedProfile>, ImportError>> {
+    let parse_line = match format {
+        "teejusb" => teejusb_line,
+        "plain" => plain_line,
+        _ => return None,
+    };
+    let mut profiles: Vec<(usize, ImportedProfile)> = Vec::new();
+    for (index, text) in payload.lines().enumerate() {
+        let line = index + 1;
+        let Some(profile) = parse_line(text.trim(), line).transpose() else {
+            continue;
+        };
+        let profile = match profile {
+            Ok(profile) => profile,
+            Err(error) => return Some(Err(error)),
+        };
+        if let Some((first, _)) = profiles.iter().find(|(_, seen)| seen.name == profile.name) {
+            return Some(Err(ImportError::Duplicate {
+                name: profile.name,
+                first: *first,
+                line,
+            }));
+        }
+        profiles.push((line, profile));
+    }
+    if profiles.is_empty() {
+        return Some(Err(ImportError::Empty));
+    }
+    Some(Ok(profiles
+        .into_iter()
+        .map(|(_, profile)| profile)
+        .collect()))
+}
+
+// `profiles.txt` as the teejusb/fsr web UI's server keeps it: the name and the thresholds
+// separated by spaces, so names have none
+fn teejusb_line(text: &str, line: usize) -> Result<Option<ImportedProfile>, ImportError> {
+    let mut parts = text.split_whitespace();
+    let Some(name) = parts.next() else {
+        return Ok(None);
+    };
+    let values: Vec<&str> = parts.collect();
+    if values.is_empty() {
+        return Err(ImportError::Malformed {
+            line,
+            expected: "name v1 v2 v3 v4",
+        });
+    }
+    Ok(Some(ImportedProfile {
+        name: name.to_string(),
+        thresholds: thresholds(&values, line)?,
+    }))
+}
+
+// `name: v1,v2,v3,v4`, with `#` comments
+fn plain_line(text: &str, line: usize) -> Result<Option<ImportedProfile>, ImportError> {
+    if text.is_empty() || text.starts_with('#') {
+        return Ok(None);
+    }
+    let malformed = ImportError::Malformed {
+        line,
+        expected: "name: v1,v2,v3,v4",
+    };
+    let Some((name, values)) = text.rsplit_once(':') else {
+        return Err(malformed);
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(malformed);
+    }
+    let values: Vec<&str> = values.split(',').map(str::trim).collect();
+    Ok(Some(ImportedProfile {
+        name: name.to_string(),
+        thresholds: thresholds(&values, line)?,
+    }))
+}
+
+fn thresholds(values: &[&str], line: usize) -> Result<Sensors<i32>, ImportError> {
+    let values = values
+        .iter()
+        .map(|value| {
+            value.parse().map_err(|_| ImportError::Number {
+                line,
+                value: value.to_string(),
+            })
+        })
+        .collect::<Result<Vec<i32>, _>>()?;
+    Sensors::from_slice(&values).ok_or(ImportError::ThresholdCount {
+        line,
+        count: values.len(),
+    })
+}
+
+// `name` if it is free, else with the first free number from 2 on
+pub fn free_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(name) {
+        return name.to_string();
+    }
+    (2..)
+        .map(|n| format!("{} ({})", name, n))
+        .find(|candidate| !taken(candidate))
+        .expect("some number is free")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEEJUSB: &str = include_str!("../fixtures/import/teejusb_profiles.txt");
+    const PLAIN: &str = include_str!("../fixtures/import/plain.txt");
+
+    fn profile(name: &str, thresholds: Sensors<i32>) -> ImportedProfile {
+        ImportedProfile {
+            name: name.to_string(),
+            thresholds,
+        }
+    }
+
+    #[test]
+    fn test_teejusb_profiles() {
+        assert_eq!(
+            parse("teejusb", TEEJUSB).unwrap().unwrap(),
+            [
+                profile("Casual", [350, 380, 360, 340].into()),
+                profile("Tournament", [420, 450, 430, 410].into()),
+            ]
+        );
+        assert_eq!(
+            parse("teejusb", "Casual 1 2 3 4 5 6 7 8 9 10").unwrap(),
+            Err(ImportError::ThresholdCount { line: 1, count: 10 })
+        );
+        assert_eq!(
+            parse("teejusb", "\nCasual").unwrap().unwrap_err().line(),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_plain_profiles() {
+        assert_eq!(
+            parse("plain", PLAIN).unwrap().unwrap(),
+            [
+                profile("Casual", [350, 380, 360, 340].into()),
+                profile("Worn pad", [300, 310, 290, 305].into()),
+                profile("Tournament", [420, 450, 430, 410].into()),
+            ]
+        );
+        assert_eq!(
+            parse("plain", "Casual: 1, 2, x, 4").unwrap(),
+            Err(ImportError::Number {
+                line: 1,
+                value: "x".to_string()
+            })
+        );
+        assert_eq!(
+            parse("plain", "Casual 1,2,3,4")
+                .unwrap()
+                .unwrap_err()
+                .reason(),
+            "malformed"
+        );
+        assert_eq!(
+            parse("plain", "a: 1,2,3,4\na: 5,6,7,8")
+                .unwrap()
+                .unwrap_err(),
+            ImportError::Duplicate {
+                name: "a".to_string(),
+                first: 1,
+                line: 2
+            }
+        );
+        assert_eq!(
+            parse("plain", "# nothing\n\n").unwrap(),
+            Err(ImportError::Empty)
+        );
+    }
+
+    #[test]
+    fn test_unknown_format_and_free_names() {
+        assert_eq!(parse("stepmania", PLAIN), None);
+        let taken = ["Casual", "Casual (2)"];
+        assert_eq!(
+            free_name("Casual", |name| taken.contains(&name)),
+            "Casual (3)"
+        );
+        assert_eq!(free_name("Other", |name| taken.contains(&name)), "Other");
+    }
+}
@@ -0,0 +1,267 @@
+use crate::audit::Origin;
+use crate::error::{AppError, StorageError, ValidationError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tokio::sync::{Mutex, MutexGuard};
+
+// Proposals kept, pending and decided; a new one pushes out the oldest decided one, and
+// none is taken while this many are pending
+pub const PROPOSAL_CAPACITY: usize = 100;
+
+// Longest note of a proposal, in characters
+pub const MAX_NOTE_LEN: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProposalStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+// A threshold change suggested by a viewer, waiting for an operator's decision
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Proposal {
+    pub id: u64,
+    pub profile: String,
+    pub index: u8,
+    pub value: i32,
+    // The threshold when it was proposed
+    pub current: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    // Of the profile when it was proposed; approving it after the profile changed again
+    // takes `force`
+    pub revision: u64,
+    pub proposed_at: DateTime<Utc>,
+    pub proposed_by: Origin,
+    pub status: ProposalStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decided_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decided_by: Option<Origin>,
+    // Given with RejectProposal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+// What `--proposals-file` holds
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Proposals {
+    pub next_id: u64,
+    pub proposals: VecDeque<Proposal>,
+}
+
+impl Proposals {
+    fn pending(&self) -> usize {
+        self.proposals
+            .iter()
+            .filter(|proposal| proposal.status == ProposalStatus::Pending)
+            .count()
+    }
+
+    // A pending proposal
+    pub fn get(&self, id: u64) -> Result<&Proposal, AppError> {
+        match self.proposals.iter().find(|proposal| proposal.id == id) {
+            Some(proposal) if proposal.status == ProposalStatus::Pending => Ok(proposal),
+            Some(proposal) => Err(ValidationError::ProposalDecided {
+                id,
+                status: proposal.status,
+            }
+            .into()),
+            None => Err(ValidationError::ProposalNotFound(id).into()),
+        }
+    }
+}
+
+// The proposals, in memory and, with `--proposals-file`, on disk. Changes are written
+// before they take effect, so a failed write changes nothing. Decisions hold the lock
+// from the check to the write, so a proposal is approved or rejected once.
+pub struct ProposalStore {
+    file: Option<PathBuf>,
+    proposals: Mutex<Proposals>,
+}
+
+impl Default for ProposalStore {
+    fn default() -> Self {
+        Self::new(None, Proposals::default())
+    }
+}
+
+impl ProposalStore {
+    pub fn new(file: Option<PathBuf>, proposals: Proposals) -> Self {
+        Self {
+            file,
+            proposals: Mutex::new(proposals),
+        }
+    }
+
+    // The store kept in `file`, empty if it doesn't exist yet
+    pub async fn load(file: PathBuf) -> Result<Self, String> {
+        let proposals = match tokio::fs::read_to_string(&file).await {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| format!("Failed to parse {}: {}", file.display(), e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Proposals::default(),
+            Err(e) => return Err(format!("Failed to read {}: {}", file.display(), e)),
+        };
+        Ok(Self::new(Some(file), proposals))
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, Proposals> {
+        self.proposals.lock().await
+    }
+
+    // Every proposal kept, oldest first
+    pub async fn list(&self) -> Vec<Proposal> {
+        self.lock().await.proposals.iter().cloned().collect()
+    }
+
+    // Store a new pending proposal, numbered by the store
+    pub async fn create(&self, mut proposal: Proposal) -> Result<Proposal, AppError> {
+        let mut proposals = self.lock().await;
+        if proposals.pending() >= PROPOSAL_CAPACITY {
+            return Err(ValidationError::QuotaExceeded {
+                kind: "proposals",
+                limit: PROPOSAL_CAPACITY,
+            }
+            .into());
+        }
+        let mut changed = proposals.clone();
+        changed.next_id += 1;
+        proposal.id = changed.next_id;
+        if changed.proposals.len() >= PROPOSAL_CAPACITY {
+            let decided = changed
+                .proposals
+                .iter()
+                .position(|proposal| proposal.status != ProposalStatus::Pending);
+            if let Some(decided) = decided {
+                changed.proposals.remove(decided);
+            }
+        }
+        changed.proposals.push_back(proposal.clone());
+        self.save(&mut proposals, changed).await?;
+        Ok(proposal)
+    }
+
+    // Record the decision on pending proposal `id`, with the lock taken for the check
+    pub async fn decide(
+        &self,
+        proposals: &mut MutexGuard<'_, Proposals>,
+        id: u64,
+        status: ProposalStatus,
+        by: Origin,
+        reason: Option<String>,
+    ) -> Result<Proposal, AppError> {
+        proposals.get(id)?;
+        let mut changed = (**proposals).clone();
+        let proposal = changed
+            .proposals
+            .iter_mut()
+            .find(|proposal| proposal.id == id)
+            .expect("checked above");
+        proposal.status = status;
+        proposal.decided_at = Some(Utc::now());
+        proposal.decided_by = Some(by);
+        proposal.reason = reason;
+        let decided = proposal.clone();
+        self.save(proposals, changed).await?;
+        Ok(decided)
+    }
+
+    async fn save(&self, proposals: &mut Proposals, changed: Proposals) -> Result<(), AppError> {
+        if let Some(file) = &self.file {
+            write(file, &changed)
+                .await
+                .map_err(|e| AppError::from(StorageError::Proposals(e)))?;
+        }
+        *proposals = changed;
+        Ok(())
+    }
+}
+
+// Written aside and renamed, so a crash never leaves half a file
+async fn write(file: &PathBuf, proposals: &Proposals) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(proposals)?;
+    let partial = file.with_extension("json.tmp");
+    tokio::fs::write(&partial, json).await?;
+    tokio::fs::rename(&partial, file).await
+}
+
+pub fn check_note(note: Option<&str>) -> Result<(), ValidationError> {
+    match note {
+        Some(note) if note.chars().count() > MAX_NOTE_LEN => {
+            Err(ValidationError::NoteTooLong(MAX_NOTE_LEN))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proposal(value: i32) -> Proposal {
+        Proposal {
+            id: 0,
+            profile: "Casual".to_string(),
+            index: 0,
+            value,
+            current: 400,
+            note: None,
+            revision: 1,
+            proposed_at: Utc::now(),
+            proposed_by: Origin::Websocket { connection: 3 },
+            status: ProposalStatus::Pending,
+            decided_at: None,
+            decided_by: None,
+            reason: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proposals_are_bounded_and_kept_in_the_file() {
+        let file = std::env::temp_dir().join(format!(
+            "fsr-rs-proposals-{}-{}.json",
+            std::process::id(),
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        let store = ProposalStore::load(file.clone()).await.unwrap();
+        for value in 0..PROPOSAL_CAPACITY as i32 {
+            store.create(proposal(value)).await.unwrap();
+        }
+        let full = store.create(proposal(1)).await.unwrap_err();
+        assert_eq!(full.code(), "QUOTA_EXCEEDED");
+
+        // A decided proposal makes room, and the oldest decided one goes
+        let mut proposals = store.lock().await;
+        let by = Origin::Websocket { connection: 4 };
+        let rejected = store
+            .decide(&mut proposals, 2, ProposalStatus::Rejected, by, None)
+            .await
+            .unwrap();
+        assert_eq!(rejected.decided_by, Some(by));
+        let twice = store
+            .decide(&mut proposals, 2, ProposalStatus::Approved, by, None)
+            .await
+            .unwrap_err();
+        assert_eq!(twice.code(), "PROPOSAL_DECIDED");
+        drop(proposals);
+        let created = store.create(proposal(7)).await.unwrap();
+        assert_eq!(created.id, PROPOSAL_CAPACITY as u64 + 1);
+        let ids: Vec<u64> = store.list().await.iter().map(|p| p.id).collect();
+        assert_eq!(ids.len(), PROPOSAL_CAPACITY);
+        assert!(!ids.contains(&2));
+
+        // A restart picks them up where they were
+        let reloaded = ProposalStore::load(file.clone()).await.unwrap();
+        assert_eq!(reloaded.list().await, store.list().await);
+        assert_eq!(
+            reloaded.lock().await.get(3).unwrap().proposed_by,
+            Origin::Websocket { connection: 3 }
+        );
+        let _ = std::fs::remove_file(file);
+    }
+}
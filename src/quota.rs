@@ -0,0 +1,78 @@
+use crate::error::ValidationError;
+use crate::profile::Profiles;
+use serde::Serialize;
+
+pub const DEFAULT_MAX_PROFILES: usize = 500;
+
+pub const DEFAULT_MAX_PLAYERS: usize = 2000;
+
+// Caps on what commands may create, so a runaway script can't grow profiles.json until
+// every save and broadcast crawls. Reported in the server info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Quotas {
+    pub max_profiles: usize,
+    pub max_players: usize,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Self {
+            max_profiles: DEFAULT_MAX_PROFILES,
+            max_players: DEFAULT_MAX_PLAYERS,
+        }
+    }
+}
+
+impl Quotas {
+    // Whether one more profile fits
+    pub fn check_new_profile(&self, profiles: &Profiles) -> Result<(), ValidationError> {
+        check("profiles", profiles.profiles.len(), self.max_profiles)
+    }
+
+    // Whether `count` more profiles fit
+    pub fn check_new_profiles(
+        &self,
+        profiles: &Profiles,
+        count: usize,
+    ) -> Result<(), ValidationError> {
+        match count {
+            0 => Ok(()),
+            count => check(
+                "profiles",
+                profiles.profiles.len() + count - 1,
+                self.max_profiles,
+            ),
+        }
+    }
+
+    // Whether one more player fits
+    pub fn check_new_player(&self, profiles: &Profiles) -> Result<(), ValidationError> {
+        check("players", profiles.players.len(), self.max_players)
+    }
+
+    // Warnings for a profiles file that is already over a limit; it still loads, only
+    // creating more is refused
+    pub fn warnings(&self, profiles: &Profiles) -> Vec<String> {
+        [
+            ("profiles", profiles.profiles.len(), self.max_profiles),
+            ("players", profiles.players.len(), self.max_players),
+        ]
+        .into_iter()
+        .filter(|(_, count, limit)| count > limit)
+        .map(|(kind, count, limit)| {
+            format!(
+                "{} {} loaded, over the limit of {}; creating more is refused",
+                count, kind, limit
+            )
+        })
+        .collect()
+    }
+}
+
+fn check(kind: &'static str, count: usize, limit: usize) -> Result<(), ValidationError> {
+    if count < limit {
+        Ok(())
+    } else {
+        Err(ValidationError::QuotaExceeded { kind, limit })
+    }
+}
@@ -0,0 +1,157 @@
+use crate::error::ValidationError;
+use crate::profile::SENSOR_MAX;
+use serde::Serialize;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+// Where the largest accepted threshold comes from, most trusted first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BoundSource {
+    // Readings above the 10-bit range showed the device's ADC is wider
+    Device,
+    // `--threshold-max`
+    Flag,
+    // The 10-bit range of the stock firmware
+    Default,
+}
+
+impl std::fmt::Display for BoundSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BoundSource::Device => "learned from the device",
+            BoundSource::Flag => "set by --threshold-max",
+            BoundSource::Default => "default",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ThresholdBound {
+    pub max: i32,
+    pub source: BoundSource,
+}
+
+impl ThresholdBound {
+    pub fn check(&self, value: i32) -> Result<(), ValidationError> {
+        if (0..=self.max).contains(&value) {
+            Ok(())
+        } else {
+            Err(ValidationError::ThresholdOutOfRange {
+                value,
+                max: self.max,
+                bound_source: self.source,
+            })
+        }
+    }
+
+    pub fn check_all(&self, thresholds: &[i32]) -> Result<(), ValidationError> {
+        thresholds.iter().try_for_each(|&value| self.check(value))
+    }
+}
+
+// Value range of the device's sensors, and how many it has. The firmware has no command
+// to ask for either, so a 12-bit board is recognized by its readings going past the
+// 10-bit maximum, and the sensors are counted in its readings.
+pub struct DeviceRange {
+    // Largest value the device can report, 0 until a reading showed it
+    learned: AtomicI32,
+    flag: Option<i32>,
+    // Values in the device's readings, 0 before the first
+    sensors: AtomicUsize,
+}
+
+impl DeviceRange {
+    pub fn new(flag: Option<i32>) -> Self {
+        Self {
+            learned: AtomicI32::new(0),
+            flag,
+            sensors: AtomicUsize::new(0),
+        }
+    }
+
+    // Called with every sensor reading; a value past the 10-bit range means the ADC is
+    // as wide as the next power of two
+    pub fn observe(&self, values: &[i32]) {
+        self.count(values.len());
+        let Some(&highest) = values.iter().max() else {
+            return;
+        };
+        if highest > SENSOR_MAX && highest > self.learned.load(Ordering::Relaxed) {
+            let max = (highest as u32 + 1).next_power_of_two() - 1;
+            self.learned
+                .fetch_max(max.min(i32::MAX as u32) as i32, Ordering::Relaxed);
+        }
+    }
+
+    // Called with the thresholds read from the device, one per sensor like a reading
+    pub fn count(&self, sensors: usize) {
+        self.sensors.store(sensors, Ordering::Relaxed);
+    }
+
+    // Number of sensors of the device, None until it was read
+    pub fn sensors(&self) -> Option<usize> {
+        Some(self.sensors.load(Ordering::Relaxed)).filter(|&sensors| sensors > 0)
+    }
+
+    pub fn bound(&self) -> ThresholdBound {
+        match (self.learned.load(Ordering::Relaxed), self.flag) {
+            (learned, _) if learned > 0 => ThresholdBound {
+                max: learned,
+                source: BoundSource::Device,
+            },
+            (_, Some(max)) => ThresholdBound {
+                max,
+                source: BoundSource::Flag,
+            },
+            _ => ThresholdBound {
+                max: SENSOR_MAX,
+                source: BoundSource::Default,
+            },
+        }
+    }
+}
+
+impl Default for DeviceRange {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bound_sources() {
+        let range = DeviceRange::default();
+        assert_eq!(range.sensors(), None);
+        assert_eq!(range.bound().max, 1023);
+        assert_eq!(range.bound().source, BoundSource::Default);
+        assert!(range.bound().check(1023).is_ok());
+        assert!(range.bound().check(-1).is_err());
+
+        let range = DeviceRange::new(Some(4095));
+        assert_eq!(range.bound().source, BoundSource::Flag);
+        // A 10-bit reading says nothing about the width
+        range.observe(&[10, 1023, 0, 5]);
+        assert_eq!(range.bound().source, BoundSource::Flag);
+        assert_eq!(range.sensors(), Some(4));
+
+        range.observe(&[10, 3000, 0, 5]);
+        let bound = range.bound();
+        assert_eq!((bound.max, bound.source), (4095, BoundSource::Device));
+        assert_eq!(
+            bound.check(5000),
+            Err(ValidationError::ThresholdOutOfRange {
+                value: 5000,
+                max: 4095,
+                bound_source: BoundSource::Device,
+            })
+        );
+        assert!(bound.check_all(&[0, 4095, 2000, 1]).is_ok());
+        // The learned range never shrinks
+        range.observe(&[1024, 0, 0, 0, 0]);
+        assert_eq!(range.bound().max, 4095);
+        assert_eq!(range.sensors(), Some(5));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::profile::{Pad, Profile, Profiles};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+
+// A reference in profiles.json to something that doesn't exist, and what it was changed
+// to on load. Files edited by hand or written by an older version after a crash can point
+// players and pads at removed profiles, which would otherwise only fail once a command
+// touches them.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Repair {
+    DefaultProfile {
+        from: String,
+        to: String,
+    },
+    PlayerProfile {
+        player: String,
+        from: String,
+        to: String,
+    },
+    PadProfile {
+        pad: String,
+        from: String,
+        to: String,
+    },
+    // Cleared, so the shared default applies to the pad again
+    PadDefaultProfile {
+        pad: String,
+        from: String,
+    },
+    // Cleared; the pad has no player selected
+    PadPlayer {
+        pad: String,
+        from: String,
+    },
+    // No profile exists to move these players to; they are left as they are and fixed on
+    // the next load once one does
+    PlayersWithoutProfiles {
+        players: Vec<String>,
+    },
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Repair::DefaultProfile { from, to } => {
+                write!(f, "default profile '{}' doesn't exist, now '{}'", from, to)
+            }
+            Repair::PlayerProfile { player, from, to } => write!(
+                f,
+                "player '{}' had missing profile '{}', now '{}'",
+                player, from, to
+            ),
+            Repair::PadProfile { pad, from, to } if from.is_empty() => {
+                write!(f, "pad '{}' had no current profile, now '{}'", pad, to)
+            }
+            Repair::PadProfile { pad, from, to } => write!(
+                f,
+                "pad '{}' had missing current profile '{}', now '{}'",
+                pad, from, to
+            ),
+            Repair::PadDefaultProfile { pad, from } => write!(
+                f,
+                "pad '{}' had missing default profile '{}', cleared",
+                pad, from
+            ),
+            Repair::PadPlayer { pad, from } => write!(
+                f,
+                "pad '{}' had missing current player '{}', cleared",
+                pad, from
+            ),
+            Repair::PlayersWithoutProfiles { players } => write!(
+                f,
+                "no profiles exist for players {}; left unchanged",
+                players.join(", ")
+            ),
+        }
+    }
+}
+
+// Point every dangling reference at something that exists: the default profile, or the
+// first profile by name if the default is missing too. Returns what was changed, in a
+// stable order; an empty list means the profiles were already consistent.
+pub fn repair(profiles: &mut Profiles) -> Vec<Repair> {
+    let mut repairs = Vec::new();
+    let fallback = if profiles.profiles.contains_key(&profiles.default_profile) {
+        Some(profiles.default_profile.clone())
+    } else {
+        profiles.profiles.keys().min().cloned()
+    };
+
+    if let Some(fallback) = &fallback {
+        if !profiles.default_profile.is_empty() && profiles.default_profile != *fallback {
+            repairs.push(Repair::DefaultProfile {
+                from: std::mem::replace(&mut profiles.default_profile, fallback.clone()),
+                to: fallback.clone(),
+            });
+        }
+    }
+
+    let mut names: Vec<String> = profiles.players.keys().cloned().collect();
+    names.sort();
+    let mut stranded = Vec::new();
+    for name in names {
+        let Some(player) = profiles.players.get_mut(&name) else {
+            continue;
+        };
+        if profiles.profiles.contains_key(&player.profile) {
+            continue;
+        }
+        match &fallback {
+            Some(fallback) => repairs.push(Repair::PlayerProfile {
+                player: name,
+                from: std::mem::replace(&mut player.profile, fallback.clone()),
+                to: fallback.clone(),
+            }),
+            None => stranded.push(name),
+        }
+    }
+    if !stranded.is_empty() {
+        repairs.push(Repair::PlayersWithoutProfiles { players: stranded });
+    }
+
+    for pad in &mut profiles.pads {
+        // A pad without a current profile gets one as well, rather than failing every
+        // command that needs its thresholds
+        if !profiles.profiles.contains_key(&pad.current_profile) {
+            let to = pad_fallback(pad, &profiles.profiles, fallback.as_deref());
+            if to != pad.current_profile {
+                repairs.push(Repair::PadProfile {
+                    pad: pad.id.clone(),
+                    from: std::mem::replace(&mut pad.current_profile, to.clone()),
+                    to,
+                });
+            }
+        }
+        if let Some(default) = pad
+            .default_profile
+            .take_if(|default| !profiles.profiles.contains_key(default))
+        {
+            repairs.push(Repair::PadDefaultProfile {
+                pad: pad.id.clone(),
+                from: default,
+            });
+        }
+        if !pad.current_player.is_empty() && !profiles.players.contains_key(&pad.current_player) {
+            repairs.push(Repair::PadPlayer {
+                pad: pad.id.clone(),
+                from: std::mem::take(&mut pad.current_player),
+            });
+        }
+    }
+    repairs
+}
+
+// Profile a pad whose current profile is missing falls back to: its own default, then
+// the shared default, then the first profile by name. Empty if there are no profiles;
+// the server creates the bootstrap profile before repairing, so that only happens when a
+// file without profiles is reloaded.
+fn pad_fallback(pad: &Pad, existing: &HashMap<String, Profile>, fallback: Option<&str>) -> String {
+    pad.default_profile
+        .as_deref()
+        .filter(|default| existing.contains_key(*default))
+        .or(fallback)
+        .unwrap_or_default()
+        .to_string()
+}
+
+// Log what repair() changed so users know their file was touched
+pub fn report(repairs: &[Repair]) {
+    if repairs.is_empty() {
+        return;
+    }
+    eprintln!(
+        "Repaired {} dangling reference(s) in profiles.json:",
+        repairs.len()
+    );
+    for repair in repairs {
+        eprintln!("  {}", repair);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Player;
+
+    fn player(name: &str, profile: &str) -> (String, Player) {
+        (
+            name.to_string(),
+            Player {
+                name: name.to_string(),
+                profile: profile.to_string(),
+            },
+        )
+    }
+
+    #[test]
+    fn test_repair_dangling_references() {
+        let mut pad = Pad::default_pad("Gone".to_string(), "Nobody".to_string());
+        pad.default_profile = Some("Removed".to_string());
+        let mut profiles = Profiles {
+            profiles: [
+                ("Casual".to_string(), Profile::new([400; 4])),
+                ("Tight".to_string(), Profile::new([600; 4])),
+            ]
+            .into(),
+            default_profile: "Missing".to_string(),
+            players: [player("Alice", "Casual"), player("Bob", "Gone")].into(),
+            pads: vec![pad],
+            revision: 0,
+            schedules: Vec::new(),
+        };
+
+        let repairs = repair(&mut profiles);
+        assert_eq!(
+            repairs,
+            vec![
+                Repair::DefaultProfile {
+                    from: "Missing".to_string(),
+                    to: "Casual".to_string(),
+                },
+                Repair::PlayerProfile {
+                    player: "Bob".to_string(),
+                    from: "Gone".to_string(),
+                    to: "Casual".to_string(),
+                },
+                Repair::PadProfile {
+                    pad: "default".to_string(),
+                    from: "Gone".to_string(),
+                    to: "Casual".to_string(),
+                },
+                Repair::PadDefaultProfile {
+                    pad: "default".to_string(),
+                    from: "Removed".to_string(),
+                },
+                Repair::PadPlayer {
+                    pad: "default".to_string(),
+                    from: "Nobody".to_string(),
+                },
+            ]
+        );
+        assert_eq!(profiles.players["Alice"].profile, "Casual");
+        assert_eq!(profiles.players["Bob"].profile, "Casual");
+        assert_eq!(profiles.current_profile(), "Casual");
+        assert_eq!(profiles.current_player(), "");
+        assert_eq!(profiles.pads[0].default_profile, None);
+        // Nothing left to repair
+        assert!(repair(&mut profiles).is_empty());
+
+        // Players are kept when there is no profile to move them to
+        let mut profiles = Profiles {
+            players: [player("Bob", "Gone")].into(),
+            ..Profiles::default()
+        };
+        assert_eq!(
+            repair(&mut profiles),
+            vec![Repair::PlayersWithoutProfiles {
+                players: vec!["Bob".to_string()],
+            }]
+        );
+        assert_eq!(profiles.players["Bob"].profile, "Gone");
+    }
+
+    #[test]
+    fn test_current_profile_falls_back() {
+        let profiles = |current: &str, pad_default: Option<&str>, default: &str| {
+            let mut pad = Pad::default_pad(current.to_string(), String::new());
+            pad.default_profile = pad_default.map(String::from);
+            Profiles {
+                profiles: [
+                    ("Casual".to_string(), Profile::new([400; 4])),
+                    ("Tight".to_string(), Profile::new([600; 4])),
+                ]
+                .into(),
+                default_profile: default.to_string(),
+                players: HashMap::new(),
+                pads: vec![pad],
+                revision: 0,
+                schedules: Vec::new(),
+            }
+        };
+        // The pad's own default, then the shared one, then the first by name
+        for (current, pad_default, default, expected) in [
+            ("Deleted", Some("Tight"), "Casual", "Tight"),
+            ("Deleted", None, "Tight", "Tight"),
+            ("", None, "Tight", "Tight"),
+            ("Deleted", None, "", "Casual"),
+        ] {
+            let mut profiles = profiles(current, pad_default, default);
+            let repairs = repair(&mut profiles);
+            assert_eq!(profiles.current_profile(), expected, "{:?}", repairs);
+            assert_eq!(
+                repairs.last(),
+                Some(&Repair::PadProfile {
+                    pad: "default".to_string(),
+                    from: current.to_string(),
+                    to: expected.to_string(),
+                })
+            );
+        }
+        assert_eq!(
+            Repair::PadProfile {
+                pad: "default".to_string(),
+                from: String::new(),
+                to: "Tight".to_string(),
+            }
+            .to_string(),
+            "pad 'default' had no current profile, now 'Tight'"
+        );
+    }
+}
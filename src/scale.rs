@@ -0,0 +1,198 @@
+use crate::event::Event;
+use crate::profile::Profiles;
+use crate::sensors::Sensors;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+// How a connection sees the sensor values of the stream frames. Only the frames sent to
+// it change: thresholds, commands and everything stored stay in raw device units.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scale {
+    // Device counts, e.g. 0-1023
+    #[default]
+    Raw,
+    // Percent of the sensor's threshold in the pad's current profile, 100 at the threshold
+    PercentOfThreshold,
+    // 0.0-1.0 over the device's range
+    Normalized,
+}
+
+impl fmt::Display for Scale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scale::Raw => "raw",
+            Scale::PercentOfThreshold => "percent_of_threshold",
+            Scale::Normalized => "normalized",
+        })
+    }
+}
+
+impl Scale {
+    // One reading in this scale. A sensor without a meaningful value, one with a zero
+    // threshold or a pad without a profile, is null.
+    pub fn apply(self, values: Sensors<i32>, thresholds: Option<Sensors<i32>>, max: i32) -> Value {
+        let scaled: Vec<Option<f64>> = match self {
+            Scale::Raw => return serde_json::json!(values),
+            Scale::PercentOfThreshold => values
+                .iter()
+                .enumerate()
+                .map(|(sensor, &value)| {
+                    let threshold =
+                        thresholds.and_then(|thresholds| thresholds.get(sensor).copied());
+                    threshold
+                        .filter(|&threshold| threshold > 0)
+                        .map(|threshold| round(value as f64 * 100.0 / threshold as f64, 10.0))
+                })
+                .collect(),
+            Scale::Normalized => values
+                .iter()
+                .map(|&value| {
+                    (max > 0).then(|| round(value.clamp(0, max) as f64 / max as f64, 1000.0))
+                })
+                .collect(),
+        };
+        serde_json::json!(scaled)
+    }
+}
+
+fn round(value: f64, per_unit: f64) -> f64 {
+    (value * per_unit).round() / per_unit
+}
+
+// Thresholds of the profile `pad` is on
+fn thresholds(profiles: &Profiles, pad: &str) -> Option<Sensors<i32>> {
+    let pad = profiles.pad(Some(pad)).ok()?;
+    let profile = profiles.profiles.get(&pad.current_profile)?;
+    Some(profile.thresholds)
+}
+
+// Rewrite `frame`, the serialized `event`, in `scale`: the values and calibrated values
+// of a sensor frame, or each pad's values of an aggregate frame. `max` is the top of the
+// device's range. Other events are left as they are.
+pub fn rescale(frame: &mut Value, event: &Event, scale: Scale, profiles: &Profiles, max: i32) {
+    match event {
+        Event::SensorFrame {
+            pad,
+            values,
+            calibrated,
+            ..
+        } => {
+            let thresholds = thresholds(profiles, pad);
+            frame["sensor_values"] = scale.apply(*values, thresholds, max);
+            if let Some(calibrated) = calibrated {
+                frame["payload"]["calibrated"] = scale.apply(*calibrated, thresholds, max);
+            }
+        }
+        Event::AggregateFrame(pads, _) => {
+            for (i, reading) in pads.iter().enumerate() {
+                if let Some(values) = reading.values {
+                    let thresholds = thresholds(profiles, &reading.pad);
+                    frame["payload"]["pads"][i]["values"] = scale.apply(values, thresholds, max);
+                }
+            }
+        }
+        _ => return,
+    }
+    frame["payload"]["scale"] = serde_json::json!(scale);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::PadReading;
+    use crate::event::WireJson;
+    use crate::profile::{Pad, Profile};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_scales() {
+        let values = [0, 310, 500, 1023].into();
+        let thresholds = Some([500, 500, 0, 400].into());
+        assert_eq!(
+            Scale::PercentOfThreshold.apply(values, thresholds, 1023),
+            serde_json::json!([0.0, 62.0, null, 255.8])
+        );
+        assert_eq!(
+            Scale::PercentOfThreshold.apply(values, None, 1023),
+            serde_json::json!([null, null, null, null])
+        );
+        assert_eq!(
+            Scale::Normalized.apply(values, thresholds, 1023),
+            serde_json::json!([0.0, 0.303, 0.489, 1.0])
+        );
+        // A reading past a configured range doesn't go over 1
+        assert_eq!(
+            Scale::Normalized.apply([-5, 0, 0, 2000].into(), None, 1023),
+            serde_json::json!([0.0, 0.0, 0.0, 1.0])
+        );
+        assert_eq!(
+            Scale::Raw.apply(values, thresholds, 1023),
+            serde_json::json!(values)
+        );
+    }
+
+    #[test]
+    fn test_rescale_frames() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("P".to_string(), Profile::new([200, 200, 0, 200]))]),
+            pads: vec![Pad::default_pad("P".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        let frame = Event::SensorFrame {
+            pad: "default".into(),
+            values: [100, 200, 300, 50].into(),
+            calibrated: Some([100, 100, 100, 100].into()),
+            rate_hz: 60,
+            wire: WireJson::default(),
+        };
+        let mut json = serde_json::to_value(frame.to_response()).unwrap();
+        rescale(
+            &mut json,
+            &frame,
+            Scale::PercentOfThreshold,
+            &profiles,
+            1023,
+        );
+        assert_eq!(
+            json["sensor_values"],
+            serde_json::json!([50.0, 100.0, null, 25.0])
+        );
+        assert_eq!(
+            json["payload"]["calibrated"],
+            serde_json::json!([50.0, 50.0, null, 50.0])
+        );
+        assert_eq!(json["payload"]["scale"], "percent_of_threshold");
+        assert_eq!(json["payload"]["rate_hz"], 60);
+        // The thresholds themselves stay raw
+        assert_eq!(profiles.profiles["P"].thresholds, [200, 200, 0, 200]);
+
+        let aggregate = Event::AggregateFrame(
+            vec![
+                PadReading {
+                    pad: "default".to_string(),
+                    values: Some([1023, 0, 0, 0].into()),
+                    age_ms: Some(3),
+                    stale: false,
+                },
+                PadReading {
+                    pad: "unknown".to_string(),
+                    values: None,
+                    age_ms: None,
+                    stale: true,
+                },
+            ]
+            .into(),
+            WireJson::default(),
+        );
+        let mut json = serde_json::to_value(aggregate.to_response()).unwrap();
+        rescale(&mut json, &aggregate, Scale::Normalized, &profiles, 1023);
+        assert_eq!(
+            json["payload"]["pads"][0]["values"],
+            serde_json::json!([1.0, 0.0, 0.0, 0.0])
+        );
+        assert_eq!(json["payload"]["pads"][1]["values"], Value::Null);
+        assert_eq!(json["payload"]["scale"], "normalized");
+    }
+}
@@ -0,0 +1,430 @@
+use crate::audit::Origin;
+use crate::commands::{handle_envelope, Envelope};
+use crate::error::ValidationError;
+use crate::event::Event;
+use crate::identify::SESSION_IDLE;
+use crate::journal::JournalKind;
+use crate::profile::{Command, Profiles};
+use crate::state::AppState;
+use chrono::{
+    DateTime, Datelike, Local, LocalResult, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Timelike,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How far ahead ListSchedules looks for the next run; covers a cron for 29 February
+const NEXT_RUN_HORIZON_DAYS: u64 = 4 * 366;
+
+// A profile switch at a local time, stored in profiles.json. `cron_or_time` is either a
+// daily `HH:MM` or a five-field cron expression, both in the server's local time zone.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Schedule {
+    pub id: String,
+    pub cron_or_time: String,
+    pub profile: String,
+    // Pad to switch; the first pad if None, like ChangeProfile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad: Option<String>,
+}
+
+// When a schedule runs: the minutes matching every field, with the usual cron rule that
+// a day matching either of day of month or day of week is enough when both are given
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct When {
+    minutes: u64,
+    hours: u32,
+    // Bit d for day of month d, 1-31
+    days: u32,
+    // Bit m for month m, 1-12
+    months: u16,
+    // Bit 0 is Sunday
+    weekdays: u8,
+    days_given: bool,
+    weekdays_given: bool,
+}
+
+impl When {
+    // `HH:MM` every day, or `minute hour day-of-month month day-of-week` where each field
+    // is `*` or a comma separated list of numbers and `a-b` ranges, each optionally
+    // followed by `/step`. Day of week is 0-7, both 0 and 7 being Sunday.
+    pub fn parse(spec: &str) -> Result<Self, ValidationError> {
+        let invalid = |reason: String| ValidationError::InvalidSchedule {
+            spec: spec.to_string(),
+            reason,
+        };
+        let spec = spec.trim();
+        if let Some((hour, minute)) = spec.split_once(':') {
+            let hour = hour.parse::<u32>().ok().filter(|hour| *hour < 24);
+            // Two digits, so `22:5` isn't taken for 22:05 or 22:50
+            let minute = Some(minute)
+                .filter(|minute| minute.len() == 2)
+                .and_then(|minute| minute.parse::<u32>().ok())
+                .filter(|minute| *minute < 60);
+            let (Some(hour), Some(minute)) = (hour, minute) else {
+                return Err(invalid("expected a time of day as HH:MM".to_string()));
+            };
+            return Self::parse(&format!("{} {} * * *", minute, hour));
+        }
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(invalid(format!(
+                "expected HH:MM or 5 cron fields, got {} field(s)",
+                fields.len()
+            )));
+        };
+        let field = |text: &str, name: &str, min: u32, max: u32| {
+            parse_field(text, min, max).map_err(|reason| invalid(format!("{}: {}", name, reason)))
+        };
+        let weekdays_bits = field(weekdays, "day of week", 0, 7)?;
+        Ok(Self {
+            minutes: field(minutes, "minute", 0, 59)?,
+            hours: field(hours, "hour", 0, 23)? as u32,
+            days: field(days, "day of month", 1, 31)? as u32,
+            months: field(months, "month", 1, 12)? as u16,
+            // 7 is Sunday as well
+            weekdays: ((weekdays_bits | weekdays_bits >> 7) & 0x7f) as u8,
+            days_given: days != "*",
+            weekdays_given: weekdays != "*",
+        })
+    }
+
+    // Whether the schedule runs in the local minute `at`
+    pub fn matches(&self, at: NaiveDateTime) -> bool {
+        self.minutes & (1 << at.minute()) != 0
+            && self.hours & (1 << at.hour()) != 0
+            && self.matches_day(at.date())
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.days_given, self.weekdays_given) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    // The first run after `after`, in local time. A time skipped by a daylight saving
+    // change doesn't run; one repeated by it runs the first time.
+    pub fn next_run(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let after = after.naive_local();
+        let first = after.date();
+        for date in first.iter_days().take(NEXT_RUN_HORIZON_DAYS as usize) {
+            if !self.matches_day(date) {
+                continue;
+            }
+            for hour in (0..24).filter(|hour| self.hours & (1 << hour) != 0) {
+                for minute in (0..60).filter(|minute| self.minutes & (1 << minute) != 0) {
+                    let at = date.and_hms_opt(hour, minute, 0)?;
+                    if at <= after {
+                        continue;
+                    }
+                    match Local.from_local_datetime(&at) {
+                        LocalResult::Single(at) | LocalResult::Ambiguous(at, _) => return Some(at),
+                        LocalResult::None => {}
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+// The values of one cron field as bits
+fn parse_field(text: &str, min: u32, max: u32) -> Result<u64, String> {
+    let number = |text: &str| {
+        text.parse::<u32>()
+            .ok()
+            .filter(|value| (min..=max).contains(value))
+            .ok_or_else(|| format!("'{}' is not a number from {} to {}", text, min, max))
+    };
+    let mut bits = 0u64;
+    for item in text.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("'{}' is not a step", step))?;
+                (range, step)
+            }
+            None => (item, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((from, to)) => (number(from)?, number(to)?),
+                None => {
+                    let value = number(range)?;
+                    // `a/step` runs from a to the end of the field
+                    (value, if item.contains('/') { max } else { value })
+                }
+            },
+        };
+        if from > to {
+            return Err(format!("'{}' is an empty range", range));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+// Id for a new schedule: one above the highest in use
+pub fn next_id(schedules: &[Schedule]) -> String {
+    let highest = schedules
+        .iter()
+        .filter_map(|schedule| schedule.id.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0);
+    (highest + 1).to_string()
+}
+
+// The server's time zone as ListSchedules reports it: its IANA name if the system
+// has one, and the current offset from UTC
+pub fn local_timezone() -> serde_json::Value {
+    serde_json::json!({
+        "name": iana_time_zone::get_timezone().ok(),
+        "utc_offset": Local::now().format("%:z").to_string(),
+    })
+}
+
+// What became of a schedule that was due
+#[derive(Debug)]
+pub enum Run {
+    // Switched the pad of this id
+    Switched(String),
+    // Someone was playing on the pad; pressed this long ago
+    Skipped(Duration),
+    // The switch itself failed, e.g. the profile was removed since
+    Failed(String),
+}
+
+// Runs the schedules in profiles.json at the start of every local minute they match.
+// Minutes the server was suspended through are not caught up on.
+pub async fn schedule_task(state: AppState) {
+    // Last minute each schedule ran, so a minute is never run twice, also not when the
+    // clock goes back for daylight saving
+    let mut last_run: HashMap<String, NaiveDateTime> = HashMap::new();
+    loop {
+        let now = Local::now();
+        let into_minute = now.second() as u64 * 1000 + now.timestamp_subsec_millis() as u64;
+        tokio::time::sleep(Duration::from_millis(60_000 - into_minute.min(59_999))).await;
+
+        let Some(minute) = Local::now().naive_local().with_second(0) else {
+            continue;
+        };
+        let profiles = state.profiles_snapshot();
+        for schedule in &profiles.schedules {
+            let Ok(when) = When::parse(&schedule.cron_or_time) else {
+                continue;
+            };
+            let repeated = last_run
+                .get(&schedule.id)
+                .is_some_and(|last| minute <= *last && *last - minute <= TimeDelta::hours(1));
+            if repeated || !when.matches(minute) {
+                continue;
+            }
+            last_run.insert(schedule.id.clone(), minute);
+            run(&state, &profiles, schedule).await;
+        }
+    }
+}
+
+// Switch the pad to the schedule's profile like ChangeProfile, unless its device was
+// pressed within SESSION_IDLE. Skips and failures are journaled; a switch is broadcast.
+pub async fn run(state: &AppState, profiles: &Profiles, schedule: &Schedule) -> Run {
+    // Presses are only seen on this server's device
+    let drives_device = profiles
+        .pad(schedule.pad.as_deref())
+        .is_ok_and(|pad| profiles.drives_device(&pad.id));
+    let pressed = state
+        .latest
+        .since_press()
+        .filter(|pressed| drives_device && *pressed < SESSION_IDLE);
+    let outcome = match pressed {
+        Some(pressed) => Run::Skipped(pressed),
+        None => {
+            let response = handle_envelope(
+                Envelope {
+                    command: Command::ChangeProfile {
+                        name: schedule.profile.clone(),
+                        pad: schedule.pad.clone(),
+                        update_player: Some(false),
+                    },
+                    idempotency_key: None,
+                    dry_run: false,
+                    expected_revision: None,
+                    origin: Origin::Schedule,
+                },
+                state,
+            )
+            .await;
+            if response.success {
+                Run::Switched(response.pad.unwrap_or_default())
+            } else {
+                Run::Failed(response.message)
+            }
+        }
+    };
+    match &outcome {
+        Run::Switched(pad) => {
+            println!(
+                "Schedule {} switched pad '{}' to profile '{}' ({})",
+                schedule.id, pad, schedule.profile, schedule.cron_or_time
+            );
+            state.publish(Event::ScheduledSwitch {
+                pad: pad.as_str().into(),
+                schedule: Arc::new(schedule.clone()),
+            });
+        }
+        Run::Skipped(pressed) => {
+            let message = format!(
+                "Schedule {} skipped the switch to profile '{}', the pad was pressed {}ms ago",
+                schedule.id,
+                schedule.profile,
+                pressed.as_millis()
+            );
+            println!("{}", message);
+            state.journal.record(JournalKind::ScheduleSkipped, message);
+        }
+        Run::Failed(reason) => {
+            let message = format!(
+                "Schedule {} failed to switch to profile '{}': {}",
+                schedule.id, schedule.profile, reason
+            );
+            eprintln!("{}", message);
+            state.journal.record(JournalKind::ScheduleSkipped, message);
+        }
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Profile;
+
+    fn at(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_times_and_cron_expressions() {
+        let daily = When::parse(" 22:00 ").unwrap();
+        assert!(daily.matches(at("2026-10-16 22:00")));
+        assert!(!daily.matches(at("2026-10-16 22:01")));
+        assert_eq!(daily, When::parse("0 22 * * *").unwrap());
+
+        // Weeknights every 15 minutes from 18:00 to 19:45
+        let weeknights = When::parse("*/15 18-19 * * 1-5").unwrap();
+        assert!(weeknights.matches(at("2026-10-16 19:45")));
+        assert!(!weeknights.matches(at("2026-10-16 19:50")));
+        assert!(!weeknights.matches(at("2026-10-17 18:00")));
+
+        // Either day field is enough when both are given; 7 is Sunday
+        let either = When::parse("0 9 1 * 7").unwrap();
+        assert!(either.matches(at("2026-10-01 09:00")));
+        assert!(either.matches(at("2026-10-18 09:00")));
+        assert!(!either.matches(at("2026-10-16 09:00")));
+
+        for invalid in [
+            "24:00",
+            "22:5",
+            "22:60",
+            "* * * *",
+            "0 22 * 13 *",
+            "5-1 * * * *",
+        ] {
+            assert!(
+                matches!(
+                    When::parse(invalid),
+                    Err(ValidationError::InvalidSchedule { .. })
+                ),
+                "{} parsed",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_next_run_is_in_local_time() {
+        let now = Local
+            .from_local_datetime(&at("2026-10-16 22:00"))
+            .earliest();
+        let Some(now) = now else {
+            return;
+        };
+        let daily = When::parse("22:00").unwrap();
+        let next = daily.next_run(now).unwrap();
+        assert_eq!(next.naive_local(), at("2026-10-17 22:00"));
+        assert_eq!(
+            When::parse("0 0 31 2 *").unwrap().next_run(now),
+            None,
+            "there is no 31 February"
+        );
+    }
+
+    #[test]
+    fn test_ids_count_up() {
+        let schedule = |id: &str| Schedule {
+            id: id.to_string(),
+            cron_or_time: "22:00".to_string(),
+            profile: "Quiet".to_string(),
+            pad: None,
+        };
+        assert_eq!(next_id(&[]), "1");
+        assert_eq!(next_id(&[schedule("3"), schedule("1")]), "4");
+    }
+
+    #[tokio::test]
+    async fn test_run_skips_mid_session() {
+        let mut profiles = Profiles::default();
+        profiles
+            .profiles
+            .insert("Quiet".to_string(), Profile::new([800; 4]));
+        let state = AppState::with_mock_port(profiles);
+        let schedule = Schedule {
+            id: "1".to_string(),
+            cron_or_time: "22:00".to_string(),
+            profile: "Quiet".to_string(),
+            pad: None,
+        };
+        let mut events = state.events.subscribe();
+
+        let profiles = state.profiles_snapshot();
+        assert!(matches!(
+            run(&state, &profiles, &schedule).await,
+            Run::Switched(_)
+        ));
+        assert_eq!(state.profiles_snapshot().current_profile(), "Quiet");
+        loop {
+            if let Event::ScheduledSwitch {
+                schedule: switched, ..
+            } = events.recv().await.unwrap()
+            {
+                assert_eq!(*switched, schedule);
+                break;
+            }
+        }
+
+        state.latest.record(
+            "default".into(),
+            [900; 4].into(),
+            Some([800; 4].into()),
+            Default::default(),
+        );
+        let profiles = state.profiles_snapshot();
+        assert!(matches!(
+            run(&state, &profiles, &schedule).await,
+            Run::Skipped(_)
+        ));
+    }
+}
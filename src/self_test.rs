@@ -0,0 +1,315 @@
+use crate::event::Event;
+use crate::info::DeviceKind;
+use crate::profile::{load_profiles_from, save_profiles_to};
+use crate::serial::{get_current_thresholds_from_device, read_sensor_values, set_threshold};
+use crate::state::AppState;
+use crate::threshold_log::WriteReason;
+use serde::Serialize;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Sensor reads of the serial probe
+const PROBE_READS: u32 = 5;
+
+// Longest wait for the loopback subscriber to see the probe event
+const BROADCAST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Tells apart the probe events of self-tests running at the same time
+static PROBE_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    // Passed against --mock-serial, which says nothing about a real device
+    Mock,
+    // Not run, e.g. no device writes in read-only mode
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub duration_us: u64,
+    pub message: String,
+}
+
+// Payload of RunSelfTest and output of `fsr-rs self-test`
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    // No check failed; mock and skipped checks don't fail the test
+    pub passed: bool,
+    pub checks: Vec<Check>,
+    pub duration_us: u64,
+}
+
+impl SelfTestReport {
+    pub fn failed(&self) -> Vec<&'static str> {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Fail)
+            .map(|check| check.name)
+            .collect()
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Pass => "PASS",
+                CheckStatus::Fail => "FAIL",
+                CheckStatus::Mock => "MOCK",
+                CheckStatus::Skipped => "SKIP",
+            };
+            writeln!(
+                f,
+                "{} {} ({:.1}ms): {}",
+                status,
+                check.name,
+                check.duration_us as f64 / 1000.0,
+                check.message
+            )?;
+        }
+        write!(
+            f,
+            "Self-test {} in {:.1}ms",
+            if self.passed { "passed" } else { "failed" },
+            self.duration_us as f64 / 1000.0
+        )
+    }
+}
+
+// Run every check in turn. The device checks queue like commands, and the threshold
+// check holds the mutation lock, so it never interleaves with a profile change.
+pub async fn run(state: &AppState) -> SelfTestReport {
+    let started = Instant::now();
+    let mock = state.info.device == DeviceKind::Mock;
+    let device_enabled = state.profiles.borrow().device_enabled();
+    let mut checks = Vec::new();
+
+    if state.read_only.mirror {
+        checks.push(skipped("serial_probe", "a mirror has no device of its own"));
+        checks.push(skipped(
+            "threshold_readback",
+            "a mirror has no device of its own",
+        ));
+    } else {
+        checks.push(match device_enabled {
+            true => device_check("serial_probe", mock, serial_probe(state)).await,
+            false => skipped("serial_probe", "the device pad is disabled"),
+        });
+        checks.push(match (device_enabled, state.read_only.is_enabled()) {
+            (false, _) => skipped("threshold_readback", "the device pad is disabled"),
+            (true, true) => skipped("threshold_readback", "read-only mode writes nothing"),
+            (true, false) => {
+                device_check("threshold_readback", mock, threshold_readback(state)).await
+            }
+        });
+    }
+    checks.push(check("profiles_file", profiles_file(state)).await);
+    checks.push(check("broadcast", broadcast(state)).await);
+
+    SelfTestReport {
+        passed: checks.iter().all(|check| check.status != CheckStatus::Fail),
+        checks,
+        duration_us: started.elapsed().as_micros() as u64,
+    }
+}
+
+async fn check(name: &'static str, run: impl Future<Output = Result<String, String>>) -> Check {
+    let started = Instant::now();
+    let result = run.await;
+    let duration_us = started.elapsed().as_micros() as u64;
+    let (status, message) = match result {
+        Ok(message) => (CheckStatus::Pass, message),
+        Err(message) => (CheckStatus::Fail, message),
+    };
+    Check {
+        name,
+        status,
+        duration_us,
+        message,
+    }
+}
+
+async fn device_check(
+    name: &'static str,
+    mock: bool,
+    run: impl Future<Output = Result<String, String>>,
+) -> Check {
+    let mut check = check(name, run).await;
+    if mock && check.status == CheckStatus::Pass {
+        check.status = CheckStatus::Mock;
+    }
+    check
+}
+
+fn skipped(name: &'static str, reason: &str) -> Check {
+    Check {
+        name,
+        status: CheckStatus::Skipped,
+        duration_us: 0,
+        message: reason.to_string(),
+    }
+}
+
+// A few sensor reads, timing each serial round trip
+async fn serial_probe(state: &AppState) -> Result<String, String> {
+    let mut round_trips = Vec::new();
+    for _ in 0..PROBE_READS {
+        let _queued = state.serial_queue.enter().map_err(|e| e.to_string())?;
+        let started = Instant::now();
+        read_sensor_values(&state.serial)
+            .await
+            .map_err(|e| format!("Reading the sensors failed: {}", e))?;
+        round_trips.push(started.elapsed());
+    }
+    let max = round_trips.iter().max().copied().unwrap_or_default();
+    let average = round_trips.iter().sum::<Duration>() / PROBE_READS;
+    Ok(format!(
+        "{} sensor reads, round trip {:.1}ms on average, {:.1}ms at most",
+        PROBE_READS,
+        average.as_secs_f64() * 1000.0,
+        max.as_secs_f64() * 1000.0
+    ))
+}
+
+// Write a threshold one step from its value, read it back and put the original back
+async fn threshold_readback(state: &AppState) -> Result<String, String> {
+    let _mutation = state.mutations.lock().await;
+    let _queued = state.serial_queue.enter().map_err(|e| e.to_string())?;
+    let original = get_current_thresholds_from_device(&state.serial)
+        .await
+        .map_err(|e| format!("Reading the thresholds failed: {}", e))?;
+    // Device order, so the sensor map doesn't matter
+    let sensor = 0;
+    let bound = state.range.bound();
+    let probe = match bound.check(original[sensor] + 1) {
+        Ok(()) => original[sensor] + 1,
+        Err(_) => original[sensor] - 1,
+    };
+    let logged = state.threshold_writes.reason(WriteReason::SelfTest);
+    let written = set_threshold(&state.serial, sensor, Some(original[sensor]), probe, logged).await;
+    let written = match written {
+        Ok(()) => get_current_thresholds_from_device(&state.serial).await,
+        Err(e) => Err(e),
+    };
+    let restored =
+        set_threshold(&state.serial, sensor, Some(probe), original[sensor], logged).await;
+    if let Err(e) = restored {
+        return Err(format!(
+            "Putting threshold {} back to {} failed, the device may be left at {}: {}",
+            sensor, original[sensor], probe, e
+        ));
+    }
+    match written {
+        Ok(thresholds) if thresholds[sensor] == probe => Ok(format!(
+            "Threshold {} read back as {} and was put back to {}",
+            sensor, probe, original[sensor]
+        )),
+        Ok(thresholds) => Err(format!(
+            "Threshold {} read back as {} after writing {}",
+            sensor, thresholds[sensor], probe
+        )),
+        Err(e) => Err(format!("Writing threshold {} failed: {}", sensor, e)),
+    }
+}
+
+// Write the current profiles to a temporary file and read them back
+async fn profiles_file(state: &AppState) -> Result<String, String> {
+    let path = std::env::temp_dir().join(format!("fsr-rs-self-test-{}.json", std::process::id()));
+    let profiles = state.profiles_snapshot();
+    let written = save_profiles_to(&path, &profiles).await;
+    let loaded = match written {
+        Ok(_) => load_profiles_from(&path).await,
+        Err(e) => Err(e),
+    };
+    let _ = tokio::fs::remove_file(&path).await;
+    match loaded {
+        Ok(loaded) if loaded == *profiles => Ok(format!(
+            "Wrote and read back {} profile(s) at {}",
+            profiles.profiles.len(),
+            path.display()
+        )),
+        Ok(_) => Err(format!(
+            "The profiles read back from {} differ from those written",
+            path.display()
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Publish a probe event and wait for a subscriber of our own to receive it
+async fn broadcast(state: &AppState) -> Result<String, String> {
+    let mut events = state.events.subscribe();
+    let token = PROBE_TOKEN.fetch_add(1, Ordering::Relaxed);
+    let started = Instant::now();
+    state.publish(Event::SelfTest(token));
+    let received = tokio::time::timeout(BROADCAST_TIMEOUT, async {
+        loop {
+            match events.recv().await {
+                Ok(Event::SelfTest(received)) if received == token => return Ok(()),
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    })
+    .await;
+    match received {
+        Ok(Ok(())) => Ok(format!(
+            "Delivered to a loopback subscriber in {}us",
+            started.elapsed().as_micros()
+        )),
+        Ok(Err(e)) => Err(format!("The event channel failed: {}", e)),
+        Err(_) => Err(format!(
+            "Not delivered within {}ms",
+            BROADCAST_TIMEOUT.as_millis()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{Pad, Profile, Profiles};
+    use crate::serial::MockSerialPort;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_self_test_on_a_mock_device() {
+        let profiles = Profiles {
+            profiles: HashMap::from([("Casual".to_string(), Profile::new([400; 4]))]),
+            pads: vec![Pad::default_pad("Casual".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        let state = AppState::with_port(profiles, Box::new(MockSerialPort::new([400; 4])));
+        let report = run(&state).await;
+        assert!(report.passed, "{}", report);
+        let statuses: Vec<_> = report
+            .checks
+            .iter()
+            .map(|check| (check.name, check.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("serial_probe", CheckStatus::Pass),
+                ("threshold_readback", CheckStatus::Pass),
+                ("profiles_file", CheckStatus::Pass),
+                ("broadcast", CheckStatus::Pass),
+            ]
+        );
+        // The threshold is back where it was
+        assert_eq!(
+            get_current_thresholds_from_device(&state.serial)
+                .await
+                .unwrap(),
+            [400; 4]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+// Most sensors a pad can have: the nine panels of the largest layout
+pub const MAX_SENSORS: usize = 9;
+
+// One value per sensor of a pad, for pads with 1 to MAX_SENSORS sensors. Stored inline,
+// so readings go through the stream without allocating. Derefs to the slice of its values.
+#[derive(Clone, Copy)]
+pub struct Sensors<T> {
+    len: u8,
+    values: [T; MAX_SENSORS],
+}
+
+impl<T: Copy + Default> Sensors<T> {
+    // Panics past MAX_SENSORS, like indexing past the end of an array
+    pub fn from_fn(len: usize, mut f: impl FnMut(usize) -> T) -> Self {
+        assert!(
+            len <= MAX_SENSORS,
+            "{} sensors, at most {}",
+            len,
+            MAX_SENSORS
+        );
+        let mut values = [T::default(); MAX_SENSORS];
+        for (index, value) in values[..len].iter_mut().enumerate() {
+            *value = f(index);
+        }
+        Self {
+            len: len as u8,
+            values,
+        }
+    }
+
+    pub fn filled(len: usize, value: T) -> Self {
+        Self::from_fn(len, |_| value)
+    }
+
+    // None for more than MAX_SENSORS values
+    pub fn from_slice(values: &[T]) -> Option<Self> {
+        (values.len() <= MAX_SENSORS).then(|| Self::from_fn(values.len(), |i| values[i]))
+    }
+
+    pub fn map<U: Copy + Default>(self, mut f: impl FnMut(T) -> U) -> Sensors<U> {
+        Sensors::from_fn(self.len(), |i| f(self[i]))
+    }
+}
+
+impl<T: Copy + Default, const N: usize> From<[T; N]> for Sensors<T> {
+    fn from(values: [T; N]) -> Self {
+        Self::from_fn(N, |i| values[i])
+    }
+}
+
+impl<T: Copy + Default> Default for Sensors<T> {
+    fn default() -> Self {
+        Self::from_fn(0, |_| T::default())
+    }
+}
+
+impl<T> Deref for Sensors<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.values[..self.len as usize]
+    }
+}
+
+impl<T> DerefMut for Sensors<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.values[..self.len as usize]
+    }
+}
+
+impl<T: PartialEq> PartialEq for Sensors<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq> Eq for Sensors<T> {}
+
+impl<T: PartialEq, const N: usize> PartialEq<[T; N]> for Sensors<T> {
+    fn eq(&self, other: &[T; N]) -> bool {
+        **self == other[..]
+    }
+}
+
+impl<T: PartialEq, const N: usize> PartialEq<Sensors<T>> for [T; N] {
+    fn eq(&self, other: &Sensors<T>) -> bool {
+        self[..] == **other
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Sensors<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T> IntoIterator for Sensors<T> {
+    type Item = T;
+    type IntoIter = std::iter::Take<std::array::IntoIter<T, MAX_SENSORS>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.into_iter().take(self.len as usize)
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Sensors<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: Serialize> Serialize for Sensors<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+// A list of 1 to MAX_SENSORS values; anything else is refused while parsing
+impl<'de, T: Deserialize<'de> + Copy + Default> Deserialize<'de> for Sensors<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SensorsVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de> + Copy + Default> Visitor<'de> for SensorsVisitor<T> {
+            type Value = Sensors<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a list of 1 to {} sensor values", MAX_SENSORS)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut sensors = Sensors::filled(MAX_SENSORS, T::default());
+                let mut len = 0;
+                while let Some(value) = seq.next_element()? {
+                    if len == MAX_SENSORS {
+                        return Err(de::Error::invalid_length(len + 1, &self));
+                    }
+                    sensors[len] = value;
+                    len += 1;
+                }
+                if len == 0 {
+                    return Err(de::Error::invalid_length(0, &self));
+                }
+                sensors.len = len as u8;
+                Ok(sensors)
+            }
+        }
+
+        deserializer.deserialize_seq(SensorsVisitor(std::marker::PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sensors() {
+        let values = Sensors::from([1, 2, 3, 4, 5]);
+        assert_eq!(values.len(), 5);
+        assert_eq!(values, [1, 2, 3, 4, 5]);
+        assert_ne!(values, [1, 2, 3, 4]);
+        assert_eq!(values.map(|value| value * 2)[4], 10);
+        assert_eq!(values.into_iter().sum::<i32>(), 15);
+        assert_eq!(format!("{:?}", values), "[1, 2, 3, 4, 5]");
+        assert!(Sensors::from_slice(&[0; 10]).is_none());
+
+        assert_eq!(serde_json::to_string(&values).unwrap(), "[1,2,3,4,5]");
+        let parsed: Sensors<i32> = serde_json::from_str("[9,8,7,6,5,4,3,2,1]").unwrap();
+        assert_eq!(parsed.len(), MAX_SENSORS);
+        assert!(serde_json::from_str::<Sensors<i32>>("[]").is_err());
+        assert!(serde_json::from_str::<Sensors<i32>>("[0,0,0,0,0,0,0,0,0,0]").is_err());
+    }
+}
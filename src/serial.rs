@@ -1,206 +1,474 @@
+use crate::config::{self, Args};
+use crate::error::SerialError;
+use crate::pipeline::SerialPipeline;
+use crate::sensors::{Sensors, MAX_SENSORS};
+use crate::serial_trace::{self, TraceSink};
+use crate::threshold_log::LoggedWrite;
 use serialport::SerialPort;
 use std::f64::consts::PI;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
+use tracing::Span;
+
+// Open the serial device on the given port, or for development a mock device with
+// `mock` sensors
+pub fn open_serial_port(
+    com_port: &str,
+    mock: Option<usize>,
+) -> serialport::Result<Box<dyn SerialPort>> {
+    if let Some(sensors) = mock {
+        return Ok(Box::new(MockSerialPort::new(config::stock_thresholds(
+            sensors,
+        ))));
+    }
+    serialport::new(com_port, 115_200)
+        .timeout(Duration::from_millis(100))
+        .open()
+}
 
-// Serial communication function
-pub async fn read_sensor_values(
-    port: &Arc<Mutex<Box<dyn SerialPort>>>,
-) -> Result<[i32; 4], Box<dyn std::error::Error + Send + Sync>> {
-    let mut port_guard = port.lock().await;
-    // Send the "v\n" command
-    let output = "v\n".as_bytes();
-    port_guard.write(output)?;
-
-    // Read the response
-    let mut serial_buf: Vec<u8> = Vec::with_capacity(23); // Max response size
-    let mut buf = [0u8; 23];
+// Open the device selected on the command line, wrapped for tracing. The sink starts with
+// the --trace-serial writers; a runtime capture adds its own later.
+pub fn open_device(args: &Args) -> serialport::Result<(Box<dyn SerialPort>, TraceSink)> {
+    let mock = args.mock_serial.then(|| args.mock_sensors());
+    let port = open_serial_port(&args.com_port, mock)?;
+    let sink = serial_trace::open_sink(&args.trace_options())?;
+    Ok((serial_trace::with_tracing(port, sink.clone()), sink))
+}
 
-    loop {
-        match port_guard.read(&mut buf) {
-            Ok(n) if n > 0 => {
-                serial_buf.extend_from_slice(&buf[..n]);
+// Records how long a serial exchange held the device on its span, however it ends
+struct ExchangeTimer {
+    span: Span,
+    started: Instant,
+}
 
-                // Check if we have a complete line
-                if let Some(pos) = serial_buf.iter().position(|&b| b == b'\n') {
-                    serial_buf.truncate(pos + 1);
-                    break;
-                }
-            }
-            Ok(0) => {
-                // No data read, continue immediately
-                continue;
-            }
-            Ok(_) => continue,
-            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
-                // Timeout occurred, check if we have any data
-                if !serial_buf.is_empty() {
-                    // If we have partial data, break and use what we have
-                    break;
-                }
-                return Err("Timeout reading sensor values".into());
-            }
-            Err(e) => return Err(Box::new(e)),
+impl ExchangeTimer {
+    fn start() -> Self {
+        Self {
+            span: Span::current(),
+            started: Instant::now(),
         }
     }
+}
 
-    // Parse the response: "v 1000 1000 1000 1000\n"
-    let response_str = String::from_utf8_lossy(&serial_buf);
-    let parts: Vec<&str> = response_str.trim().split_whitespace().collect();
-
-    if parts.len() != 5 || parts[0] != "v" {
-        return Err("Invalid response format".into());
+impl Drop for ExchangeTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed().as_micros() as u64;
+        self.span.record("duration_us", elapsed);
     }
+}
 
-    let mut values = [0i32; 4];
-    for i in 0..4 {
-        values[i] = parts[i + 1]
-            .parse::<i32>()
-            .map_err(|_| "Failed to parse sensor value")?;
-    }
+// Serial communication function
+#[tracing::instrument(
+    level = "trace",
+    skip_all,
+    fields(bytes_written, lines_read, duration_us)
+)]
+pub async fn read_sensor_values(
+    port: &Arc<Mutex<Box<dyn SerialPort>>>,
+) -> Result<Sensors<i32>, SerialError> {
+    let mut port_guard = port.lock().await;
+    let _timer = ExchangeTimer::start();
+    // Send the "v\n" command
+    let output = "v\n".as_bytes();
+    port_guard.write_all(output)?;
+    Span::current().record("bytes_written", output.len());
 
-    Ok(values)
+    // Parse the response: "v 1000 1000 1000 1000\n", a value per sensor
+    let mut reader = LineReader::new();
+    let line = read_response(&mut reader, &mut **port_guard, b"v", "sensor values").await?;
+    parse_values(line, b"v", "response", "sensor value")
+}
+
+// read_sensor_values keeping one request ahead: it answers with the response to the
+// request the previous call left in flight, then sends the next one, so the device works
+// on it while the frame is broadcast. Only for a port wrapped by with_pipeline, which
+// drops the request again before anything else is written.
+#[tracing::instrument(
+    level = "trace",
+    skip_all,
+    fields(bytes_written, lines_read, duration_us)
+)]
+pub async fn read_sensor_values_pipelined(
+    port: &Arc<Mutex<Box<dyn SerialPort>>>,
+    pipeline: &SerialPipeline,
+) -> Result<Sensors<i32>, SerialError> {
+    let mut port_guard = port.lock().await;
+    let _timer = ExchangeTimer::start();
+    let mut written = 0;
+    if !pipeline.take_in_flight() {
+        // First read, or the request was flushed for another exchange
+        port_guard.write_all(b"v\n")?;
+        written += 2;
+    }
+
+    let mut reader = LineReader::new();
+    let line = read_response(&mut reader, &mut **port_guard, b"v", "sensor values").await?;
+    let values = parse_values(line, b"v", "response", "sensor value");
+
+    // A failed read starts over with a fresh request next time
+    if values.is_ok() {
+        port_guard.write_all(b"v\n")?;
+        written += 2;
+        pipeline.set_in_flight();
+    }
+    Span::current().record("bytes_written", written);
+    values
 }
 
-// Function to set threshold on serial device
+// Function to set threshold on serial device. The write is logged to `log` with
+// `before`, the value the caller knows the threshold had.
 pub async fn set_threshold(
     port: &Arc<Mutex<Box<dyn SerialPort>>>,
     threshold_index: usize,
+    before: Option<i32>,
     value: i32,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut port_guard = port.lock().await;
+    log: LoggedWrite<'_>,
+) -> Result<(), SerialError> {
+    set_threshold_locked(
+        &mut **port.lock().await,
+        threshold_index,
+        before,
+        value,
+        log,
+    )
+    .await
+}
+
+// set_threshold for a caller that already holds the port
+pub async fn set_threshold_locked(
+    port_guard: &mut dyn SerialPort,
+    threshold_index: usize,
+    before: Option<i32>,
+    value: i32,
+    log: LoggedWrite<'_>,
+) -> Result<(), SerialError> {
+    let written = write_threshold(port_guard, threshold_index, value).await;
+    let sensors = *written.as_ref().unwrap_or(&0);
+    log.one(threshold_index, sensors, before, value, &written);
+    written.map(drop)
+}
+
+#[tracing::instrument(
+    name = "set_threshold",
+    level = "debug",
+    skip(port_guard),
+    fields(bytes_written, lines_read, duration_us)
+)]
+// Returns the number of thresholds the device acknowledged with
+async fn write_threshold(
+    port_guard: &mut dyn SerialPort,
+    threshold_index: usize,
+    value: i32,
+) -> Result<usize, SerialError> {
+    let _timer = ExchangeTimer::start();
 
     // Send the threshold command: "0 123\n" for threshold 0 with value 123
     let command = format!("{} {}\n", threshold_index, value);
     let output = command.as_bytes();
-    port_guard.write(output)?;
-
-    // Read the response
-    let mut serial_buf: Vec<u8> = Vec::with_capacity(25); // Max response size for "t 123 1000 1000 1000\n"
-    let mut buf = [0u8; 25];
-
-    loop {
-        match port_guard.read(&mut buf) {
-            Ok(n) if n > 0 => {
-                serial_buf.extend_from_slice(&buf[..n]);
-
-                // Check if we have a complete line
-                if let Some(pos) = serial_buf.iter().position(|&b| b == b'\n') {
-                    serial_buf.truncate(pos + 1);
-                    break;
-                }
-            }
-            Ok(0) => {
-                // No data read, continue immediately
-                continue;
-            }
-            Ok(_) => continue,
-            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
-                // Timeout occurred, check if we have any data
-                if !serial_buf.is_empty() {
-                    // If we have partial data, break and use what we have
-                    break;
-                }
-                return Err("Timeout reading threshold response".into());
-            }
-            Err(e) => return Err(Box::new(e)),
-        }
-    }
+    port_guard.write_all(output)?;
+    Span::current().record("bytes_written", output.len());
 
     // Parse the response: "t 123 1000 1000 1000\n"
-    let response_str = String::from_utf8_lossy(&serial_buf);
-    let parts: Vec<&str> = response_str.trim().split_whitespace().collect();
-
-    if parts.len() != 5 || parts[0] != "t" {
-        return Err("Invalid threshold response format".into());
-    }
+    let mut reader = LineReader::new();
+    let line = read_response(&mut reader, port_guard, b"t", "threshold response").await?;
+    let fields = split_fields(line)
+        .filter(|fields| fields.tag() == b"t")
+        .ok_or(SerialError::InvalidResponse("threshold response"))?;
+    let field = fields
+        .values()
+        .get(threshold_index)
+        .ok_or(SerialError::SensorCount {
+            device: fields.values().len(),
+            thresholds: threshold_index + 1,
+        })?;
 
     // Validate that the correct threshold was set
-    let set_threshold = parts[threshold_index + 1]
-        .parse::<i32>()
-        .map_err(|_| "Failed to parse threshold value")?;
+    let set_threshold = parse_i32(field).ok_or(SerialError::Parse("threshold value"))?;
 
     if set_threshold != value {
-        return Err(format!(
-            "Threshold validation failed: expected {}, got {}",
-            value, set_threshold
-        )
-        .into());
+        return Err(SerialError::ThresholdMismatch {
+            expected: value,
+            actual: set_threshold,
+        });
     }
 
-    Ok(())
+    Ok(fields.values().len())
 }
 
-// Function to set all thresholds for a profile on the serial device
+// Function to set all thresholds for a profile on the serial device. The device's values
+// are read first, so a write failing halfway can put back the ones already changed
+// instead of leaving a mix of old and new thresholds. The port is held throughout, so no
+// other exchange sees the device in between. Thresholds for another number of sensors
+// than the device has are refused before anything is written. Returns the thresholds it
+// replaced. The whole write, rollback included, is one entry in `log`.
+#[tracing::instrument(level = "debug", skip(port, log))]
 pub async fn set_all_thresholds(
     port: &Arc<Mutex<Box<dyn SerialPort>>>,
-    thresholds: [i32; 4],
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    thresholds: Sensors<i32>,
+    log: LoggedWrite<'_>,
+) -> Result<Sensors<i32>, SerialError> {
+    let written = write_all_thresholds(&mut **port.lock().await, thresholds).await;
+    log.all(thresholds, &written);
+    written
+}
+
+async fn write_all_thresholds(
+    port_guard: &mut dyn SerialPort,
+    thresholds: Sensors<i32>,
+) -> Result<Sensors<i32>, SerialError> {
+    let original = read_thresholds_locked(port_guard).await?;
+    if original.len() != thresholds.len() {
+        return Err(SerialError::SensorCount {
+            device: original.len(),
+            thresholds: thresholds.len(),
+        });
+    }
     for (index, &value) in thresholds.iter().enumerate() {
-        set_threshold(port, index, value).await?;
+        if let Err(source) = write_threshold(port_guard, index, value).await {
+            return Err(roll_back(port_guard, original, thresholds, index, source).await);
+        }
+    }
+    Ok(original)
+}
+
+// Put back the original values after the write of `failed` failed. The failed write may
+// have landed before its answer went wrong, so it is restored as well. Without anything
+// to undo the failure is returned as it is.
+async fn roll_back(
+    port_guard: &mut dyn SerialPort,
+    original: Sensors<i32>,
+    thresholds: Sensors<i32>,
+    failed: usize,
+    source: SerialError,
+) -> SerialError {
+    let differs = |index: &usize| thresholds[*index] != original[*index];
+    let changed: Vec<usize> = (0..failed).filter(differs).collect();
+    if changed.is_empty() && !differs(&failed) {
+        return source;
+    }
+    let mut restored = Vec::new();
+    let mut inconsistent = Vec::new();
+    for index in (0..=failed).filter(differs) {
+        match write_threshold(port_guard, index, original[index]).await {
+            Ok(_) => restored.push(index),
+            Err(_) => inconsistent.push(index),
+        }
+    }
+    if changed.is_empty() && inconsistent.is_empty() {
+        return source;
+    }
+    SerialError::PartialWrite {
+        failed,
+        source: Box::new(source),
+        changed,
+        restored,
+        inconsistent,
     }
-    Ok(())
 }
 
 // Function to read current thresholds from the serial device
 pub async fn get_current_thresholds_from_device(
     port: &Arc<Mutex<Box<dyn SerialPort>>>,
-) -> Result<[i32; 4], Box<dyn std::error::Error + Send + Sync>> {
-    let mut port_guard = port.lock().await;
+) -> Result<Sensors<i32>, SerialError> {
+    read_thresholds_locked(&mut **port.lock().await).await
+}
+
+// get_current_thresholds_from_device for a caller that already holds the port
+#[tracing::instrument(
+    name = "get_current_thresholds_from_device",
+    level = "debug",
+    skip_all,
+    fields(bytes_written, lines_read, duration_us)
+)]
+pub async fn read_thresholds_locked(
+    port_guard: &mut dyn SerialPort,
+) -> Result<Sensors<i32>, SerialError> {
+    let _timer = ExchangeTimer::start();
 
     // Send a command to get current thresholds (assuming "t\n" gets current thresholds)
     let command = "t\n".as_bytes();
-    port_guard.write(command)?;
+    port_guard.write_all(command)?;
+    Span::current().record("bytes_written", command.len());
 
-    // Read the response
-    let mut serial_buf: Vec<u8> = Vec::with_capacity(25); // Max response size for "t 123 1000 1000 1000\n"
-    let mut buf = [0u8; 25];
+    // Parse the response: "t 123 1000 1000 1000\n"
+    let mut reader = LineReader::new();
+    let line = read_response(&mut reader, port_guard, b"t", "threshold values").await?;
+    parse_values(line, b"t", "threshold response", "threshold value")
+}
 
-    loop {
-        match port_guard.read(&mut buf) {
-            Ok(n) if n > 0 => {
-                serial_buf.extend_from_slice(&buf[..n]);
+// Longest response line accepted; a valid one is at most 46 bytes plus whitespace. A
+// device streaming noise without newlines fails the exchange here instead of filling
+// memory until the read times out.
+const MAX_LINE_LEN: usize = 256;
+
+// Reads one response line into a fixed buffer, so the exchanges the sensor stream makes
+// every tick don't allocate however the device splits its output
+struct LineReader {
+    buf: [u8; MAX_LINE_LEN],
+    // Bytes in buf: the line last read, then whatever came after it
+    len: usize,
+    line_len: usize,
+}
 
-                // Check if we have a complete line
-                if let Some(pos) = serial_buf.iter().position(|&b| b == b'\n') {
-                    serial_buf.truncate(pos + 1);
-                    break;
-                }
-            }
-            Ok(0) => {
-                // No data read, continue immediately
-                continue;
+impl LineReader {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_LINE_LEN],
+            len: 0,
+            line_len: 0,
+        }
+    }
+
+    // Read up to and including the next newline. Bytes after it are kept for the next
+    // call, so a skipped line doesn't take the following one with it; they are dropped
+    // with the reader. A timeout after part of a line returns that part.
+    async fn read(
+        &mut self,
+        port_guard: &mut dyn SerialPort,
+        what: &'static str,
+    ) -> Result<&[u8], SerialError> {
+        self.buf.copy_within(self.line_len..self.len, 0);
+        self.len -= self.line_len;
+        self.line_len = 0;
+        if let Some(pos) = self.buf[..self.len].iter().position(|&b| b == b'\n') {
+            self.line_len = pos + 1;
+            return Ok(self.line());
+        }
+        loop {
+            if self.len == MAX_LINE_LEN {
+                return Err(SerialError::LineTooLong(MAX_LINE_LEN));
             }
-            Ok(_) => continue,
-            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
-                // Timeout occurred, check if we have any data
-                if !serial_buf.is_empty() {
-                    // If we have partial data, break and use what we have
-                    break;
+            match port_guard.read(&mut self.buf[self.len..]) {
+                Ok(0) => {
+                    // No data yet; yield so a stuck exchange can still be cancelled
+                    tokio::task::yield_now().await;
+                }
+                Ok(n) => {
+                    let start = self.len;
+                    self.len += n;
+                    // Check if we have a complete line
+                    if let Some(pos) = self.buf[start..self.len].iter().position(|&b| b == b'\n') {
+                        self.line_len = start + pos + 1;
+                        break;
+                    }
                 }
-                return Err("Timeout reading threshold values".into());
+                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    // Timeout occurred; if we have partial data, use what we have
+                    if self.len > 0 {
+                        self.line_len = self.len;
+                        break;
+                    }
+                    return Err(SerialError::Timeout(what));
+                }
+                Err(e) => return Err(e.into()),
             }
-            Err(e) => return Err(Box::new(e)),
         }
+        Ok(self.line())
     }
 
-    // Parse the response: "t 123 1000 1000 1000\n"
-    let response_str = String::from_utf8_lossy(&serial_buf);
-    let parts: Vec<&str> = response_str.trim().split_whitespace().collect();
+    // The line last read
+    fn line(&self) -> &[u8] {
+        &self.buf[..self.line_len]
+    }
+}
 
-    if parts.len() != 5 || parts[0] != "t" {
-        return Err("Invalid threshold response format".into());
+// Most lines answering an earlier exchange that one exchange skips before its own
+const MAX_SKIPPED_LINES: usize = 2;
+
+// Read the response line of an exchange expecting `tag`. A complete line with the other
+// tag ("v" for "t" and the other way round) answers an earlier exchange: a pipelined
+// sensor read that couldn't be flushed, or a threshold ack that came after its exchange
+// timed out. It is skipped rather than taken for this exchange's answer. Anything else
+// is returned for the caller to reject.
+async fn read_response<'a>(
+    reader: &'a mut LineReader,
+    port_guard: &mut dyn SerialPort,
+    tag: &[u8],
+    what: &'static str,
+) -> Result<&'a [u8], SerialError> {
+    let other: &[u8] = if tag == b"v" { b"t" } else { b"v" };
+    let mut lines = 0;
+    for skipped in 0.. {
+        reader.read(port_guard, what).await?;
+        lines += usize::from(reader.line().ends_with(b"\n"));
+        let stale = split_fields(reader.line()).is_some_and(|fields| fields.tag() == other);
+        if !stale || skipped == MAX_SKIPPED_LINES {
+            break;
+        }
     }
+    Span::current().record("lines_read", lines);
+    Ok(reader.line())
+}
 
-    let mut thresholds = [0i32; 4];
-    for i in 0..4 {
-        thresholds[i] = parts[i + 1]
-            .parse::<i32>()
-            .map_err(|_| "Failed to parse threshold value")?;
+// The values of a "<tag> 1 2 3 4" line, one per sensor
+fn parse_values(
+    line: &[u8],
+    tag: &[u8],
+    response: &'static str,
+    value: &'static str,
+) -> Result<Sensors<i32>, SerialError> {
+    let fields = split_fields(line)
+        .filter(|fields| fields.tag() == tag)
+        .ok_or(SerialError::InvalidResponse(response))?;
+    let mut values = Sensors::filled(fields.values().len(), 0);
+    for (value_out, field) in values.iter_mut().zip(fields.values()) {
+        *value_out = parse_i32(field).ok_or(SerialError::Parse(value))?;
     }
+    Ok(values)
+}
+
+// The fields of a response line: its tag and a value per sensor
+struct Fields<'a> {
+    fields: [&'a [u8]; MAX_SENSORS + 1],
+    len: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn tag(&self) -> &'a [u8] {
+        self.fields[0]
+    }
+
+    fn values(&self) -> &[&'a [u8]] {
+        &self.fields[1..self.len]
+    }
+}
 
-    Ok(thresholds)
+// A tag and 1 to MAX_SENSORS values, whitespace separated, split like
+// str::split_whitespace splits ASCII
+fn split_fields(line: &[u8]) -> Option<Fields<'_>> {
+    let mut fields: [&[u8]; MAX_SENSORS + 1] = [&[]; MAX_SENSORS + 1];
+    let mut len = 0;
+    for field in line
+        .split(|&b| matches!(b, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c))
+        .filter(|field| !field.is_empty())
+    {
+        *fields.get_mut(len)? = field;
+        len += 1;
+    }
+    (len >= 2).then_some(Fields { fields, len })
+}
+
+// str::parse::<i32> without the str: an optional sign and ASCII digits, None on anything
+// else or on overflow
+fn parse_i32(field: &[u8]) -> Option<i32> {
+    let (negative, digits) = match field {
+        [b'-', digits @ ..] => (true, digits),
+        [b'+', digits @ ..] => (false, digits),
+        digits => (false, digits),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0i32, |value, &b| {
+        let digit = i32::from(b.checked_sub(b'0').filter(|digit| *digit < 10)?);
+        let value = value.checked_mul(10)?;
+        if negative {
+            value.checked_sub(digit)
+        } else {
+            value.checked_add(digit)
+        }
+    })
 }
 
 // Dummy serial port for when the real one is not available
@@ -321,8 +589,8 @@ impl std::io::Read for DummySerialPort {
 }
 
 impl std::io::Write for DummySerialPort {
-    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
-        Ok(0) // Pretend we wrote everything
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len()) // Pretend we wrote everything
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -332,41 +600,116 @@ impl std::io::Write for DummySerialPort {
 
 // Mock serial port that simulates a real device for development
 pub struct MockSerialPort {
-    thresholds: [i32; 4],
+    // One per sensor of the emulated pad
+    thresholds: Sensors<i32>,
     read_buffer: Vec<u8>,
     timeout: Duration,
-    phases: [f64; 4],
+    phases: Sensors<f64>,
     phase_step: f64,
+    // Time every write blocks for, to simulate a slow device
+    latency: Duration,
+    // Time an answer takes to become readable after its command, without blocking the
+    // write: a device that works while the host does
+    response_delay: Duration,
+    ready_at: Instant,
+    sensor_reads: u32,
+    // Sensor read (1-based) that panics, to exercise task supervision
+    panic_on_read: Option<u32>,
+    // Sensor read (1-based) that never gets a reply, to exercise the stream watchdog
+    hang_on_read: Option<u32>,
+    // Answer every command with endless bytes that never contain a newline
+    noise: bool,
+    threshold_writes: u32,
+    // Threshold writes (1-based) the device ignores, acknowledging its old value
+    rejected_writes: Vec<u32>,
 }
 
 impl MockSerialPort {
-    pub fn new(initial_thresholds: [i32; 4]) -> Self {
+    // A device with a sensor for each of the thresholds
+    pub fn new(initial_thresholds: impl Into<Sensors<i32>>) -> Self {
+        let thresholds = initial_thresholds.into();
         // Phase offsets to differentiate channels
-        let phases = [0.0, PI * 0.5, PI, PI * 1.5];
+        let sensors = thresholds.len();
+        let phases = Sensors::from_fn(sensors, |i| 2.0 * PI * i as f64 / sensors as f64);
         // Roughly 0.2 Hz at ~60Hz polling → period ~5s
         let phase_step = 2.0 * PI * 0.2 / 60.0;
         Self {
-            thresholds: initial_thresholds,
+            thresholds,
             read_buffer: Vec::new(),
             timeout: Duration::from_millis(100),
             phases,
             phase_step,
+            latency: Duration::ZERO,
+            response_delay: Duration::ZERO,
+            ready_at: Instant::now(),
+            sensor_reads: 0,
+            panic_on_read: None,
+            hang_on_read: None,
+            noise: false,
+            threshold_writes: 0,
+            rejected_writes: Vec::new(),
         }
     }
 
-    fn generate_sensor_values(&mut self) -> [i32; 4] {
-        let mut values = [0i32; 4];
-        for i in 0..4 {
+    #[cfg(test)]
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_response_delay(mut self, delay: Duration) -> Self {
+        self.response_delay = delay;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_panic_on_read(mut self, read: u32) -> Self {
+        self.panic_on_read = Some(read);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_hang_on_read(mut self, read: u32) -> Self {
+        self.hang_on_read = Some(read);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_noise(mut self) -> Self {
+        self.noise = true;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_rejected_writes(mut self, writes: &[u32]) -> Self {
+        self.rejected_writes = writes.to_vec();
+        self
+    }
+
+    fn generate_sensor_values(&mut self) -> Sensors<i32> {
+        let mut values = Sensors::filled(self.phases.len(), 0);
+        for (value, phase) in values.iter_mut().zip(self.phases.iter_mut()) {
             // Update phase and wrap around 2π
-            self.phases[i] = (self.phases[i] + self.phase_step) % (2.0 * PI);
-            let s = self.phases[i].sin(); // -1..1
-            let v = ((s + 1.0) * 0.5 * 1023.0).round() as i32; // 0..1023
-            values[i] = v;
+            *phase = (*phase + self.phase_step) % (2.0 * PI);
+            let s = phase.sin(); // -1..1
+            *value = ((s + 1.0) * 0.5 * 1023.0).round() as i32; // 0..1023
         }
         values
     }
 
+    // A "<tag> 1 2 3 4" line with the values
+    fn enqueue_values(&mut self, tag: char, values: Sensors<i32>) {
+        let mut line = tag.to_string();
+        for value in values {
+            line.push_str(&format!(" {}", value));
+        }
+        line.push('\n');
+        self.enqueue_line(line);
+    }
+
     fn enqueue_line(&mut self, line: String) {
+        self.ready_at = Instant::now() + self.response_delay;
         self.read_buffer.extend_from_slice(line.as_bytes());
     }
 }
@@ -471,6 +814,15 @@ impl SerialPort for MockSerialPort {
             timeout: self.timeout,
             phases: self.phases,
             phase_step: self.phase_step,
+            latency: self.latency,
+            response_delay: self.response_delay,
+            ready_at: self.ready_at,
+            sensor_reads: self.sensor_reads,
+            panic_on_read: self.panic_on_read,
+            hang_on_read: self.hang_on_read,
+            noise: self.noise,
+            threshold_writes: self.threshold_writes,
+            rejected_writes: self.rejected_writes.clone(),
         }))
     }
 
@@ -485,7 +837,11 @@ impl SerialPort for MockSerialPort {
 
 impl std::io::Read for MockSerialPort {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.read_buffer.is_empty() {
+        if self.noise {
+            buf.fill(0xaa);
+            return Ok(buf.len());
+        }
+        if self.read_buffer.is_empty() || Instant::now() < self.ready_at {
             // No data queued; simulate non-blocking empty read
             return Ok(0);
         }
@@ -498,35 +854,36 @@ impl std::io::Read for MockSerialPort {
 
 impl std::io::Write for MockSerialPort {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.latency.is_zero() {
+            std::thread::sleep(self.latency);
+        }
         let s = std::str::from_utf8(buf).unwrap_or("");
         let line = s.trim();
 
         if line == "v" {
+            self.sensor_reads += 1;
+            if self.panic_on_read == Some(self.sensor_reads) {
+                panic!("mock panic on sensor read {}", self.sensor_reads);
+            }
+            if self.hang_on_read == Some(self.sensor_reads) {
+                // Never answer; reads keep coming back empty without a timeout
+                return Ok(buf.len());
+            }
             let values = self.generate_sensor_values();
-            self.enqueue_line(format!(
-                "v {} {} {} {}\n",
-                values[0], values[1], values[2], values[3]
-            ));
+            self.enqueue_values('v', values);
         } else if line == "t" {
-            self.enqueue_line(format!(
-                "t {} {} {} {}\n",
-                self.thresholds[0], self.thresholds[1], self.thresholds[2], self.thresholds[3]
-            ));
+            self.enqueue_values('t', self.thresholds);
         } else {
             // Expecting: "<index> <value>"
             let parts: Vec<&str> = line.split_whitespace().collect();
             if parts.len() == 2 {
                 if let (Ok(idx), Ok(val)) = (parts[0].parse::<usize>(), parts[1].parse::<i32>()) {
-                    if idx < 4 {
+                    self.threshold_writes += 1;
+                    let rejected = self.rejected_writes.contains(&self.threshold_writes);
+                    if idx < self.thresholds.len() && !rejected {
                         self.thresholds[idx] = val;
                     }
-                    self.enqueue_line(format!(
-                        "t {} {} {} {}\n",
-                        self.thresholds[0],
-                        self.thresholds[1],
-                        self.thresholds[2],
-                        self.thresholds[3]
-                    ));
+                    self.enqueue_values('t', self.thresholds);
                 }
             }
         }
@@ -538,3 +895,221 @@ impl std::io::Write for MockSerialPort {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::threshold_log::{ThresholdWriteLog, WriteReason};
+
+    // The parser before it worked on bytes, to check the new one against
+    fn parse_values_str(line: &[u8], tag: &str) -> Option<Sensors<i32>> {
+        let line = String::from_utf8_lossy(line);
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if !(2..=MAX_SENSORS + 1).contains(&parts.len()) || parts[0] != tag {
+            return None;
+        }
+        let mut values = Sensors::filled(parts.len() - 1, 0);
+        for (value, part) in values.iter_mut().zip(&parts[1..]) {
+            *value = part.parse().ok()?;
+        }
+        Some(values)
+    }
+
+    #[test]
+    fn test_parse_sensor_lines() {
+        let lines: [&[u8]; 19] = [
+            b"v 1 2 3 4\n",
+            b"v 1000 0 1023 512",
+            b"  v 1 2 3 4  \r\n",
+            b"v\t1   2\t\t3 4\n",
+            b"v -1 +2 3 4\n",
+            b"v 2147483647 -2147483648 0 0\n",
+            b"v 2147483648 0 0 0\n",
+            b"v 99999999999999999999 0 0 0\n",
+            b"v 1 2 3\n",
+            b"v 1 2 3 4 5\n",
+            b"v 1 2 3 4 5 6 7 8 9\n",
+            b"v 1 2 3 4 5 6 7 8 9 10\n",
+            b"v\n",
+            b"t 1 2 3 4\n",
+            b"v 1 2 3 4x\n",
+            b"v - 2 3 4\n",
+            b"v 1 2 \xff 4\n",
+            b"vv 1 2 3 4\n",
+            b"",
+        ];
+        for line in lines {
+            assert_eq!(
+                parse_values(line, b"v", "response", "sensor value").ok(),
+                parse_values_str(line, "v"),
+                "{:?}",
+                String::from_utf8_lossy(line)
+            );
+        }
+        assert_eq!(
+            parse_values(b"  v 1 2 3 4  \r\n", b"v", "response", "sensor value").unwrap(),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(
+            parse_values(b"v 1 2 3 4 5\n", b"v", "response", "sensor value").unwrap(),
+            [1, 2, 3, 4, 5]
+        );
+        assert!(matches!(
+            parse_values(b"v\n", b"v", "response", "sensor value"),
+            Err(SerialError::InvalidResponse("response"))
+        ));
+        assert!(matches!(
+            parse_values(b"v 2147483648 0 0 0\n", b"v", "response", "sensor value"),
+            Err(SerialError::Parse("sensor value"))
+        ));
+
+        let ((), allocations, _) = crate::alloc_count::measure(|| {
+            for line in lines {
+                let _ = parse_values(line, b"v", "response", "sensor value");
+            }
+        });
+        assert_eq!(allocations, 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_line() {
+        let noisy: Arc<Mutex<Box<dyn SerialPort>>> = Arc::new(Mutex::new(Box::new(
+            MockSerialPort::new([0; 4]).with_noise(),
+        )));
+        assert!(matches!(
+            read_sensor_values(&noisy).await,
+            Err(SerialError::LineTooLong(MAX_LINE_LEN))
+        ));
+
+        let port: Arc<Mutex<Box<dyn SerialPort>>> =
+            Arc::new(Mutex::new(Box::new(MockSerialPort::new([10, 20, 30, 40]))));
+        let values = read_sensor_values(&port).await.unwrap();
+        assert!(values.iter().all(|value| (0..=1023).contains(value)));
+        assert_eq!(
+            get_current_thresholds_from_device(&port).await.unwrap(),
+            [10, 20, 30, 40]
+        );
+        let log = ThresholdWriteLog::default();
+        let written = set_threshold(&port, 2, Some(30), 35, log.reason(WriteReason::UserCommand));
+        written.await.unwrap();
+        assert_eq!(
+            get_current_thresholds_from_device(&port).await.unwrap(),
+            [10, 20, 35, 40]
+        );
+        let entry = &log.tail(1)[0];
+        assert_eq!((entry.before[2], entry.after[2]), (Some(30), Some(35)));
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_rolls_back() {
+        let original = [10, 20, 30, 40];
+        let rejected = |writes: &[u32]| -> Arc<Mutex<Box<dyn SerialPort>>> {
+            let port = MockSerialPort::new(original).with_rejected_writes(writes);
+            Arc::new(Mutex::new(Box::new(port)))
+        };
+        let log = ThresholdWriteLog::default();
+        let logged = log.reason(WriteReason::ProfileSwitch);
+
+        // The third write fails; the first two are put back
+        let port = rejected(&[3]);
+        let Err(SerialError::PartialWrite {
+            failed,
+            source,
+            changed,
+            restored,
+            inconsistent,
+        }) = set_all_thresholds(&port, [11, 21, 31, 41].into(), logged).await
+        else {
+            panic!("expected a partial write");
+        };
+        assert_eq!(failed, 2);
+        assert!(matches!(*source, SerialError::ThresholdMismatch { .. }));
+        assert_eq!(
+            (changed, restored, inconsistent),
+            (vec![0, 1], vec![0, 1, 2], vec![])
+        );
+        assert_eq!(
+            get_current_thresholds_from_device(&port).await.unwrap(),
+            original
+        );
+
+        // Restoring threshold 1 fails as well, so it keeps the new value
+        let port = rejected(&[3, 5]);
+        let Err(SerialError::PartialWrite { inconsistent, .. }) =
+            set_all_thresholds(&port, [11, 21, 31, 41].into(), logged).await
+        else {
+            panic!("expected a partial write");
+        };
+        assert_eq!(inconsistent, [1]);
+        assert_eq!(
+            get_current_thresholds_from_device(&port).await.unwrap(),
+            [10, 21, 30, 40]
+        );
+
+        // Nothing changed before the first write failed: the plain error
+        let port = rejected(&[1]);
+        let result = set_all_thresholds(&port, [11, 21, 31, 41].into(), logged).await;
+        assert!(matches!(result, Err(SerialError::ThresholdMismatch { .. })));
+        // Each write is one failed entry, its rollback included
+        let entries = log.tail(usize::MAX);
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|entry| !entry.success));
+    }
+
+    #[tokio::test]
+    async fn test_sensor_count() {
+        let port: Arc<Mutex<Box<dyn SerialPort>>> =
+            Arc::new(Mutex::new(Box::new(MockSerialPort::new([
+                10, 20, 30, 40, 50,
+            ]))));
+        assert_eq!(read_sensor_values(&port).await.unwrap().len(), 5);
+        let log = ThresholdWriteLog::default();
+        let logged = log.reason(WriteReason::ProfileSwitch);
+
+        // Four thresholds on a five-sensor device: nothing is written
+        let result = set_all_thresholds(&port, [1, 2, 3, 4].into(), logged).await;
+        assert!(matches!(
+            result,
+            Err(SerialError::SensorCount {
+                device: 5,
+                thresholds: 4
+            })
+        ));
+        let written = set_all_thresholds(&port, [1, 2, 3, 4, 5].into(), logged).await;
+        assert_eq!(written.unwrap(), [10, 20, 30, 40, 50]);
+        let written = set_threshold(&port, 4, Some(5), 55, logged).await;
+        written.unwrap();
+        assert_eq!(
+            get_current_thresholds_from_device(&port).await.unwrap(),
+            [1, 2, 3, 4, 55]
+        );
+    }
+
+    // cargo test --release bench_parse_sensor_line -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_parse_sensor_line() {
+        const LINES: u32 = 1_000_000;
+        let line = b"v 1000 512 0 1023\n";
+        let started = Instant::now();
+        let (sum, allocations, _) = crate::alloc_count::measure(|| {
+            (0..LINES)
+                .map(|_| parse_values(std::hint::black_box(line), b"v", "response", "x"))
+                .map(|values| values.unwrap()[0] as u64)
+                .sum::<u64>()
+        });
+        let elapsed = started.elapsed();
+        let started = Instant::now();
+        let old: u64 = (0..LINES)
+            .map(|_| parse_values_str(std::hint::black_box(line), "v").unwrap()[0] as u64)
+            .sum();
+        println!(
+            "{} lines: {:?} ({} allocations), {:?} with the str parser",
+            LINES,
+            elapsed,
+            allocations,
+            started.elapsed()
+        );
+        assert_eq!(sum, old);
+    }
+}
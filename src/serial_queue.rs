@@ -0,0 +1,130 @@
+use crate::error::AppError;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+pub const DEFAULT_SERIAL_QUEUE_LIMIT: usize = 32;
+
+// Commands waiting for the serial port, in front of it or using it. The port is a FIFO
+// mutex, so every queued command adds one device exchange to the latency of the next;
+// past the limit new commands are refused instead of waiting ever longer. Sensor stream
+// reads are the low-priority lane: they skip their turn while any command is queued.
+pub struct SerialQueue {
+    limit: usize,
+    depth: AtomicUsize,
+    max_depth: AtomicUsize,
+    rejected: AtomicU64,
+    dropped_reads: AtomicU64,
+}
+
+// Payload of GetSerialStats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SerialQueueStats {
+    pub depth: usize,
+    pub max_depth: usize,
+    pub limit: usize,
+    // Commands refused with SERIAL_BUSY
+    pub rejected: u64,
+    // Stream reads skipped so queued commands went first
+    pub dropped_reads: u64,
+}
+
+// A command's place in the queue, left when it is dropped
+pub struct QueueSlot<'a>(&'a SerialQueue);
+
+impl SerialQueue {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            depth: AtomicUsize::new(0),
+            max_depth: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            dropped_reads: AtomicU64::new(0),
+        }
+    }
+
+    // Take a place for a command about to use the port, or fail with SERIAL_BUSY
+    pub fn enter(&self) -> Result<QueueSlot<'_>, AppError> {
+        let entered = self
+            .depth
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |depth| {
+                (depth < self.limit).then_some(depth + 1)
+            });
+        match entered {
+            Ok(depth) => {
+                self.max_depth.fetch_max(depth + 1, Ordering::Relaxed);
+                Ok(QueueSlot(self))
+            }
+            Err(depth) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(AppError::SerialBusy {
+                    depth,
+                    limit: self.limit,
+                })
+            }
+        }
+    }
+
+    // Whether a stream read may use the port now; false, and counted as dropped, while
+    // commands are queued
+    pub fn admit_read(&self) -> bool {
+        if self.depth.load(Ordering::Acquire) == 0 {
+            return true;
+        }
+        self.dropped_reads.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    pub fn stats(&self) -> SerialQueueStats {
+        SerialQueueStats {
+            depth: self.depth.load(Ordering::Acquire),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            limit: self.limit,
+            rejected: self.rejected.load(Ordering::Relaxed),
+            dropped_reads: self.dropped_reads.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.depth.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Default for SerialQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_SERIAL_QUEUE_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_limit_and_stream_lane() {
+        let queue = SerialQueue::new(2);
+        assert!(queue.admit_read());
+        let first = queue.enter().unwrap();
+        let second = queue.enter().unwrap();
+        let busy = queue.enter().err().unwrap();
+        assert_eq!(busy.code(), "SERIAL_BUSY");
+        // Stream reads give way to the queued commands
+        assert!(!queue.admit_read());
+
+        drop(first);
+        let third = queue.enter().unwrap();
+        drop((second, third));
+        assert!(queue.admit_read());
+        assert_eq!(
+            queue.stats(),
+            SerialQueueStats {
+                depth: 0,
+                max_depth: 2,
+                limit: 2,
+                rejected: 1,
+                dropped_reads: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use serialport::SerialPort;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Where serial traffic traces go (--trace-serial / --trace-serial-file)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceOptions {
+    pub stderr: bool,
+    pub file: Option<PathBuf>,
+}
+
+// Destinations for trace lines, shared by a port and its clones. Writers can be added
+// while the port is open, which is how a runtime capture attaches.
+pub type TraceSink = Arc<Mutex<Vec<Box<dyn Write + Send>>>>;
+
+// First lines of every capture file
+pub fn capture_header() -> String {
+    format!(
+        "# fsr-rs serial capture started {}\n# time direction length hex |ascii| [+ms since last write]\n",
+        chrono::Local::now().to_rfc3339()
+    )
+}
+
+pub fn open_sink(options: &TraceOptions) -> io::Result<TraceSink> {
+    let mut writers: Vec<Box<dyn Write + Send>> = Vec::new();
+    if options.stderr {
+        writers.push(Box::new(io::stderr()));
+    }
+    if let Some(path) = &options.file {
+        let mut file = File::create(path)?;
+        file.write_all(capture_header().as_bytes())?;
+        writers.push(Box::new(file));
+    }
+    Ok(Arc::new(Mutex::new(writers)))
+}
+
+// Wrap a port in a TracingPort feeding `sink`. Without writers in the sink nothing is
+// formatted, so the wrapper stays in place even when tracing is off.
+pub fn with_tracing(port: Box<dyn SerialPort>, sink: TraceSink) -> Box<dyn SerialPort> {
+    Box::new(TracingPort::new(port, sink))
+}
+
+// Hex dump with a printable ASCII column, e.g. `76 0a |v.|`
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let ascii: String = bytes
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    format!("{} |{}|", hex, ascii)
+}
+
+// Decorator that logs every write and read chunk of the wrapped port
+pub struct TracingPort<T: ?Sized + SerialPort> {
+    sink: TraceSink,
+    last_write: Option<Instant>,
+    inner: Box<T>,
+}
+
+impl<T: ?Sized + SerialPort> TracingPort<T> {
+    pub fn new(inner: Box<T>, sink: TraceSink) -> Self {
+        Self {
+            sink,
+            last_write: None,
+            inner,
+        }
+    }
+
+    fn since_write(&self) -> String {
+        match self.last_write {
+            Some(at) => format!(" +{:.3}ms", at.elapsed().as_secs_f64() * 1000.0),
+            None => String::new(),
+        }
+    }
+
+    fn trace(&self, line: impl FnOnce() -> String) {
+        let Ok(mut writers) = self.sink.lock() else {
+            return;
+        };
+        if writers.is_empty() {
+            return;
+        }
+        let line = format!(
+            "{} {}\n",
+            chrono::Local::now().format("%H:%M:%S%.6f"),
+            line()
+        );
+        for writer in writers.iter_mut() {
+            let _ = writer.write_all(line.as_bytes());
+        }
+    }
+}
+
+impl<T: ?Sized + SerialPort> Write for TracingPort<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        match &result {
+            Ok(n) => self.trace(|| format!("TX {:>3} {}", n, hex_dump(&buf[..*n]))),
+            Err(e) => self.trace(|| format!("TX error: {}", e)),
+        }
+        self.last_write = Some(Instant::now());
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: ?Sized + SerialPort> Read for TracingPort<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        match &result {
+            Ok(0) => {}
+            Ok(n) => {
+                self.trace(|| format!("RX {:>3} {}{}", n, hex_dump(&buf[..*n]), self.since_write()))
+            }
+            Err(e) => self.trace(|| format!("RX error: {}{}", e, self.since_write())),
+        }
+        result
+    }
+}
+
+impl<T: ?Sized + SerialPort> SerialPort for TracingPort<T> {
+    fn name(&self) -> Option<String> {
+        self.inner.name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.inner.baud_rate()
+    }
+
+    fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+        self.inner.data_bits()
+    }
+
+    fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+        self.inner.flow_control()
+    }
+
+    fn parity(&self) -> serialport::Result<serialport::Parity> {
+        self.inner.parity()
+    }
+
+    fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+        self.inner.stop_bits()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: serialport::DataBits) -> serialport::Result<()> {
+        self.inner.set_data_bits(data_bits)
+    }
+
+    fn set_flow_control(
+        &mut self,
+        flow_control: serialport::FlowControl,
+    ) -> serialport::Result<()> {
+        self.inner.set_flow_control(flow_control)
+    }
+
+    fn set_parity(&mut self, parity: serialport::Parity) -> serialport::Result<()> {
+        self.inner.set_parity(parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: serialport::StopBits) -> serialport::Result<()> {
+        self.inner.set_stop_bits(stop_bits)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_request_to_send(level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_data_terminal_ready(level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.inner.read_clear_to_send()
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.inner.read_data_set_ready()
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.inner.read_ring_indicator()
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.inner.read_carrier_detect()
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_read()
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_write()
+    }
+
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        self.inner.clear(buffer_to_clear)
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(TracingPort::new(
+            self.inner.try_clone()?,
+            self.sink.clone(),
+        )))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.inner.set_break()
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.inner.clear_break()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::{get_current_thresholds_from_device, MockSerialPort};
+    use tokio::sync::Mutex as AsyncMutex;
+
+    // Writer that appends into a shared buffer the test can inspect
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!(hex_dump(b"v\n"), "76 0a |v.|");
+        assert_eq!(hex_dump(b"t 1"), "74 20 31 |t 1|");
+    }
+
+    #[tokio::test]
+    async fn test_tracing_port_logs_both_directions() {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let sink: TraceSink = Arc::new(Mutex::new(vec![
+            Box::new(SharedBuffer(captured.clone())) as Box<dyn Write + Send>
+        ]));
+        let port: Box<dyn SerialPort> = Box::new(TracingPort::new(
+            Box::new(MockSerialPort::new([100, 200, 300, 400])),
+            sink,
+        ));
+        let port = Arc::new(AsyncMutex::new(port));
+
+        // The decorator is transparent to the protocol helpers
+        let thresholds = get_current_thresholds_from_device(&port).await.unwrap();
+        assert_eq!(thresholds, [100, 200, 300, 400]);
+
+        let log = String::from_utf8(captured.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert!(lines[0].contains("TX   2 74 0a |t.|"));
+        assert!(lines[1].contains("RX"));
+        assert!(lines[1].contains("|t 100 200 300 400.|"));
+        assert!(lines[1].contains("ms"));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::commands::ReadOnlyPolicy;
+use crate::health::Health;
+use crate::state::StreamConfig;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+// Name used when registering with the service manager
+#[cfg_attr(not(windows), allow(dead_code))]
+pub const SERVICE_NAME: &str = "fsr-rs";
+
+// systemd notification protocol (sd_notify): datagrams to $NOTIFY_SOCKET
+#[cfg(target_os = "linux")]
+mod systemd {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+    use std::time::Duration;
+
+    pub fn notify(state: &str) -> std::io::Result<()> {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(()); // Not started by systemd with Type=notify
+        };
+        let path = path.to_string_lossy();
+        let addr = match path.strip_prefix('@') {
+            Some(abstract_name) => SocketAddr::from_abstract_name(abstract_name.as_bytes())?,
+            None => SocketAddr::from_pathname(path.as_ref())?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    }
+
+    // Watchdog period requested by the unit (WatchdogSec=), if any
+    pub fn watchdog_period() -> Option<Duration> {
+        if let Some(pid) = std::env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+        {
+            if pid != std::process::id() {
+                return None;
+            }
+        }
+        std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0)
+            .map(Duration::from_micros)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn notify(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        eprintln!("Failed to notify systemd ({}): {}", state.trim(), e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify(_state: &str) {}
+
+// Tell the service manager the server is accepting connections
+pub fn notify_ready() {
+    notify("READY=1\nSTATUS=Serving\n");
+}
+
+pub fn notify_stopping() {
+    notify("STOPPING=1\n");
+}
+
+#[cfg(target_os = "linux")]
+fn watchdog_period() -> Option<Duration> {
+    systemd::watchdog_period()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watchdog_period() -> Option<Duration> {
+    None
+}
+
+// Pet the systemd watchdog at half its period while the server is healthy. Uses the
+// same health report as `/health`, so a failing health check lets the watchdog expire.
+pub fn spawn_watchdog(
+    health: Arc<Health>,
+    stream: Arc<watch::Sender<StreamConfig>>,
+    read_only: ReadOnlyPolicy,
+) {
+    let Some(period) = watchdog_period() else {
+        return;
+    };
+    println!("systemd watchdog enabled ({}ms)", period.as_millis());
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period / 2);
+        loop {
+            interval.tick().await;
+            let report = health.current_report(&stream, read_only);
+            if report.healthy {
+                notify("WATCHDOG=1\n");
+            } else {
+                eprintln!(
+                    "Health check failed, not petting the watchdog: {}",
+                    report.problems.join(", ")
+                );
+                notify(&format!(
+                    "STATUS=Unhealthy: {}\n",
+                    report.problems.join(", ")
+                ));
+            }
+        }
+    });
+}
+
+// Windows service control: start/stop from the SCM mapped to the graceful shutdown path
+#[cfg(windows)]
+pub mod windows {
+    use super::SERVICE_NAME;
+    use crate::config::Args;
+    use std::ffi::OsString;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    // Arguments and runtime handed from `run` to the service thread started by the SCM
+    static SERVICE_CONTEXT: Mutex<Option<(Args, tokio::runtime::Handle)>> = Mutex::new(None);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    // Hand the process over to the service control dispatcher until the service stops
+    pub async fn run(args: Args) -> i32 {
+        if let Ok(mut context) = SERVICE_CONTEXT.lock() {
+            *context = Some((args, tokio::runtime::Handle::current()));
+        }
+        match tokio::task::spawn_blocking(|| {
+            service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        })
+        .await
+        {
+            Ok(Ok(())) => 0,
+            Ok(Err(e)) => {
+                eprintln!("Failed to start the service dispatcher: {}", e);
+                1
+            }
+            Err(e) => {
+                eprintln!("Service dispatcher panicked: {}", e);
+                1
+            }
+        }
+    }
+
+    fn status(state: ServiceState, controls_accepted: ServiceControlAccept) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::from_secs(5),
+            process_id: None,
+        }
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let Some((args, handle)) = SERVICE_CONTEXT.lock().ok().and_then(|mut c| c.take()) else {
+            return;
+        };
+
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let stop_tx = Mutex::new(Some(stop_tx));
+        let event_handler = move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(tx) = stop_tx.lock().ok().and_then(|mut tx| tx.take()) {
+                    let _ = tx.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+
+        let status_handle = match service_control_handler::register(SERVICE_NAME, event_handler) {
+            Ok(status_handle) => status_handle,
+            Err(e) => {
+                eprintln!("Failed to register the service control handler: {}", e);
+                return;
+            }
+        };
+
+        let _ = status_handle.set_service_status(status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ));
+
+        let code = handle.block_on(crate::serve(args, async move {
+            let _ = stop_rx.await;
+            println!("Service stop requested");
+        }));
+
+        let mut stopped = status(ServiceState::Stopped, ServiceControlAccept::empty());
+        if code != 0 {
+            stopped.exit_code = ServiceExitCode::ServiceSpecific(code as u32);
+        }
+        let _ = status_handle.set_service_status(stopped);
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_sd_notify_datagram() {
+        let dir = std::env::temp_dir().join(format!("fsr-rs-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        systemd::notify("READY=1\n").unwrap();
+        std::env::remove_var("NOTIFY_SOCKET");
+
+        let mut buf = [0u8; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
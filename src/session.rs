@@ -0,0 +1,84 @@
+use crate::event::Subscription;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long the subscriptions of a disconnected client are kept for it to resume
+pub const SESSION_GRACE: Duration = Duration::from_secs(60);
+
+// Longest client id accepted; anything longer is ignored like a missing one
+pub const MAX_CLIENT_ID_LEN: usize = 128;
+
+// What a client that reconnects with its client id gets back
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parked {
+    pub subscription: Subscription,
+    // Sequence number of the last event delivered before the disconnect
+    pub seq: u64,
+    // Connections the session ran on, whose command results the resuming one receives
+    pub connections: Vec<u64>,
+}
+
+// Subscriptions of recently disconnected clients, by the client id they chose
+#[derive(Default)]
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, (Parked, Instant)>>,
+}
+
+// A client id as sent in the query or Hello, if it is usable
+pub fn client_id(id: Option<String>) -> Option<String> {
+    id.filter(|id| !id.is_empty() && id.len() <= MAX_CLIENT_ID_LEN)
+}
+
+impl SessionStore {
+    // Keep a disconnected client's state until the grace period ends
+    pub fn park(&self, id: String, parked: Parked) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.retain(|_, (_, expires)| *expires > Instant::now());
+            sessions.insert(id, (parked, Instant::now() + SESSION_GRACE));
+        }
+    }
+
+    // The parked state of `id`, unless it expired; a session resumes only once
+    pub fn resume(&self, id: &str) -> Option<Parked> {
+        let mut sessions = self.sessions.lock().ok()?;
+        sessions
+            .remove(id)
+            .and_then(|(parked, expires)| (expires > Instant::now()).then_some(parked))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Topic;
+    use crate::scale::Scale;
+
+    #[test]
+    fn test_park_and_resume() {
+        let store = SessionStore::default();
+        let topic: Topic = serde_json::from_value(serde_json::json!("identify")).unwrap();
+        let parked = Parked {
+            subscription: Subscription::new(vec![topic], Scale::Normalized),
+            seq: 12,
+            connections: vec![3],
+        };
+        store.park("overlay".to_string(), parked.clone());
+        assert_eq!(store.resume("unknown"), None);
+        assert_eq!(store.resume("overlay"), Some(parked.clone()));
+        assert_eq!(store.resume("overlay"), None);
+
+        // Past the grace period the id is treated like an unknown one
+        if let Ok(mut sessions) = store.sessions.lock() {
+            sessions.insert("stale".to_string(), (parked, Instant::now()));
+        }
+        assert_eq!(store.resume("stale"), None);
+
+        assert_eq!(client_id(Some(String::new())), None);
+        assert_eq!(client_id(Some("x".repeat(MAX_CLIENT_ID_LEN + 1))), None);
+        assert_eq!(
+            client_id(Some("overlay".to_string())),
+            Some("overlay".to_string())
+        );
+    }
+}
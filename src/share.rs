@@ -0,0 +1,312 @@
+use crate::layout::DEFAULT_LAYOUT;
+use crate::profile::Profile;
+use crate::sensors::{Sensors, MAX_SENSORS};
+use base64::alphabet::URL_SAFE;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::{DecodeError, Engine};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+// Start of every share code, so a pasted one is recognized before it's decoded
+pub const SHARE_PREFIX: &str = "fsr:";
+
+// Version of the encoding, the first byte after decoding
+pub const SHARE_VERSION: u8 = 3;
+
+// Version without tags, still used for four sensor profiles that have none, so servers
+// from before tags can import them
+const UNTAGGED_VERSION: u8 = 1;
+
+// Version with tags and four thresholds, still used for four sensor profiles with tags
+const TAGGED_VERSION: u8 = 2;
+
+// Unpadded base64url. A code cut off mid-character has stray bits in its last one, which
+// are let through so it's reported as truncated rather than as a bad character.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_allow_trailing_bits(true)
+        .with_decode_padding_mode(DecodePaddingMode::RequireNone),
+);
+
+// Truncated sha256 of the bytes before it, at the end of a code
+const CHECKSUM_LEN: usize = 4;
+
+// Longest profile name or layout a code holds, its length is one byte
+pub const MAX_SHARED_TEXT: usize = u8::MAX as usize;
+
+// What a share code carries of a profile. Calibration belongs to one pad's sensors and
+// drift compensation to its setup, so neither is shared.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SharedProfile {
+    pub name: String,
+    pub thresholds: Sensors<i32>,
+    pub layout: String,
+    pub tags: Vec<String>,
+}
+
+impl SharedProfile {
+    pub fn new(name: &str, profile: &Profile) -> Self {
+        Self {
+            name: name.to_string(),
+            thresholds: profile.thresholds,
+            layout: profile.layout.clone(),
+            tags: profile.tags.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum ShareError {
+    #[error("it doesn't start with '{SHARE_PREFIX}'")]
+    MissingPrefix,
+    #[error("it contains characters that aren't base64url")]
+    Encoding,
+    #[error("it is truncated")]
+    Truncated,
+    #[error("it is corrupted, the checksum doesn't match")]
+    Checksum,
+    #[error("version {0} is not supported, this server reads versions up to {SHARE_VERSION}")]
+    UnsupportedVersion(u8),
+    #[error("its {0} is not valid text")]
+    InvalidText(&'static str),
+    #[error("the profile name is empty")]
+    EmptyName,
+    #[error("{0} is longer than {MAX_SHARED_TEXT} bytes")]
+    TooLong(&'static str),
+    #[error("threshold {0} is outside 0-65535")]
+    ThresholdRange(i32),
+    #[error("it has {0} thresholds, a pad has 1 to {MAX_SENSORS}")]
+    ThresholdCount(u8),
+}
+
+impl ShareError {
+    // For the `reason` param of INVALID_SHARE_CODE
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ShareError::MissingPrefix => "missing_prefix",
+            ShareError::Encoding => "encoding",
+            ShareError::Truncated => "truncated",
+            ShareError::Checksum => "checksum",
+            ShareError::UnsupportedVersion(_) => "unsupported_version",
+            ShareError::InvalidText(_) => "invalid_text",
+            ShareError::EmptyName => "empty_name",
+            ShareError::TooLong(_) => "too_long",
+            ShareError::ThresholdRange(_) => "threshold_range",
+            ShareError::ThresholdCount(_) => "threshold_count",
+        }
+    }
+}
+
+// `fsr:` and the base64url of: version, name and layout (each a length byte and UTF-8),
+// from version 3 a count byte, the thresholds as big-endian u16 (four before version 3),
+// from version 2 a count byte and the tags (each like the name), and the checksum. The
+// default layout is left empty. "Casual" with dance4 and no tags comes to 32 characters.
+pub fn encode(profile: &SharedProfile) -> Result<String, ShareError> {
+    let layout = if profile.layout == DEFAULT_LAYOUT {
+        ""
+    } else {
+        profile.layout.as_str()
+    };
+    let version = match (profile.thresholds.len(), profile.tags.is_empty()) {
+        (4, true) => UNTAGGED_VERSION,
+        (4, false) => TAGGED_VERSION,
+        _ => SHARE_VERSION,
+    };
+    let mut bytes = vec![version];
+    push_text(&mut bytes, &profile.name, "name")?;
+    push_text(&mut bytes, layout, "layout")?;
+    if version == SHARE_VERSION {
+        bytes.push(profile.thresholds.len() as u8);
+    }
+    for threshold in profile.thresholds {
+        let threshold =
+            u16::try_from(threshold).map_err(|_| ShareError::ThresholdRange(threshold))?;
+        bytes.extend(threshold.to_be_bytes());
+    }
+    if version > UNTAGGED_VERSION {
+        let count = u8::try_from(profile.tags.len()).map_err(|_| ShareError::TooLong("tags"))?;
+        bytes.push(count);
+        for tag in &profile.tags {
+            push_text(&mut bytes, tag, "tag")?;
+        }
+    }
+    let checksum = checksum(&bytes);
+    bytes.extend(checksum);
+    Ok(format!("{}{}", SHARE_PREFIX, BASE64.encode(bytes)))
+}
+
+fn push_text(bytes: &mut Vec<u8>, text: &str, what: &'static str) -> Result<(), ShareError> {
+    let len = u8::try_from(text.len()).map_err(|_| ShareError::TooLong(what))?;
+    bytes.push(len);
+    bytes.extend(text.as_bytes());
+    Ok(())
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(bytes);
+    std::array::from_fn(|i| digest[i])
+}
+
+// A pasted code; surrounding whitespace is ignored
+pub fn decode(code: &str) -> Result<SharedProfile, ShareError> {
+    let encoded = code
+        .trim()
+        .strip_prefix(SHARE_PREFIX)
+        .ok_or(ShareError::MissingPrefix)?;
+    let bytes = BASE64.decode(encoded).map_err(|e| match e {
+        // One character past a whole number of bytes
+        DecodeError::InvalidLength(_) => ShareError::Truncated,
+        _ => ShareError::Encoding,
+    })?;
+    let mut reader = Reader { bytes: &bytes };
+    let version = reader.take(1)?[0];
+    if !(UNTAGGED_VERSION..=SHARE_VERSION).contains(&version) {
+        return Err(ShareError::UnsupportedVersion(version));
+    }
+    let name = reader.text("name")?;
+    let layout = reader.text("layout")?;
+    let count = if version == SHARE_VERSION {
+        reader.take(1)?[0]
+    } else {
+        4
+    };
+    if !(1..=MAX_SENSORS).contains(&usize::from(count)) {
+        return Err(ShareError::ThresholdCount(count));
+    }
+    let mut thresholds = Sensors::filled(usize::from(count), 0);
+    for threshold in thresholds.iter_mut() {
+        let [high, low] = reader.take(2)? else {
+            unreachable!("took two bytes");
+        };
+        *threshold = u16::from_be_bytes([*high, *low]) as i32;
+    }
+    let mut tags = Vec::new();
+    if version > UNTAGGED_VERSION {
+        let count = reader.take(1)?[0];
+        for _ in 0..count {
+            tags.push(reader.text("tag")?);
+        }
+    }
+    let body = bytes.len() - reader.bytes.len();
+    if reader.take(CHECKSUM_LEN)? != checksum(&bytes[..body]) || !reader.bytes.is_empty() {
+        return Err(ShareError::Checksum);
+    }
+    if name.is_empty() {
+        return Err(ShareError::EmptyName);
+    }
+    Ok(SharedProfile {
+        name,
+        thresholds,
+        layout: if layout.is_empty() {
+            DEFAULT_LAYOUT.to_string()
+        } else {
+            layout
+        },
+        tags,
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ShareError> {
+        if self.bytes.len() < len {
+            return Err(ShareError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn text(&mut self, what: &'static str) -> Result<String, ShareError> {
+        let len = self.take(1)?[0] as usize;
+        let text = self.take(len)?;
+        String::from_utf8(text.to_vec()).map_err(|_| ShareError::InvalidText(what))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared(name: &str, layout: &str) -> SharedProfile {
+        SharedProfile {
+            name: name.to_string(),
+            thresholds: [400, 0, 1023, 65535].into(),
+            layout: layout.to_string(),
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_share_code_round_trip() {
+        for profile in [
+            shared("Casual", DEFAULT_LAYOUT),
+            shared("Stamina ✨", "custom"),
+            shared(&"n".repeat(MAX_SHARED_TEXT), DEFAULT_LAYOUT),
+        ] {
+            let code = encode(&profile).unwrap();
+            assert!(code.starts_with(SHARE_PREFIX));
+            assert_eq!(decode(&format!("  {}\n", code)).unwrap(), profile);
+        }
+        assert_eq!(encode(&shared("Casual", DEFAULT_LAYOUT)).unwrap().len(), 32);
+
+        // Tags need version 2; without them the code stays readable by version 1 servers
+        let mut tagged = shared("Casual", DEFAULT_LAYOUT);
+        tagged.tags = vec!["socks".to_string(), "kids ✨".to_string()];
+        let code = encode(&tagged).unwrap();
+        assert_eq!(BASE64.decode(&code[SHARE_PREFIX.len()..]).unwrap()[0], 2);
+        assert_eq!(decode(&code).unwrap(), tagged);
+
+        // Other sensor counts need version 3, which counts the thresholds
+        let mut pump = shared("Pump", "pump5");
+        pump.thresholds = [400, 0, 1023, 65535, 7].into();
+        let code = encode(&pump).unwrap();
+        assert_eq!(BASE64.decode(&code[SHARE_PREFIX.len()..]).unwrap()[0], 3);
+        assert_eq!(decode(&code).unwrap(), pump);
+
+        assert_eq!(
+            encode(&shared(&"n".repeat(MAX_SHARED_TEXT + 1), DEFAULT_LAYOUT)),
+            Err(ShareError::TooLong("name"))
+        );
+        let mut negative = shared("P", DEFAULT_LAYOUT);
+        negative.thresholds[0] = -1;
+        assert_eq!(encode(&negative), Err(ShareError::ThresholdRange(-1)));
+    }
+
+    #[test]
+    fn test_damaged_codes_are_rejected() {
+        let code = encode(&shared("Casual", DEFAULT_LAYOUT)).unwrap();
+        let encoded = &code[SHARE_PREFIX.len()..];
+
+        assert_eq!(decode(encoded), Err(ShareError::MissingPrefix));
+        assert_eq!(decode(&format!("{}!", code)), Err(ShareError::Encoding));
+        // Cut off anywhere, including inside the name
+        for len in [1, 2, 4, 12, 19, 20, 27] {
+            assert_eq!(
+                decode(&code[..SHARE_PREFIX.len() + len]),
+                Err(ShareError::Truncated),
+                "{} characters",
+                len
+            );
+        }
+        // Every single changed character is noticed
+        for at in 0..encoded.len() {
+            let mut tampered = encoded.to_string().into_bytes();
+            tampered[at] = if tampered[at] == b'A' { b'B' } else { b'A' };
+            let tampered = format!("{}{}", SHARE_PREFIX, String::from_utf8(tampered).unwrap());
+            assert!(decode(&tampered).is_err(), "changed at {}", at);
+        }
+
+        let mut bytes = BASE64.decode(encoded).unwrap();
+        bytes[0] = 9;
+        let future = format!("{}{}", SHARE_PREFIX, BASE64.encode(&bytes));
+        assert_eq!(decode(&future), Err(ShareError::UnsupportedVersion(9)));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::error::ValidationError;
+use crate::event::Event;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+
+// Maximum events waiting per sink before new ones are dropped
+const QUEUE_CAPACITY: usize = 32;
+
+// What a sink is, as ListSinks shows it
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SinkInfo {
+    // e.g. "webhook"
+    pub kind: &'static str,
+    // Where it sends to
+    pub target: String,
+    // The events it notifies of
+    pub events: Vec<String>,
+}
+
+// Somewhere outside the server that events are pushed to, such as a webhook. The sink
+// task offers every event to each enabled sink and queues the ones it wants for the
+// sink's own worker, so a slow or stuck sink only ever holds up itself.
+pub trait NotificationSink: Send + Sync {
+    // Unique among the sinks; what EnableSink and DisableSink take
+    fn name(&self) -> &str;
+
+    fn info(&self) -> SinkInfo;
+
+    // Asked for every event on the sink task, so it should be cheap. A sink configured
+    // with topics like Subscribe takes what Topic::matches.
+    fn wants(&self, event: &Event) -> bool;
+
+    // Push one event out, retrying as the sink sees fit. Returns how many notifications it
+    // sent, none for an event that turned out not to need one.
+    fn deliver(&self, event: Event) -> BoxFuture<'_, Result<usize, String>>;
+}
+
+// Delivery counters of one sink, answered by ListSinks
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SinkStatus {
+    pub name: String,
+    #[serde(flatten)]
+    pub info: SinkInfo,
+    pub enabled: bool,
+    pub delivered: u64,
+    pub failed: u64,
+    // Not queued because the sink's queue was full
+    pub dropped: u64,
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    delivered: u64,
+    failed: u64,
+    dropped: u64,
+    consecutive_failures: u64,
+    last_error: Option<String>,
+}
+
+struct Registered {
+    sink: Arc<dyn NotificationSink>,
+    enabled: AtomicBool,
+    counters: Mutex<Counters>,
+}
+
+impl Registered {
+    fn status(&self) -> SinkStatus {
+        let counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        SinkStatus {
+            name: self.sink.name().to_string(),
+            info: self.sink.info(),
+            enabled: self.enabled.load(Ordering::Relaxed),
+            delivered: counters.delivered,
+            failed: counters.failed,
+            dropped: counters.dropped,
+            consecutive_failures: counters.consecutive_failures,
+            last_error: counters.last_error.clone(),
+        }
+    }
+
+    fn record(&self, update: impl FnOnce(&mut Counters)) {
+        update(&mut self.counters.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+// The sinks configured at startup, each enabled until DisableSink
+#[derive(Default)]
+pub struct SinkRegistry {
+    sinks: Vec<Registered>,
+}
+
+impl SinkRegistry {
+    pub fn new(sinks: Vec<Arc<dyn NotificationSink>>) -> Self {
+        let sinks = sinks
+            .into_iter()
+            .map(|sink| Registered {
+                sink,
+                enabled: AtomicBool::new(true),
+                counters: Mutex::default(),
+            })
+            .collect();
+        Self { sinks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    pub fn status(&self) -> Vec<SinkStatus> {
+        self.sinks.iter().map(Registered::status).collect()
+    }
+
+    fn find(&self, name: &str) -> Result<&Registered, ValidationError> {
+        self.sinks
+            .iter()
+            .find(|registered| registered.sink.name() == name)
+            .ok_or_else(|| ValidationError::SinkNotFound(name.to_string()))
+    }
+
+    pub fn get(&self, name: &str) -> Result<SinkStatus, ValidationError> {
+        self.find(name).map(Registered::status)
+    }
+
+    // Events are offered to a disabled sink again from the next one on. Returns whether
+    // this changed anything.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> Result<bool, ValidationError> {
+        let registered = self.find(name)?;
+        Ok(registered.enabled.swap(enabled, Ordering::Relaxed) != enabled)
+    }
+}
+
+// Deliver one sink's queue in order
+async fn delivery_worker(
+    registry: Arc<SinkRegistry>,
+    index: usize,
+    mut queue: mpsc::Receiver<Event>,
+) {
+    let registered = &registry.sinks[index];
+    while let Some(event) = queue.recv().await {
+        match registered.sink.deliver(event).await {
+            Ok(0) => {}
+            Ok(sent) => registered.record(|counters| {
+                counters.delivered += sent as u64;
+                counters.consecutive_failures = 0;
+            }),
+            Err(e) => {
+                eprintln!("Notification sink {} failed: {}", registered.sink.name(), e);
+                registered.record(|counters| {
+                    counters.failed += 1;
+                    counters.consecutive_failures += 1;
+                    counters.last_error = Some(e);
+                });
+            }
+        }
+    }
+}
+
+// Consumes the event broadcast and hands the events each sink wants to its worker
+pub async fn sink_task(registry: Arc<SinkRegistry>, mut rx: broadcast::Receiver<Event>) {
+    let queues: Vec<mpsc::Sender<Event>> = (0..registry.sinks.len())
+        .map(|index| {
+            let (queue_tx, queue_rx) = mpsc::channel(QUEUE_CAPACITY);
+            tokio::spawn(delivery_worker(registry.clone(), index, queue_rx));
+            queue_tx
+        })
+        .collect();
+
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        for (registered, queue) in registry.sinks.iter().zip(&queues) {
+            if !registered.enabled.load(Ordering::Relaxed) || !registered.sink.wants(&event) {
+                continue;
+            }
+            if queue.try_send(event.clone()).is_err() {
+                registered.record(|counters| counters.dropped += 1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Topic;
+    use std::time::Duration;
+
+    // Records what it is given; a stuck one never finishes a delivery
+    struct RecordingSink {
+        name: &'static str,
+        topics: Vec<Topic>,
+        stuck: bool,
+        delivered: Mutex<Vec<&'static str>>,
+    }
+
+    impl RecordingSink {
+        fn new(name: &'static str, topics: &str, stuck: bool) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                topics: serde_json::from_str(topics).unwrap(),
+                stuck,
+                delivered: Mutex::default(),
+            })
+        }
+    }
+
+    impl NotificationSink for RecordingSink {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn info(&self) -> SinkInfo {
+            SinkInfo {
+                kind: "test",
+                target: "memory".to_string(),
+                events: self.topics.iter().map(Topic::to_string).collect(),
+            }
+        }
+
+        fn wants(&self, event: &Event) -> bool {
+            self.topics.iter().any(|topic| topic.matches(event))
+        }
+
+        fn deliver(&self, event: Event) -> BoxFuture<'_, Result<usize, String>> {
+            Box::pin(async move {
+                if self.stuck {
+                    std::future::pending::<()>().await;
+                }
+                if let Event::Recovered { task, .. } = event {
+                    return Err(format!("{} is not for me", task));
+                }
+                self.delivered.lock().unwrap().push(event.kind());
+                Ok(1)
+            })
+        }
+    }
+
+    fn identify(pad: &str) -> Event {
+        Event::Identify {
+            pad: pad.into(),
+            duration: Duration::from_secs(1),
+        }
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn test_sinks_filter_and_count_on_their_own() {
+        let left = RecordingSink::new("left", r#"["identify:left", "recovered"]"#, false);
+        let stuck = RecordingSink::new("stuck", r#"["identify"]"#, true);
+        let registry = Arc::new(SinkRegistry::new(vec![left.clone(), stuck.clone()]));
+        let (tx, rx) = broadcast::channel::<Event>(256);
+        let handle = tokio::spawn(sink_task(registry.clone(), rx));
+
+        // The stuck sink's queue fills up without holding up the other one
+        tx.send(identify("left")).unwrap();
+        settle().await;
+        for _ in 0..QUEUE_CAPACITY {
+            tx.send(identify("left")).unwrap();
+        }
+        tx.send(identify("right")).unwrap();
+        settle().await;
+        tx.send(Event::Recovered {
+            task: "stream",
+            stalled_for: Duration::from_secs(1),
+        })
+        .unwrap();
+        settle().await;
+        assert_eq!(left.delivered.lock().unwrap().len(), QUEUE_CAPACITY + 1);
+        let status = registry.status();
+        assert_eq!(status[0].name, "left");
+        assert_eq!(status[0].info.events, ["identify:left", "recovered"]);
+        assert_eq!(status[0].delivered, QUEUE_CAPACITY as u64 + 1);
+        assert_eq!(status[0].failed, 1);
+        assert_eq!(
+            status[0].last_error.as_deref(),
+            Some("stream is not for me")
+        );
+        assert_eq!(status[0].dropped, 0);
+        assert_eq!((status[1].delivered, status[1].dropped), (0, 1));
+
+        // A disabled sink isn't offered anything
+        assert!(registry.set_enabled("left", false).unwrap());
+        assert!(!registry.set_enabled("left", false).unwrap());
+        tx.send(identify("left")).unwrap();
+        settle().await;
+        assert_eq!(left.delivered.lock().unwrap().len(), QUEUE_CAPACITY + 1);
+        assert!(!registry.get("left").unwrap().enabled);
+        assert!(registry.set_enabled("left", true).unwrap());
+        tx.send(identify("left")).unwrap();
+        settle().await;
+        assert_eq!(left.delivered.lock().unwrap().len(), QUEUE_CAPACITY + 2);
+
+        let missing = registry.set_enabled("nope", true).unwrap_err();
+        assert_eq!(missing, ValidationError::SinkNotFound("nope".to_string()));
+        handle.abort();
+    }
+}
@@ -0,0 +1,183 @@
+use crate::error::{AppError, StorageError, ValidationError};
+use crate::profile::{Profiles, PROFILES_FILE};
+use crate::sensors::Sensors;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+// Directory for snapshots unless configured, next to profiles.json
+pub const SNAPSHOT_DIR: &str = "snapshots";
+
+// Snapshots kept unless configured; creating one more deletes the oldest
+pub const DEFAULT_SNAPSHOT_RETENTION: usize = 20;
+
+// Longest label of a snapshot, in characters
+pub const MAX_LABEL_LEN: usize = 64;
+
+pub fn default_snapshot_dir() -> PathBuf {
+    Path::new(PROFILES_FILE)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(SNAPSHOT_DIR)
+}
+
+// One snapshot file: everything in profiles.json, and what the device had
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Snapshot {
+    #[serde(flatten)]
+    pub info: SnapshotInfo,
+    pub profiles: Profiles,
+}
+
+// What ListSnapshots shows of a snapshot
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub label: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // Read from the device when the snapshot was taken, in logical order; null if it
+    // couldn't be read
+    pub device_thresholds: Option<Sensors<i32>>,
+}
+
+// The snapshot files. Creating, listing and loading take turns, so the retention cap
+// never deletes a file that is being read.
+pub struct SnapshotStore {
+    dir: PathBuf,
+    retention: usize,
+    files: Mutex<()>,
+}
+
+impl SnapshotStore {
+    pub fn new(dir: PathBuf, retention: usize) -> Self {
+        Self {
+            dir,
+            retention: retention.max(1),
+            files: Mutex::new(()),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Write a snapshot of `profiles`, then delete the oldest beyond the retention cap.
+    // Returns it with the ids that were deleted.
+    pub async fn create(
+        &self,
+        label: Option<String>,
+        profiles: &Profiles,
+        device_thresholds: Option<Sensors<i32>>,
+    ) -> Result<(SnapshotInfo, Vec<String>), AppError> {
+        check_label(label.as_deref())?;
+        self.write(label, profiles, device_thresholds)
+            .await
+            .map_err(|e| StorageError::Snapshot(e).into())
+    }
+
+    async fn write(
+        &self,
+        label: Option<String>,
+        profiles: &Profiles,
+        device_thresholds: Option<Sensors<i32>>,
+    ) -> std::io::Result<(SnapshotInfo, Vec<String>)> {
+        let _files = self.files.lock().await;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let created_at = Utc::now();
+        let base = created_at.format("%Y%m%d-%H%M%S-%3f").to_string();
+        // Two snapshots within a millisecond get a suffix
+        let mut id = base.clone();
+        let mut n = 1;
+        while tokio::fs::try_exists(self.path(&id)).await? {
+            id = format!("{}-{}", base, n);
+            n += 1;
+        }
+        let snapshot = Snapshot {
+            info: SnapshotInfo {
+                id,
+                label,
+                created_at,
+                device_thresholds,
+            },
+            profiles: profiles.clone(),
+        };
+        let json = serde_json::to_string_pretty(&snapshot)?;
+        // Written aside and renamed, so a crash never leaves half a snapshot
+        let path = self.path(&snapshot.info.id);
+        let partial = path.with_extension("json.tmp");
+        tokio::fs::write(&partial, json).await?;
+        tokio::fs::rename(&partial, &path).await?;
+
+        let mut deleted = Vec::new();
+        let infos = self.read_all().await?;
+        let excess = infos.len().saturating_sub(self.retention);
+        for info in &infos[..excess] {
+            tokio::fs::remove_file(self.path(&info.id)).await?;
+            deleted.push(info.id.clone());
+        }
+        Ok((snapshot.info, deleted))
+    }
+
+    // Every snapshot, oldest first
+    pub async fn list(&self) -> Result<Vec<SnapshotInfo>, StorageError> {
+        let _files = self.files.lock().await;
+        self.read_all().await.map_err(StorageError::Snapshot)
+    }
+
+    pub async fn load(&self, id: &str) -> Result<Snapshot, AppError> {
+        // Ids are timestamps; anything else could name a file outside the directory
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            return Err(ValidationError::SnapshotNotFound(id.to_string()).into());
+        }
+        let _files = self.files.lock().await;
+        match read(&self.path(id)).await {
+            Ok(snapshot) => Ok(snapshot),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(ValidationError::SnapshotNotFound(id.to_string()).into())
+            }
+            Err(e) => Err(StorageError::Snapshot(e).into()),
+        }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    // Snapshots in the directory, oldest first. Files that don't read as one are skipped.
+    async fn read_all(&self) -> std::io::Result<Vec<SnapshotInfo>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut infos = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            match read(&path).await {
+                Ok(snapshot) => infos.push(snapshot.info),
+                Err(e) => eprintln!("Skipping snapshot {}: {}", path.display(), e),
+            }
+        }
+        infos.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        Ok(infos)
+    }
+}
+
+async fn read(path: &Path) -> std::io::Result<Snapshot> {
+    let content = tokio::fs::read_to_string(path).await?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn check_label(label: Option<&str>) -> Result<(), ValidationError> {
+    match label {
+        Some(label) if label.chars().count() > MAX_LABEL_LEN => {
+            Err(ValidationError::LabelTooLong(MAX_LABEL_LEN))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,285 @@
+use crate::health::Health;
+use crate::layout;
+use crate::profile::Profiles;
+use crate::sensors::Sensors;
+use crate::serial::{get_current_thresholds_from_device, set_all_thresholds};
+use crate::threshold_log::{ThresholdWriteLog, WriteReason};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// Which way the thresholds of the current profile and the device are reconciled on startup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum StartupSync {
+    // Write the profile's thresholds to the device
+    #[default]
+    ProfileToDevice,
+    // Take the device's thresholds into the profile, for a device tuned on its own
+    DeviceToProfile,
+    // Leave both alone and only compare them
+    None,
+}
+
+impl fmt::Display for StartupSync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StartupSync::ProfileToDevice => "profile-to-device",
+            StartupSync::DeviceToProfile => "device-to-profile",
+            StartupSync::None => "none",
+        })
+    }
+}
+
+// What the startup synchronization did, thresholds in the pad's logical order
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum SyncOutcome {
+    // The profile's thresholds were written to the device
+    Pushed {
+        thresholds: Sensors<i32>,
+    },
+    // The device's thresholds replaced the profile's
+    Pulled {
+        previous: Sensors<i32>,
+        thresholds: Sensors<i32>,
+    },
+    // Device and profile already agreed
+    InSync {
+        thresholds: Sensors<i32>,
+    },
+    // Device and profile differ and were left that way; `thresholds` are the profile's
+    Mismatch {
+        device: Sensors<i32>,
+        thresholds: Sensors<i32>,
+    },
+    // Nothing to synchronize
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+// Logged on startup, kept in the server info and sent with the first heartbeat
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct StartupSyncReport {
+    pub mode: StartupSync,
+    pub profile: String,
+    #[serde(flatten)]
+    pub outcome: SyncOutcome,
+}
+
+impl StartupSyncReport {
+    // Whether the device was left unlike the profile
+    pub fn is_warning(&self) -> bool {
+        matches!(
+            self.outcome,
+            SyncOutcome::Mismatch { .. } | SyncOutcome::Failed { .. }
+        )
+    }
+}
+
+impl fmt::Display for StartupSyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Startup sync ({}): ", self.mode)?;
+        match &self.outcome {
+            SyncOutcome::Pushed { thresholds } => write!(
+                f,
+                "wrote thresholds {:?} of profile '{}' to the device",
+                thresholds, self.profile
+            ),
+            SyncOutcome::Pulled {
+                previous,
+                thresholds,
+            } => write!(
+                f,
+                "took thresholds {:?} from the device into profile '{}', was {:?}",
+                thresholds, self.profile, previous
+            ),
+            SyncOutcome::InSync { thresholds } => write!(
+                f,
+                "device thresholds {:?} match profile '{}'",
+                thresholds, self.profile
+            ),
+            SyncOutcome::Mismatch { device, thresholds } => write!(
+                f,
+                "device thresholds {:?} don't match profile '{}' {:?}, left as they are",
+                device, self.profile, thresholds
+            ),
+            SyncOutcome::Skipped { reason } => write!(f, "skipped, {}", reason),
+            SyncOutcome::Failed { error } => write!(f, "failed: {}", error),
+        }
+    }
+}
+
+// Reconcile the current profile and the device. Pulled thresholds change `profiles`, for
+// the caller to save; a device that ends up unlike the profile is flagged out of sync.
+// Thresholds pushed to the device are logged in `writes`.
+pub async fn run(
+    mode: StartupSync,
+    port: &Arc<Mutex<Box<dyn SerialPort>>>,
+    connected: bool,
+    profiles: &mut Profiles,
+    health: &Health,
+    writes: &ThresholdWriteLog,
+) -> StartupSyncReport {
+    let profile = profiles.current_profile().to_string();
+    let outcome = sync(mode, port, connected, profiles, health, writes).await;
+    StartupSyncReport {
+        mode,
+        profile,
+        outcome,
+    }
+}
+
+async fn sync(
+    mode: StartupSync,
+    port: &Arc<Mutex<Box<dyn SerialPort>>>,
+    connected: bool,
+    profiles: &mut Profiles,
+    health: &Health,
+    writes: &ThresholdWriteLog,
+) -> SyncOutcome {
+    let skipped = |reason: String| SyncOutcome::Skipped { reason };
+    if !connected {
+        return skipped("no device".to_string());
+    }
+    if !profiles.device_enabled() {
+        return skipped(format!("pad '{}' is disabled", profiles.device_pad_id()));
+    }
+    let sensor_map = profiles.sensor_map();
+    let current = profiles.current_profile().to_string();
+    if current.is_empty() {
+        return skipped("no current profile".to_string());
+    }
+    let Some(profile) = profiles.profiles.get_mut(&current) else {
+        return skipped(format!("current profile '{}' not found", current));
+    };
+
+    if mode == StartupSync::ProfileToDevice {
+        let physical = sensor_map.to_physical(profile.thresholds);
+        let logged = writes.reason(WriteReason::StartupSync);
+        let written = set_all_thresholds(port, physical, logged).await;
+        return match written {
+            Ok(_) => SyncOutcome::Pushed {
+                thresholds: profile.thresholds,
+            },
+            Err(e) => {
+                health.set_device_out_of_sync(e.left_inconsistent());
+                SyncOutcome::Failed {
+                    error: e.to_string(),
+                }
+            }
+        };
+    }
+
+    let device = match get_current_thresholds_from_device(port).await {
+        Ok(physical) => sensor_map.to_logical(physical),
+        Err(e) => {
+            return SyncOutcome::Failed {
+                error: e.to_string(),
+            }
+        }
+    };
+    if device == profile.thresholds {
+        return SyncOutcome::InSync { thresholds: device };
+    }
+    if mode == StartupSync::DeviceToProfile {
+        let previous = std::mem::replace(&mut profile.thresholds, device);
+        // A device with another number of sensors brings its layout along
+        if previous.len() != device.len() {
+            profile.layout = layout::for_sensor_count(device.len()).to_string();
+        }
+        return SyncOutcome::Pulled {
+            previous,
+            thresholds: device,
+        };
+    }
+    health.set_device_out_of_sync(true);
+    SyncOutcome::Mismatch {
+        device,
+        thresholds: profile.thresholds,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{Pad, Profile};
+    use crate::serial::MockSerialPort;
+    use std::collections::HashMap;
+
+    async fn sync_with(
+        mode: StartupSync,
+        writes: &ThresholdWriteLog,
+    ) -> (StartupSyncReport, Profiles, Health, Sensors<i32>) {
+        let mut profiles = Profiles {
+            profiles: HashMap::from([("Tuned".to_string(), Profile::new([10, 20, 30, 40]))]),
+            pads: vec![Pad::default_pad("Tuned".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        let port: Arc<Mutex<Box<dyn SerialPort>>> =
+            Arc::new(Mutex::new(Box::new(MockSerialPort::new([15, 25, 35, 45]))));
+        let health = Health::new(true);
+        let report = run(mode, &port, true, &mut profiles, &health, writes).await;
+        let device = get_current_thresholds_from_device(&port).await.unwrap();
+        (report, profiles, health, device)
+    }
+
+    #[tokio::test]
+    async fn test_startup_sync_modes() {
+        let writes = ThresholdWriteLog::default();
+        let (report, profiles, health, device) =
+            sync_with(StartupSync::ProfileToDevice, &writes).await;
+        assert_eq!(
+            report.outcome,
+            SyncOutcome::Pushed {
+                thresholds: [10, 20, 30, 40].into()
+            }
+        );
+        assert_eq!(device, [10, 20, 30, 40]);
+        assert_eq!(profiles.profiles["Tuned"].thresholds, [10, 20, 30, 40]);
+        assert!(!health.device_out_of_sync());
+        let pushed = &writes.tail(1)[0];
+        assert_eq!(pushed.reason, WriteReason::StartupSync);
+        assert_eq!(pushed.before, [Some(15), Some(25), Some(35), Some(45)]);
+
+        // The device's own values win and end up in the profile
+        let (report, profiles, health, device) =
+            sync_with(StartupSync::DeviceToProfile, &writes).await;
+        assert_eq!(
+            report.outcome,
+            SyncOutcome::Pulled {
+                previous: [10, 20, 30, 40].into(),
+                thresholds: [15, 25, 35, 45].into()
+            }
+        );
+        assert_eq!(device, [15, 25, 35, 45]);
+        assert_eq!(profiles.profiles["Tuned"].thresholds, [15, 25, 35, 45]);
+        assert!(!health.device_out_of_sync());
+
+        // Neither changes; the difference is reported
+        let (report, profiles, health, device) = sync_with(StartupSync::None, &writes).await;
+        assert!(report.is_warning());
+        assert_eq!(device, [15, 25, 35, 45]);
+        assert_eq!(profiles.profiles["Tuned"].thresholds, [10, 20, 30, 40]);
+        assert!(health.device_out_of_sync());
+        // Only the push wrote anything
+        assert_eq!(writes.tail(10).len(), 1);
+        let serialized = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            serialized,
+            serde_json::json!({
+                "mode": "none",
+                "profile": "Tuned",
+                "outcome": "mismatch",
+                "device": [15, 25, 35, 45],
+                "thresholds": [10, 20, 30, 40],
+            })
+        );
+    }
+}
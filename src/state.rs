@@ -0,0 +1,309 @@
+use crate::audit::AuditLog;
+use crate::auth::Auth;
+use crate::capture::{default_capture_dir, SerialCapture, CAPTURE_MAX_BYTES};
+use crate::chunk::ChunkLimits;
+use crate::clients::ClientRegistry;
+use crate::coalesce::Coalescer;
+use crate::commands::ReadOnlyPolicy;
+use crate::deflate::WsCompression;
+use crate::drift::DriftCompensation;
+use crate::event::Event;
+use crate::fresh::FreshReadLimiter;
+use crate::health::Health;
+use crate::heartbeat::RateMeter;
+use crate::history::EventHistory;
+use crate::idempotency::IdempotencyCache;
+use crate::idle::IdleSignOut;
+use crate::info::ServerInfo;
+use crate::instance::RemoteShutdown;
+use crate::journal::Journal;
+use crate::latency::PressLatency;
+use crate::latest::LatestFrame;
+use crate::metrics::Metrics;
+use crate::mirror::Mirror;
+use crate::panel_test::PanelTest;
+use crate::persist::SaveControl;
+use crate::pipeline::SerialPipeline;
+use crate::profile::{LoadFailure, Profiles};
+use crate::proposal::ProposalStore;
+use crate::range::DeviceRange;
+use crate::serial_queue::SerialQueue;
+use crate::session::SessionStore;
+use crate::sink::SinkRegistry;
+use crate::snapshot::{default_snapshot_dir, SnapshotStore, DEFAULT_SNAPSHOT_RETENTION};
+use crate::threshold_log::ThresholdWriteLog;
+use axum::extract::FromRef;
+use serialport::SerialPort;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch, Mutex};
+
+// Capacity of the event channel, sized for the 60Hz sensor stream
+pub const EVENT_CHANNEL_CAPACITY: usize = 1000;
+
+// Sensor polling rate when the stream is started
+pub const DEFAULT_STREAM_RATE_HZ: u32 = 60;
+
+// Whether the sensor stream runs and how fast it polls the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamConfig {
+    pub enabled: bool,
+    pub rate_hz: u32,
+    // With `--adaptive-stream`, how long the pad must be still before polling drops to
+    // IDLE_RATE_HZ
+    pub adaptive: Option<Duration>,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rate_hz: DEFAULT_STREAM_RATE_HZ,
+            adaptive: None,
+        }
+    }
+}
+
+impl StreamConfig {
+    // Time between two sensor reads, 16ms at 60Hz
+    pub fn period(&self) -> Duration {
+        period(self.rate_hz)
+    }
+
+    // "fixed" or "adaptive", as shown in the stream status
+    pub fn mode(&self) -> &'static str {
+        if self.adaptive.is_some() {
+            "adaptive"
+        } else {
+            "fixed"
+        }
+    }
+}
+
+pub fn period(rate_hz: u32) -> Duration {
+    Duration::from_millis(1000 / u64::from(rate_hz.max(1)))
+}
+
+// State shared by the websocket handlers, the command layer and the background tasks.
+// Cloning is cheap: every shared piece sits behind an Arc.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    // Canonical profiles; every change publishes a new snapshot
+    pub profiles: Arc<watch::Sender<Arc<Profiles>>>,
+    // Held while a command commits its changes to the profiles, never across serial I/O
+    pub mutations: Arc<Mutex<()>>,
+    // Events broadcast to every connected client and other sinks
+    pub events: Arc<broadcast::Sender<Event>>,
+    // Numbered copies of the events other than sensor frames, see publish()
+    pub history: Arc<EventHistory>,
+    pub serial: Arc<Mutex<Box<dyn SerialPort>>>,
+    // Sensor stream configuration; the stream task wakes whenever it changes
+    pub stream: Arc<watch::Sender<StreamConfig>>,
+    // Where events are pushed outside the server, such as webhooks, see sink.rs
+    pub sinks: Arc<SinkRegistry>,
+    pub read_only: ReadOnlyPolicy,
+    // Tokens and the roles they grant; without any every client is admin
+    pub auth: Arc<Auth>,
+    // Websocket responses larger than the chunk size are sent in parts
+    pub chunk_limits: ChunkLimits,
+    // Whether websocket clients that offer permessage-deflate get it, see deflate.rs
+    pub ws_compression: WsCompression,
+    pub health: Arc<Health>,
+    pub metrics: Arc<Metrics>,
+    pub journal: Arc<Journal>,
+    // Mutating commands with where they came from and how they ended
+    pub audit: Arc<AuditLog>,
+    // Build and configuration details; uptime is filled in by server_info()
+    pub info: Arc<ServerInfo>,
+    // Open websocket connections and their send-path counters
+    pub clients: Arc<ClientRegistry>,
+    // Serial capture toggled by StartSerialCapture/StopSerialCapture
+    pub capture: Arc<SerialCapture>,
+    // Subscriptions of disconnected clients that gave a client id, see session.rs
+    pub sessions: Arc<SessionStore>,
+    // Sensor value range, the ceiling for thresholds
+    pub range: Arc<DeviceRange>,
+    // Merges UpdateThreshold commands for the same threshold, see coalesce.rs
+    pub coalescer: Arc<Coalescer>,
+    // Bounds the commands waiting for the serial port, see serial_queue.rs
+    pub serial_queue: Arc<SerialQueue>,
+    // Responses of recent mutating commands by idempotency key, see idempotency.rs
+    pub idempotency: Arc<IdempotencyCache>,
+    // profiles.json failed to parse on startup and was moved aside, see RetryLoadProfiles
+    pub load_failure: Arc<std::sync::Mutex<Option<LoadFailure>>>,
+    // Rate the adaptive stream polls at right now; 0 while it runs at the configured rate
+    pub stream_rate: Arc<AtomicU32>,
+    // Number of changes published to `profiles`, so the saver can tell how many it folded
+    pub profiles_generation: Arc<AtomicU64>,
+    // Newest sensor reading, written by the stream task
+    pub latest: Arc<LatestFrame>,
+    // Set with --pipelined-reads, see pipeline.rs
+    pub serial_pipeline: Option<Arc<SerialPipeline>>,
+    // Rate limit of `GET /api/sensors?fresh=true`, one for all HTTP clients
+    pub http_fresh_reads: Arc<FreshReadLimiter>,
+    // Achieved stream rate as measured by GetStreamStatus
+    pub stream_meter: Arc<std::sync::Mutex<RateMeter>>,
+    // Autosave switch and explicit saves, see persist.rs
+    pub saves: Arc<SaveControl>,
+    // Device threshold offsets following the sensors' rest values, see drift.rs
+    pub drift: Arc<DriftCompensation>,
+    // Panel test started by StartPanelTest, see panel_test.rs
+    pub panel_test: Arc<PanelTest>,
+    // Files of CreateSnapshot and RestoreSnapshot
+    pub snapshots: Arc<SnapshotStore>,
+    // Round trip and dispatch time of the stream's presses, see latency.rs
+    pub latency: Arc<PressLatency>,
+    // `POST /api/shutdown` by a server taking over, see instance.rs
+    pub shutdown: Arc<RemoteShutdown>,
+    // The instance followed with --mirror, see mirror.rs
+    pub mirror: Option<Arc<Mirror>>,
+    // Session and countdown of --idle-sign-out, see idle.rs
+    pub idle: Arc<IdleSignOut>,
+    // Threshold changes waiting for an operator, see proposal.rs
+    pub proposals: Arc<ProposalStore>,
+    // Every write of the device's thresholds and its reason, see threshold_log.rs
+    pub threshold_writes: Arc<ThresholdWriteLog>,
+}
+
+impl AppState {
+    // State with the stream stopped, no notification sinks, read-only off and a healthy device.
+    // The capture isn't attached to `serial`; callers that wrap the port set their own.
+    pub fn new(profiles: Profiles, serial: Arc<Mutex<Box<dyn SerialPort>>>) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            profiles: Arc::new(watch::Sender::new(Arc::new(profiles))),
+            mutations: Arc::new(Mutex::new(())),
+            events: Arc::new(events),
+            history: Arc::new(EventHistory::default()),
+            serial,
+            stream: Arc::new(watch::Sender::new(StreamConfig::default())),
+            sinks: Arc::default(),
+            read_only: ReadOnlyPolicy::default(),
+            auth: Arc::default(),
+            chunk_limits: ChunkLimits::default(),
+            ws_compression: WsCompression::default(),
+            health: Arc::new(Health::new(true)),
+            metrics: Arc::new(Metrics::new()),
+            journal: Arc::new(Journal::disabled()),
+            audit: Arc::default(),
+            info: Arc::new(ServerInfo::default()),
+            clients: Arc::new(ClientRegistry::default()),
+            capture: Arc::new(SerialCapture::new(
+                Default::default(),
+                default_capture_dir(),
+                CAPTURE_MAX_BYTES,
+            )),
+            sessions: Arc::new(SessionStore::default()),
+            range: Arc::new(DeviceRange::default()),
+            coalescer: Arc::new(Coalescer::default()),
+            serial_queue: Arc::new(SerialQueue::default()),
+            idempotency: Arc::new(IdempotencyCache::default()),
+            load_failure: Arc::default(),
+            stream_rate: Arc::default(),
+            profiles_generation: Arc::default(),
+            latest: Arc::default(),
+            serial_pipeline: None,
+            stream_meter: Arc::new(std::sync::Mutex::new(RateMeter::new(0))),
+            saves: Arc::default(),
+            http_fresh_reads: Arc::default(),
+            drift: Arc::default(),
+            panel_test: Arc::default(),
+            snapshots: Arc::new(SnapshotStore::new(
+                default_snapshot_dir(),
+                DEFAULT_SNAPSHOT_RETENTION,
+            )),
+            latency: Arc::default(),
+            shutdown: Arc::default(),
+            mirror: None,
+            idle: Arc::default(),
+            proposals: Arc::default(),
+            threshold_writes: Arc::default(),
+        }
+    }
+
+    // Current profiles, shared with every other reader
+    pub fn profiles_snapshot(&self) -> Arc<Profiles> {
+        self.profiles.borrow().clone()
+    }
+
+    // Replace the profiles, notifying subscribers only if something actually changed
+    pub fn publish_profiles(&self, profiles: Arc<Profiles>) -> bool {
+        self.profiles.send_if_modified(|current| {
+            if **current == *profiles {
+                return false;
+            }
+            *current = profiles;
+            // Bumped under the channel's lock, so it always matches the snapshot
+            self.profiles_generation.fetch_add(1, Ordering::Relaxed);
+            true
+        })
+    }
+
+    // Broadcast an event, numbering it in the history unless it's a sensor frame
+    pub fn publish(&self, event: Event) {
+        self.history.publish(event, &self.events);
+    }
+
+    pub fn load_failure(&self) -> Option<LoadFailure> {
+        self.load_failure
+            .lock()
+            .ok()
+            .and_then(|failure| failure.clone())
+    }
+
+    pub fn set_load_failure(&self, failure: Option<LoadFailure>) {
+        if let Ok(mut current) = self.load_failure.lock() {
+            *current = failure;
+        }
+    }
+
+    pub fn server_info(&self) -> ServerInfo {
+        ServerInfo {
+            uptime_secs: self.metrics.uptime_secs(),
+            profiles_load_failure: self.load_failure(),
+            ..(*self.info).clone()
+        }
+    }
+
+    // Rate the stream polls the device at, lower than the configured one while an
+    // adaptive stream is idle
+    pub fn effective_rate_hz(&self) -> u32 {
+        match self.stream_rate.load(Ordering::Relaxed) {
+            0 => self.stream.borrow().rate_hz,
+            rate => rate,
+        }
+    }
+
+    pub fn stream_enabled(&self) -> bool {
+        self.stream.borrow().enabled
+    }
+
+    // Start or stop the sensor stream, returning whether it was running. Only an actual
+    // change wakes the stream task and is announced, whoever made it.
+    pub fn set_stream_enabled(&self, enabled: bool) -> bool {
+        let mut was_enabled = enabled;
+        let changed = self.stream.send_if_modified(|config| {
+            was_enabled = config.enabled;
+            config.enabled = enabled;
+            was_enabled != enabled
+        });
+        if changed {
+            self.publish(Event::StreamStateChanged { enabled });
+        }
+        was_enabled
+    }
+
+    #[cfg(test)]
+    pub fn with_port(profiles: Profiles, port: Box<dyn SerialPort>) -> Self {
+        Self::new(profiles, Arc::new(Mutex::new(port)))
+    }
+
+    #[cfg(test)]
+    pub fn with_mock_port(profiles: Profiles) -> Self {
+        Self::with_port(
+            profiles,
+            Box::new(crate::serial::MockSerialPort::new([100, 200, 300, 400])),
+        )
+    }
+}
@@ -0,0 +1,250 @@
+use crate::event::Event;
+use crate::journal::{Journal, JournalKind};
+use crate::state::AppState;
+use std::backtrace::Backtrace;
+use std::future::Future;
+use std::panic::PanicHookInfo;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::{AbortHandle, JoinError, JoinHandle};
+use tokio::time::{Instant, MissedTickBehavior};
+
+// Delay before restarting a task, doubled after every failure up to the maximum
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    // A task that ran at least this long starts over at the initial delay
+    pub reset_after: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+// Log panics with a backtrace to stderr (through the previous hook) and to the journal
+pub fn install_panic_hook(journal: Arc<Journal>) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let backtrace = Backtrace::force_capture();
+        journal.record(
+            JournalKind::Panic,
+            format!("{}\n{}", panic_summary(info), backtrace),
+        );
+    }));
+}
+
+fn panic_summary(info: &PanicHookInfo<'_>) -> String {
+    let message = panic_message(info.payload());
+    match info.location() {
+        Some(location) => format!("panicked at {}: {}", location, message),
+        None => format!("panicked: {}", message),
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn failure_reason(error: JoinError) -> String {
+    if error.is_panic() {
+        format!("panicked: {}", panic_message(&*error.into_panic()))
+    } else {
+        "cancelled".to_string()
+    }
+}
+
+// Aborts the supervised task when the supervisor itself is aborted or dropped
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Run the task built by `spawn` and restart it with backoff whenever it panics. A task
+// that returns normally is done (e.g. its channel closed) and isn't restarted. Failures
+// are logged, journaled and broadcast as a `degraded` event.
+pub async fn supervise<F, Fut>(name: &'static str, state: AppState, backoff: Backoff, spawn: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut delay = backoff.initial;
+    loop {
+        let started = Instant::now();
+        let handle = tokio::spawn(spawn());
+        let _abort = AbortOnDrop(handle.abort_handle());
+        let error = match handle.await {
+            Ok(()) => return,
+            Err(error) => error,
+        };
+
+        if started.elapsed() >= backoff.reset_after {
+            delay = backoff.initial;
+        }
+        let reason = failure_reason(error);
+        eprintln!(
+            "Background task '{}' {}, restarting in {}ms",
+            name,
+            reason,
+            delay.as_millis()
+        );
+        state
+            .journal
+            .record(JournalKind::TaskRestarted, format!("{} {}", name, reason));
+        state.publish(Event::Degraded {
+            task: name,
+            reason,
+            restart_in: delay,
+        });
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(backoff.max);
+    }
+}
+
+// Restart the sensor stream task built by `start` when it stops ticking for longer than
+// `timeout` while streaming is enabled. A panic is handled by the supervisor, but an
+// exchange that never completes leaves the task alive and silent; aborting it drops the
+// in-flight serial exchange and releases the port for the new task.
+pub async fn stream_watchdog<F>(state: AppState, timeout: Duration, start: F)
+where
+    F: Fn() -> JoinHandle<()>,
+{
+    let mut stream = AbortOnDrop(start().abort_handle());
+    let mut check = tokio::time::interval((timeout / 4).max(Duration::from_millis(50)));
+    check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The task only ticks while enabled, so a stall is counted from when that started
+    let mut enabled_since: Option<Instant> = None;
+    loop {
+        check.tick().await;
+        if !state.stream_enabled() {
+            enabled_since = None;
+            continue;
+        }
+        let enabled_for = enabled_since.get_or_insert_with(Instant::now).elapsed();
+        let stalled_for = state.health.since_stream_tick().min(enabled_for);
+        if stalled_for <= timeout {
+            continue;
+        }
+
+        stream.0.abort();
+        stream = AbortOnDrop(start().abort_handle());
+        enabled_since = Some(Instant::now());
+        state.metrics.record_stream_restart();
+        eprintln!(
+            "Sensor stream made no progress for {}ms, restarted it",
+            stalled_for.as_millis()
+        );
+        state.journal.record(
+            JournalKind::TaskRestarted,
+            format!("sensor_stream stalled for {}ms", stalled_for.as_millis()),
+        );
+        state.publish(Event::Recovered {
+            task: "sensor_stream",
+            stalled_for,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Profiles;
+    use crate::serial::MockSerialPort;
+
+    #[tokio::test]
+    async fn test_stream_recovers_from_panic() {
+        // The third sensor read panics inside the serial write
+        let port = MockSerialPort::new([100, 200, 300, 400]).with_panic_on_read(3);
+        let state = AppState::with_port(Profiles::default(), Box::new(port));
+        let mut events = state.events.subscribe();
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            ..Backoff::default()
+        };
+        let supervisor = tokio::spawn(supervise("sensor_stream", state.clone(), backoff, {
+            let state = state.clone();
+            move || crate::sensor_stream_task(state.clone())
+        }));
+        state.set_stream_enabled(true);
+
+        let mut frames_before = 0;
+        let mut frames_after = 0;
+        let mut degraded = None;
+        while frames_after < 3 {
+            let event = tokio::time::timeout(Duration::from_secs(2), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::SensorFrame { .. } if degraded.is_none() => frames_before += 1,
+                Event::SensorFrame { .. } => frames_after += 1,
+                event @ Event::Degraded { .. } => degraded = Some(event.to_response()),
+                _ => {}
+            }
+        }
+
+        assert_eq!(frames_before, 2);
+        let degraded = degraded.unwrap();
+        assert_eq!(degraded.response_type.as_deref(), Some("degraded"));
+        assert!(degraded.message.contains("sensor_stream"));
+        assert!(degraded.message.contains("mock panic on sensor read 3"));
+        supervisor.abort();
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_restarts_wedged_stream() {
+        // The second sensor read never gets a reply, so that tick never finishes
+        let port = MockSerialPort::new([100, 200, 300, 400]).with_hang_on_read(2);
+        let state = AppState::with_port(Profiles::default(), Box::new(port));
+        let mut events = state.events.subscribe();
+        let watchdog = tokio::spawn(stream_watchdog(
+            state.clone(),
+            Duration::from_millis(200),
+            {
+                let state = state.clone();
+                move || tokio::spawn(crate::sensor_stream_task(state.clone()))
+            },
+        ));
+        state.set_stream_enabled(true);
+
+        let mut frames_before = 0;
+        let mut frames_after = 0;
+        let mut recovered = None;
+        while frames_after < 3 {
+            let event = tokio::time::timeout(Duration::from_secs(2), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event {
+                Event::SensorFrame { .. } if recovered.is_none() => frames_before += 1,
+                Event::SensorFrame { .. } => frames_after += 1,
+                event @ Event::Recovered { .. } => recovered = Some(event.to_response()),
+                _ => {}
+            }
+        }
+
+        assert_eq!(frames_before, 1);
+        let recovered = recovered.unwrap();
+        assert_eq!(recovered.response_type.as_deref(), Some("recovered"));
+        assert_eq!(recovered.payload.unwrap()["code"], "TASK_RECOVERED");
+        assert_eq!(state.metrics.snapshot().stream_restarts, 1);
+        watchdog.abort();
+    }
+}
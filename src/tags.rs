@@ -0,0 +1,66 @@
+use crate::error::ValidationError;
+
+// Most tags on one profile
+pub const MAX_TAGS: usize = 16;
+
+// Longest tag, in characters
+pub const MAX_TAG_LEN: usize = 32;
+
+// Tags as a profile keeps them: trimmed, each 1-MAX_TAG_LEN characters, at most MAX_TAGS,
+// in the order given. Tags that differ only in case are the same tag; the first spelling
+// is kept.
+pub fn normalize(tags: Vec<String>) -> Result<Vec<String>, ValidationError> {
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+            return Err(ValidationError::InvalidTag {
+                tag: tag.to_string(),
+                max: MAX_TAG_LEN,
+            });
+        }
+        if !normalized.iter().any(|kept| matches(kept, tag)) {
+            normalized.push(tag.to_string());
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(ValidationError::TooManyTags(MAX_TAGS));
+    }
+    Ok(normalized)
+}
+
+// Whether `tag` is `filter`, ignoring case and surrounding whitespace
+pub fn matches(tag: &str, filter: &str) -> bool {
+    tag.trim().to_lowercase() == filter.trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn test_tags_are_normalized() {
+        assert_eq!(
+            normalize(tags(&[" shoes ", "Kids", "SHOES", "kids"])).unwrap(),
+            tags(&["shoes", "Kids"])
+        );
+        assert_eq!(normalize(Vec::new()).unwrap(), Vec::<String>::new());
+        assert_eq!(
+            normalize(tags(&["socks", "  "])),
+            Err(ValidationError::InvalidTag {
+                tag: String::new(),
+                max: MAX_TAG_LEN,
+            })
+        );
+        assert!(normalize(tags(&[&"x".repeat(MAX_TAG_LEN)])).is_ok());
+        assert!(normalize(tags(&[&"x".repeat(MAX_TAG_LEN + 1)])).is_err());
+
+        let many: Vec<String> = (0..=MAX_TAGS).map(|i| format!("tag{}", i)).collect();
+        assert_eq!(normalize(many), Err(ValidationError::TooManyTags(MAX_TAGS)));
+        assert!(matches("Heavy Players", " heavy players"));
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::Args;
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+// Log level when RUST_LOG isn't set
+const DEFAULT_LOG_FILTER: &str = "warn";
+
+// Keeps the span exporter alive; shut it down to flush spans before exiting
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+// Install the global tracing subscriber: stderr logging filtered by RUST_LOG and, with the
+// otlp feature and --tracing-otlp, export of command and device spans to a collector
+pub fn init(args: &Args) -> Telemetry {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER));
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(filter);
+    let registry = tracing_subscriber::registry().with(fmt);
+
+    #[cfg(feature = "otlp")]
+    {
+        let provider =
+            args.tracing_otlp
+                .as_deref()
+                .and_then(|endpoint| match otlp::provider(endpoint) {
+                    Ok(provider) => Some(provider),
+                    Err(e) => {
+                        eprintln!("Failed to set up OTLP export to {}: {}", endpoint, e);
+                        None
+                    }
+                });
+        let layer = provider.as_ref().map(otlp::layer);
+        registry.with(layer).init();
+        Telemetry { provider }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        let _ = args;
+        registry.init();
+        Telemetry {}
+    }
+}
+
+impl Telemetry {
+    // Flush pending spans; a no-op without an exporter
+    pub async fn shutdown(self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider {
+            // The exporter blocks on HTTP, keep it off the runtime threads
+            let result = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+            if let Ok(Err(e)) = result {
+                eprintln!("Failed to flush spans: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    // Batch exporter sending spans over OTLP/HTTP, e.g. to http://collector:4318/v1/traces
+    pub fn provider(endpoint: &str) -> Result<SdkTracerProvider, String> {
+        let endpoint = endpoint.to_string();
+        // The blocking HTTP client can't be built on a runtime thread
+        let exporter = std::thread::spawn(move || {
+            SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()
+        })
+        .join()
+        .map_err(|_| "exporter setup panicked".to_string())?
+        .map_err(|e| e.to_string())?;
+
+        Ok(SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("fsr-rs").build())
+            .build())
+    }
+
+    // Commands, connections and device writes; the per-tick stream spans stay local
+    pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("fsr-rs"))
+            .with_filter(LevelFilter::DEBUG)
+    }
+}
@@ -0,0 +1,179 @@
+use crate::commands::handle_command;
+use crate::event::Event;
+use crate::profile::{Command, Profiles};
+use crate::state::AppState;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Longest an ApplyTemporaryProfile lasts; longer durations are cut to this
+pub const MAX_TEMPORARY_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Wait before trying a failed revert again, e.g. while the device doesn't answer
+const REVERT_RETRY: Duration = Duration::from_secs(5);
+
+// A pad's profile for now, stored with the pad: what it switches back to and when
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TemporaryProfile {
+    // The pad's profile before the first ApplyTemporaryProfile
+    pub previous: String,
+    pub until: DateTime<Utc>,
+}
+
+impl TemporaryProfile {
+    // Zero once the deadline passed
+    pub fn remaining(&self) -> Duration {
+        (self.until - Utc::now()).to_std().unwrap_or_default()
+    }
+}
+
+// The device pad's temporary profile as the heartbeat shows it
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TemporaryStatus {
+    pub profile: String,
+    pub previous: String,
+    pub until: DateTime<Utc>,
+    pub remaining_secs: u64,
+}
+
+pub fn status(profiles: &Profiles) -> Option<TemporaryStatus> {
+    let pad = profiles.pads.first()?;
+    let temporary = pad.temporary.as_ref()?;
+    Some(TemporaryStatus {
+        profile: pad.current_profile.clone(),
+        previous: temporary.previous.clone(),
+        until: temporary.until,
+        remaining_secs: temporary.remaining().as_secs(),
+    })
+}
+
+// Why a command ended the temporary profiles it did, for the event
+pub fn end_reason(command: &Command) -> &'static str {
+    match command {
+        Command::RevertTemporaryProfile { .. } => "reverted",
+        Command::ChangePlayer { .. } => "player_changed",
+        _ => "replaced",
+    }
+}
+
+// Events for the temporary profiles that are in `before` but not in `after`
+pub fn ended(before: &Profiles, after: &Profiles, reason: &'static str) -> Vec<Event> {
+    before
+        .pads
+        .iter()
+        .filter_map(|pad| {
+            let temporary = pad.temporary.as_ref()?;
+            let now = after.pads.iter().find(|now| now.id == pad.id);
+            if now.is_some_and(|now| now.temporary.is_some()) {
+                return None;
+            }
+            let reason = match reason {
+                "reverted" if temporary.remaining().is_zero() => "expired",
+                reason => reason,
+            };
+            Some(Event::TemporaryProfileEnded {
+                pad: pad.id.as_str().into(),
+                profile: pad.current_profile.clone(),
+                restored: now.map(|now| now.current_profile.clone()),
+                reason,
+            })
+        })
+        .collect()
+}
+
+// Put back the profile from before of every pad on a temporary one, for a server starting
+// with a profiles.json saved while one was on. Returns the pads with the temporary profile
+// and the one put back; a profile that no longer exists leaves the pad as it is.
+pub fn revert_all(profiles: &mut Profiles) -> Vec<(String, String, String)> {
+    let mut reverted = Vec::new();
+    for pad in &mut profiles.pads {
+        let Some(temporary) = pad.temporary.take() else {
+            continue;
+        };
+        if profiles.profiles.contains_key(&temporary.previous) {
+            let profile = std::mem::replace(&mut pad.current_profile, temporary.previous);
+            reverted.push((pad.id.clone(), profile, pad.current_profile.clone()));
+        }
+    }
+    reverted
+}
+
+// Switches pads back from their temporary profile once its time is up
+pub async fn temporary_task(state: AppState) {
+    let mut changes = state.profiles.subscribe();
+    loop {
+        let profiles = changes.borrow_and_update().clone();
+        let next = profiles
+            .pads
+            .iter()
+            .filter_map(|pad| pad.temporary.as_ref().map(|temporary| (pad, temporary)))
+            .min_by_key(|(_, temporary)| temporary.until);
+        let wait = match next {
+            Some((pad, temporary)) if temporary.remaining().is_zero() => {
+                let command = Command::RevertTemporaryProfile {
+                    pad: Some(pad.id.clone()),
+                };
+                let response = handle_command(command, &state).await;
+                if response.success {
+                    continue;
+                }
+                let message = format!(
+                    "Failed to switch pad '{}' back to profile '{}': {}",
+                    pad.id, temporary.previous, response.message
+                );
+                // Serial failures are journaled with the command
+                eprintln!("{}", message);
+                Some(REVERT_RETRY)
+            }
+            Some((_, temporary)) => Some(temporary.remaining()),
+            None => None,
+        };
+        let changed = match wait {
+            Some(wait) => tokio::select! {
+                () = tokio::time::sleep(wait) => Ok(()),
+                changed = changes.changed() => changed,
+            },
+            None => changes.changed().await,
+        };
+        if changed.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{Pad, Profile};
+
+    #[test]
+    fn test_startup_puts_back_the_previous_profile() {
+        let mut profiles = Profiles {
+            pads: vec![Pad::default_pad("Guest".to_string(), String::new())],
+            ..Profiles::default()
+        };
+        for name in ["Guest", "Mine"] {
+            profiles
+                .profiles
+                .insert(name.to_string(), Profile::new([500; 4]));
+        }
+        profiles.pads[0].temporary = Some(TemporaryProfile {
+            previous: "Mine".to_string(),
+            until: Utc::now() + chrono::TimeDelta::hours(1),
+        });
+        assert_eq!(status(&profiles).unwrap().remaining_secs / 60, 59);
+
+        let reverted = revert_all(&mut profiles);
+        assert_eq!(
+            reverted,
+            [(
+                "default".to_string(),
+                "Guest".to_string(),
+                "Mine".to_string()
+            )]
+        );
+        assert_eq!(profiles.current_profile(), "Mine");
+        assert_eq!(profiles.pads[0].temporary, None);
+        assert_eq!(status(&profiles), None);
+    }
+}
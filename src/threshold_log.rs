@@ -0,0 +1,198 @@
+use crate::journal::{Journal, JournalKind};
+use crate::sensors::Sensors;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// Device threshold writes kept in memory for GetThresholdWriteLog
+pub const WRITE_LOG_CAPACITY: usize = 200;
+
+// Entries returned by GetThresholdWriteLog when no limit is given
+pub const DEFAULT_WRITE_LOG_LIMIT: usize = 50;
+
+// Why the device's thresholds were written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteReason {
+    // UpdateThreshold, an approved proposal or the set-thresholds subcommand
+    UserCommand,
+    // Another profile put on the device: ChangeProfile, ChangePlayer, a schedule, a
+    // temporary profile, a pad enabled again or the players of a removed profile moved
+    ProfileSwitch,
+    // SetSensorMap moving the thresholds to other sensors
+    SensorMap,
+    // The profiles loaded by RetryLoadProfiles or RestoreSnapshot
+    Reload,
+    // --startup-sync profile-to-device
+    StartupSync,
+    DriftCompensation,
+    // GetCurrentThresholds found the device unlike the current profile and rewrote it
+    Resync,
+    // IdentifyPad lowering a threshold and putting it back
+    Identify,
+    // The threshold_readback check of RunSelfTest
+    SelfTest,
+}
+
+impl fmt::Display for WriteReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WriteReason::UserCommand => "user command",
+            WriteReason::ProfileSwitch => "profile switch",
+            WriteReason::SensorMap => "sensor map",
+            WriteReason::Reload => "reload",
+            WriteReason::StartupSync => "startup sync",
+            WriteReason::DriftCompensation => "drift compensation",
+            WriteReason::Resync => "resync",
+            WriteReason::Identify => "identify",
+            WriteReason::SelfTest => "self-test",
+        })
+    }
+}
+
+// One write of the device's thresholds, in device order, null for the sensors not written
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ThresholdWrite {
+    pub timestamp: DateTime<Utc>,
+    pub reason: WriteReason,
+    // As read from the device before a write of all of them, or as the server had it for
+    // a single one; null where unknown
+    pub before: Sensors<Option<i32>>,
+    pub after: Sensors<Option<i32>>,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl fmt::Display for ThresholdWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: Option<i32>| value.map_or("?".to_string(), |value| value.to_string());
+        let written: Vec<usize> = (0..self.after.len())
+            .filter(|&i| self.after[i].is_some())
+            .collect();
+        let list = |values: &Sensors<Option<i32>>| {
+            let shown: Vec<String> = values.iter().map(|&value| show(value)).collect();
+            shown.join(", ")
+        };
+        match written[..] {
+            [sensor] => write!(
+                f,
+                "{}: sensor {} from {} to {}",
+                self.reason,
+                sensor,
+                show(self.before[sensor]),
+                show(self.after[sensor])
+            )?,
+            _ => write!(
+                f,
+                "{}: thresholds from [{}] to [{}]",
+                self.reason,
+                list(&self.before),
+                list(&self.after)
+            )?,
+        }
+        match &self.error {
+            Some(error) => write!(f, ", failed: {}", error),
+            None => Ok(()),
+        }
+    }
+}
+
+// Bounded log of every write of the device's thresholds and why it happened, so a device
+// that changed by itself can be traced back. Each write also goes to the journal.
+pub struct ThresholdWriteLog {
+    entries: Mutex<VecDeque<ThresholdWrite>>,
+    capacity: usize,
+    journal: Arc<Journal>,
+}
+
+impl Default for ThresholdWriteLog {
+    fn default() -> Self {
+        Self::new(WRITE_LOG_CAPACITY, Arc::new(Journal::disabled()))
+    }
+}
+
+impl ThresholdWriteLog {
+    pub fn new(capacity: usize, journal: Arc<Journal>) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            journal,
+        }
+    }
+
+    // The log of writes made for `reason`, taken by the write helpers in serial.rs
+    pub fn reason(&self, reason: WriteReason) -> LoggedWrite<'_> {
+        LoggedWrite { log: self, reason }
+    }
+
+    fn record<T, E: fmt::Display>(
+        &self,
+        reason: WriteReason,
+        before: Sensors<Option<i32>>,
+        after: Sensors<Option<i32>>,
+        result: &Result<T, E>,
+    ) {
+        let entry = ThresholdWrite {
+            timestamp: Utc::now(),
+            reason,
+            before,
+            after,
+            success: result.is_ok(),
+            error: result.as_ref().err().map(E::to_string),
+        };
+        self.journal
+            .record(JournalKind::ThresholdWrite, entry.to_string());
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    // The last `limit` writes, oldest first
+    pub fn tail(&self, limit: usize) -> Vec<ThresholdWrite> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let skip = entries.len().saturating_sub(limit);
+        entries.iter().skip(skip).cloned().collect()
+    }
+}
+
+// Where a write of the device's thresholds is logged and why it happens. Every helper in
+// serial.rs that writes a threshold takes one and records the write itself, so no write
+// can skip the log.
+#[derive(Clone, Copy)]
+pub struct LoggedWrite<'a> {
+    log: &'a ThresholdWriteLog,
+    reason: WriteReason,
+}
+
+impl LoggedWrite<'_> {
+    // A write of all thresholds; `result` holds the ones set_all_thresholds replaced
+    pub fn all<E: fmt::Display>(&self, after: Sensors<i32>, result: &Result<Sensors<i32>, E>) {
+        let before = match result {
+            Ok(before) => before.map(Some),
+            Err(_) => Sensors::filled(after.len(), None),
+        };
+        self.log
+            .record(self.reason, before, after.map(Some), result);
+    }
+
+    // A write of device sensor `sensor`, of the `sensors` the device has
+    pub fn one<T, E: fmt::Display>(
+        &self,
+        sensor: usize,
+        sensors: usize,
+        before: Option<i32>,
+        after: i32,
+        result: &Result<T, E>,
+    ) {
+        let none = Sensors::filled(sensors.max(sensor + 1), None);
+        let mut values = (none, none);
+        values.0[sensor] = before;
+        values.1[sensor] = Some(after);
+        self.log.record(self.reason, values.0, values.1, result);
+    }
+}
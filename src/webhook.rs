@@ -0,0 +1,511 @@
+use crate::event::Event;
+use crate::profile::Profiles;
+use crate::sink::{NotificationSink, SinkInfo, SinkStatus};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Events that can trigger a webhook
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    PlayerChanged,
+    ProfileChanged,
+}
+
+impl WebhookEventKind {
+    pub const ALL: [WebhookEventKind; 2] = [
+        WebhookEventKind::PlayerChanged,
+        WebhookEventKind::ProfileChanged,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEventKind::PlayerChanged => "player_changed",
+            WebhookEventKind::ProfileChanged => "profile_changed",
+        }
+    }
+}
+
+impl FromStr for WebhookEventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        WebhookEventKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown webhook event '{}' (expected one of: {})",
+                    s,
+                    WebhookEventKind::ALL.map(|kind| kind.as_str()).join(", ")
+                )
+            })
+    }
+}
+
+// A webhook target configured with `--webhook url=...,events=...`
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub events: Vec<WebhookEventKind>,
+}
+
+impl WebhookConfig {
+    // Validate a target; no events means all events
+    pub fn new(url: String, mut events: Vec<WebhookEventKind>) -> Result<Self, String> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("webhook url must be http(s), got '{}'", url));
+        }
+        if events.is_empty() {
+            events = WebhookEventKind::ALL.to_vec();
+        }
+        Ok(Self { url, events })
+    }
+
+    pub fn wants(&self, kind: WebhookEventKind) -> bool {
+        self.events.contains(&kind)
+    }
+}
+
+// Parse `url=https://...,events=player_changed,profile_changed`.
+// Values without a `key=` prefix continue the previous key's list.
+pub fn parse_webhook_arg(arg: &str) -> Result<WebhookConfig, String> {
+    let mut url = None;
+    let mut events = Vec::new();
+    let mut current_key = String::new();
+
+    for part in arg.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let value = match part.split_once('=') {
+            Some((key, value)) if key == "url" || key == "events" => {
+                current_key = key.to_string();
+                value
+            }
+            Some((key, _)) => return Err(format!("unknown webhook option '{}'", key)),
+            None => part,
+        };
+        match current_key.as_str() {
+            "url" if url.is_none() => url = Some(value.to_string()),
+            "url" => return Err("webhook url given more than once".to_string()),
+            "events" => events.push(value.parse::<WebhookEventKind>()?),
+            _ => return Err(format!("expected url=... or events=..., got '{}'", part)),
+        }
+    }
+
+    let url = url.ok_or_else(|| "webhook is missing url=...".to_string())?;
+    WebhookConfig::new(url, events)
+}
+
+// JSON body POSTed to webhook targets
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WebhookPayload {
+    pub event: WebhookEventKind,
+    pub timestamp: String,
+    pub player: String,
+    pub profile: String,
+    pub pad_name: String,
+    // Human readable summary, which is what chat services like Discord display
+    pub content: String,
+}
+
+impl WebhookPayload {
+    pub fn new(event: WebhookEventKind, profiles: &Profiles, pad_name: &str) -> Self {
+        let content = match event {
+            WebhookEventKind::PlayerChanged if profiles.current_player().is_empty() => {
+                format!("No player is signed onto {}", pad_name)
+            }
+            WebhookEventKind::PlayerChanged => format!(
+                "{} signed onto {} (profile '{}')",
+                profiles.current_player(),
+                pad_name,
+                profiles.current_profile()
+            ),
+            WebhookEventKind::ProfileChanged => format!(
+                "{} switched to profile '{}'",
+                pad_name,
+                profiles.current_profile()
+            ),
+        };
+        Self {
+            event,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            player: profiles.current_player().to_string(),
+            profile: profiles.current_profile().to_string(),
+            pad_name: pad_name.to_string(),
+            content,
+        }
+    }
+}
+
+// Delivery counters for one webhook target, as GetWebhookStatus answers them
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct WebhookStatus {
+    pub url: String,
+    pub events: Vec<WebhookEventKind>,
+    pub delivered: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+}
+
+impl WebhookStatus {
+    // The status of a webhook sink, None for other sinks
+    pub fn from_sink(status: SinkStatus) -> Option<Self> {
+        (status.info.kind == WEBHOOK_SINK).then(|| Self {
+            url: status.info.target,
+            events: status
+                .info
+                .events
+                .iter()
+                .filter_map(|event| event.parse().ok())
+                .collect(),
+            delivered: status.delivered,
+            failed: status.failed,
+            dropped: status.dropped,
+            consecutive_failures: status.consecutive_failures,
+            last_error: status.last_error,
+        })
+    }
+}
+
+// Bounded retry policy for a single delivery
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+    pub request_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            request_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+// Kind of the webhook sinks in ListSinks
+pub const WEBHOOK_SINK: &str = "webhook";
+
+// POSTs the player and profile changes of the device pad to one `--webhook` target
+pub struct WebhookSink {
+    name: String,
+    config: WebhookConfig,
+    pad_name: String,
+    policy: RetryPolicy,
+    client: reqwest::Client,
+    // Player and profile of the last snapshot seen
+    last: Mutex<(String, String)>,
+}
+
+impl WebhookSink {
+    // Changes are notified from `profiles` on, the profiles the server starts with. The
+    // sink task may subscribe after the first snapshots went out, so it can't wait for one
+    // to compare with.
+    pub fn new(
+        name: String,
+        config: WebhookConfig,
+        pad_name: String,
+        policy: RetryPolicy,
+        profiles: &Profiles,
+    ) -> Self {
+        Self {
+            name,
+            config,
+            pad_name,
+            policy,
+            client: reqwest::Client::new(),
+            last: Mutex::new(seen(profiles)),
+        }
+    }
+}
+
+fn seen(profiles: &Profiles) -> (String, String) {
+    (
+        profiles.current_player().to_string(),
+        profiles.current_profile().to_string(),
+    )
+}
+
+// A sink per target, named `webhook-1`, `webhook-2`, ... in the order given, as the URLs
+// may hold secrets
+pub fn sinks(
+    configs: &[WebhookConfig],
+    pad_name: &str,
+    profiles: &Profiles,
+) -> Vec<Arc<dyn NotificationSink>> {
+    configs
+        .iter()
+        .enumerate()
+        .map(|(index, config)| {
+            Arc::new(WebhookSink::new(
+                format!("{}-{}", WEBHOOK_SINK, index + 1),
+                config.clone(),
+                pad_name.to_string(),
+                RetryPolicy::default(),
+                profiles,
+            )) as Arc<dyn NotificationSink>
+        })
+        .collect()
+}
+
+impl NotificationSink for WebhookSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn info(&self) -> SinkInfo {
+        SinkInfo {
+            kind: WEBHOOK_SINK,
+            target: self.config.url.clone(),
+            events: self
+                .config
+                .events
+                .iter()
+                .map(|kind| kind.as_str().to_string())
+                .collect(),
+        }
+    }
+
+    fn wants(&self, event: &Event) -> bool {
+        matches!(event, Event::ProfilesUpdated(_))
+    }
+
+    // Each snapshot is compared with the one before, so keepalives send nothing
+    fn deliver(&self, event: Event) -> BoxFuture<'_, Result<usize, String>> {
+        Box::pin(async move {
+            let Event::ProfilesUpdated(profiles) = event else {
+                return Ok(0);
+            };
+            let changes = {
+                let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+                let changes = detect_changes(&last, &profiles);
+                *last = seen(&profiles);
+                changes
+            };
+            let mut sent = 0;
+            for kind in changes.into_iter().filter(|kind| self.config.wants(*kind)) {
+                let payload = WebhookPayload::new(kind, &profiles, &self.pad_name);
+                deliver(&self.client, &self.config.url, &payload, self.policy).await?;
+                sent += 1;
+            }
+            Ok(sent)
+        })
+    }
+}
+
+// Compare the last seen player/profile with a new snapshot
+pub fn detect_changes(last: &(String, String), profiles: &Profiles) -> Vec<WebhookEventKind> {
+    let (player, profile) = last;
+    let mut events = Vec::new();
+    if *player != profiles.current_player() {
+        events.push(WebhookEventKind::PlayerChanged);
+    }
+    if *profile != profiles.current_profile() {
+        events.push(WebhookEventKind::ProfileChanged);
+    }
+    events
+}
+
+// POST one payload, retrying with exponential backoff
+async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    payload: &WebhookPayload,
+    policy: RetryPolicy,
+) -> Result<(), String> {
+    let mut backoff = policy.initial_backoff;
+    let mut last_error = String::new();
+
+    for attempt in 1..=policy.attempts {
+        let result = client
+            .post(url)
+            .timeout(policy.request_timeout)
+            .json(payload)
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => last_error = format!("HTTP {}", response.status()),
+            Err(e) => last_error = e.to_string(),
+        }
+        if attempt < policy.attempts {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Pad;
+    use crate::sink::{sink_task, SinkRegistry};
+    use axum::{routing::post, Json, Router};
+    use std::collections::HashMap;
+    use tokio::sync::{broadcast, mpsc};
+
+    fn profiles_with(player: &str, profile: &str) -> Profiles {
+        Profiles {
+            profiles: HashMap::new(),
+            default_profile: String::new(),
+            players: HashMap::new(),
+            pads: vec![Pad::default_pad(profile.to_string(), player.to_string())],
+            revision: 0,
+            schedules: Vec::new(),
+        }
+    }
+
+    fn snapshot(player: &str, profile: &str) -> Event {
+        Event::ProfilesUpdated(Arc::new(profiles_with(player, profile)))
+    }
+
+    #[test]
+    fn test_parse_webhook_arg() {
+        let config = parse_webhook_arg(
+            "url=https://discord.com/api/webhooks/1/abc,events=player_changed,profile_changed",
+        )
+        .unwrap();
+        assert_eq!(config.url, "https://discord.com/api/webhooks/1/abc");
+        assert_eq!(
+            config.events,
+            vec![
+                WebhookEventKind::PlayerChanged,
+                WebhookEventKind::ProfileChanged
+            ]
+        );
+
+        let config = parse_webhook_arg("url=http://localhost/hook?a=b").unwrap();
+        assert_eq!(config.url, "http://localhost/hook?a=b");
+        assert_eq!(config.events, WebhookEventKind::ALL.to_vec());
+
+        let config = parse_webhook_arg("url=http://localhost/hook,events=profile_changed").unwrap();
+        assert!(!config.wants(WebhookEventKind::PlayerChanged));
+        assert!(config.wants(WebhookEventKind::ProfileChanged));
+    }
+
+    #[test]
+    fn test_parse_webhook_arg_errors() {
+        assert!(parse_webhook_arg("events=player_changed").is_err());
+        assert!(parse_webhook_arg("url=ftp://example.com").is_err());
+        assert!(parse_webhook_arg("url=http://example.com,events=bogus").is_err());
+        assert!(parse_webhook_arg("url=http://example.com,colour=red").is_err());
+    }
+
+    #[test]
+    fn test_detect_changes() {
+        let last = ("Alice".to_string(), "Soft".to_string());
+        assert!(detect_changes(&last, &profiles_with("Alice", "Soft")).is_empty());
+        assert_eq!(
+            detect_changes(&last, &profiles_with("Bob", "Soft")),
+            vec![WebhookEventKind::PlayerChanged]
+        );
+        assert_eq!(
+            detect_changes(&last, &profiles_with("Bob", "Hard")),
+            vec![
+                WebhookEventKind::PlayerChanged,
+                WebhookEventKind::ProfileChanged
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_first_change_after_startup_is_delivered() {
+        let (hook_tx, mut hook_rx) = mpsc::channel::<WebhookPayload>(10);
+        let app = Router::new().route(
+            "/hook",
+            post(move |Json(payload): Json<WebhookPayload>| {
+                let hook_tx = hook_tx.clone();
+                async move {
+                    let _ = hook_tx.send(payload).await;
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = WebhookConfig {
+            url: format!("http://{}/hook", addr),
+            events: vec![WebhookEventKind::PlayerChanged],
+        };
+        let started = profiles_with("", "Soft");
+        let registry = Arc::new(SinkRegistry::new(sinks(&[config], "Cab", &started)));
+        let (tx, rx) = broadcast::channel::<Event>(10);
+        let handle = tokio::spawn(sink_task(registry.clone(), rx));
+
+        // The first change after startup is compared with the profiles the server
+        // started with, not taken as a baseline
+        tx.send(snapshot("Alice", "Hard")).unwrap();
+
+        let payload = tokio::time::timeout(Duration::from_secs(5), hook_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload.event, WebhookEventKind::PlayerChanged);
+        assert_eq!(payload.player, "Alice");
+        assert_eq!(payload.profile, "Hard");
+        assert_eq!(payload.pad_name, "Cab");
+
+        // profile_changed isn't subscribed, so only one delivery is counted
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let status = registry.status();
+        assert_eq!(status[0].name, "webhook-1");
+        assert_eq!(status[0].delivered, 1);
+        assert_eq!(status[0].failed, 0);
+        let status = WebhookStatus::from_sink(status[0].clone()).unwrap();
+        assert_eq!(status.events, [WebhookEventKind::PlayerChanged]);
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_webhook_failure_counter() {
+        // Grab a free port and close it again so nothing is listening
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let config = WebhookConfig {
+            url: format!("http://{}/hook", addr),
+            events: WebhookEventKind::ALL.to_vec(),
+        };
+        let policy = RetryPolicy {
+            attempts: 2,
+            initial_backoff: Duration::from_millis(10),
+            request_timeout: Duration::from_millis(500),
+        };
+        let started = profiles_with("Alice", "Soft");
+        let sink = WebhookSink::new(
+            "hook".to_string(),
+            config,
+            "Cab".to_string(),
+            policy,
+            &started,
+        );
+        let registry = Arc::new(SinkRegistry::new(vec![Arc::new(sink)]));
+        let (tx, rx) = broadcast::channel::<Event>(10);
+        let handle = tokio::spawn(sink_task(registry.clone(), rx));
+
+        tx.send(snapshot("Bob", "Soft")).unwrap();
+
+        let mut status = registry.status();
+        for _ in 0..50 {
+            if status[0].failed > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            status = registry.status();
+        }
+        assert_eq!(status[0].failed, 1);
+        assert_eq!(status[0].consecutive_failures, 1);
+        assert_eq!(status[0].delivered, 0);
+        assert!(status[0].last_error.is_some());
+        handle.abort();
+    }
+}